@@ -1,5 +1,5 @@
 
-use crate::net::client::SqlClient;
+use engine::net::client::SqlClient;
 use criterion::{Criterion, criterion_group, criterion_main};
 use tokio::runtime::Runtime;
 
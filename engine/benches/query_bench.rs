@@ -0,0 +1,259 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use engine::database::{Database, DatabaseConfig};
+use engine::net::copy;
+use engine::query::binder::Value;
+use engine::storage::buffer_pool::READ_AHEAD_PAGES;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Rows in the table every benchmark reads or builds.
+const ROWS: usize = 10_000;
+// Rows per INSERT when loading, as `mydb dump` writes them.
+const INSERT_ROWS: usize = 500;
+
+// Where the files go: a temporary directory on disk, and tmpfs where the
+// system has one, so page and log I/O stay in memory and what is left is
+// the engine. Pool sizes are the server's default, and one big enough to
+// hold every page.
+fn backends() -> Vec<(&'static str, PathBuf, usize)> {
+    let name = format!("mydb_bench_{}", std::process::id());
+    let mut backends = vec![("tmpfile", std::env::temp_dir().join(&name), 10)];
+    if Path::new("/dev/shm").is_dir() {
+        backends.push(("memory", Path::new("/dev/shm").join(&name), 4096));
+    }
+    backends
+}
+
+fn open(dir: &Path, pool_size: usize) -> Database {
+    let _ = std::fs::remove_dir_all(dir);
+    Database::open(DatabaseConfig {
+        pool_size,
+        ..DatabaseConfig::new(dir)
+    })
+    .unwrap()
+}
+
+// Replaces `t` with `ROWS` rows, loaded in one transaction.
+fn load(db: &mut Database) {
+    let _ = db.execute("DROP TABLE t;");
+    db.execute("CREATE TABLE t (id INT, name TEXT, score INT);")
+        .unwrap();
+    db.begin().unwrap();
+    for start in (0..ROWS).step_by(INSERT_ROWS) {
+        let values: Vec<String> = (start..start + INSERT_ROWS)
+            .map(|id| format!("({}, 'name{}', {})", id, id, id % 100))
+            .collect();
+        db.execute(&format!(
+            "INSERT INTO t (id, name, score) VALUES {};",
+            values.join(", ")
+        ))
+        .unwrap();
+    }
+    db.commit().unwrap();
+}
+
+// Replaces `t` with the same rows as `load`, sent through COPY in the binary
+// format.
+fn copy_load(db: &mut Database, rows: &[u8]) {
+    let _ = db.execute("DROP TABLE t;");
+    db.execute("CREATE TABLE t (id INT, name TEXT, score INT);")
+        .unwrap();
+    let copied = db.copy("COPY t FROM STDIN BINARY;", rows).unwrap();
+    assert_eq!(copied.affected, Some(ROWS as u64));
+}
+
+fn copy_rows() -> Vec<u8> {
+    let rows: Vec<Vec<Value>> = (0..ROWS as i64)
+        .map(|id| {
+            let name = Value::String(format!("name{}", id));
+            vec![Value::Int(id), name, Value::Int(id % 100)]
+        })
+        .collect();
+    let mut encoded = Vec::new();
+    copy::write_binary(&mut encoded, &rows).unwrap();
+    encoded
+}
+
+fn bench_point_select(c: &mut Criterion) {
+    let mut group = c.benchmark_group("point_select");
+    group.throughput(Throughput::Elements(1));
+    for (backend, dir, pool_size) in backends() {
+        let mut db = open(&dir, pool_size);
+        load(&mut db);
+        db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+        let mut id = 0;
+        group.bench_function(backend, |b| {
+            b.iter(|| {
+                id = (id + 7919) % ROWS;
+                let result = db
+                    .execute(&format!("SELECT name FROM t WHERE id = {};", id))
+                    .unwrap();
+                assert_eq!(result.rows.len(), 1);
+            })
+        });
+        db.close().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+fn bench_filtered_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("filtered_scan");
+    // Counted in rows scanned, not rows returned.
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(20);
+    for (backend, dir, pool_size) in backends() {
+        let mut db = open(&dir, pool_size);
+        load(&mut db);
+        group.bench_function(backend, |b| {
+            b.iter(|| {
+                let result = db.execute("SELECT id FROM t WHERE score = 42;").unwrap();
+                assert_eq!(result.rows.len(), ROWS / 100);
+            })
+        });
+        db.close().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+// A full scan of a table of mostly text, whose rows are filtered on one
+// string and projected down to others, with every page in the pool. What
+// is left is the cost of turning stored rows into values.
+fn bench_string_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("string_scan");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(20);
+    let (backend, dir, _) = backends().pop().unwrap();
+    let mut db = open(&dir, 4096);
+    db.execute("CREATE TABLE people (id INT, name TEXT, email TEXT, city TEXT, note TEXT);")
+        .unwrap();
+    db.begin().unwrap();
+    for start in (0..ROWS).step_by(INSERT_ROWS) {
+        let values: Vec<String> = (start..start + INSERT_ROWS)
+            .map(|id| {
+                format!(
+                    "({}, 'person number {}', 'person.{}@example.com', 'city {}', \
+                     'a note of some length about person {}')",
+                    id,
+                    id,
+                    id,
+                    id % 50,
+                    id
+                )
+            })
+            .collect();
+        db.execute(&format!(
+            "INSERT INTO people (id, name, email, city, note) VALUES {};",
+            values.join(", ")
+        ))
+        .unwrap();
+    }
+    db.commit().unwrap();
+    group.bench_function(backend, |b| {
+        b.iter(|| {
+            let result = db
+                .execute("SELECT name, email, note FROM people WHERE city <> 'city 7';")
+                .unwrap();
+            assert_eq!(result.rows.len(), ROWS - ROWS / 50);
+        })
+    });
+    db.close().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    group.finish();
+}
+
+// A full scan of a table on disk with a pool too small to hold it, so every
+// page comes from the file, with and without reading ahead of the scan.
+fn bench_cold_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cold_scan");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(20);
+    let (backend, dir, pool_size) = backends().remove(0);
+    for read_ahead in [0, READ_AHEAD_PAGES] {
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut db = Database::open(DatabaseConfig {
+            pool_size,
+            read_ahead,
+            ..DatabaseConfig::new(&dir)
+        })
+        .unwrap();
+        load(&mut db);
+        let name = format!("{}/read_ahead_{}", backend, read_ahead);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let result = db.execute("SELECT id FROM t WHERE score < 0;").unwrap();
+                assert!(result.rows.is_empty());
+            })
+        });
+        db.close().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+fn bench_bulk_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_insert");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(10);
+    for (backend, dir, pool_size) in backends() {
+        let mut db = open(&dir, pool_size);
+        group.bench_function(backend, |b| b.iter(|| load(&mut db)));
+        db.close().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+// The same load as `bench_bulk_insert`, through COPY.
+fn bench_copy_in(c: &mut Criterion) {
+    let mut group = c.benchmark_group("copy_in");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(10);
+    let rows = copy_rows();
+    for (backend, dir, pool_size) in backends() {
+        let mut db = open(&dir, pool_size);
+        group.bench_function(backend, |b| b.iter(|| copy_load(&mut db, &rows)));
+        db.close().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+fn bench_index_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_build");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(10);
+    for (backend, dir, pool_size) in backends() {
+        let mut db = open(&dir, pool_size);
+        // There is no DROP INDEX, so each build gets a freshly loaded table;
+        // only the CREATE INDEX is timed.
+        group.bench_function(backend, |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    load(&mut db);
+                    let started = Instant::now();
+                    db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+                    elapsed += started.elapsed();
+                }
+                elapsed
+            })
+        });
+        db.close().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_point_select,
+    bench_filtered_scan,
+    bench_string_scan,
+    bench_cold_scan,
+    bench_bulk_insert,
+    bench_copy_in,
+    bench_index_build
+);
+criterion_main!(benches);
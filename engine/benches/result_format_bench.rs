@@ -0,0 +1,100 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use engine::net::binary;
+use engine::net::client::DbValue;
+use engine::net::row::{ColumnType, Schema};
+use engine::query::binder::Value;
+use serde::Deserialize;
+
+// Rows in the result both formats carry: three INT columns, as a report
+// over numbers would have.
+const ROWS: usize = 100_000;
+
+// The JSON body as the client reads it.
+#[derive(Deserialize)]
+struct JsonResult {
+    #[allow(dead_code)]
+    columns: Vec<String>,
+    rows: Vec<Vec<DbValue>>,
+}
+
+fn rows() -> Vec<Vec<Value>> {
+    (0..ROWS as i64)
+        .map(|id| vec![Value::Int(id), Value::Int(id % 100), Value::Int(id * 7919)])
+        .collect()
+}
+
+fn schema() -> Schema {
+    Schema::typed(vec![
+        ("ID".into(), ColumnType::Int),
+        ("SCORE".into(), ColumnType::Int),
+        ("BALANCE".into(), ColumnType::Int),
+    ])
+}
+
+// Both bodies the way the server writes them.
+fn json_body(rows: &[Vec<Value>]) -> Vec<u8> {
+    let mut body = format!(
+        r#"{{"columns":{},"rows":["#,
+        serde_json::to_string(schema().names()).unwrap()
+    )
+    .into_bytes();
+    for (i, row) in rows.iter().enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        let values: Vec<i64> = row
+            .iter()
+            .map(|v| match v {
+                Value::Int(i) => *i,
+                Value::String(_) => unreachable!(),
+            })
+            .collect();
+        serde_json::to_writer(&mut body, &values).unwrap();
+    }
+    body.extend_from_slice(format!(r#"],"row_count":{}}}"#, rows.len()).as_bytes());
+    body
+}
+
+fn binary_body(rows: &[Vec<Value>]) -> Vec<u8> {
+    let mut body = binary::header(&schema());
+    for row in rows {
+        binary::push_row(&mut body, row);
+    }
+    body.extend(binary::trailer(&format!(
+        r#"{{"row_count":{}}}"#,
+        rows.len()
+    )));
+    body
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let rows = rows();
+    let json = json_body(&rows);
+    let binary = binary_body(&rows);
+    println!(
+        "{} rows on the wire: JSON {} bytes, binary {} bytes",
+        ROWS,
+        json.len(),
+        binary.len()
+    );
+
+    let mut group = c.benchmark_group("decode_result");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(20);
+    group.bench_function("json", |b| {
+        b.iter(|| {
+            let result: JsonResult = serde_json::from_slice(&json).unwrap();
+            assert_eq!(result.rows.len(), ROWS);
+        })
+    });
+    group.bench_function("binary", |b| {
+        b.iter(|| {
+            let frame = binary::decode(&binary).unwrap();
+            assert_eq!(frame.rows.len(), ROWS);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
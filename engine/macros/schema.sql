@@ -0,0 +1,6 @@
+-- mydb dump
+
+CREATE TABLE USERS (ID INT, NAME TEXT);
+CREATE INDEX USERS_ID ON USERS (ID) USING BTREE;
+
+CREATE TABLE ORDERS (ID INT, USER_ID INT REFERENCES USERS(ID) ON DELETE RESTRICT, ITEM TEXT);
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use engine::cli::shell::split_script;
+use engine::database::{Database, Description};
+use engine::net::row::ColumnType;
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::{Expr, Ident, LitStr, Token, parse_macro_input};
+
+/// Runs a statement on an embedded `Database`, checked when the program is
+/// built against the schema in `MYDB_SCHEMA`, a file written by `mydb dump
+/// --schema-only` (`schema.sql` beside the crate's `Cargo.toml` when it is
+/// not set):
+///
+/// ```ignore
+/// let users = query!(db, "SELECT id, name FROM users WHERE id = ?", id)?;
+/// println!("{}", users[0].name);
+/// ```
+///
+/// A statement that makes rows gives a `Vec` of a struct with a field for
+/// each column, named in lower case and typed `i64` or `String`; any other
+/// gives how many rows it changed. A statement the engine cannot bind, a
+/// wrong number of values, or a value of the wrong type for its `?` fails
+/// the build.
+#[proc_macro]
+pub fn query(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as QueryInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+// `db, "SQL", values...`
+struct QueryInput {
+    db: Expr,
+    sql: LitStr,
+    args: Vec<Expr>,
+}
+
+impl Parse for QueryInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let db = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let sql = input.parse()?;
+        let mut args = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            args.push(input.parse()?);
+        }
+        Ok(QueryInput { db, sql, args })
+    }
+}
+
+fn expand(input: QueryInput) -> syn::Result<TokenStream2> {
+    let schema_file = schema_file();
+    let schema = std::fs::read_to_string(&schema_file).map_err(|e| {
+        syn::Error::new(
+            Span::call_site(),
+            format!(
+                "Failed to read the schema {:?}: {} (MYDB_SCHEMA names the output of \
+                 `mydb dump --schema-only`)",
+                schema_file, e
+            ),
+        )
+    })?;
+    let sql = input.sql.value();
+    let description = describe(&schema, &sql)
+        .map_err(|e| syn::Error::new(input.sql.span(), format!("{:#}", e)))?;
+    if description.params.len() != input.args.len() {
+        return Err(syn::Error::new(
+            input.sql.span(),
+            format!(
+                "The statement has {} placeholders but {} values were given",
+                description.params.len(),
+                input.args.len()
+            ),
+        ));
+    }
+
+    // A value of the wrong type fails to compile where it is passed.
+    let params = input
+        .args
+        .iter()
+        .zip(&description.params)
+        .map(|(arg, param)| match param {
+            Some(ColumnType::Int) => quote_spanned! {arg.span()=>
+                ::engine::query::value::Value::Int(#arg)
+            },
+            Some(ColumnType::Text) => quote_spanned! {arg.span()=>
+                ::engine::query::value::Value::String(::std::string::String::from(
+                    ::std::convert::AsRef::<str>::as_ref(&#arg),
+                ))
+            },
+            None => quote_spanned! {arg.span()=>
+                ::engine::query::value::Value::from(#arg)
+            },
+        });
+    let db = &input.db;
+    // Rebuilds the crate when the schema changes.
+    let schema_path = schema_file.to_string_lossy();
+    let track = quote! { const _: &str = ::std::include_str!(#schema_path); };
+
+    if description.columns.is_empty() {
+        return Ok(quote! {{
+            #track
+            (#db)
+                .execute_with(#sql, &[#(#params),*])
+                .map(|result| result.affected.unwrap_or(0))
+        }});
+    }
+    let fields = field_names(&description.columns);
+    let names = description.columns.iter().map(|(name, _)| name);
+    let types: Vec<TokenStream2> = description
+        .columns
+        .iter()
+        .map(|(_, column_type)| match column_type {
+            ColumnType::Int => quote!(i64),
+            ColumnType::Text => quote!(::std::string::String),
+        })
+        .collect();
+    Ok(quote! {{
+        #track
+        #[allow(dead_code)]
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        struct Row {
+            #(pub #fields: #types,)*
+        }
+        (#db).query_map(#sql, &[#(#params),*], |row| {
+            ::std::result::Result::Ok(Row {
+                #(#fields: row.get::<#types>(#names)?,)*
+            })
+        })
+    }})
+}
+
+fn schema_file() -> PathBuf {
+    let dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    dir.join(std::env::var("MYDB_SCHEMA").unwrap_or_else(|_| "schema.sql".to_string()))
+}
+
+// Describes `sql` in a database of its own that has only `schema` in it.
+fn describe(schema: &str, sql: &str) -> Result<Description> {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "mydb_query_{}_{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let described = (|| {
+        let mut db = Database::open(&dir)?;
+        for statement in split_script(schema)? {
+            db.execute(&statement.sql)
+                .with_context(|| format!("line {} of the schema", statement.line))?;
+        }
+        let described = db.describe(sql);
+        db.close()?;
+        described
+    })();
+    let _ = std::fs::remove_dir_all(&dir);
+    described
+}
+
+// The columns as field names: lower case, with anything a name cannot
+// hold made an underscore, and a suffix where two would be the same.
+fn field_names(columns: &[(String, ColumnType)]) -> Vec<Ident> {
+    let mut fields: Vec<Ident> = Vec::new();
+    for (name, _) in columns {
+        let mut field: String = name
+            .to_ascii_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        if !field.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            field.insert_str(0, "column_");
+        }
+        let mut ident =
+            syn::parse_str::<Ident>(&field).unwrap_or_else(|_| format_ident!("{}_", field));
+        let mut n = 2usize;
+        while fields.contains(&ident) {
+            ident = format_ident!("{}_{}", field, n);
+            n += 1;
+        }
+        fields.push(ident);
+    }
+    fields
+}
@@ -0,0 +1,59 @@
+use engine::database::Database;
+use engine_macros::query;
+use std::path::PathBuf;
+
+// The statements are checked against `schema.sql` beside this crate's
+// Cargo.toml; the database they run on here has the same tables.
+fn fresh_db(name: &str) -> (Database, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("mydb_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut db = Database::open(&dir).unwrap();
+    let schema =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/schema.sql")).unwrap();
+    for statement in engine::cli::shell::split_script(&schema).unwrap() {
+        db.execute(&statement.sql).unwrap();
+    }
+    (db, dir)
+}
+
+#[test]
+fn test_query_returns_typed_rows() {
+    let (mut db, dir) = fresh_db("query_macro");
+    let name = String::from("o'brien");
+    let inserted = query!(
+        db,
+        "INSERT INTO users (id, name) VALUES (?, ?), (2, 'ada');",
+        1,
+        name
+    )
+    .unwrap();
+    assert_eq!(inserted, 2);
+    query!(
+        db,
+        "INSERT INTO orders (id, user_id, item) VALUES (?, ?, ?);",
+        10,
+        2,
+        "tea"
+    )
+    .unwrap();
+
+    let id: i64 = 1;
+    let users = query!(db, "SELECT id, name FROM users WHERE id = ?;", id).unwrap();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].id, 1);
+    assert_eq!(users[0].name, "o'brien");
+
+    let db = &mut db;
+    let orders = query!(
+        db,
+        "SELECT user_id, item FROM orders WHERE item = ?;",
+        "tea",
+    )
+    .unwrap();
+    assert_eq!(orders.len(), 1);
+    assert_eq!((orders[0].user_id, orders[0].item.as_str()), (2, "tea"));
+
+    let counted = query!(db, "SELECT COUNT(*) FROM users;").unwrap();
+    assert_eq!(counted[0].count, 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
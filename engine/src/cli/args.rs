@@ -0,0 +1,592 @@
+use crate::cli::shell::{DEFAULT_MAX_WIDTH, Format};
+use crate::net::{
+    admission::WhenBusy,
+    auth::BOOTSTRAP_ADMIN,
+    server::{
+        DEFAULT_AUTO_ANALYZE, DEFAULT_LOG_FILTER, DEFAULT_MAX_BODY_BYTES,
+        DEFAULT_MAX_RUNNING_QUERIES, DEFAULT_QUERY_TIMEOUT, DEFAULT_SESSION_TTL,
+    },
+    settings::ConfigFile,
+};
+use crate::query::memory::DEFAULT_WORK_MEM;
+use crate::storage::storage::DEFAULT_MAX_COLUMNS;
+use anyhow::{Context, Result, anyhow, bail};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use tracing_subscriber::EnvFilter;
+
+pub const MIN_PAGE_SIZE: usize = 512;
+// Slot offsets inside a page are u16, so a page has to fit below 64 KiB.
+pub const MAX_PAGE_SIZE: usize = 32 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerArgs {
+    pub listen: SocketAddr,
+    pub data_dir: PathBuf,
+    pub page_size: usize,
+    pub pool_size: usize,
+    pub wal: PathBuf,
+    pub session_ttl: Duration,
+    pub metrics_login: bool,
+    pub query_timeout: Duration,
+    pub max_body_bytes: usize,
+    pub log_level: String,
+    pub max_queries: usize,
+    pub when_busy: WhenBusy,
+    // Requests per second per session; None for no limit.
+    pub rate_limit: Option<u32>,
+    // URL of the primary to follow as a read-only standby, and the account
+    // to read its log as. The password comes from MYDB_STANDBY_PASSWORD.
+    pub standby_of: Option<String>,
+    pub standby_user: String,
+    // Memory for cached SELECT responses; 0 leaves the cache off.
+    pub result_cache_bytes: usize,
+    // Memory a statement's sorts, aggregates and joins may hold.
+    pub work_mem: usize,
+    // Log kept behind the latest checkpoint for AS OF queries.
+    pub history_window_bytes: u64,
+    // Only run reads and log nothing, as `ServerConfig::read_only`.
+    pub read_only: bool,
+    // Where DDL, DML and account statements are audited; None for nowhere.
+    pub audit_log: Option<PathBuf>,
+    // Percent of a table's rows changed that has it analyzed again; 0 for
+    // never, as a benchmark wants its statistics to stay put.
+    pub auto_analyze: u32,
+    // The most columns CREATE TABLE takes.
+    pub max_columns: usize,
+    // Read from `--config`. Its restart-only keys have already gone into the
+    // fields above; its settings are applied by the server.
+    pub config: Option<ConfigFile>,
+}
+
+impl ServerArgs {
+    pub fn data_file(&self) -> PathBuf {
+        self.data_dir.join("data.db")
+    }
+
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_with_env(args, |key| std::env::var(key).ok())
+    }
+
+    // `[--listen <addr>] [--data-dir <dir>] [--page-size <n>] [--pool-size <n>]
+    // [--wal <path>] [--session-ttl <secs>] [--metrics-login <bool>]
+    // [--query-timeout <secs>] [--max-body <bytes>] [--log-level <filter>]
+    // [--max-queries <n>] [--when-busy reject|queue] [--rate-limit <per sec>]
+    // [--standby-of <url>] [--standby-user <name>] [--result-cache <bytes>]
+    // [--work-mem <bytes>] [--history-window <bytes>] [--read-only]
+    // [--audit-log <file>] [--auto-analyze <percent>] [--config <file>]`, each
+    // falling back to its MYDB_* variable, RUST_LOG for the log level, and
+    // then to the defaults. A key in the config file goes over the flag and
+    // variable it stands in for.
+    pub fn parse_with_env(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut flags = Flags::parse_with_switches(
+            args,
+            &[
+                "--listen",
+                "--data-dir",
+                "--page-size",
+                "--pool-size",
+                "--wal",
+                "--session-ttl",
+                "--metrics-login",
+                "--query-timeout",
+                "--max-body",
+                "--log-level",
+                "--max-queries",
+                "--when-busy",
+                "--rate-limit",
+                "--standby-of",
+                "--standby-user",
+                "--result-cache",
+                "--work-mem",
+                "--history-window",
+                "--audit-log",
+                "--auto-analyze",
+                "--max-columns",
+                "--config",
+            ],
+            &["--read-only"],
+        )?;
+        let config = flags
+            .take("--config")
+            .or_else(|| env("MYDB_CONFIG"))
+            .map(|path| ConfigFile::read(&PathBuf::from(path)))
+            .transpose()?;
+        let mut get = |flag: &'static str, var: &'static str| -> Option<(&'static str, String)> {
+            let given = flags
+                .take(flag)
+                .map(|v| (flag, v))
+                .or_else(|| env(var).map(|v| (var, v)));
+            config.as_ref().and_then(|c| c.get(flag)).or(given)
+        };
+
+        let listen = parse_value(get("--listen", "MYDB_LISTEN"))?
+            .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3000)));
+        let data_dir = get("--data-dir", "MYDB_DATA_DIR")
+            .map_or_else(|| PathBuf::from("."), |(_, v)| PathBuf::from(v));
+        let page_size = parse_value(get("--page-size", "MYDB_PAGE_SIZE"))?.unwrap_or(4096);
+        let pool_size = parse_value(get("--pool-size", "MYDB_POOL_SIZE"))?.unwrap_or(10);
+        let wal = get("--wal", "MYDB_WAL")
+            .map_or_else(|| data_dir.join("wal.log"), |(_, v)| PathBuf::from(v));
+        let session_ttl = parse_value(get("--session-ttl", "MYDB_SESSION_TTL"))?
+            .map_or(DEFAULT_SESSION_TTL, Duration::from_secs);
+        let metrics_login =
+            parse_value(get("--metrics-login", "MYDB_METRICS_LOGIN"))?.unwrap_or(false);
+        let query_timeout = parse_value(get("--query-timeout", "MYDB_QUERY_TIMEOUT"))?
+            .map_or(DEFAULT_QUERY_TIMEOUT, Duration::from_secs);
+        let max_body_bytes =
+            parse_value(get("--max-body", "MYDB_MAX_BODY"))?.unwrap_or(DEFAULT_MAX_BODY_BYTES);
+        let log_level = get("--log-level", "RUST_LOG")
+            .map_or_else(|| DEFAULT_LOG_FILTER.to_string(), |(_, v)| v);
+        let max_queries = parse_value(get("--max-queries", "MYDB_MAX_QUERIES"))?
+            .unwrap_or(DEFAULT_MAX_RUNNING_QUERIES);
+        let when_busy = get("--when-busy", "MYDB_WHEN_BUSY")
+            .map(|(name, v)| {
+                WhenBusy::parse(&v).ok_or_else(|| {
+                    anyhow!(
+                        "invalid value {:?} for {}, expected reject or queue",
+                        v,
+                        name
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or_default();
+        // 0 turns the limit off, like leaving it unset.
+        let rate_limit = parse_value(get("--rate-limit", "MYDB_RATE_LIMIT"))?.filter(|&r| r > 0);
+        let standby_of = get("--standby-of", "MYDB_STANDBY_OF")
+            .map(|(_, v)| v.trim_end_matches('/').to_string());
+        let standby_user = get("--standby-user", "MYDB_STANDBY_USER")
+            .map_or_else(|| BOOTSTRAP_ADMIN.to_string(), |(_, v)| v);
+        let result_cache_bytes =
+            parse_value(get("--result-cache", "MYDB_RESULT_CACHE"))?.unwrap_or(0);
+        let work_mem = parse_value(get("--work-mem", "MYDB_WORK_MEM"))?.unwrap_or(DEFAULT_WORK_MEM);
+        let history_window_bytes =
+            parse_value(get("--history-window", "MYDB_HISTORY_WINDOW"))?.unwrap_or(0);
+        let read_only = parse_value(get("--read-only", "MYDB_READ_ONLY"))?.unwrap_or(false);
+        let audit_log = get("--audit-log", "MYDB_AUDIT_LOG").map(|(_, v)| PathBuf::from(v));
+        let auto_analyze = parse_value(get("--auto-analyze", "MYDB_AUTO_ANALYZE"))?
+            .unwrap_or(DEFAULT_AUTO_ANALYZE);
+        let max_columns =
+            parse_value(get("--max-columns", "MYDB_MAX_COLUMNS"))?.unwrap_or(DEFAULT_MAX_COLUMNS);
+
+        let args = ServerArgs {
+            listen,
+            data_dir,
+            page_size,
+            pool_size,
+            wal,
+            session_ttl,
+            metrics_login,
+            query_timeout,
+            max_body_bytes,
+            log_level,
+            max_queries,
+            when_busy,
+            rate_limit,
+            standby_of,
+            standby_user,
+            result_cache_bytes,
+            work_mem,
+            history_window_bytes,
+            read_only,
+            audit_log,
+            auto_analyze,
+            max_columns,
+            config,
+        };
+        args.validate()?;
+        Ok(args)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_page_size(self.page_size)?;
+        if self.pool_size == 0 {
+            bail!("Pool size must be at least 1 page");
+        }
+        if self.session_ttl.is_zero() {
+            bail!("Session TTL must be at least 1 second");
+        }
+        if self.query_timeout.is_zero() {
+            bail!("Query timeout must be at least 1 second");
+        }
+        if self.max_body_bytes == 0 {
+            bail!("Maximum body size must be at least 1 byte");
+        }
+        if self.work_mem == 0 {
+            bail!("work_mem must be at least 1 byte");
+        }
+        if self.max_columns == 0 {
+            bail!("A table must be allowed at least 1 column");
+        }
+        if self.max_queries == 0 {
+            bail!("At least 1 query must be allowed to run");
+        }
+        if let Some(url) = &self.standby_of
+            && !url.starts_with("http://")
+            && !url.starts_with("https://")
+        {
+            bail!(
+                "Primary URL must start with http:// or https://, got {:?}",
+                url
+            );
+        }
+        if self.read_only && self.standby_of.is_some() {
+            bail!("--read-only cannot be combined with --standby-of");
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            bail!("Invalid log level {:?}: {}", self.log_level, e);
+        }
+        if self.data_dir.exists() && !self.data_dir.is_dir() {
+            bail!("Data directory {:?} is not a directory", self.data_dir);
+        }
+        Ok(())
+    }
+}
+
+fn validate_page_size(page_size: usize) -> Result<()> {
+    if !page_size.is_power_of_two() {
+        bail!("Page size must be a power of two, got {}", page_size);
+    }
+    if !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        bail!(
+            "Page size must be between {} and {} bytes, got {}",
+            MIN_PAGE_SIZE,
+            MAX_PAGE_SIZE,
+            page_size
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitArgs {
+    pub data_dir: PathBuf,
+    pub page_size: usize,
+    pub wal: PathBuf,
+    // Initialize a directory that already has files in it, replacing the
+    // database there.
+    pub force: bool,
+    // Create the admin account now rather than on the server's first start.
+    pub admin: bool,
+}
+
+impl InitArgs {
+    pub fn data_file(&self) -> PathBuf {
+        self.data_dir.join("data.db")
+    }
+
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_with_env(args, |key| std::env::var(key).ok())
+    }
+
+    // `[--data-dir <dir>] [--page-size <n>] [--wal <path>] [--force]
+    // [--admin]`, falling back to the same MYDB_* variables as the server.
+    pub fn parse_with_env(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut flags = Flags::parse_with_switches(
+            args,
+            &["--data-dir", "--page-size", "--wal"],
+            &["--force", "--admin"],
+        )?;
+        let mut get = |flag: &'static str, var: &'static str| -> Option<(&'static str, String)> {
+            flags
+                .take(flag)
+                .map(|v| (flag, v))
+                .or_else(|| env(var).map(|v| (var, v)))
+        };
+        let data_dir = get("--data-dir", "MYDB_DATA_DIR")
+            .map_or_else(|| PathBuf::from("."), |(_, v)| PathBuf::from(v));
+        let page_size = parse_value(get("--page-size", "MYDB_PAGE_SIZE"))?.unwrap_or(4096);
+        let wal = get("--wal", "MYDB_WAL")
+            .map_or_else(|| data_dir.join("wal.log"), |(_, v)| PathBuf::from(v));
+        let force = parse_value(flags.take("--force").map(|v| ("--force", v)))?.unwrap_or(false);
+        let admin = parse_value(flags.take("--admin").map(|v| ("--admin", v)))?.unwrap_or(false);
+        validate_page_size(page_size)?;
+        if data_dir.exists() && !data_dir.is_dir() {
+            bail!("Data directory {:?} is not a directory", data_dir);
+        }
+        Ok(InitArgs {
+            data_dir,
+            page_size,
+            wal,
+            force,
+            admin,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShellArgs {
+    pub url: String,
+    // Widest a table cell prints before it is cut short.
+    pub max_width: usize,
+    pub format: Format,
+    // A script to run instead of prompting for statements.
+    pub file: Option<PathBuf>,
+    // Run the script as one transaction, all of it or none.
+    pub single_transaction: bool,
+    // Statements to run instead of prompting for them.
+    pub command: Option<String>,
+    // Who to log in as; prompted for when unset.
+    pub user: Option<String>,
+}
+
+impl ShellArgs {
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_with_env(args, |key| std::env::var(key).ok())
+    }
+
+    // `[--url <url>] [--max-width <chars>] [--format table|plain|csv|json]
+    // [--file <script> | -c <sql>] [--single-transaction] [--user <name>]`,
+    // falling back to MYDB_URL, MYDB_MAX_WIDTH, MYDB_FORMAT and MYDB_USER.
+    pub fn parse_with_env(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut flags = Flags::parse_with_switches(
+            args,
+            &["--url", "--max-width", "--format", "--file", "-c", "--user"],
+            &["--single-transaction"],
+        )?;
+        let url = server_url(flags.take("--url").or_else(|| env("MYDB_URL")))?;
+        let max_width = parse_value(
+            flags
+                .take("--max-width")
+                .map(|v| ("--max-width", v))
+                .or_else(|| env("MYDB_MAX_WIDTH").map(|v| ("MYDB_MAX_WIDTH", v))),
+        )?
+        .unwrap_or(DEFAULT_MAX_WIDTH);
+        if max_width == 0 {
+            bail!("Max column width must be at least 1");
+        }
+        let format = flags
+            .take("--format")
+            .map(|v| ("--format", v))
+            .or_else(|| env("MYDB_FORMAT").map(|v| ("MYDB_FORMAT", v)))
+            .map(|(name, v)| {
+                Format::parse(&v, max_width).ok_or_else(|| {
+                    anyhow!(
+                        "invalid value {:?} for {}, expected table, plain, csv or json",
+                        v,
+                        name
+                    )
+                })
+            })
+            .transpose()?
+            .unwrap_or(Format::Table { max_width });
+        let file = flags.take("--file").map(PathBuf::from);
+        let single_transaction = parse_value(
+            flags
+                .take("--single-transaction")
+                .map(|v| ("--single-transaction", v)),
+        )?
+        .unwrap_or(false);
+        let command = flags.take("-c");
+        if file.is_some() && command.is_some() {
+            bail!("--file and -c cannot be given together");
+        }
+        if single_transaction && file.is_none() && command.is_none() {
+            bail!("--single-transaction needs statements to run with --file or -c");
+        }
+        let user = flags.take("--user").or_else(|| env("MYDB_USER"));
+        Ok(ShellArgs {
+            url,
+            max_width,
+            format,
+            file,
+            single_transaction,
+            command,
+            user,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpArgs {
+    pub url: String,
+    pub user: Option<String>,
+    // Tables to dump; all of them when empty.
+    pub tables: Vec<String>,
+    // Only the tables and indexes, without their rows.
+    pub schema_only: bool,
+}
+
+impl DumpArgs {
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_with_env(args, |key| std::env::var(key).ok())
+    }
+
+    // `[--url <url>] [--user <name>] [--table <name>]... [--schema-only]`,
+    // falling back to MYDB_URL and MYDB_USER.
+    pub fn parse_with_env(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut flags =
+            Flags::parse_with_switches(args, &["--url", "--user", "--table"], &["--schema-only"])?;
+        let url = server_url(flags.take("--url").or_else(|| env("MYDB_URL")))?;
+        let schema_only = parse_value(flags.take("--schema-only").map(|v| ("--schema-only", v)))?
+            .unwrap_or(false);
+        Ok(DumpArgs {
+            url,
+            user: flags.take("--user").or_else(|| env("MYDB_USER")),
+            tables: flags.take_all("--table"),
+            schema_only,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupArgs {
+    pub url: String,
+    pub user: Option<String>,
+    // Where the server puts the backup, on its own machine.
+    pub dir: PathBuf,
+}
+
+impl BackupArgs {
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_with_env(args, |key| std::env::var(key).ok())
+    }
+
+    // `--to <dir> [--url <url>] [--user <name>]`, falling back to MYDB_URL
+    // and MYDB_USER.
+    pub fn parse_with_env(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut flags = Flags::parse(args, &["--url", "--user", "--to"])?;
+        Ok(BackupArgs {
+            url: server_url(flags.take("--url").or_else(|| env("MYDB_URL")))?,
+            user: flags.take("--user").or_else(|| env("MYDB_USER")),
+            dir: flags
+                .take("--to")
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("--to is required: the directory to back up into"))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrateArgs {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    // The new database's; the old one's unless given.
+    pub page_size: Option<usize>,
+    // The old database's, which a data file from before `mydb init` does
+    // not say.
+    pub from_page_size: Option<usize>,
+}
+
+impl MigrateArgs {
+    // `--from <dir> --to <dir> [--page-size <n>] [--from-page-size <n>]`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut flags = Flags::parse(args, &["--from", "--to", "--page-size", "--from-page-size"])?;
+        let mut dir = |flag: &str, what: &str| {
+            flags
+                .take(flag)
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("{} is required: {}", flag, what))
+        };
+        let from = dir("--from", "the data directory to migrate")?;
+        let to = dir("--to", "the directory to migrate it into")?;
+        if from == to {
+            bail!("--from and --to are both {:?}", from);
+        }
+        let page_size = parse_value(flags.take("--page-size").map(|v| ("--page-size", v)))?;
+        if let Some(page_size) = page_size {
+            validate_page_size(page_size)?;
+        }
+        let from_page_size = parse_value(
+            flags
+                .take("--from-page-size")
+                .map(|v| ("--from-page-size", v)),
+        )?;
+        Ok(MigrateArgs {
+            from,
+            to,
+            page_size,
+            from_page_size,
+        })
+    }
+}
+
+// The server a client subcommand talks to, by default the one a server
+// started without `--listen` is on.
+fn server_url(url: Option<String>) -> Result<String> {
+    let url = url.unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        bail!(
+            "Server URL must start with http:// or https://, got {:?}",
+            url
+        );
+    }
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+// `--flag value` pairs, checked against the flags a subcommand knows.
+struct Flags(Vec<(String, String)>);
+
+impl Flags {
+    fn parse(args: &[String], known: &[&str]) -> Result<Self> {
+        Self::parse_with_switches(args, known, &[])
+    }
+
+    // Like `parse`, with `switches` also known: flags that stand alone and
+    // read as `true`, unless given a value with `=`.
+    fn parse_with_switches(args: &[String], known: &[&str], switches: &[&str]) -> Result<Self> {
+        let mut flags = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let is_switch = switches.contains(&flag);
+            if !known.contains(&flag) && !is_switch {
+                bail!(
+                    "Unknown option {} (expected one of {})",
+                    flag,
+                    [known, switches].concat().join(", ")
+                );
+            }
+            let value = match value {
+                Some(value) => value,
+                None if is_switch => "true".to_string(),
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| anyhow!("{} needs a value", flag))?,
+            };
+            flags.push((flag.to_string(), value));
+        }
+        Ok(Flags(flags))
+    }
+
+    // The last occurrence wins, as with most command-line tools.
+    fn take(&mut self, flag: &str) -> Option<String> {
+        let value = self
+            .0
+            .iter()
+            .rev()
+            .find(|(f, _)| f == flag)
+            .map(|(_, v)| v.clone());
+        self.0.retain(|(f, _)| f != flag);
+        value
+    }
+
+    // Every occurrence, in order, for flags that may be repeated.
+    fn take_all(&mut self, flag: &str) -> Vec<String> {
+        let values = self
+            .0
+            .iter()
+            .filter(|(f, _)| f == flag)
+            .map(|(_, v)| v.clone())
+            .collect();
+        self.0.retain(|(f, _)| f != flag);
+        values
+    }
+}
+
+fn parse_value<T>(source: Option<(&str, String)>) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    source
+        .map(|(name, v)| {
+            v.parse()
+                .with_context(|| format!("invalid value {:?} for {}", v, name))
+        })
+        .transpose()
+}
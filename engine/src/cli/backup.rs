@@ -0,0 +1,19 @@
+use crate::cli::{args::BackupArgs, shell::connect_with_stored_login};
+use anyhow::Result;
+use std::io::Write;
+
+// Asks the server for a backup and says where it went. Like `dump`, it
+// needs a stored login.
+pub async fn run_backup(args: &BackupArgs, out: &mut impl Write) -> Result<()> {
+    let client = connect_with_stored_login(&args.url, args.user.clone()).await?;
+    let report = client.backup(&args.dir).await?;
+    writeln!(
+        out,
+        "Backed up to {} at LSN {} ({} files, {} bytes)",
+        report.dir.display(),
+        report.lsn,
+        report.files,
+        report.bytes
+    )?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use crate::storage::check::{CheckReport, check_file};
+use anyhow::{Context, Result, anyhow, bail};
+use std::{io::Write, path::PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckArgs {
+    pub file: PathBuf,
+    pub page_size: usize,
+    pub json: bool,
+}
+
+impl CheckArgs {
+    // `<data.db | data dir> [--page-size <n>] [--json]`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut file = None;
+        let mut page_size = 4096;
+        let mut json = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--page-size" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--page-size needs a value"))?;
+                    page_size = v
+                        .parse()
+                        .with_context(|| format!("invalid value {:?} for --page-size", v))?;
+                }
+                "--json" => json = true,
+                flag if flag.starts_with("--") => bail!("Unknown option {}", flag),
+                path if file.is_none() => file = Some(PathBuf::from(path)),
+                extra => bail!("Unexpected argument {:?}", extra),
+            }
+        }
+        let mut file = file.ok_or_else(|| {
+            anyhow!("Usage: check <data.db | data dir> [--page-size <n>] [--json]")
+        })?;
+        if file.is_dir() {
+            file.push("data.db");
+        }
+        if page_size == 0 {
+            bail!("Page size must be positive");
+        }
+        Ok(CheckArgs {
+            file,
+            page_size,
+            json,
+        })
+    }
+}
+
+// Checks the file and writes the report, one problem per line or as JSON.
+// The file is only read. Run it on a file no server has open: pages the
+// server has not written back yet would look stale.
+pub fn run_check(args: &CheckArgs, out: &mut impl Write) -> Result<CheckReport> {
+    let report = check_file(&args.file, args.page_size)?;
+    if args.json {
+        serde_json::to_writer_pretty(&mut *out, &report)?;
+    } else {
+        write!(out, "{}", report)?;
+    }
+    writeln!(out)?;
+    Ok(report)
+}
@@ -0,0 +1,152 @@
+use crate::net::client::SqlClient;
+use crate::query::lexer;
+use anyhow::Result;
+use rustyline::{
+    Context, Helper, completion::Completer, highlight::Highlighter, hint::Hinter,
+    validate::Validator,
+};
+use std::{borrow::Cow, collections::HashMap};
+
+// Words the parser knows, offered wherever a keyword could go.
+// The type names a column definition takes, on top of the lexer's keywords.
+const TYPE_NAMES: &[&str] = &["INT", "TEXT"];
+
+// Keywords a table name follows.
+const BEFORE_TABLE: &[&str] = &["FROM", "INTO", "TABLE", "ON", "ANALYZE"];
+
+// The server's tables and their columns, as last fetched.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaCache {
+    // By table name, in the catalog's upper case.
+    pub columns: HashMap<String, Vec<String>>,
+}
+
+impl SchemaCache {
+    // One request for the list and one per table for its columns.
+    pub async fn load(client: &SqlClient) -> Result<Self> {
+        let mut columns = HashMap::new();
+        for table in client.tables().await? {
+            // A table dropped in between is simply left out.
+            if let Some(schema) = client.table(&table.name).await? {
+                let names = schema.columns.into_iter().map(|c| c.name).collect();
+                columns.insert(table.name, names);
+            }
+        }
+        Ok(SchemaCache { columns })
+    }
+}
+
+// Completes keywords, table names and the columns of the tables the
+// statement names.
+#[derive(Debug, Default)]
+pub struct SqlHelper {
+    pub schema: SchemaCache,
+    // Lines of the statement typed so far, before the one being edited.
+    pub pending: String,
+    // Set while a password is typed: nothing is completed and every
+    // character shows as `*`.
+    pub masking: bool,
+}
+
+impl SqlHelper {
+    // Where the word under the cursor starts, and what it could become.
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| !c.is_alphanumeric() && c != '_')
+            .map_or(0, |i| i + 1);
+        let prefix = &before[start..];
+        if prefix.is_empty() {
+            return (pos, Vec::new());
+        }
+        // Right after FROM and the like only a table name fits.
+        let previous = format!("{}\n{}", self.pending, &before[..start]);
+        let wants_table = previous
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .rfind(|w| !w.is_empty())
+            .is_some_and(|w| BEFORE_TABLE.iter().any(|k| k.eq_ignore_ascii_case(w)));
+
+        let mut names: Vec<&str> = self.schema.columns.keys().map(String::as_str).collect();
+        if !wants_table {
+            names.extend(lexer::KEYWORDS.iter().map(|(kw, _)| *kw));
+            names.extend(TYPE_NAMES);
+            let statement = format!("{}\n{}", self.pending, line);
+            for table in named_tables(&statement) {
+                if let Some(columns) = self.schema.columns.get(&table) {
+                    names.extend(columns.iter().map(String::as_str));
+                }
+            }
+        }
+        let upper = prefix.to_ascii_uppercase();
+        // Identifiers are case-insensitive, so candidates follow the case
+        // the word was started in.
+        let lower = prefix.chars().any(|c| c.is_ascii_lowercase());
+        let mut found: Vec<String> = names
+            .into_iter()
+            .filter(|name| name.to_ascii_uppercase().starts_with(&upper))
+            .map(|name| {
+                if lower {
+                    name.to_ascii_lowercase()
+                } else {
+                    name.to_string()
+                }
+            })
+            .collect();
+        found.sort();
+        found.dedup();
+        (start, found)
+    }
+}
+
+// Tables the statement names after FROM, INTO and the like, wherever they
+// are in it, upper-cased as the catalog keeps them.
+fn named_tables(statement: &str) -> Vec<String> {
+    let words: Vec<String> = statement
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|w| !w.is_empty())
+        .map(str::to_ascii_uppercase)
+        .collect();
+    words
+        .windows(2)
+        .filter(|pair| BEFORE_TABLE.contains(&pair[0].as_str()))
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+impl Completer for SqlHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        if self.masking {
+            return Ok((pos, Vec::new()));
+        }
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for SqlHelper {
+    type Hint = String;
+}
+
+impl Highlighter for SqlHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if self.masking {
+            Cow::Owned("*".repeat(line.chars().count()))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        self.masking
+    }
+}
+
+impl Validator for SqlHelper {}
+
+impl Helper for SqlHelper {}
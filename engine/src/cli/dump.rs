@@ -0,0 +1,200 @@
+use crate::cli::{
+    args::DumpArgs,
+    shell::connect_with_stored_login,
+};
+use crate::net::{
+    client::{DbValue, SqlClient},
+    schema::{IndexSchema, TableSchema},
+};
+use crate::query::lexer::quote_identifier;
+use anyhow::{Result, anyhow, bail};
+use std::borrow::Cow;
+use std::io::{BufWriter, Write};
+
+// Rows per INSERT, and the text one may grow to before it is cut short
+// anyway, so that each stays far below the server's body limit.
+const ROWS_PER_INSERT: usize = 500;
+const MAX_INSERT_BYTES: usize = 256 * 1024;
+
+// Writes the dump to stdout. There is no one to prompt for a login, so it
+// has to be stored.
+pub async fn run_dump(args: &DumpArgs) -> Result<()> {
+    let client = connect_with_stored_login(&args.url, args.user.clone()).await?;
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    write_dump(&client, &args.tables, args.schema_only, &mut out).await?;
+    out.flush()?;
+    Ok(())
+}
+
+// Writes `tables`, or all of them, as SQL that recreates them: each table,
+// its rows, then its indexes, which are quicker built over the rows than
+// kept up as they go in. A table comes after those its foreign keys refer
+// to, so its rows find theirs already there. With `schema_only` no rows are
+// written.
+pub async fn write_dump(
+    client: &SqlClient,
+    tables: &[String],
+    schema_only: bool,
+    out: &mut impl Write,
+) -> Result<()> {
+    let names = match tables {
+        [] => client.tables().await?.into_iter().map(|t| t.name).collect(),
+        tables => tables.to_vec(),
+    };
+    let mut schemas = Vec::new();
+    for name in &names {
+        let schema = client
+            .table(name)
+            .await?
+            .ok_or_else(|| anyhow!("Table '{}' does not exist", name))?;
+        schemas.push(schema);
+    }
+    let schemas = parents_first(schemas);
+
+    writeln!(out, "-- mydb dump")?;
+    // Every table is read from the same snapshot.
+    client.query("BEGIN;").await?;
+    let dumped = async {
+        for schema in &schemas {
+            dump_table(client, schema, schema_only, out).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    let ended = client.query("ROLLBACK;").await;
+    dumped?;
+    ended?;
+    Ok(())
+}
+
+async fn dump_table(
+    client: &SqlClient,
+    schema: &TableSchema,
+    schema_only: bool,
+    out: &mut impl Write,
+) -> Result<()> {
+    let table = quote_identifier(&schema.name);
+    writeln!(out)?;
+    writeln!(out, "{}", create_table_sql(&table, schema))?;
+
+    if !schema_only {
+        let columns: Vec<Cow<str>> = schema
+            .columns
+            .iter()
+            .map(|c| quote_identifier(&c.name))
+            .collect();
+        dump_rows(client, &table, &columns, out).await?;
+    }
+
+    for index in &schema.indexes {
+        writeln!(
+            out,
+            "{}",
+            create_index_sql(index, &quote_identifier(&index.table))
+        )?;
+    }
+    Ok(())
+}
+
+// The `CREATE TABLE` that makes `schema` again, under the name `table`
+// already quoted.
+pub fn create_table_sql(table: &str, schema: &TableSchema) -> String {
+    let mut definitions: Vec<String> = schema
+        .columns
+        .iter()
+        .map(|c| {
+            let mut definition = format!("{} {}", quote_identifier(&c.name), c.data_type);
+            if let Some(collation) = &c.collation {
+                definition.push_str(&format!(" COLLATE {}", collation));
+            }
+            for key in schema.foreign_keys.iter().filter(|k| k.column == c.name) {
+                definition.push_str(&format!(
+                    " REFERENCES {}({}) ON DELETE {}",
+                    quote_identifier(&key.parent),
+                    quote_identifier(&key.parent_column),
+                    key.on_delete
+                ));
+            }
+            definition
+        })
+        .collect();
+    definitions.extend(
+        schema
+            .checks
+            .iter()
+            .map(|check| format!("CHECK ({})", check.condition)),
+    );
+    format!("CREATE TABLE {} ({});", table, definitions.join(", "))
+}
+
+// Likewise the `CREATE INDEX` for `index`, on `table`.
+pub fn create_index_sql(index: &IndexSchema, table: &str) -> String {
+    let indexed: Vec<Cow<str>> = index.columns.iter().map(|c| quote_identifier(c)).collect();
+    format!(
+        "CREATE INDEX {} ON {} ({}) USING {};",
+        quote_identifier(&index.name),
+        table,
+        indexed.join(", "),
+        index.kind.to_ascii_uppercase()
+    )
+}
+
+async fn dump_rows(
+    client: &SqlClient,
+    table: &str,
+    columns: &[Cow<'_, str>],
+    out: &mut impl Write,
+) -> Result<()> {
+    let select = format!("SELECT {} FROM {};", columns.join(", "), table);
+    let mut rows = client.query_stream(&select).await?;
+    let mut insert = String::new();
+    let mut rows_in_insert = 0;
+    while let Some(row) = rows.next_row().await? {
+        if rows_in_insert == 0 {
+            insert = format!("INSERT INTO {} ({}) VALUES\n", table, columns.join(", "));
+        } else {
+            insert.push_str(",\n");
+        }
+        let values = row.iter().map(literal).collect::<Result<Vec<_>>>()?;
+        insert.push_str(&format!("({})", values.join(", ")));
+        rows_in_insert += 1;
+        if rows_in_insert == ROWS_PER_INSERT || insert.len() >= MAX_INSERT_BYTES {
+            writeln!(out, "{};", insert)?;
+            rows_in_insert = 0;
+        }
+    }
+    if rows_in_insert > 0 {
+        writeln!(out, "{};", insert)?;
+    }
+    Ok(())
+}
+
+// Orders tables so each follows the ones its foreign keys refer to, keeping
+// the order they were given in otherwise. Keys to tables outside the dump,
+// or to the table itself, do not hold it back.
+pub fn parents_first(mut pending: Vec<TableSchema>) -> Vec<TableSchema> {
+    let mut ordered: Vec<TableSchema> = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let waiting = |schema: &TableSchema| {
+            schema.foreign_keys.iter().any(|key| {
+                key.parent != schema.name && pending.iter().any(|other| other.name == key.parent)
+            })
+        };
+        // Tables can only refer to ones created before them, so some table
+        // is always ready; were none, the rest go as they are.
+        let next = pending.iter().position(|s| !waiting(s)).unwrap_or(0);
+        ordered.push(pending.remove(next));
+    }
+    ordered
+}
+
+// A value as SQL reads it back.
+pub fn literal(value: &DbValue) -> Result<String> {
+    match value {
+        // Its magnitude is one past what a literal can hold.
+        DbValue::Int(i64::MIN) => bail!("{} cannot be written as SQL", i64::MIN),
+        DbValue::Int(i) => Ok(i.to_string()),
+        DbValue::Text(s) => Ok(format!("'{}'", s.replace('\'', "''"))),
+        DbValue::Null => bail!("NULL cannot be written as SQL"),
+    }
+}
@@ -0,0 +1,124 @@
+use crate::net::{
+    client::SqlClient,
+    csv_io::{CsvOptions, ImportProgress, ImportReport},
+};
+use crate::storage::atomic_file::{atomic_read, atomic_remove, atomic_write};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+// Rows the shell commits at a time, so the most an import that fails
+// partway has to do over.
+pub const IMPORT_BATCH_ROWS: usize = 10_000;
+
+// How far an import of a file got, kept beside the file until the import
+// finishes so a failed one can be picked up where it left off.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportState {
+    pub table: String,
+    // Line of the file the last committed batch ends on.
+    pub committed_line: u64,
+    // Rows committed by every attempt so far.
+    pub rows_imported: usize,
+}
+
+impl ImportState {
+    // `data.csv` keeps its state in `data.csv.import-state`.
+    pub fn path(file: &Path) -> PathBuf {
+        let mut name = file.as_os_str().to_owned();
+        name.push(".import-state");
+        PathBuf::from(name)
+    }
+
+    pub fn load(file: &Path) -> Result<Option<Self>> {
+        let path = Self::path(file);
+        let Some(bytes) = atomic_read(&path)? else {
+            return Ok(None);
+        };
+        let state =
+            serde_json::from_slice(&bytes).with_context(|| format!("Bad state in {:?}", path))?;
+        Ok(Some(state))
+    }
+
+    // Written whole and renamed into place, so a crash leaves the old state
+    // rather than half of the new one.
+    pub fn save(&self, file: &Path) -> Result<()> {
+        let path = Self::path(file);
+        atomic_write(&path, serde_json::to_string(self)?.as_bytes())
+            .with_context(|| format!("Failed to write {:?}", path))
+    }
+}
+
+// Loads the CSV file at `path` into `table`, creating it if need be, in
+// batches of `IMPORT_BATCH_ROWS`. With `resume`, rows an earlier import of
+// the file committed are passed over. `on_progress` hears how far this
+// attempt has got. Returns the report and the rows earlier attempts added.
+pub async fn import_file(
+    client: &SqlClient,
+    table: &str,
+    path: &Path,
+    resume: bool,
+    mut on_progress: impl FnMut(&ImportProgress),
+) -> Result<(ImportReport, usize)> {
+    let earlier = match (resume, ImportState::load(path)?) {
+        (true, Some(state)) if !state.table.eq_ignore_ascii_case(table) => bail!(
+            "The unfinished import of {} was into {}, not {}",
+            path.display(),
+            state.table,
+            table
+        ),
+        (true, Some(state)) => Some(state),
+        (true, None) => bail!(
+            "There is no unfinished import of {} to resume",
+            path.display()
+        ),
+        (false, _) => None,
+    };
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    let options = CsvOptions {
+        create: true,
+        batch_rows: Some(IMPORT_BATCH_ROWS),
+        resume_after: earlier.as_ref().map(|state| state.committed_line),
+        ..CsvOptions::default()
+    };
+    let rows_before = earlier.as_ref().map_or(0, |state| state.rows_imported);
+    let mut saved = earlier.map(|state| state.committed_line);
+    let mut save_error = None;
+    let imported = client
+        .import_csv_with_progress(table, file, options, |progress| {
+            if let Some(line) = progress.committed_line
+                && saved != Some(line)
+            {
+                let state = ImportState {
+                    table: table.to_string(),
+                    committed_line: line,
+                    rows_imported: rows_before + progress.rows_imported,
+                };
+                match state.save(path) {
+                    Ok(()) => saved = Some(line),
+                    Err(e) => save_error = Some(e),
+                }
+            }
+            on_progress(progress);
+        })
+        .await;
+    match imported {
+        Ok(report) => {
+            atomic_remove(&ImportState::path(path))
+                .context("Imported, but failed to remove its state")?;
+            Ok((report, rows_before))
+        }
+        Err(e) => match (save_error, saved) {
+            (Some(save_error), _) => {
+                Err(e.context(format!("resuming is not possible: {:#}", save_error)))
+            }
+            (None, Some(line)) => Err(e.context(format!(
+                "rows up to line {} are in; add --resume to carry on from there",
+                line
+            ))),
+            (None, None) => Err(e),
+        },
+    }
+}
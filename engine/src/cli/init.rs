@@ -0,0 +1,85 @@
+use crate::cli::args::InitArgs;
+use crate::net::auth::{BOOTSTRAP_ADMIN, Secret, UserStore};
+use crate::storage::atomic_file::atomic_remove;
+use crate::storage::backup::catalog_path;
+use crate::storage::format::create_data_file;
+use crate::tx::log_manager::{LogManager, Manifest, MasterRecord, segment_path};
+use anyhow::{Context, Result, bail};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+// Creates the data file, the WAL and, with `--admin`, the admin account,
+// whose password is `admin_password` or one made up and shown once. A
+// directory that already has files in it is left alone unless `--force`
+// is given; then the database in it, WAL, accounts and catalog included, is
+// replaced.
+pub fn run_init(
+    args: &InitArgs,
+    admin_password: Option<Secret>,
+    out: &mut impl Write,
+) -> Result<()> {
+    fs::create_dir_all(&args.data_dir)
+        .with_context(|| format!("Failed to create data directory {:?}", args.data_dir))?;
+    let in_use = fs::read_dir(&args.data_dir)?.next().is_some()
+        || Manifest::path(&args.wal).exists();
+    if in_use {
+        if !args.force {
+            bail!(
+                "{:?} is not empty; pass --force to replace the database in it",
+                args.data_dir
+            );
+        }
+        remove_wal(&args.wal)?;
+    }
+
+    let data_file = args.data_file();
+    create_data_file(&data_file, args.page_size)?;
+    writeln!(
+        out,
+        "Created {} with {}-byte pages",
+        data_file.display(),
+        args.page_size
+    )?;
+    drop(LogManager::new(args.wal.clone()).context("Failed to create the WAL")?);
+    writeln!(out, "Created the WAL at {}", args.wal.display())?;
+
+    if args.admin {
+        let users = UserStore::open(UserStore::path(&args.wal))?;
+        match users.bootstrap(admin_password)? {
+            Some(password) => writeln!(
+                out,
+                "Created user '{}' with password {}",
+                BOOTSTRAP_ADMIN, password.0
+            )?,
+            None => writeln!(out, "Created user '{}'", BOOTSTRAP_ADMIN)?,
+        }
+    }
+    Ok(())
+}
+
+// The segments, manifest, master record, accounts and catalog kept next to
+// the WAL, the last four with their previous generations.
+fn remove_wal(wal: &Path) -> Result<()> {
+    let mut segments = Vec::new();
+    if let Some(manifest) = Manifest::read(wal)? {
+        segments.extend(manifest.segments().map(|segment| segment_path(wal, segment)));
+    }
+    for path in segments {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to remove {:?}", path));
+            }
+            _ => {}
+        }
+    }
+    for path in [
+        Manifest::path(wal),
+        MasterRecord::path(wal),
+        UserStore::path(wal),
+        catalog_path(wal),
+    ] {
+        atomic_remove(&path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,406 @@
+use crate::cli::args::{InitArgs, MigrateArgs};
+use crate::cli::dump::{create_index_sql, create_table_sql, parents_first};
+use crate::cli::init::run_init;
+use crate::database::{Database, DatabaseConfig};
+use crate::net::client::DbValue;
+use crate::net::schema::{self, TableSchema};
+use crate::query::lexer::quote_identifier;
+use crate::storage::atomic_file::{atomic_read, atomic_remove, atomic_write};
+use crate::storage::backup::{DATA_FILE, WAL_FILE};
+use crate::storage::format::FileHeader;
+use crate::storage::legacy::LegacyDatabase;
+use crate::storage::sequence::Sequence;
+use crate::storage::storage::{DEFAULT_SCHEMA, split_name};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Rows per INSERT.
+const ROWS_PER_INSERT: usize = 200;
+// About how many rows of each table are compared with the old database's
+// once it is copied, spread evenly over the table.
+const SAMPLED_ROWS: usize = 100;
+const POOL_PAGES: usize = 64;
+
+// Kept in the new directory until the migration is done, so a run that
+// stopped part way can pick up where it was.
+const PROGRESS_FILE: &str = "migrate.progress";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Progress {
+    from: PathBuf,
+    // Tables whose rows and indexes are committed and in the saved catalog.
+    tables: Vec<String>,
+}
+
+// What `run_migrate` did with a table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigratedTable {
+    pub name: String,
+    pub rows: usize,
+    // Of those, the ones compared value for value with the old database's.
+    pub sampled: usize,
+    // Copied by an earlier run that stopped before the end.
+    pub resumed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrateReport {
+    // The old database's format version and page size.
+    pub version: u32,
+    pub page_size: usize,
+    pub tables: Vec<MigratedTable>,
+    // Views and grants, which are not carried over.
+    pub left_out: Vec<String>,
+}
+
+// Copies the database in `args.from`, which is only read, into a new one
+// in `args.to`: each sequence is made to go on where the old one was,
+// then each table is created, filled and indexed and saved as done. Once every table is, the row counts and a sample of each table's
+// rows are checked against the old database's.
+pub fn run_migrate(args: &MigrateArgs, out: &mut impl Write) -> Result<MigrateReport> {
+    let mut source = LegacyDatabase::open(&args.from, args.from_page_size)?;
+    writeln!(
+        out,
+        "Reading {} (format version {}, {}-byte pages)",
+        args.from.display(),
+        source.version,
+        source.page_size
+    )?;
+    let (mut progress, page_size) = start(args, source.page_size, out)?;
+    let mut db = Database::open(DatabaseConfig {
+        page_size,
+        pool_size: POOL_PAGES,
+        ..DatabaseConfig::new(&args.to)
+    })?;
+
+    // A table created by a run that stopped before saving it is made again
+    // from the start.
+    let unfinished: Vec<String> = db
+        .catalog()
+        .tables
+        .values()
+        .map(|t| t.name.clone())
+        .filter(|name| !progress.tables.contains(name))
+        .collect();
+    for name in unfinished {
+        db.execute(&format!("DROP TABLE {};", quote_name(&name)))?;
+    }
+    for name in &source.catalog().schemas {
+        if !db.catalog().schemas.contains(name) {
+            db.execute(&format!("CREATE SCHEMA {};", quote_identifier(name)))?;
+        }
+    }
+
+    for sequence in source.catalog().sequences.values() {
+        if !db.catalog().sequences.contains_key(&sequence.name) {
+            let (sql, next) = create_sequence_sql(sequence)?;
+            db.execute(&sql)?;
+            writeln!(
+                out,
+                "Copied sequence {}: next value {}",
+                sequence.name, next
+            )?;
+        }
+    }
+
+    let catalog = source.catalog();
+    let mut keys: Vec<&String> = catalog.tables.keys().collect();
+    keys.sort();
+    let tables = parents_first(
+        keys.into_iter()
+            .filter_map(|key| schema::table(catalog, key))
+            .collect(),
+    );
+    let left_out = left_out(&source);
+    let resumed = progress.tables.clone();
+    for table in &tables {
+        if progress.tables.contains(&table.name) {
+            continue;
+        }
+        let rows = copy_table(&mut db, &mut source, table)?;
+        db.checkpoint()?;
+        progress.tables.push(table.name.clone());
+        save_progress(&args.to, &progress)?;
+        writeln!(
+            out,
+            "Copied {}: {} rows, {} indexes",
+            table.name,
+            rows,
+            table.indexes.len()
+        )?;
+    }
+
+    let mut report = MigrateReport {
+        version: source.version,
+        page_size: source.page_size,
+        tables: Vec::with_capacity(tables.len()),
+        left_out,
+    };
+    for table in &tables {
+        let (rows, sampled) = verify_table(&mut db, &mut source, table)?;
+        writeln!(
+            out,
+            "Verified {}: {} rows, {} sampled",
+            table.name, rows, sampled
+        )?;
+        report.tables.push(MigratedTable {
+            name: table.name.clone(),
+            rows,
+            sampled,
+            resumed: resumed.contains(&table.name),
+        });
+    }
+    for name in &report.left_out {
+        writeln!(out, "Not migrated: {}", name)?;
+    }
+    db.close()?;
+    atomic_remove(&args.to.join(PROGRESS_FILE))?;
+    writeln!(
+        out,
+        "Migrated {} tables into {} with {}-byte pages",
+        report.tables.len(),
+        args.to.display(),
+        page_size
+    )?;
+    Ok(report)
+}
+
+// Initializes `args.to` for a new migration, or finds the one under way
+// there. Returns the progress so far and the new database's page size.
+fn start(
+    args: &MigrateArgs,
+    source_page_size: usize,
+    out: &mut impl Write,
+) -> Result<(Progress, usize)> {
+    let path = args.to.join(PROGRESS_FILE);
+    if let Some(bytes) = atomic_read(&path)? {
+        let progress: Progress =
+            serde_json::from_slice(&bytes).with_context(|| format!("Malformed {:?}", path))?;
+        if progress.from != args.from {
+            bail!(
+                "{:?} holds a migration from {:?}, not {:?}",
+                args.to,
+                progress.from,
+                args.from
+            );
+        }
+        let header = FileHeader::read(&args.to.join(DATA_FILE))?
+            .with_context(|| format!("No data file in {:?}", args.to))?;
+        if let Some(page_size) = args.page_size.filter(|&p| p != header.page_size) {
+            bail!(
+                "The migration into {:?} was started with {}-byte pages, not {}",
+                args.to,
+                header.page_size,
+                page_size
+            );
+        }
+        writeln!(
+            out,
+            "Resuming: {} tables were copied before",
+            progress.tables.len()
+        )?;
+        return Ok((progress, header.page_size));
+    }
+
+    if args.to.exists() && fs::read_dir(&args.to)?.next().is_some() {
+        bail!("{:?} is not empty; migrate into a new directory", args.to);
+    }
+    let page_size = args.page_size.unwrap_or(source_page_size);
+    let init = InitArgs {
+        data_dir: args.to.clone(),
+        page_size,
+        wal: args.to.join(WAL_FILE),
+        force: false,
+        admin: false,
+    };
+    run_init(&init, None, out)?;
+    let progress = Progress {
+        from: args.from.clone(),
+        tables: Vec::new(),
+    };
+    save_progress(&args.to, &progress)?;
+    Ok((progress, page_size))
+}
+
+fn save_progress(dir: &Path, progress: &Progress) -> Result<()> {
+    atomic_write(&dir.join(PROGRESS_FILE), &serde_json::to_vec(progress)?)
+}
+
+// Creates the table, copies its rows in one transaction and then builds
+// its indexes over them. Returns the rows copied.
+fn copy_table(
+    db: &mut Database,
+    source: &mut LegacyDatabase,
+    table: &TableSchema,
+) -> Result<usize> {
+    let name = quote_name(&table.name);
+    db.execute(&create_table_sql(&name, table))?;
+    let columns: Vec<Cow<str>> = table
+        .columns
+        .iter()
+        .map(|c| quote_identifier(&c.name))
+        .collect();
+    let row = format!("({})", vec!["?"; columns.len()].join(", "));
+
+    db.begin()?;
+    let copied = (|| {
+        let mut rows = 0;
+        for records in source.records(&table.name)?.chunks(ROWS_PER_INSERT) {
+            let mut values = Vec::new();
+            let mut batch = 0;
+            for &rid in records {
+                if let Some(row) = source.row(rid)? {
+                    values.extend(row);
+                    batch += 1;
+                }
+            }
+            if batch == 0 {
+                continue;
+            }
+            let insert = format!(
+                "INSERT INTO {} ({}) VALUES {};",
+                name,
+                columns.join(", "),
+                vec![row.as_str(); batch].join(", ")
+            );
+            db.execute_with(&insert, &values)?;
+            rows += batch;
+        }
+        Ok::<_, anyhow::Error>(rows)
+    })();
+    let rows = match copied {
+        Ok(rows) => rows,
+        Err(e) => {
+            let _ = db.rollback();
+            return Err(e.context(format!("Failed to copy the rows of {}", table.name)));
+        }
+    };
+    db.commit()?;
+
+    for index in &table.indexes {
+        db.execute(&create_index_sql(index, &name))?;
+    }
+    Ok(rows)
+}
+
+// Checks the new table has as many rows as the old one, and that every
+// so many of them hold the same values in the same order. Returns the
+// rows and how many of them were compared.
+fn verify_table(
+    db: &mut Database,
+    source: &mut LegacyDatabase,
+    table: &TableSchema,
+) -> Result<(usize, usize)> {
+    let records = source.records(&table.name)?;
+    let every = (records.len() / SAMPLED_ROWS).max(1);
+    let mut expected = crc32fast::Hasher::new();
+    let mut rows = 0;
+    for rid in records {
+        if let Some(row) = source.row(rid)? {
+            if rows % every == 0 {
+                hash_row(&mut expected, row.into_iter().map(DbValue::from));
+            }
+            rows += 1;
+        }
+    }
+
+    let mut found = crc32fast::Hasher::new();
+    let mut copied = 0;
+    let mut sampled = 0;
+    db.for_each_row(&table.name, |row| {
+        if copied % every == 0 {
+            hash_row(&mut found, row.into_iter().map(DbValue::from));
+            sampled += 1;
+        }
+        copied += 1;
+        Ok(())
+    })?;
+    if copied != rows {
+        bail!(
+            "{} has {} rows, but {} in the old database",
+            table.name,
+            copied,
+            rows
+        );
+    }
+    if found.finalize() != expected.finalize() {
+        bail!(
+            "The sampled rows of {} differ from the old database's",
+            table.name
+        );
+    }
+    Ok((rows, sampled))
+}
+
+// Each value with its type, and a string with its length, so that no two
+// different rows run together into the same bytes.
+fn hash_row(hasher: &mut crc32fast::Hasher, row: impl Iterator<Item = DbValue>) {
+    for value in row {
+        match value {
+            DbValue::Null => hasher.update(&[0]),
+            DbValue::Int(i) => {
+                hasher.update(&[1]);
+                hasher.update(&i.to_le_bytes());
+            }
+            DbValue::Text(s) => {
+                hasher.update(&[2]);
+                hasher.update(&(s.len() as u64).to_le_bytes());
+                hasher.update(s.as_bytes());
+            }
+        }
+    }
+}
+
+// A catalog key as SQL names it: `<schema>.<name>` outside the default
+// schema, each part quoted as it needs.
+fn quote_name(key: &str) -> String {
+    match split_name(key) {
+        (DEFAULT_SCHEMA, name) => quote_identifier(name).into_owned(),
+        (schema, name) => format!("{}.{}", quote_identifier(schema), quote_identifier(name)),
+    }
+}
+
+// The `CREATE SEQUENCE` that carries `sequence` on from the value it would
+// hand out next, and that value. The old database may have handed out any
+// value up to its reservation, so it starts after that.
+fn create_sequence_sql(sequence: &Sequence) -> Result<(String, i64)> {
+    let info = sequence.info();
+    let next = match info.reserved {
+        None => Some(info.start),
+        Some(end) => end.checked_add(info.increment),
+    }
+    .with_context(|| format!("Sequence {} has run out of values", info.name))?;
+    let sql = format!(
+        "CREATE SEQUENCE {} START {} INCREMENT {};",
+        quote_name(&info.name),
+        next,
+        info.increment
+    );
+    Ok((sql, next))
+}
+
+fn left_out(source: &LegacyDatabase) -> Vec<String> {
+    let catalog = source.catalog();
+    let mut left_out: Vec<String> = catalog
+        .views
+        .keys()
+        .map(|name| format!("view {}", name))
+        .collect();
+    let mut granted: Vec<&String> = catalog
+        .grants
+        .iter()
+        .filter(|(_, users)| !users.is_empty())
+        .map(|(table, _)| table)
+        .collect();
+    granted.sort();
+    left_out.extend(
+        granted
+            .into_iter()
+            .map(|table| format!("grants on {}", table)),
+    );
+    left_out
+}
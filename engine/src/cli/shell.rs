@@ -1,35 +1,1140 @@
 
-use crate::net::client::SqlClient;
-use anyhow::Result;
-use rustyline::{Editor, error::ReadlineError};
-
-pub async fn run_shell(base_url: &str) -> Result<()> {
-    let client = SqlClient::new(base_url);
-    
-    println!("Username: ");
-    let mut rl = Editor::<()>::new()?;
-    let user = rl.readline("user> ")?;
-    let pass = rl.readline("pass> ")?;
-    client.login(&user, &pass).await?;
+use crate::cli::{
+    args::ShellArgs,
+    completion::{SchemaCache, SqlHelper},
+    import::import_file,
+};
+use crate::net::{
+    auth::Secret,
+    client::{DbError, DbValue, QueryResult, QueryStats, SqlClient},
+    csv_io::{ImportProgress, ImportReport},
+    row::Schema,
+    schema::TableSchema,
+    session::TxState,
+};
+use crate::query::parser::Parser;
+use anyhow::{Context, Result, anyhow, bail};
+use rustyline::{ColorMode, Config, Editor, config::Configurer, error::ReadlineError};
+use std::{
+    fmt,
+    fs::File,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+// How `mydb shell` exits when running statements given up front: a failed
+// statement is told apart from not reaching the server at all.
+pub const EXIT_SQL_ERROR: i32 = 1;
+pub const EXIT_CONNECTION_ERROR: i32 = 2;
+
+// Rows shown at a time while a result is still arriving.
+const ROWS_PER_PAGE: usize = 500;
+
+// A server that does not answer in this long is taken to be down.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Read for the password when no prompt should be shown.
+pub const PASSWORD_ENV: &str = "MYDB_PASSWORD";
+
+// Looked for in the home directory for logins that need no typing.
+pub const PASSWORD_FILE: &str = ".mydbpass";
+
+// Widest a table cell prints by default; longer values end in `…`.
+pub const DEFAULT_MAX_WIDTH: usize = 40;
+
+const HELP: &str = "\
+\\dt             list tables
+\\d [table]      describe a table, or list tables
+\\c [schema]     look for tables in another schema, or show where they are looked for
+\\i <file>       run the statements in a file
+\\import <table> <file> [--resume]
+                load a CSV file into a table, or carry on with one that failed
+\\timing         turn printing how long each statement took, and what it cost, on or off
+\\format [name]  print results as table, plain, csv or json
+\\o [file]       write results to a file, or back to the terminal
+\\q              quit
+\\help           show this list
+";
+
+pub async fn run_shell(args: &ShellArgs) -> Result<()> {
+    let client = SqlClient::builder(&args.url)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()?;
+    let mut settings = Settings {
+        format: args.format,
+        max_width: args.max_width,
+        timing: true,
+        output: None,
+    };
+
+    // Statements given up front, or piped in, run without prompts or a
+    // banner, and the shell exits with how they went.
+    let stdin_is_terminal = std::io::stdin().is_terminal();
+    let one_shot = args.command.is_some() || !stdin_is_terminal;
+
+    // Lines are only added to history by hand, so the login never is.
+    let config = Config::builder().auto_add_history(false).build();
+    let mut rl = Editor::<SqlHelper>::with_config(config)?;
+    rl.set_helper(Some(SqlHelper::default()));
+    let env = |key: &str| std::env::var(key).ok();
+    let (user, password) = stored_login(&args.url, args.user.clone(), env).context(LoginFailed)?;
+    // A prompt would read the piped statements as the login.
+    let user = match user {
+        Some(user) => user,
+        None if stdin_is_terminal => rl.readline("user> ")?,
+        None => {
+            return Err(anyhow!("no user given; pass --user or set MYDB_USER"))
+                .context(LoginFailed);
+        }
+    };
+    let password = match password {
+        Some(password) => password,
+        None if stdin_is_terminal => read_password(&mut rl)?,
+        None => {
+            return Err(anyhow!(
+                "no password given; set {} or add it to ~/{}",
+                PASSWORD_ENV,
+                PASSWORD_FILE
+            ))
+            .context(LoginFailed);
+        }
+    };
+    client
+        .login(&user, &password.0)
+        .await
+        .context(LoginFailed)?;
+
+    // A script runs on its own; the shell exits with its outcome.
+    if let Some(file) = &args.file {
+        return run_file(&client, file, args.single_transaction, &mut settings).await;
+    }
+    if one_shot {
+        // Only the results go to stdout, so they can be parsed.
+        settings.timing = false;
+        let (name, script) = match &args.command {
+            Some(sql) => (None, sql.clone()),
+            None => (
+                Some("stdin"),
+                std::io::read_to_string(std::io::stdin()).context("Failed to read stdin")?,
+            ),
+        };
+        run_script(
+            &client,
+            name,
+            &script,
+            args.single_transaction,
+            &mut settings,
+        )
+        .await?;
+        return Ok(());
+    }
 
     println!("Welcome to SQL-CLI. Type SQL statements ending with ‘;’");
+    let mut pending = StatementBuffer::new();
+    let mut schema_changed = true;
     loop {
-        match rl.readline("sql> ") {
-            Ok(line) if line.trim().eq_ignore_ascii_case("exit") => break,
-            Ok(sql) => match client.query(&sql).await {
-                Ok(rows) => {
-                    for row in rows {
-                        println!("{}", row.join(" | "));
+        let helper = rl.helper_mut().expect("helper is set");
+        if schema_changed {
+            // Completion makes do with what it had if this fails.
+            if let Ok(schema) = SchemaCache::load(&client).await {
+                helper.schema = schema;
+            }
+            schema_changed = false;
+        }
+        helper.pending = pending.text().to_string();
+        let prompt = match pending.is_empty() {
+            true => prompt(
+                &user,
+                &args.url,
+                client.search_path().as_deref(),
+                client.tx_state(),
+            ),
+            false => "...> ".to_string(),
+        };
+        let read = rl.readline(&prompt);
+        if let Ok(line) = &read
+            && !line.trim().is_empty()
+        {
+            rl.add_history_entry(line.as_str());
+        }
+        match read {
+            Ok(line) if pending.is_empty() && line.trim().eq_ignore_ascii_case("exit") => break,
+            // Meta commands are the shell's own and never reach the server.
+            Ok(line) if pending.is_empty() && line.trim_start().starts_with('\\') => {
+                match MetaCommand::parse(&line) {
+                    Ok(MetaCommand::Quit) => break,
+                    Ok(MetaCommand::Include(path)) => {
+                        if let Err(e) = run_file(&client, &path, false, &mut settings).await {
+                            eprintln!("Error: {:#}", e);
+                        }
+                        schema_changed = true;
+                    }
+                    Ok(MetaCommand::Import {
+                        table,
+                        path,
+                        resume,
+                    }) => {
+                        let imported =
+                            import_file(&client, &table, &path, resume, show_progress).await;
+                        end_progress();
+                        match imported {
+                            Ok((report, earlier)) => print!("{}", import_summary(&report, earlier)),
+                            Err(e) => eprintln!("Error: {:#}", e),
+                        }
+                        // The import may have created the table.
+                        schema_changed = true;
+                    }
+                    Ok(command @ MetaCommand::Connect(_)) => {
+                        run_meta(&client, command, &mut settings).await;
+                        schema_changed = true;
                     }
+                    Ok(command) => run_meta(&client, command, &mut settings).await,
+                    Err(e) => eprintln!("Error: {}", e),
                 }
-                Err(e) => println!("Error: {:?}", e),
-            },
-            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            }
+            Ok(line) => {
+                for sql in pending.push_line(&line) {
+                    run_statement(&client, &sql, &mut settings).await;
+                    schema_changed |= changes_tables(&sql);
+                }
+            }
+            // Ctrl-C throws away a statement half typed; Ctrl-D leaves.
+            Err(ReadlineError::Interrupted) => pending.clear(),
+            Err(ReadlineError::Eof) => break,
             Err(err) => {
-                println!("Error: {:?}", err);
+                eprintln!("Error: {:?}", err);
                 break;
             }
         }
     }
     Ok(())
 }
+
+// Marks errors from getting logged in, which exit with
+// EXIT_CONNECTION_ERROR.
+#[derive(Debug)]
+pub struct LoginFailed;
+
+impl fmt::Display for LoginFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Logging in failed")
+    }
+}
+
+// The exit code for an error the shell stopped on.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    let unreachable = err.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_connect() || e.is_timeout())
+            || matches!(e.downcast_ref::<DbError>(), Some(DbError::Unauthorized(_)))
+    });
+    if unreachable || err.downcast_ref::<LoginFailed>().is_some() {
+        EXIT_CONNECTION_ERROR
+    } else {
+        EXIT_SQL_ERROR
+    }
+}
+
+// Prompts with every typed character shown as `*`.
+fn read_password(rl: &mut Editor<SqlHelper>) -> Result<Secret> {
+    rl.helper_mut().expect("helper is set").masking = true;
+    // The mask is drawn as highlighting, which has to stay on even where
+    // the terminal would otherwise go without colour.
+    rl.set_color_mode(ColorMode::Forced);
+    let read = rl.readline("pass> ");
+    rl.set_color_mode(ColorMode::Enabled);
+    rl.helper_mut().expect("helper is set").masking = false;
+    Ok(Secret(read?))
+}
+
+// The user and password to log in with before anything is prompted for.
+// The user is `user` when given; the password comes from MYDB_PASSWORD,
+// else from the first line of ~/.mydbpass that matches the server and user,
+// which can also supply the user.
+pub fn stored_login(
+    url: &str,
+    user: Option<String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<(Option<String>, Option<Secret>)> {
+    if let Some(password) = env(PASSWORD_ENV) {
+        return Ok((user, Some(Secret(password))));
+    }
+    let Some(home) = env("HOME") else {
+        return Ok((user, None));
+    };
+    let path = Path::new(&home).join(PASSWORD_FILE);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((user, None)),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    check_private(&path)?;
+    match find_password(&text, url, user.as_deref()) {
+        Some((found, password)) => Ok((user.or(found), Some(password))),
+        None => Ok((user, None)),
+    }
+}
+
+// A client logged in with the stored login, for subcommands that have no
+// one to prompt.
+pub async fn connect_with_stored_login(url: &str, user: Option<String>) -> Result<SqlClient> {
+    let client = SqlClient::builder(url)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()?;
+    let env = |key: &str| std::env::var(key).ok();
+    let (user, password) = stored_login(url, user, env).context(LoginFailed)?;
+    let (Some(user), Some(password)) = (user, password) else {
+        return Err(anyhow!(
+            "no login given; pass --user or set MYDB_USER, and set {} or add it to ~/{}",
+            PASSWORD_ENV,
+            PASSWORD_FILE
+        ))
+        .context(LoginFailed);
+    };
+    client
+        .login(&user, &password.0)
+        .await
+        .context(LoginFailed)?;
+    Ok(client)
+}
+
+// Like ssh with its keys, passwords others can read are refused.
+#[cfg(unix)]
+fn check_private(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "{} can be read by others (mode {:o}); restrict it with chmod 600",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Lines are `<server url> <user> <password>`, where `*` stands for any
+// server or user and the password runs to the end of the line. Blank lines
+// and lines starting with `#` are skipped. A `*` user only matches when the
+// user is already known.
+pub fn find_password(
+    text: &str,
+    url: &str,
+    user: Option<&str>,
+) -> Option<(Option<String>, Secret)> {
+    text.lines().find_map(|line| {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (server, rest) = line.split_once(char::is_whitespace)?;
+        let (name, password) = rest.trim_start().split_once(char::is_whitespace)?;
+        let password = password.trim();
+        if password.is_empty() || (server != "*" && server.trim_end_matches('/') != url) {
+            return None;
+        }
+        match (name, user) {
+            ("*", Some(_)) => Some((None, Secret(password.to_string()))),
+            ("*", None) => None,
+            (name, Some(user)) if name != user => None,
+            (name, _) => Some((Some(name.to_string()), Secret(password.to_string()))),
+        }
+    })
+}
+
+// The shell's prompt: who it is logged in as, where, and the schema that
+// unqualified names are looked for in first, once the server has said.
+// `sql*>` is inside BEGIN ... COMMIT and `sql!>` in a transaction a failed
+// statement aborted, which only ROLLBACK ends.
+pub fn prompt(user: &str, url: &str, search_path: Option<&[String]>, tx: TxState) -> String {
+    let server = url
+        .trim_start_matches("http://")
+        .trim_start_matches("https://")
+        .trim_end_matches('/');
+    let sql = match tx {
+        TxState::Idle => "sql>",
+        TxState::Open(_) => "sql*>",
+        TxState::Aborted(_) => "sql!>",
+    };
+    let at = match search_path.and_then(<[String]>::first) {
+        Some(schema) => format!("{}@{}/{}", user, server, schema.to_ascii_lowercase()),
+        None => format!("{}@{}", user, server),
+    };
+    format!("{} {} ", at, sql)
+}
+
+// A statement the server turns away for want of a session, because it was
+// restarted say, never ran. The shell logs in again, with the search path
+// it had, and sends it once more.
+async fn run_statement(client: &SqlClient, sql: &str, settings: &mut Settings) {
+    let mut ran = stream_statement(client, sql, settings).await;
+    if let Err(e) = &ran
+        && matches!(e.downcast_ref::<DbError>(), Some(DbError::Unauthorized(_)))
+        && client.reconnect().await.is_ok()
+    {
+        eprintln!("Session lost; logged in again.");
+        ran = stream_statement(client, sql, settings).await;
+    }
+    if let Err(e) = ran {
+        eprintln!("Error: {:?}", e);
+    }
+}
+
+// Shows the result a page at a time as its rows arrive, so the start of a
+// long one is on screen well before the end.
+async fn stream_statement(client: &SqlClient, sql: &str, settings: &mut Settings) -> Result<()> {
+    let started = Instant::now();
+    let mut rows = client.query_stream(sql).await?;
+    let mut renderer = PageRenderer::new(settings.result_format());
+    let mut page = QueryResult {
+        schema: rows.schema().clone(),
+        ..QueryResult::default()
+    };
+    let mut shown = false;
+    while let Some(row) = rows.next_row().await? {
+        page.rows.push(row);
+        if page.rows.len() == ROWS_PER_PAGE {
+            settings.write(&renderer.page(&page))?;
+            page.rows.clear();
+            shown = true;
+        }
+    }
+    if !shown || !page.rows.is_empty() {
+        settings.write(&renderer.page(&page))?;
+    }
+    page.affected = rows.affected();
+    let elapsed = settings.timing.then(|| started.elapsed());
+    settings.write(&renderer.finish(&page, elapsed))?;
+    match rows.stats() {
+        Some(stats) if settings.timing => settings.write(&stats_line(&stats)),
+        _ => Ok(()),
+    }
+}
+
+// What the server said a statement cost, printed under its result while
+// timing is on.
+pub fn stats_line(stats: &QueryStats) -> String {
+    format!(
+        "Server: {} ms, {} {}, {} pages read, {} cached, {} ms waiting for locks\n",
+        stats.millis,
+        stats.rows,
+        if stats.rows == 1 { "row" } else { "rows" },
+        stats.pages_read,
+        stats.pages_cached,
+        stats.lock_wait_millis
+    )
+}
+
+// Whether `sql` adds or drops a table, which completion has to learn of.
+fn changes_tables(sql: &str) -> bool {
+    let mut words = sql.split_whitespace().map(str::to_ascii_uppercase);
+    matches!(words.next().as_deref(), Some("CREATE" | "DROP"))
+        && words.next().as_deref() == Some("TABLE")
+}
+
+// What the meta commands can change.
+struct Settings {
+    format: Format,
+    max_width: usize,
+    timing: bool,
+    // Where `\o` sends results instead of stdout.
+    output: Option<(PathBuf, File)>,
+}
+
+impl Settings {
+    // Tables are for people; results going to a file or a pipe get one
+    // plain line per row instead.
+    fn result_format(&self) -> Format {
+        match self.format {
+            Format::Table { .. } if self.output.is_some() || !std::io::stdout().is_terminal() => {
+                Format::Plain
+            }
+            format => format,
+        }
+    }
+
+    // The shell's own listings always go to the terminal.
+    fn listing_format(&self) -> Format {
+        if std::io::stdout().is_terminal() {
+            Format::Table {
+                max_width: self.max_width,
+            }
+        } else {
+            Format::Plain
+        }
+    }
+
+    fn show(&mut self, result: &QueryResult, elapsed: Duration) -> Result<()> {
+        let text = self
+            .result_format()
+            .render(result, self.timing.then_some(elapsed));
+        self.write(&text)
+    }
+
+    fn write(&mut self, text: &str) -> Result<()> {
+        match &mut self.output {
+            Some((path, file)) => file
+                .write_all(text.as_bytes())
+                .with_context(|| format!("Failed to write to {:?}", path)),
+            None => {
+                print!("{}", text);
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn run_meta(client: &SqlClient, command: MetaCommand, settings: &mut Settings) {
+    let shown = match command {
+        MetaCommand::ListTables => client.tables().await.map(|tables| {
+            let rows = tables
+                .into_iter()
+                .map(|t| vec![DbValue::from(t.name), DbValue::Int(t.row_count as i64)])
+                .collect();
+            let result = QueryResult::new(Schema::new(vec!["name".into(), "rows".into()]), rows);
+            settings.listing_format().render(&result, None)
+        }),
+        MetaCommand::Describe(name) => client.table(&name).await.map(|table| match table {
+            Some(table) => describe_table(&table, settings.listing_format()),
+            None => format!("No table named {}\n", name),
+        }),
+        MetaCommand::Connect(None) => Ok(match client.search_path() {
+            Some(path) => format!("Tables are looked for in {}.\n", path.join(", ")),
+            None => "The server has not said where tables are looked for.\n".to_string(),
+        }),
+        MetaCommand::Connect(Some(schemas)) => {
+            let sql = format!("SET search_path = '{}';", schemas.replace('\'', "''"));
+            client.query(&sql).await.map(|_| {
+                let path = client.search_path().unwrap_or_default();
+                format!("Tables are looked for in {}.\n", path.join(", "))
+            })
+        }
+        MetaCommand::Timing => {
+            settings.timing = !settings.timing;
+            let state = if settings.timing { "on" } else { "off" };
+            Ok(format!("Timing is {}.\n", state))
+        }
+        MetaCommand::Format(None) => Ok(format!("Output format is {}.\n", settings.format.name())),
+        MetaCommand::Format(Some(name)) => match Format::parse(&name, settings.max_width) {
+            Some(format) => {
+                settings.format = format;
+                Ok(format!("Output format is {}.\n", format.name()))
+            }
+            None => Err(anyhow!(
+                "Unknown format {}, expected table, plain, csv or json",
+                name
+            )),
+        },
+        MetaCommand::Output(None) => {
+            settings.output = None;
+            Ok("Results go to the terminal.\n".to_string())
+        }
+        MetaCommand::Output(Some(path)) => File::create(&path)
+            .with_context(|| format!("Failed to open {:?}", path))
+            .map(|file| {
+                let shown = format!("Results go to {}.\n", path.display());
+                settings.output = Some((path, file));
+                shown
+            }),
+        MetaCommand::Help => Ok(HELP.to_string()),
+        MetaCommand::Include(_) | MetaCommand::Import { .. } | MetaCommand::Quit => {
+            Ok(String::new())
+        }
+    };
+    match shown {
+        Ok(text) => print!("{}", text),
+        Err(e) => eprintln!("Error: {:?}", e),
+    }
+}
+
+// Rewrites one line on the terminal as an import goes on.
+fn show_progress(progress: &ImportProgress) {
+    if std::io::stderr().is_terminal() {
+        eprint!(
+            "\r{} rows, {:.1} MB, {} rows/s",
+            progress.rows_imported,
+            progress.bytes_read as f64 / 1_000_000.0,
+            progress.rows_per_second
+        );
+    }
+}
+
+// Moves past the progress line, if there is one.
+fn end_progress() {
+    if std::io::stderr().is_terminal() {
+        eprintln!();
+    }
+}
+
+// What `\import` prints once it is done: the rows in, then the first of
+// those left out and why.
+pub fn import_summary(report: &ImportReport, earlier: usize) -> String {
+    let mut text = format!(
+        "Imported {} rows into {} in {} ms ({} rows/s)",
+        report.rows_imported, report.table, report.elapsed_ms, report.rows_per_second
+    );
+    if earlier > 0 {
+        text.push_str(&format!(", {} in all", earlier + report.rows_imported));
+    }
+    text.push_str(".\n");
+    if report.error_count > 0 {
+        text.push_str(&format!("Skipped {} rows:\n", report.error_count));
+        for error in &report.errors {
+            text.push_str(&format!("  line {}: {}\n", error.line, error.error));
+        }
+    }
+    text
+}
+
+// A line starting with a backslash, handled by the shell itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetaCommand {
+    ListTables,
+    Describe(String),
+    // The schemas to look for tables in, separated by commas, or None to
+    // show them.
+    Connect(Option<String>),
+    Include(PathBuf),
+    Import {
+        table: String,
+        path: PathBuf,
+        resume: bool,
+    },
+    Timing,
+    Format(Option<String>),
+    Output(Option<PathBuf>),
+    Quit,
+    Help,
+}
+
+impl MetaCommand {
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        if command == "\\import" {
+            let words: Vec<&str> = words.collect();
+            return match words[..] {
+                [table, path] => Ok(MetaCommand::Import {
+                    table: table.to_string(),
+                    path: PathBuf::from(path),
+                    resume: false,
+                }),
+                [table, path, "--resume"] => Ok(MetaCommand::Import {
+                    table: table.to_string(),
+                    path: PathBuf::from(path),
+                    resume: true,
+                }),
+                _ => bail!("Usage: \\import <table> <file> [--resume]"),
+            };
+        }
+        let arg = words.next();
+        if words.next().is_some() {
+            bail!("Too many arguments for {}", command);
+        }
+        let no_arg = |parsed: MetaCommand| match arg {
+            Some(_) => bail!("{} takes no arguments", command),
+            None => Ok(parsed),
+        };
+        match command {
+            "\\dt" => no_arg(MetaCommand::ListTables),
+            "\\d" => Ok(match arg {
+                Some(table) => MetaCommand::Describe(table.trim_end_matches(';').to_string()),
+                None => MetaCommand::ListTables,
+            }),
+            "\\c" => Ok(MetaCommand::Connect(
+                arg.map(|schemas| schemas.trim_end_matches(';').to_string()),
+            )),
+            "\\i" => match arg {
+                Some(path) => Ok(MetaCommand::Include(PathBuf::from(path))),
+                None => bail!("\\i needs a file to run"),
+            },
+            "\\timing" => no_arg(MetaCommand::Timing),
+            "\\format" => Ok(MetaCommand::Format(arg.map(str::to_string))),
+            "\\o" => Ok(MetaCommand::Output(arg.map(PathBuf::from))),
+            "\\q" => no_arg(MetaCommand::Quit),
+            "\\help" | "\\?" => no_arg(MetaCommand::Help),
+            _ => bail!("Unknown command {}; \\help lists the commands", command),
+        }
+    }
+}
+
+// A statement of a script, with the line of the script it starts on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptStatement {
+    pub line: usize,
+    pub sql: String,
+}
+
+// Splits a script into its statements the way the prompt would.
+pub fn split_script(script: &str) -> Result<Vec<ScriptStatement>> {
+    let mut buffer = StatementBuffer::new();
+    let mut statements = Vec::new();
+    let mut start = 1;
+    for (i, line) in script.lines().enumerate() {
+        if buffer.is_empty() {
+            start = i + 1;
+        }
+        for sql in buffer.push_line(line) {
+            statements.push(ScriptStatement { line: start, sql });
+            // Another statement can only begin on the line this one ended.
+            start = i + 1;
+        }
+    }
+    if !buffer.is_empty() {
+        bail!("line {}: statement has no closing `;`", start);
+    }
+    Ok(statements)
+}
+
+// Runs the script at `path` statement by statement, printing each result,
+// and stops at the first that fails, naming its line. With
+// `single_transaction` the script goes as one batch, applied whole or not
+// at all.
+async fn run_file(
+    client: &SqlClient,
+    path: &Path,
+    single_transaction: bool,
+    settings: &mut Settings,
+) -> Result<()> {
+    let script =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let name = path.display().to_string();
+    let started = Instant::now();
+    let count = run_script(client, Some(&name), &script, single_transaction, settings).await?;
+    eprintln!(
+        "Ran {} statements from {} in {} ms",
+        count,
+        name,
+        started.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+// Runs the statements of `script` and returns how many there were. Errors
+// point at the line of `name` the failing statement starts on; a script
+// without a name is short enough to need no pointing. Nothing runs unless
+// the whole script parses, and then every syntax error is reported at once.
+async fn run_script(
+    client: &SqlClient,
+    name: Option<&str>,
+    script: &str,
+    single_transaction: bool,
+    settings: &mut Settings,
+) -> Result<usize> {
+    let place = |line: usize| name.map(|name| format!("{}:{}", name, line));
+    let statements = split_script(script).map_err(|e| match name {
+        Some(name) => anyhow!("{}:{}", name, e),
+        None => e,
+    })?;
+    check_syntax(name, script)?;
+    let started = Instant::now();
+    if single_transaction {
+        let sql: Vec<&str> = statements.iter().map(|s| s.sql.as_str()).collect();
+        let results = client
+            .batch(&sql)
+            .await
+            .map_err(|e| match e.downcast_ref::<DbError>() {
+                Some(DbError::BatchFailed {
+                    index: Some(index),
+                    message,
+                }) => match place(statements[*index].line) {
+                    Some(place) => anyhow!("{}: {} (nothing was applied)", place, message),
+                    None => anyhow!("{} (nothing was applied)", message),
+                },
+                _ => e.context(format!("{}: nothing was applied", name.unwrap_or("batch"))),
+            })?;
+        let elapsed = started.elapsed();
+        for result in &results {
+            settings.show(result, elapsed)?;
+        }
+    } else {
+        for statement in &statements {
+            // Kept as context so a lost connection is still told apart
+            // from a failed statement.
+            stream_statement(client, &statement.sql, settings)
+                .await
+                .map_err(|e| match place(statement.line) {
+                    Some(place) => e.context(place),
+                    None => e,
+                })?;
+        }
+    }
+    Ok(statements.len())
+}
+
+// Fails listing every syntax error in `script`, placed in `name` if it has
+// one.
+pub fn check_syntax(name: Option<&str>, script: &str) -> Result<()> {
+    let Err(diagnostics) = Parser::parse_script(script) else {
+        return Ok(());
+    };
+    let errors: Vec<String> = diagnostics
+        .0
+        .iter()
+        .map(|e| match name {
+            Some(name) => format!("{}:{}:{}: {}\n{}", name, e.line, e.col, e.message, e.excerpt),
+            None => e.to_string(),
+        })
+        .collect();
+    match errors.len() {
+        1 => bail!("Syntax error, nothing was run:\n{}", errors[0]),
+        n => bail!("{} syntax errors, nothing was run:\n{}", n, errors.join("\n")),
+    }
+}
+
+// A table's columns laid out like a result, followed by its indexes.
+pub fn describe_table(table: &TableSchema, format: Format) -> String {
+    let rows = table
+        .columns
+        .iter()
+        .map(|c| {
+            let data_type = match &c.collation {
+                Some(collation) => format!("{} COLLATE {}", c.data_type, collation),
+                None => c.data_type.clone(),
+            };
+            vec![DbValue::from(c.name.as_str()), DbValue::from(data_type)]
+        })
+        .collect();
+    let columns = QueryResult::new(Schema::new(vec!["column".into(), "type".into()]), rows);
+    let mut out = format!("Table {}\n", table.name);
+    out.push_str(&format.render(&columns, None));
+    if !table.indexes.is_empty() {
+        out.push_str("Indexes:\n");
+        for index in &table.indexes {
+            out.push_str(&format!(
+                "    {} {} ({})\n",
+                index.name,
+                index.kind,
+                index.columns.join(", ")
+            ));
+        }
+    }
+    if !table.foreign_keys.is_empty() {
+        out.push_str("Foreign keys:\n");
+        for key in &table.foreign_keys {
+            out.push_str(&format!(
+                "    {} ({}) REFERENCES {} ({}) ON DELETE {}\n",
+                key.name, key.column, key.parent, key.parent_column, key.on_delete
+            ));
+        }
+    }
+    if !table.checks.is_empty() {
+        out.push_str("Checks:\n");
+        for check in &table.checks {
+            out.push_str(&format!("    {} CHECK ({})\n", check.name, check.condition));
+        }
+    }
+    out
+}
+
+// How the shell prints a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // A box-drawn table with a header and a footer counting the rows.
+    Table { max_width: usize },
+    // Each row on a line of its own, values separated by ` | `.
+    Plain,
+    // A header line of column names, then the rows, quoted where needed.
+    // NULL is an empty field.
+    Csv,
+    // An array with an object per row, keyed by column name.
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str, max_width: usize) -> Option<Self> {
+        match name {
+            "table" => Some(Format::Table { max_width }),
+            "plain" => Some(Format::Plain),
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Table { .. } => "table",
+            Format::Plain => "plain",
+            Format::Csv => "csv",
+            Format::Json => "json",
+        }
+    }
+
+    // `elapsed` goes in the table's footer, if given.
+    pub fn render(self, result: &QueryResult, elapsed: Option<Duration>) -> String {
+        let mut renderer = PageRenderer::new(self);
+        renderer.page(result) + &renderer.finish(result, elapsed)
+    }
+}
+
+// Renders a result a page of rows at a time, so a long one can be shown
+// while the rest is still arriving. Each page of a table is laid out on its
+// own; the other formats come out as if rendered whole.
+pub struct PageRenderer {
+    format: Format,
+    rows: usize,
+}
+
+impl PageRenderer {
+    pub fn new(format: Format) -> Self {
+        PageRenderer { format, rows: 0 }
+    }
+
+    // The next rows, with the result's columns.
+    pub fn page(&mut self, page: &QueryResult) -> String {
+        let before = self.rows;
+        self.rows += page.rows.len();
+        match self.format {
+            Format::Table { max_width } => table_box(page, max_width),
+            Format::Plain => page
+                .rows
+                .iter()
+                .map(|row| {
+                    let cells: Vec<String> = row.iter().map(DbValue::to_string).collect();
+                    cells.join(" | ") + "\n"
+                })
+                .collect(),
+            Format::Csv => render_csv(page, before == 0),
+            Format::Json if page.rows.is_empty() => String::new(),
+            Format::Json => {
+                let start = if before == 0 { "[\n" } else { ",\n" };
+                start.to_string() + &json_objects(page).join(",\n")
+            }
+        }
+    }
+
+    // What follows the last page. `result` carries the columns and the
+    // affected count; its rows are not looked at.
+    pub fn finish(&mut self, result: &QueryResult, elapsed: Option<Duration>) -> String {
+        match self.format {
+            Format::Table { .. } => table_footer(self.rows, result, elapsed),
+            Format::Json if self.rows == 0 => "[]\n".to_string(),
+            Format::Json => "\n]\n".to_string(),
+            Format::Plain | Format::Csv => String::new(),
+        }
+    }
+}
+
+fn render_csv(result: &QueryResult, with_header: bool) -> String {
+    if (result.columns().is_empty() || !with_header) && result.rows.is_empty() {
+        return String::new();
+    }
+    let mut out = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_writer(Vec::new());
+    let header = Some(result.columns().to_vec()).filter(|c| with_header && !c.is_empty());
+    let rows = result.rows.iter().map(|row| {
+        row.iter()
+            .map(|value| match value {
+                DbValue::Null => String::new(),
+                value => value.to_string(),
+            })
+            .collect()
+    });
+    for record in header.into_iter().chain(rows) {
+        // Writing to memory cannot fail.
+        out.write_record(&record).unwrap();
+    }
+    String::from_utf8(out.into_inner().unwrap()).unwrap()
+}
+
+// Built by hand to keep each object's keys in column order.
+fn json_objects(result: &QueryResult) -> Vec<String> {
+    result
+        .rows
+        .iter()
+        .map(|row| {
+            let fields: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    let name = result.columns().get(i).map_or("", String::as_str);
+                    // Both encode as JSON without fail.
+                    let name = serde_json::to_string(name).unwrap();
+                    format!("{}:{}", name, serde_json::to_string(value).unwrap())
+                })
+                .collect();
+            format!("  {{{}}}", fields.join(","))
+        })
+        .collect()
+}
+
+// `(42 rows, 13 ms)`, or just `OK` for a statement with no result to show.
+fn table_footer(rows: usize, result: &QueryResult, elapsed: Option<Duration>) -> String {
+    // CREATE TABLE, INSERT and the like have nothing to lay out.
+    if rows == 0 && result.columns().is_empty() {
+        let affected = match result.affected {
+            Some(1) => ", 1 row affected".to_string(),
+            Some(n) => format!(", {} rows affected", n),
+            None => String::new(),
+        };
+        return match elapsed {
+            Some(elapsed) => format!("OK{} ({} ms)\n", affected, elapsed.as_millis()),
+            None => format!("OK{}\n", affected),
+        };
+    }
+    let timing = elapsed.map_or(String::new(), |e| format!(", {} ms", e.as_millis()));
+    format!(
+        "({} {}{})\n",
+        rows,
+        if rows == 1 { "row" } else { "rows" },
+        timing
+    )
+}
+
+// The rows boxed under a header, without the footer.
+fn table_box(result: &QueryResult, max_width: usize) -> String {
+    let width = result
+        .rows
+        .iter()
+        .map(|row| row.len())
+        .chain([result.columns().len()])
+        .max()
+        .unwrap_or(0);
+    if width == 0 {
+        return String::new();
+    }
+    let header: Vec<String> = (0..width)
+        .map(|i| {
+            let name = result.columns().get(i).map_or("", String::as_str);
+            truncate(name, max_width)
+        })
+        .collect();
+    let rows: Vec<Vec<(String, bool)>> = result
+        .rows
+        .iter()
+        .map(|row| {
+            (0..width)
+                .map(|i| match row.values().get(i) {
+                    // Told apart from a string that says NULL.
+                    Some(DbValue::Null) | None => ("(null)".to_string(), false),
+                    Some(value) => (
+                        truncate(&value.to_string(), max_width),
+                        matches!(value, DbValue::Int(_)),
+                    ),
+                })
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = (0..width)
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].0.chars().count())
+                .chain([header[i].chars().count()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let rule = |left: &str, middle: &str, right: &str| {
+        let segments: Vec<String> = widths.iter().map(|w| "─".repeat(w + 2)).collect();
+        format!("{}{}{}\n", left, segments.join(middle), right)
+    };
+    let line = |cells: Vec<(&str, bool)>| {
+        let cells: Vec<String> = cells
+            .into_iter()
+            .zip(&widths)
+            .map(|((cell, numeric), &w)| {
+                // Numbers line up on their last digit.
+                if numeric {
+                    format!(" {:>w$} ", cell)
+                } else {
+                    format!(" {:<w$} ", cell)
+                }
+            })
+            .collect();
+        format!("│{}│\n", cells.join("│"))
+    };
+
+    let mut out = rule("┌", "┬", "┐");
+    out.push_str(&line(header.iter().map(|h| (h.as_str(), false)).collect()));
+    out.push_str(&rule("├", "┼", "┤"));
+    for row in &rows {
+        out.push_str(&line(row.iter().map(|(c, n)| (c.as_str(), *n)).collect()));
+    }
+    out.push_str(&rule("└", "┴", "┘"));
+    out
+}
+
+// Cuts `value` to at most `max_width` characters, the last of them `…`.
+// Line breaks would tear the table apart, so they print escaped.
+fn truncate(value: &str, max_width: usize) -> String {
+    let value = value.replace('\n', "\\n").replace('\r', "\\r");
+    if value.chars().count() <= max_width {
+        return value;
+    }
+    let mut cut: String = value.chars().take(max_width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+// Input gathered across prompts until it holds whole statements, each ending
+// in a `;` that is outside string literals and `--` comments.
+#[derive(Debug, Default)]
+pub struct StatementBuffer {
+    text: String,
+}
+
+impl StatementBuffer {
+    pub fn new() -> Self {
+        StatementBuffer::default()
+    }
+
+    // Adds a line of input and returns the statements it completes, in
+    // order. Whatever follows the last `;` waits for the next line.
+    pub fn push_line(&mut self, line: &str) -> Vec<String> {
+        self.text.push_str(line);
+        self.text.push('\n');
+        let mut statements = Vec::new();
+        let mut start = 0;
+        let mut in_string = false;
+        let mut chars = self.text.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\'' => in_string = !in_string,
+                '-' if !in_string && chars.peek().is_some_and(|&(_, next)| next == '-') => {
+                    // The comment runs to the end of its line.
+                    while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                }
+                ';' if !in_string => {
+                    statements.push(self.text[start..=i].trim().to_string());
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        self.text.drain(..start);
+        if is_blank(&self.text) {
+            self.text.clear();
+        }
+        statements
+    }
+
+    // The statement typed so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // True when nothing but whitespace and comments is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+    }
+}
+
+fn is_blank(text: &str) -> bool {
+    text.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with("--")
+    })
+}
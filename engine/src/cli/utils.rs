@@ -1,46 +1,34 @@
 
+use crate::net::csv_io::{self, CsvOptions, ImportReport};
 use crate::storage::storage::Storage;
-use anyhow::Result;
-use csv::{ReaderBuilder, WriterBuilder};
-use std::path::Path;
+use anyhow::{Context, Result};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use std::{fs::File, path::Path};
 
 
 
-pub fn import_csv<P: AsRef<Path>>(storage: &mut Storage, table: &str, path: P) -> Result<()> {
-    let mut rdr = ReaderBuilder::new().from_path(path)?;
-    let headers = rdr.headers()?.clone();
-
-    
-    if storage.catalog.get_table(table).is_err() {
-        let columns: Vec<_> = headers
-            .iter()
-            .map(|h| crate::storage::storage::ColumnInfo {
-                name: h.to_string(),
-                data_type: crate::storage::storage::DataType::String, 
-            })
-            .collect();
-        storage.create_table(table.to_string(), columns)?;
-    }
-
-    
-    for result in rdr.records() {
-        let record = result?;
-        let mut values = Vec::new();
-
-        for val in record.iter() {
-            
-            if let Ok(i) = val.parse::<i64>() {
-                values.push(crate::query::binder::Value::Int(i));
-            } else {
-                values.push(crate::query::binder::Value::String(val.to_string()));
-            }
-        }
-
-        let column_names: Vec<String> = headers.iter().map(|s| s.to_string()).collect();
-        storage.insert_row(table, &column_names, values)?;
-    }
-
-    Ok(())
+// Loads a CSV file into `table` as the server's import does: fields are
+// read as the table declares them, and a missing table is created with
+// types inferred from the first rows.
+pub fn import_csv<P: AsRef<Path>>(
+    storage: &mut Storage,
+    table: &str,
+    path: P,
+) -> Result<ImportReport> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let options = CsvOptions {
+        create: true,
+        ..CsvOptions::default()
+    };
+    csv_io::import(
+        storage,
+        &table.to_ascii_uppercase(),
+        file,
+        options,
+        |_, _| Ok(()),
+        |_| {},
+    )
 }
 
 
@@ -77,53 +65,34 @@ pub fn infer_csv_schema<P: AsRef<Path>>(
     let mut rdr = ReaderBuilder::new().from_path(path)?;
     let headers = rdr.headers()?.clone();
 
-    let mut columns = Vec::new();
-
     
-    let mut is_int: Vec<bool> = vec![true; headers.len()];
-
-    
-    for result in rdr.records().take(100) {
-        
-        let record = result?;
-        for (i, val) in record.iter().enumerate() {
-            if is_int[i] && val.parse::<i64>().is_err() {
-                is_int[i] = false;
-            }
-        }
-    }
-
-    
-    for (i, header) in headers.iter().enumerate() {
-        let data_type = if is_int[i] {
-            crate::storage::storage::DataType::Int
-        } else {
-            crate::storage::storage::DataType::String
-        };
-
-        columns.push(crate::storage::storage::ColumnInfo {
-            name: header.to_string(),
-            data_type,
-        });
-    }
-
-    Ok(columns)
+    let sample = rdr.records().take(100).collect::<Result<Vec<_>, _>>()?;
+    Ok(infer_columns(&headers, &sample))
 }
 
-
-pub fn import_csv_with_inference<P: AsRef<Path>>(
-    storage: &mut Storage,
-    table: &str,
-    path: P,
-) -> Result<()> {
-    
-    let columns = infer_csv_schema(&path)?;
-
-    
-    if storage.catalog.get_table(table).is_err() {
-        storage.create_table(table.to_string(), columns)?;
-    }
-
-    
-    import_csv(storage, table, path)
+// A column is INT when every sampled value parses as one, TEXT otherwise.
+pub fn infer_columns(
+    headers: &StringRecord,
+    sample: &[StringRecord],
+) -> Vec<crate::storage::storage::ColumnInfo> {
+    headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            let is_int = sample
+                .iter()
+                .filter_map(|record| record.get(i))
+                .all(|val| val.parse::<i64>().is_ok());
+            let data_type = if is_int {
+                crate::storage::storage::DataType::Int
+            } else {
+                crate::storage::storage::DataType::String
+            };
+            crate::storage::storage::ColumnInfo {
+                name: header.to_string(),
+                data_type,
+                collation: Default::default(),
+            }
+        })
+        .collect()
 }
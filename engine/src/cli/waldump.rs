@@ -0,0 +1,219 @@
+use crate::tx::log_manager::{
+    CheckpointPayload, CompensationPayload, DdlPayload, LogRecordType, Lsn, TxId, UpdatePayload,
+};
+use crate::tx::wal_reader::{RecoveryLogRecord, WalReader};
+use anyhow::{Context, Result, anyhow, bail};
+use std::{collections::BTreeMap, fmt::Write as _, io::Write, path::PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WaldumpArgs {
+    pub wal: PathBuf,
+    pub tx: Option<TxId>,
+    pub from_lsn: Option<Lsn>,
+}
+
+impl WaldumpArgs {
+    // `<wal> [--tx <id>] [--from-lsn <n>]`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut wal = None;
+        let mut parsed = WaldumpArgs::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| -> Result<u64> {
+                let v = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value", flag))?;
+                v.parse()
+                    .with_context(|| format!("invalid value {:?} for {}", v, flag))
+            };
+            match arg.as_str() {
+                "--tx" => parsed.tx = Some(value("--tx")?),
+                "--from-lsn" => parsed.from_lsn = Some(value("--from-lsn")?),
+                flag if flag.starts_with("--") => bail!("Unknown option {}", flag),
+                path if wal.is_none() => wal = Some(PathBuf::from(path)),
+                extra => bail!("Unexpected argument {:?}", extra),
+            }
+        }
+        parsed.wal =
+            wal.ok_or_else(|| anyhow!("Usage: waldump <wal> [--tx <id>] [--from-lsn <n>]"))?;
+        Ok(parsed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct TxSummary {
+    records: usize,
+    status: Option<LogRecordType>,
+}
+
+// Prints every record of the log that passes the filters, one per line, then
+// how each transaction seen ended.
+pub fn dump(args: &WaldumpArgs, out: &mut impl Write) -> Result<()> {
+    let mut reader =
+        WalReader::open(&args.wal).with_context(|| format!("opening WAL {:?}", args.wal))?;
+    let mut txns: BTreeMap<TxId, TxSummary> = BTreeMap::new();
+    while let Some(record) = reader.next_record()? {
+        let hdr = &record.header;
+        if args.tx.is_some_and(|tx| tx != hdr.tx_id)
+            || args.from_lsn.is_some_and(|lsn| hdr.lsn < lsn)
+        {
+            continue;
+        }
+        writeln!(out, "{}", describe(&record))?;
+        if hdr.typ != LogRecordType::Checkpoint {
+            let summary = txns.entry(hdr.tx_id).or_default();
+            summary.records += 1;
+            if matches!(hdr.typ, LogRecordType::Commit | LogRecordType::Abort) {
+                summary.status = Some(hdr.typ);
+            }
+        }
+    }
+    if let Some(offset) = reader.torn_at() {
+        writeln!(out, "log ends in a torn record at offset {}", offset)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "transactions:")?;
+    for (tx, summary) in txns {
+        let status = match summary.status {
+            Some(LogRecordType::Commit) => "committed",
+            Some(LogRecordType::Abort) => "aborted",
+            _ => "in progress",
+        };
+        writeln!(out, "  tx {}: {} ({} records)", tx, status, summary.records)?;
+    }
+    Ok(())
+}
+
+fn describe(record: &RecoveryLogRecord) -> String {
+    let hdr = &record.header;
+    let mut line = format!(
+        "lsn={} prev={} tx={} {:?}",
+        hdr.lsn,
+        hdr.prev_lsn.map_or("-".to_string(), |lsn| lsn.to_string()),
+        hdr.tx_id,
+        hdr.typ
+    );
+    let payload = &record.payload;
+    let detail = match hdr.typ {
+        LogRecordType::Update => UpdatePayload::decode(payload).map(|u| describe_update(&u)),
+        LogRecordType::Compensation => CompensationPayload::decode(payload)
+            .map(|c| format!("undo_next={} {}", c.undo_next, describe_update(&c.update))),
+        LogRecordType::Checkpoint => CheckpointPayload::decode(payload).map(|c| {
+            let active: Vec<String> = c.active_txns.iter().map(|t| t.tx_id.to_string()).collect();
+            format!(
+                "dirty_pages={} active=[{}] max_tx={}",
+                c.dirty_pages.len(),
+                active.join(","),
+                c.max_tx_id
+            )
+        }),
+        LogRecordType::CreateTable
+        | LogRecordType::CreateIndex
+        | LogRecordType::DropTable
+        | LogRecordType::Grant
+        | LogRecordType::CreateSequence
+        | LogRecordType::DropSequence
+        | LogRecordType::ReserveSequence
+        | LogRecordType::CreateView
+        | LogRecordType::DropView
+        | LogRecordType::AddForeignKey
+        | LogRecordType::AddCheck
+        | LogRecordType::RenameTable
+        | LogRecordType::RenameIndex
+        | LogRecordType::CreateSchema
+        | LogRecordType::DropSchema
+        | LogRecordType::AddRows
+        | LogRecordType::RemoveRows => DdlPayload::decode(payload).map(|ddl| describe_ddl(&ddl)),
+        LogRecordType::Begin | LogRecordType::Commit | LogRecordType::Abort => {
+            return line;
+        }
+    };
+    match detail {
+        Ok(detail) => write!(line, " {}", detail).unwrap(),
+        Err(e) => write!(line, " <{:#}>", e).unwrap(),
+    }
+    line
+}
+
+fn describe_ddl(ddl: &DdlPayload) -> String {
+    match ddl {
+        DdlPayload::CreateTable { name, columns } => {
+            format!("table={} columns={}", name, columns.len())
+        }
+        DdlPayload::CreateIndex { index, pages } => format!(
+            "index={} table={} column={} root={} pages={}",
+            index.name,
+            index.table,
+            index.columns().cloned().collect::<Vec<_>>().join(","),
+            index.root_page,
+            pages.len()
+        ),
+        DdlPayload::DropTable {
+            table,
+            indexes,
+            grants,
+        } => format!(
+            "table={} indexes={} grants={}",
+            table.name,
+            indexes.len(),
+            grants.len()
+        ),
+        DdlPayload::Grant {
+            table, user, after, ..
+        } => {
+            let granted: Vec<String> = after.table.iter().map(|p| p.to_string()).collect();
+            format!(
+                "table={} user={} granted=[{}] columns={}",
+                table,
+                user,
+                granted.join(","),
+                after.columns.len()
+            )
+        }
+        DdlPayload::CreateSequence { sequence } | DdlPayload::DropSequence { sequence } => {
+            format!(
+                "sequence={} start={} increment={}",
+                sequence.name, sequence.start, sequence.increment
+            )
+        }
+        DdlPayload::ReserveSequence { name, end } => format!("sequence={} end={}", name, end),
+        DdlPayload::CreateView { view } | DdlPayload::DropView { view } => {
+            format!("view={} table={}", view.name, view.table)
+        }
+        DdlPayload::AddForeignKey { table, key } => format!(
+            "table={} key={} column={} references={}({}) on_delete={}",
+            table, key.name, key.column, key.parent, key.parent_column, key.on_delete
+        ),
+        DdlPayload::AddCheck { table, check } => {
+            format!("table={} check={} ({})", table, check.name, check.condition)
+        }
+        DdlPayload::RenameTable { from, to } => format!("table={} to={}", from, to),
+        DdlPayload::RenameIndex { table, from, to } => {
+            format!("table={} index={} to={}", table, from, to)
+        }
+        DdlPayload::CreateSchema { name } | DdlPayload::DropSchema { name } => {
+            format!("schema={}", name)
+        }
+        DdlPayload::AddRows { table, rows } | DdlPayload::RemoveRows { table, rows } => {
+            format!("table={} rows={}", table, rows.len())
+        }
+    }
+}
+
+fn describe_update(update: &UpdatePayload) -> String {
+    format!(
+        "page={} offset={} before={} after={}",
+        update.page_no,
+        update.offset,
+        hex(&update.before),
+        hex(&update.after)
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        write!(s, "{:02x}", b).unwrap();
+        s
+    })
+}
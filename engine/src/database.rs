@@ -0,0 +1,567 @@
+use crate::net::{
+    client::{DbError, DbValue, QueryResult},
+    copy,
+    row::{ColumnType, Row},
+};
+use crate::query::{
+    binder::{Catalog as BinderCatalog, resolve_names},
+    memory::{DEFAULT_WORK_MEM, OutOfMemory},
+    params,
+    parser::{Parser, Statement},
+    pipeline::{create_executor_from_statement, describe_statement, is_ddl, run_ddl},
+    source::excerpt_for,
+    value::Value,
+};
+use crate::storage::{
+    backup,
+    buffer_pool::READ_AHEAD_PAGES,
+    storage::{
+        Catalog, DEFAULT_MAX_COLUMNS, DEFAULT_SCHEMA, ForeignKeyViolation, SchemaChanged, Storage,
+    },
+};
+use crate::tx::{
+    log_manager::{LogManager, TxId},
+    recovery_manager::{self, recover_storage},
+};
+use anyhow::{Context, Result, anyhow};
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// Where an embedded database keeps its files, laid out as the server lays
+// out its data directory: `data.db` and `wal.log`. Only one of them may have
+// a directory open at a time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseConfig {
+    pub dir: PathBuf,
+    pub page_size: usize,
+    pub pool_size: usize,
+    // Pages a sequential scan has the pool read at a time.
+    pub read_ahead: usize,
+    // WAL kept past what recovery needs, for AS OF queries.
+    pub history_window: u64,
+    // Memory each statement's sorts, aggregates and joins may hold.
+    pub work_mem: usize,
+    // The most columns CREATE TABLE takes.
+    pub max_columns: usize,
+}
+
+impl DatabaseConfig {
+    // The server's defaults for everything but the directory.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DatabaseConfig {
+            dir: dir.into(),
+            page_size: 4096,
+            pool_size: 10,
+            read_ahead: READ_AHEAD_PAGES,
+            history_window: 0,
+            work_mem: DEFAULT_WORK_MEM,
+            max_columns: DEFAULT_MAX_COLUMNS,
+        }
+    }
+
+    pub fn data_file(&self) -> PathBuf {
+        self.dir.join("data.db")
+    }
+
+    pub fn wal(&self) -> PathBuf {
+        self.dir.join("wal.log")
+    }
+}
+
+impl From<&Path> for DatabaseConfig {
+    fn from(dir: &Path) -> Self {
+        DatabaseConfig::new(dir)
+    }
+}
+
+impl From<&PathBuf> for DatabaseConfig {
+    fn from(dir: &PathBuf) -> Self {
+        DatabaseConfig::new(dir)
+    }
+}
+
+impl From<PathBuf> for DatabaseConfig {
+    fn from(dir: PathBuf) -> Self {
+        DatabaseConfig::new(dir)
+    }
+}
+
+impl From<&str> for DatabaseConfig {
+    fn from(dir: &str) -> Self {
+        DatabaseConfig::new(dir)
+    }
+}
+
+// What `Database::describe` finds out about a statement without running it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    // The columns of its rows, none for a statement that makes no rows.
+    pub columns: Vec<(String, ColumnType)>,
+    // What each `?` has to be, where the statement says: the type of what
+    // it is compared with or inserted into.
+    pub params: Vec<Option<ColumnType>>,
+}
+
+// Numbers no statement is going to hold, which stand in for its `?`s while
+// it is described.
+const STAND_IN: i64 = i64::MAX - 0x5eed;
+
+/// The engine run in-process: statements go through the same parser,
+/// planner and executor as the server's, without a socket in between.
+/// Failures come back as the `DbError` a `SqlClient` would give.
+///
+/// ```
+/// use engine::database::Database;
+///
+/// let dir = std::env::temp_dir().join("mydb_doc_database");
+/// # let _ = std::fs::remove_dir_all(&dir);
+/// let mut db = Database::open(&dir)?;
+/// db.execute("CREATE TABLE users (id INT, name TEXT);")?;
+/// db.execute("INSERT INTO users (id, name) VALUES (1, 'ada'), (2, 'bob');")?;
+///
+/// let result = db.execute("SELECT name FROM users WHERE id = 2;")?;
+/// assert_eq!(result.columns(), vec!["NAME"]);
+/// assert_eq!(result.rows[0].get::<String>("name")?, "bob");
+/// assert!(result.rows[0].get::<i64>("name").is_err());
+/// db.close()?;
+/// # std::fs::remove_dir_all(&dir)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// Statements run in a transaction of their own unless one was begun,
+/// with `begin` or with `BEGIN;`:
+///
+/// ```
+/// use engine::database::Database;
+///
+/// let dir = std::env::temp_dir().join("mydb_doc_transaction");
+/// # let _ = std::fs::remove_dir_all(&dir);
+/// let mut db = Database::open(&dir)?;
+/// db.execute("CREATE TABLE t (id INT);")?;
+/// db.begin()?;
+/// db.execute("INSERT INTO t (id) VALUES (1);")?;
+/// db.rollback()?;
+/// assert!(db.execute("SELECT id FROM t;")?.rows.is_empty());
+/// # drop(db);
+/// # std::fs::remove_dir_all(&dir)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Database {
+    storage: Storage,
+    wal: Arc<LogManager>,
+    // The transaction `begin` opened, until `commit` or `rollback`.
+    open: Option<TxId>,
+    closed: bool,
+}
+
+impl Database {
+    // Opens the database in the configured directory, creating it if need
+    // be, and recovers whatever the log says did not reach the data file.
+    pub fn open(config: impl Into<DatabaseConfig>) -> Result<Self> {
+        let config = config.into();
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Failed to create data directory {:?}", config.dir))?;
+        let data_file = config.data_file();
+        let mut storage = Storage::new(
+            data_file
+                .to_str()
+                .context("Data directory is not valid UTF-8")?,
+            config.page_size,
+            config.pool_size,
+        )
+        .context("Failed to initialize storage")?;
+        storage.buffer_pool.set_read_ahead(config.read_ahead);
+        storage.work_mem = config.work_mem;
+        storage.max_columns = config.max_columns;
+        let wal =
+            Arc::new(LogManager::new(config.wal())?.with_history_window(config.history_window));
+        storage.attach_wal(wal.clone());
+        storage.txns.advance_past(wal.max_tx_id());
+        backup::load_catalog(&mut storage, &config.wal())?;
+        recover_storage(&config.wal(), &mut storage).context("Recovery failed")?;
+        Ok(Database {
+            storage,
+            wal,
+            open: None,
+            closed: false,
+        })
+    }
+
+    // Runs one statement. BEGIN, COMMIT and ROLLBACK work as they do over
+    // the server; a failed statement rolls back the transaction it was in.
+    pub fn execute(&mut self, sql: &str) -> Result<QueryResult> {
+        let stmt = Parser::parse_one(sql).map_err(|diagnostics| DbError::Parse {
+            message: format!("Parse error: {}", diagnostics),
+            diagnostics: diagnostics.0,
+        })?;
+        let stmt = self.resolve(stmt)?;
+        match stmt {
+            Statement::Begin => self.begin(),
+            Statement::Commit => self.commit(),
+            Statement::Rollback => self.rollback(),
+            Statement::SetVariable { name, value } => {
+                self.storage.variables.insert(name, value);
+                Ok(())
+            }
+            Statement::CreateUser { .. } | Statement::DropUser { .. } => {
+                return Err(DbError::Execution(
+                    "Users only exist on a server; an embedded database has none".to_string(),
+                )
+                .into());
+            }
+            Statement::Set { .. } | Statement::ShowSettings { .. } => {
+                return Err(DbError::Execution(
+                    "Settings only exist on a server; an embedded database has none".to_string(),
+                )
+                .into());
+            }
+            Statement::DeclareCursor { .. }
+            | Statement::Fetch { .. }
+            | Statement::CloseCursor { .. } => {
+                return Err(DbError::Execution(
+                    "Cursors only exist in a server session; an embedded database has none"
+                        .to_string(),
+                )
+                .into());
+            }
+            Statement::Copy { .. } => {
+                return Err(DbError::Execution(
+                    "COPY reads the rows passed to Database::copy".to_string(),
+                )
+                .into());
+            }
+            Statement::SetConstraints { .. } if self.open.is_none() => {
+                return Err(DbError::Execution(
+                    "SET CONSTRAINTS can only run inside a transaction block".to_string(),
+                )
+                .into());
+            }
+            _ if self.open.is_some() && is_ddl(&stmt) => {
+                return Err(DbError::Execution(
+                    "DDL cannot run inside a transaction block".to_string(),
+                )
+                .into());
+            }
+            stmt => return self.run(sql, |storage| execute_statement(storage, stmt)),
+        }
+        .map(|()| QueryResult::default())
+    }
+
+    // Runs `sql` with its `?`s standing for `params`, in order.
+    pub fn execute_with(&mut self, sql: &str, params: &[Value]) -> Result<QueryResult> {
+        let sql = params::bind_params(sql, params)
+            .map_err(|e| DbError::Execution(format!("{:#}", e)))?;
+        self.execute(&sql)
+    }
+
+    // Runs `sql` as `execute_with` does and makes a `T` of each row.
+    pub fn query_map<T>(
+        &mut self,
+        sql: &str,
+        params: &[Value],
+        map: impl FnMut(&Row) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        self.execute_with(sql, params)?.rows.iter().map(map).collect()
+    }
+
+    // The columns `sql` would return and what its `?`s have to be, from
+    // binding and planning it against the tables as they are now. Nothing
+    // runs, and DDL and BEGIN, COMMIT and ROLLBACK make no rows.
+    pub fn describe(&mut self, sql: &str) -> Result<Description> {
+        let count = params::placeholders(sql).len();
+        let stand_ins: Vec<Value> = (0..count as i64).map(|i| Value::Int(STAND_IN - i)).collect();
+        let sql = params::bind_params(sql, &stand_ins)?;
+        let stmt = Parser::parse_one(&sql).map_err(|diagnostics| DbError::Parse {
+            message: format!("Parse error: {}", diagnostics),
+            diagnostics: diagnostics.0,
+        })?;
+        let stmt = self.resolve(stmt)?;
+        let mut description = Description {
+            columns: Vec::new(),
+            params: vec![None; count],
+        };
+        if is_ddl(&stmt) || matches!(stmt, Statement::Begin | Statement::Commit | Statement::Rollback) {
+            return Ok(description);
+        }
+        let (schema, literals) = describe_statement(stmt, &self.storage).map_err(|e| {
+            let message = format!("{:#}", e);
+            match message.contains("Bind failed:") {
+                true => DbError::Bind(message),
+                false => DbError::Execution(message),
+            }
+        })?;
+        for (i, name) in schema.names().iter().enumerate() {
+            let column_type = schema.column_type(i).unwrap_or(ColumnType::Text);
+            description.columns.push((name.clone(), column_type));
+        }
+        for (n, column_type) in literals {
+            if let Some(param) = STAND_IN
+                .checked_sub(n)
+                .and_then(|i| usize::try_from(i).ok())
+                .and_then(|i| description.params.get_mut(i))
+            {
+                param.get_or_insert(column_type);
+            }
+        }
+        Ok(description)
+    }
+
+    // Runs `COPY t FROM STDIN`, reading the rows from `rows` in the format
+    // the statement names. `affected` is how many went in.
+    pub fn copy(&mut self, sql: &str, rows: impl Read) -> Result<QueryResult> {
+        let stmt = Parser::parse_one(sql).map_err(|diagnostics| DbError::Parse {
+            message: format!("Parse error: {}", diagnostics),
+            diagnostics: diagnostics.0,
+        })?;
+        let stmt = self.resolve(stmt)?;
+        let Statement::Copy {
+            table,
+            columns,
+            format,
+        } = stmt
+        else {
+            return Err(DbError::Execution("Database::copy only runs COPY".to_string()).into());
+        };
+        self.run(sql, |storage| {
+            let report = copy::copy_in(storage, &table, &columns, format, rows)?;
+            Ok(QueryResult {
+                affected: Some(report.rows as u64),
+                ..QueryResult::default()
+            })
+        })
+    }
+
+    pub fn begin(&mut self) -> Result<()> {
+        if self.open.is_some() {
+            return Err(
+                DbError::Execution("A transaction is already in progress".to_string()).into(),
+            );
+        }
+        self.open = Some(self.start()?);
+        Ok(())
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        let tx_id = self.take_open()?;
+        self.finish(tx_id)
+    }
+
+    pub fn rollback(&mut self) -> Result<()> {
+        let tx_id = self.take_open()?;
+        self.abort(tx_id);
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.open.is_some()
+    }
+
+    // Rolls back a transaction left open and checkpoints, so the next open
+    // has nothing to recover. Dropping the database does the same, but
+    // cannot say whether it worked.
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        self.shut_down()
+    }
+
+    // Gone the way a killed process is: no rollback, no checkpoint, and the
+    // pool's dirty pages never written. The next open recovers from the log.
+    #[cfg(feature = "testing")]
+    pub(crate) fn kill(mut self) {
+        self.closed = true;
+    }
+
+    pub(crate) fn catalog(&self) -> &Catalog {
+        &self.storage.catalog
+    }
+
+    // Hands each committed row of `table` to `each`, in the order a scan
+    // returns them, reading one at a time rather than holding the table's
+    // rows as `execute` would. Only for when nothing else is running.
+    pub(crate) fn for_each_row(
+        &mut self,
+        table: &str,
+        mut each: impl FnMut(Vec<Value>) -> Result<()>,
+    ) -> Result<()> {
+        let rids = self.storage.catalog.get_table(table)?.records.clone();
+        for rid in rids {
+            if let Some(data) = self.storage.fetch_visible(rid)? {
+                each(self.storage.deserialize_row(&data)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Flushes every page and saves the catalog with a checkpoint, so the
+    // next open has no log to go through up to here.
+    pub(crate) fn checkpoint(&mut self) -> Result<()> {
+        recovery_manager::checkpoint(&mut self.storage, &self.wal)?;
+        Ok(())
+    }
+
+    fn shut_down(&mut self) -> Result<()> {
+        if let Some(tx_id) = self.open.take() {
+            self.abort(tx_id);
+        }
+        recovery_manager::checkpoint(&mut self.storage, &self.wal)
+            .context("Shutdown checkpoint failed")?;
+        Ok(())
+    }
+
+    fn take_open(&mut self) -> Result<TxId> {
+        self.open
+            .take()
+            .ok_or_else(|| DbError::Execution("No transaction in progress".to_string()).into())
+    }
+
+    // There are no settings to SET a search path with, so a name without a
+    // schema is always looked for in the default one.
+    fn resolve(&self, stmt: Statement) -> Result<Statement> {
+        let catalog = &self.storage.catalog;
+        let search_path = [DEFAULT_SCHEMA.to_string()];
+        resolve_names(catalog, &search_path, |t| catalog.is_temp(t), stmt)
+            .map_err(|e| DbError::Execution(format!("{:#}", e)).into())
+    }
+
+    fn start(&mut self) -> Result<TxId> {
+        let tx_id = self.storage.txns.begin();
+        self.storage.set_transaction(Some(tx_id));
+        if let Err(e) = self.wal.log_begin(tx_id) {
+            self.abort(tx_id);
+            return Err(e.context("WAL begin error"));
+        }
+        self.storage.take_snapshot();
+        Ok(tx_id)
+    }
+
+    fn finish(&mut self, tx_id: TxId) -> Result<()> {
+        if let Err(e) = self.storage.check_deferred() {
+            self.abort(tx_id);
+            let message = format!("{:#} (transaction {} rolled back)", e, tx_id);
+            let error = match e.downcast_ref::<ForeignKeyViolation>() {
+                Some(violation) => DbError::ForeignKeyViolation {
+                    message,
+                    constraint: violation.constraint.clone(),
+                    table: violation.table.clone(),
+                },
+                None => DbError::Execution(message),
+            };
+            return Err(error.into());
+        }
+        if let Err(e) = self.wal.log_commit(tx_id) {
+            self.abort(tx_id);
+            return Err(DbError::Execution(format!("WAL commit error: {:#}", e)).into());
+        }
+        self.storage.txns.commit(tx_id);
+        self.storage.set_transaction(None);
+        // As on the server, a failed checkpoint is tried again after the
+        // next commit rather than failing this one.
+        let _ = recovery_manager::maybe_checkpoint(&mut self.storage, &self.wal);
+        Ok(())
+    }
+
+    fn abort(&mut self, tx_id: TxId) {
+        // A rollback that fails leaves the transaction to recovery, as on
+        // the server.
+        let _ = recovery_manager::abort_transaction(&mut self.storage, &self.wal, tx_id);
+        self.storage.set_transaction(None);
+    }
+
+    fn run(
+        &mut self,
+        sql: &str,
+        work: impl FnOnce(&mut Storage) -> Result<QueryResult>,
+    ) -> Result<QueryResult> {
+        let (tx_id, in_block) = match self.open {
+            Some(tx_id) => (tx_id, true),
+            None => (self.start()?, false),
+        };
+        match work(&mut self.storage) {
+            Ok(result) => {
+                if !in_block {
+                    self.finish(tx_id)?;
+                }
+                Ok(result)
+            }
+            Err(e) => {
+                self.open = None;
+                self.abort(tx_id);
+                let mut message = format!("{:#}", e);
+                if in_block {
+                    message.push_str(&format!(" (transaction {} rolled back)", tx_id));
+                }
+                if let Some(excerpt) = excerpt_for(sql, &e) {
+                    message.push('\n');
+                    message.push_str(&excerpt);
+                }
+                // Told apart the way a client tells the server's apart.
+                let error = if let Some(violation) = e.downcast_ref::<ForeignKeyViolation>() {
+                    DbError::ForeignKeyViolation {
+                        message,
+                        constraint: violation.constraint.clone(),
+                        table: violation.table.clone(),
+                    }
+                } else if let Some(changed) = e.downcast_ref::<SchemaChanged>() {
+                    DbError::SchemaChanged {
+                        message,
+                        table: changed.table.clone(),
+                    }
+                } else if let Some(oom) = e.downcast_ref::<OutOfMemory>() {
+                    DbError::OutOfMemory {
+                        message,
+                        operator: oom.operator.to_string(),
+                        budget: oom.budget as u64,
+                    }
+                } else if message.contains("Bind failed:") {
+                    DbError::Bind(message)
+                } else {
+                    DbError::Execution(message)
+                };
+                Err(error.into())
+            }
+        }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.shut_down();
+        }
+    }
+}
+
+// Runs a statement in the transaction `storage` is set up for and collects
+// its rows.
+fn execute_statement(storage: &mut Storage, stmt: Statement) -> Result<QueryResult> {
+    if let Some(result) = run_ddl(storage, &stmt, None) {
+        return result.map(|()| QueryResult::default());
+    }
+    let mut bind_catalog = BinderCatalog::from_storage(&storage.catalog)
+        .with_random(storage.random.clone())
+        .with_variables(storage.variables.clone())
+        .with_work_mem(storage.work_mem);
+    let mut exec =
+        create_executor_from_statement(stmt, storage, &mut bind_catalog).context("Build error")?;
+    let rows = exec.execute().context("Exec error")?;
+    let schema = exec.schema().clone();
+    Ok(QueryResult {
+        rows: rows
+            .into_iter()
+            .map(|tuple| {
+                let values = tuple.into_iter().map(DbValue::from).collect();
+                Row::new(schema.clone(), values)
+            })
+            .collect(),
+        schema,
+        affected: exec
+            .affected()
+            .map(|n| u64::try_from(n).map_err(|_| anyhow!("Row count overflow")))
+            .transpose()?,
+        ..QueryResult::default()
+    })
+}
@@ -0,0 +1,70 @@
+use crate::index::node_serializer::IndexKey;
+use anyhow::{Result, bail};
+use byteorder::{ByteOrder, LittleEndian};
+
+const HEADER: usize = 4;
+const MAX_HASHES: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BloomStats {
+    pub hits: u64,
+    pub skips: u64,
+}
+
+// A Bloom filter occupying one whole page: a u32 hash count followed by the
+// bit array. Keys are only ever added, never removed, so a filter can go stale
+// towards false positives but never produces a false negative. ANALYZE (or
+// REINDEX) rebuilds it from the keys currently in the tree.
+pub fn init(page: &mut [u8], expected_keys: usize) {
+    page.fill(0);
+    let bits = bit_count(page.len());
+    let hashes = ((bits as f64 / expected_keys.max(1) as f64) * std::f64::consts::LN_2).round();
+    LittleEndian::write_u32(&mut page[0..HEADER], (hashes as u32).clamp(1, MAX_HASHES));
+}
+
+pub fn insert<K: IndexKey>(page: &mut [u8], key: &K) -> Result<()> {
+    for bit in positions(page, key)? {
+        page[HEADER + bit / 8] |= 1 << (bit % 8);
+    }
+    Ok(())
+}
+
+pub fn may_contain<K: IndexKey>(page: &[u8], key: &K) -> Result<bool> {
+    Ok(positions(page, key)?
+        .into_iter()
+        .all(|bit| page[HEADER + bit / 8] & (1 << (bit % 8)) != 0))
+}
+
+fn bit_count(page_size: usize) -> usize {
+    page_size.saturating_sub(HEADER) * 8
+}
+
+fn positions<K: IndexKey>(page: &[u8], key: &K) -> Result<Vec<usize>> {
+    let bits = bit_count(page.len());
+    if bits == 0 {
+        bail!(
+            "Page of {} bytes is too small for a Bloom filter",
+            page.len()
+        );
+    }
+    let hashes = LittleEndian::read_u32(&page[0..HEADER]);
+    if !(1..=MAX_HASHES).contains(&hashes) {
+        bail!("Corrupt Bloom filter page: {} hash functions", hashes);
+    }
+
+    let mut bytes = Vec::with_capacity(key.encoded_len());
+    key.encode(&mut bytes);
+    let h1 = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    let h2 = mix(h1) | 1;
+    Ok((0..hashes as u64)
+        .map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits as u64) as usize)
+        .collect())
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -1,40 +1,38 @@
-
+use crate::index::bloom;
 use crate::index::bplustree_search::BPlusTreeSearch;
+use crate::index::key::{self, Key};
 use crate::index::node_modifier::NodeModifier;
-use crate::index::node_serializer::{LeafNodeSerializer, NodeHeader, NodeType};
-use crate::query::binder::BoundExpr;
+use crate::index::node_serializer::{
+    IndexKey, InternalNodeSerializer, LeafNode, LeafNodeSerializer, NodeError, NodeHeader, NodeType,
+};
+use crate::query::binder::{BoundExpr, Value};
+use crate::query::parser::BinaryOp;
 use crate::storage::record::RID;
-use crate::storage::storage::Storage;
-use anyhow::{Context, Result, anyhow};
-
+use crate::storage::storage::{ColumnInfo, IndexInfo, Storage};
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::HashMap;
+use std::marker::PhantomData;
 
-pub struct BPlusTree {
-    storage: Storage,
+pub struct BPlusTree<'a, K: IndexKey = u64> {
+    storage: &'a mut Storage,
     order: usize,
     root_page: u64,
-    table_name: String, 
+    table_name: String,
+    bloom_page: Option<u64>,
+    _key: PhantomData<K>,
 }
 
-impl BPlusTree {
-    
-    pub fn new(
-        path: &str,
-        page_size: usize,
-        pool_size: usize,
-        order: usize,
-        table_name: String,
-    ) -> Result<Self> {
-        let mut storage =
-            Storage::new(path, page_size, pool_size).context("Initializing storage failed")?;
-        
+impl<'a, K: IndexKey> BPlusTree<'a, K> {
+    pub fn create(storage: &'a mut Storage, order: usize, table_name: String) -> Result<Self> {
         let root_page = storage.buffer_pool.pagefile.allocate_page()?;
-        
+
         let header = NodeHeader {
             node_type: NodeType::Leaf,
             key_count: 0,
             parent: 0,
         };
-        let buf = LeafNodeSerializer { order }.serialize(&header, &[], &[], 0, page_size);
+        let buf =
+            LeafNodeSerializer::<K>::new(order).serialize(&header, &[], &[], 0, storage.page_size);
         let frame = storage.buffer_pool.fetch_page(root_page)?;
         frame.data = buf;
         storage.buffer_pool.unpin_page(root_page, true);
@@ -44,99 +42,673 @@ impl BPlusTree {
             order,
             root_page,
             table_name,
+            bloom_page: None,
+            _key: PhantomData,
+        })
+    }
+
+    pub fn bulk_load(
+        storage: &'a mut Storage,
+        order: usize,
+        table_name: String,
+        entries: &[(K, RID)],
+    ) -> Result<Self> {
+        if entries.is_empty() {
+            let mut tree = Self::create(storage, order, table_name)?;
+            tree.bloom_page = Some(write_bloom::<K>(tree.storage, None, &[])?);
+            return Ok(tree);
+        }
+        if let Some(w) = entries.windows(2).find(|w| w[0].0 >= w[1].0) {
+            bail!(
+                "Bulk load requires strictly increasing keys, got {:?} then {:?}",
+                w[0].0,
+                w[1].0
+            );
+        }
+        let longest = entries
+            .iter()
+            .map(|(k, _)| k.encoded_len())
+            .max()
+            .unwrap_or(0);
+        if longest > max_key_len(storage.page_size) {
+            bail!("Index key of {} bytes does not fit in a page", longest);
+        }
+        // Variable-length keys can fill a page before `order` is reached, so
+        // size every node for the longest key in the input.
+        let usable = storage.page_size - NodeHeader::SIZE - 8;
+        let leaf_max = order.min(usable / (longest + 10));
+        let internal_max = order.min(usable / (longest + 8));
+
+        let mut leaves = Vec::new();
+        for chunk in even_chunks(entries, leaf_max) {
+            leaves.push((storage.buffer_pool.pagefile.allocate_page()?, chunk));
+        }
+        let mut level: Vec<(u64, K)> = leaves.iter().map(|(p, c)| (*p, c[0].0.clone())).collect();
+        let mut parents: HashMap<u64, u64> = HashMap::new();
+        let mut internals = Vec::new();
+        while level.len() > 1 {
+            let mut next_level = Vec::new();
+            for group in even_chunks(&level, internal_max + 1) {
+                let page = storage.buffer_pool.pagefile.allocate_page()?;
+                let keys: Vec<K> = group[1..].iter().map(|(_, min)| min.clone()).collect();
+                let children: Vec<u64> = group.iter().map(|(child, _)| *child).collect();
+                for &child in &children {
+                    parents.insert(child, page);
+                }
+                next_level.push((page, group[0].1.clone()));
+                internals.push((page, keys, children));
+            }
+            level = next_level;
+        }
+        let root_page = level[0].0;
+
+        let leaf_serializer = LeafNodeSerializer::<K>::new(order);
+        for (i, (page, chunk)) in leaves.iter().enumerate() {
+            let header = NodeHeader {
+                node_type: NodeType::Leaf,
+                key_count: chunk.len() as u16,
+                parent: parents.get(page).copied().unwrap_or(0),
+            };
+            let keys: Vec<K> = chunk.iter().map(|(k, _)| k.clone()).collect();
+            let rids: Vec<RID> = chunk.iter().map(|(_, r)| *r).collect();
+            let next_leaf = leaves.get(i + 1).map_or(0, |(p, _)| *p);
+            let buf =
+                leaf_serializer.serialize(&header, &keys, &rids, next_leaf, storage.page_size);
+            write_node(storage, *page, buf)?;
+        }
+        let internal_serializer = InternalNodeSerializer::<K>::new(order);
+        for (page, keys, children) in internals {
+            let header = NodeHeader {
+                node_type: NodeType::Internal,
+                key_count: keys.len() as u16,
+                parent: parents.get(&page).copied().unwrap_or(0),
+            };
+            let buf = internal_serializer.serialize(&header, &keys, &children, storage.page_size);
+            write_node(storage, page, buf)?;
+        }
+
+        let keys: Vec<K> = entries.iter().map(|(k, _)| k.clone()).collect();
+        let bloom_page = write_bloom(storage, None, &keys)?;
+
+        Ok(Self {
+            storage,
+            order,
+            root_page,
+            table_name,
+            bloom_page: Some(bloom_page),
+            _key: PhantomData,
         })
     }
 
-    
+    pub fn open(storage: &'a mut Storage, info: &IndexInfo) -> Self {
+        Self {
+            storage,
+            order: info.order,
+            root_page: info.root_page,
+            table_name: info.table.clone(),
+            bloom_page: info.bloom_page,
+            _key: PhantomData,
+        }
+    }
+
     pub fn table_name(&self) -> &str {
         &self.table_name
     }
 
-    
-    pub fn insert(&mut self, key: u64, rid: RID) -> Result<()> {
-        let mut modifier = NodeModifier::new(&mut self.storage, self.order);
-        let new_root = modifier.insert(self.root_page, key, rid)?;
+    pub fn root_page(&self) -> u64 {
+        self.root_page
+    }
+
+    pub fn bloom_page(&self) -> Option<u64> {
+        self.bloom_page
+    }
+
+    pub fn insert(&mut self, key: K, rid: RID) -> Result<()> {
+        if key.encoded_len() > max_key_len(self.storage.page_size) {
+            bail!(
+                "Index key of {} bytes does not fit in a page",
+                key.encoded_len()
+            );
+        }
+        let mut modifier = NodeModifier::new(self.storage, self.order);
+        let new_root = modifier.insert(self.root_page, key.clone(), rid)?;
         self.root_page = new_root;
+        if let Some(page) = self.bloom_page {
+            add_to_bloom(self.storage, page, &key)?;
+        }
         Ok(())
     }
 
-    
-    pub fn get(&mut self, key: u64) -> Result<Option<RID>> {
-        let mut searcher = BPlusTreeSearch::new(&mut self.storage, self.order);
-        let leaf = searcher.locate_leaf(self.root_page, key)?;
-        let frame = self.storage.buffer_pool.fetch_page(leaf)?;
-        let (_hdr, keys, rids, _) =
-            LeafNodeSerializer { order: self.order }.deserialize(&frame.data)?;
-        self.storage.buffer_pool.unpin_page(leaf, false);
-        if let Some(idx) = keys.iter().position(|&k| k == key) {
+    pub fn get(&mut self, key: K) -> Result<Option<RID>> {
+        if let Some(page) = self.bloom_page {
+            let frame = self.storage.buffer_pool.fetch_page(page)?;
+            let maybe = bloom::may_contain(&frame.data, &key);
+            self.storage.buffer_pool.unpin_page(page, false);
+            let maybe =
+                maybe.with_context(|| format!("Failed to read Bloom filter page {}", page))?;
+            let stats = self.storage.bloom_stats.entry(page).or_default();
+            if !maybe {
+                stats.skips += 1;
+                return Ok(None);
+            }
+            stats.hits += 1;
+        }
+        let mut searcher = BPlusTreeSearch::<K>::new(self.storage, self.order);
+        let leaf = searcher.locate_leaf(self.root_page, &key)?;
+        let (_hdr, keys, rids, _) = self.read_leaf(leaf)?;
+        if let Some(idx) = keys.iter().position(|k| *k == key) {
             Ok(Some(rids[idx]))
         } else {
             Ok(None)
         }
     }
 
-    
-    pub fn range_scan_keys(&mut self, lo: u64, hi: u64) -> Result<Vec<(u64, RID)>> {
-        let mut results = Vec::new();
-        let mut searcher = BPlusTreeSearch::new(&mut self.storage, self.order);
-        let mut leaf = searcher.locate_leaf(self.root_page, lo)?;
+    pub fn range_scan_keys(&mut self, lo: K, hi: K) -> Result<Vec<(K, RID)>> {
+        scan_keys(self.storage, self.order, self.root_page, lo, hi)
+    }
+
+    pub fn rebuild_bloom(&mut self) -> Result<u64> {
+        let mut page = self.root_page;
         loop {
-            let frame = self.storage.buffer_pool.fetch_page(leaf)?;
-            let (_hdr, keys, rids, next_leaf) =
-                LeafNodeSerializer { order: self.order }.deserialize(&frame.data)?;
-            for (&k, &rid) in keys.iter().zip(rids.iter()) {
-                if k > hi {
-                    break;
-                }
-                if k >= lo {
-                    results.push((k, rid));
+            let frame = self.storage.buffer_pool.fetch_page(page)?;
+            let node = InternalNodeSerializer::<K>::new(self.order).deserialize(&frame.data);
+            self.storage.buffer_pool.unpin_page(page, false);
+            match node {
+                Ok((_, _, children)) => page = children[0],
+                Err(NodeError::UnexpectedNodeType { .. }) => break,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read page {}", page));
                 }
             }
-            self.storage.buffer_pool.unpin_page(leaf, false);
+        }
+        let mut keys = Vec::new();
+        loop {
+            let (_, leaf_keys, _, next_leaf) = self.read_leaf(page)?;
+            keys.extend(leaf_keys);
             if next_leaf == 0 {
                 break;
             }
-            leaf = next_leaf;
-        }
-        Ok(results)
-    }
-
-    
-    
-    pub fn range_scan(&mut self, predicate: &BoundExpr) -> Result<Vec<RID>> {
-        match predicate {
-            BoundExpr::BinaryOp {
-                left, op, right, ..
-            } => {
-                let key = match (left.as_ref(), right.as_ref()) {
-                    (_, BoundExpr::Literal(crate::query::binder::Value::Int(val))) => *val as u64,
-                    (BoundExpr::Literal(crate::query::binder::Value::Int(val)), _) => *val as u64,
-                    _ => return Err(anyhow!("Cannot extract key from predicate")),
-                };
-
-                match op {
-                    crate::query::parser::BinaryOp::Eq => {
-                        
-                        if let Some(rid) = self.get(key)? {
-                            Ok(vec![rid])
-                        } else {
-                            Ok(vec![])
-                        }
-                    }
-                    crate::query::parser::BinaryOp::Lt => {
-                        
-                        let results = self.range_scan_keys(0, key.saturating_sub(1))?;
-                        Ok(results.into_iter().map(|(_, rid)| rid).collect())
-                    }
-                    crate::query::parser::BinaryOp::Gt => {
-                        
-                        let results = self.range_scan_keys(key + 1, u64::MAX)?;
-                        Ok(results.into_iter().map(|(_, rid)| rid).collect())
-                    }
-                    _ => Err(anyhow!("Unsupported operator for index scan")),
+            page = next_leaf;
+        }
+        let bloom_page = write_bloom(self.storage, self.bloom_page, &keys)?;
+        self.bloom_page = Some(bloom_page);
+        Ok(bloom_page)
+    }
+
+    pub fn pages(&mut self) -> Result<Vec<u64>> {
+        let mut pages: Vec<u64> = self.bloom_page.into_iter().collect();
+        let mut stack = vec![self.root_page];
+        while let Some(page) = stack.pop() {
+            pages.push(page);
+            let frame = self.storage.buffer_pool.fetch_page(page)?;
+            let node = InternalNodeSerializer::<K>::new(self.order).deserialize(&frame.data);
+            self.storage.buffer_pool.unpin_page(page, false);
+            match node {
+                Ok((_, _, children)) => stack.extend(children),
+                Err(NodeError::UnexpectedNodeType { .. }) => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read page {}", page));
+                }
+            }
+        }
+        Ok(pages)
+    }
+
+    fn read_leaf(&mut self, page: u64) -> Result<LeafNode<K>> {
+        read_leaf(self.storage, self.order, page)
+    }
+
+    pub fn check(&mut self) -> Result<usize> {
+        let mut leaves = Vec::new();
+        let mut key_count = 0;
+        self.check_node(self.root_page, 0, None, None, &mut leaves, &mut key_count)?;
+
+        let mut chain = Vec::new();
+        let mut leaf = leaves[0];
+        loop {
+            chain.push(leaf);
+            if chain.len() > leaves.len() {
+                bail!("Leaf chain is longer than the number of leaves");
+            }
+            match self.read_leaf(leaf)?.3 {
+                0 => break,
+                next => leaf = next,
+            }
+        }
+        if chain != leaves {
+            bail!(
+                "Leaf chain {:?} does not match tree order {:?}",
+                chain,
+                leaves
+            );
+        }
+        Ok(key_count)
+    }
+
+    fn check_node(
+        &mut self,
+        page: u64,
+        parent: u64,
+        lo: Option<&K>,
+        hi: Option<&K>,
+        leaves: &mut Vec<u64>,
+        key_count: &mut usize,
+    ) -> Result<()> {
+        let frame = self.storage.buffer_pool.fetch_page(page)?;
+        let data = frame.data.clone();
+        self.storage.buffer_pool.unpin_page(page, false);
+
+        let header = NodeHeader::deserialize(&data)
+            .with_context(|| format!("Failed to read header of page {}", page))?;
+        if header.parent != parent {
+            bail!(
+                "Page {} has parent pointer {} but is a child of {}",
+                page,
+                header.parent,
+                parent
+            );
+        }
+
+        let keys = match header.node_type {
+            NodeType::Leaf => {
+                let (_, keys, _, _) = LeafNodeSerializer::<K>::new(self.order)
+                    .deserialize(&data)
+                    .with_context(|| format!("Failed to read leaf page {}", page))?;
+                leaves.push(page);
+                *key_count += keys.len();
+                keys
+            }
+            NodeType::Internal => {
+                let (_, keys, children) = InternalNodeSerializer::<K>::new(self.order)
+                    .deserialize(&data)
+                    .with_context(|| format!("Failed to read internal page {}", page))?;
+                if children.len() != keys.len() + 1 {
+                    bail!(
+                        "Internal page {} has {} keys but {} children",
+                        page,
+                        keys.len(),
+                        children.len()
+                    );
+                }
+                for (i, &child) in children.iter().enumerate() {
+                    let child_lo = if i == 0 { lo } else { Some(&keys[i - 1]) };
+                    let child_hi = keys.get(i).or(hi);
+                    self.check_node(child, page, child_lo, child_hi, leaves, key_count)?;
                 }
+                keys
             }
-            _ => Err(anyhow!("Invalid predicate for index scan")),
+        };
+
+        if keys.windows(2).any(|w| w[0] >= w[1]) {
+            bail!("Keys on page {} are not strictly increasing", page);
+        }
+        if let (Some(lo), Some(first)) = (lo, keys.first())
+            && first < lo
+        {
+            bail!(
+                "Key {:?} on page {} is below separator {:?}",
+                first,
+                page,
+                lo
+            );
+        }
+        if let (Some(hi), Some(last)) = (hi, keys.last())
+            && last >= hi
+        {
+            bail!(
+                "Key {:?} on page {} is not below separator {:?}",
+                last,
+                page,
+                hi
+            );
+        }
+        Ok(())
+    }
+}
+
+impl BPlusTree<'_, i64> {
+    pub fn range_scan(&mut self, column: &str, predicate: &BoundExpr) -> Result<Vec<(i64, RID)>> {
+        let (lo, hi) = key_range(column, predicate)
+            .ok_or_else(|| anyhow!("Cannot extract key range for '{}' from predicate", column))?;
+        self.range_scan_keys(lo, hi)
+    }
+}
+
+// `range_scan` for readers that share storage with others, which only ever
+// read pages and so need no tree of their own. The ranges are read in turn,
+// so ones in order and apart, as `index_ranges` gives them, come out in key
+// order.
+pub fn scan(storage: &Storage, info: &IndexInfo, ranges: &[(Key, Key)]) -> Result<Vec<(Key, RID)>> {
+    let mut results = Vec::new();
+    for range in ranges {
+        match range {
+            (Key::Int(lo), Key::Int(hi)) => {
+                let keys = scan_keys(storage, info.order, info.root_page, *lo, *hi)?;
+                results.extend(keys.into_iter().map(|(key, rid)| (Key::Int(key), rid)));
+            }
+            (Key::Bytes(lo), Key::Bytes(hi)) => {
+                let keys = scan_keys(storage, info.order, info.root_page, lo.clone(), hi.clone())?;
+                results.extend(keys.into_iter().map(|(key, rid)| (Key::Bytes(key), rid)));
+            }
+            _ => bail!(
+                "A range of keys of index '{}' mixes INTs and bytes",
+                info.name
+            ),
+        }
+    }
+    Ok(results)
+}
+
+fn scan_keys<K: IndexKey>(
+    storage: &Storage,
+    order: usize,
+    root_page: u64,
+    lo: K,
+    hi: K,
+) -> Result<Vec<(K, RID)>> {
+    let mut results = Vec::new();
+    if lo > hi {
+        return Ok(results);
+    }
+    let mut searcher = BPlusTreeSearch::<K>::new(storage, order);
+    let mut leaf = searcher.locate_leaf(root_page, &lo)?;
+    loop {
+        let (_hdr, keys, rids, next_leaf) = read_leaf(storage, order, leaf)?;
+        for (k, &rid) in keys.into_iter().zip(rids.iter()) {
+            if k > hi {
+                return Ok(results);
+            }
+            if k >= lo {
+                results.push((k, rid));
+            }
+        }
+        if next_leaf == 0 {
+            break;
+        }
+        leaf = next_leaf;
+    }
+    Ok(results)
+}
+
+fn read_leaf<K: IndexKey>(storage: &Storage, order: usize, page: u64) -> Result<LeafNode<K>> {
+    let data = storage.buffer_pool.read_page(page)?;
+    LeafNodeSerializer::<K>::new(order)
+        .deserialize(&data)
+        .with_context(|| format!("Failed to read leaf page {}", page))
+}
+
+fn even_chunks<T>(items: &[T], max: usize) -> Vec<&[T]> {
+    let groups = items.len().div_ceil(max);
+    let (base, extra) = (items.len() / groups, items.len() % groups);
+    let mut chunks = Vec::with_capacity(groups);
+    let mut start = 0;
+    for i in 0..groups {
+        let len = base + usize::from(i < extra);
+        chunks.push(&items[start..start + len]);
+        start += len;
+    }
+    chunks
+}
+
+fn write_bloom<K: IndexKey>(storage: &mut Storage, page: Option<u64>, keys: &[K]) -> Result<u64> {
+    let page = match page {
+        Some(page) => page,
+        None => storage.buffer_pool.pagefile.allocate_page()?,
+    };
+    let frame = storage.buffer_pool.fetch_page(page)?;
+    bloom::init(&mut frame.data, keys.len());
+    let result = keys
+        .iter()
+        .try_for_each(|k| bloom::insert(&mut frame.data, k));
+    storage.buffer_pool.unpin_page(page, true);
+    result?;
+    Ok(page)
+}
+
+pub fn add_to_bloom<K: IndexKey>(storage: &mut Storage, page: u64, key: &K) -> Result<()> {
+    let frame = storage.buffer_pool.fetch_page(page)?;
+    let result = bloom::insert(&mut frame.data, key);
+    storage.buffer_pool.unpin_page(page, true);
+    result.with_context(|| format!("Failed to update Bloom filter page {}", page))
+}
+
+fn write_node(storage: &mut Storage, page: u64, buf: Vec<u8>) -> Result<()> {
+    let frame = storage.buffer_pool.fetch_page(page)?;
+    frame.data = buf;
+    storage.buffer_pool.unpin_page(page, true);
+    Ok(())
+}
+
+pub const MIN_ORDER: usize = 3;
+
+pub fn max_order<K: IndexKey>(page_size: usize) -> usize {
+    InternalNodeSerializer::<K>::max_keys(page_size)
+        .min(LeafNodeSerializer::<K>::max_keys(page_size))
+}
+
+pub fn max_key_len(page_size: usize) -> usize {
+    page_size / 8
+}
+
+pub fn key_range(column: &str, predicate: &BoundExpr) -> Option<(i64, i64)> {
+    if let BoundExpr::BinaryOp {
+        left,
+        op: BinaryOp::And,
+        right,
+        ..
+    } = predicate
+    {
+        return match (key_range(column, left), key_range(column, right)) {
+            (Some((l1, h1)), Some((l2, h2))) => Some((l1.max(l2), h1.min(h2))),
+            (Some(r), None) | (None, Some(r)) => Some(r),
+            (None, None) => None,
+        };
+    }
+
+    let (Value::Int(key), op) = compared(column, predicate)? else {
+        return None;
+    };
+    let key = *key;
+    match op {
+        BinaryOp::Eq | BinaryOp::IsNotDistinctFrom => Some((key, key)),
+        BinaryOp::Lt => Some((i64::MIN, key.checked_sub(1)?)),
+        BinaryOp::LtEq => Some((i64::MIN, key)),
+        BinaryOp::Gt => Some((key.checked_add(1)?, i64::MAX)),
+        BinaryOp::GtEq => Some((key, i64::MAX)),
+        _ => None,
+    }
+}
+
+// The literal `predicate` compares `column` with, and how, as if the column
+// were on the left.
+fn compared<'p>(column: &str, predicate: &'p BoundExpr) -> Option<(&'p Value, BinaryOp)> {
+    let BoundExpr::BinaryOp {
+        left, op, right, ..
+    } = predicate
+    else {
+        return None;
+    };
+    match (left.as_ref(), right.as_ref()) {
+        (BoundExpr::Column { col, .. }, BoundExpr::Literal(v))
+            if col.eq_ignore_ascii_case(column) =>
+        {
+            Some((v, *op))
+        }
+        (BoundExpr::Literal(v), BoundExpr::Column { col, .. })
+            if col.eq_ignore_ascii_case(column) =>
+        {
+            let flipped = match op {
+                BinaryOp::Lt => BinaryOp::Gt,
+                BinaryOp::LtEq => BinaryOp::GtEq,
+                BinaryOp::Gt => BinaryOp::Lt,
+                BinaryOp::GtEq => BinaryOp::LtEq,
+                other => *other,
+            };
+            Some((v, flipped))
+        }
+        _ => None,
+    }
+}
+
+// The ranges of keys of `column` a row satisfying `predicate` can have,
+// sorted and apart: AND narrows them, OR joins them, so `IN (...)` gives one
+// for each value, and a comparison of the column with an INT gives one.
+// None when the predicate does not pin the column down that way, as when a
+// side of an OR says nothing about it or compares it with a TEXT, which no
+// key can be.
+pub fn key_ranges(column: &str, predicate: &BoundExpr) -> Option<Vec<(i64, i64)>> {
+    ranges_of(predicate, &|leaf| key_range(column, leaf), merge_ranges)
+}
+
+// The ranges of keys of an index on `columns`, declared so, a row
+// satisfying `predicate` can have, sorted and apart. An index keyed by INT
+// takes `key_ranges`. Any other is pinned down a column at a time: while
+// the predicate allows a column only a list of values, each key so far is
+// extended by each of them, and the first column it gives ranges for, or
+// the last, ends the keys. A literal is taken as a key of its column holds
+// it, so one of another type says nothing about the column. None when the
+// predicate does not pin the first column down.
+pub fn index_ranges(columns: &[ColumnInfo], predicate: &BoundExpr) -> Option<Vec<(Key, Key)>> {
+    if key::int_keyed(columns) {
+        let ranges = key_ranges(&columns[0].name, predicate)?;
+        return Some(
+            ranges
+                .into_iter()
+                .map(|(lo, hi)| (Key::Int(lo), Key::Int(hi)))
+                .collect(),
+        );
+    }
+    let mut ranges: Option<Vec<(Vec<u8>, Vec<u8>)>> = None;
+    for column in columns {
+        let Some(column_ranges) = ranges_of(
+            predicate,
+            &|leaf| value_range(column, leaf),
+            merge_byte_ranges,
+        ) else {
+            break;
+        };
+        let prefixes = match &ranges {
+            Some(ranges) => ranges.iter().map(|(lo, _)| lo.clone()).collect(),
+            None => vec![Vec::new()],
+        };
+        if ranges.is_some() && prefixes.len() * column_ranges.len() > MAX_PROBES {
+            break;
+        }
+        let points = column_ranges.iter().all(|(lo, hi)| is_point(lo, hi));
+        ranges = Some(
+            prefixes
+                .iter()
+                .flat_map(|prefix| {
+                    column_ranges.iter().map(move |(lo, hi)| {
+                        (
+                            [prefix.as_slice(), lo].concat(),
+                            [prefix.as_slice(), hi].concat(),
+                        )
+                    })
+                })
+                .collect(),
+        );
+        if !points {
+            break;
+        }
+    }
+    Some(
+        ranges?
+            .into_iter()
+            .map(|(lo, hi)| (Key::Bytes(lo), Key::Bytes(hi)))
+            .collect(),
+    )
+}
+
+// How many lookups the values of a composite index's later columns can
+// multiply its earlier ones' into; past that the rest are left to the
+// filter.
+const MAX_PROBES: usize = 256;
+
+type Ranges<T> = Vec<(T, T)>;
+
+// AND narrows the ranges `leaf` gives, OR joins them.
+fn ranges_of<T: Ord + Clone>(
+    predicate: &BoundExpr,
+    leaf: &dyn Fn(&BoundExpr) -> Option<(T, T)>,
+    merge: fn(Ranges<T>) -> Ranges<T>,
+) -> Option<Ranges<T>> {
+    let BoundExpr::BinaryOp {
+        left, op, right, ..
+    } = predicate
+    else {
+        return None;
+    };
+    match op {
+        BinaryOp::And => match (ranges_of(left, leaf, merge), ranges_of(right, leaf, merge)) {
+            (Some(l), Some(r)) => {
+                let both = l.iter().flat_map(|(l1, h1)| {
+                    r.iter()
+                        .map(move |(l2, h2)| (l1.max(l2).clone(), h1.min(h2).clone()))
+                });
+                Some(merge(both.collect()))
+            }
+            (Some(ranges), None) | (None, Some(ranges)) => Some(ranges),
+            (None, None) => None,
+        },
+        BinaryOp::Or => {
+            let mut ranges = ranges_of(left, leaf, merge)?;
+            ranges.extend(ranges_of(right, leaf, merge)?);
+            Some(merge(ranges))
+        }
+        _ => leaf(predicate).map(|range| vec![range]),
+    }
+}
+
+// The bytes of a key that follow those of the columns before `column` when
+// a row satisfies the comparison `predicate`, from the first to the last
+// included. A range takes in more than the comparison where that is
+// simpler, as `< v` does a key ending in `v`; the filter above the scan
+// drops what it should not have.
+fn value_range(column: &ColumnInfo, predicate: &BoundExpr) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (value, op) = compared(&column.name, predicate)?;
+    let mut at = Vec::new();
+    key::encode(column, value, &mut at)?;
+    let after = [at.as_slice(), &[key::AFTER]].concat();
+    match op {
+        BinaryOp::Eq | BinaryOp::IsNotDistinctFrom => Some((at, after)),
+        BinaryOp::Lt => Some((Vec::new(), at)),
+        BinaryOp::LtEq => Some((Vec::new(), after)),
+        BinaryOp::Gt => Some((after, vec![key::AFTER])),
+        BinaryOp::GtEq => Some((at, vec![key::AFTER])),
+        _ => None,
+    }
+}
+
+// Whether the range holds one value of its column.
+fn is_point(lo: &[u8], hi: &[u8]) -> bool {
+    key::value_len(lo) == Some(lo.len()) && hi.strip_prefix(lo) == Some(&[key::AFTER])
+}
+
+// Drops the empty ranges and joins the ones that overlap or touch.
+fn merge_ranges(mut ranges: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    ranges.retain(|(lo, hi)| lo <= hi);
+    ranges.sort_unstable();
+    let mut merged: Vec<(i64, i64)> = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+            _ => merged.push((lo, hi)),
+        }
+    }
+    merged
+}
+
+// Likewise for ranges of bytes, which can only be joined where they overlap.
+fn merge_byte_ranges(mut ranges: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    ranges.retain(|(lo, hi)| lo <= hi);
+    ranges.sort_unstable();
+    let mut merged: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if lo <= last.1 => last.1 = last.1.clone().max(hi),
+            _ => merged.push((lo, hi)),
         }
     }
+    merged
 }
@@ -0,0 +1,353 @@
+use crate::storage::record::RID;
+use crate::storage::storage::{IndexInfo, Storage};
+use anyhow::{Result, bail};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashSet;
+
+const INITIAL_BUCKETS: u64 = 4;
+const META_HEADER: usize = 36;
+const BUCKET_HEADER: usize = 10;
+const ENTRY_SIZE: usize = 18;
+
+// Linear hashing: buckets split in round-robin order whenever the load factor
+// goes above 3/4, and full buckets grow overflow pages in a singly linked chain.
+pub struct HashIndex<'a> {
+    storage: &'a mut Storage,
+    meta_page: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashIndexStats {
+    pub level: u32,
+    pub buckets: usize,
+    pub entries: u64,
+    pub overflow_pages: usize,
+}
+
+struct Meta {
+    level: u32,
+    next: u64,
+    initial: u64,
+    entries: u64,
+    buckets: Vec<u64>,
+}
+
+impl Meta {
+    fn bucket_of(&self, key: u64) -> usize {
+        let h = hash(key);
+        let mut b = h % (self.initial << self.level);
+        if b < self.next {
+            b = h % (self.initial << (self.level + 1));
+        }
+        b as usize
+    }
+}
+
+impl<'a> HashIndex<'a> {
+    pub fn bucket_capacity(page_size: usize) -> usize {
+        page_size.saturating_sub(BUCKET_HEADER) / ENTRY_SIZE
+    }
+
+    pub fn max_buckets(page_size: usize) -> usize {
+        page_size.saturating_sub(META_HEADER) / 8
+    }
+
+    pub fn create(storage: &'a mut Storage) -> Result<Self> {
+        if Self::bucket_capacity(storage.page_size) == 0
+            || Self::max_buckets(storage.page_size) < INITIAL_BUCKETS as usize
+        {
+            bail!(
+                "Page size {} is too small for a hash index",
+                storage.page_size
+            );
+        }
+        let meta_page = storage.buffer_pool.pagefile.allocate_page()?;
+        let mut index = Self { storage, meta_page };
+        let mut buckets = Vec::with_capacity(INITIAL_BUCKETS as usize);
+        for _ in 0..INITIAL_BUCKETS {
+            let page = index.storage.buffer_pool.pagefile.allocate_page()?;
+            index.write_bucket(page, 0, &[])?;
+            buckets.push(page);
+        }
+        index.write_meta(&Meta {
+            level: 0,
+            next: 0,
+            initial: INITIAL_BUCKETS,
+            entries: 0,
+            buckets,
+        })?;
+        Ok(index)
+    }
+
+    pub fn open(storage: &'a mut Storage, info: &IndexInfo) -> Self {
+        Self {
+            storage,
+            meta_page: info.root_page,
+        }
+    }
+
+    pub fn meta_page(&self) -> u64 {
+        self.meta_page
+    }
+
+    pub fn get(&mut self, key: u64) -> Result<Vec<RID>> {
+        lookup(self.storage, self.meta_page, key)
+    }
+
+    // `get` for readers that share storage with others.
+    pub fn lookup(storage: &Storage, info: &IndexInfo, key: u64) -> Result<Vec<RID>> {
+        lookup(storage, info.root_page, key)
+    }
+
+    pub fn insert(&mut self, key: u64, rid: RID) -> Result<()> {
+        let mut meta = read_meta(self.storage, self.meta_page)?;
+        let capacity = Self::bucket_capacity(self.storage.page_size);
+        let mut page = meta.buckets[meta.bucket_of(key)];
+        loop {
+            let (next, mut entries) = read_bucket(self.storage, page)?;
+            if entries.len() < capacity {
+                entries.push((key, rid));
+                self.write_bucket(page, next, &entries)?;
+                break;
+            }
+            if next == 0 {
+                let overflow = self.storage.buffer_pool.pagefile.allocate_page()?;
+                self.write_bucket(overflow, 0, &[(key, rid)])?;
+                self.write_bucket(page, overflow, &entries)?;
+                break;
+            }
+            page = next;
+        }
+
+        meta.entries += 1;
+        let slots = (meta.buckets.len() * capacity) as u64;
+        if meta.entries * 4 > slots * 3
+            && meta.buckets.len() < Self::max_buckets(self.storage.page_size)
+        {
+            self.split(&mut meta)?;
+        }
+        self.write_meta(&meta)
+    }
+
+    pub fn stats(&mut self) -> Result<HashIndexStats> {
+        let meta = read_meta(self.storage, self.meta_page)?;
+        let mut overflow_pages = 0;
+        for &bucket in &meta.buckets {
+            let (mut next, _) = read_bucket(self.storage, bucket)?;
+            while next != 0 {
+                overflow_pages += 1;
+                next = read_bucket(self.storage, next)?.0;
+            }
+        }
+        Ok(HashIndexStats {
+            level: meta.level,
+            buckets: meta.buckets.len(),
+            entries: meta.entries,
+            overflow_pages,
+        })
+    }
+
+    pub fn pages(&mut self) -> Result<Vec<u64>> {
+        let meta = read_meta(self.storage, self.meta_page)?;
+        let mut pages = vec![self.meta_page];
+        for &bucket in &meta.buckets {
+            let mut page = bucket;
+            while page != 0 {
+                pages.push(page);
+                page = read_bucket(self.storage, page)?.0;
+            }
+        }
+        Ok(pages)
+    }
+
+    // Walks every chain, checking that no page turns up twice, that each
+    // entry sits in the bucket its key hashes to and that the meta page counts
+    // them right. Returns the entries.
+    pub fn check(&mut self) -> Result<Vec<(u64, RID)>> {
+        let meta = read_meta(self.storage, self.meta_page)?;
+        let mut seen = HashSet::from([self.meta_page]);
+        let mut all = Vec::new();
+        for (bucket, &first) in meta.buckets.iter().enumerate() {
+            let mut page = first;
+            while page != 0 {
+                if !seen.insert(page) {
+                    bail!("Page {} is reached twice from bucket {}", page, bucket);
+                }
+                let (next, entries) = read_bucket(self.storage, page)?;
+                if let Some((key, _)) = entries.iter().find(|(k, _)| meta.bucket_of(*k) != bucket) {
+                    bail!(
+                        "Key {} on page {} belongs in bucket {}, not {}",
+                        key,
+                        page,
+                        meta.bucket_of(*key),
+                        bucket
+                    );
+                }
+                all.extend(entries);
+                page = next;
+            }
+        }
+        if all.len() as u64 != meta.entries {
+            bail!(
+                "Meta page {} counts {} entries but the buckets hold {}",
+                self.meta_page,
+                meta.entries,
+                all.len()
+            );
+        }
+        Ok(all)
+    }
+
+    fn split(&mut self, meta: &mut Meta) -> Result<()> {
+        let old = meta.next as usize;
+        let image = meta.buckets.len();
+
+        let mut pages = Vec::new();
+        let mut entries = Vec::new();
+        let mut page = meta.buckets[old];
+        while page != 0 {
+            let (next, chunk) = read_bucket(self.storage, page)?;
+            pages.push(page);
+            entries.extend(chunk);
+            page = next;
+        }
+
+        let modulus = meta.initial << (meta.level + 1);
+        let (moved, stay): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|(k, _)| (hash(*k) % modulus) as usize == image);
+
+        let image_page = self.storage.buffer_pool.pagefile.allocate_page()?;
+        meta.buckets.push(image_page);
+        let spare = self.write_chain(pages, &stay)?;
+        let mut image_pages = vec![image_page];
+        image_pages.extend(spare);
+        self.write_chain(image_pages, &moved)?;
+
+        meta.next += 1;
+        if meta.next == meta.initial << meta.level {
+            meta.level += 1;
+            meta.next = 0;
+        }
+        Ok(())
+    }
+
+    fn write_chain(&mut self, pages: Vec<u64>, entries: &[(u64, RID)]) -> Result<Vec<u64>> {
+        let capacity = Self::bucket_capacity(self.storage.page_size);
+        let chunks: Vec<&[(u64, RID)]> = if entries.is_empty() {
+            vec![&[]]
+        } else {
+            entries.chunks(capacity).collect()
+        };
+        let mut pages = pages.into_iter();
+        let mut chain = Vec::with_capacity(chunks.len());
+        for _ in 0..chunks.len() {
+            match pages.next() {
+                Some(page) => chain.push(page),
+                None => chain.push(self.storage.buffer_pool.pagefile.allocate_page()?),
+            }
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(0);
+            self.write_bucket(chain[i], next, chunk)?;
+        }
+        Ok(pages.collect())
+    }
+
+    fn write_meta(&mut self, meta: &Meta) -> Result<()> {
+        let frame = self.storage.buffer_pool.fetch_page(self.meta_page)?;
+        let data = &mut frame.data;
+        LittleEndian::write_u32(&mut data[0..4], meta.level);
+        LittleEndian::write_u64(&mut data[4..12], meta.next);
+        LittleEndian::write_u64(&mut data[12..20], meta.initial);
+        LittleEndian::write_u64(&mut data[20..28], meta.entries);
+        LittleEndian::write_u64(&mut data[28..36], meta.buckets.len() as u64);
+        for (i, &bucket) in meta.buckets.iter().enumerate() {
+            let pos = META_HEADER + i * 8;
+            LittleEndian::write_u64(&mut data[pos..pos + 8], bucket);
+        }
+        self.storage.buffer_pool.unpin_page(self.meta_page, true);
+        Ok(())
+    }
+
+    fn write_bucket(&mut self, page: u64, next: u64, entries: &[(u64, RID)]) -> Result<()> {
+        let frame = self.storage.buffer_pool.fetch_page(page)?;
+        let data = &mut frame.data;
+        data.fill(0);
+        LittleEndian::write_u64(&mut data[0..8], next);
+        LittleEndian::write_u16(&mut data[8..10], entries.len() as u16);
+        for (i, &(key, (page_no, slot_no))) in entries.iter().enumerate() {
+            let pos = BUCKET_HEADER + i * ENTRY_SIZE;
+            LittleEndian::write_u64(&mut data[pos..pos + 8], key);
+            LittleEndian::write_u64(&mut data[pos + 8..pos + 16], page_no);
+            LittleEndian::write_u16(&mut data[pos + 16..pos + 18], slot_no);
+        }
+        self.storage.buffer_pool.unpin_page(page, true);
+        Ok(())
+    }
+}
+
+fn lookup(storage: &Storage, meta_page: u64, key: u64) -> Result<Vec<RID>> {
+    let meta = read_meta(storage, meta_page)?;
+    let mut page = meta.buckets[meta.bucket_of(key)];
+    let mut rids = Vec::new();
+    while page != 0 {
+        let (next, entries) = read_bucket(storage, page)?;
+        rids.extend(entries.iter().filter(|(k, _)| *k == key).map(|(_, r)| *r));
+        page = next;
+    }
+    Ok(rids)
+}
+
+fn read_meta(storage: &Storage, meta_page: u64) -> Result<Meta> {
+    let data = storage.buffer_pool.read_page(meta_page)?;
+    let level = LittleEndian::read_u32(&data[0..4]);
+    let next = LittleEndian::read_u64(&data[4..12]);
+    let initial = LittleEndian::read_u64(&data[12..20]);
+    let entries = LittleEndian::read_u64(&data[20..28]);
+    let count = LittleEndian::read_u64(&data[28..36]) as usize;
+    let max = HashIndex::max_buckets(data.len());
+    let buckets = (0..count.min(max))
+        .map(|i| {
+            let pos = META_HEADER + i * 8;
+            LittleEndian::read_u64(&data[pos..pos + 8])
+        })
+        .collect::<Vec<_>>();
+    if count == 0 || count > max || initial == 0 {
+        bail!("Corrupt hash index meta page {}", meta_page);
+    }
+    Ok(Meta {
+        level,
+        next,
+        initial,
+        entries,
+        buckets,
+    })
+}
+
+fn read_bucket(storage: &Storage, page: u64) -> Result<(u64, Vec<(u64, RID)>)> {
+    let data = storage.buffer_pool.read_page(page)?;
+    let next = LittleEndian::read_u64(&data[0..8]);
+    let count = LittleEndian::read_u16(&data[8..10]) as usize;
+    let capacity = HashIndex::bucket_capacity(data.len());
+    let entries = (0..count.min(capacity))
+        .map(|i| {
+            let pos = BUCKET_HEADER + i * ENTRY_SIZE;
+            let key = LittleEndian::read_u64(&data[pos..pos + 8]);
+            let page_no = LittleEndian::read_u64(&data[pos + 8..pos + 16]);
+            let slot_no = LittleEndian::read_u16(&data[pos + 16..pos + 18]);
+            (key, (page_no, slot_no))
+        })
+        .collect();
+    if count > capacity {
+        bail!("Corrupt hash bucket page {}: {} entries", page, count);
+    }
+    Ok((next, entries))
+}
+
+fn hash(key: u64) -> u64 {
+    let mut z = key.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,141 @@
+use crate::query::value::{Collation, Value};
+use crate::storage::storage::{ColumnInfo, DataType};
+use std::fmt;
+
+// The key an index keeps for a row. An index on one INT column is keyed by
+// the INT, as every index was before TEXT and composite ones; any other by
+// its columns' values encoded one after another, so that the bytes sort as
+// the values do under the columns' collations.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Key {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+const INT_TAG: u8 = 1;
+const TEXT_TAG: u8 = 2;
+
+// Sorts after the tag every encoded value starts with, so a prefix followed
+// by it comes after every key that starts with the prefix.
+pub const AFTER: u8 = 0xFF;
+
+// Whether an index on `columns` is keyed by INTs rather than bytes.
+pub fn int_keyed(columns: &[ColumnInfo]) -> bool {
+    matches!(columns, [column] if column.data_type == DataType::Int)
+}
+
+impl Key {
+    // The key of a row whose key columns, declared as `columns`, hold
+    // `values`; None when a value is not of its column's type.
+    pub fn of(columns: &[ColumnInfo], values: &[Value]) -> Option<Key> {
+        if int_keyed(columns) {
+            return values.first()?.as_int().map(Key::Int);
+        }
+        let mut bytes = Vec::new();
+        for (column, value) in columns.iter().zip(values) {
+            encode(column, value, &mut bytes)?;
+        }
+        Some(Key::Bytes(bytes))
+    }
+
+    pub fn int(self) -> Option<i64> {
+        match self {
+            Key::Int(key) => Some(key),
+            Key::Bytes(_) => None,
+        }
+    }
+
+    pub fn bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Key::Bytes(bytes) => Some(bytes),
+            Key::Int(_) => None,
+        }
+    }
+
+    // The values the key holds, a NOCASE TEXT in lower case.
+    pub fn values(&self) -> Vec<Value> {
+        let mut bytes = match self {
+            Key::Int(key) => return vec![Value::Int(*key)],
+            Key::Bytes(bytes) => bytes.as_slice(),
+        };
+        let mut values = Vec::new();
+        while let Some((value, len)) = decode(bytes) {
+            values.push(value);
+            bytes = &bytes[len..];
+        }
+        values
+    }
+}
+
+// As SQL would write the values, for messages.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self.values().iter().map(Value::to_string).collect();
+        write!(f, "{}", values.join(", "))
+    }
+}
+
+// Appends `value` as a key of `column` holds it, a TEXT folded as the
+// column's collation compares it. None when the value is not of the
+// column's type, which no key can hold. A TEXT ends in two zero bytes, and
+// a zero byte in it is followed by `AFTER`, so that no value's bytes start
+// another's and a shorter TEXT sorts first.
+pub fn encode(column: &ColumnInfo, value: &Value, out: &mut Vec<u8>) -> Option<()> {
+    match (&column.data_type, value) {
+        (DataType::Int, Value::Int(i)) => {
+            out.push(INT_TAG);
+            out.extend_from_slice(&((*i as u64) ^ (1 << 63)).to_be_bytes());
+        }
+        (DataType::String, Value::String(s)) => {
+            out.push(TEXT_TAG);
+            for b in s.bytes() {
+                let b = match column.collation {
+                    Collation::NoCase => b.to_ascii_lowercase(),
+                    Collation::Binary => b,
+                };
+                out.push(b);
+                if b == 0 {
+                    out.push(AFTER);
+                }
+            }
+            out.extend_from_slice(&[0, 0]);
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+// How many bytes the value `bytes` starts with takes, if they start with a
+// whole one.
+pub fn value_len(bytes: &[u8]) -> Option<usize> {
+    decode(bytes).map(|(_, len)| len)
+}
+
+fn decode(bytes: &[u8]) -> Option<(Value, usize)> {
+    match *bytes.first()? {
+        INT_TAG => {
+            let raw: [u8; 8] = bytes.get(1..9)?.try_into().ok()?;
+            Some((Value::Int((u64::from_be_bytes(raw) ^ (1 << 63)) as i64), 9))
+        }
+        TEXT_TAG => {
+            let mut text = Vec::new();
+            let mut i = 1;
+            loop {
+                match (*bytes.get(i)?, bytes.get(i + 1)) {
+                    (0, Some(0)) => break,
+                    (0, Some(&AFTER)) => {
+                        text.push(0);
+                        i += 2;
+                    }
+                    (0, _) => return None,
+                    (b, _) => {
+                        text.push(b);
+                        i += 1;
+                    }
+                }
+            }
+            Some((Value::String(String::from_utf8(text).ok()?), i + 2))
+        }
+        _ => None,
+    }
+}
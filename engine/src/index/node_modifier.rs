@@ -1,63 +1,61 @@
-
-
 use crate::index::bplustree_search::BPlusTreeSearch;
 use crate::index::node_serializer::{
-    InternalNodeSerializer, LeafNodeSerializer, NodeHeader, NodeType,
+    IndexKey, InternalNodeSerializer, LeafNodeSerializer, NodeHeader, NodeType,
 };
 use crate::storage::record::RID;
 use crate::storage::storage::Storage;
 use anyhow::{Context, Result};
 
-
-
-pub struct NodeModifier<'a> {
+pub struct NodeModifier<'a, K: IndexKey = u64> {
     storage: &'a mut Storage,
     order: usize,
-    internal_serializer: InternalNodeSerializer,
-    leaf_serializer: LeafNodeSerializer,
+    internal_serializer: InternalNodeSerializer<K>,
+    leaf_serializer: LeafNodeSerializer<K>,
     
     path_cache: Vec<u64>,
 }
 
-impl<'a> NodeModifier<'a> {
+impl<'a, K: IndexKey> NodeModifier<'a, K> {
     pub fn new(storage: &'a mut Storage, order: usize) -> Self {
         Self {
             storage,
             order,
-            internal_serializer: InternalNodeSerializer { order },
-            leaf_serializer: LeafNodeSerializer { order },
+            internal_serializer: InternalNodeSerializer::new(order),
+            leaf_serializer: LeafNodeSerializer::new(order),
             path_cache: Vec::new(),
         }
     }
 
-    
-    
-    pub fn insert(&mut self, root_page: u64, key: u64, rid: RID) -> Result<u64> {
-        
-        let mut searcher = BPlusTreeSearch::new(self.storage, self.order);
-        self.path_cache = searcher.search_path(root_page, key)?;
+    pub fn insert(&mut self, root_page: u64, key: K, rid: RID) -> Result<u64> {
+        let mut searcher = BPlusTreeSearch::<K>::new(self.storage, self.order);
+        self.path_cache = searcher.search_path(root_page, &key)?;
         let leaf_page = *self.path_cache.last().unwrap();
         
-        let (new_root, _, _) = self.insert_into_leaf(leaf_page, key, rid, root_page)?;
-        Ok(new_root)
+        self.insert_into_leaf(leaf_page, key, rid, root_page)
     }
 
     fn insert_into_leaf(
         &mut self,
         leaf_page: u64,
-        key: u64,
+        key: K,
         rid: RID,
         root_page: u64,
-    ) -> Result<(u64, Option<u64>, Option<u64>)> {
-        
+    ) -> Result<u64> {
         let frame = self.storage.buffer_pool.fetch_page(leaf_page)?;
         let buf = &frame.data;
-        let (mut header, mut keys, mut rids, next_leaf) = self
-            .leaf_serializer
-            .deserialize(buf)
-            .context("Leaf deserialize failed")?;
+        let (mut header, mut keys, mut rids, next_leaf) =
+            match self.leaf_serializer.deserialize(buf) {
+                Ok(node) => node,
+                Err(e) => {
+                    self.storage.buffer_pool.unpin_page(leaf_page, false);
+                    return Err(e).with_context(|| {
+                        format!("Leaf deserialize failed for page {}", leaf_page)
+                    });
+                }
+            };
         
         if keys.binary_search(&key).is_ok() {
+            self.storage.buffer_pool.unpin_page(leaf_page, false);
             return Err(anyhow::anyhow!("Duplicate key insertion not allowed"));
         }
         
@@ -66,14 +64,14 @@ impl<'a> NodeModifier<'a> {
         rids.insert(idx, rid);
         header.key_count += 1;
 
-        
         header.parent = *self
             .path_cache
             .get(self.path_cache.len().saturating_sub(2))
             .unwrap_or(&0);
 
-        if (header.key_count as usize) <= self.order {
-            
+        if (header.key_count as usize) <= self.order
+            && LeafNodeSerializer::encoded_size(&keys) <= self.storage.page_size
+        {
             let new_buf = self.leaf_serializer.serialize(
                 &header,
                 &keys,
@@ -83,24 +81,17 @@ impl<'a> NodeModifier<'a> {
             );
             frame.data.copy_from_slice(&new_buf);
             self.storage.buffer_pool.unpin_page(leaf_page, true);
-            
-            let used_space = new_buf.len();
-            let free_space = self.storage.page_size.saturating_sub(used_space);
-            self.storage.free_list.register(leaf_page, free_space);
-            Ok((root_page, None, None))
+            Ok(root_page)
         } else {
-            
             self.storage.buffer_pool.unpin_page(leaf_page, false);
 
-            let mid = (header.key_count as usize + 1) / 2;
+            let mid = (header.key_count as usize).div_ceil(2);
             let right_keys = keys.split_off(mid);
             let right_rids = rids.split_off(mid);
             header.key_count = keys.len() as u16;
-            let split_key = right_keys[0];
+            let split_key = right_keys[0].clone();
 
-            
             let right_page = self.storage.buffer_pool.pagefile.allocate_page()?;
-
             
             let right_header = NodeHeader {
                 node_type: NodeType::Leaf,
@@ -123,26 +114,16 @@ impl<'a> NodeModifier<'a> {
                 self.storage.page_size,
             );
 
-            
             let left_frame = self.storage.buffer_pool.fetch_page(leaf_page)?;
             left_frame.data.copy_from_slice(&left_buf);
             self.storage.buffer_pool.unpin_page(leaf_page, true);
-            let left_free_space = self.storage.page_size.saturating_sub(left_buf.len());
-            self.storage.free_list.register(leaf_page, left_free_space);
-
             
             let right_frame = self.storage.buffer_pool.fetch_page(right_page)?;
             right_frame.data.copy_from_slice(&right_buf);
             self.storage.buffer_pool.unpin_page(right_page, true);
-            let right_free_space = self.storage.page_size.saturating_sub(right_buf.len());
-            self.storage
-                .free_list
-                .register(right_page, right_free_space);
-
             
-            let (new_root, _, _) =
-                self.insert_into_parent(root_page, leaf_page, split_key, right_page)?;
-            Ok((new_root, Some(split_key), Some(right_page)))
+            let depth = self.path_cache.len() - 1;
+            self.insert_into_parent(root_page, leaf_page, split_key, right_page, depth)
         }
     }
 
@@ -150,10 +131,10 @@ impl<'a> NodeModifier<'a> {
         &mut self,
         root_page: u64,
         left_page: u64,
-        split_key: u64,
+        split_key: K,
         right_page: u64,
-    ) -> Result<(u64, Option<u64>, Option<u64>)> {
-        
+        depth: usize,
+    ) -> Result<u64> {
         if left_page == root_page {
             let new_root = self.storage.buffer_pool.pagefile.allocate_page()?;
             let header = NodeHeader {
@@ -163,28 +144,33 @@ impl<'a> NodeModifier<'a> {
             };
             let buf = self.internal_serializer.serialize(
                 &header,
-                &[split_key],
+                std::slice::from_ref(&split_key),
                 &[left_page, right_page],
                 self.storage.page_size,
             );
             let frame = self.storage.buffer_pool.fetch_page(new_root)?;
             frame.data.copy_from_slice(&buf);
             self.storage.buffer_pool.unpin_page(new_root, true);
-            
-            let free_space = self.storage.page_size.saturating_sub(buf.len());
-            self.storage.free_list.register(new_root, free_space);
-            Ok((new_root, Some(split_key), Some(right_page)))
+            self.set_parent(left_page, new_root)?;
+            self.set_parent(right_page, new_root)?;
+            Ok(new_root)
         } else {
-            
-            let parent_page = *self.path_cache.get(self.path_cache.len() - 2).unwrap();
+            let parent_page = self.path_cache[depth - 1];
             let frame = self.storage.buffer_pool.fetch_page(parent_page)?;
             let buf = &frame.data;
-            let (mut header, mut keys, mut children) = self
-                .internal_serializer
-                .deserialize(buf)
-                .context("Internal deserialize failed")?;
+            let (mut header, mut keys, mut children) =
+                match self.internal_serializer.deserialize(buf) {
+                    Ok(node) => node,
+                    Err(e) => {
+                        self.storage.buffer_pool.unpin_page(parent_page, false);
+                        return Err(e).with_context(|| {
+                            format!("Internal deserialize failed for page {}", parent_page)
+                        });
+                    }
+                };
             
-            if keys.iter().any(|&k| k == split_key) {
+            if keys.contains(&split_key) {
+                self.storage.buffer_pool.unpin_page(parent_page, false);
                 return Err(anyhow::anyhow!(
                     "Duplicate key insertion not allowed in internal node"
                 ));
@@ -194,12 +180,15 @@ impl<'a> NodeModifier<'a> {
             keys.insert(idx - 1, split_key);
             children.insert(idx, right_page);
             header.key_count += 1;
-            header.parent = *self
-                .path_cache
-                .get(self.path_cache.len().saturating_sub(3))
-                .unwrap_or(&0);
+            header.parent = if depth >= 2 {
+                self.path_cache[depth - 2]
+            } else {
+                0
+            };
 
-            if (header.key_count as usize) <= self.order {
+            if (header.key_count as usize) <= self.order
+                && InternalNodeSerializer::encoded_size(&keys) <= self.storage.page_size
+            {
                 let new_buf = self.internal_serializer.serialize(
                     &header,
                     &keys,
@@ -208,23 +197,19 @@ impl<'a> NodeModifier<'a> {
                 );
                 frame.data.copy_from_slice(&new_buf);
                 self.storage.buffer_pool.unpin_page(parent_page, true);
-                let free_space = self.storage.page_size.saturating_sub(new_buf.len());
-                self.storage.free_list.register(parent_page, free_space);
-                Ok((root_page, None, None))
+                Ok(root_page)
             } else {
-                
                 self.storage.buffer_pool.unpin_page(parent_page, false);
 
                 let mid = header.key_count as usize / 2;
-                let promote_key = keys[mid];
+                let promote_key = keys[mid].clone();
                 let right_keys = keys.split_off(mid + 1);
                 let right_children = children.split_off(mid + 1);
                 header.key_count = mid as u16;
+                keys.truncate(mid);
                 children.truncate(mid + 1);
 
-                
                 let new_right_page = self.storage.buffer_pool.pagefile.allocate_page()?;
-
                 
                 let left_buf = self.internal_serializer.serialize(
                     &header,
@@ -233,15 +218,9 @@ impl<'a> NodeModifier<'a> {
                     self.storage.page_size,
                 );
 
-                
                 let left_frame = self.storage.buffer_pool.fetch_page(parent_page)?;
                 left_frame.data.copy_from_slice(&left_buf);
                 self.storage.buffer_pool.unpin_page(parent_page, true);
-                let left_free_space = self.storage.page_size.saturating_sub(left_buf.len());
-                self.storage
-                    .free_list
-                    .register(parent_page, left_free_space);
-
                 
                 let right_header = NodeHeader {
                     node_type: NodeType::Internal,
@@ -257,14 +236,33 @@ impl<'a> NodeModifier<'a> {
                 let right_frame = self.storage.buffer_pool.fetch_page(new_right_page)?;
                 right_frame.data.copy_from_slice(&right_buf);
                 self.storage.buffer_pool.unpin_page(new_right_page, true);
-                let right_free_space = self.storage.page_size.saturating_sub(right_buf.len());
-                self.storage
-                    .free_list
-                    .register(new_right_page, right_free_space);
+                for &child in &right_children {
+                    self.set_parent(child, new_right_page)?;
+                }
 
-                
-                self.insert_into_parent(root_page, parent_page, promote_key, new_right_page)
+                self.insert_into_parent(
+                    root_page,
+                    parent_page,
+                    promote_key,
+                    new_right_page,
+                    depth - 1,
+                )
             }
         }
     }
+
+    fn set_parent(&mut self, page: u64, parent: u64) -> Result<()> {
+        let frame = self.storage.buffer_pool.fetch_page(page)?;
+        let mut header = match NodeHeader::deserialize(&frame.data) {
+            Ok(h) => h,
+            Err(e) => {
+                self.storage.buffer_pool.unpin_page(page, false);
+                return Err(e).with_context(|| format!("Reading header of page {}", page));
+            }
+        };
+        header.parent = parent;
+        header.serialize(&mut frame.data[0..NodeHeader::SIZE]);
+        self.storage.buffer_pool.unpin_page(page, true);
+        Ok(())
+    }
 }
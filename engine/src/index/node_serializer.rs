@@ -1,7 +1,6 @@
-
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Cursor, Result};
-
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use std::fmt;
+use std::marker::PhantomData;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NodeType {
@@ -9,13 +8,37 @@ pub enum NodeType {
     Leaf = 1,
 }
 
-
 pub struct NodeHeader {
     pub node_type: NodeType, 
     pub key_count: u16,      
     pub parent: u64,         
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeError {
+    InvalidNodeType(u8),
+    UnexpectedNodeType { expected: NodeType, got: NodeType },
+    TruncatedNode { expected: usize, got: usize },
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeError::InvalidNodeType(b) => write!(f, "invalid node type byte {}", b),
+            NodeError::UnexpectedNodeType { expected, got } => {
+                write!(f, "expected {:?} node, found {:?}", expected, got)
+            }
+            NodeError::TruncatedNode { expected, got } => write!(
+                f,
+                "truncated node: need {} bytes, page has {}",
+                expected, got
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
 impl NodeHeader {
     pub const SIZE: usize = 1 + 2 + 8;
 
@@ -29,21 +52,20 @@ impl NodeHeader {
             .unwrap();
     }
 
-    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+    pub fn deserialize(buf: &[u8]) -> Result<Self, NodeError> {
+        if buf.len() < Self::SIZE {
+            return Err(NodeError::TruncatedNode {
+                expected: Self::SIZE,
+                got: buf.len(),
+            });
+        }
         let node_type = match buf[0] {
             0 => NodeType::Internal,
             1 => NodeType::Leaf,
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid node type",
-                ));
-            }
+            other => return Err(NodeError::InvalidNodeType(other)),
         };
-        let mut rdr = Cursor::new(&buf[1..3]);
-        let key_count = rdr.read_u16::<LittleEndian>()?;
-        let mut rdr2 = Cursor::new(&buf[3..11]);
-        let parent = rdr2.read_u64::<LittleEndian>()?;
+        let key_count = LittleEndian::read_u16(&buf[1..3]);
+        let parent = LittleEndian::read_u64(&buf[3..11]);
         Ok(NodeHeader {
             node_type,
             key_count,
@@ -52,57 +74,175 @@ impl NodeHeader {
     }
 }
 
+pub trait IndexKey: Clone + Ord + fmt::Debug {
+    const FIXED_LEN: Option<usize>;
+
+    fn encoded_len(&self) -> usize;
+
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), NodeError>;
+}
+
+impl IndexKey for u64 {
+    const FIXED_LEN: Option<usize> = Some(8);
+
+    fn encoded_len(&self) -> usize {
+        8
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), NodeError> {
+        expect_len(buf, 8)?;
+        Ok((BigEndian::read_u64(buf), 8))
+    }
+}
+
+impl IndexKey for i64 {
+    const FIXED_LEN: Option<usize> = Some(8);
+
+    fn encoded_len(&self) -> usize {
+        8
+    }
+
+    // Flipping the sign bit makes the big-endian bytes sort like the signed value.
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&((*self as u64) ^ (1 << 63)).to_be_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), NodeError> {
+        expect_len(buf, 8)?;
+        Ok(((BigEndian::read_u64(buf) ^ (1 << 63)) as i64, 8))
+    }
+}
+
+impl IndexKey for Vec<u8> {
+    const FIXED_LEN: Option<usize> = None;
+
+    fn encoded_len(&self) -> usize {
+        2 + self.len()
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.len() as u16).to_le_bytes());
+        out.extend_from_slice(self);
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), NodeError> {
+        expect_len(buf, 2)?;
+        let len = LittleEndian::read_u16(buf) as usize;
+        expect_len(buf, 2 + len)?;
+        Ok((buf[2..2 + len].to_vec(), 2 + len))
+    }
+}
+
+impl<A: IndexKey, B: IndexKey> IndexKey for (A, B) {
+    const FIXED_LEN: Option<usize> = match (A::FIXED_LEN, B::FIXED_LEN) {
+        (Some(a), Some(b)) => Some(a + b),
+        _ => None,
+    };
+
+    fn encoded_len(&self) -> usize {
+        self.0.encoded_len() + self.1.encoded_len()
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), NodeError> {
+        let (a, used_a) = A::decode(buf)?;
+        let (b, used_b) = B::decode(&buf[used_a..])?;
+        Ok(((a, b), used_a + used_b))
+    }
+}
+
+fn min_key_len<K: IndexKey>() -> usize {
+    K::FIXED_LEN.unwrap_or(3)
+}
 
-pub struct InternalNodeSerializer {
+fn decode_keys<K: IndexKey>(
+    buf: &[u8],
+    pos: &mut usize,
+    count: usize,
+) -> Result<Vec<K>, NodeError> {
+    let mut keys = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (key, used) = K::decode(&buf[*pos..]).map_err(|_| NodeError::TruncatedNode {
+            expected: *pos + min_key_len::<K>(),
+            got: buf.len(),
+        })?;
+        keys.push(key);
+        *pos += used;
+    }
+    Ok(keys)
+}
+
+pub struct InternalNodeSerializer<K = u64> {
     pub order: usize,
+    _key: PhantomData<K>,
 }
 
-impl InternalNodeSerializer {
+impl<K: IndexKey> InternalNodeSerializer<K> {
+    pub fn new(order: usize) -> Self {
+        Self {
+            order,
+            _key: PhantomData,
+        }
+    }
+
+    pub fn max_keys(page_size: usize) -> usize {
+        page_size.saturating_sub(NodeHeader::SIZE + 8) / (min_key_len::<K>() + 8)
+    }
+
+    pub fn encoded_size(keys: &[K]) -> usize {
+        NodeHeader::SIZE + keys.iter().map(K::encoded_len).sum::<usize>() + 8 * (keys.len() + 1)
+    }
+
     
     
     pub fn serialize(
         &self,
         header: &NodeHeader,
-        keys: &[u64],     
+        keys: &[K],     
         children: &[u64], 
         page_size: usize,
     ) -> Vec<u8> {
-        let mut buf = vec![0u8; page_size];
+        let mut buf = vec![0u8; NodeHeader::SIZE];
         header.serialize(&mut buf[0..NodeHeader::SIZE]);
-        let mut pos = NodeHeader::SIZE;
         
-        for &key in keys.iter() {
-            (&mut buf[pos..pos + 8])
-                .write_u64::<LittleEndian>(key)
-                .unwrap();
-            pos += 8;
+        for key in keys.iter() {
+            key.encode(&mut buf);
         }
         
         for &child in children.iter() {
-            (&mut buf[pos..pos + 8])
-                .write_u64::<LittleEndian>(child)
-                .unwrap();
-            pos += 8;
+            buf.write_u64::<LittleEndian>(child).unwrap();
         }
+        debug_assert!(buf.len() <= page_size, "node does not fit in a page");
+        buf.resize(page_size, 0);
         buf
     }
 
     
-    pub fn deserialize(&self, buf: &[u8]) -> Result<(NodeHeader, Vec<u64>, Vec<u64>)> {
-        let header = NodeHeader::deserialize(&buf[0..NodeHeader::SIZE])?;
-        assert_eq!(header.node_type, NodeType::Internal);
+    pub fn deserialize(&self, buf: &[u8]) -> Result<(NodeHeader, Vec<K>, Vec<u64>), NodeError> {
+        let header = NodeHeader::deserialize(buf)?;
+        expect_type(&header, NodeType::Internal)?;
+        let key_count = header.key_count as usize;
+        let child_count = key_count + 1;
+        expect_len(
+            buf,
+            NodeHeader::SIZE + (min_key_len::<K>() + 8) * key_count + 8,
+        )?;
         let mut pos = NodeHeader::SIZE;
-        let mut keys = Vec::with_capacity(header.key_count as usize);
-        for _ in 0..header.key_count {
-            let key = (&buf[pos..pos + 8]).read_u64::<LittleEndian>()?;
-            keys.push(key);
-            pos += 8;
-        }
-        let child_count = (header.key_count as usize) + 1;
+        let keys = decode_keys(buf, &mut pos, key_count)?;
+        expect_len(buf, pos + 8 * child_count)?;
         let mut children = Vec::with_capacity(child_count);
         for _ in 0..child_count {
-            let child = (&buf[pos..pos + 8]).read_u64::<LittleEndian>()?;
-            children.push(child);
+            children.push(LittleEndian::read_u64(&buf[pos..pos + 8]));
             pos += 8;
         }
         Ok((header, keys, children))
@@ -110,69 +250,98 @@ impl InternalNodeSerializer {
 }
 
 
-pub struct LeafNodeSerializer {
+pub type LeafNode<K = u64> = (NodeHeader, Vec<K>, Vec<(u64, u16)>, u64);
+
+
+pub struct LeafNodeSerializer<K = u64> {
     pub order: usize,
+    _key: PhantomData<K>,
 }
 
-impl LeafNodeSerializer {
+impl<K: IndexKey> LeafNodeSerializer<K> {
+    pub fn new(order: usize) -> Self {
+        Self {
+            order,
+            _key: PhantomData,
+        }
+    }
+
+    pub fn max_keys(page_size: usize) -> usize {
+        page_size.saturating_sub(NodeHeader::SIZE + 8) / (min_key_len::<K>() + 10)
+    }
+
+    pub fn encoded_size(keys: &[K]) -> usize {
+        NodeHeader::SIZE + keys.iter().map(K::encoded_len).sum::<usize>() + 10 * keys.len() + 8
+    }
+
     
     
     pub fn serialize(
         &self,
         header: &NodeHeader,
-        keys: &[u64],        
+        keys: &[K],        
         rids: &[(u64, u16)], 
         next_leaf: u64,
         page_size: usize,
     ) -> Vec<u8> {
-        let mut buf = vec![0u8; page_size];
+        let mut buf = vec![0u8; NodeHeader::SIZE];
         header.serialize(&mut buf[0..NodeHeader::SIZE]);
-        let mut pos = NodeHeader::SIZE;
         
-        for &key in keys.iter() {
-            (&mut buf[pos..pos + 8])
-                .write_u64::<LittleEndian>(key)
-                .unwrap();
-            pos += 8;
+        for key in keys.iter() {
+            key.encode(&mut buf);
         }
         
         for &(page_no, slot_no) in rids.iter() {
-            (&mut buf[pos..pos + 8])
-                .write_u64::<LittleEndian>(page_no)
-                .unwrap();
-            pos += 8;
-            (&mut buf[pos..pos + 2])
-                .write_u16::<LittleEndian>(slot_no)
-                .unwrap();
-            pos += 2;
+            buf.write_u64::<LittleEndian>(page_no).unwrap();
+            buf.write_u16::<LittleEndian>(slot_no).unwrap();
         }
         
-        (&mut buf[pos..pos + 8])
-            .write_u64::<LittleEndian>(next_leaf)
-            .unwrap();
+        buf.write_u64::<LittleEndian>(next_leaf).unwrap();
+        debug_assert!(buf.len() <= page_size, "node does not fit in a page");
+        buf.resize(page_size, 0);
         buf
     }
 
     
-    pub fn deserialize(&self, buf: &[u8]) -> Result<(NodeHeader, Vec<u64>, Vec<(u64, u16)>, u64)> {
-        let header = NodeHeader::deserialize(&buf[0..NodeHeader::SIZE])?;
-        assert_eq!(header.node_type, NodeType::Leaf);
+    pub fn deserialize(&self, buf: &[u8]) -> Result<LeafNode<K>, NodeError> {
+        let header = NodeHeader::deserialize(buf)?;
+        expect_type(&header, NodeType::Leaf)?;
+        let key_count = header.key_count as usize;
+        expect_len(
+            buf,
+            NodeHeader::SIZE + (min_key_len::<K>() + 10) * key_count + 8,
+        )?;
         let mut pos = NodeHeader::SIZE;
-        let mut keys = Vec::with_capacity(header.key_count as usize);
-        for _ in 0..header.key_count {
-            let key = (&buf[pos..pos + 8]).read_u64::<LittleEndian>()?;
-            keys.push(key);
-            pos += 8;
-        }
-        let mut rids = Vec::with_capacity(header.key_count as usize);
-        for _ in 0..header.key_count {
-            let page_no = (&buf[pos..pos + 8]).read_u64::<LittleEndian>()?;
-            pos += 8;
-            let slot_no = (&buf[pos..pos + 2]).read_u16::<LittleEndian>()?;
-            pos += 2;
+        let keys = decode_keys(buf, &mut pos, key_count)?;
+        expect_len(buf, pos + 10 * key_count + 8)?;
+        let mut rids = Vec::with_capacity(key_count);
+        for _ in 0..key_count {
+            let page_no = LittleEndian::read_u64(&buf[pos..pos + 8]);
+            let slot_no = LittleEndian::read_u16(&buf[pos + 8..pos + 10]);
+            pos += 10;
             rids.push((page_no, slot_no));
         }
-        let next_leaf = (&buf[pos..pos + 8]).read_u64::<LittleEndian>()?;
+        let next_leaf = LittleEndian::read_u64(&buf[pos..pos + 8]);
         Ok((header, keys, rids, next_leaf))
     }
 }
+
+fn expect_type(header: &NodeHeader, expected: NodeType) -> Result<(), NodeError> {
+    if header.node_type != expected {
+        return Err(NodeError::UnexpectedNodeType {
+            expected,
+            got: header.node_type,
+        });
+    }
+    Ok(())
+}
+
+fn expect_len(buf: &[u8], expected: usize) -> Result<(), NodeError> {
+    if buf.len() < expected {
+        return Err(NodeError::TruncatedNode {
+            expected,
+            got: buf.len(),
+        });
+    }
+    Ok(())
+}
@@ -1,41 +1,94 @@
 
 pub mod cli {
+    pub mod args;
+    pub mod backup;
+    pub mod check;
+    pub mod completion;
+    pub mod dump;
+    pub mod import;
+    pub mod init;
+    pub mod migrate;
     pub mod shell;
     pub mod utils;
+    pub mod waldump;
 }
 
 pub mod net {
+    pub mod admission;
+    pub mod audit;
+    pub mod auth;
+    pub mod binary;
     pub mod client;
+    pub mod compression;
+    pub mod copy;
+    pub mod csv_io;
+    pub mod metrics;
+    pub mod queries;
+    pub mod replication;
+    pub mod result_cache;
+    pub mod row;
+    pub mod schema;
     pub mod server;
+    pub mod session;
+    pub mod settings;
 }
 
 pub mod storage {
+    pub mod atomic_file;
+    pub mod backup;
     pub mod buffer_pool;
+    pub mod check;
+    pub mod column_stats;
+    pub mod failpoint;
+    pub mod format;
     pub mod free_list;
+    pub mod index_build;
+    pub mod legacy;
     pub mod pagefile;
     pub mod record;
+    pub mod sequence;
+    #[allow(clippy::module_inception)]
     pub mod storage;
 }
 
 pub mod index {
+    pub mod bloom;
     pub mod bplustree;
     pub mod bplustree_search;
+    pub mod hash_index;
+    pub mod key;
     pub mod node_modifier;
     pub mod node_serializer;
 }
 
 pub mod tx {
+    pub mod history;
     pub mod lock_manager;
     pub mod log_manager;
+    pub mod mvcc;
     pub mod recovery_manager;
+    pub mod wal_reader;
 }
 
 pub mod query {
     pub mod binder;
+    pub mod datetime;
     pub mod executor;
     pub mod lexer;
+    pub mod memory;
     pub mod optimizer;
+    pub mod params;
     pub mod parser;
+    pub mod pipeline;
     pub mod physical_planner;
     pub mod planner;
+    pub mod privileges;
+    pub mod random;
+    pub mod source;
+    pub mod value;
 }
+
+pub mod database;
+
+#[cfg(feature = "testing")]
+pub mod testing;
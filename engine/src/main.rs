@@ -1,36 +1,150 @@
 
 use anyhow::Context;
-use engine::{cli::shell::run_shell, storage::storage::Storage};
-use std::{net::SocketAddr, path::PathBuf};
+use engine::{
+    cli::{
+        args::{BackupArgs, DumpArgs, InitArgs, MigrateArgs, ServerArgs, ShellArgs},
+        backup::run_backup,
+        check::{CheckArgs, run_check},
+        dump::run_dump,
+        init::run_init,
+        migrate::run_migrate,
+        shell::{exit_code, run_shell},
+        waldump::{WaldumpArgs, dump},
+    },
+    storage::{format::FileHeader, storage::Storage},
+};
 use tokio::runtime::Runtime;
 
 
-use engine::net::server::run_server;
+use engine::net::{
+    auth::{ADMIN_PASSWORD_ENV, Secret},
+    replication::{DEFAULT_POLL_INTERVAL, STANDBY_PASSWORD_ENV, StandbyConfig},
+    server::{ServerConfig, run_server},
+};
 
 fn main() -> anyhow::Result<()> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: {} <server|shell>", args[0]);
+        eprintln!(
+            "Usage: {} <init|server|shell|dump|backup|waldump|check|migrate> [options]",
+            args[0]
+        );
         std::process::exit(1);
     }
 
     match args[1].as_str() {
         "server" => {
-            let addr: SocketAddr = "127.0.0.1:3000"
-                .parse()
-                .context("Failed to parse server address")?;
-            let storage =
-                Storage::new("data.db", 4096, 10).context("Failed to initialize storage")?;
-            let wal = PathBuf::from("wal.log");
+            let args = ServerArgs::parse(&args[2..])?;
+            let data_file = args.data_file();
+            match FileHeader::read(&data_file)? {
+                None => anyhow::bail!(
+                    "No database in {:?}; create one with `mydb init --data-dir {}`",
+                    args.data_dir,
+                    args.data_dir.display()
+                ),
+                Some(header) if header.page_size != args.page_size => anyhow::bail!(
+                    "{:?} has {}-byte pages; start the server with --page-size {}",
+                    data_file,
+                    header.page_size,
+                    header.page_size
+                ),
+                Some(_) => {}
+            }
+            let mut storage = Storage::new(
+                data_file
+                    .to_str()
+                    .context("Data directory is not valid UTF-8")?,
+                args.page_size,
+                args.pool_size,
+            )
+            .context("Failed to initialize storage")?;
+            storage.max_columns = args.max_columns;
+
+            let standby_of = match args.standby_of.clone() {
+                Some(primary) => Some(StandbyConfig {
+                    primary,
+                    user: args.standby_user.clone(),
+                    password: std::env::var(STANDBY_PASSWORD_ENV)
+                        .map(Secret)
+                        .with_context(|| {
+                            format!("{} must be set to follow a primary", STANDBY_PASSWORD_ENV)
+                        })?,
+                    poll_interval: DEFAULT_POLL_INTERVAL,
+                }),
+                None => None,
+            };
+            let config = ServerConfig {
+                admin_password: std::env::var(ADMIN_PASSWORD_ENV).ok().map(Secret),
+                session_ttl: Some(args.session_ttl),
+                metrics_require_login: args.metrics_login,
+                query_timeout: Some(args.query_timeout),
+                max_body_bytes: Some(args.max_body_bytes),
+                log_filter: Some(args.log_level),
+                max_running_queries: Some(args.max_queries),
+                when_busy: args.when_busy,
+                rate_limit: args.rate_limit,
+                standby_of,
+                result_cache_bytes: Some(args.result_cache_bytes),
+                work_mem: Some(args.work_mem),
+                history_window_bytes: args.history_window_bytes,
+                read_only: args.read_only,
+                config_file: args.config,
+                audit_log: args.audit_log,
+                auto_analyze: Some(args.auto_analyze),
+                ..ServerConfig::default()
+            };
 
             let rt = Runtime::new().context("Failed to create Tokio runtime")?;
 
-            rt.block_on(async { run_server(addr, storage, wal).await })?;
+            rt.block_on(async { run_server(args.listen, storage, args.wal, config).await })?;
+        }
+        "init" => {
+            let args = InitArgs::parse(&args[2..])?;
+            let admin_password = std::env::var(ADMIN_PASSWORD_ENV).ok().map(Secret);
+            run_init(&args, admin_password, &mut std::io::stdout().lock())?;
         }
         "shell" => {
+            let args = ShellArgs::parse(&args[2..])?;
+            let rt = Runtime::new().context("Failed to create Tokio runtime")?;
+
+            // Errors go to stderr, leaving stdout to the results.
+            if let Err(e) = rt.block_on(async { run_shell(&args).await }) {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(exit_code(&e));
+            }
+        }
+        "dump" => {
+            let args = DumpArgs::parse(&args[2..])?;
             let rt = Runtime::new().context("Failed to create Tokio runtime")?;
 
-            rt.block_on(async { run_shell("http://127.0.0.1:3000").await })?;
+            if let Err(e) = rt.block_on(async { run_dump(&args).await }) {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(exit_code(&e));
+            }
+        }
+        "backup" => {
+            let args = BackupArgs::parse(&args[2..])?;
+            let rt = Runtime::new().context("Failed to create Tokio runtime")?;
+
+            let run = rt.block_on(async { run_backup(&args, &mut std::io::stdout()).await });
+            if let Err(e) = run {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(exit_code(&e));
+            }
+        }
+        "waldump" => {
+            let args = WaldumpArgs::parse(&args[2..])?;
+            dump(&args, &mut std::io::stdout().lock())?;
+        }
+        "check" => {
+            let args = CheckArgs::parse(&args[2..])?;
+            if !run_check(&args, &mut std::io::stdout().lock())?.is_ok() {
+                std::process::exit(1);
+            }
+        }
+        "migrate" => {
+            let args = MigrateArgs::parse(&args[2..])?;
+            run_migrate(&args, &mut std::io::stdout().lock())?;
         }
         other => {
             eprintln!("Unknown command: {}", other);
@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Sessions tracked before idle buckets are dropped. A bucket that has not
+// been used for a second is full again, the same as a new one.
+const BUCKETS_BEFORE_PRUNE: usize = 1024;
+
+// What happens to a query that arrives while the most allowed are running.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhenBusy {
+    // Turned away at once with 503.
+    #[default]
+    Reject,
+    // Waits for a slot, for as long as it would be allowed to run.
+    Queue,
+}
+
+impl WhenBusy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "reject" => Some(WhenBusy::Reject),
+            "queue" => Some(WhenBusy::Queue),
+            _ => None,
+        }
+    }
+}
+
+// Bounds how many statements run at once and, optionally, how many requests
+// a session may make per second.
+pub struct Admission {
+    slots: Arc<Semaphore>,
+    max_running: usize,
+    when_busy: WhenBusy,
+    // 0 for no limit.
+    rate_limit: AtomicU32,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    rejected_busy: AtomicU64,
+    rejected_rate: AtomicU64,
+}
+
+// A session's requests per second, with one second's worth to burst.
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+// Held for as long as a statement runs.
+pub struct Permit {
+    _slot: OwnedSemaphorePermit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    Busy,
+    RateLimited { retry_after: Duration },
+}
+
+impl Refused {
+    // Whole seconds, as the Retry-After header wants them.
+    pub fn retry_after_secs(&self) -> u64 {
+        match self {
+            Refused::Busy => 1,
+            Refused::RateLimited { retry_after } => {
+                retry_after.as_secs_f64().ceil().max(1.0) as u64
+            }
+        }
+    }
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refused::Busy => f.write_str("Too many statements are running, try again later"),
+            Refused::RateLimited { .. } => f.write_str("Too many requests, slow down"),
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(max_running: usize, when_busy: WhenBusy, rate_limit: Option<u32>) -> Self {
+        Admission {
+            slots: Arc::new(Semaphore::new(max_running)),
+            max_running,
+            when_busy,
+            rate_limit: AtomicU32::new(rate_limit.unwrap_or(0)),
+            buckets: Mutex::new(HashMap::new()),
+            rejected_busy: AtomicU64::new(0),
+            rejected_rate: AtomicU64::new(0),
+        }
+    }
+
+    // A slot to run a statement in. A queued statement gives up after
+    // `wait`, its timeout, since by then it would have been cancelled anyway.
+    pub async fn admit(&self, wait: Duration) -> Result<Permit, Refused> {
+        let slot = match self.when_busy {
+            WhenBusy::Reject => self.slots.clone().try_acquire_owned().ok(),
+            WhenBusy::Queue => tokio::time::timeout(wait, self.slots.clone().acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+        };
+        match slot {
+            Some(slot) => Ok(Permit { _slot: slot }),
+            None => {
+                self.rejected_busy.fetch_add(1, Ordering::Relaxed);
+                Err(Refused::Busy)
+            }
+        }
+    }
+
+    // Takes a token from the session's bucket, if there is a limit.
+    pub fn check_rate(&self, session: &str) -> Result<(), Refused> {
+        let rate = match self.rate_limit.load(Ordering::Relaxed) {
+            0 => return Ok(()),
+            rate => rate as f64,
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= BUCKETS_BEFORE_PRUNE && !buckets.contains_key(session) {
+            buckets.retain(|_, b| now.duration_since(b.refilled) < Duration::from_secs(1));
+        }
+        let bucket = buckets.entry(session.to_string()).or_insert(Bucket {
+            tokens: rate,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        self.rejected_rate.fetch_add(1, Ordering::Relaxed);
+        Err(Refused::RateLimited {
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+        })
+    }
+
+    // Buckets fuller than the new rate allows are cut down on their next use.
+    pub fn set_rate_limit(&self, rate_limit: Option<u32>) {
+        self.rate_limit
+            .store(rate_limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_running - self.slots.available_permits()
+    }
+
+    pub fn rejected_busy(&self) -> u64 {
+        self.rejected_busy.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_rate(&self) -> u64 {
+        self.rejected_rate.load(Ordering::Relaxed)
+    }
+}
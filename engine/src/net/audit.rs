@@ -0,0 +1,196 @@
+use crate::net::metrics::statement_kind;
+use crate::query::parser::Statement;
+use crate::query::pipeline::{changes_data, subqueries, table_of};
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError, sync_channel},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, warn};
+
+// Entries waiting for the writer. Past this many a statement's entry is
+// dropped rather than the statement waiting, so this is also the most a
+// crash can lose.
+pub const AUDIT_QUEUE_ENTRIES: usize = 4096;
+
+// The audit trail: one JSON line per DDL, DML or account statement, appended
+// to a file by a thread of its own. Statements only ever queue their entry.
+// One that finds the queue full drops it; the next entry written says how
+// many went missing before it.
+pub struct AuditLog {
+    queue: Option<SyncSender<AuditEntry>>,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    // Milliseconds since the Unix epoch, when the statement arrived.
+    pub at_ms: u64,
+    pub user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    // As /metrics counts statements: `insert`, `ddl`, `user` and so on.
+    pub kind: &'static str,
+    // Tables, views, sequences, schemas, indexes and users, as the
+    // statement names them.
+    pub objects: Vec<String>,
+    // `ok` or `failed`, with why in `error`.
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Entries dropped since the last one written, when there were any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped_before: Option<u64>,
+}
+
+impl AuditEntry {
+    fn new(user: &str, tag: Option<&str>, kind: &'static str, objects: Vec<String>) -> Self {
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        AuditEntry {
+            at_ms,
+            user: user.to_string(),
+            tag: tag.map(str::to_string),
+            kind,
+            objects,
+            outcome: "ok",
+            error: None,
+            dropped_before: None,
+        }
+    }
+
+    pub fn finish(mut self, error: Option<&str>) -> Self {
+        self.outcome = match error {
+            Some(_) => "failed",
+            None => "ok",
+        };
+        self.error = error.map(str::to_string);
+        self
+    }
+}
+
+// The entry `stmt` is audited with, or None for one that changes nothing.
+pub fn entry(user: &str, tag: Option<&str>, stmt: &Statement) -> Option<AuditEntry> {
+    let kind = statement_kind(stmt);
+    if !changes_data(stmt) && kind != "user" {
+        return None;
+    }
+    Some(AuditEntry::new(user, tag, kind, objects(stmt)))
+}
+
+// The entry for a CSV import into `table`, which has no statement.
+pub fn import(user: &str, tag: Option<&str>, table: &str) -> AuditEntry {
+    AuditEntry::new(user, tag, "insert", vec![table.to_string()])
+}
+
+fn objects(stmt: &Statement) -> Vec<String> {
+    let mut objects: Vec<String> = match stmt {
+        Statement::RenameTable { table, new_name } => vec![table.clone(), new_name.clone()],
+        Statement::RenameIndex {
+            index_name,
+            new_name,
+        } => vec![index_name.clone(), new_name.clone()],
+        Statement::CreateIndex {
+            index_name, table, ..
+        }
+        | Statement::Reindex { index_name, table } => vec![table.clone(), index_name.clone()],
+        Statement::Grant { table, user, .. } | Statement::Revoke { table, user, .. } => {
+            vec![table.clone(), user.to_ascii_lowercase()]
+        }
+        Statement::CreateSequence { name, .. }
+        | Statement::DropSequence { name }
+        | Statement::CreateView { name, .. }
+        | Statement::DropView { name }
+        | Statement::CreateSchema { name }
+        | Statement::DropSchema { name, .. } => vec![name.clone()],
+        Statement::CreateUser { name, .. } | Statement::DropUser { name } => {
+            vec![name.to_ascii_lowercase()]
+        }
+        _ => table_of(stmt).map(str::to_string).into_iter().collect(),
+    };
+    if let Statement::CreateView { select, .. } | Statement::CreateTableAs { select, .. } = stmt {
+        objects.extend(table_of(select).map(str::to_string));
+    }
+    for subquery in subqueries(stmt) {
+        objects.extend(table_of(subquery).map(str::to_string));
+    }
+    objects.dedup();
+    objects
+}
+
+impl AuditLog {
+    // Appends to `path`, creating it if it is not there.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = sync_channel(AUDIT_QUEUE_ENTRIES);
+        std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || write_entries(receiver, BufWriter::new(file)))?;
+        Ok(AuditLog {
+            queue: Some(sender),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    // An audit log that writes nothing, for a server without one.
+    pub fn disabled() -> Self {
+        AuditLog {
+            queue: None,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    // Queues `entry` without waiting for room.
+    pub fn record(&self, mut entry: AuditEntry) {
+        let Some(sender) = &self.queue else {
+            return;
+        };
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        entry.dropped_before = (dropped > 0).then_some(dropped);
+        match sender.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(dropped + 1, Ordering::Relaxed);
+                warn!("Audit queue full, entry dropped");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(dropped + 1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Entries dropped and not yet reported in the log.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// Writes entries as they come, flushing whenever the queue runs dry, until
+// every sender is gone.
+fn write_entries(receiver: Receiver<AuditEntry>, mut file: BufWriter<std::fs::File>) {
+    while let Ok(entry) = receiver.recv() {
+        let mut written = write_entry(&mut file, &entry);
+        for entry in receiver.try_iter() {
+            written = written.and_then(|()| write_entry(&mut file, &entry));
+        }
+        if let Err(e) = written.and_then(|()| file.flush()) {
+            error!("Writing the audit log failed: {}", e);
+        }
+    }
+}
+
+fn write_entry(file: &mut BufWriter<std::fs::File>, entry: &AuditEntry) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *file, entry)?;
+    file.write_all(b"\n")
+}
@@ -0,0 +1,300 @@
+use crate::storage::atomic_file::{atomic_read, atomic_write};
+use crate::tx::log_manager::with_suffix;
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{
+        SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// Read by `main` to pick the first admin's password.
+pub const ADMIN_PASSWORD_ENV: &str = "MYDB_ADMIN_PASSWORD";
+
+pub const BOOTSTRAP_ADMIN: &str = "admin";
+
+// A password on its way to being hashed. Its Debug output is redacted so it
+// cannot leak through a logged statement or config.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Secret(s.to_string())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub admin: bool,
+    hash: String,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("name", &self.name)
+            .field("admin", &self.admin)
+            .finish_non_exhaustive()
+    }
+}
+
+// Accounts live in `<wal>.users`, one `name admin hash` line per user, and
+// the file is rewritten whole on every change. Only argon2 hashes are kept.
+// Names are case-insensitive, like every other SQL identifier.
+pub struct UserStore {
+    path: PathBuf,
+    users: Mutex<BTreeMap<String, User>>,
+}
+
+impl UserStore {
+    pub fn path(wal_path: &Path) -> PathBuf {
+        with_suffix(wal_path, ".users")
+    }
+
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let mut users = BTreeMap::new();
+        if let Some(bytes) = atomic_read(&path)? {
+            let text = String::from_utf8(bytes)
+                .with_context(|| format!("{:?} is not valid UTF-8", path))?;
+            for (i, line) in text.lines().enumerate() {
+                let mut fields = line.split(' ');
+                let (Some(name), Some(admin), Some(hash), None) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                else {
+                    bail!("Malformed user entry on line {} of {:?}", i + 1, path);
+                };
+                PasswordHash::new(hash)
+                    .map_err(|e| anyhow!("Bad password hash for user {}: {}", name, e))?;
+                users.insert(
+                    name.to_string(),
+                    User {
+                        name: name.to_string(),
+                        admin: admin == "1",
+                        hash: hash.to_string(),
+                    },
+                );
+            }
+        }
+        Ok(UserStore {
+            path,
+            users: Mutex::new(users),
+        })
+    }
+
+    // On first startup, creates the `admin` account with `password`, or with
+    // a random one if none was given. A generated password is returned so
+    // the caller can show it once; it is never stored in the clear.
+    pub fn bootstrap(&self, password: Option<Secret>) -> Result<Option<Secret>> {
+        if !self.users.lock().unwrap().is_empty() {
+            return Ok(None);
+        }
+        let (password, generated) = match password {
+            Some(password) => (password, false),
+            None => {
+                let mut bytes = [0u8; 12];
+                OsRng.fill_bytes(&mut bytes);
+                (Secret(hex(&bytes)), true)
+            }
+        };
+        self.insert(BOOTSTRAP_ADMIN, &password, true)?;
+        Ok(generated.then_some(password))
+    }
+
+    pub fn create_user(&self, name: &str, password: &Secret) -> Result<()> {
+        let name = &name.to_ascii_lowercase();
+        if !is_valid_name(name) {
+            bail!("Invalid user name {:?}", name);
+        }
+        if password.0.is_empty() {
+            bail!("Password must not be empty");
+        }
+        if self.users.lock().unwrap().contains_key(name) {
+            bail!("User '{}' already exists", name);
+        }
+        self.insert(name, password, false)
+    }
+
+    pub fn drop_user(&self, name: &str) -> Result<()> {
+        let name = &name.to_ascii_lowercase();
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .get(name)
+            .ok_or_else(|| anyhow!("User '{}' does not exist", name))?;
+        if user.admin && users.values().filter(|u| u.admin).count() == 1 {
+            bail!("Cannot drop '{}', the only admin", name);
+        }
+        let mut remaining = users.clone();
+        remaining.remove(name);
+        self.write(&remaining)?;
+        *users = remaining;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(&name.to_ascii_lowercase())
+            .cloned()
+    }
+
+    // Checks `password` against the stored hash. Slow on purpose, so callers
+    // on the async runtime should run it on a blocking thread.
+    pub fn authenticate(&self, name: &str, password: &Secret) -> Option<User> {
+        let user = self.get(name)?;
+        let hash = PasswordHash::new(&user.hash).ok()?;
+        Argon2::default()
+            .verify_password(password.0.as_bytes(), &hash)
+            .ok()
+            .map(|()| user)
+    }
+
+    fn insert(&self, name: &str, password: &Secret, admin: bool) -> Result<()> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.0.as_bytes(), &salt)
+            .map_err(|e| anyhow!("Hashing password failed: {}", e))?
+            .to_string();
+        let mut users = self.users.lock().unwrap();
+        // Checked again: another session may have taken the name while the
+        // password was being hashed.
+        if users.contains_key(name) {
+            bail!("User '{}' already exists", name);
+        }
+        let mut updated = users.clone();
+        updated.insert(
+            name.to_string(),
+            User {
+                name: name.to_string(),
+                admin,
+                hash,
+            },
+        );
+        self.write(&updated)?;
+        *users = updated;
+        Ok(())
+    }
+
+    fn write(&self, users: &BTreeMap<String, User>) -> Result<()> {
+        let mut text = String::new();
+        for user in users.values() {
+            text.push_str(&format!(
+                "{} {} {}\n",
+                user.name,
+                if user.admin { 1 } else { 0 },
+                user.hash
+            ));
+        }
+        atomic_write(&self.path, text.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginError {
+    // No session cookie at all.
+    Missing,
+    // A token this server never issued, or one that was logged out.
+    Unknown,
+    Expired,
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LoginError::Missing => "Not logged in",
+            LoginError::Unknown => "Invalid session token, log in again",
+            LoginError::Expired => "Session expired, log in again",
+        })
+    }
+}
+
+struct Login {
+    user: String,
+    created: Instant,
+}
+
+// Session tokens handed out by /login. Each is 32 random bytes, valid for
+// `ttl` after it was issued or until it is logged out.
+pub struct Logins {
+    tokens: Mutex<HashMap<String, Login>>,
+    ttl: Duration,
+}
+
+impl Logins {
+    pub fn new(ttl: Duration) -> Self {
+        Logins {
+            tokens: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn issue(&self, user: &str) -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex(&bytes);
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, login| now.duration_since(login.created) < self.ttl);
+        tokens.insert(
+            token.clone(),
+            Login {
+                user: user.to_string(),
+                created: now,
+            },
+        );
+        token
+    }
+
+    // The user `token` was issued to. An expired token is forgotten, so it
+    // reads as unknown from then on.
+    pub fn check(&self, token: Option<&str>) -> Result<String, LoginError> {
+        let token = token.filter(|t| !t.is_empty()).ok_or(LoginError::Missing)?;
+        let mut tokens = self.tokens.lock().unwrap();
+        let login = tokens.get(token).ok_or(LoginError::Unknown)?;
+        if login.created.elapsed() >= self.ttl {
+            tokens.remove(token);
+            return Err(LoginError::Expired);
+        }
+        Ok(login.user.clone())
+    }
+
+    pub fn revoke(&self, token: &str) -> bool {
+        self.tokens.lock().unwrap().remove(token).is_some()
+    }
+
+    pub fn revoke_user(&self, user: &str) {
+        self.tokens
+            .lock()
+            .unwrap()
+            .retain(|_, login| !login.user.eq_ignore_ascii_case(user));
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
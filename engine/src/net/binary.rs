@@ -0,0 +1,125 @@
+use crate::net::{
+    client::DbValue,
+    row::{ColumnType, Schema},
+};
+use crate::query::binder::Value;
+use crate::storage::storage::{decode_values, encode_values};
+use anyhow::{Result, anyhow};
+use hyper::{HeaderMap, header::ACCEPT};
+
+// The binary result format /query answers with when the request accepts
+// `application/x-mydb-binary`. All numbers are little-endian.
+//
+// A result starts with a u32 column count, then for each column a type byte
+// (0 INT, 1 TEXT, 255 when it is not known) and its name as a u32 length
+// and UTF-8. Each row is a u32 length and that many bytes of values, encoded
+// as rows are stored. A length of u32::MAX ends the rows, and what follows
+// to the end of the body is the trailer: the JSON object with `row_count`
+// and `affected`, or `error`, that the JSON format ends with.
+
+pub const CONTENT_TYPE: &str = "application/x-mydb-binary";
+
+const END_OF_ROWS: u32 = u32::MAX;
+const UNKNOWN_TYPE: u8 = 255;
+
+// Whether the request's Accept header lists the binary format.
+pub fn accepted(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|media| {
+            let name = media.split(';').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case(CONTENT_TYPE)
+        })
+}
+
+pub fn header(schema: &Schema) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(schema.len() as u32).to_le_bytes());
+    for (i, name) in schema.names().iter().enumerate() {
+        buf.push(match schema.column_type(i) {
+            Some(ColumnType::Int) => 0,
+            Some(ColumnType::Text) => 1,
+            None => UNKNOWN_TYPE,
+        });
+        buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+    }
+    buf
+}
+
+pub fn push_row(buf: &mut Vec<u8>, values: &[Value]) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    encode_values(values, buf);
+    let len = (buf.len() - start - 4) as u32;
+    buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+pub fn trailer(json: &str) -> Vec<u8> {
+    let mut buf = END_OF_ROWS.to_le_bytes().to_vec();
+    buf.extend_from_slice(json.as_bytes());
+    buf
+}
+
+// A whole binary result, read back.
+pub struct Frame<'a> {
+    pub schema: Schema,
+    pub rows: Vec<Vec<DbValue>>,
+    pub trailer: &'a [u8],
+}
+
+// A body cut short, by a lost connection or a server that died mid-result,
+// is an error rather than a short result.
+pub fn decode(body: &[u8]) -> Result<Frame<'_>> {
+    let mut reader = Reader { body, cursor: 0 };
+    let count = reader.u32()? as usize;
+    let mut columns = Vec::with_capacity(count.min(body.len()));
+    for _ in 0..count {
+        let column_type = match reader.take(1)?[0] {
+            0 => Some(ColumnType::Int),
+            1 => Some(ColumnType::Text),
+            _ => None,
+        };
+        let len = reader.u32()? as usize;
+        let name = String::from_utf8(reader.take(len)?.to_vec())?;
+        columns.push((name, column_type));
+    }
+
+    let mut rows = Vec::new();
+    loop {
+        let len = reader.u32()?;
+        if len == END_OF_ROWS {
+            break;
+        }
+        let values = decode_values(reader.take(len as usize)?, 0)?;
+        rows.push(values.into_iter().map(DbValue::from).collect());
+    }
+    Ok(Frame {
+        schema: Schema::partly_typed(columns),
+        rows,
+        trailer: &body[reader.cursor..],
+    })
+}
+
+struct Reader<'a> {
+    body: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .body
+            .get(self.cursor..self.cursor + len)
+            .ok_or_else(|| anyhow!("Binary result truncated at byte {}", self.cursor))?;
+        self.cursor += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
@@ -1,8 +1,37 @@
 
-use anyhow::Result;
-use reqwest::{Client, cookie::Jar};
+use crate::net::{
+    binary,
+    copy::{self, CopyReport},
+    csv_io::{CsvOptions, ImportProgress, ImportReport},
+    queries::QueryList,
+    replication::ReplicationPoint,
+    row::{Row, Schema},
+    schema::{IndexList, IndexSchema, TableList, TableSchema, TableSummary},
+    server::{
+        LOCK_WAIT_HEADER, PAGES_CACHED_HEADER, PAGES_READ_HEADER, QUERY_MILLIS_HEADER, READ_ONLY,
+        ROWS_HEADER, SEARCH_PATH_HEADER, TRANSACTION_ABORTED, TX_ID_HEADER, TX_STATUS_HEADER,
+    },
+    session::TxState,
+};
+use crate::query::binder::Value as EngineValue;
+use crate::query::memory::OUT_OF_MEMORY;
+use crate::query::privileges::PERMISSION_DENIED;
+use crate::query::source::SourceError;
+use crate::storage::backup::BackupReport;
+use crate::storage::storage::{FOREIGN_KEY_VIOLATION, SCHEMA_CHANGED};
+use anyhow::{Result, bail};
+use futures_util::{Stream, StreamExt, future::BoxFuture};
+use reqwest::{Certificate, Client, Response, StatusCode, cookie::Jar, header::ACCEPT};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use serde_json::Value;
+use std::{
+    fmt,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
 
 #[derive(Serialize)]
 struct LoginReq<'a> {
@@ -13,26 +42,533 @@ struct LoginReq<'a> {
 struct QueryReq<'a> {
     sql: &'a str,
 }
+#[derive(Serialize)]
+struct BackupReq<'a> {
+    dir: &'a Path,
+}
+#[derive(Serialize)]
+struct BatchReq<'a> {
+    statements: &'a [&'a str],
+}
+#[derive(Deserialize)]
+struct BatchResp {
+    status: String,
+    results: Vec<QueryResp>,
+    failed_index: Option<usize>,
+    error: Option<String>,
+}
 #[derive(Deserialize)]
 struct QueryResp {
-    rows: Vec<Vec<String>>,
+    #[serde(default)]
+    columns: Vec<String>,
+    rows: Vec<Vec<DbValue>>,
+    #[serde(flatten)]
+    trailer: Trailer,
+}
+
+impl QueryResp {
+    fn into_result(self) -> Result<QueryResult> {
+        let skipped_pages = self.trailer.skipped_pages;
+        let affected = self.trailer.check()?;
+        Ok(QueryResult {
+            affected,
+            skipped_pages,
+            ..QueryResult::new(Schema::new(self.columns), self.rows)
+        })
+    }
+}
+
+// A statement's rows with the columns they share. `affected` is how many
+// rows it wrote, for statements that write rows, `skipped_pages` how many
+// damaged pages its scans passed over under skip_page, and `stats` what the
+// server said the statement cost.
+#[derive(Debug, Clone, Default)]
+pub struct QueryResult {
+    pub schema: Arc<Schema>,
+    pub rows: Vec<Row>,
+    pub affected: Option<u64>,
+    pub skipped_pages: u64,
+    pub stats: Option<QueryStats>,
+}
+
+// How long a statement ran on the server, the rows it sent or wrote, the
+// pages it read from disk and found in the buffer pool, and how long it
+// waited for its table lock. The server only knows them once the statement
+// has finished, so a result streamed in more than one chunk has none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    pub millis: u64,
+    pub rows: u64,
+    pub pages_read: u64,
+    pub pages_cached: u64,
+    pub lock_wait_millis: u64,
+}
+
+impl QueryResult {
+    pub fn new(schema: Schema, rows: Vec<Vec<DbValue>>) -> Self {
+        let schema = Arc::new(schema);
+        QueryResult {
+            rows: rows
+                .into_iter()
+                .map(|values| Row::new(schema.clone(), values))
+                .collect(),
+            schema,
+            affected: None,
+            skipped_pages: 0,
+            stats: None,
+        }
+    }
+
+    pub fn columns(&self) -> &[String] {
+        self.schema.names()
+    }
+}
+
+// Two results are the same when they hold the same rows, however long each
+// took to get.
+impl PartialEq for QueryResult {
+    fn eq(&self, other: &Self) -> bool {
+        self.schema == other.schema
+            && self.rows == other.rows
+            && self.affected == other.affected
+            && self.skipped_pages == other.skipped_pages
+    }
+}
+
+// One value of a result row, as the server typed it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DbValue {
+    Null,
+    Int(i64),
+    Text(String),
+}
+
+impl DbValue {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            DbValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            DbValue::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == DbValue::Null
+    }
+}
+
+// NULL prints as `NULL`; text as it is, without quotes.
+impl fmt::Display for DbValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbValue::Null => f.write_str("NULL"),
+            DbValue::Int(i) => write!(f, "{}", i),
+            DbValue::Text(s) => f.write_str(s),
+        }
+    }
+}
+
+impl From<i64> for DbValue {
+    fn from(i: i64) -> Self {
+        DbValue::Int(i)
+    }
+}
+
+impl From<&str> for DbValue {
+    fn from(s: &str) -> Self {
+        DbValue::Text(s.to_string())
+    }
+}
+
+impl From<String> for DbValue {
+    fn from(s: String) -> Self {
+        DbValue::Text(s)
+    }
+}
+
+impl From<EngineValue> for DbValue {
+    fn from(value: EngineValue) -> Self {
+        match value {
+            EngineValue::Int(i) => DbValue::Int(i),
+            EngineValue::String(s) => DbValue::Text(s),
+        }
+    }
+}
+
+// What the server appends after the last row.
+#[derive(Debug, Default, Deserialize)]
+struct Trailer {
+    row_count: Option<usize>,
+    affected: Option<u64>,
+    #[serde(default)]
+    skipped_pages: u64,
+    error: Option<String>,
+    tx_status: Option<String>,
+    tx_id: Option<u64>,
+}
+
+impl Trailer {
+    // Where the statement left the session's transaction, which for one
+    // that failed after its rows started is not what the headers said.
+    fn tx_state(&self) -> Option<TxState> {
+        TxState::parse(self.tx_status.as_deref()?, self.tx_id)
+    }
+
+    // The rows written, once the result turns out to be complete.
+    fn check(self) -> Result<Option<u64>> {
+        match (self.error, self.row_count) {
+            (Some(error), _) => Err(DbError::Execution(error).into()),
+            (None, _) => Ok(self.affected),
+        }
+    }
+}
+
+// Failures a caller may want to handle rather than just report, told apart
+// by the response's status and the prefix the server puts on its message.
+// They come wrapped in `anyhow::Error`; look for them with `downcast_ref`.
+// A server that could not be reached at all fails with a `reqwest::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbError {
+    // The statement is not valid SQL. `diagnostics` has every syntax error
+    // found in it, with where each is.
+    Parse {
+        message: String,
+        diagnostics: Vec<SourceError>,
+    },
+    // The statement names a table, column or index that does not exist, or
+    // uses one the wrong way.
+    Bind(String),
+    // The statement failed while it ran, and was rolled back.
+    Execution(String),
+    // A row inserted into or deleted from `table` would have broken foreign
+    // key `constraint`, and the statement was rolled back.
+    ForeignKeyViolation {
+        message: String,
+        constraint: String,
+        table: String,
+    },
+    // `table` changed between planning the statement and running it, which
+    // did nothing; running it again plans it afresh.
+    SchemaChanged {
+        message: String,
+        table: String,
+    },
+    // `operator` needed more memory than the statement's work_mem of
+    // `budget` bytes and could not spill, so the statement was rolled back.
+    OutOfMemory {
+        message: String,
+        operator: String,
+        budget: u64,
+    },
+    // Another transaction held a lock past the lock timeout.
+    LockTimeout(String),
+    // The statement lost out over a lock: its transaction was a deadlock
+    // victim, or has to retry.
+    LockConflict(String),
+    // The session's transaction had already been rolled back, for example
+    // for being idle too long.
+    TransactionAborted(String),
+    // The statement ran past its time limit and was rolled back.
+    Timeout {
+        elapsed_ms: u64,
+        timeout_ms: u64,
+    },
+    // Not logged in, the login expired, or the credentials were wrong.
+    Unauthorized(String),
+    // Logged in, but not allowed to do this.
+    Forbidden(String),
+    // The user has not been granted what the statement needs: `privilege`
+    // on `table`, or neither when only an admin may run it.
+    PermissionDenied {
+        message: String,
+        table: Option<String>,
+        privilege: Option<String>,
+    },
+    // The server only runs reads: a standby, or one started read-only.
+    ReadOnly(String),
+    // The server was too busy or the client over its rate limit; worth
+    // trying again after `retry_after_secs`.
+    Busy {
+        retry_after_secs: Option<u64>,
+        message: String,
+    },
+    // The server refused the request body as too large (413).
+    BodyTooLarge {
+        limit_bytes: Option<u64>,
+    },
+    // A batch was rolled back. `index` is the statement that failed, if the
+    // failure was in one rather than in the commit.
+    BatchFailed {
+        index: Option<usize>,
+        message: String,
+    },
+    // Any other refusal, with its HTTP status.
+    Server {
+        status: u16,
+        message: String,
+    },
+}
+
+impl DbError {
+    // `body` is the server's message, either as plain text or as the
+    // `{"error": ...}` the JSON endpoints send.
+    fn from_response(status: StatusCode, retry_after_secs: Option<u64>, body: &str) -> Self {
+        let json: Option<Value> = serde_json::from_str(body).ok();
+        let field = |name: &str| json.as_ref().and_then(|j| j[name].as_u64());
+        let text = |name: &str| {
+            json.as_ref()
+                .and_then(|j| j[name].as_str())
+                .map(str::to_string)
+        };
+        let message = json
+            .as_ref()
+            .and_then(|j| j["error"].as_str())
+            .unwrap_or(body)
+            .to_string();
+        match status {
+            StatusCode::PAYLOAD_TOO_LARGE => DbError::BodyTooLarge {
+                limit_bytes: field("limit_bytes"),
+            },
+            StatusCode::REQUEST_TIMEOUT => DbError::Timeout {
+                elapsed_ms: field("elapsed_ms").unwrap_or_default(),
+                timeout_ms: field("timeout_ms").unwrap_or_default(),
+            },
+            StatusCode::UNAUTHORIZED => DbError::Unauthorized(message),
+            StatusCode::FORBIDDEN if text("code").as_deref() == Some(PERMISSION_DENIED) => {
+                DbError::PermissionDenied {
+                    message,
+                    table: text("table"),
+                    privilege: text("privilege"),
+                }
+            }
+            StatusCode::FORBIDDEN if text("code").as_deref() == Some(READ_ONLY) => {
+                DbError::ReadOnly(message)
+            }
+            StatusCode::FORBIDDEN => DbError::Forbidden(message),
+            StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS => DbError::Busy {
+                retry_after_secs,
+                message,
+            },
+            _ if message.starts_with("Parse error:") => DbError::Parse {
+                message,
+                diagnostics: json
+                    .as_ref()
+                    .and_then(|j| serde_json::from_value(j["diagnostics"].clone()).ok())
+                    .unwrap_or_default(),
+            },
+            // Binding happens while the executor is built, under that
+            // step's own context.
+            _ if message.contains("Bind failed:") => DbError::Bind(message),
+            StatusCode::CONFLICT if text("code").as_deref() == Some(FOREIGN_KEY_VIOLATION) => {
+                DbError::ForeignKeyViolation {
+                    message,
+                    constraint: text("constraint").unwrap_or_default(),
+                    table: text("table").unwrap_or_default(),
+                }
+            }
+            StatusCode::CONFLICT if text("code").as_deref() == Some(SCHEMA_CHANGED) => {
+                DbError::SchemaChanged {
+                    message,
+                    table: text("table").unwrap_or_default(),
+                }
+            }
+            StatusCode::INSUFFICIENT_STORAGE if text("code").as_deref() == Some(OUT_OF_MEMORY) => {
+                DbError::OutOfMemory {
+                    message,
+                    operator: text("operator").unwrap_or_default(),
+                    budget: field("budget").unwrap_or_default(),
+                }
+            }
+            StatusCode::CONFLICT if text("code").as_deref() == Some(TRANSACTION_ABORTED) => {
+                DbError::TransactionAborted(message)
+            }
+            StatusCode::CONFLICT if message.starts_with("Lock error: timed out") => {
+                DbError::LockTimeout(message)
+            }
+            StatusCode::CONFLICT if message.starts_with("Lock error:") => {
+                DbError::LockConflict(message)
+            }
+            StatusCode::CONFLICT => DbError::TransactionAborted(message),
+            StatusCode::INTERNAL_SERVER_ERROR => DbError::Execution(message),
+            status => DbError::Server {
+                status: status.as_u16(),
+                message,
+            },
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Parse { message, .. }
+            | DbError::Bind(message)
+            | DbError::Execution(message)
+            | DbError::LockTimeout(message)
+            | DbError::LockConflict(message)
+            | DbError::TransactionAborted(message)
+            | DbError::Unauthorized(message)
+            | DbError::Forbidden(message)
+            | DbError::ReadOnly(message)
+            | DbError::PermissionDenied { message, .. }
+            | DbError::ForeignKeyViolation { message, .. }
+            | DbError::SchemaChanged { message, .. }
+            | DbError::OutOfMemory { message, .. }
+            | DbError::Busy { message, .. } => f.write_str(message),
+            DbError::Timeout {
+                elapsed_ms,
+                timeout_ms,
+            } => write!(
+                f,
+                "Statement timed out after {} ms (limit {} ms)",
+                elapsed_ms, timeout_ms
+            ),
+            DbError::BodyTooLarge {
+                limit_bytes: Some(limit),
+            } => write!(
+                f,
+                "Request is larger than the server's {} byte limit",
+                limit
+            ),
+            DbError::BodyTooLarge { limit_bytes: None } => {
+                f.write_str("Request is larger than the server accepts")
+            }
+            DbError::BatchFailed {
+                index: Some(index),
+                message,
+            } => write!(f, "Batch statement {} failed: {}", index, message),
+            DbError::BatchFailed {
+                index: None,
+                message,
+            } => write!(f, "Batch failed: {}", message),
+            DbError::Server { status, message } => {
+                write!(f, "Server answered {}: {}", status, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+// How often a read is tried again when the server cannot be reached or is
+// too busy. Writes never are: one may have gone through even though its
+// answer was lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    // Waited before the first retry, and that much longer before each one
+    // after it.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+// Connection settings for a `SqlClient`, all optional.
+#[derive(Default)]
+pub struct SqlClientBuilder {
+    base_url: String,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    root_certificates: Vec<Certificate>,
+    accept_invalid_certs: bool,
+    retry: RetryPolicy,
+}
+
+impl SqlClientBuilder {
+    // Gives up on a server that does not accept the connection in time.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    // Gives up on a request, rows included, that takes longer than this.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    // Trusts a server certificate signed by this PEM-encoded authority, on
+    // top of the usual ones.
+    pub fn root_certificate_pem(mut self, pem: &[u8]) -> Result<Self> {
+        self.root_certificates.push(Certificate::from_pem(pem)?);
+        Ok(self)
+    }
+
+    // Accepts any server certificate. Only for testing against a server
+    // with a self-signed one.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn build(self) -> Result<SqlClient> {
+        let mut http = Client::builder()
+            .cookie_provider(Arc::new(Jar::default()))
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(timeout) = self.connect_timeout {
+            http = http.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.request_timeout {
+            http = http.timeout(timeout);
+        }
+        for certificate in self.root_certificates {
+            http = http.add_root_certificate(certificate);
+        }
+        Ok(SqlClient {
+            http: http.build()?,
+            base_url: self.base_url,
+            retry: self.retry,
+            session: Mutex::default(),
+        })
+    }
 }
 
 pub struct SqlClient {
     http: Client,
     base_url: String,
+    retry: RetryPolicy,
+    session: Mutex<SessionState>,
+}
+
+// What the client knows of its session: the login to open another with,
+// and the search path and transaction state as the server last reported
+// them.
+#[derive(Default)]
+struct SessionState {
+    login: Option<(String, String)>,
+    search_path: Option<String>,
+    tx_state: TxState,
 }
 
 impl SqlClient {
+    // A client with reqwest's defaults: no timeouts and no retries.
     pub fn new(base_url: &str) -> Self {
-        let jar = Jar::default();
-        let http = Client::builder()
-            .cookie_provider(Arc::new(jar))
-            .build()
-            .unwrap();
-        SqlClient {
-            http,
+        Self::builder(base_url).build().unwrap()
+    }
+
+    pub fn builder(base_url: &str) -> SqlClientBuilder {
+        SqlClientBuilder {
             base_url: base_url.into(),
+            ..SqlClientBuilder::default()
         }
     }
 
@@ -44,14 +580,613 @@ impl SqlClient {
             .json(&LoginReq { user, pass })
             .send()
             .await?;
-        resp.error_for_status()?;
+        let resp = check_status(resp).await?;
+        let mut session = self.session.lock().unwrap();
+        session.login = Some((user.to_string(), pass.to_string()));
+        session.search_path = search_path_of(&resp);
+        session.tx_state = TxState::Idle;
         Ok(())
     }
 
-    pub async fn query(&self, sql: &str) -> Result<Vec<Vec<String>>> {
+    // Logs in again as the last login did, for when the server has lost
+    // the session, and SETs the search path the old session had if the new
+    // one starts with another.
+    pub async fn reconnect(&self) -> Result<()> {
+        let (login, path) = {
+            let session = self.session.lock().unwrap();
+            (session.login.clone(), session.search_path.clone())
+        };
+        let Some((user, pass)) = login else {
+            bail!("Not logged in");
+        };
+        self.login(&user, &pass).await?;
+        if let Some(path) = path
+            && self.session.lock().unwrap().search_path.as_ref() != Some(&path)
+        {
+            self.query(&format!(
+                "SET search_path = '{}';",
+                path.replace('\'', "''")
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    // The schemas unqualified names are looked for in, in order, as the
+    // server last reported them: on login and on each SET of search_path.
+    pub fn search_path(&self) -> Option<Vec<String>> {
+        let session = self.session.lock().unwrap();
+        let path = session.search_path.as_ref()?;
+        Some(path.split(',').map(str::to_string).collect())
+    }
+
+    // Whether the session is inside BEGIN ... COMMIT, and whether a failed
+    // statement has aborted its transaction, as of the last statement.
+    pub fn tx_state(&self) -> TxState {
+        self.session.lock().unwrap().tx_state
+    }
+
+    // Called for failed statements as well, which can leave the
+    // transaction aborted.
+    fn note_session(&self, resp: &Response) {
+        let mut session = self.session.lock().unwrap();
+        if let Some(path) = search_path_of(resp) {
+            session.search_path = Some(path);
+        }
+        if let Some(tx) = tx_state_of(resp) {
+            session.tx_state = tx;
+        }
+    }
+
+    fn note_trailer(&self, trailer: &Trailer) {
+        if let Some(tx) = trailer.tx_state() {
+            self.session.lock().unwrap().tx_state = tx;
+        }
+    }
+
+    // Failures come back as a `DbError` where the server said what went
+    // wrong. Results are asked for in the binary format, which brings the
+    // column types along; a server that answers in JSON is read as JSON.
+    pub async fn query(&self, sql: &str) -> Result<QueryResult> {
+        let url = format!("{}/query", self.base_url);
+        let resp = self
+            .http
+            .post(&url)
+            .header(ACCEPT, binary::CONTENT_TYPE)
+            .json(&QueryReq { sql })
+            .send()
+            .await?;
+        self.note_session(&resp);
+        let resp = check_status(resp).await?;
+        let stats = stats_of(&resp);
+        let is_binary = resp
+            .headers()
+            .get("content-type")
+            .is_some_and(|t| t == binary::CONTENT_TYPE);
+        if !is_binary {
+            let qr: QueryResp = resp.json().await?;
+            self.note_trailer(&qr.trailer);
+            let result = qr.into_result()?;
+            return Ok(QueryResult { stats, ..result });
+        }
+        let body = resp.bytes().await?;
+        let frame = binary::decode(&body)?;
+        let trailer: Trailer = serde_json::from_slice(frame.trailer)?;
+        self.note_trailer(&trailer);
+        let skipped_pages = trailer.skipped_pages;
+        let affected = trailer.check()?;
+        Ok(QueryResult {
+            skipped_pages,
+            affected,
+            stats,
+            ..QueryResult::new(frame.schema, frame.rows)
+        })
+    }
+
+    // Runs the statements in one transaction and returns the result of
+    // each, or `DbError::BatchFailed` once the server has rolled them all
+    // back.
+    pub async fn batch(&self, statements: &[&str]) -> Result<Vec<QueryResult>> {
+        let url = format!("{}/batch", self.base_url);
+        let resp = self
+            .http
+            .post(&url)
+            .json(&BatchReq { statements })
+            .send()
+            .await?;
+        let is_report = resp
+            .headers()
+            .get("content-type")
+            .is_some_and(|t| t == "application/json");
+        if !is_report {
+            check_status(resp).await?;
+            bail!("Batch response is not JSON");
+        }
+        let br: BatchResp = resp.json().await?;
+        if br.status != "committed" {
+            return Err(DbError::BatchFailed {
+                index: br.failed_index,
+                message: br.error.unwrap_or(br.status),
+            }
+            .into());
+        }
+        br.results.into_iter().map(QueryResp::into_result).collect()
+    }
+
+    pub async fn tables(&self) -> Result<Vec<TableSummary>> {
+        let url = format!("{}/tables", self.base_url);
+        let resp = self.get(&url).await?;
+        let list: TableList = check_status(resp).await?.json().await?;
+        Ok(list.tables)
+    }
+
+    // The table's columns and indexes, or None if there is no such table.
+    pub async fn table(&self, name: &str) -> Result<Option<TableSchema>> {
+        let url = format!("{}/tables/{}", self.base_url, name);
+        let resp = self.get(&url).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(check_status(resp).await?.json().await?))
+    }
+
+    pub async fn indexes(&self) -> Result<Vec<IndexSchema>> {
+        let url = format!("{}/indexes", self.base_url);
+        let resp = self.get(&url).await?;
+        let list: IndexList = check_status(resp).await?.json().await?;
+        Ok(list.indexes)
+    }
+
+    // The queries running on the server and the last ones it finished; all
+    // of them for an admin, the user's own for anyone else.
+    pub async fn queries(&self) -> Result<QueryList> {
+        let url = format!("{}/debug/queries", self.base_url);
+        let resp = self.get(&url).await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    // Stops a running query by the id /debug/queries lists it with.
+    pub async fn cancel_query(&self, id: u64) -> Result<()> {
+        let url = format!("{}/debug/queries/{}/cancel", self.base_url, id);
+        let resp = self.http.post(&url).send().await?;
+        check_status(resp).await?;
+        Ok(())
+    }
+
+    // Loads CSV text into `table` in one transaction. Rows the server could
+    // not use are listed in the report rather than failing the import.
+    pub async fn import_csv(
+        &self,
+        table: &str,
+        csv: impl Into<reqwest::Body>,
+        options: CsvOptions,
+    ) -> Result<ImportReport> {
+        let url = format!(
+            "{}/tables/{}/import?{}",
+            self.base_url,
+            table,
+            options.to_query()
+        );
+        let resp = self
+            .http
+            .post(&url)
+            .header("content-type", "text/csv")
+            .body(csv)
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    // Adds `rows` to `table` with `COPY ... FROM STDIN BINARY`, in one
+    // transaction. `columns` orders the values, the table's order when
+    // empty.
+    pub async fn copy_in(
+        &self,
+        table: &str,
+        columns: &[&str],
+        rows: &[Vec<EngineValue>],
+    ) -> Result<CopyReport> {
+        let mut body = match columns {
+            [] => format!("COPY {} FROM STDIN BINARY;\n", table),
+            columns => format!(
+                "COPY {} ({}) FROM STDIN BINARY;\n",
+                table,
+                columns.join(", ")
+            ),
+        }
+        .into_bytes();
+        copy::write_binary(&mut body, rows)?;
+        let url = format!("{}/copy", self.base_url);
+        let resp = self
+            .http
+            .post(&url)
+            .header("content-type", "application/octet-stream")
+            .body(body)
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    // Like `import_csv`, calling `on_progress` each time the server says how
+    // far it has got.
+    pub async fn import_csv_with_progress(
+        &self,
+        table: &str,
+        csv: impl Into<reqwest::Body>,
+        options: CsvOptions,
+        mut on_progress: impl FnMut(&ImportProgress),
+    ) -> Result<ImportReport> {
+        let url = format!(
+            "{}/tables/{}/import?{}",
+            self.base_url,
+            table,
+            options.to_query()
+        );
+        let resp = self
+            .http
+            .post(&url)
+            .header("content-type", "text/csv")
+            .header("accept", "text/event-stream")
+            .body(csv)
+            .send()
+            .await?;
+        let mut resp = check_status(resp).await?;
+        let mut buf = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            buf.extend_from_slice(&chunk);
+            while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = buf.drain(..end + 2).collect();
+                let event = std::str::from_utf8(&event)?;
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(str::trim_start)
+                        .unwrap_or_default()
+                };
+                let data = field("data:");
+                match field("event:") {
+                    "progress" => on_progress(&serde_json::from_str(data)?),
+                    "done" => return Ok(serde_json::from_str(data)?),
+                    "error" => {
+                        let status = serde_json::from_str::<Value>(data)?["status"]
+                            .as_u64()
+                            .and_then(|s| StatusCode::from_u16(s as u16).ok())
+                            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                        return Err(DbError::from_response(status, None, data).into());
+                    }
+                    other => bail!("Unexpected import event {:?}", other),
+                }
+            }
+        }
+        bail!("Import response ended before the import did")
+    }
+
+    pub async fn export_csv(&self, table: &str, options: CsvOptions) -> Result<String> {
+        let url = format!(
+            "{}/tables/{}/export?{}",
+            self.base_url,
+            table,
+            options.to_query()
+        );
+        let resp = self.get(&url).await?;
+        Ok(check_status(resp).await?.text().await?)
+    }
+
+    // Where the server's log ends, and its catalog unless `known_end` says
+    // the caller has everything up to there already.
+    pub async fn replication_point(&self, known_end: Option<u64>) -> Result<ReplicationPoint> {
+        let mut url = format!("{}/replication", self.base_url);
+        if let Some(end) = known_end {
+            url.push_str(&format!("?known_end={}", end));
+        }
+        let resp = self.get(&url).await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    // Log records from offset `from` on, framed as on disk. The server may
+    // stop short of `to`; the records returned are always whole.
+    pub async fn wal(&self, from: u64, to: u64) -> Result<Vec<u8>> {
+        let url = format!("{}/wal?from={}&to={}", self.base_url, from, to);
+        let resp = self.get(&url).await?;
+        Ok(check_status(resp).await?.bytes().await?.to_vec())
+    }
+
+    // Turns a standby into a server that takes writes.
+    pub async fn promote(&self) -> Result<()> {
+        let url = format!("{}/promote", self.base_url);
+        let resp = self.http.post(&url).send().await?;
+        check_status(resp).await?;
+        Ok(())
+    }
+
+    // Has the server copy its database into `dir`, a directory on the
+    // server's machine. Admins only.
+    pub async fn backup(&self, dir: &Path) -> Result<BackupReport> {
+        let url = format!("{}/backup", self.base_url);
+        let resp = self.http.post(&url).json(&BackupReq { dir }).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    // Sends a GET, trying again as the retry policy allows while the server
+    // cannot be reached or is too busy.
+    async fn get(&self, url: &str) -> Result<Response> {
+        let mut retries = 0;
+        loop {
+            let sent = self.http.get(url).send().await;
+            let retry = match &sent {
+                Err(e) => e.is_connect() || e.is_timeout(),
+                Ok(resp) => matches!(
+                    resp.status(),
+                    StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS
+                ),
+            };
+            if !retry || retries >= self.retry.max_retries {
+                return Ok(sent?);
+            }
+            retries += 1;
+            tokio::time::sleep(self.retry.backoff * retries).await;
+        }
+    }
+
+    // Like `query`, but hands out rows as they arrive instead of waiting
+    // for the whole result. Returns once the columns are known.
+    pub async fn query_stream(&self, sql: &str) -> Result<RowStream> {
         let url = format!("{}/query", self.base_url);
         let resp = self.http.post(&url).json(&QueryReq { sql }).send().await?;
-        let qr: QueryResp = resp.error_for_status()?.json().await?;
-        Ok(qr.rows)
+        self.note_session(&resp);
+        let resp = check_status(resp).await?;
+        RowStream::new(resp).await
+    }
+}
+
+fn search_path_of(resp: &Response) -> Option<String> {
+    let path = resp.headers().get(SEARCH_PATH_HEADER)?;
+    path.to_str().ok().map(str::to_string)
+}
+
+fn stats_of(resp: &Response) -> Option<QueryStats> {
+    let header = |name| resp.headers().get(name)?.to_str().ok()?.parse().ok();
+    Some(QueryStats {
+        millis: header(QUERY_MILLIS_HEADER)?,
+        rows: header(ROWS_HEADER)?,
+        pages_read: header(PAGES_READ_HEADER)?,
+        pages_cached: header(PAGES_CACHED_HEADER)?,
+        lock_wait_millis: header(LOCK_WAIT_HEADER)?,
+    })
+}
+
+fn tx_state_of(resp: &Response) -> Option<TxState> {
+    let header = |name| resp.headers().get(name)?.to_str().ok();
+    let tx_id = header(TX_ID_HEADER).and_then(|id| id.parse().ok());
+    TxState::parse(header(TX_STATUS_HEADER)?, tx_id)
+}
+
+// Turns an error status into a `DbError` carrying the server's message.
+async fn check_status(resp: Response) -> Result<Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let retry_after_secs = resp
+        .headers()
+        .get("retry-after")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse().ok());
+    let body = resp.text().await.unwrap_or_default();
+    Err(DbError::from_response(status, retry_after_secs, &body).into())
+}
+
+// The rows of a result as they arrive, read off the response a chunk at a
+// time. The columns are known from the start; `affected` once the last row
+// has been read. A result the server fails partway through, or that is cut
+// off, ends with an error rather than just ending.
+pub struct RowStream {
+    schema: Arc<Schema>,
+    affected: Option<u64>,
+    stats: Option<QueryStats>,
+    reader: Option<RowReader>,
+    reading: Option<BoxFuture<'static, (RowReader, Result<Option<Row>>)>>,
+}
+
+impl RowStream {
+    async fn new(resp: Response) -> Result<Self> {
+        let stats = stats_of(&resp);
+        let mut reader = RowReader {
+            resp,
+            schema: Arc::default(),
+            buf: Vec::new(),
+            state: StreamState::Header,
+            affected: None,
+        };
+        reader.read_header().await?;
+        Ok(RowStream {
+            schema: reader.schema.clone(),
+            affected: None,
+            stats,
+            reader: Some(reader),
+            reading: None,
+        })
+    }
+
+    pub fn schema(&self) -> &Arc<Schema> {
+        &self.schema
+    }
+
+    pub fn columns(&self) -> &[String] {
+        self.schema.names()
+    }
+
+    // How many rows the statement wrote, once the stream has ended.
+    pub fn affected(&self) -> Option<u64> {
+        self.affected
+    }
+
+    // What the statement cost, if it had finished before its rows started.
+    pub fn stats(&self) -> Option<QueryStats> {
+        self.stats
+    }
+
+    pub async fn next_row(&mut self) -> Result<Option<Row>> {
+        self.next().await.transpose()
+    }
+}
+
+impl Stream for RowStream {
+    type Item = Result<Row>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut reading = match (self.reading.take(), self.reader.take()) {
+            (Some(reading), _) => reading,
+            (None, Some(mut reader)) => Box::pin(async move {
+                let row = reader.next_row().await;
+                (reader, row)
+            }),
+            // Ended, or failed.
+            (None, None) => return Poll::Ready(None),
+        };
+        match reading.as_mut().poll(cx) {
+            Poll::Pending => {
+                self.reading = Some(reading);
+                Poll::Pending
+            }
+            Poll::Ready((reader, row)) => match row {
+                Ok(Some(row)) => {
+                    self.reader = Some(reader);
+                    Poll::Ready(Some(Ok(row)))
+                }
+                Ok(None) => {
+                    self.affected = reader.affected;
+                    Poll::Ready(None)
+                }
+                Err(e) => Poll::Ready(Some(Err(e))),
+            },
+        }
+    }
+}
+
+enum StreamState {
+    Header,
+    Rows,
+    Done,
+}
+
+// Parses a `{"columns":[...],"rows":[...],"row_count":N}` response out of
+// the body as it comes.
+struct RowReader {
+    resp: Response,
+    schema: Arc<Schema>,
+    buf: Vec<u8>,
+    state: StreamState,
+    affected: Option<u64>,
+}
+
+impl RowReader {
+    async fn read_header(&mut self) -> Result<()> {
+        while !self.parse_header()? {
+            self.read_chunk().await?;
+        }
+        Ok(())
+    }
+
+    // Takes the column names off the front of the body, once they are all
+    // in. Returns whether they were.
+    fn parse_header(&mut self) -> Result<bool> {
+        const COLUMNS: &[u8] = br#"{"columns":"#;
+        const ROWS: &[u8] = br#","rows":["#;
+        if !matches!(self.state, StreamState::Header) {
+            return Ok(true);
+        }
+        if self.buf.len() < COLUMNS.len() {
+            return Ok(false);
+        }
+        if !self.buf.starts_with(COLUMNS) {
+            bail!("Unexpected start of query response");
+        }
+        let mut columns = serde_json::Deserializer::from_slice(&self.buf[COLUMNS.len()..])
+            .into_iter::<Vec<String>>();
+        match columns.next() {
+            Some(Ok(names)) => {
+                let end = COLUMNS.len() + columns.byte_offset();
+                if self.buf.len() < end + ROWS.len() {
+                    return Ok(false);
+                }
+                if !self.buf[end..].starts_with(ROWS) {
+                    bail!("Unexpected start of query response");
+                }
+                self.buf.drain(..end + ROWS.len());
+                self.schema = Arc::new(Schema::new(names));
+                self.state = StreamState::Rows;
+                Ok(true)
+            }
+            Some(Err(e)) if !e.is_eof() => Err(e.into()),
+            _ => Ok(false),
+        }
+    }
+
+    async fn read_chunk(&mut self) -> Result<()> {
+        match self.resp.chunk().await? {
+            Some(chunk) => {
+                self.buf.extend_from_slice(&chunk);
+                Ok(())
+            }
+            None => bail!("Query response ended before its last row"),
+        }
+    }
+
+    async fn next_row(&mut self) -> Result<Option<Row>> {
+        loop {
+            match self.state {
+                StreamState::Done => return Ok(None),
+                StreamState::Header => {
+                    if self.parse_header()? {
+                        continue;
+                    }
+                }
+                StreamState::Rows => {
+                    let start = self
+                        .buf
+                        .iter()
+                        .position(|b| !b.is_ascii_whitespace() && *b != b',');
+                    if let Some(start) = start {
+                        if self.buf[start] == b']' {
+                            if let Some(trailer) = self.trailer(start + 1)? {
+                                self.state = StreamState::Done;
+                                self.affected = trailer.check()?;
+                                return Ok(None);
+                            }
+                        } else {
+                            let mut rows = serde_json::Deserializer::from_slice(&self.buf[start..])
+                                .into_iter::<Vec<DbValue>>();
+                            match rows.next() {
+                                Some(Ok(values)) => {
+                                    let end = start + rows.byte_offset();
+                                    self.buf.drain(..end);
+                                    return Ok(Some(Row::new(self.schema.clone(), values)));
+                                }
+                                Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+            self.read_chunk().await?;
+        }
+    }
+
+    // `,"row_count":N}` or `,"error":"..."}` from `at` on, once it is all in.
+    fn trailer(&self, at: usize) -> Result<Option<Trailer>> {
+        let rest = &self.buf[at..];
+        let Some(body) = rest.strip_prefix(b",") else {
+            return Ok(None);
+        };
+        let mut object = b"{".to_vec();
+        object.extend_from_slice(body);
+        match serde_json::from_slice(&object) {
+            Ok(trailer) => Ok(Some(trailer)),
+            Err(e) if e.is_eof() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
     }
 }
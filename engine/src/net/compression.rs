@@ -0,0 +1,111 @@
+use crate::net::server::ResponseBody;
+use flate2::{Compression, write::GzEncoder};
+use hyper::{
+    HeaderMap, Response,
+    body::Bytes,
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY},
+};
+use std::io::Write;
+use tokio::sync::mpsc;
+use tracing::error;
+
+// Bodies smaller than this go out as they are; gzip would save little and
+// could even make them larger.
+pub const GZIP_MIN_BYTES: usize = 1024;
+
+// Whether the client takes gzip, going by its Accept-Encoding header. A
+// `q=0` turns an encoding off.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+// Gzips a response body of at least GZIP_MIN_BYTES. A streamed body is read
+// until that much has arrived, so a small result still goes out whole and
+// uncompressed; a larger one is compressed chunk by chunk as it streams.
+pub async fn gzip(response: Response<ResponseBody>) -> Response<ResponseBody> {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.append(VARY, ACCEPT_ENCODING.into());
+    let body = match body {
+        ResponseBody::Full(Some(bytes)) if bytes.len() >= GZIP_MIN_BYTES => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            match encoder.write_all(&bytes).and_then(|_| encoder.finish()) {
+                Ok(compressed) => {
+                    parts
+                        .headers
+                        .insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+                    ResponseBody::Full(Some(Bytes::from(compressed)))
+                }
+                Err(e) => {
+                    error!("Compressing a response failed: {}", e);
+                    ResponseBody::Full(Some(bytes))
+                }
+            }
+        }
+        ResponseBody::Channel(mut chunks) => {
+            let mut head = Vec::new();
+            while head.len() < GZIP_MIN_BYTES {
+                match chunks.recv().await {
+                    Some(chunk) => head.extend_from_slice(&chunk),
+                    None => {
+                        return Response::from_parts(parts, ResponseBody::Full(Some(head.into())));
+                    }
+                }
+            }
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+            let (compressed_tx, compressed) = mpsc::channel(1);
+            tokio::spawn(compress_stream(head, chunks, compressed_tx));
+            ResponseBody::Channel(compressed)
+        }
+        body => body,
+    };
+    Response::from_parts(parts, body)
+}
+
+// Each chunk is flushed through the encoder as it comes, so a client reading
+// rows as they arrive still gets them without waiting for the end.
+async fn compress_stream(
+    head: Vec<u8>,
+    mut chunks: mpsc::Receiver<Bytes>,
+    out: mpsc::Sender<Bytes>,
+) {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut chunk = Bytes::from(head);
+    loop {
+        if let Err(e) = encoder.write_all(&chunk).and_then(|_| encoder.flush()) {
+            // The body is cut short, which the client sees as a broken
+            // stream rather than a valid but incomplete one.
+            error!("Compressing a response failed: {}", e);
+            return;
+        }
+        let compressed = std::mem::take(encoder.get_mut());
+        if out.send(Bytes::from(compressed)).await.is_err() {
+            return;
+        }
+        match chunks.recv().await {
+            Some(next) => chunk = next,
+            None => break,
+        }
+    }
+    match encoder.finish() {
+        Ok(rest) => {
+            let _ = out.send(Bytes::from(rest)).await;
+        }
+        Err(e) => error!("Compressing a response failed: {}", e),
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    query::{binder::Value, parser::CopyFormat},
+    storage::storage::{DataType, Storage},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    time::Instant,
+};
+
+// The rows of `COPY t FROM STDIN`, sent after the statement.
+//
+// As text, a row is a line of fields separated by tabs, with `\\`, `\t`,
+// `\n` and `\r` standing for what they escape. As binary, a row is a
+// big-endian u16 field count, then for each field a big-endian u32 length
+// and that many bytes: an INT is its 8 bytes, big-endian, and TEXT its
+// UTF-8. Either way the rows end where the body does.
+
+// No row is larger than a page, so a field of more than this is a
+// mistake, not one to allocate for.
+const MAX_FIELD_BYTES: usize = 1 << 20;
+
+// What a COPY answers with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CopyReport {
+    pub table: String,
+    pub rows: usize,
+    pub elapsed_ms: u64,
+    pub rows_per_second: u64,
+}
+
+// The rows sent do not fit the table, so none of them are added. Any
+// other error is the server's.
+#[derive(Debug)]
+pub struct BadCopy(pub String);
+
+impl fmt::Display for BadCopy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BadCopy {}
+
+// Adds the rows of `input` to `table`, in `columns` order, or in the
+// table's when none are listed. The caller owns the transaction and has
+// the whole table locked; it rolls back on error.
+pub fn copy_in<R: Read>(
+    storage: &mut Storage,
+    table: &str,
+    columns: &[String],
+    format: CopyFormat,
+    input: R,
+) -> Result<CopyReport> {
+    let started_at = Instant::now();
+    let declared = storage.catalog.get_table(table)?.columns.clone();
+    let columns: Vec<String> = match columns {
+        [] => declared.iter().map(|c| c.name.clone()).collect(),
+        listed => listed.to_vec(),
+    };
+    let types = columns
+        .iter()
+        .map(|name| {
+            declared
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(name))
+                .map(|c| c.data_type.clone())
+                .ok_or_else(|| BadCopy(format!("Table '{}' has no column '{}'", table, name)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut reader = RowReader {
+        input: BufReader::new(input),
+        format,
+        types: &types,
+        columns: &columns,
+        row: 0,
+    };
+    let rows = std::iter::from_fn(|| reader.next().transpose());
+    let rows = storage.bulk_insert(table, &columns, rows)?;
+    let elapsed = started_at.elapsed().as_millis() as u64;
+    Ok(CopyReport {
+        table: table.to_string(),
+        rows,
+        elapsed_ms: elapsed,
+        rows_per_second: rows as u64 * 1000 / elapsed.max(1),
+    })
+}
+
+struct RowReader<'a, R> {
+    input: BufReader<R>,
+    format: CopyFormat,
+    types: &'a [DataType],
+    columns: &'a [String],
+    // The row being read, counting from 1, so the one that fails can be
+    // named.
+    row: u64,
+}
+
+impl<R: Read> RowReader<'_, R> {
+    fn next(&mut self) -> Result<Option<Vec<Value>>> {
+        self.row += 1;
+        let fields = match self.format {
+            CopyFormat::Text => self.next_line()?,
+            CopyFormat::Binary => self.next_record()?,
+        };
+        let Some(fields) = fields else {
+            return Ok(None);
+        };
+        if fields.len() != self.types.len() {
+            return Err(self.bad(format!(
+                "Expected {} fields, found {}",
+                self.types.len(),
+                fields.len()
+            )));
+        }
+        fields
+            .into_iter()
+            .zip(self.types)
+            .zip(self.columns)
+            .map(|((field, data_type), column)| {
+                self.value(field, data_type)
+                    .map_err(|problem| self.bad(format!("Column '{}': {}", column, problem)))
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    fn value(&self, field: Vec<u8>, data_type: &DataType) -> Result<Value, String> {
+        match (self.format, data_type) {
+            (CopyFormat::Binary, DataType::Int) => <[u8; 8]>::try_from(field.as_slice())
+                .map(|bytes| Value::Int(i64::from_be_bytes(bytes)))
+                .map_err(|_| format!("An integer is 8 bytes, not {}", field.len())),
+            (CopyFormat::Text, DataType::Int) => {
+                let text = String::from_utf8_lossy(&field);
+                text.parse()
+                    .map(Value::Int)
+                    .map_err(|_| format!("{:?} is not an integer", text))
+            }
+            (_, DataType::String) => String::from_utf8(field)
+                .map(Value::String)
+                .map_err(|_| "Text is not UTF-8".to_string()),
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Option<Vec<Vec<u8>>>> {
+        let mut line = Vec::new();
+        let read = self
+            .input
+            .read_until(b'\n', &mut line)
+            .context("Reading the body failed")?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        let mut fields = vec![Vec::new()];
+        let mut bytes = line.into_iter();
+        while let Some(byte) = bytes.next() {
+            let field = fields.last_mut().unwrap();
+            match byte {
+                b'\t' => fields.push(Vec::new()),
+                b'\\' => match bytes.next() {
+                    Some(b't') => field.push(b'\t'),
+                    Some(b'n') => field.push(b'\n'),
+                    Some(b'r') => field.push(b'\r'),
+                    Some(b'\\') => field.push(b'\\'),
+                    other => {
+                        let escape = other.map_or(String::new(), |b| (b as char).to_string());
+                        return Err(self.bad(format!("Unknown escape \\{}", escape)));
+                    }
+                },
+                byte => field.push(byte),
+            }
+        }
+        Ok(Some(fields))
+    }
+
+    fn next_record(&mut self) -> Result<Option<Vec<Vec<u8>>>> {
+        let mut count = [0; 2];
+        if !self.read_exact(&mut count, true)? {
+            return Ok(None);
+        }
+        let count = u16::from_be_bytes(count) as usize;
+        let mut fields = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut len = [0; 4];
+            self.read_exact(&mut len, false)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_FIELD_BYTES {
+                return Err(self.bad(format!("A field of {} bytes is too large", len)));
+            }
+            let mut field = vec![0; len];
+            self.read_exact(&mut field, false)?;
+            fields.push(field);
+        }
+        Ok(Some(fields))
+    }
+
+    // Fills `buf`, or returns false when the body has ended right before
+    // it and that is where a row may end.
+    fn read_exact(&mut self, buf: &mut [u8], may_end: bool) -> Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.input.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 && may_end => return Ok(false),
+                Ok(0) => return Err(self.bad("The body ends inside it".to_string())),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(anyhow::Error::new(e).context("Reading the body failed")),
+            }
+        }
+        Ok(true)
+    }
+
+    fn bad(&self, problem: String) -> anyhow::Error {
+        BadCopy(format!("Row {}: {}", self.row, problem)).into()
+    }
+}
+
+// Writes `rows` in the binary format, as a client sends them.
+pub fn write_binary(out: &mut impl Write, rows: &[Vec<Value>]) -> io::Result<()> {
+    for row in rows {
+        let count = u16::try_from(row.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Too many fields"))?;
+        out.write_all(&count.to_be_bytes())?;
+        for value in row {
+            let bytes = match value {
+                Value::Int(i) => i.to_be_bytes().to_vec(),
+                Value::String(s) => s.as_bytes().to_vec(),
+            };
+            out.write_all(&(bytes.len() as u32).to_be_bytes())?;
+            out.write_all(&bytes)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,582 @@
+use crate::{
+    cli::utils::infer_columns,
+    query::{binder::Value, executor::Executor},
+    storage::storage::{DataType, Storage},
+};
+use anyhow::{Context, Result};
+use csv::{ByteRecord, QuoteStyle, ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, Read},
+    time::Instant,
+};
+use tokio::sync::mpsc;
+
+// Records sampled to pick column types when an import creates its table.
+const INFERENCE_ROWS: usize = 100;
+
+// A file that is wrong throughout would otherwise produce a report as large
+// as itself.
+const MAX_REPORTED_ERRORS: usize = 100;
+
+// Rows between progress reports when no batch is committed in between.
+const PROGRESS_ROWS: usize = 10_000;
+
+// How /tables/{name}/import and /export read and write CSV, from the query
+// string: `header=false`, `delimiter=;` (or `tab`), `quote='` (or `none`)
+// and, for imports, `create=true`, `null=%5CN`, `trim=true`, `skip=N`,
+// `columns=a,b`, `on_error=abort`, `batch=N` and `resume_after=N`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub header: bool,
+    pub delimiter: u8,
+    // None reads quote characters as any other.
+    pub quote: Option<u8>,
+    pub create: bool,
+    // A field that reads as NULL, such as `\N` or the empty string.
+    pub null: Option<String>,
+    pub trim: bool,
+    // Rows before the header, or before the first row without one. Blank
+    // lines do not count.
+    pub skip_rows: usize,
+    // The file's columns in order, in place of its header.
+    pub columns: Option<Vec<String>>,
+    pub on_error: OnError,
+    // Commit every this many rows rather than once at the end.
+    pub batch_rows: Option<usize>,
+    // The line an earlier import of the same file had committed up to.
+    // Rows starting on or before it are already in the table and are
+    // passed over.
+    pub resume_after: Option<u64>,
+}
+
+// What becomes of a row that does not fit the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    // Left out and listed in the report.
+    Skip,
+    // Fails the whole import.
+    Abort,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            header: true,
+            delimiter: b',',
+            quote: Some(b'"'),
+            create: false,
+            null: None,
+            trim: false,
+            skip_rows: 0,
+            columns: None,
+            on_error: OnError::Skip,
+            batch_rows: None,
+            resume_after: None,
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn from_query(query: Option<&str>) -> Result<Self, String> {
+        let mut options = CsvOptions::default();
+        for pair in query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|p| !p.is_empty())
+        {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "header" => options.header = flag(key, &value)?,
+                "create" => options.create = flag(key, &value)?,
+                "trim" => options.trim = flag(key, &value)?,
+                "delimiter" => {
+                    options.delimiter = match value.as_str() {
+                        "tab" => b'\t',
+                        v => one_byte(key, v)?,
+                    }
+                }
+                "quote" => {
+                    options.quote = match value.as_str() {
+                        "none" => None,
+                        v => Some(one_byte(key, v)?),
+                    }
+                }
+                "null" => options.null = Some(value),
+                "skip" => {
+                    options.skip_rows = value
+                        .parse()
+                        .map_err(|_| format!("skip must be a number, not {:?}", value))?
+                }
+                "columns" => {
+                    options.columns = Some(value.split(',').map(|c| c.trim().to_string()).collect())
+                }
+                "batch" => {
+                    options.batch_rows =
+                        Some(
+                            value.parse().ok().filter(|&n| n > 0).ok_or_else(|| {
+                                format!("batch must be a row count, not {:?}", value)
+                            })?,
+                        )
+                }
+                "resume_after" => {
+                    options.resume_after = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("resume_after must be a line, not {:?}", value))?,
+                    )
+                }
+                "on_error" => {
+                    options.on_error = match value.as_str() {
+                        "skip" => OnError::Skip,
+                        "abort" => OnError::Abort,
+                        v => return Err(format!("on_error must be skip or abort, not {:?}", v)),
+                    }
+                }
+                other => return Err(format!("Unknown CSV option {:?}", other)),
+            }
+        }
+        Ok(options)
+    }
+
+    // The query string `from_query` reads back, as the client sends it.
+    pub fn to_query(&self) -> String {
+        let mut query = format!(
+            "header={}&delimiter=%{:02X}&create={}&trim={}&skip={}",
+            self.header, self.delimiter, self.create, self.trim, self.skip_rows
+        );
+        match self.quote {
+            Some(quote) => query.push_str(&format!("&quote=%{:02X}", quote)),
+            None => query.push_str("&quote=none"),
+        }
+        if let Some(null) = &self.null {
+            query.push_str(&format!("&null={}", percent_encode(null)));
+        }
+        if let Some(columns) = &self.columns {
+            let columns: Vec<String> = columns.iter().map(|c| percent_encode(c)).collect();
+            query.push_str(&format!("&columns={}", columns.join(",")));
+        }
+        if self.on_error == OnError::Abort {
+            query.push_str("&on_error=abort");
+        }
+        if let Some(rows) = self.batch_rows {
+            query.push_str(&format!("&batch={}", rows));
+        }
+        if let Some(line) = self.resume_after {
+            query.push_str(&format!("&resume_after={}", line));
+        }
+        query
+    }
+}
+
+fn one_byte(key: &str, value: &str) -> Result<u8, String> {
+    match value.as_bytes() {
+        &[b] if b.is_ascii() => Ok(b),
+        _ => Err(format!("{} must be one character, not {:?}", key, value)),
+    }
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("Bad escape in {:?}", value))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("{:?} is not UTF-8", value))
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn flag(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        v => Err(format!("{} must be true or false, not {:?}", key, v)),
+    }
+}
+
+// What an import answers with. Rows listed in `errors` were skipped; the
+// rest are in the table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub table: String,
+    pub created: bool,
+    pub rows_imported: usize,
+    // All rows skipped, of which only the first few are in `errors`.
+    pub error_count: usize,
+    pub errors: Vec<RowError>,
+    pub elapsed_ms: u64,
+    pub rows_per_second: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RowError {
+    // Line of the file the row starts on, counting from 1.
+    pub line: u64,
+    // The table column whose value did not fit, if it was one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<String>,
+    pub error: String,
+}
+
+// How far an import has got, reported every few thousand rows and after
+// each committed batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportProgress {
+    pub rows_imported: usize,
+    pub bytes_read: u64,
+    pub rows_per_second: u64,
+    // Line of the last row of the last committed batch, if one was: what
+    // to resume after should the import not finish.
+    pub committed_line: Option<u64>,
+}
+
+// The file as a whole does not fit the table, so nothing is imported. Any
+// other error is the server's.
+#[derive(Debug)]
+pub struct BadCsv(pub String);
+
+impl fmt::Display for BadCsv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BadCsv {}
+
+// A request body read from a blocking task, as the connection hands it over.
+pub struct BodyReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl BodyReader {
+    pub fn new(chunks: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+        BodyReader {
+            chunks,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+// Adds the rows of `input` to `table`, creating it first when it is missing
+// and `options.create` is set. Rows that do not fit are skipped and reported.
+// The caller owns the transaction and rolls it back on error; with
+// `options.batch_rows` set, `commit` is called with the rows so far each
+// time that many more are in, to commit them and start the next batch.
+// `progress` hears how far it has got after each batch and every
+// `PROGRESS_ROWS` rows.
+pub fn import<R: Read>(
+    storage: &mut Storage,
+    table: &str,
+    input: R,
+    options: CsvOptions,
+    mut commit: impl FnMut(&mut Storage, usize) -> Result<()>,
+    mut progress: impl FnMut(&ImportProgress),
+) -> Result<ImportReport> {
+    let started_at = Instant::now();
+    // Headers are read by hand, after any lines to skip.
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(options.delimiter)
+        .quote(options.quote.unwrap_or(b'"'))
+        .quoting(options.quote.is_some())
+        .trim(if options.trim { Trim::All } else { Trim::None })
+        .flexible(true)
+        .from_reader(input);
+    for _ in 0..options.skip_rows {
+        // What is skipped need not parse.
+        match reader.read_byte_record(&mut ByteRecord::new()) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) if e.is_io_error() => return Err(read_error(e)),
+            Err(_) => {}
+        }
+    }
+    let mut header = StringRecord::new();
+    if options.header {
+        reader.read_record(&mut header).map_err(read_error)?;
+    }
+    let headers = match (&options.columns, options.header) {
+        (Some(columns), _) => Some(StringRecord::from(columns.clone())),
+        (None, true) => Some(header),
+        (None, false) => None,
+    };
+    let mut report = ImportReport {
+        table: table.to_string(),
+        created: false,
+        rows_imported: 0,
+        error_count: 0,
+        errors: Vec::new(),
+        elapsed_ms: 0,
+        rows_per_second: 0,
+    };
+
+    // Records read to infer the column types are imported like any other.
+    let mut sample = Vec::new();
+    if storage.catalog.get_table(table).is_err() {
+        if !options.create {
+            return Err(BadCsv(format!("Table '{}' does not exist", table)).into());
+        }
+        let Some(headers) = &headers else {
+            return Err(
+                BadCsv("Creating a table needs a header row or a column list".to_string()).into(),
+            );
+        };
+        while sample.len() < INFERENCE_ROWS {
+            match next_record(&mut reader, &mut report, &options)? {
+                Some(record) => sample.push(record),
+                None => break,
+            }
+        }
+        let mut columns = infer_columns(headers, &sample);
+        for column in &mut columns {
+            column.name = column.name.trim().to_ascii_uppercase();
+        }
+        storage.create_table(table.to_string(), columns)?;
+        report.created = true;
+    }
+
+    let columns = storage.catalog.get_table(table)?.columns.clone();
+    let names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+    // Where each table column is found in a record.
+    let fields: Vec<usize> = match &headers {
+        Some(headers) => {
+            let header: Vec<&str> = headers.iter().map(str::trim).collect();
+            if let Some(extra) = header
+                .iter()
+                .find(|h| !names.iter().any(|n| n.eq_ignore_ascii_case(h)))
+            {
+                return Err(BadCsv(format!("Table '{}' has no column '{}'", table, extra)).into());
+            }
+            names
+                .iter()
+                .map(|name| {
+                    header
+                        .iter()
+                        .position(|h| h.eq_ignore_ascii_case(name))
+                        .ok_or_else(|| BadCsv(format!("Header has no column '{}'", name)))
+                })
+                .collect::<Result<_, _>>()?
+        }
+        None => (0..names.len()).collect(),
+    };
+
+    let mut sample = sample.into_iter();
+    let mut committed_line = None;
+    loop {
+        let record = match sample.next() {
+            Some(record) => record,
+            None => match next_record(&mut reader, &mut report, &options)? {
+                Some(record) => record,
+                None => break,
+            },
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        if options.resume_after.is_some_and(|done| line <= done) {
+            continue;
+        }
+        if record.len() != fields.len() {
+            let message = format!("Expected {} fields, found {}", fields.len(), record.len());
+            let error = RowError {
+                line,
+                column: None,
+                error: message,
+            };
+            reject(&mut report, error, options.on_error)?;
+            continue;
+        }
+        // Each field is read as its column is declared, so a table never
+        // gets a value of the wrong type.
+        let values: Result<Vec<Value>, RowError> = columns
+            .iter()
+            .zip(&fields)
+            .map(|(column, &field)| {
+                let text = &record[field];
+                let bad = |problem: String| RowError {
+                    line,
+                    column: Some(column.name.clone()),
+                    error: format!("Column '{}': {}", column.name, problem),
+                };
+                if options.null.as_deref() == Some(text) {
+                    // Nothing in a table can hold NULL.
+                    return Err(bad("NULL is not supported".to_string()));
+                }
+                match column.data_type {
+                    DataType::Int => text
+                        .trim()
+                        .parse()
+                        .map(Value::Int)
+                        .map_err(|_| bad(format!("{:?} is not an integer", text))),
+                    DataType::String => Ok(Value::String(text.to_string())),
+                }
+            })
+            .collect();
+        match values {
+            Ok(values) => {
+                storage
+                    .insert_row(table, &names, values)
+                    .with_context(|| format!("Insert of line {} failed", line))?;
+                report.rows_imported += 1;
+                let batch_done = options
+                    .batch_rows
+                    .is_some_and(|rows| report.rows_imported.is_multiple_of(rows));
+                if batch_done {
+                    commit(storage, report.rows_imported)?;
+                    committed_line = Some(line);
+                }
+                if batch_done || report.rows_imported.is_multiple_of(PROGRESS_ROWS) {
+                    progress(&ImportProgress {
+                        rows_imported: report.rows_imported,
+                        bytes_read: reader.position().byte(),
+                        rows_per_second: rate(report.rows_imported, started_at),
+                        committed_line,
+                    });
+                }
+            }
+            Err(error) => reject(&mut report, error, options.on_error)?,
+        }
+    }
+    // Records that did not parse while sampling were reported first.
+    report.errors.sort_by_key(|e| e.line);
+    report.elapsed_ms = started_at.elapsed().as_millis() as u64;
+    report.rows_per_second = rate(report.rows_imported, started_at);
+    Ok(report)
+}
+
+fn rate(rows: usize, started_at: Instant) -> u64 {
+    rows as u64 * 1000 / (started_at.elapsed().as_millis() as u64).max(1)
+}
+
+// The next record that parses. Ones that do not are rejected like any bad
+// row, unless they were already passed over by the import being resumed;
+// failing to read the body ends the import.
+fn next_record<R: Read>(
+    reader: &mut csv::Reader<R>,
+    report: &mut ImportReport,
+    options: &CsvOptions,
+) -> Result<Option<StringRecord>> {
+    loop {
+        let mut record = StringRecord::new();
+        match reader.read_record(&mut record) {
+            Ok(true) => return Ok(Some(record)),
+            Ok(false) => return Ok(None),
+            Err(e) if e.is_io_error() => return Err(read_error(e)),
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                if options.resume_after.is_some_and(|done| line <= done) {
+                    continue;
+                }
+                let error = RowError {
+                    line,
+                    column: None,
+                    error: e.to_string(),
+                };
+                reject(report, error, options.on_error)?;
+            }
+        }
+    }
+}
+
+fn read_error(e: csv::Error) -> anyhow::Error {
+    if e.is_io_error() {
+        anyhow::Error::new(e).context("Reading the body failed")
+    } else {
+        BadCsv(e.to_string()).into()
+    }
+}
+
+fn reject(report: &mut ImportReport, error: RowError, on_error: OnError) -> Result<()> {
+    if on_error == OnError::Abort {
+        return Err(BadCsv(format!("Line {}: {}", error.line, error.error)).into());
+    }
+    report.error_count += 1;
+    if report.errors.len() < MAX_REPORTED_ERRORS {
+        report.errors.push(error);
+    }
+    Ok(())
+}
+
+// Writes what `exec` produces as CSV under a header of `columns`, passing it
+// to `send` `rows_per_chunk` rows at a time. Returns the number of rows.
+pub fn export(
+    exec: &mut Executor,
+    columns: &[String],
+    options: CsvOptions,
+    rows_per_chunk: usize,
+    mut send: impl FnMut(Bytes) -> Result<()>,
+) -> Result<usize> {
+    // A chunk is what one writer wrote; the next chunk gets a new one.
+    let builder = {
+        let mut builder = WriterBuilder::new();
+        builder
+            .delimiter(options.delimiter)
+            .quote(options.quote.unwrap_or(b'"'))
+            .quote_style(match options.quote {
+                Some(_) => QuoteStyle::Necessary,
+                None => QuoteStyle::Never,
+            });
+        builder
+    };
+    let mut writer = builder.from_writer(Vec::new());
+    if options.header {
+        writer.write_record(columns)?;
+    }
+    exec.open()?;
+    let mut rows = 0;
+    while let Some(tuple) = exec.next_row()? {
+        writer.write_record(tuple.into_iter().map(|value| match value {
+            Value::Int(i) => i.to_string(),
+            Value::String(s) => s,
+        }))?;
+        rows += 1;
+        if rows % rows_per_chunk == 0 {
+            let full = std::mem::replace(&mut writer, builder.from_writer(Vec::new()));
+            send(Bytes::from(full.into_inner()?))?;
+        }
+    }
+    exec.close()?;
+    let rest = writer.into_inner()?;
+    if !rest.is_empty() {
+        send(Bytes::from(rest))?;
+    }
+    Ok(rows)
+}
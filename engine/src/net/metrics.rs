@@ -0,0 +1,275 @@
+use crate::{
+    net::{admission::Admission, result_cache::ResultCache},
+    query::parser::Statement,
+    storage::buffer_pool::PoolStats,
+    tx::{lock_manager::LockManager, log_manager::LogManager, mvcc::TxStatusTable},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+// What the server counts itself. The rest of /metrics is read from the
+// buffer pool, WAL, lock manager, transaction table and catalog when it is
+// served.
+#[derive(Default)]
+pub struct Metrics {
+    queries: Mutex<BTreeMap<&'static str, u64>>,
+    latency: Mutex<Histogram>,
+    retried: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Per bucket, not cumulative; `render` adds them up.
+    counts: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+// The sources `Metrics::render` reads gauges and counters from.
+pub struct Sources<'a> {
+    pub pool: &'a PoolStats,
+    pub wal: &'a LogManager,
+    pub locks: &'a LockManager,
+    pub txns: &'a TxStatusTable,
+    pub admission: &'a Admission,
+    pub result_cache: &'a ResultCache,
+    // Each table's deleted rows not yet vacuumed, by table name.
+    pub dead_rows: &'a [(String, u64)],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_query(&self, kind: &'static str) {
+        *self.queries.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn observe_latency(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut latency = self.latency.lock().unwrap();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            latency.counts[i] += 1;
+        }
+        latency.sum += secs;
+        latency.count += 1;
+    }
+
+    // Counts a statement run again after losing a lock conflict, once
+    // however many runs it took.
+    pub fn record_retried(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Everything in the Prometheus text exposition format.
+    pub fn render(&self, sources: &Sources) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP mydb_queries_total Statements received, by type.\n");
+        out.push_str("# TYPE mydb_queries_total counter\n");
+        for (kind, count) in self.queries.lock().unwrap().iter() {
+            writeln!(out, "mydb_queries_total{{type=\"{}\"}} {}", kind, count).unwrap();
+        }
+
+        out.push_str("# HELP mydb_query_duration_seconds Time to run a statement.\n");
+        out.push_str("# TYPE mydb_query_duration_seconds histogram\n");
+        {
+            let latency = self.latency.lock().unwrap();
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.counts) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "mydb_query_duration_seconds_bucket{{le=\"{}\"}} {}",
+                    bound, cumulative
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "mydb_query_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+                latency.count
+            )
+            .unwrap();
+            writeln!(out, "mydb_query_duration_seconds_sum {}", latency.sum).unwrap();
+            writeln!(out, "mydb_query_duration_seconds_count {}", latency.count).unwrap();
+        }
+
+        let mut single = |name: &str, typ: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, typ).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        };
+        single(
+            "mydb_buffer_pool_hits_total",
+            "counter",
+            "Page requests served from the buffer pool.",
+            sources.pool.hits.load(Ordering::Relaxed),
+        );
+        single(
+            "mydb_buffer_pool_misses_total",
+            "counter",
+            "Page requests that had to read the page from disk.",
+            sources.pool.misses.load(Ordering::Relaxed),
+        );
+        single(
+            "mydb_buffer_pool_prefetched_total",
+            "counter",
+            "Pages read ahead of a sequential scan.",
+            sources.pool.prefetched.load(Ordering::Relaxed),
+        );
+        single(
+            "mydb_buffer_pool_prefetch_hits_total",
+            "counter",
+            "Page requests served from a page read ahead.",
+            sources.pool.prefetch_hits.load(Ordering::Relaxed),
+        );
+        single(
+            "mydb_active_transactions",
+            "gauge",
+            "Transactions begun and not yet committed or rolled back.",
+            sources.txns.active_count() as u64,
+        );
+        single(
+            "mydb_queries_retried_total",
+            "counter",
+            "Statements run again after losing a lock conflict.",
+            self.retried.load(Ordering::Relaxed),
+        );
+        single(
+            "mydb_lock_waits_total",
+            "counter",
+            "Lock requests that had to wait for another transaction.",
+            sources.locks.waits(),
+        );
+        single(
+            "mydb_wal_bytes_written_total",
+            "counter",
+            "Bytes written to the WAL since startup.",
+            sources.wal.bytes_written(),
+        );
+        let (checkpoint_lsn, checkpoint_took) = sources.wal.last_checkpoint().unwrap_or_default();
+        single(
+            "mydb_last_checkpoint_lsn",
+            "gauge",
+            "LSN of the last checkpoint since startup, 0 before the first.",
+            checkpoint_lsn,
+        );
+        single(
+            "mydb_queries_in_flight",
+            "gauge",
+            "Statements running now, batches and CSV transfers included.",
+            sources.admission.in_flight() as u64,
+        );
+
+        single(
+            "mydb_result_cache_hits_total",
+            "counter",
+            "SELECTs answered from the result cache.",
+            sources.result_cache.hits(),
+        );
+        single(
+            "mydb_result_cache_misses_total",
+            "counter",
+            "SELECTs looked up in the result cache and not found.",
+            sources.result_cache.misses(),
+        );
+        single(
+            "mydb_result_cache_bytes",
+            "gauge",
+            "Bytes of responses held by the result cache.",
+            sources.result_cache.used() as u64,
+        );
+
+        out.push_str(
+            "# HELP mydb_last_checkpoint_duration_seconds Time the last checkpoint took to flush \
+             the buffer pool and log itself.\n",
+        );
+        out.push_str("# TYPE mydb_last_checkpoint_duration_seconds gauge\n");
+        writeln!(
+            out,
+            "mydb_last_checkpoint_duration_seconds {}",
+            checkpoint_took.as_secs_f64()
+        )
+        .unwrap();
+
+        out.push_str("# HELP mydb_admission_rejections_total Requests turned away, by reason.\n");
+        out.push_str("# TYPE mydb_admission_rejections_total counter\n");
+        for (reason, count) in [
+            ("busy", sources.admission.rejected_busy()),
+            ("rate_limited", sources.admission.rejected_rate()),
+        ] {
+            writeln!(
+                out,
+                "mydb_admission_rejections_total{{reason=\"{}\"}} {}",
+                reason, count
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP mydb_table_dead_rows Deleted rows still taking up a table's heap pages \
+             until VACUUM.\n",
+        );
+        out.push_str("# TYPE mydb_table_dead_rows gauge\n");
+        for (table, count) in sources.dead_rows {
+            writeln!(out, "mydb_table_dead_rows{{table=\"{}\"}} {}", table, count).unwrap();
+        }
+        out
+    }
+}
+
+// The `type` label a statement is counted under.
+pub fn statement_kind(stmt: &Statement) -> &'static str {
+    match stmt {
+        Statement::Select { .. }
+        | Statement::UnionAll { .. }
+        | Statement::DeclareCursor { .. }
+        | Statement::Fetch { .. } => "select",
+        Statement::Insert { .. } | Statement::Copy { .. } => "insert",
+        Statement::Delete { .. } => "delete",
+        Statement::CreateTable { .. }
+        | Statement::CreateTableAs { .. }
+        | Statement::CreateIndex { .. }
+        | Statement::Reindex { .. }
+        | Statement::Analyze { .. }
+        | Statement::Vacuum { .. }
+        | Statement::DropTable { .. }
+        | Statement::CreateSequence { .. }
+        | Statement::DropSequence { .. }
+        | Statement::CreateView { .. }
+        | Statement::DropView { .. }
+        | Statement::AddCheck { .. }
+        | Statement::RenameTable { .. }
+        | Statement::RenameIndex { .. }
+        | Statement::CreateSchema { .. }
+        | Statement::DropSchema { .. } => "ddl",
+        Statement::Explain { .. }
+        | Statement::ShowLocks
+        | Statement::ShowTables { .. }
+        | Statement::ShowGrants
+        | Statement::Check
+        | Statement::Checkpoint
+        | Statement::FlushTables { .. }
+        | Statement::Set { .. }
+        | Statement::SetVariable { .. }
+        | Statement::ShowSettings { .. } => "utility",
+        Statement::Begin
+        | Statement::Commit
+        | Statement::Rollback
+        | Statement::SetConstraints { .. }
+        | Statement::CloseCursor { .. } => "transaction",
+        Statement::CreateUser { .. }
+        | Statement::DropUser { .. }
+        | Statement::Grant { .. }
+        | Statement::Revoke { .. } => "user",
+    }
+}
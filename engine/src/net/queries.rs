@@ -0,0 +1,267 @@
+use crate::net::audit::AuditEntry;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+// How much of a statement's text an entry keeps.
+const KEPT_SQL_CHARS: usize = 1000;
+
+// The queries a server is running and the last few it finished, for
+// /debug/queries. A query is registered when it arrives and moves to the
+// finished ones when its `RunningQuery` is dropped.
+pub struct QueryRegistry {
+    running: Mutex<BTreeMap<u64, Arc<QueryContext>>>,
+    finished: Mutex<VecDeque<QueryEntry>>,
+    keep_finished: usize,
+}
+
+// Where a running query has got to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryState {
+    // Waiting for a slot to run in.
+    Queued,
+    Parsing,
+    // Waiting for a table lock or for the storage.
+    WaitingOnLock,
+    // Being checked, bound and planned.
+    Planning,
+    Executing,
+    Finished,
+    Failed,
+}
+
+// A query as /debug/queries lists it. Phases are in microseconds and absent
+// until the query has been through them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryEntry {
+    pub id: u64,
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    pub sql: String,
+    pub state: QueryState,
+    // Rows produced so far, or in all once finished.
+    pub rows: u64,
+    // Since the query arrived, or how long it took once finished.
+    pub elapsed_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parse_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_wait_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execute_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// What /debug/queries answers with, running queries oldest first and
+// finished ones newest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryList {
+    pub running: Vec<QueryEntry>,
+    pub finished: Vec<QueryEntry>,
+}
+
+// One running query, shared by whoever moves it along.
+pub struct QueryContext {
+    pub id: u64,
+    pub user: String,
+    // What the statement or its session was tagged with.
+    pub tag: Option<String>,
+    sql: String,
+    started_at: Instant,
+    rows: AtomicU64,
+    // Set to stop the query, as its timeout does.
+    pub cancel: Arc<AtomicBool>,
+    progress: Mutex<Progress>,
+    // The audit log's entry for the statement, written by whoever sees it
+    // end.
+    audit: Mutex<Option<AuditEntry>>,
+}
+
+#[derive(Debug)]
+struct Progress {
+    state: QueryState,
+    // When the state was entered, to time the phase it ends.
+    since: Instant,
+    parse_us: Option<u64>,
+    lock_wait_us: Option<u64>,
+    plan_us: Option<u64>,
+    execute_us: Option<u64>,
+    error: Option<String>,
+}
+
+impl QueryContext {
+    // Moves the query on to `state`, adding the time since the last move
+    // to the phase it leaves. A phase may be entered more than once.
+    pub fn enter(&self, state: QueryState) {
+        let mut progress = self.progress.lock().unwrap();
+        let now = Instant::now();
+        let spent = now.duration_since(progress.since).as_micros() as u64;
+        let phase = match progress.state {
+            QueryState::Parsing => Some(&mut progress.parse_us),
+            QueryState::WaitingOnLock => Some(&mut progress.lock_wait_us),
+            QueryState::Planning => Some(&mut progress.plan_us),
+            QueryState::Executing => Some(&mut progress.execute_us),
+            QueryState::Queued | QueryState::Finished | QueryState::Failed => None,
+        };
+        if let Some(phase) = phase {
+            *phase = Some(phase.unwrap_or(0) + spent);
+        }
+        progress.state = state;
+        progress.since = now;
+    }
+
+    pub fn add_row(&self) {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Marks the query as failed with `error` once it is done.
+    pub fn fail(&self, error: &str) {
+        self.progress.lock().unwrap().error = Some(error.to_string());
+    }
+
+    pub fn set_audit(&self, entry: Option<AuditEntry>) {
+        *self.audit.lock().unwrap() = entry;
+    }
+
+    pub fn take_audit(&self) -> Option<AuditEntry> {
+        self.audit.lock().unwrap().take()
+    }
+
+    fn entry(&self) -> QueryEntry {
+        let progress = self.progress.lock().unwrap();
+        QueryEntry {
+            id: self.id,
+            user: self.user.clone(),
+            tag: self.tag.clone(),
+            sql: self.sql.clone(),
+            state: progress.state,
+            rows: self.rows.load(Ordering::Relaxed),
+            elapsed_ms: self.started_at.elapsed().as_millis() as u64,
+            parse_us: progress.parse_us,
+            lock_wait_us: progress.lock_wait_us,
+            plan_us: progress.plan_us,
+            execute_us: progress.execute_us,
+            error: progress.error.clone(),
+        }
+    }
+}
+
+// A registered query, moved to the finished ones when the last clone is
+// dropped, however the query ended.
+#[derive(Clone)]
+pub struct RunningQuery(Arc<Registered>);
+
+struct Registered {
+    registry: Arc<QueryRegistry>,
+    context: Arc<QueryContext>,
+}
+
+impl std::ops::Deref for RunningQuery {
+    type Target = QueryContext;
+
+    fn deref(&self) -> &QueryContext {
+        &self.0.context
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let failed = self.context.progress.lock().unwrap().error.is_some();
+        self.context.enter(match failed {
+            true => QueryState::Failed,
+            false => QueryState::Finished,
+        });
+        self.registry.finish(self.context.id);
+    }
+}
+
+impl QueryRegistry {
+    // Keeps the last `keep_finished` queries once they are done.
+    pub fn new(keep_finished: usize) -> Arc<Self> {
+        Arc::new(QueryRegistry {
+            running: Mutex::new(BTreeMap::new()),
+            finished: Mutex::new(VecDeque::with_capacity(keep_finished)),
+            keep_finished,
+        })
+    }
+
+    pub fn start(
+        self: &Arc<Self>,
+        id: u64,
+        user: &str,
+        tag: Option<&str>,
+        sql: &str,
+        cancel: Arc<AtomicBool>,
+    ) -> RunningQuery {
+        let now = Instant::now();
+        let sql = match sql.char_indices().nth(KEPT_SQL_CHARS) {
+            Some((end, _)) => &sql[..end],
+            None => sql,
+        };
+        let context = Arc::new(QueryContext {
+            id,
+            user: user.to_string(),
+            tag: tag.map(str::to_string),
+            sql: sql.to_string(),
+            started_at: now,
+            rows: AtomicU64::new(0),
+            cancel,
+            progress: Mutex::new(Progress {
+                state: QueryState::Queued,
+                since: now,
+                parse_us: None,
+                lock_wait_us: None,
+                plan_us: None,
+                execute_us: None,
+                error: None,
+            }),
+            audit: Mutex::new(None),
+        });
+        self.running.lock().unwrap().insert(id, context.clone());
+        RunningQuery(Arc::new(Registered {
+            registry: self.clone(),
+            context,
+        }))
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<QueryContext>> {
+        self.running.lock().unwrap().get(&id).cloned()
+    }
+
+    // The queries `user` may see, or everyone's for None.
+    pub fn list(&self, user: Option<&str>) -> QueryList {
+        let visible = |entry: &QueryEntry| user.is_none_or(|u| entry.user.eq_ignore_ascii_case(u));
+        let running = self.running.lock().unwrap();
+        let running = running.values().map(|q| q.entry()).filter(visible);
+        let finished = self.finished.lock().unwrap();
+        let finished = finished.iter().rev().filter(|e| visible(e));
+        QueryList {
+            running: running.collect(),
+            finished: finished.cloned().collect(),
+        }
+    }
+
+    fn finish(&self, id: u64) {
+        let Some(context) = self.running.lock().unwrap().remove(&id) else {
+            return;
+        };
+        let mut finished = self.finished.lock().unwrap();
+        if finished.len() == self.keep_finished {
+            finished.pop_front();
+        }
+        if self.keep_finished > 0 {
+            finished.push_back(context.entry());
+        }
+    }
+}
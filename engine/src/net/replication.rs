@@ -0,0 +1,361 @@
+use crate::{
+    net::{auth::Secret, client::SqlClient, result_cache::ResultCache},
+    storage::storage::{Catalog, IndexInfo, IndexKind, Storage},
+    tx::{
+        log_manager::{
+            CheckpointPayload, CompensationPayload, LogManager, LogRecordType, TxId, UpdatePayload,
+            write_frame,
+        },
+        wal_reader::{WalReader, deserialize_record},
+    },
+};
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock, watch};
+use tracing::{debug, error, info};
+
+// Most WAL one /wal response carries; a standby further behind than this
+// asks again for the rest.
+pub const MAX_WAL_FETCH_BYTES: usize = 1024 * 1024;
+
+// Read by `main` for the password a standby logs in to its primary with.
+pub const STANDBY_PASSWORD_ENV: &str = "MYDB_STANDBY_PASSWORD";
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// A standby's own transactions only read, but they share the status table
+// with the primary's replayed ones. Its ids start here, well clear of any
+// the primary hands out, so a reader never takes a writer's changes for its
+// own.
+pub const STANDBY_TX_IDS: TxId = 1 << 62;
+
+// What /replication answers with: the primary's log from `wal_base` to
+// `wal_end`, and its catalog as of `wal_end`. The catalog is left out when
+// the standby already has everything up to there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationPoint {
+    pub wal_base: u64,
+    pub wal_end: u64,
+    pub catalog: Option<Catalog>,
+}
+
+// The standby asked for log the primary has since truncated.
+#[derive(Debug)]
+pub struct WalTruncated {
+    pub from: u64,
+    pub base: u64,
+}
+
+impl fmt::Display for WalTruncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "WAL offset {} has been truncated (log starts at {})",
+            self.from, self.base
+        )
+    }
+}
+
+impl std::error::Error for WalTruncated {}
+
+// The records of the log at `wal_path` from offset `from` up to `to`,
+// framed as on disk. Stops early at a record boundary once
+// MAX_WAL_FETCH_BYTES are read.
+pub fn read_wal(wal_path: &Path, from: u64, to: u64) -> Result<Vec<u8>> {
+    let mut reader = WalReader::open(wal_path)?;
+    if from < reader.base() {
+        return Err(WalTruncated {
+            from,
+            base: reader.base(),
+        }
+        .into());
+    }
+    reader.seek(from)?;
+    let mut wal = Vec::new();
+    while wal.len() < MAX_WAL_FETCH_BYTES && reader.position()? < to {
+        match reader.next_frame()? {
+            Some(body) => write_frame(&mut wal, &body),
+            None => break,
+        }
+    }
+    Ok(wal)
+}
+
+// Splits what `read_wal` produced back into record bodies.
+fn frames(mut wal: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut bodies = Vec::new();
+    while !wal.is_empty() {
+        let len = wal
+            .get(..4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(|| anyhow!("Shipped WAL ends inside a record"))?;
+        let frame = wal
+            .get(..4 + len + 4)
+            .ok_or_else(|| anyhow!("Shipped WAL ends inside a record"))?;
+        let crc = u32::from_le_bytes(frame[4 + len..].try_into().unwrap());
+        if crc32fast::hash(&frame[..4 + len]) != crc {
+            bail!("Shipped WAL record fails its checksum");
+        }
+        bodies.push(&frame[4..4 + len]);
+        wal = &wal[frame.len()..];
+    }
+    Ok(bodies)
+}
+
+// Where a standby finds its primary, and the account it reads the log as.
+#[derive(Debug, Clone)]
+pub struct StandbyConfig {
+    pub primary: String,
+    pub user: String,
+    pub password: Secret,
+    pub poll_interval: Duration,
+}
+
+// A server following a primary: it redoes the primary's log on its own
+// pages and takes no writes until promoted.
+#[derive(Default)]
+pub struct Standby {
+    promoted: AtomicBool,
+    replayed: Mutex<Replayed>,
+}
+
+#[derive(Default)]
+struct Replayed {
+    // Offset of the primary's log to fetch next; unset until the first poll,
+    // which starts at the oldest log the primary has.
+    applied: Option<u64>,
+    // Transactions begun on the primary and not yet ended.
+    active: HashSet<TxId>,
+    // The primary's indexes. Index pages are not logged, so the standby's
+    // queries scan instead, and the indexes are built on promotion.
+    indexes: HashMap<String, Vec<IndexInfo>>,
+}
+
+impl Standby {
+    pub fn new() -> Self {
+        Standby::default()
+    }
+
+    pub fn is_promoted(&self) -> bool {
+        self.promoted.load(Ordering::SeqCst)
+    }
+
+    // Offset of the primary's log replayed up to, if any has been.
+    pub fn applied(&self) -> Option<u64> {
+        self.replayed.lock().unwrap().applied
+    }
+
+    // Polls the primary until promoted or until `stop` turns true. A failed
+    // poll is logged and retried on the next, logging in again first in
+    // case the login expired.
+    pub async fn run(
+        self: Arc<Self>,
+        config: StandbyConfig,
+        storage: Arc<RwLock<Storage>>,
+        wal: Arc<LogManager>,
+        cache: Arc<ResultCache>,
+        mut stop: watch::Receiver<bool>,
+    ) {
+        let client = SqlClient::new(&config.primary);
+        let mut logged_in = false;
+        let mut ticker = tokio::time::interval(config.poll_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        info!("Following primary {}", config.primary);
+        while !self.is_promoted() {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = stop.changed() => break,
+            }
+            let polled = async {
+                if !logged_in {
+                    client.login(&config.user, &config.password.0).await?;
+                    logged_in = true;
+                }
+                self.catch_up(&client, &storage, &wal, &cache).await
+            };
+            if let Err(e) = polled.await {
+                error!("Replaying the primary's WAL failed: {:#}", e);
+                logged_in = false;
+            }
+        }
+        info!("Stopped following primary {}", config.primary);
+    }
+
+    async fn catch_up(
+        self: &Arc<Self>,
+        client: &SqlClient,
+        storage: &Arc<RwLock<Storage>>,
+        wal: &Arc<LogManager>,
+        cache: &ResultCache,
+    ) -> Result<()> {
+        let applied = self.applied();
+        let point = client.replication_point(applied).await?;
+        let Some(catalog) = point.catalog else {
+            return Ok(());
+        };
+        let mut from = applied.unwrap_or(point.wal_base);
+        if from < point.wal_base {
+            bail!(
+                "The primary's WAL starts at {} but the standby needs it from {}; \
+                 start the standby again from a fresh copy of the data directory",
+                point.wal_base,
+                from
+            );
+        }
+        let mut log = Vec::new();
+        while from < point.wal_end {
+            let chunk = client.wal(from, point.wal_end).await?;
+            if chunk.is_empty() {
+                bail!("The primary sent no WAL from offset {}", from);
+            }
+            from += chunk.len() as u64;
+            log.extend_from_slice(&chunk);
+        }
+        let storage = storage.clone().write_owned().await;
+        let standby = self.clone();
+        let wal = wal.clone();
+        tokio::task::spawn_blocking(move || {
+            standby.apply(storage, &log, catalog, point.wal_end, &wal)
+        })
+        .await??;
+        // Which tables the log touched is not worth working out; every
+        // cached result may be stale now.
+        cache.clear();
+        Ok(())
+    }
+
+    // Redoes `log` and puts the primary's catalog in place of this server's,
+    // all under one storage lock so readers see both or neither.
+    fn apply(
+        &self,
+        mut storage: OwnedRwLockWriteGuard<Storage>,
+        log: &[u8],
+        mut catalog: Catalog,
+        end: u64,
+        wal: &LogManager,
+    ) -> Result<()> {
+        // Promotion takes the same lock, so nothing is applied once the
+        // server takes writes.
+        if self.is_promoted() {
+            return Ok(());
+        }
+        let mut replayed = self.replayed.lock().unwrap();
+        let (mut records, mut pages, mut last_lsn) = (0, 0, 0);
+        for body in frames(log)? {
+            let record = deserialize_record(body)?;
+            let (lsn, tx) = (record.header.lsn, record.header.tx_id);
+            last_lsn = last_lsn.max(lsn);
+            records += 1;
+            let update = match record.header.typ {
+                LogRecordType::Begin => {
+                    storage.txns.begin_replayed(tx);
+                    replayed.active.insert(tx);
+                    None
+                }
+                LogRecordType::Commit => {
+                    storage.txns.commit(tx);
+                    replayed.active.remove(&tx);
+                    None
+                }
+                // The primary logs the abort after undoing the transaction,
+                // so its compensations have already been replayed.
+                LogRecordType::Abort => {
+                    storage.txns.abort(tx);
+                    storage.txns.forget(tx);
+                    replayed.active.remove(&tx);
+                    None
+                }
+                // Transactions that began before the oldest log the primary
+                // still has are only known from its checkpoints.
+                LogRecordType::Checkpoint => {
+                    for active in CheckpointPayload::decode(&record.payload)?.active_txns {
+                        storage.txns.begin_replayed(active.tx_id);
+                        replayed.active.insert(active.tx_id);
+                    }
+                    None
+                }
+                LogRecordType::Update => Some(UpdatePayload::decode(&record.payload)?),
+                LogRecordType::Compensation => {
+                    Some(CompensationPayload::decode(&record.payload)?.update)
+                }
+                // The catalog comes along with the log instead.
+                LogRecordType::CreateTable
+                | LogRecordType::CreateIndex
+                | LogRecordType::DropTable
+                | LogRecordType::Grant
+                | LogRecordType::CreateSequence
+                | LogRecordType::DropSequence
+                | LogRecordType::ReserveSequence
+                | LogRecordType::CreateView
+                | LogRecordType::DropView
+                | LogRecordType::AddForeignKey
+                | LogRecordType::AddCheck
+                | LogRecordType::RenameTable
+                | LogRecordType::RenameIndex
+                | LogRecordType::CreateSchema
+                | LogRecordType::DropSchema
+                | LogRecordType::AddRows
+                | LogRecordType::RemoveRows => None,
+            };
+            if let Some(update) = update
+                && storage.redo_update(&update, lsn)?
+            {
+                pages += 1;
+            }
+        }
+        wal.advance_lsn_past(last_lsn);
+        replayed.indexes = std::mem::take(&mut catalog.indexes);
+        storage.catalog = catalog;
+        replayed.applied = Some(end);
+        debug!(
+            records,
+            pages, "Replayed the primary's WAL up to offset {}", end
+        );
+        Ok(())
+    }
+
+    // Stops replay for good. Transactions the primary left open can no
+    // longer end, so they count as rolled back, and the indexes replay could
+    // not keep are built from the rows. Returns false if the standby was
+    // already promoted.
+    pub fn promote(&self, storage: &mut Storage) -> Result<bool> {
+        if self.promoted.swap(true, Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let mut replayed = self.replayed.lock().unwrap();
+        for tx in replayed.active.drain() {
+            storage.txns.abort(tx);
+        }
+        storage.set_transaction(None);
+        for index in std::mem::take(&mut replayed.indexes)
+            .into_values()
+            .flatten()
+        {
+            match index.kind {
+                IndexKind::BTree => {
+                    let columns: Vec<String> = index.columns().cloned().collect();
+                    storage.create_index_on(
+                        &index.table,
+                        &columns,
+                        &index.name,
+                        Some(index.order),
+                    )?
+                }
+                IndexKind::Hash => {
+                    storage.create_hash_index(&index.table, &index.column, &index.name)?
+                }
+            };
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,255 @@
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+// Whole responses to SELECTs, kept until a table they read changes or the
+// memory budget needs their room. Every table has a version, bumped once a
+// change to it is committed; an entry remembers the versions its query saw
+// and is only served while they are current. A standby, which cannot tell
+// which tables the primary's log touched, clears everything instead.
+pub struct ResultCache {
+    budget: AtomicUsize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // Entries by when they were last used, least recent first.
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    used: usize,
+    versions: HashMap<String, u64>,
+    // Bumped by `clear`, so results computed before it are not stored after.
+    generation: u64,
+}
+
+struct Entry {
+    body: Bytes,
+    seen: Versions,
+    last_used: u64,
+}
+
+// The versions of the tables a query reads, taken before it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versions {
+    generation: u64,
+    tables: Vec<(String, u64)>,
+}
+
+impl ResultCache {
+    // A budget of 0 turns the cache off.
+    pub fn new(budget: usize) -> Self {
+        ResultCache {
+            budget: AtomicUsize::new(budget),
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.budget() > 0
+    }
+
+    // Largest response worth collecting: one that fits the budget alone.
+    pub fn budget(&self) -> usize {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    // Evicts the least recently used entries until what is left fits.
+    pub fn set_budget(&self, budget: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        self.budget.store(budget, Ordering::Relaxed);
+        while inner.used > budget {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let mut inner = self.inner.lock().unwrap();
+        let found = match inner.entries.get(key) {
+            Some(entry) if inner.is_current(&entry.seen) => Some(entry.body.clone()),
+            Some(_) => {
+                inner.remove(key);
+                None
+            }
+            None => None,
+        };
+        match &found {
+            Some(_) => {
+                inner.touch(key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        found
+    }
+
+    pub fn versions(&self, tables: &[&str]) -> Versions {
+        let inner = self.inner.lock().unwrap();
+        Versions {
+            generation: inner.generation,
+            tables: tables
+                .iter()
+                .map(|t| {
+                    let table = t.to_ascii_uppercase();
+                    let version = inner.versions.get(&table).copied().unwrap_or(0);
+                    (table, version)
+                })
+                .collect(),
+        }
+    }
+
+    // Stores a response unless a table it read has changed since `seen` was
+    // taken, evicting the least recently used entries to make room.
+    pub fn insert(&self, key: String, seen: Versions, body: Bytes) {
+        let mut inner = self.inner.lock().unwrap();
+        let budget = self.budget();
+        if body.len() > budget {
+            return;
+        }
+        if !inner.is_current(&seen) {
+            return;
+        }
+        inner.remove(&key);
+        while inner.used + body.len() > budget {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.used += body.len();
+        inner.lru.insert(tick, key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                body,
+                seen,
+                last_used: tick,
+            },
+        );
+    }
+
+    // Called once a change to `table` is committed.
+    pub fn invalidate(&self, table: &str) {
+        let table = table.to_ascii_uppercase();
+        let mut inner = self.inner.lock().unwrap();
+        *inner.versions.entry(table.clone()).or_default() += 1;
+        let stale: Vec<String> = inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.seen.tables.iter().any(|(t, _)| *t == table))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            inner.remove(&key);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.entries.clear();
+        inner.lru.clear();
+        inner.used = 0;
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    // Bytes of responses held.
+    pub fn used(&self) -> usize {
+        self.inner.lock().unwrap().used
+    }
+}
+
+impl Inner {
+    fn is_current(&self, seen: &Versions) -> bool {
+        seen.generation == self.generation
+            && seen
+                .tables
+                .iter()
+                .all(|(table, version)| self.versions.get(table).copied().unwrap_or(0) == *version)
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.lru.insert(tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.used -= entry.body.len();
+        }
+    }
+}
+
+// What a query is cached under: its text as the lexer reads it, with
+// comments dropped, runs of whitespace made one space, everything outside
+// string literals upper-cased and a trailing `;` left off, together with
+// what the response looks like. None for anything but a SELECT, which is all
+// the cache holds.
+pub fn key(sql: &str, variant: &str) -> Option<String> {
+    let mut key = String::with_capacity(variant.len() + 1 + sql.len());
+    key.push_str(variant);
+    key.push('\n');
+    let start = key.len();
+    let mut chars = sql.chars().peekable();
+    let mut in_string = false;
+    let mut space = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            key.push(c);
+            in_string = c != '\'';
+            continue;
+        }
+        if c == '-' && chars.peek() == Some(&'-') {
+            chars.find(|&c| c == '\n');
+            space = true;
+            continue;
+        }
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        if space && key.len() > start {
+            key.push(' ');
+        }
+        space = false;
+        in_string = c == '\'';
+        key.push(c.to_ascii_uppercase());
+    }
+    if key.ends_with(';') {
+        key.pop();
+        if key.ends_with(' ') {
+            key.pop();
+        }
+    }
+    key[start..].starts_with("SELECT ").then_some(key)
+}
@@ -0,0 +1,215 @@
+use crate::net::client::DbValue;
+use anyhow::{Result, anyhow, bail};
+use std::{fmt, ops::Deref, sync::Arc};
+
+// The type of a result column, as far as the one who ran the statement
+// knows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColumnType {
+    Int,
+    Text,
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColumnType::Int => "INT",
+            ColumnType::Text => "TEXT",
+        })
+    }
+}
+
+// The columns of a result, which every row of it shares. A result run
+// embedded has the types the plan worked out, and so does one the server
+// sent in the binary format; one read off the server as JSON only has
+// names, as that is all the JSON says.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    names: Vec<String>,
+    types: Vec<Option<ColumnType>>,
+}
+
+impl Schema {
+    pub fn new(names: Vec<String>) -> Self {
+        let types = vec![None; names.len()];
+        Schema { names, types }
+    }
+
+    pub fn typed(columns: Vec<(String, ColumnType)>) -> Self {
+        let (names, types) = columns.into_iter().map(|(n, t)| (n, Some(t))).unzip();
+        Schema { names, types }
+    }
+
+    // Columns whose types are known only for some.
+    pub fn partly_typed(columns: Vec<(String, Option<ColumnType>)>) -> Self {
+        let (names, types) = columns.into_iter().unzip();
+        Schema { names, types }
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn column_type(&self, i: usize) -> Option<ColumnType> {
+        self.types.get(i).copied().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // Names are matched the way SQL matches them, without case; one that
+    // only differs in case from another is found only when spelled as it is.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .or_else(|| self.names.iter().position(|n| n.eq_ignore_ascii_case(name)))
+    }
+}
+
+// What a row's value can be read out as with `Row::get`.
+pub trait FromDbValue: Sized {
+    // How the type is named in the error when a value is not one.
+    const TYPE: &'static str;
+
+    fn from_db_value(value: &DbValue) -> Option<Self>;
+}
+
+impl FromDbValue for i64 {
+    const TYPE: &'static str = "INT";
+
+    fn from_db_value(value: &DbValue) -> Option<Self> {
+        value.as_i64()
+    }
+}
+
+impl FromDbValue for String {
+    const TYPE: &'static str = "TEXT";
+
+    fn from_db_value(value: &DbValue) -> Option<Self> {
+        value.as_str().map(str::to_string)
+    }
+}
+
+// Any value but NULL.
+impl FromDbValue for DbValue {
+    const TYPE: &'static str = "a value";
+
+    fn from_db_value(value: &DbValue) -> Option<Self> {
+        Some(value.clone()).filter(|v| !v.is_null())
+    }
+}
+
+// One row of a result, with the columns it came with. It derefs to its
+// values, so it can also be indexed and iterated by position.
+#[derive(Clone)]
+pub struct Row {
+    schema: Arc<Schema>,
+    values: Vec<DbValue>,
+}
+
+impl Row {
+    pub fn new(schema: Arc<Schema>, values: Vec<DbValue>) -> Self {
+        Row { schema, values }
+    }
+
+    pub fn schema(&self) -> &Arc<Schema> {
+        &self.schema
+    }
+
+    pub fn values(&self) -> &[DbValue] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<DbValue> {
+        self.values
+    }
+
+    // The value of `column`. An unknown column, a NULL or a value of
+    // another type is an error naming the column.
+    pub fn get<T: FromDbValue>(&self, column: &str) -> Result<T> {
+        self.get_opt(column)?
+            .ok_or_else(|| anyhow!("Column '{}' is NULL, not {}", column, T::TYPE))
+    }
+
+    // Like `get`, but a NULL is None.
+    pub fn get_opt<T: FromDbValue>(&self, column: &str) -> Result<Option<T>> {
+        let i = self
+            .schema
+            .index_of(column)
+            .ok_or_else(|| anyhow!("No column '{}' in the result", column))?;
+        let value = &self.values[i];
+        if value.is_null() {
+            return Ok(None);
+        }
+        let found = match value {
+            DbValue::Int(_) => ColumnType::Int,
+            _ => ColumnType::Text,
+        };
+        match T::from_db_value(value) {
+            Some(v) => Ok(Some(v)),
+            None => bail!("Column '{}' is {}, not {}", column, found, T::TYPE),
+        }
+    }
+
+    // The values with the names of their columns, in order.
+    pub fn named(&self) -> impl Iterator<Item = (&str, &DbValue)> {
+        self.schema
+            .names()
+            .iter()
+            .map(String::as_str)
+            .zip(&self.values)
+    }
+}
+
+impl Deref for Row {
+    type Target = [DbValue];
+
+    fn deref(&self) -> &[DbValue] {
+        &self.values
+    }
+}
+
+impl IntoIterator for Row {
+    type Item = DbValue;
+    type IntoIter = std::vec::IntoIter<DbValue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Row {
+    type Item = &'a DbValue;
+    type IntoIter = std::slice::Iter<'a, DbValue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.iter()
+    }
+}
+
+// Rows compare by their values alone, so a row read off the server equals
+// the same row run embedded, whose columns also have types.
+impl PartialEq for Row {
+    fn eq(&self, other: &Row) -> bool {
+        self.values == other.values
+    }
+}
+
+impl PartialEq<Vec<DbValue>> for Row {
+    fn eq(&self, other: &Vec<DbValue>) -> bool {
+        self.values == *other
+    }
+}
+
+// Printed as its values, the way a plain row would be.
+impl fmt::Debug for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.values.fmt(f)
+    }
+}
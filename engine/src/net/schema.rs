@@ -0,0 +1,201 @@
+use crate::storage::storage::{
+    Catalog, CheckConstraint, Collation, DataType, ForeignKey, IndexInfo, IndexKind, TableInfo,
+};
+use serde::{Deserialize, Serialize};
+
+// What /tables, /tables/{name} and /indexes answer with, shared by the
+// server that builds it and the client that reads it.
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableSummary {
+    pub name: String,
+    // Rows stored, those of transactions still open included.
+    pub row_count: usize,
+    // Heap pages holding them and the bytes they take there.
+    #[serde(default)]
+    pub page_count: usize,
+    #[serde(default)]
+    pub bytes: u64,
+    // Rows inserted and deleted since the last ANALYZE, and when that ran,
+    // in milliseconds since the Unix epoch; absent before the first one.
+    #[serde(default)]
+    pub inserted: u64,
+    #[serde(default)]
+    pub deleted: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub analyzed_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableSchema {
+    pub name: String,
+    pub row_count: usize,
+    #[serde(default)]
+    pub page_count: usize,
+    #[serde(default)]
+    pub bytes: u64,
+    pub columns: Vec<ColumnSchema>,
+    pub indexes: Vec<IndexSchema>,
+    #[serde(default)]
+    pub foreign_keys: Vec<ForeignKeySchema>,
+    #[serde(default)]
+    pub checks: Vec<CheckSchema>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnSchema {
+    pub name: String,
+    // As CREATE TABLE spells it: INT or TEXT.
+    #[serde(rename = "type")]
+    pub data_type: String,
+    // NOCASE for a column declared so, and absent for BINARY.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collation: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexSchema {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    // `btree` or `hash`.
+    pub kind: String,
+    pub root_page: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForeignKeySchema {
+    pub name: String,
+    pub column: String,
+    pub parent: String,
+    pub parent_column: String,
+    // `RESTRICT` or `CASCADE`, as CREATE TABLE spells it.
+    pub on_delete: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckSchema {
+    pub name: String,
+    // The condition as it was written.
+    pub condition: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableList {
+    pub tables: Vec<TableSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexList {
+    pub indexes: Vec<IndexSchema>,
+}
+
+// Every table, by name.
+pub fn tables(catalog: &Catalog) -> TableList {
+    let mut tables: Vec<TableSummary> = catalog
+        .tables
+        .values()
+        .map(|t| TableSummary {
+            name: t.name.clone(),
+            row_count: t.stats.rows as usize,
+            page_count: t.stats.page_count(),
+            bytes: t.stats.bytes,
+            inserted: t.churn.inserted,
+            deleted: t.churn.deleted,
+            analyzed_at: t.analyzed_at,
+        })
+        .collect();
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+    TableList { tables }
+}
+
+// Looks `name` up the way SQL would, so `users` finds USERS: the lexer
+// upper-cases identifiers before they reach the catalog.
+pub fn table(catalog: &Catalog, name: &str) -> Option<TableSchema> {
+    let name = name.to_ascii_uppercase();
+    let table = catalog.tables.get(&name)?;
+    Some(TableSchema {
+        name: table.name.clone(),
+        row_count: table.stats.rows as usize,
+        page_count: table.stats.page_count(),
+        bytes: table.stats.bytes,
+        columns: columns(table),
+        indexes: table_indexes(catalog, &name),
+        foreign_keys: table.foreign_keys.iter().map(foreign_key).collect(),
+        checks: table.checks.iter().map(check).collect(),
+    })
+}
+
+// Every index, by table and then by name.
+pub fn indexes(catalog: &Catalog) -> IndexList {
+    let mut tables: Vec<&String> = catalog.indexes.keys().collect();
+    tables.sort();
+    IndexList {
+        indexes: tables
+            .into_iter()
+            .flat_map(|t| table_indexes(catalog, t))
+            .collect(),
+    }
+}
+
+fn columns(table: &TableInfo) -> Vec<ColumnSchema> {
+    table
+        .columns
+        .iter()
+        .map(|c| ColumnSchema {
+            name: c.name.clone(),
+            data_type: match c.data_type {
+                DataType::Int => "INT",
+                DataType::String => "TEXT",
+            }
+            .to_string(),
+            collation: match c.collation {
+                Collation::Binary => None,
+                collation => Some(collation.name().to_string()),
+            },
+        })
+        .collect()
+}
+
+fn table_indexes(catalog: &Catalog, table: &str) -> Vec<IndexSchema> {
+    let mut indexes: Vec<IndexSchema> = catalog
+        .indexes
+        .get(table)
+        .into_iter()
+        .flatten()
+        .map(index)
+        .collect();
+    indexes.sort_by(|a, b| a.name.cmp(&b.name));
+    indexes
+}
+
+fn foreign_key(key: &ForeignKey) -> ForeignKeySchema {
+    ForeignKeySchema {
+        name: key.name.clone(),
+        column: key.column.clone(),
+        parent: key.parent.clone(),
+        parent_column: key.parent_column.clone(),
+        on_delete: key.on_delete.to_string(),
+    }
+}
+
+fn check(check: &CheckConstraint) -> CheckSchema {
+    CheckSchema {
+        name: check.name.clone(),
+        condition: check.condition.clone(),
+    }
+}
+
+fn index(info: &IndexInfo) -> IndexSchema {
+    IndexSchema {
+        name: info.name.clone(),
+        table: info.table.clone(),
+        columns: info.columns().cloned().collect(),
+        kind: match info.kind {
+            IndexKind::BTree => "btree",
+            IndexKind::Hash => "hash",
+        }
+        .to_string(),
+        root_page: info.root_page,
+    }
+}
@@ -1,24 +1,62 @@
-
-
 use crate::{
+    net::{
+        admission::{Admission, Permit, Refused, WhenBusy},
+        audit::{self, AuditEntry, AuditLog},
+        auth::{BOOTSTRAP_ADMIN, LoginError, Logins, Secret, UserStore},
+        binary, compression,
+        copy::{self, BadCopy, CopyReport},
+        csv_io::{self, BadCsv, BodyReader, CsvOptions, ImportProgress, ImportReport},
+        metrics::{self, Metrics, Sources},
+        queries::{QueryRegistry, QueryState, RunningQuery},
+        replication::{
+            self, ReplicationPoint, STANDBY_TX_IDS, Standby, StandbyConfig, WalTruncated,
+        },
+        result_cache::{self, ResultCache, Versions},
+        row::Schema,
+        schema,
+        session::{Cursor, OpenTransaction, SessionManager, TxState},
+        settings::{ConfigFile, RESTART_ONLY, Setting, SettingValue, Settings},
+    },
     query::{
-        binder::{Binder, Catalog as BinderCatalog, Value},
-        executor::{Executor, FilterOp, PhysicalOp, ProjectionOp, SeqScanOp},
-        optimizer::Optimizer,
-        parser::{Parser, Statement},
-        physical_planner::PhysicalPlanner,
-        planner::Planner as LogicalPlanner,
+        binder::{Catalog as BinderCatalog, VOLATILE_FUNCTIONS, Value, resolve_names},
+        executor::{Executor, SeqScanOp, Tuple},
+        memory::{DEFAULT_WORK_MEM, OUT_OF_MEMORY, OutOfMemory},
+        parser::{CopyFormat, Diagnostics, Parser, Statement},
+        pipeline::{
+            calls_volatile, changes_data, create_executor_from_statement, create_read_executor,
+            is_ddl, is_read_only, record_elapsed, run_ddl, runs_read_only, subqueries, table_of,
+            uses_variables, written_table,
+        },
+        privileges::{self, PERMISSION_DENIED, PermissionDenied},
+        random::Random,
+        source::excerpt_for,
+    },
+    storage::{
+        backup,
+        buffer_pool::{PageCounts, PoolStats},
+        index_build::BACKFILL_CHUNK_ROWS,
+        storage::{
+            Cancelled, Catalog, DEFAULT_SCHEMA, FOREIGN_KEY_VIOLATION, ForeignKeyViolation,
+            Privilege, ReadView, SCHEMA_CHANGED, ScanErrorPolicy, SchemaChanged, Storage,
+            TableInfo,
+        },
     },
-    storage::storage::{ColumnInfo, DataType, Storage},
     tx::{
-        lock_manager::{LockManager, LockMode, Resource},
-        log_manager::LogManager,
-        recovery_manager::RecoveryManager,
+        lock_manager::{IsolationLevel, LockError, LockManager, LockMode, Resource},
+        log_manager::{FlushPolicy, LogManager},
+        mvcc::TxStatusTable,
+        recovery_manager::{self, RecoveryManager},
     },
 };
 use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
 use hyper::{
-    Method, Request, Response, StatusCode, body::Bytes, server::conn::http1, service::service_fn,
+    Method, Request, Response, StatusCode,
+    body::{Body, Bytes, Frame, SizeHint},
+    header::HeaderValue,
+    server::conn::http1,
+    service::service_fn,
+    upgrade::Upgraded,
 };
 use hyper_util::rt::TokioIo;
 use serde::{Deserialize, Serialize};
@@ -26,60 +64,367 @@ use std::{
     collections::HashMap,
     convert::Infallible,
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    pin::Pin,
     sync::{
-        Arc,
-        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
+    task::Poll,
+    time::{Duration, Instant},
 };
-use tokio::{net::TcpListener, sync::RwLock};
-use tracing::{debug, error, info};
+use tokio::{
+    net::TcpListener,
+    sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock, mpsc, oneshot, watch},
+    task::JoinSet,
+};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{Message, handshake::derive_accept_key, protocol::Role},
+};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
+use tracing_subscriber::{EnvFilter, Registry, prelude::*, reload};
 
+// The filter `run_server` logs through, which `log_level` swaps. Unset when
+// someone else's subscriber was installed first; theirs is left alone.
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
 
 #[derive(Deserialize)]
 struct LoginReq {
     user: String,
     pass: String,
+    // What the session's statements are tagged with, in the logs,
+    // /debug/queries and the audit log.
+    #[serde(default, alias = "application_name")]
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BackupReq {
+    // On the server's machine.
+    dir: PathBuf,
 }
 
 #[derive(Debug, Deserialize)]
 struct QueryBody {
     sql: String,
+    // Overrides the server's query timeout for this statement.
+    timeout_ms: Option<u64>,
+    // False keeps the statement away from the result cache, both ways.
+    cache: Option<bool>,
+    // Tags this statement instead of what the session was tagged with.
+    #[serde(default, alias = "application_name")]
+    tag: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryResponse<T> {
+    rows: Vec<Vec<T>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchBody {
+    statements: Vec<String>,
+    // Overrides the server's query timeout for the whole batch.
+    timeout_ms: Option<u64>,
+    #[serde(default, alias = "application_name")]
+    tag: Option<String>,
+}
+
+// What /batch answers with. `results` has one entry per statement that ran;
+// on failure the batch is `rolled_back` and `failed_index` points at the
+// statement that failed, or is absent if committing failed.
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    status: &'static str,
+    results: Vec<BatchResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    columns: Vec<String>,
+    rows: Vec<serde_json::Value>,
+    row_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    affected: Option<usize>,
+}
+
+// What a client sends over /ws. Every query has an id of the client's
+// choosing, which the messages about it carry.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SocketRequest {
+    Auth {
+        user: String,
+        pass: String,
+        #[serde(default, alias = "application_name")]
+        tag: Option<String>,
+    },
+    Query {
+        id: u64,
+        sql: String,
+        timeout_ms: Option<u64>,
+        #[serde(default)]
+        format: ResultFormat,
+        #[serde(default, alias = "application_name")]
+        tag: Option<String>,
+    },
+    Cancel {
+        id: u64,
+    },
+}
+
+// How /query renders values: typed JSON by default, or every value as a
+// string with `?format=text`, the way responses used to look.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ResultFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+impl ResultFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ResultFormat::Json => "json",
+            ResultFormat::Text => "text",
+        }
+    }
+
+    fn from_query(query: Option<&str>) -> Result<Self, String> {
+        let format = query
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|kv| kv.strip_prefix("format="));
+        match format {
+            None | Some("json") => Ok(ResultFormat::Json),
+            Some("text") => Ok(ResultFormat::Text),
+            Some(other) => Err(format!("Unknown result format {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct LockDump {
+    resource: String,
+    holders: Vec<LockHolder>,
+    waiters: Vec<LockWaiter>,
+}
+
+#[derive(Debug, Serialize)]
+struct LockHolder {
+    tx: u64,
+    mode: String,
 }
 
 #[derive(Debug, Serialize)]
-struct QueryResponse {
-    rows: Vec<Vec<String>>,
+struct LockWaiter {
+    tx: u64,
+    mode: String,
+    waited_ms: u64,
 }
 
-static TX_COUNTER: AtomicU64 = AtomicU64::new(1);
+const DEADLOCK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LOCK_RETRIES: u32 = 3;
+
+const LOCK_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+const IDLE_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(300);
+
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+const ROWS_PER_CHUNK: usize = 256;
+
+// Chunks a slow client can fall behind by before the executor waits for it.
+const STREAM_CHANNEL_CHUNKS: usize = 4;
+
+// Finished queries /debug/queries still lists.
+const FINISHED_QUERIES_KEPT: usize = 100;
+
+// How far into a COPY body its statement line may run.
+const MAX_COPY_STATEMENT_BYTES: usize = 64 * 1024;
+
+// Says whether a cacheable SELECT was answered from the result cache.
+pub const RESULT_CACHE_HEADER: &str = "x-result-cache";
+
+// How many times a statement was run again after losing a lock conflict,
+// when it was.
+pub const LOCK_RETRIES_HEADER: &str = "x-lock-retries";
+
+// The session's search path, its schemas separated by commas, sent on a
+// login and on every SET of search_path so a client can follow it.
+pub const SEARCH_PATH_HEADER: &str = "x-search-path";
+
+// Where the session stands after a statement: idle, open inside BEGIN, or
+// aborted until ROLLBACK. The transaction's id goes with the last two.
+pub const TX_STATUS_HEADER: &str = "x-tx-status";
+pub const TX_ID_HEADER: &str = "x-tx-id";
+
+// What a statement cost: how long it ran, the rows it sent or wrote, the
+// pages it read from disk and found in the pool, and how long it waited for
+// its table lock. Sent once the statement has finished, so a result
+// streamed in more than one chunk goes without them.
+pub const QUERY_MILLIS_HEADER: &str = "x-query-millis";
+pub const ROWS_HEADER: &str = "x-rows";
+pub const PAGES_READ_HEADER: &str = "x-pages-read";
+pub const PAGES_CACHED_HEADER: &str = "x-pages-cached";
+pub const LOCK_WAIT_HEADER: &str = "x-lock-wait-millis";
+
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const DEFAULT_MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+pub const DEFAULT_MAX_RUNNING_QUERIES: usize = 64;
+
+pub const DEFAULT_AUTO_ANALYZE: u32 = 10;
+
+pub const DEFAULT_AUTO_ANALYZE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Below this many rows changed a table is not analyzed again whatever its
+// size, so a small one is not after every few rows.
+const AUTO_ANALYZE_MIN_ROWS: u64 = 50;
+
+// Settings `run_server` takes beyond where to listen and what to serve.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    pub flush_policy: FlushPolicy,
+    // Password for the `admin` account created on first startup. Without
+    // one a random password is generated and printed.
+    pub admin_password: Option<Secret>,
+    // How long a login stays valid, DEFAULT_SESSION_TTL if unset.
+    pub session_ttl: Option<Duration>,
+    // How long a statement may run, DEFAULT_QUERY_TIMEOUT if unset.
+    pub query_timeout: Option<Duration>,
+    // Largest request body accepted, DEFAULT_MAX_BODY_BYTES if unset.
+    pub max_body_bytes: Option<usize>,
+    // Whether /metrics needs a login like everything but /health does.
+    pub metrics_require_login: bool,
+    // What `run_server` logs, in `RUST_LOG` syntax; DEFAULT_LOG_FILTER if
+    // unset.
+    pub log_filter: Option<String>,
+    // How many statements may run at once, DEFAULT_MAX_RUNNING_QUERIES if
+    // unset, and what happens to the next one.
+    pub max_running_queries: Option<usize>,
+    pub when_busy: WhenBusy,
+    // Requests per second allowed each session; unlimited if unset.
+    pub rate_limit: Option<u32>,
+    // Primary to follow as a read-only standby; unset for a server that
+    // takes writes.
+    pub standby_of: Option<StandbyConfig>,
+    // Memory for cached SELECT responses; no cache if unset or 0.
+    pub result_cache_bytes: Option<usize>,
+    // Memory each statement's sorts, aggregates and joins may hold unless
+    // its session SETs work_mem, DEFAULT_WORK_MEM if unset.
+    pub work_mem: Option<usize>,
+    // WAL kept past what recovery needs, for AS OF queries to read back
+    // through; 0 keeps none.
+    pub history_window_bytes: u64,
+    // Refuse everything but reads, and log nothing once recovery is done.
+    // Cannot be combined with `standby_of`.
+    pub read_only: bool,
+    // Settings applied over the ones above, read again on SIGHUP or
+    // `POST /reload`.
+    pub config_file: Option<ConfigFile>,
+    // File DDL, DML and account statements are appended to; no audit log
+    // if unset.
+    pub audit_log: Option<PathBuf>,
+    // The auto_analyze setting to start with, DEFAULT_AUTO_ANALYZE if
+    // unset; 0 leaves statistics to ANALYZE alone.
+    pub auto_analyze: Option<u32>,
+    // How often tables are looked over for one to analyze,
+    // DEFAULT_AUTO_ANALYZE_INTERVAL if unset.
+    pub auto_analyze_interval: Option<Duration>,
+}
 
 #[derive(Clone)]
 struct AppState {
     storage: Arc<RwLock<Storage>>,
     logmgr: Arc<LogManager>,
     locks: Arc<LockManager>,
-    wal_path: PathBuf,
+    txns: Arc<TxStatusTable>,
+    sessions: Arc<SessionManager>,
+    users: Arc<UserStore>,
+    logins: Arc<Logins>,
+    metrics: Arc<Metrics>,
+    pool_stats: Arc<PoolStats>,
+    metrics_require_login: bool,
+    // What SET GLOBAL changes. A session's own SETs go over it.
+    settings: Arc<Mutex<Settings>>,
+    max_body_bytes: usize,
+    admission: Arc<Admission>,
+    result_cache: Arc<ResultCache>,
+    queries: Arc<QueryRegistry>,
+    audit: Arc<AuditLog>,
+    // Set on a standby, which refuses writes until it is promoted.
+    standby: Option<Arc<Standby>>,
+    // Set by `ServerConfig::read_only`; for good, unlike a standby.
+    read_only: bool,
+    // As the server started with it; a reload tells what changed since.
+    config_file: Option<ConfigFile>,
+    // Turns true when the server starts shutting down.
+    stop: watch::Receiver<bool>,
+}
+
+impl AppState {
+    // What a statement of `session` runs with.
+    fn settings(&self, session: &str) -> Settings {
+        let overrides = self.sessions.settings(session);
+        self.settings.lock().unwrap().with(&overrides)
+    }
+
+    fn is_standby(&self) -> bool {
+        self.standby.as_ref().is_some_and(|s| !s.is_promoted())
+    }
+
+    // What a write is refused with here, if it is.
+    fn write_refusal(&self) -> Option<&'static str> {
+        if self.read_only {
+            Some(READ_ONLY_REFUSAL)
+        } else if self.is_standby() {
+            Some(STANDBY_REFUSAL)
+        } else {
+            None
+        }
+    }
 }
 
 async fn handle_request(
     req: Request<hyper::body::Incoming>,
     state: Arc<AppState>,
-) -> Result<Response<String>, Infallible> {
+) -> Result<Response<ResponseBody>, Infallible> {
     debug!("Received {} {}", req.method(), req.uri().path());
+    if req.uri().path() != "/health"
+        && let Some(token) = session_token(&req)
+        && let Err(e) = state.admission.check_rate(token)
+    {
+        debug!("Session over its request rate");
+        return Ok(refused(e));
+    }
+    let login = session(&state, &req);
+    // Only results are worth compressing; everything else is small.
+    let path = req.uri().path();
+    let gzip = compression::accepts_gzip(req.headers())
+        && (path == "/query" || (path.starts_with("/tables/") && path.ends_with("/export")));
 
     let response = match (req.method(), req.uri().path()) {
-        
         (&Method::POST, "/login") => {
-            let body = match collect_body(req.into_body()).await {
+            let body = match collect_body(req, state.max_body_bytes).await {
                 Ok(b) => b,
-                Err(e) => {
-                    error!("Failed to read login body: {:#}", e);
-                    return Ok(Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body("Failed to read body".into())
-                        .unwrap());
-                }
+                Err(response) => return Ok(response),
             };
             let creds: LoginReq = match serde_json::from_slice(&body) {
                 Ok(c) => c,
@@ -91,12 +436,34 @@ async fn handle_request(
                         .unwrap());
                 }
             };
-            if creds.user == "admin" && creds.pass == "password" {
-                Response::builder()
+            let users = state.users.clone();
+            let tag = creds.tag;
+            let user = tokio::task::spawn_blocking(move || {
+                users.authenticate(&creds.user, &Secret(creds.pass))
+            })
+            .await
+            .ok()
+            .flatten();
+            if let Some(user) = user {
+                let token = state.logins.issue(&user.name);
+                state
+                    .sessions
+                    .open(&token, tokio::time::Instant::now() + state.logins.ttl());
+                state.sessions.set_tag(&token, tag);
+                info!("User {} logged in", user.name);
+                let response = Response::builder()
                     .status(StatusCode::OK)
-                    .header("Set-Cookie", "session_token=secret-token; HttpOnly; Path=/")
+                    .header(
+                        "Set-Cookie",
+                        format!(
+                            "session_token={}; HttpOnly; SameSite=Strict; Path=/; Max-Age={}",
+                            token,
+                            state.logins.ttl().as_millis().div_ceil(1000)
+                        ),
+                    )
                     .body("Login successful".into())
-                    .unwrap()
+                    .unwrap();
+                with_search_path(response, &state, &token)
             } else {
                 Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
@@ -105,45 +472,152 @@ async fn handle_request(
             }
         }
 
-        
-        (&Method::POST, "/query") => {
-            
-            let authed = req
-                .headers()
-                .get("cookie")
-                .and_then(|h| h.to_str().ok())
-                .map_or(false, |c| c.contains("session_token=secret-token"));
-            if !authed {
-                error!("Unauthorized query");
-                return Ok(Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .body("Not authenticated".into())
-                    .unwrap());
+        (&Method::POST, "/logout") => {
+            let session = match login {
+                Ok(session) => session.token,
+                Err(e) => return Ok(unauthorized(e)),
+            };
+            // A transaction left open is rolled back rather than left for
+            // the idle sweeper.
+            if state.sessions.in_transaction(&session) {
+                end_transaction(&state, &session, false).await;
             }
+            end_session(&state, &session).await;
+            state.logins.revoke(&session);
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(
+                    "Set-Cookie",
+                    "session_token=; HttpOnly; SameSite=Strict; Path=/; Max-Age=0",
+                )
+                .body("Logged out".into())
+                .unwrap()
+        }
 
-            
-            let rm = RecoveryManager::new(state.wal_path.clone(), state.storage.clone());
-            if let Err(e) = rm.recover().await {
-                error!("Recovery failed: {:#}", e);
-                return Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(format!("Recovery error: {:#}", e))
-                    .unwrap());
+        // Liveness only: answers without a login and without waiting on a
+        // running statement.
+        (&Method::GET, "/health") => health(&state),
+
+        (&Method::GET, "/metrics") => {
+            if state.metrics_require_login
+                && let Err(e) = login
+            {
+                return Ok(unauthorized(e));
             }
-            info!("Recovery complete");
+            // A scrape does not wait behind a writer; while one runs, the
+            // per-table gauges are left out.
+            let mut dead_rows: Vec<(String, u64)> = match state.storage.try_read() {
+                Ok(storage) => storage
+                    .catalog
+                    .tables
+                    .values()
+                    .map(|t| (t.name.clone(), t.stats.dead_rows))
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            dead_rows.sort();
+            let text = state.metrics.render(&Sources {
+                pool: &state.pool_stats,
+                wal: &state.logmgr,
+                locks: &state.locks,
+                txns: &state.txns,
+                admission: &state.admission,
+                result_cache: &state.result_cache,
+                dead_rows: &dead_rows,
+            });
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(text.into())
+                .unwrap()
+        }
 
-            
-            let body = match collect_body(req.into_body()).await {
-                Ok(b) => b,
+        (&Method::GET, "/debug/locks") => {
+            if let Err(e) = login {
+                error!("Unauthorized lock dump");
+                return Ok(unauthorized(e));
+            }
+            let locks: Vec<LockDump> = state
+                .locks
+                .snapshot()
+                .into_iter()
+                .map(|entry| LockDump {
+                    resource: format!("{:?}", entry.resource),
+                    holders: entry
+                        .holders
+                        .into_iter()
+                        .map(|(tx, mode)| LockHolder {
+                            tx,
+                            mode: format!("{:?}", mode),
+                        })
+                        .collect(),
+                    waiters: entry
+                        .waiters
+                        .into_iter()
+                        .map(|w| LockWaiter {
+                            tx: w.tx,
+                            mode: format!("{:?}", w.mode),
+                            waited_ms: w.waited.as_millis() as u64,
+                        })
+                        .collect(),
+                })
+                .collect();
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&locks).unwrap().into())
+                .unwrap()
+        }
+
+        (&Method::GET, "/debug/queries") => {
+            let user = match login {
+                Ok(session) => session.user,
+                Err(e) => return Ok(unauthorized(e)),
+            };
+            // Only admins see everyone's queries.
+            let only = (!is_admin(&state, &user)).then_some(user.as_str());
+            let list = state.queries.list(only);
+            json_response(StatusCode::OK, serde_json::to_string(&list).unwrap())
+        }
+
+        (&Method::POST, path)
+            if path.starts_with("/debug/queries/") && path.ends_with("/cancel") =>
+        {
+            let user = match login {
+                Ok(session) => session.user,
+                Err(e) => return Ok(unauthorized(e)),
+            };
+            let id = &path["/debug/queries/".len()..path.len() - "/cancel".len()];
+            cancel_query(&state, &user, id)
+        }
+
+        (&Method::POST, "/query") => {
+            let (session, user) = match login {
+                Ok(login) => (login.token, login.user),
+                Err(e) => {
+                    error!("Unauthorized query: {}", e);
+                    return Ok(unauthorized(e));
+                }
+            };
+            let format = match ResultFormat::from_query(req.uri().query()) {
+                Ok(format) => format,
                 Err(e) => {
-                    error!("Failed to read query body: {:#}", e);
                     return Ok(Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(format!("Body read error: {:#}", e))
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(e.into())
                         .unwrap());
                 }
             };
-            debug!("Query body bytes: {:?}", body);
+
+            let framing = match binary::accepted(req.headers()) {
+                true => Framing::Binary,
+                false => Framing::Http,
+            };
+
+            let body = match collect_body(req, state.max_body_bytes).await {
+                Ok(b) => b,
+                Err(response) => return Ok(response),
+            };
 
             let qb: QueryBody = match serde_json::from_slice(&body) {
                 Ok(q) => q,
@@ -151,230 +625,209 @@ async fn handle_request(
                     error!("Invalid query JSON: {:#}", e);
                     return Ok(Response::builder()
                         .status(StatusCode::BAD_REQUEST)
-                        .body(format!("Invalid JSON: {:#}", e))
+                        .body(format!("Invalid JSON: {:#}", e).into())
                         .unwrap());
                 }
             };
-            debug!("SQL: {:?}", qb.sql);
 
-            
-            let mut parser = match Parser::new(&qb.sql) {
-                Ok(p) => p,
+            let cancel = Arc::new(AtomicBool::new(false));
+            run_query(&state, &user, session, qb, format, framing, cancel).await
+        }
+
+        (&Method::GET, "/ws") => upgrade_socket(&state, req, login.ok()),
+
+        (&Method::GET, "/replication") => {
+            if let Err(e) = login {
+                return Ok(unauthorized(e));
+            }
+            replication_point(&state, req.uri().query()).await
+        }
+
+        (&Method::GET, "/wal") => {
+            if let Err(e) = login {
+                return Ok(unauthorized(e));
+            }
+            ship_wal(&state, req.uri().query()).await
+        }
+
+        (&Method::POST, "/reload") => {
+            let user = match login {
+                Ok(session) => session.user,
+                Err(e) => return Ok(unauthorized(e)),
+            };
+            if !is_admin(&state, &user) {
+                error!("User {} tried to reload the config file", user);
+                return Ok(json_error(
+                    StatusCode::FORBIDDEN,
+                    "Permission denied: only an admin can reload the config file".to_string(),
+                ));
+            }
+            match reload(&state) {
+                Ok(reloaded) => {
+                    json_response(StatusCode::OK, serde_json::to_string(&reloaded).unwrap())
+                }
+                Err(e) => json_error(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+            }
+        }
+
+        (&Method::POST, "/promote") => {
+            let user = match login {
+                Ok(session) => session.user,
+                Err(e) => return Ok(unauthorized(e)),
+            };
+            promote(&state, &user).await
+        }
+
+        (&Method::POST, "/backup") => {
+            let user = match login {
+                Ok(session) => session.user,
+                Err(e) => return Ok(unauthorized(e)),
+            };
+            let body = match collect_body(req, state.max_body_bytes).await {
+                Ok(b) => b,
+                Err(response) => return Ok(response),
+            };
+            match serde_json::from_slice::<BackupReq>(&body) {
+                Ok(request) => take_backup(&state, &user, &request.dir).await,
+                Err(e) => json_error(StatusCode::BAD_REQUEST, format!("Invalid JSON: {:#}", e)),
+            }
+        }
+
+        (&Method::GET, "/tables") | (&Method::GET, "/indexes") => {
+            if let Err(e) = login {
+                return Ok(unauthorized(e));
+            }
+            let storage = state.storage.read().await;
+            let body = if req.uri().path() == "/tables" {
+                serde_json::to_string(&schema::tables(&storage.catalog))
+            } else {
+                serde_json::to_string(&schema::indexes(&storage.catalog))
+            };
+            json_response(StatusCode::OK, body.unwrap())
+        }
+
+        (&Method::POST, path) if path.starts_with("/tables/") && path.ends_with("/import") => {
+            let name = &path["/tables/".len()..path.len() - "/import".len()];
+            let name = name.to_ascii_uppercase();
+            import_table(&state, req, login, name).await
+        }
+
+        (&Method::POST, "/copy") => copy_table(&state, req, login).await,
+
+        (&Method::GET, path) if path.starts_with("/tables/") && path.ends_with("/export") => {
+            let name = &path["/tables/".len()..path.len() - "/export".len()];
+            export_table(&state, &req, login, name.to_ascii_uppercase()).await
+        }
+
+        (&Method::GET, path) if path.starts_with("/tables/") => {
+            if let Err(e) = login {
+                return Ok(unauthorized(e));
+            }
+            let name = &path["/tables/".len()..];
+            let storage = state.storage.read().await;
+            match schema::table(&storage.catalog, name) {
+                Some(table) => {
+                    json_response(StatusCode::OK, serde_json::to_string(&table).unwrap())
+                }
+                None => json_error(
+                    StatusCode::NOT_FOUND,
+                    format!("Table '{}' does not exist", name.to_ascii_uppercase()),
+                ),
+            }
+        }
+
+        (&Method::POST, "/batch") => {
+            let (session, user) = match login {
+                Ok(login) => (login.token, login.user),
+                Err(e) => {
+                    error!("Unauthorized batch: {}", e);
+                    return Ok(unauthorized(e));
+                }
+            };
+            let format = match ResultFormat::from_query(req.uri().query()) {
+                Ok(format) => format,
                 Err(e) => {
-                    error!("Parser init failed: {:#}", e);
                     return Ok(Response::builder()
                         .status(StatusCode::BAD_REQUEST)
-                        .body(format!("Parse error: {:#}", e))
+                        .body(e.into())
                         .unwrap());
                 }
             };
-            let stmt = match parser.parse_statement() {
-                Ok(s) => s,
+            let body = match collect_body(req, state.max_body_bytes).await {
+                Ok(b) => b,
+                Err(response) => return Ok(response),
+            };
+            let batch: BatchBody = match serde_json::from_slice(&body) {
+                Ok(b) => b,
                 Err(e) => {
-                    error!("Parse statement failed: {:#}", e);
+                    error!("Invalid batch JSON: {:#}", e);
                     return Ok(Response::builder()
                         .status(StatusCode::BAD_REQUEST)
-                        .body(format!("Parse error: {:#}", e))
+                        .body(format!("Invalid JSON: {:#}", e).into())
                         .unwrap());
                 }
             };
-            info!("AST: {:?}", stmt);
-
-            
-            let tx_id = TX_COUNTER.fetch_add(1, Ordering::SeqCst);
-            state
-                .logmgr
-                .log_begin(tx_id)
-                .context("WAL begin failed")
-                .map_err(|e| {
-                    error!("{}", e);
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(format!("WAL begin error: {:#}", e))
-                        .unwrap()
-                })
-                .unwrap();
-            info!("Transaction {} begun", tx_id);
-
-            
-            let (res, mode) = match &stmt {
-                Statement::Select { table, .. } => {
-                    (Resource::Table(table.clone()), LockMode::Shared)
-                }
-                Statement::Insert { table, .. }
-                | Statement::CreateTable { name: table, .. }
-                | Statement::CreateIndex { table, .. } => {
-                    (Resource::Table(table.clone()), LockMode::Exclusive)
+            let mut settings = state.settings(&session);
+            settings.query_timeout = match batch.timeout_ms {
+                Some(0) => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body("timeout_ms must be at least 1".into())
+                        .unwrap());
                 }
+                Some(ms) => Duration::from_millis(ms),
+                None => settings.query_timeout,
             };
-            state
-                .locks
-                .lock(tx_id, res.clone(), mode)
-                .await
-                .map_err(|e| {
-                    error!("Lock failed: {}", e);
-                    let _ = state.logmgr.log_abort(tx_id);
-                    state.locks.unlock_all(tx_id);
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(format!("Lock error: {:#}", e))
-                        .unwrap()
-                })
-                .unwrap();
-            info!("Lock acquired: {:?} {:?}", res, mode);
-
-            
-            let mut storage = state.storage.write().await;
-            let mut bind_catalog = BinderCatalog::new();
-
-            
-            if let Statement::CreateTable { name, columns } = &stmt {
-                let infos = columns
-                    .iter()
-                    .map(|(n, t)| ColumnInfo {
-                        name: n.clone(),
-                        data_type: if t.eq_ignore_ascii_case("INT") {
-                            DataType::Int
-                        } else {
-                            DataType::String
-                        },
-                    })
-                    .collect();
-                storage
-                    .create_table(name.clone(), infos)
-                    .context("CREATE TABLE failed")
-                    .map_err(|e| {
-                        error!("{}", e);
-                        let _ = state.logmgr.log_abort(tx_id);
-                        state.locks.unlock_all(tx_id);
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(format!("CREATE TABLE failed: {:#}", e))
-                            .unwrap()
-                    })
-                    .unwrap();
-                state
-                    .logmgr
-                    .log_commit(tx_id)
-                    .context("WAL commit failed")
-                    .map_err(|e| {
-                        error!("{}", e);
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(format!("WAL commit error: {:#}", e))
-                            .unwrap()
-                    })
-                    .unwrap();
-                state.locks.unlock_all(tx_id);
+            if let Some(notice) = state.sessions.take_abort_notice(&session) {
                 return Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .body(String::new())
+                    .status(StatusCode::CONFLICT)
+                    .body(notice.into())
                     .unwrap());
             }
-            if let Statement::CreateIndex {
-                index_name,
-                table,
-                column,
-            } = &stmt
-            {
-                storage
-                    .create_index(table, column, index_name, 4)
-                    .context("CREATE INDEX failed")
-                    .map_err(|e| {
-                        error!("{}", e);
-                        let _ = state.logmgr.log_abort(tx_id);
-                        state.locks.unlock_all(tx_id);
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(format!("CREATE INDEX failed: {:#}", e))
-                            .unwrap()
-                    })
-                    .unwrap();
-                state
-                    .logmgr
-                    .log_commit(tx_id)
-                    .context("WAL commit failed")
-                    .map_err(|e| {
-                        error!("{}", e);
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(format!("WAL commit error: {:#}", e))
-                            .unwrap()
-                    })
-                    .unwrap();
-                state.locks.unlock_all(tx_id);
+            // A rolled back transaction is still open until ROLLBACK, and
+            // says so rather than that the batch is inside one.
+            if let TxState::Aborted(tx_id) = state.sessions.tx_state(&session) {
+                return Ok(aborted(tx_id));
+            }
+            if state.sessions.in_transaction(&session) {
                 return Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .body(String::new())
+                    .status(StatusCode::BAD_REQUEST)
+                    .body("A batch cannot run inside a transaction block".into())
                     .unwrap());
             }
-
-            
-            let mut exec = create_executor_from_statement(stmt, &mut storage, &mut bind_catalog)
-                .map_err(|e| {
-                    error!("Build failed: {:#}", e);
-                    let _ = state.logmgr.log_abort(tx_id);
-                    state.locks.unlock_all(tx_id);
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(format!("Build error: {:#}", e))
-                        .unwrap()
-                })
-                .unwrap();
-            debug!("Executor built");
-
-            
-            let tuples = exec
-                .execute()
-                .map_err(|e| {
-                    error!("Exec failed: {:#}", e);
-                    let _ = state.logmgr.log_abort(tx_id);
-                    state.locks.unlock_all(tx_id);
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(format!("Exec error: {:#}", e))
-                        .unwrap()
-                })
-                .unwrap();
-            info!("Executed, {} rows", tuples.len());
-
-            
-            state
-                .logmgr
-                .log_commit(tx_id)
-                .context("WAL commit failed")
-                .map_err(|e| {
-                    error!("{}", e);
-                    let _ = state.logmgr.log_abort(tx_id);
-                    state.locks.unlock_all(tx_id);
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(format!("WAL commit error: {:#}", e))
-                        .unwrap()
-                })
-                .unwrap();
-            state.locks.unlock_all(tx_id);
-
-            
-            let rows = tuples
-                .into_iter()
-                .map(|tuple| {
-                    tuple
-                        .into_iter()
-                        .map(|v| match v {
-                            Value::Int(i) => i.to_string(),
-                            Value::String(s) => s,
-                        })
-                        .collect()
-                })
-                .collect();
-            let body = serde_json::to_string(&QueryResponse { rows }).unwrap();
-
-            Response::builder()
-                .status(StatusCode::OK)
-                .header("content-type", "application/json")
-                .body(body)
-                .unwrap()
+            let _permit = match state.admission.admit(settings.query_timeout).await {
+                Ok(permit) => permit,
+                Err(e) => return Ok(refused(e)),
+            };
+            let tag = batch.tag.or_else(|| state.sessions.tag(&session));
+            let span = info_span!(
+                "batch",
+                id = NEXT_QUERY.fetch_add(1, Ordering::Relaxed),
+                user,
+                tag,
+                statements = batch.statements.len(),
+                tx_id = field::Empty,
+            );
+            let started_at = Instant::now();
+            let response = run_batch(
+                &state,
+                &user,
+                &session,
+                tag,
+                batch.statements,
+                format,
+                settings,
+            )
+            .instrument(span.clone())
+            .await;
+            span.in_scope(|| {
+                info!(
+                    status = response.status().as_u16(),
+                    latency_ms = started_at.elapsed().as_millis() as u64,
+                    "Batch finished"
+                )
+            });
+            with_tx_status(&state, &session, response)
         }
 
         _ => {
@@ -386,94 +839,4012 @@ async fn handle_request(
         }
     };
 
+    if gzip {
+        return Ok(compression::gzip(response).await);
+    }
     Ok(response)
 }
 
-async fn collect_body(body: hyper::body::Incoming) -> Result<Bytes, hyper::Error> {
-    use http_body_util::BodyExt;
-    let collected = body.collect().await?;
-    Ok(collected.to_bytes())
+// Numbers queries for the logs.
+static NEXT_QUERY: AtomicU64 = AtomicU64::new(1);
+
+// How much of a statement's text its log lines carry.
+const LOGGED_SQL_CHARS: usize = 200;
+
+// Runs one statement for `session` and answers with its result. Rows are
+// streamed as `framing` says; setting `cancel` stops the statement the way
+// running out of time does.
+async fn run_query(
+    state: &Arc<AppState>,
+    user: &str,
+    session: String,
+    qb: QueryBody,
+    format: ResultFormat,
+    framing: Framing,
+    cancel: Arc<AtomicBool>,
+) -> Response<ResponseBody> {
+    // Everything logged for the query, here and on the executor's thread,
+    // is tagged with this span. The executor fills in the timings.
+    let id = NEXT_QUERY.fetch_add(1, Ordering::Relaxed);
+    let tag = qb.tag.clone().or_else(|| state.sessions.tag(&session));
+    let query = state
+        .queries
+        .start(id, user, tag.as_deref(), &qb.sql, cancel.clone());
+    let span = info_span!(
+        "query",
+        id,
+        user,
+        tag,
+        tx_id = field::Empty,
+        sql = logged_sql(&qb.sql),
+        parse_us = field::Empty,
+        bind_us = field::Empty,
+        plan_us = field::Empty,
+        execute_us = field::Empty,
+    );
+    let started_at = Instant::now();
+    let outcome = start_query(
+        state,
+        user,
+        session.clone(),
+        qb,
+        format,
+        framing,
+        query.clone(),
+    )
+    .instrument(span.clone())
+    .await;
+    match outcome {
+        Outcome::Running(response) => with_tx_status(state, &session, response),
+        Outcome::Answered(response) => {
+            let status = response.status();
+            if !status.is_success() {
+                query.fail(&format!("Answered with {}", status));
+            }
+            record_answered(state, query.take_audit().into_iter().collect(), &response);
+            span.in_scope(|| {
+                info!(
+                    status = response.status().as_u16(),
+                    latency_ms = started_at.elapsed().as_millis() as u64,
+                    "Query answered"
+                )
+            });
+            with_tx_status(state, &session, response)
+        }
+    }
 }
 
-fn create_executor_from_statement<'a>(
-    stmt: Statement,
-    storage: &'a mut Storage,
-    bind_catalog: &'a mut BinderCatalog,
-) -> anyhow::Result<Executor<'a>> {
-    
-    let mut binder = Binder::new(bind_catalog, storage);
-    let bound = binder.bind(stmt).context("Bind failed")?;
-    
-    let mut lp = LogicalPlanner::new(&bind_catalog.tables, storage);
-    let logical = lp.plan(bound).context("Logical planning failed")?;
-    
-    let optimized = Optimizer::optimize(logical).context("Optimize failed")?;
-    
-    let mut pp = PhysicalPlanner::new(bind_catalog, storage);
-    let phys = pp
-        .create_physical_plan(optimized)
-        .context("Physical planning failed")?;
-    
-    fn build<'a>(
-        plan: crate::query::physical_planner::PhysicalPlan,
-        storage: &'a mut Storage,
-        catalog: &'a BinderCatalog,
-    ) -> Box<dyn PhysicalOp + 'a> {
-        use crate::query::physical_planner::PhysicalPlan::*;
-        match plan {
-            SeqScan {
-                table_name,
-                predicate,
-            } => Box::new(SeqScanOp::new(storage, catalog, table_name, predicate)),
-            Filter { input, predicate } => {
-                let child = build(*input, storage, catalog);
-                Box::new(FilterOp::new(child, predicate))
-            }
-            Projection { input, exprs } => {
-                let child = build(*input, storage, catalog);
-                Box::new(ProjectionOp::new(child, exprs))
-            }
-            other => unimplemented!("PhysicalPlan::{:?}", other),
-        }
-    }
-    let root = build(phys, storage, bind_catalog);
-    Ok(Executor::new(root))
+// A statement that fails while rows are streaming leaves the session
+// aborted after these went out as open; the trailer, written last, says so.
+fn with_tx_status(
+    state: &AppState,
+    session: &str,
+    mut response: Response<ResponseBody>,
+) -> Response<ResponseBody> {
+    let tx = state.sessions.tx_state(session);
+    let headers = response.headers_mut();
+    headers.insert(TX_STATUS_HEADER, HeaderValue::from_static(tx.name()));
+    if let Some(tx_id) = tx.tx_id() {
+        headers.insert(TX_ID_HEADER, HeaderValue::from(tx_id));
+    }
+    response
 }
 
-pub async fn run_server(
-    addr: SocketAddr,
-    storage: Storage,
-    wal_path: PathBuf,
-) -> anyhow::Result<()> {
-    
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::TRACE)
-        .init();
-    info!("Server starting");
+// Whether a query got as far as the executor, which logs how it ends.
+enum Outcome {
+    Answered(Response<ResponseBody>),
+    Running(Response<ResponseBody>),
+}
 
-    let logmgr = Arc::new(LogManager::new(wal_path.clone())?);
-    let locks = Arc::new(LockManager::new());
+fn logged_sql(sql: &str) -> &str {
+    match sql.char_indices().nth(LOGGED_SQL_CHARS) {
+        Some((end, _)) => &sql[..end],
+        None => sql,
+    }
+}
+
+async fn start_query(
+    state: &Arc<AppState>,
+    user: &str,
+    session: String,
+    qb: QueryBody,
+    format: ResultFormat,
+    framing: Framing,
+    query: RunningQuery,
+) -> Outcome {
+    let cancel = query.cancel.clone();
+    let settings = state.settings(&session);
+    let timeout = match qb.timeout_ms {
+        Some(0) => {
+            return Outcome::Answered(
+                Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body("timeout_ms must be at least 1".into())
+                    .unwrap(),
+            );
+        }
+        Some(ms) => Duration::from_millis(ms),
+        None => settings.query_timeout,
+    };
+    // A cached response skips everything below, admission included. Inside
+    // BEGIN ... COMMIT a read has to see the transaction's own writes. What
+    // a user may read depends on their grants, so only admins share entries.
+    // A session's temporary tables hide tables of the same name, so its
+    // results are its own. Under another search path the same text reads
+    // other tables. A binary result has typed values whatever the format.
+    let has_temp = state.sessions.has_temp_tables(&session);
+    let path = settings.search_path.join(",");
+    let shape = match framing {
+        Framing::Binary => "binary",
+        _ => format.name(),
+    };
+    let cache_key = match qb.cache != Some(false)
+        && settings.result_cache
+        && !matches!(framing, Framing::Socket(_))
+        && state.result_cache.enabled()
+        && !state.sessions.in_transaction(&session)
+        && !has_temp
+    {
+        true if is_admin(state, user) => result_cache::key(&qb.sql, &format!("{} {}", shape, path)),
+        true => result_cache::key(&qb.sql, &format!("{} {} {}", shape, path, user)),
+        false => None,
+    };
+    if let Some(key) = &cache_key
+        && let Some(body) = state.result_cache.get(key)
+    {
+        if let Some(notice) = state.sessions.take_abort_notice(&session) {
+            return Outcome::Answered(
+                Response::builder()
+                    .status(StatusCode::CONFLICT)
+                    .body(notice.into())
+                    .unwrap(),
+            );
+        }
+        debug!("Answered from the result cache");
+        state.metrics.record_query("select");
+        state.metrics.observe_latency(Duration::ZERO);
+        return Outcome::Answered(
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", framing.content_type())
+                .header(RESULT_CACHE_HEADER, "hit")
+                .body(ResponseBody::Full(Some(body)))
+                .unwrap(),
+        );
+    }
+    let permit = match state.admission.admit(timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return Outcome::Answered(refused(e)),
+    };
+
+    query.enter(QueryState::Parsing);
+    let parse_started = Instant::now();
+    let stmt = match Parser::parse_one(&qb.sql) {
+        Ok(stmt) => stmt,
+        Err(diagnostics) => {
+            error!("Parse failed: {}", diagnostics);
+            return Outcome::Answered(parse_failed(&diagnostics));
+        }
+    };
+    record_elapsed("parse_us", parse_started);
+    query.enter(QueryState::Planning);
+    debug!("AST: {:?}", stmt);
+    if state.audit.enabled() {
+        query.set_audit(audit::entry(user, query.tag.as_deref(), &stmt));
+    }
+    state.metrics.record_query(metrics::statement_kind(&stmt));
+    let started_at = Instant::now();
+
+    if let Some(notice) = state.sessions.take_abort_notice(&session) {
+        return Outcome::Answered(
+            Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body(notice.into())
+                .unwrap(),
+        );
+    }
+    // Statements meant for a transaction that has been rolled back are
+    // refused rather than each run in one of its own.
+    if let TxState::Aborted(tx_id) = state.sessions.tx_state(&session)
+        && stmt != Statement::Rollback
+    {
+        return Outcome::Answered(aborted(tx_id));
+    }
+    if let Err((_, refusal)) = refuse_writes(state, std::slice::from_ref(&stmt)) {
+        return Outcome::Answered(write_refused(refusal));
+    }
+    if matches!(
+        stmt,
+        Statement::Begin
+            | Statement::Commit
+            | Statement::Rollback
+            | Statement::CreateUser { .. }
+            | Statement::DropUser { .. }
+            | Statement::Set { .. }
+            | Statement::SetVariable { .. }
+            | Statement::ShowSettings { .. }
+    ) {
+        query.enter(QueryState::Executing);
+    }
+    let response = match &stmt {
+        Statement::Begin => Some(begin_transaction(state, &session)),
+        Statement::Commit | Statement::Rollback => {
+            let commit = stmt == Statement::Commit;
+            Some(end_transaction(state, &session, commit).await)
+        }
+        Statement::CreateUser { .. } | Statement::DropUser { .. } => {
+            Some(manage_users(state, user, stmt.clone()).await)
+        }
+        Statement::Set {
+            name,
+            value,
+            global,
+        } => Some(set_setting(state, &session, user, name, value, *global)),
+        Statement::SetVariable { name, value } => {
+            state.sessions.set_variable(&session, name, value.clone());
+            Some(empty_rows())
+        }
+        Statement::ShowSettings { name } => Some(show_settings(state, &session, name.as_deref())),
+        _ => None,
+    };
+    if let Some(response) = response {
+        state.metrics.observe_latency(started_at.elapsed());
+        return Outcome::Answered(response);
+    }
+    let stmt = match resolve(state, &session, &settings, stmt).await {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            return Outcome::Answered(json_error(StatusCode::BAD_REQUEST, format!("{:#}", e)));
+        }
+    };
+    // A session may do what it likes with its own temporary tables, whatever
+    // is granted on a table of the same name. A subquery of any other table
+    // still needs its grants.
+    let is_temp = |stmt: &Statement| {
+        table_of(stmt).is_some_and(|table| state.sessions.has_temp_table(&session, table))
+    };
+    let on_temp = is_temp(&stmt) && subqueries(&stmt).into_iter().all(is_temp);
+    if !on_temp && let Err((_, denied)) = authorize(state, user, std::slice::from_ref(&stmt)).await
+    {
+        return Outcome::Answered(permission_denied(&denied));
+    }
+    if let Statement::Grant { user: grantee, .. } = &stmt
+        && state.users.get(grantee).is_none()
+    {
+        return Outcome::Answered(json_error(
+            StatusCode::BAD_REQUEST,
+            format!("User '{}' does not exist", grantee.to_ascii_lowercase()),
+        ));
+    }
+
+    // Inside BEGIN ... COMMIT the statement joins the session's
+    // transaction; otherwise it runs in one of its own.
+    let mut open = state.sessions.take(&session);
+    if let Some(tx) = open.take_if(|_| is_ddl(&stmt)) {
+        state.sessions.put_back(&session, tx);
+        return Outcome::Answered(
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("DDL cannot run inside a transaction block".into())
+                .unwrap(),
+        );
+    }
+    if open.is_none() && matches!(stmt, Statement::SetConstraints { .. }) {
+        return Outcome::Answered(
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("SET CONSTRAINTS can only run inside a transaction block".into())
+                .unwrap(),
+        );
+    }
+    if let Some(response) = settle_cursor(&stmt, open.as_mut()) {
+        if let Some(tx) = open {
+            state.sessions.put_back(&session, tx);
+        }
+        return Outcome::Answered(response);
+    }
+    // A statement of its own transaction that loses a lock conflict before
+    // anything has gone out is rolled back and run again in a new one, up
+    // to lock_retries times. One inside BEGIN ... COMMIT has its earlier
+    // statements to think of, so its client decides.
+    let (mut stmt, mut query, mut permit, mut sql) = (stmt, query, permit, qb.sql);
+    let mut retries = 0;
+    let mut lock_wait = Duration::ZERO;
+    let outcome = loop {
+        let retry = open.is_none() && retries < settings.lock_retries;
+        let tx_id = match &open {
+            Some(open) => open.tx_id,
+            None => {
+                let tx_id = state.txns.begin();
+                if let Err(e) = state.logmgr.log_begin(tx_id) {
+                    error!("WAL begin of transaction {} failed: {:#}", tx_id, e);
+                    let mut storage = state.storage.write().await;
+                    resume(&mut storage, tx_id, None);
+                    abort(state, &mut storage, tx_id);
+                    break Outcome::Answered(
+                        Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(format!("WAL begin error: {:#}", e).into())
+                            .unwrap(),
+                    );
+                }
+                debug!("Transaction {} begun", tx_id);
+                tx_id
+            }
+        };
+        Span::current().record("tx_id", tx_id);
+
+        if let Statement::CreateIndex {
+            index_name,
+            table,
+            columns,
+            ..
+        } = &stmt
+        {
+            let lock_timeout = settings.lock_timeout;
+            backfill_index(
+                state,
+                tx_id,
+                table,
+                columns,
+                index_name,
+                lock_timeout,
+                &cancel,
+            )
+            .await;
+        }
+        query.enter(QueryState::WaitingOnLock);
+        if let Some((res, mode)) = lock_target(&stmt) {
+            let waited_from = Instant::now();
+            let locked = state
+                .locks
+                .lock_with_timeout(tx_id, res.clone(), mode, Some(settings.lock_timeout))
+                .await;
+            lock_wait += waited_from.elapsed();
+            if let Err(e) = locked {
+                error!("Lock failed: {}", e);
+                let mut storage = state.storage.write().await;
+                resume(&mut storage, tx_id, open.as_mut());
+                abort(state, &mut storage, tx_id);
+                drop(storage);
+                if retry && lost_lock_conflict(&e) {
+                    back_off(&settings, &mut retries).await;
+                    continue;
+                }
+                let status = if e.downcast_ref::<LockError>().is_some() {
+                    StatusCode::CONFLICT
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                let mut message = format!("Lock error: {:#}", e);
+                if open.is_some() {
+                    state.sessions.fail(&session, tx_id);
+                    message.push_str(&aborted_suffix(tx_id));
+                }
+                break Outcome::Answered(
+                    Response::builder()
+                        .status(status)
+                        .body(message.into())
+                        .unwrap(),
+                );
+            }
+            debug!("Lock acquired: {:?} {:?}", res, mode);
+        }
+
+        // The statement runs on a blocking thread that holds the storage
+        // lock until its last row has been handed to the connection.
+        // Reads share it, so one streaming to a slow client holds up
+        // writers but not other reads. A session's temporary tables are lent
+        // to the catalog, which takes the lock to itself.
+        let storage = if is_read_only(&stmt) && !has_temp {
+            StorageGuard::Read(state.storage.clone().read_owned().await)
+        } else {
+            StorageGuard::Write(state.storage.clone().write_owned().await)
+        };
+        let (started_tx, started) = oneshot::channel();
+        let (chunks_tx, chunks) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+        let mut writer = RowWriter::new(format, framing, started_tx, chunks_tx);
+        writer.stats.lock_wait = lock_wait;
+        // A volatile function's values change without any table changing, so
+        // results using them are not kept. Nor are those read through a view,
+        // which writes to the table under it would have to know about. The
+        // tables of subqueries are read as much as the one after FROM.
+        let read: Vec<&str> = table_of(&stmt)
+            .into_iter()
+            .chain(subqueries(&stmt).into_iter().filter_map(table_of))
+            .collect();
+        if let (Some(key), Statement::Select { table: Some(_), .. }, None) =
+            (cache_key.clone(), &stmt, &open)
+            && !calls_volatile(&stmt)
+            && !uses_variables(&stmt)
+            && !read
+                .iter()
+                .any(|table| storage.storage().catalog.views.contains_key(*table))
+        {
+            // Taken before the statement's snapshot, so a commit in between
+            // leaves the versions behind and the response is not kept.
+            writer.capture = Some(Capture {
+                cache: state.result_cache.clone(),
+                key,
+                seen: state.result_cache.versions(&read),
+                body: Vec::new(),
+            });
+        }
+        let cache_header = writer.capture.as_ref().map(|_| "miss");
+        let run = StatementRun {
+            span: Span::current(),
+            state: state.clone(),
+            owner: (!is_admin(state, user)).then(|| user.to_string()),
+            tx_id,
+            open: open.take(),
+            session: session.clone(),
+            started_at,
+            timeout,
+            isolation: settings.isolation,
+            work_mem: settings.work_mem,
+            scan_errors: settings.scan_error_policy,
+            retry,
+            query,
+            permit,
+            sql,
+        };
+        let running = tokio::task::spawn_blocking(move || run.execute(storage, stmt, writer));
+        // Setting the flag makes the executor stop at its next row; the
+        // statement then fails and is rolled back like any other. The
+        // watchdog outlives this handler while rows are still streaming.
+        let remaining = timeout.saturating_sub(started_at.elapsed());
+        let watched = cancel.clone();
+        tokio::spawn(async move {
+            if tokio::time::timeout(remaining, running).await.is_err() {
+                watched.store(true, Ordering::Relaxed);
+            }
+        });
+        break Outcome::Running(match started.await {
+            Ok(Ok(stats)) => {
+                let mut response = Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", framing.content_type());
+                if let Some(value) = cache_header {
+                    response = response.header(RESULT_CACHE_HEADER, value);
+                }
+                let response = response.body(ResponseBody::Channel(chunks)).unwrap();
+                match stats {
+                    Some(stats) => stats.add_headers(response),
+                    None => response,
+                }
+            }
+            Ok(Err(Stopped::Failed(response))) => response,
+            Ok(Err(Stopped::Conflicted(rerun))) => {
+                Rerun {
+                    stmt,
+                    query,
+                    permit,
+                    sql,
+                } = *rerun;
+                back_off(&settings, &mut retries).await;
+                continue;
+            }
+            Err(_) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Statement execution failed".into())
+                .unwrap(),
+        });
+    };
+    if retries == 0 {
+        return outcome;
+    }
+    state.metrics.record_retried();
+    match outcome {
+        Outcome::Answered(response) => Outcome::Answered(with_retries(response, retries)),
+        Outcome::Running(response) => Outcome::Running(with_retries(response, retries)),
+    }
+}
+
+// Whether `e` is a lock conflict the transaction lost, which running the
+// statement again may win: it was chosen as a deadlock victim, died to an
+// older transaction, or found a row taken. A lock timeout has already
+// waited as long as it should.
+fn lost_lock_conflict(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<LockError>(),
+            Some(
+                LockError::DeadlockVictim { .. }
+                    | LockError::Retry { .. }
+                    | LockError::WouldBlock { .. }
+            )
+        )
+    })
+}
+
+// Waits lock_retry_backoff before the first retry and twice as long before
+// each one after.
+async fn back_off(settings: &Settings, retries: &mut u32) {
+    let backoff = settings.lock_retry_backoff.saturating_mul(2u32.saturating_pow(*retries));
+    *retries += 1;
+    debug!(retries = *retries, "Lock conflict lost, running the statement again");
+    tokio::time::sleep(backoff).await;
+}
+
+fn with_retries(mut response: Response<ResponseBody>, retries: u32) -> Response<ResponseBody> {
+    response
+        .headers_mut()
+        .insert(LOCK_RETRIES_HEADER, HeaderValue::from(retries));
+    response
+}
+
+// Stops a running query the way its timeout would. Users may cancel their
+// own queries, admins anyone's.
+fn cancel_query(state: &AppState, user: &str, id: &str) -> Response<ResponseBody> {
+    let query = id.parse().ok().and_then(|id| state.queries.get(id));
+    let Some(query) = query else {
+        return json_error(StatusCode::NOT_FOUND, format!("No query {} is running", id));
+    };
+    if !query.user.eq_ignore_ascii_case(user) && !is_admin(state, user) {
+        let denied = PermissionDenied {
+            user: user.to_ascii_lowercase(),
+            table: None,
+            privilege: None,
+            statement: "a cancel of another user's query",
+        };
+        return permission_denied(&denied);
+    }
+    info!(id = query.id, user, "Query cancelled");
+    query.cancel.store(true, Ordering::Relaxed);
+    let body = serde_json::json!({ "id": query.id, "cancelling": true });
+    json_response(StatusCode::ACCEPTED, body.to_string())
+}
+
+// Numbers the sessions of WebSocket connections, which have no token.
+static NEXT_SOCKET: AtomicU64 = AtomicU64::new(1);
+
+// Answers a WebSocket handshake and serves the connection as a session of
+// its own. A client already logged in through /login is authenticated by its
+// cookie; any other has to send an `auth` message first.
+fn upgrade_socket(
+    state: &Arc<AppState>,
+    mut req: Request<hyper::body::Incoming>,
+    login: Option<Session>,
+) -> Response<ResponseBody> {
+    let is_upgrade = req
+        .headers()
+        .get("upgrade")
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.eq_ignore_ascii_case("websocket"));
+    let key = req.headers().get("sec-websocket-key");
+    let (true, Some(key)) = (is_upgrade, key) else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("Expected a WebSocket upgrade".into())
+            .unwrap();
+    };
+    let accept = derive_accept_key(key.as_bytes());
+    let user = login.map(|session| session.user);
+    let upgrade = hyper::upgrade::on(&mut req);
+    let state = state.clone();
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                let socket =
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None)
+                        .await;
+                serve_socket(state, socket, user).await;
+            }
+            Err(e) => error!("WebSocket upgrade failed: {}", e),
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("upgrade", "websocket")
+        .header("connection", "Upgrade")
+        .header("sec-websocket-accept", accept)
+        .body(ResponseBody::Full(None))
+        .unwrap()
+}
+
+// The statement a socket is running. `busy` is cleared just before its last
+// message goes out, so by the time the client sees it the socket is free for
+// the next one.
+struct SocketQuery {
+    id: u64,
+    cancel: Arc<AtomicBool>,
+    busy: Arc<AtomicBool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+// Reads requests off the socket until it closes, runs their statements one
+// at a time, and rolls back whatever transaction is left open at the end.
+async fn serve_socket(
+    state: Arc<AppState>,
+    socket: WebSocketStream<TokioIo<Upgraded>>,
+    mut user: Option<String>,
+) {
+    let session = format!("socket-{}", NEXT_SOCKET.fetch_add(1, Ordering::Relaxed));
+    info!("WebSocket session {} opened", session);
+    let (sink, mut incoming) = socket.split();
+    // Rows are bounded like an HTTP body; replies are not, so a client that
+    // is slow to read its rows can still have a cancel acknowledged.
+    let (rows_tx, rows_rx) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    let (replies_tx, replies_rx) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_socket(sink, rows_rx, replies_rx));
+    let mut running: Option<SocketQuery> = None;
+    let mut stop = state.stop.clone();
+    loop {
+        let message = tokio::select! {
+            message = incoming.next() => message,
+            _ = stop.changed() => break,
+        };
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            Some(Ok(_)) => continue,
+        };
+        let reply = match serde_json::from_str::<SocketRequest>(&text) {
+            Err(e) => {
+                serde_json::json!({ "type": "error", "error": format!("Invalid message: {}", e) })
+                    .to_string()
+            }
+            Ok(SocketRequest::Auth {
+                user: name,
+                pass,
+                tag,
+            }) => {
+                let users = state.users.clone();
+                let authenticated =
+                    tokio::task::spawn_blocking(move || users.authenticate(&name, &Secret(pass)))
+                        .await
+                        .ok()
+                        .flatten();
+                match authenticated {
+                    Some(authenticated) => {
+                        info!("User {} logged in over WebSocket", authenticated.name);
+                        let reply = serde_json::json!({
+                            "type": "authenticated",
+                            "user": authenticated.name,
+                        });
+                        user = Some(authenticated.name);
+                        state.sessions.set_tag(&session, tag);
+                        reply.to_string()
+                    }
+                    None => serde_json::json!({ "type": "error", "error": "Invalid credentials" })
+                        .to_string(),
+                }
+            }
+            Ok(SocketRequest::Query {
+                id,
+                sql,
+                timeout_ms,
+                format,
+                tag,
+            }) => match &user {
+                None => socket_error(
+                    id,
+                    Some(StatusCode::UNAUTHORIZED),
+                    "Not logged in",
+                    state.sessions.tx_state(&session),
+                ),
+                Some(_)
+                    if running
+                        .as_ref()
+                        .is_some_and(|r| r.busy.load(Ordering::Acquire)) =>
+                {
+                    socket_error(
+                        id,
+                        Some(StatusCode::CONFLICT),
+                        "Another statement is still running",
+                        state.sessions.tx_state(&session),
+                    )
+                }
+                Some(_) if let Err(e) = state.admission.check_rate(&session) => socket_error(
+                    id,
+                    Some(StatusCode::TOO_MANY_REQUESTS),
+                    &e.to_string(),
+                    state.sessions.tx_state(&session),
+                ),
+                Some(user) => {
+                    let qb = QueryBody {
+                        sql,
+                        timeout_ms,
+                        cache: None,
+                        tag,
+                    };
+                    let query =
+                        spawn_socket_query(&state, user, &session, id, qb, format, &rows_tx);
+                    running = Some(query);
+                    continue;
+                }
+            },
+            Ok(SocketRequest::Cancel { id }) => match &running {
+                Some(query) if query.id == id && query.busy.load(Ordering::Acquire) => {
+                    query.cancel.store(true, Ordering::Relaxed);
+                    serde_json::json!({ "type": "cancelling", "id": id }).to_string()
+                }
+                _ => socket_error(
+                    id,
+                    None,
+                    "No such statement is running",
+                    state.sessions.tx_state(&session),
+                ),
+            },
+        };
+        if replies_tx.send(reply).is_err() {
+            break;
+        }
+    }
+
+    // Waiting for the statement to finish makes sure its transaction is back
+    // with the session, where it can be rolled back.
+    if let Some(query) = running {
+        query.cancel.store(true, Ordering::Relaxed);
+        let _ = query.task.await;
+    }
+    if state.sessions.in_transaction(&session) {
+        end_transaction(&state, &session, false).await;
+    }
+    end_session(&state, &session).await;
+    drop((rows_tx, replies_tx));
+    let _ = writer.await;
+    info!("WebSocket session {} closed", session);
+}
+
+// Forgets a session that has ended, freeing the pages of its temporary
+// tables.
+async fn end_session(state: &AppState, session: &str) {
+    let temp = state.sessions.forget(session);
+    if temp.is_empty() {
+        return;
+    }
+    if let Err(e) = state.storage.write().await.drop_temp_tables(temp) {
+        error!("Dropping the temporary tables of a session failed: {:#}", e);
+    }
+}
+
+fn spawn_socket_query(
+    state: &Arc<AppState>,
+    user: &str,
+    session: &str,
+    id: u64,
+    qb: QueryBody,
+    format: ResultFormat,
+    rows: &mpsc::Sender<String>,
+) -> SocketQuery {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let busy = Arc::new(AtomicBool::new(true));
+    let task = {
+        let (state, user, session) = (state.clone(), user.to_string(), session.to_string());
+        let (cancel, busy, rows) = (cancel.clone(), busy.clone(), rows.clone());
+        tokio::spawn(async move {
+            let response = run_query(
+                &state,
+                &user,
+                session.clone(),
+                qb,
+                format,
+                Framing::Socket(id),
+                cancel,
+            )
+            .await;
+            let (parts, body) = response.into_parts();
+            // Every chunk is a message of its own; the last is held back
+            // until the statement is over. Sending keeps going after the
+            // client is gone so that the statement runs to its end.
+            let mut last = None;
+            match body {
+                ResponseBody::Channel(mut chunks) => {
+                    while let Some(chunk) = chunks.recv().await {
+                        let chunk = String::from_utf8_lossy(&chunk).into_owned();
+                        if let Some(previous) = last.replace(chunk) {
+                            let _ = rows.send(previous).await;
+                        }
+                    }
+                }
+                ResponseBody::Full(bytes) => {
+                    let text =
+                        bytes.map_or(String::new(), |b| String::from_utf8_lossy(&b).into_owned());
+                    let tx = state.sessions.tx_state(&session);
+                    last = Some(if parts.status.is_success() {
+                        socket_done(id, &[], 0, None, 0, tx)
+                    } else {
+                        // A timeout comes as JSON with the message inside.
+                        let message = serde_json::from_str::<serde_json::Value>(&text)
+                            .ok()
+                            .and_then(|v| v["error"].as_str().map(str::to_string))
+                            .unwrap_or(text);
+                        socket_error(id, Some(parts.status), &message, tx)
+                    });
+                }
+            }
+            let last = last.unwrap_or_else(|| {
+                let tx = state.sessions.tx_state(&session);
+                socket_error(id, None, "Statement execution failed", tx)
+            });
+            busy.store(false, Ordering::Release);
+            let _ = rows.send(last).await;
+        })
+    };
+    SocketQuery {
+        id,
+        cancel,
+        busy,
+        task,
+    }
+}
+
+async fn write_socket(
+    mut sink: futures_util::stream::SplitSink<WebSocketStream<TokioIo<Upgraded>>, Message>,
+    mut rows: mpsc::Receiver<String>,
+    mut replies: mpsc::UnboundedReceiver<String>,
+) {
+    loop {
+        let message = tokio::select! {
+            biased;
+            Some(reply) = replies.recv() => reply,
+            Some(chunk) = rows.recv() => chunk,
+            else => break,
+        };
+        if let Err(e) = sink.send(Message::Text(message)).await {
+            debug!("WebSocket write failed: {}", e);
+            break;
+        }
+    }
+    let _ = sink.close().await;
+}
+
+fn health(state: &AppState) -> Response<ResponseBody> {
+    // A statement holding the lock shows storage is in use, which is enough.
+    let storage = match state.storage.try_read() {
+        Ok(storage) => storage
+            .buffer_pool
+            .pagefile
+            .check()
+            .context("Data file check failed"),
+        Err(_) => Ok(()),
+    };
+    let wal = state.logmgr.check_writable();
+    let describe = |result: &anyhow::Result<()>| match result {
+        Ok(()) => "ok".to_string(),
+        Err(e) => format!("{:#}", e),
+    };
+    let status = if storage.is_ok() && wal.is_ok() {
+        StatusCode::OK
+    } else {
+        error!("Health check failed: storage {:?}, WAL {:?}", storage, wal);
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::json!({
+        "status": if status == StatusCode::OK { "ok" } else { "unavailable" },
+        "role": if state.is_standby() { "standby" } else { "primary" },
+        "mode": if state.write_refusal().is_some() { "read_only" } else { "read_write" },
+        "storage": describe(&storage),
+        "wal": describe(&wal),
+    });
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+fn session_token(req: &Request<hyper::body::Incoming>) -> Option<&str> {
+    req.headers()
+        .get("cookie")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|c| {
+            c.split(';')
+                .find_map(|kv| kv.trim().strip_prefix("session_token="))
+        })
+}
+
+// A logged-in caller: who it is, and the token its session's state is kept
+// under.
+struct Session {
+    token: String,
+    user: String,
+}
+
+// Looked up once a request, for whichever handler needs it.
+fn session(state: &AppState, req: &Request<hyper::body::Incoming>) -> Result<Session, LoginError> {
+    let token = session_token(req);
+    let user = state.logins.check(token)?;
+    Ok(Session {
+        token: token.unwrap_or_default().to_string(),
+        user,
+    })
+}
+
+fn unauthorized(e: LoginError) -> Response<ResponseBody> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(e.to_string().into())
+        .unwrap()
+}
+
+// The code a write refused by a standby or a read-only server carries in
+// its JSON body.
+pub const READ_ONLY: &str = "READ_ONLY";
+
+const STANDBY_REFUSAL: &str = "This server is a read-only standby; promote it to write";
+
+const READ_ONLY_REFUSAL: &str = "This server was started with --read-only and only runs reads";
+
+// The one check of statements against a server that refuses writes, made
+// before anything else is done with them. A standby runs whatever leaves
+// the data alone; a read-only server only what `runs_read_only` lists.
+// Returns the first statement refused.
+fn refuse_writes(state: &AppState, stmts: &[Statement]) -> Result<(), (usize, &'static str)> {
+    let Some(refusal) = state.write_refusal() else {
+        return Ok(());
+    };
+    let refused = if state.read_only {
+        stmts.iter().position(|stmt| !runs_read_only(stmt))
+    } else {
+        stmts.iter().position(changes_data)
+    };
+    match refused {
+        Some(i) => Err((i, refusal)),
+        None => Ok(()),
+    }
+}
+
+// The code a statement refused by an aborted transaction carries in its
+// JSON body.
+pub const TRANSACTION_ABORTED: &str = "TRANSACTION_ABORTED";
+
+// What a statement sent to a transaction that has been rolled back gets.
+fn aborted(tx_id: u64) -> Response<ResponseBody> {
+    let mut body = serde_json::json!({
+        "error": format!(
+            "Transaction {} is aborted; statements are refused until ROLLBACK",
+            tx_id
+        ),
+        "code": TRANSACTION_ABORTED,
+    });
+    add_tx_status(&mut body, TxState::Aborted(tx_id));
+    json_response(StatusCode::CONFLICT, body.to_string())
+}
+
+fn write_refused(refusal: &str) -> Response<ResponseBody> {
+    debug!("Refused a write: {}", refusal);
+    let body = serde_json::json!({ "error": refusal, "code": READ_ONLY });
+    json_response(StatusCode::FORBIDDEN, body.to_string())
+}
+
+// Points `storage` at `tx_id`, restoring what an open transaction left
+// behind after its previous statement.
+fn resume(storage: &mut Storage, tx_id: u64, open: Option<&mut OpenTransaction>) {
+    storage.set_transaction(Some(tx_id));
+    match open {
+        Some(open) => {
+            storage.pending_rows = std::mem::take(&mut open.pending_rows);
+            storage.deferred = open.deferred.take();
+            storage.snapshot = Some(open.snapshot.clone());
+        }
+        None => storage.take_snapshot(),
+    }
+}
+
+enum StorageGuard {
+    Read(OwnedRwLockReadGuard<Storage>),
+    Write(OwnedRwLockWriteGuard<Storage>),
+}
+
+impl StorageGuard {
+    fn storage(&self) -> &Storage {
+        match self {
+            StorageGuard::Read(storage) => storage,
+            StorageGuard::Write(storage) => storage,
+        }
+    }
+}
+
+// A statement on its way through the executor, with what it needs to
+// commit or roll back once its rows are out.
+struct StatementRun {
+    span: Span,
+    state: Arc<AppState>,
+    // Who is granted ALL on a table the statement creates: the user, unless
+    // an admin.
+    owner: Option<String>,
+    tx_id: u64,
+    open: Option<OpenTransaction>,
+    session: String,
+    started_at: Instant,
+    timeout: Duration,
+    isolation: IsolationLevel,
+    work_mem: usize,
+    scan_errors: ScanErrorPolicy,
+    // Whether losing a lock conflict hands the statement back to be run
+    // again rather than failing it.
+    retry: bool,
+    // Its entry in /debug/queries, holding the flag that cancels it.
+    query: RunningQuery,
+    // The statement's slot, given back when it is done.
+    permit: Permit,
+    // Its text, to quote when it fails.
+    sql: String,
+}
+
+impl StatementRun {
+    fn execute(self, storage: StorageGuard, stmt: Statement, mut writer: RowWriter) {
+        let StatementRun {
+            span,
+            state,
+            owner,
+            tx_id,
+            mut open,
+            session,
+            started_at,
+            timeout,
+            isolation,
+            work_mem,
+            scan_errors,
+            retry,
+            query,
+            permit,
+            sql,
+        } = self;
+        let _entered = span.enter();
+        let cancel = query.cancel.clone();
+        query.enter(QueryState::Planning);
+        let in_block = open.is_some();
+        let rerun = retry.then(|| stmt.clone());
+        let written = written_table(&stmt).map(str::to_string);
+        // DECLARE and FETCH run the cursor's SELECT, DECLARE only as far as
+        // planning, to check it and answer with its columns.
+        let step = matches!(
+            stmt,
+            Statement::DeclareCursor { .. } | Statement::Fetch { .. }
+        )
+        .then(|| stmt.clone());
+        let stmt = match stmt {
+            Statement::DeclareCursor { select, .. } => {
+                writer.window = Some((0, 0));
+                *select
+            }
+            Statement::Fetch { cursor, count } => {
+                match open.as_ref().and_then(|open| open.cursors.get(&cursor)) {
+                    Some(found) => {
+                        writer.window = Some(found.window(count));
+                        found.select.clone()
+                    }
+                    None => Statement::Fetch { cursor, count },
+                }
+            }
+            stmt => stmt,
+        };
+        // A read never touches the transaction state kept on `Storage`; it
+        // brings its own snapshot and gives up the lock as soon as it is done.
+        let skipped_pages = Arc::new(AtomicU64::new(0));
+        let (produced, mut storage) = match storage {
+            StorageGuard::Write(mut storage) => {
+                resume(&mut storage, tx_id, open.as_mut());
+                storage.catalog.temp = state.sessions.take_temp(&session);
+                storage.random = state.sessions.random(&session);
+                storage.variables = state.sessions.variables(&session);
+                storage.cancel = Some(cancel);
+                storage.isolation = isolation;
+                storage.work_mem = work_mem;
+                storage.scan_errors = scan_errors;
+                storage.skipped_pages = skipped_pages.clone();
+                let (produced, pages) = PageCounts::track(|| {
+                    produce_rows(&mut storage, stmt, owner.as_deref(), &query, &mut writer)
+                });
+                writer.stats.pages = pages;
+                storage.cancel = None;
+                storage.variables.clear();
+                storage.scan_errors = ScanErrorPolicy::default();
+                (produced, Some(storage))
+            }
+            StorageGuard::Read(storage) => {
+                let snapshot = match &open {
+                    Some(open) => open.snapshot.clone(),
+                    None => state.txns.snapshot(Some(tx_id)),
+                };
+                let view = ReadView {
+                    storage: &storage,
+                    tx_id: Some(tx_id),
+                    snapshot: Some(snapshot),
+                    cancel: Some(cancel),
+                    isolation,
+                    scan_errors,
+                    skipped_pages: skipped_pages.clone(),
+                };
+                let random = state.sessions.random(&session);
+                let variables = state.sessions.variables(&session);
+                let (produced, pages) = PageCounts::track(|| {
+                    produce_read_rows(view, random, variables, work_mem, stmt, &query, &mut writer)
+                });
+                writer.stats.pages = pages;
+                (produced, None)
+            }
+        };
+        writer.skipped_pages = skipped_pages.load(Ordering::Relaxed);
+        if produced.is_ok()
+            && let (Some(open), Some(step)) = (open.as_mut(), step)
+        {
+            match step {
+                Statement::DeclareCursor { name, select } => {
+                    open.cursors.insert(name, Cursor::new(*select));
+                }
+                Statement::Fetch { cursor, count } => {
+                    if let Some(cursor) = open.cursors.get_mut(&cursor) {
+                        cursor.advance(writer.rows, count);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let result = produced.and_then(|()| match open.take() {
+            Some(mut open) => {
+                if let Some(storage) = &mut storage {
+                    open.pending_rows = std::mem::take(&mut storage.pending_rows);
+                    open.deferred = storage.deferred.take();
+                }
+                open.written.extend(written);
+                state.sessions.put_back(&session, open);
+                Ok(())
+            }
+            None => {
+                state.logmgr.log_commit(tx_id).context("WAL commit error")?;
+                state.txns.commit(tx_id);
+                if let Some(table) = &written {
+                    state.result_cache.invalidate(table);
+                }
+                // A read wrote nothing worth a checkpoint.
+                if let Some(storage) = &mut storage {
+                    maybe_checkpoint(&state, storage);
+                }
+                state.locks.unlock_all(tx_id);
+                Ok(())
+            }
+        });
+        let conflicted = writer.started.is_some()
+            && result.as_ref().is_err_and(lost_lock_conflict);
+        // Every failure rolls the transaction back through `abort`, which
+        // also releases its locks.
+        let result = result.map_err(|e| {
+            error!("Statement failed: {:#}", e);
+            match &mut storage {
+                Some(storage) => abort(&state, storage, tx_id),
+                // The read itself changed nothing, but the transaction may
+                // have written before it, and undoing that needs the storage
+                // to itself.
+                None => {
+                    let mut storage = state.storage.blocking_write();
+                    resume(&mut storage, tx_id, open.as_mut());
+                    abort(&state, &mut storage, tx_id);
+                }
+            }
+            let elapsed = started_at.elapsed();
+            // The flag is also set when a socket client cancels, well
+            // before the timeout is up.
+            let mut failure = if let Some(violation) = e.downcast_ref::<ForeignKeyViolation>() {
+                Failure::violation(format!("{:#}", e), violation.clone())
+            } else if let Some(changed) = e.downcast_ref::<SchemaChanged>() {
+                Failure::schema_changed(format!("{:#}", e), changed.clone())
+            } else if let Some(oom) = e.downcast_ref::<OutOfMemory>() {
+                Failure::out_of_memory(format!("{:#}", e), oom.clone())
+            } else if e.downcast_ref::<Cancelled>().is_none() {
+                Failure::error(format!("{:#}", e))
+            } else if elapsed >= timeout {
+                Failure::timed_out(elapsed, timeout)
+            } else {
+                Failure::error("Statement cancelled".to_string())
+            };
+            if in_block {
+                state.sessions.fail(&session, tx_id);
+                failure.message.push_str(&aborted_suffix(tx_id));
+            }
+            if let Some(excerpt) = excerpt_for(&sql, &e) {
+                failure.message.push('\n');
+                failure.message.push_str(&excerpt);
+            }
+            failure
+        });
+        if let Some(storage) = &mut storage {
+            let temp = std::mem::take(&mut storage.catalog.temp);
+            state.sessions.put_back_temp(&session, temp);
+        }
+        drop(storage);
+        if conflicted
+            && let Some(stmt) = rerun
+            && let Some(started) = writer.started.take()
+        {
+            let rerun = Rerun {
+                stmt,
+                query,
+                permit,
+                sql,
+            };
+            let _ = started.send(Err(Stopped::Conflicted(Box::new(rerun))));
+            return;
+        }
+        let latency = started_at.elapsed();
+        state.metrics.observe_latency(latency);
+        let latency_ms = latency.as_millis() as u64;
+        writer.stats.elapsed = latency;
+        if let Some(entry) = query.take_audit() {
+            let error = result.as_ref().err().map(|f| f.message.as_str());
+            state.audit.record(entry.finish(error));
+        }
+        match &result {
+            Ok(()) => info!(rows = writer.rows, latency_ms, "Query finished"),
+            Err(failure) => {
+                query.fail(&failure.message);
+                info!(
+                    rows = writer.rows,
+                    latency_ms,
+                    error = %failure.message,
+                    "Query failed"
+                )
+            }
+        }
+        writer.tx = state.sessions.tx_state(&session);
+        writer.finish(result);
+    }
+}
+
+fn produce_rows(
+    storage: &mut Storage,
+    stmt: Statement,
+    owner: Option<&str>,
+    query: &RunningQuery,
+    writer: &mut RowWriter,
+) -> anyhow::Result<()> {
+    if let Some(result) = run_ddl(storage, &stmt, owner) {
+        query.enter(QueryState::Executing);
+        return result;
+    }
+    let mut bind_catalog = BinderCatalog::from_storage(&storage.catalog)
+        .with_random(storage.random.clone())
+        .with_variables(storage.variables.clone())
+        .with_work_mem(storage.work_mem);
+    let exec =
+        create_executor_from_statement(stmt, storage, &mut bind_catalog).context("Build error")?;
+    send_rows(exec, query, writer)
+}
+
+// Many sessions read the same `Storage` at once, so the session's generator,
+// variables and work_mem come separately.
+fn produce_read_rows(
+    view: ReadView,
+    random: Random,
+    variables: HashMap<String, Value>,
+    work_mem: usize,
+    stmt: Statement,
+    query: &RunningQuery,
+    writer: &mut RowWriter,
+) -> anyhow::Result<()> {
+    let mut bind_catalog = BinderCatalog::from_storage(&view.storage.catalog)
+        .with_random(random)
+        .with_variables(variables)
+        .with_work_mem(work_mem);
+    let exec = create_read_executor(stmt, view, &mut bind_catalog).context("Build error")?;
+    send_rows(exec, query, writer)
+}
+
+fn send_rows(
+    mut exec: Executor,
+    query: &RunningQuery,
+    writer: &mut RowWriter,
+) -> anyhow::Result<()> {
+    debug!("Executor built");
+    query.enter(QueryState::Executing);
+    writer.set_schema(exec.schema());
+    writer.affected = exec.affected();
+    let started = Instant::now();
+    let (skip, take) = writer.window.unwrap_or((0, usize::MAX));
+    if take == 0 {
+        return Ok(());
+    }
+    exec.open().context("Exec error")?;
+    let mut skipped = 0;
+    while writer.rows < take
+        && let Some(tuple) = exec.next_row().context("Exec error")?
+    {
+        if skipped < skip {
+            skipped += 1;
+            continue;
+        }
+        writer.push(tuple)?;
+        query.add_row();
+    }
+    let closed = exec.close().context("Exec error");
+    record_elapsed("execute_us", started);
+    closed
+}
+
+// Sent when the first chunk is ready to go out, with what the statement
+// cost if it has already finished.
+type Started = Result<Option<StatementStats>, Stopped>;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct StatementStats {
+    elapsed: Duration,
+    rows: usize,
+    pages: PageCounts,
+    lock_wait: Duration,
+}
+
+impl StatementStats {
+    fn add_headers(&self, mut response: Response<ResponseBody>) -> Response<ResponseBody> {
+        let headers = response.headers_mut();
+        let millis = |d: Duration| HeaderValue::from(d.as_millis() as u64);
+        headers.insert(QUERY_MILLIS_HEADER, millis(self.elapsed));
+        headers.insert(ROWS_HEADER, HeaderValue::from(self.rows));
+        headers.insert(PAGES_READ_HEADER, HeaderValue::from(self.pages.read));
+        headers.insert(PAGES_CACHED_HEADER, HeaderValue::from(self.pages.cached));
+        headers.insert(LOCK_WAIT_HEADER, millis(self.lock_wait));
+        response
+    }
+}
+
+// Why a statement ended before its first chunk went out.
+enum Stopped {
+    Failed(Response<ResponseBody>),
+    // It lost a lock conflict and was rolled back, with nothing sent, to be
+    // run again.
+    Conflicted(Box<Rerun>),
+}
+
+// What a statement run again needs back from the run that lost.
+struct Rerun {
+    stmt: Statement,
+    query: RunningQuery,
+    permit: Permit,
+    sql: String,
+}
+
+// How a result is cut into the chunks handed to the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    // One JSON object for the whole result, split wherever a chunk ends.
+    Http,
+    // The same, in the format of `net::binary`, for a request that accepts
+    // it.
+    Binary,
+    // Every chunk a complete WebSocket message for the query with this id:
+    // `{"type":"rows",...}` per chunk, then `{"type":"done",...}` or
+    // `{"type":"error",...}`.
+    Socket(u64),
+}
+
+impl Framing {
+    fn content_type(self) -> &'static str {
+        match self {
+            Framing::Binary => binary::CONTENT_TYPE,
+            Framing::Http | Framing::Socket(_) => "application/json",
+        }
+    }
+}
+
+// Writes a result as one JSON object, `{"columns":[...],"rows":[...],
+// "row_count":N,"tx_status":"idle"}`, with `"affected":N` after the count
+// for statements that write rows and `"tx_id":N` after the status inside a
+// transaction, sent
+// ROWS_PER_CHUNK rows at a time, or as socket messages of as many rows. A
+// binary result goes out the same way, ending in the same trailer.
+// Nothing goes out until the first chunk is full or the statement is over,
+// so a statement that fails early still gets an error status; a failure
+// after that ends the object with `"error"` in place of `"row_count"`.
+struct RowWriter {
+    format: ResultFormat,
+    framing: Framing,
+    schema: Arc<Schema>,
+    affected: Option<usize>,
+    // Damaged pages the statement's scans passed over, under skip_page.
+    skipped_pages: u64,
+    buffer: Vec<u8>,
+    buffered: usize,
+    rows: usize,
+    started: Option<oneshot::Sender<Started>>,
+    chunks: mpsc::Sender<Bytes>,
+    // Set when the response is to be kept in the result cache.
+    capture: Option<Capture>,
+    // Set for a cursor's rows: how many to pass over and the most to send.
+    window: Option<(usize, usize)>,
+    stats: StatementStats,
+    // Where the statement left the session's transaction, for the trailer.
+    tx: TxState,
+}
+
+// A response collected for the result cache as it goes out, given up on if
+// it outgrows the cache.
+struct Capture {
+    cache: Arc<ResultCache>,
+    key: String,
+    seen: Versions,
+    body: Vec<u8>,
+}
+
+impl RowWriter {
+    fn new(
+        format: ResultFormat,
+        framing: Framing,
+        started: oneshot::Sender<Started>,
+        chunks: mpsc::Sender<Bytes>,
+    ) -> Self {
+        RowWriter {
+            format,
+            framing,
+            schema: Arc::default(),
+            affected: None,
+            skipped_pages: 0,
+            buffer: Self::header(framing, &Schema::default()),
+            buffered: 0,
+            rows: 0,
+            started: Some(started),
+            chunks,
+            capture: None,
+            window: None,
+            stats: StatementStats::default(),
+            tx: TxState::Idle,
+        }
+    }
+
+    fn header(framing: Framing, schema: &Schema) -> Vec<u8> {
+        match framing {
+            Framing::Http => format!(
+                r#"{{"columns":{},"rows":["#,
+                serde_json::to_string(schema.names()).unwrap()
+            )
+            .into_bytes(),
+            Framing::Binary => binary::header(schema),
+            Framing::Socket(_) => Vec::new(),
+        }
+    }
+
+    // Names the result's columns; called before the first row. Statements
+    // that produce no rows leave the list empty.
+    fn set_schema(&mut self, schema: &Arc<Schema>) {
+        self.schema = schema.clone();
+        self.buffer = Self::header(self.framing, schema);
+    }
+
+    fn push(&mut self, tuple: Tuple) -> anyhow::Result<()> {
+        let first_in_chunk = match self.framing {
+            Framing::Http => self.rows == 0,
+            Framing::Binary => true,
+            Framing::Socket(_) => self.buffered == 0,
+        };
+        if !first_in_chunk {
+            self.buffer.push(b',');
+        }
+        match (self.framing, self.format) {
+            (Framing::Binary, _) => binary::push_row(&mut self.buffer, &tuple),
+            (_, ResultFormat::Json) => serde_json::to_writer(
+                &mut self.buffer,
+                &tuple.into_iter().map(json_value).collect::<Vec<_>>(),
+            )?,
+            (_, ResultFormat::Text) => serde_json::to_writer(
+                &mut self.buffer,
+                &tuple.into_iter().map(text_value).collect::<Vec<_>>(),
+            )?,
+        }
+        self.rows += 1;
+        self.buffered += 1;
+        if self.buffered >= ROWS_PER_CHUNK {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(started) = self.started.take() {
+            let _ = started.send(Ok(None));
+        }
+        let rows = std::mem::take(&mut self.buffer);
+        let chunk = match self.framing {
+            Framing::Http | Framing::Binary => rows,
+            Framing::Socket(_) if self.buffered == 0 => return Ok(()),
+            Framing::Socket(id) => {
+                let rows = String::from_utf8(rows)?;
+                format!(r#"{{"type":"rows","id":{},"rows":[{}]}}"#, id, rows).into_bytes()
+            }
+        };
+        self.buffered = 0;
+        self.send(chunk)
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) -> anyhow::Result<()> {
+        let chunk = Bytes::from(chunk);
+        self.chunks
+            .blocking_send(chunk.clone())
+            .map_err(|_| anyhow::anyhow!("Client disconnected while rows were being sent"))?;
+        if let Some(capture) = &mut self.capture {
+            match capture.body.len() + chunk.len() <= capture.cache.budget() {
+                true => capture.body.extend_from_slice(&chunk),
+                false => self.capture = None,
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, result: Result<(), Failure>) {
+        self.stats.rows = self.affected.unwrap_or(self.rows);
+        let result = match result {
+            Ok(()) => Ok(()),
+            Err(failure) => match self.started.take() {
+                Some(started) => {
+                    let response = self.stats.add_headers(failure.into_response());
+                    let _ = started.send(Err(Stopped::Failed(response)));
+                    return;
+                }
+                None => Err(failure.message),
+            },
+        };
+        match self.framing {
+            Framing::Http | Framing::Binary => {
+                let succeeded = result.is_ok();
+                let mut fields = match result {
+                    Ok(()) => {
+                        let mut fields = format!(r#""row_count":{}"#, self.rows);
+                        if let Some(affected) = self.affected {
+                            fields.push_str(&format!(r#","affected":{}"#, affected));
+                        }
+                        if self.skipped_pages > 0 {
+                            fields.push_str(&format!(r#","skipped_pages":{}"#, self.skipped_pages));
+                        }
+                        fields
+                    }
+                    Err(message) => {
+                        format!(r#""error":{}"#, serde_json::Value::String(message))
+                    }
+                };
+                fields.push_str(&format!(r#","tx_status":"{}""#, self.tx.name()));
+                if let Some(tx_id) = self.tx.tx_id() {
+                    fields.push_str(&format!(r#","tx_id":{}"#, tx_id));
+                }
+                let trailer = match self.framing {
+                    Framing::Binary => binary::trailer(&format!("{{{}}}", fields)),
+                    _ => format!("],{}}}", fields).into_bytes(),
+                };
+                self.buffer.extend_from_slice(&trailer);
+                if let Some(started) = self.started.take() {
+                    let _ = started.send(Ok(Some(self.stats)));
+                }
+                let complete = self.flush().is_ok() && succeeded;
+                if let Some(capture) = self.capture.take().filter(|_| complete) {
+                    capture
+                        .cache
+                        .insert(capture.key, capture.seen, Bytes::from(capture.body));
+                }
+            }
+            Framing::Socket(id) => {
+                if self.flush().is_err() {
+                    return;
+                }
+                let last = match result {
+                    Ok(()) => socket_done(
+                        id,
+                        self.schema.names(),
+                        self.rows,
+                        self.affected,
+                        self.skipped_pages,
+                        self.tx,
+                    ),
+                    Err(message) => socket_error(id, None, &message, self.tx),
+                };
+                let _ = self.send(last.into_bytes());
+            }
+        }
+    }
+}
+
+fn socket_done(
+    id: u64,
+    columns: &[String],
+    row_count: usize,
+    affected: Option<usize>,
+    skipped_pages: u64,
+    tx: TxState,
+) -> String {
+    let mut done = serde_json::json!({
+        "type": "done",
+        "id": id,
+        "columns": columns,
+        "row_count": row_count,
+    });
+    if let Some(affected) = affected {
+        done["affected"] = affected.into();
+    }
+    if skipped_pages > 0 {
+        done["skipped_pages"] = skipped_pages.into();
+    }
+    add_tx_status(&mut done, tx);
+    done.to_string()
+}
+
+fn socket_error(id: u64, status: Option<StatusCode>, message: &str, tx: TxState) -> String {
+    let mut error = serde_json::json!({ "type": "error", "id": id, "error": message });
+    if let Some(status) = status {
+        error["status"] = status.as_u16().into();
+    }
+    add_tx_status(&mut error, tx);
+    error.to_string()
+}
+
+// `"tx_status"`, and `"tx_id"` when there is a transaction, as the trailer
+// of a result has them.
+fn add_tx_status(object: &mut serde_json::Value, tx: TxState) {
+    object["tx_status"] = tx.name().into();
+    if let Some(tx_id) = tx.tx_id() {
+        object["tx_id"] = tx_id.into();
+    }
+}
+
+// Why a statement failed. A timeout is answered with 408 and a JSON body
+// giving the time taken, a foreign key violation or a stale plan with 409
+// and a code, running out of work_mem with 507 and a code, everything else
+// with 500 and the message.
+struct Failure {
+    message: String,
+    timed_out: Option<(Duration, Duration)>,
+    violation: Option<ForeignKeyViolation>,
+    schema_changed: Option<SchemaChanged>,
+    out_of_memory: Option<OutOfMemory>,
+}
+
+impl Failure {
+    fn error(message: String) -> Self {
+        Failure {
+            message,
+            timed_out: None,
+            violation: None,
+            schema_changed: None,
+            out_of_memory: None,
+        }
+    }
+
+    fn out_of_memory(message: String, oom: OutOfMemory) -> Self {
+        Failure {
+            out_of_memory: Some(oom),
+            ..Failure::error(message)
+        }
+    }
+
+    fn violation(message: String, violation: ForeignKeyViolation) -> Self {
+        Failure {
+            violation: Some(violation),
+            ..Failure::error(message)
+        }
+    }
+
+    fn schema_changed(message: String, changed: SchemaChanged) -> Self {
+        Failure {
+            schema_changed: Some(changed),
+            ..Failure::error(message)
+        }
+    }
+
+    fn timed_out(elapsed: Duration, timeout: Duration) -> Self {
+        Failure {
+            message: format!(
+                "Statement timed out after {} ms (limit {} ms)",
+                elapsed.as_millis(),
+                timeout.as_millis()
+            ),
+            timed_out: Some((elapsed, timeout)),
+            ..Failure::error(String::new())
+        }
+    }
+
+    fn into_response(self) -> Response<ResponseBody> {
+        if let Some(violation) = self.violation {
+            let body = serde_json::json!({
+                "error": self.message,
+                "code": FOREIGN_KEY_VIOLATION,
+                "constraint": violation.constraint,
+                "table": violation.table,
+            });
+            return json_response(StatusCode::CONFLICT, body.to_string());
+        }
+        if let Some(changed) = self.schema_changed {
+            let body = serde_json::json!({
+                "error": self.message,
+                "code": SCHEMA_CHANGED,
+                "table": changed.table,
+            });
+            return json_response(StatusCode::CONFLICT, body.to_string());
+        }
+        if let Some(oom) = self.out_of_memory {
+            let body = serde_json::json!({
+                "error": self.message,
+                "code": OUT_OF_MEMORY,
+                "operator": oom.operator,
+                "budget": oom.budget,
+            });
+            return json_response(StatusCode::INSUFFICIENT_STORAGE, body.to_string());
+        }
+        match self.timed_out {
+            Some((elapsed, timeout)) => {
+                let body = serde_json::json!({
+                    "error": self.message,
+                    "elapsed_ms": elapsed.as_millis() as u64,
+                    "timeout_ms": timeout.as_millis() as u64,
+                });
+                Response::builder()
+                    .status(StatusCode::REQUEST_TIMEOUT)
+                    .header("content-type", "application/json")
+                    .body(body.to_string().into())
+                    .unwrap()
+            }
+            None => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(self.message.into())
+                .unwrap(),
+        }
+    }
+}
+
+// Most responses are built whole; a query result is fed in from a channel
+// while the statement is still running.
+pub enum ResponseBody {
+    Full(Option<Bytes>),
+    Channel(mpsc::Receiver<Bytes>),
+}
+
+impl From<String> for ResponseBody {
+    fn from(s: String) -> Self {
+        ResponseBody::Full(Some(Bytes::from(s)))
+    }
+}
+
+impl From<&str> for ResponseBody {
+    fn from(s: &str) -> Self {
+        s.to_string().into()
+    }
+}
+
+impl Body for ResponseBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        match self.get_mut() {
+            ResponseBody::Full(bytes) => Poll::Ready(bytes.take().map(|b| Ok(Frame::data(b)))),
+            ResponseBody::Channel(chunks) => chunks
+                .poll_recv(cx)
+                .map(|chunk| chunk.map(|b| Ok(Frame::data(b)))),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        matches!(self, ResponseBody::Full(None))
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            ResponseBody::Full(bytes) => {
+                SizeHint::with_exact(bytes.as_ref().map_or(0, |b| b.len() as u64))
+            }
+            ResponseBody::Channel(_) => SizeHint::default(),
+        }
+    }
+}
+
+fn json_value(value: Value) -> serde_json::Value {
+    match value {
+        Value::Int(i) => serde_json::Value::from(i),
+        Value::String(s) => serde_json::Value::String(s),
+    }
+}
+
+fn text_value(value: Value) -> String {
+    match value {
+        Value::Int(i) => i.to_string(),
+        Value::String(s) => s,
+    }
+}
+
+fn empty_rows() -> Response<ResponseBody> {
+    let empty = QueryResponse::<String> { rows: Vec::new() };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&empty).unwrap().into())
+        .unwrap()
+}
+
+// Runs every statement of a batch in one transaction that commits only if
+// all of them succeed. Rollback cannot undo DDL on its own, so the batch
+// keeps storage to itself from first statement to last and puts the catalog
+// back as it found it when anything fails.
+async fn run_batch(
+    state: &Arc<AppState>,
+    user: &str,
+    session: &str,
+    tag: Option<String>,
+    sql: Vec<String>,
+    format: ResultFormat,
+    settings: Settings,
+) -> Response<ResponseBody> {
+    // Every statement is parsed before any runs, so all their syntax errors
+    // are reported together; the first of them counts as where it failed.
+    let mut stmts = Vec::with_capacity(sql.len());
+    let mut failed = Vec::new();
+    for (i, sql) in sql.iter().enumerate() {
+        match Parser::parse_one(sql) {
+            Ok(stmt) => stmts.push(stmt),
+            Err(diagnostics) => {
+                error!("Batch statement {} failed to parse: {}", i, diagnostics);
+                failed.push((i, diagnostics));
+            }
+        }
+    }
+    if let Some(&(first, _)) = failed.first() {
+        let message = match failed.as_slice() {
+            [(_, diagnostics)] => format!("Parse error: {}", diagnostics),
+            _ => failed.iter().fold("Parse error:".to_string(), |message, (i, d)| {
+                format!("{}\nstatement {}: {}", message, i, d)
+            }),
+        };
+        let report = BatchResponse::rolled_back(Vec::new(), Some(first), message);
+        return report.into_response(StatusCode::BAD_REQUEST);
+    }
+    if stmts.is_empty() {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("A batch needs at least one statement".into())
+            .unwrap();
+    }
+    let audited: Vec<AuditEntry> = match state.audit.enabled() {
+        true => stmts
+            .iter()
+            .filter_map(|stmt| audit::entry(user, tag.as_deref(), stmt))
+            .collect(),
+        false => Vec::new(),
+    };
+    let response = run_parsed_batch(state, user, session, &sql, stmts, format, settings).await;
+    // The batch commits or rolls back as a whole, and so do its entries.
+    record_answered(state, audited, &response);
+    response
+}
+
+// Queues the audit entries of statements that ended with `response`.
+fn record_answered(state: &AppState, entries: Vec<AuditEntry>, response: &Response<ResponseBody>) {
+    let status = response.status();
+    let failure = (!status.is_success()).then(|| format!("Answered with {}", status));
+    for entry in entries {
+        state.audit.record(entry.finish(failure.as_deref()));
+    }
+}
+
+async fn run_parsed_batch(
+    state: &Arc<AppState>,
+    user: &str,
+    session: &str,
+    sql: &[String],
+    stmts: Vec<Statement>,
+    format: ResultFormat,
+    settings: Settings,
+) -> Response<ResponseBody> {
+    // Names are resolved against the catalog as it is before the batch, the
+    // way its locks are worked out.
+    let mut resolved = Vec::with_capacity(stmts.len());
+    for (i, stmt) in stmts.into_iter().enumerate() {
+        match resolve(state, session, &settings, stmt).await {
+            Ok(stmt) => resolved.push(stmt),
+            Err(e) => {
+                let report = BatchResponse::rolled_back(Vec::new(), Some(i), format!("{:#}", e));
+                return report.into_response(StatusCode::BAD_REQUEST);
+            }
+        }
+    }
+    let stmts = resolved;
+    if let Some((i, stmt)) = stmts.iter().enumerate().find(|(_, s)| !runs_in_batch(s)) {
+        let report = BatchResponse::rolled_back(
+            Vec::new(),
+            Some(i),
+            format!("{:?} cannot run in a batch", stmt),
+        );
+        return report.into_response(StatusCode::BAD_REQUEST);
+    }
+    if let Err((i, refusal)) = refuse_writes(state, &stmts) {
+        let report = BatchResponse::rolled_back(Vec::new(), Some(i), refusal.to_string());
+        return report.into_response(StatusCode::FORBIDDEN);
+    }
+    if let Err((i, denied)) = authorize(state, user, &stmts).await {
+        error!("{}", denied);
+        let report = BatchResponse::rolled_back(Vec::new(), Some(i), denied.to_string());
+        return report.into_response(StatusCode::FORBIDDEN);
+    }
+    let owner = (!is_admin(state, user)).then(|| user.to_string());
+
+    let started_at = Instant::now();
+    let tx_id = state.txns.begin();
+    Span::current().record("tx_id", tx_id);
+    if let Err(e) = state.logmgr.log_begin(tx_id) {
+        error!("WAL begin of transaction {} failed: {:#}", tx_id, e);
+        let mut storage = state.storage.write().await;
+        resume(&mut storage, tx_id, None);
+        abort(state, &mut storage, tx_id);
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("WAL begin error: {:#}", e).into())
+            .unwrap();
+    }
+    // Every table lock is taken before storage is, so the batch never waits
+    // for a lock while holding up everybody else.
+    for (i, stmt) in stmts.iter().enumerate() {
+        let Some((res, mode)) = lock_target(stmt) else {
+            continue;
+        };
+        let locked = state
+            .locks
+            .lock_with_timeout(tx_id, res, mode, Some(settings.lock_timeout))
+            .await;
+        if let Err(e) = locked {
+            error!("Batch lock failed: {}", e);
+            let mut storage = state.storage.write().await;
+            resume(&mut storage, tx_id, None);
+            abort(state, &mut storage, tx_id);
+            let status = if e.downcast_ref::<LockError>().is_some() {
+                StatusCode::CONFLICT
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            let report =
+                BatchResponse::rolled_back(Vec::new(), Some(i), format!("Lock error: {:#}", e));
+            return report.into_response(status);
+        }
+    }
+
+    let mut storage = state.storage.clone().write_owned().await;
+    storage.isolation = settings.isolation;
+    storage.work_mem = settings.work_mem;
+    storage.random = state.sessions.random(session);
+    storage.variables = state.sessions.variables(session);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let watchdog = {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(settings.query_timeout).await;
+            cancel.store(true, Ordering::Relaxed);
+        })
+    };
+    let run = {
+        let state = state.clone();
+        let cancel = cancel.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            execute_batch(&state, storage, tx_id, stmts, owner, format, cancel)
+        })
+    };
+    let result = run.await;
+    watchdog.abort();
+    state.metrics.observe_latency(started_at.elapsed());
+    match result {
+        Ok(Ok(results)) => BatchResponse {
+            status: "committed",
+            results,
+            failed_index: None,
+            error: None,
+        }
+        .into_response(StatusCode::OK),
+        Ok(Err((mut report, e))) => {
+            if let Some(i) = report.failed_index
+                && let Some(excerpt) = excerpt_for(&sql[i], &e)
+                && let Some(error) = &mut report.error
+            {
+                error.push('\n');
+                error.push_str(&excerpt);
+            }
+            let status = if e.downcast_ref::<Cancelled>().is_some() {
+                StatusCode::REQUEST_TIMEOUT
+            } else if e.downcast_ref::<OutOfMemory>().is_some() {
+                StatusCode::INSUFFICIENT_STORAGE
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            report.into_response(status)
+        }
+        Err(e) => {
+            error!("Batch did not finish: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Batch execution failed".into())
+                .unwrap()
+        }
+    }
+}
+
+// Loads a CSV body into a table in a transaction of its own. The body is
+// read as it arrives instead of being collected first, so the body size
+// limit does not apply; the import has storage to itself until it ends.
+// A client that accepts `text/event-stream` is sent `progress` events as
+// it goes, then the report as a `done` event or the failure as an `error`
+// event.
+async fn import_table(
+    state: &Arc<AppState>,
+    req: Request<hyper::body::Incoming>,
+    login: Result<Session, LoginError>,
+    table: String,
+) -> Response<ResponseBody> {
+    let (session, user) = match login {
+        Ok(login) => (login.token, login.user),
+        Err(e) => {
+            error!("Unauthorized import: {}", e);
+            return unauthorized(e);
+        }
+    };
+    let options = match CsvOptions::from_query(req.uri().query()) {
+        Ok(options) => options,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let settings = state.settings(&session);
+    if state.sessions.in_transaction(&session) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "An import cannot run inside a transaction block".to_string(),
+        );
+    }
+    if let Some(refusal) = state.write_refusal() {
+        return write_refused(refusal);
+    }
+    let (exists, allowed) = {
+        let storage = state.storage.read().await;
+        let exists = storage.catalog.get_table(&table).is_ok();
+        let allowed = match (is_admin(state, &user), exists) {
+            (true, _) => Ok(()),
+            (false, true) => privileges::check_table(
+                &storage.catalog,
+                &user,
+                &table,
+                Privilege::Insert,
+                "an import",
+            ),
+            // The table would be created without anyone granted it.
+            (false, false) => Err(PermissionDenied {
+                user: user.to_ascii_lowercase(),
+                table: None,
+                privilege: None,
+                statement: "an import that creates a table",
+            }),
+        };
+        (exists, allowed)
+    };
+    if !options.create && !exists {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            format!("Table '{}' does not exist", table),
+        );
+    }
+    let tag = state.sessions.tag(&session);
+    let audited = state
+        .audit
+        .enabled()
+        .then(|| audit::import(&user, tag.as_deref(), &table));
+    if let Err(denied) = allowed {
+        let response = permission_denied(&denied);
+        record_answered(state, audited.into_iter().collect(), &response);
+        return response;
+    }
+
+    let _permit = match state.admission.admit(settings.query_timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return refused(e),
+    };
+    let span = info_span!(
+        "import",
+        id = NEXT_QUERY.fetch_add(1, Ordering::Relaxed),
+        user,
+        tag,
+        table,
+        tx_id = field::Empty,
+    );
+    let started_at = Instant::now();
+    let response = load_csv(state, req, table, options, settings.lock_timeout, audited)
+        .instrument(span.clone())
+        .await;
+    span.in_scope(|| {
+        info!(
+            status = response.status().as_u16(),
+            latency_ms = started_at.elapsed().as_millis() as u64,
+            "Import finished"
+        )
+    });
+    response
+}
+
+async fn load_csv(
+    state: &Arc<AppState>,
+    req: Request<hyper::body::Incoming>,
+    table: String,
+    options: CsvOptions,
+    lock_timeout: Duration,
+    audited: Option<AuditEntry>,
+) -> Response<ResponseBody> {
+    let tx_id = match begin_locked(state, &table, lock_timeout).await {
+        Ok(tx_id) => tx_id,
+        Err(response) => {
+            record_answered(state, audited.into_iter().collect(), &response);
+            return response;
+        }
+    };
+    let events = req
+        .headers()
+        .get("accept")
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.contains("text/event-stream"));
+    let chunks = forward_body(req.into_body(), Bytes::new());
+
+    let (events_tx, events_rx) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    let storage = state.storage.clone().write_owned().await;
+    let run = {
+        let state = state.clone();
+        let span = Span::current();
+        let events_tx = events_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            import_rows(
+                &state,
+                storage,
+                tx_id,
+                &table,
+                chunks,
+                options,
+                |progress| {
+                    // A client that went away misses the rest; the import itself
+                    // goes on to the end of the body it has.
+                    if events {
+                        let _ = events_tx.blocking_send(server_event(
+                            "progress",
+                            &serde_json::to_string(progress).unwrap(),
+                        ));
+                    }
+                },
+            )
+        })
+    };
+    if !events {
+        return match import_outcome(state, audited, run.await) {
+            Ok(report) => json_response(StatusCode::OK, serde_json::to_string(&report).unwrap()),
+            Err((status, message)) => json_error(status, message),
+        };
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        // The status line is long gone, so a failure carries its status.
+        let event = match import_outcome(&state, audited, run.await) {
+            Ok(report) => server_event("done", &serde_json::to_string(&report).unwrap()),
+            Err((status, message)) => {
+                let body = serde_json::json!({ "error": message, "status": status.as_u16() });
+                server_event("error", &body.to_string())
+            }
+        };
+        let _ = events_tx.send(event).await;
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(ResponseBody::Channel(events_rx))
+        .unwrap()
+}
+
+// Starts the transaction an import or COPY runs in and locks the whole
+// table for it, so the rows it adds need no locks of their own.
+async fn begin_locked(
+    state: &AppState,
+    table: &str,
+    lock_timeout: Duration,
+) -> Result<u64, Response<ResponseBody>> {
+    let tx_id = state.txns.begin();
+    Span::current().record("tx_id", tx_id);
+    if let Err(e) = state.logmgr.log_begin(tx_id) {
+        error!("WAL begin of transaction {} failed: {:#}", tx_id, e);
+        let mut storage = state.storage.write().await;
+        resume(&mut storage, tx_id, None);
+        abort(state, &mut storage, tx_id);
+        return Err(json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("WAL begin error: {:#}", e),
+        ));
+    }
+    let res = Resource::Table(table.to_string());
+    let locked = state
+        .locks
+        .lock_with_timeout(tx_id, res, LockMode::Exclusive, Some(lock_timeout))
+        .await;
+    if let Err(e) = locked {
+        error!("Table lock failed: {}", e);
+        let mut storage = state.storage.write().await;
+        resume(&mut storage, tx_id, None);
+        abort(state, &mut storage, tx_id);
+        let status = if e.downcast_ref::<LockError>().is_some() {
+            StatusCode::CONFLICT
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        return Err(json_error(status, format!("Lock error: {:#}", e)));
+    }
+    Ok(tx_id)
+}
+
+// Hands the rest of a request body, after `first`, to a blocking task as
+// it arrives.
+fn forward_body(
+    mut body: hyper::body::Incoming,
+    first: Bytes,
+) -> mpsc::Receiver<std::io::Result<Bytes>> {
+    let (chunks_tx, chunks) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    tokio::spawn(async move {
+        use http_body_util::BodyExt;
+        if !first.is_empty() && chunks_tx.send(Ok(first)).await.is_err() {
+            return;
+        }
+        while let Some(frame) = body.frame().await {
+            let chunk = match frame {
+                Ok(frame) => match frame.into_data() {
+                    Ok(data) => Ok(data),
+                    Err(_) => continue,
+                },
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+            let failed = chunk.is_err();
+            if chunks_tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    chunks
+}
+
+// The report of an import, or the status and message it failed with. Its
+// audit entry is queued with the same outcome.
+fn import_outcome(
+    state: &AppState,
+    audited: Option<AuditEntry>,
+    run: Result<anyhow::Result<ImportReport>, tokio::task::JoinError>,
+) -> Result<ImportReport, (StatusCode, String)> {
+    let outcome = import_result(run);
+    if let Some(entry) = audited {
+        let error = outcome.as_ref().err().map(|(_, message)| message.as_str());
+        state.audit.record(entry.finish(error));
+    }
+    outcome
+}
+
+fn import_result(
+    run: Result<anyhow::Result<ImportReport>, tokio::task::JoinError>,
+) -> Result<ImportReport, (StatusCode, String)> {
+    match run {
+        Ok(Ok(report)) => {
+            info!(
+                rows = report.rows_imported,
+                skipped = report.error_count,
+                rows_per_second = report.rows_per_second,
+                "Rows imported"
+            );
+            Ok(report)
+        }
+        Ok(Err(e)) => {
+            let status = if e.downcast_ref::<BadCsv>().is_some() {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            Err((status, format!("Import failed: {:#}", e)))
+        }
+        Err(e) => {
+            error!("Import did not finish: {}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Import failed".to_string(),
+            ))
+        }
+    }
+}
+
+// One server-sent event. `data` is JSON, so it has no line breaks.
+fn server_event(name: &str, data: &str) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+fn import_rows(
+    state: &AppState,
+    mut storage: OwnedRwLockWriteGuard<Storage>,
+    tx_id: u64,
+    table: &str,
+    chunks: mpsc::Receiver<std::io::Result<Bytes>>,
+    options: CsvOptions,
+    progress: impl FnMut(&ImportProgress),
+) -> anyhow::Result<ImportReport> {
+    let mut catalog = storage.catalog.clone();
+    resume(&mut storage, tx_id, None);
+    let body = BodyReader::new(chunks);
+    // With batches, each commit starts the next transaction, which takes
+    // over the table lock; a failure rolls back only the batch it is in.
+    let mut tx_id = tx_id;
+    let mut committed = 0;
+    let imported = csv_io::import(
+        &mut storage,
+        table,
+        body,
+        options,
+        |storage, rows| {
+            state.logmgr.log_commit(tx_id).context("WAL commit error")?;
+            state.txns.commit(tx_id);
+            state.result_cache.invalidate(table);
+            debug!(
+                "Batch of {} committed as {}, {} rows in",
+                table, tx_id, rows
+            );
+            committed = rows;
+            catalog = storage.catalog.clone();
+            maybe_checkpoint(state, storage);
+            let next = state.txns.begin();
+            state.locks.hand_over(tx_id, next);
+            tx_id = next;
+            resume(storage, tx_id, None);
+            state.logmgr.log_begin(tx_id).context("WAL begin error")?;
+            Ok(())
+        },
+        progress,
+    )
+    .and_then(|report| {
+        state.logmgr.log_commit(tx_id).context("WAL commit error")?;
+        Ok(report)
+    });
+    match imported {
+        Ok(report) => {
+            state.txns.commit(tx_id);
+            state.result_cache.invalidate(table);
+            maybe_checkpoint(state, &mut storage);
+            state.locks.unlock_all(tx_id);
+            Ok(report)
+        }
+        Err(e) => {
+            error!("Import into {} failed: {:#}", table, e);
+            // As in a batch, the catalog goes back before the rollback. That
+            // also forgets a table the import created.
+            storage.catalog = catalog;
+            abort(state, &mut storage, tx_id);
+            match committed {
+                0 => Err(e),
+                rows => Err(e.context(format!("{} rows were committed before it", rows))),
+            }
+        }
+    }
+}
+
+// Runs `COPY t FROM STDIN`. The body is the statement on a line of its
+// own, then the rows, read as they arrive like an import's and added in a
+// transaction of their own through the bulk path.
+async fn copy_table(
+    state: &Arc<AppState>,
+    req: Request<hyper::body::Incoming>,
+    login: Result<Session, LoginError>,
+) -> Response<ResponseBody> {
+    let (session, user) = match login {
+        Ok(login) => (login.token, login.user),
+        Err(e) => {
+            error!("Unauthorized COPY: {}", e);
+            return unauthorized(e);
+        }
+    };
+    let settings = state.settings(&session);
+    if state.sessions.in_transaction(&session) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "COPY cannot run inside a transaction block".to_string(),
+        );
+    }
+    if let Some(refusal) = state.write_refusal() {
+        return write_refused(refusal);
+    }
+    let mut body = req.into_body();
+    let (sql, rest) = match read_statement_line(&mut body).await {
+        Ok(read) => read,
+        Err(message) => return json_error(StatusCode::BAD_REQUEST, message),
+    };
+    let stmt = match Parser::parse_one(&sql) {
+        Ok(stmt) => stmt,
+        Err(diagnostics) => {
+            error!("Parse failed: {}", diagnostics);
+            return parse_failed(&diagnostics);
+        }
+    };
+    let stmt = match resolve(state, &session, &settings, stmt).await {
+        Ok(stmt) => stmt,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+    };
+    let Statement::Copy {
+        table,
+        columns,
+        format,
+    } = &stmt
+    else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "The body has to start with COPY ... FROM STDIN".to_string(),
+        );
+    };
+    if state.storage.read().await.catalog.get_table(table).is_err() {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            format!("Table '{}' does not exist", table),
+        );
+    }
+    let tag = state.sessions.tag(&session);
+    let audited = audit::entry(&user, tag.as_deref(), &stmt).filter(|_| state.audit.enabled());
+    if let Err((_, denied)) = authorize(state, &user, std::slice::from_ref(&stmt)).await {
+        let response = permission_denied(&denied);
+        record_answered(state, audited.into_iter().collect(), &response);
+        return response;
+    }
+
+    let _permit = match state.admission.admit(settings.query_timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return refused(e),
+    };
+    let span = info_span!(
+        "copy",
+        id = NEXT_QUERY.fetch_add(1, Ordering::Relaxed),
+        user,
+        tag,
+        table,
+        tx_id = field::Empty,
+    );
+    let started_at = Instant::now();
+    let response = load_copy(
+        state,
+        body,
+        rest,
+        table.clone(),
+        columns.clone(),
+        *format,
+        settings.lock_timeout,
+    )
+    .instrument(span.clone())
+    .await;
+    span.in_scope(|| {
+        info!(
+            status = response.status().as_u16(),
+            latency_ms = started_at.elapsed().as_millis() as u64,
+            "COPY finished"
+        )
+    });
+    record_answered(state, audited.into_iter().collect(), &response);
+    response
+}
+
+// Reads a body up to the end of its first line, returning that line and
+// whatever came after it in the same frames. A body of one line without
+// its line break has no rows.
+async fn read_statement_line(body: &mut hyper::body::Incoming) -> Result<(String, Bytes), String> {
+    use http_body_util::BodyExt;
+    let mut read = Vec::new();
+    let mut finished = false;
+    loop {
+        let end = read.iter().position(|&b| b == b'\n');
+        if end.is_some() || finished {
+            let rest = Bytes::from(read.split_off(end.map_or(read.len(), |end| end + 1)));
+            let line = String::from_utf8(read).map_err(|_| "The statement is not UTF-8")?;
+            return Ok((line, rest));
+        }
+        if read.len() > MAX_COPY_STATEMENT_BYTES {
+            return Err(format!(
+                "The statement has to end its line within {} bytes",
+                MAX_COPY_STATEMENT_BYTES
+            ));
+        }
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Ok(data) = frame.into_data() {
+                    read.extend_from_slice(&data);
+                }
+            }
+            Some(Err(e)) => return Err(format!("Reading the body failed: {}", e)),
+            None => finished = true,
+        }
+    }
+}
+
+async fn load_copy(
+    state: &Arc<AppState>,
+    body: hyper::body::Incoming,
+    first: Bytes,
+    table: String,
+    columns: Vec<String>,
+    format: CopyFormat,
+    lock_timeout: Duration,
+) -> Response<ResponseBody> {
+    let tx_id = match begin_locked(state, &table, lock_timeout).await {
+        Ok(tx_id) => tx_id,
+        Err(response) => return response,
+    };
+    let chunks = forward_body(body, first);
+    let mut storage = state.storage.clone().write_owned().await;
+    let run = {
+        let state = state.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let catalog = storage.catalog.clone();
+            resume(&mut storage, tx_id, None);
+            let body = BodyReader::new(chunks);
+            let copied =
+                copy::copy_in(&mut storage, &table, &columns, format, body).and_then(|report| {
+                    state.logmgr.log_commit(tx_id).context("WAL commit error")?;
+                    Ok(report)
+                });
+            match copied {
+                Ok(report) => {
+                    state.txns.commit(tx_id);
+                    state.result_cache.invalidate(&table);
+                    maybe_checkpoint(&state, &mut storage);
+                    state.locks.unlock_all(tx_id);
+                    Ok(report)
+                }
+                Err(e) => {
+                    error!("COPY into {} failed: {:#}", table, e);
+                    // The index roots the bulk load moved go back with it.
+                    storage.catalog = catalog;
+                    abort(&state, &mut storage, tx_id);
+                    Err(e)
+                }
+            }
+        })
+    };
+    match run.await {
+        Ok(Ok(report)) => {
+            let CopyReport {
+                rows,
+                rows_per_second,
+                ..
+            } = report;
+            info!(rows, rows_per_second, "Rows copied");
+            json_response(StatusCode::OK, serde_json::to_string(&report).unwrap())
+        }
+        Ok(Err(e)) => {
+            let message = format!("COPY failed: {:#}", e);
+            if e.downcast_ref::<BadCopy>().is_some() {
+                json_error(StatusCode::BAD_REQUEST, message)
+            } else if let Some(violation) = e.downcast_ref::<ForeignKeyViolation>() {
+                Failure::violation(message, violation.clone()).into_response()
+            } else {
+                Failure::error(message).into_response()
+            }
+        }
+        Err(e) => {
+            error!("COPY did not finish: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "COPY failed".to_string())
+        }
+    }
+}
+
+// Streams a table out as CSV, read from a snapshot like any SELECT.
+async fn export_table(
+    state: &Arc<AppState>,
+    req: &Request<hyper::body::Incoming>,
+    login: Result<Session, LoginError>,
+    table: String,
+) -> Response<ResponseBody> {
+    let (session, user) = match login {
+        Ok(login) => (login.token, login.user),
+        Err(e) => {
+            error!("Unauthorized export: {}", e);
+            return unauthorized(e);
+        }
+    };
+    let options = match CsvOptions::from_query(req.uri().query()) {
+        Ok(options) => options,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let settings = state.settings(&session);
+    let permit = match state.admission.admit(settings.query_timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return refused(e),
+    };
+    let storage = state.storage.clone().read_owned().await;
+    if !is_admin(state, &user)
+        && let Err(denied) = privileges::check_table(
+            &storage.catalog,
+            &user,
+            &table,
+            Privilege::Select,
+            "an export",
+        )
+    {
+        return permission_denied(&denied);
+    }
+    let columns: Vec<String> = match storage.catalog.get_table(&table) {
+        Ok(info) => info.columns.iter().map(|c| c.name.clone()).collect(),
+        Err(_) => {
+            return json_error(
+                StatusCode::NOT_FOUND,
+                format!("Table '{}' does not exist", table),
+            );
+        }
+    };
+
+    let (chunks_tx, chunks) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    let snapshot = state.txns.snapshot(None);
+    tokio::task::spawn_blocking(move || {
+        let view = ReadView {
+            storage: &storage,
+            tx_id: None,
+            snapshot: Some(snapshot),
+            cancel: None,
+            isolation: IsolationLevel::default(),
+            scan_errors: ScanErrorPolicy::default(),
+            skipped_pages: Arc::default(),
+        };
+        let mut exec = Executor::new(Box::new(SeqScanOp::new(view, table.clone(), None)));
+        let exported = csv_io::export(&mut exec, &columns, options, ROWS_PER_CHUNK, |chunk| {
+            chunks_tx
+                .blocking_send(chunk)
+                .map_err(|_| anyhow::anyhow!("Client went away"))
+        });
+        // The status line is long gone by now, so a failure can only cut the
+        // body short.
+        match exported {
+            Ok(rows) => debug!("Exported {} rows of {}", rows, table),
+            Err(e) => error!("Export of {} failed: {:#}", table, e),
+        }
+        drop(permit);
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "text/csv")
+        .body(ResponseBody::Channel(chunks))
+        .unwrap()
+}
+
+// Transaction control would end the batch's transaction early, user
+// management and settings are not transactional, and REINDEX, ANALYZE and
+// VACUUM rewrite pages a rolled back catalog would still point at. Cursors belong to a
+// session's transaction block.
+fn runs_in_batch(stmt: &Statement) -> bool {
+    !matches!(
+        stmt,
+        Statement::Begin
+            | Statement::Commit
+            | Statement::Rollback
+            | Statement::DeclareCursor { .. }
+            | Statement::Fetch { .. }
+            | Statement::CloseCursor { .. }
+            | Statement::CreateUser { .. }
+            | Statement::DropUser { .. }
+            | Statement::Reindex { .. }
+            | Statement::Analyze { .. }
+            | Statement::Vacuum { .. }
+            | Statement::Set { .. }
+            | Statement::SetVariable { .. }
+            | Statement::ShowSettings { .. }
+    )
+}
+
+type BatchFailure = (BatchResponse, anyhow::Error);
+
+fn execute_batch(
+    state: &AppState,
+    mut storage: OwnedRwLockWriteGuard<Storage>,
+    tx_id: u64,
+    stmts: Vec<Statement>,
+    owner: Option<String>,
+    format: ResultFormat,
+    cancel: Arc<AtomicBool>,
+) -> Result<Vec<BatchResult>, BatchFailure> {
+    let catalog = storage.catalog.clone();
+    resume(&mut storage, tx_id, None);
+    storage.cancel = Some(cancel);
+    let mut results = Vec::with_capacity(stmts.len());
+    let mut failure = None;
+    let written: Vec<String> = stmts
+        .iter()
+        .filter_map(written_table)
+        .map(str::to_string)
+        .collect();
+    for (i, stmt) in stmts.into_iter().enumerate() {
+        state.metrics.record_query(metrics::statement_kind(&stmt));
+        match collect_rows(&mut storage, stmt, owner.as_deref(), format) {
+            Ok((columns, rows, affected)) => results.push(BatchResult {
+                columns,
+                row_count: rows.len(),
+                rows,
+                affected,
+            }),
+            Err(e) => {
+                error!("Batch statement {} failed: {:#}", i, e);
+                failure = Some((Some(i), e));
+                break;
+            }
+        }
+    }
+    storage.cancel = None;
+    let failure = match failure {
+        Some(failure) => failure,
+        None => match storage.check_deferred().and_then(|()| {
+            state.logmgr.log_commit(tx_id).context("WAL commit error")
+        }) {
+            Ok(_) => {
+                state.txns.commit(tx_id);
+                for table in &written {
+                    state.result_cache.invalidate(table);
+                }
+                maybe_checkpoint(state, &mut storage);
+                state.locks.unlock_all(tx_id);
+                debug!("Batch transaction {} committed", tx_id);
+                return Ok(results);
+            }
+            Err(e) => (None, e),
+        },
+    };
+    // The catalog goes back first, so the rows the batch added to tables that
+    // existed before are dropped from their indexes by the rollback.
+    storage.catalog = catalog;
+    abort(state, &mut storage, tx_id);
+    let (failed_index, e) = failure;
+    let message = match e.downcast_ref::<Cancelled>() {
+        Some(_) => format!("Batch timed out: {:#}", e),
+        None => format!("{:#}", e),
+    };
+    Err((
+        BatchResponse::rolled_back(results, failed_index, message),
+        e,
+    ))
+}
+
+fn collect_rows(
+    storage: &mut Storage,
+    stmt: Statement,
+    owner: Option<&str>,
+    format: ResultFormat,
+) -> anyhow::Result<(Vec<String>, Vec<serde_json::Value>, Option<usize>)> {
+    if let Some(result) = run_ddl(storage, &stmt, owner) {
+        return result.map(|()| (Vec::new(), Vec::new(), None));
+    }
+    let mut bind_catalog = BinderCatalog::from_storage(&storage.catalog)
+        .with_random(storage.random.clone())
+        .with_variables(storage.variables.clone())
+        .with_work_mem(storage.work_mem);
+    let mut exec =
+        create_executor_from_statement(stmt, storage, &mut bind_catalog).context("Build error")?;
+    let rows = exec.execute().context("Exec error")?;
+    let (columns, affected) = (exec.columns().to_vec(), exec.affected());
+    let rows = rows
+        .into_iter()
+        .map(|tuple| match format {
+            ResultFormat::Json => tuple.into_iter().map(json_value).collect(),
+            ResultFormat::Text => tuple
+                .into_iter()
+                .map(|v| serde_json::Value::String(text_value(v)))
+                .collect(),
+        })
+        .collect();
+    Ok((columns, rows, affected))
+}
+
+impl BatchResponse {
+    fn rolled_back(results: Vec<BatchResult>, failed_index: Option<usize>, error: String) -> Self {
+        BatchResponse {
+            status: "rolled_back",
+            results,
+            failed_index,
+            error: Some(error),
+        }
+    }
+
+    fn into_response(self, status: StatusCode) -> Response<ResponseBody> {
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&self).unwrap().into())
+            .unwrap()
+    }
+}
+
+// Answers a cursor statement that needs no storage: CLOSE, and whatever
+// names a cursor the session's transaction does not have, or has already.
+// Cursors only live in a transaction block.
+fn settle_cursor(
+    stmt: &Statement,
+    open: Option<&mut OpenTransaction>,
+) -> Option<Response<ResponseBody>> {
+    let refuse = |message: String| {
+        Some(
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(message.into())
+                .unwrap(),
+        )
+    };
+    let has = |name: &String| open.as_ref().is_some_and(|open| open.cursors.contains_key(name));
+    match stmt {
+        Statement::DeclareCursor { .. } if open.is_none() => {
+            refuse("DECLARE CURSOR can only run inside a transaction block".to_string())
+        }
+        Statement::DeclareCursor { name, .. } if has(name) => {
+            refuse(format!("Cursor '{}' already exists", name))
+        }
+        // Each FETCH runs the SELECT again, which has to come out the same.
+        Statement::DeclareCursor { select, .. } if calls_volatile(select) => refuse(format!(
+            "A cursor's SELECT cannot call {}",
+            VOLATILE_FUNCTIONS.join(", ")
+        )),
+        Statement::Fetch { cursor: name, .. } | Statement::CloseCursor { name } if !has(name) => {
+            refuse(format!("Cursor '{}' does not exist", name))
+        }
+        Statement::CloseCursor { name } => {
+            open?.cursors.remove(name);
+            Some(empty_rows())
+        }
+        _ => None,
+    }
+}
+
+fn begin_transaction(state: &AppState, session: &str) -> Response<ResponseBody> {
+    let tx_id = state.txns.begin();
+    let begun = state.logmgr.log_begin(tx_id).and_then(|_| {
+        let snapshot = state.txns.snapshot(Some(tx_id));
+        state.sessions.begin(session, tx_id, snapshot)
+    });
+    match begun {
+        Ok(()) => {
+            info!("Transaction {} begun for session", tx_id);
+            empty_rows()
+        }
+        Err(e) => {
+            error!("BEGIN failed: {:#}", e);
+            let _ = state.logmgr.log_abort(tx_id);
+            state.txns.abort(tx_id);
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(format!("BEGIN failed: {:#}", e).into())
+                .unwrap()
+        }
+    }
+}
+
+async fn end_transaction(state: &AppState, session: &str, commit: bool) -> Response<ResponseBody> {
+    let Some(mut open) = state.sessions.end(session) else {
+        // The aborted transaction was rolled back when its statement failed.
+        if !commit && let Some(tx_id) = state.sessions.clear_failed(session) {
+            info!("Aborted transaction {} ended for session", tx_id);
+            return empty_rows();
+        }
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("No transaction in progress".into())
+            .unwrap();
+    };
+    let tx_id = open.tx_id;
+    let mut storage = state.storage.write().await;
+    // Deferred foreign keys are checked before the commit is logged; any
+    // still broken roll the transaction back.
+    if commit && open.deferred.is_some() {
+        resume(&mut storage, tx_id, Some(&mut open));
+        if let Err(e) = storage.check_deferred() {
+            error!("Transaction {} failed its deferred checks: {:#}", tx_id, e);
+            abort(state, &mut storage, tx_id);
+            let message = format!("{:#} (transaction {} rolled back)", e, tx_id);
+            return match e.downcast_ref::<ForeignKeyViolation>() {
+                Some(violation) => Failure::violation(message, violation.clone()),
+                None => Failure::error(message),
+            }
+            .into_response();
+        }
+        open.pending_rows = std::mem::take(&mut storage.pending_rows);
+    }
+    if commit {
+        match state.logmgr.log_commit(tx_id) {
+            Ok(_) => {
+                state.txns.commit(tx_id);
+                for table in &open.written {
+                    state.result_cache.invalidate(table);
+                }
+                maybe_checkpoint(state, &mut storage);
+                state.locks.unlock_all(tx_id);
+                info!("Transaction {} committed", tx_id);
+                return empty_rows();
+            }
+            Err(e) => {
+                error!("WAL commit of transaction {} failed: {:#}", tx_id, e);
+                resume(&mut storage, tx_id, Some(&mut open));
+                abort(state, &mut storage, tx_id);
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(format!("WAL commit error: {:#}", e).into())
+                    .unwrap();
+            }
+        }
+    }
+    resume(&mut storage, tx_id, Some(&mut open));
+    abort(state, &mut storage, tx_id);
+    empty_rows()
+}
+
+// SET and SHOW of settings, which the server keeps rather than storage. Only
+// an admin may SET GLOBAL, which every session that has not SET its own
+// then runs with from its next statement.
+fn set_setting(
+    state: &AppState,
+    session: &str,
+    user: &str,
+    name: &str,
+    value: &str,
+    global: bool,
+) -> Response<ResponseBody> {
+    if global && !is_admin(state, user) {
+        let denied = PermissionDenied {
+            user: user.to_ascii_lowercase(),
+            table: None,
+            privilege: None,
+            statement: "SET GLOBAL",
+        };
+        return permission_denied(&denied);
+    }
+    let parsed = Setting::parse(name).and_then(|setting| {
+        if !global && setting.server_wide() {
+            anyhow::bail!(
+                "{} is the server's, not a session's; change it with SET GLOBAL",
+                setting.name()
+            );
+        }
+        let value = setting.parse_value(value)?;
+        check_result_cache(setting, &value, state.result_cache.budget())?;
+        Ok((setting, value))
+    });
+    let result = parsed.and_then(|(setting, value)| {
+        info!(setting = setting.name(), %value, global, "Setting changed");
+        match global {
+            true => set_global(state, setting, value)?,
+            false => state.sessions.set(session, setting, value),
+        }
+        Ok(setting)
+    });
+    match result {
+        Ok(Setting::SearchPath) => with_search_path(empty_rows(), state, session),
+        Ok(_) => empty_rows(),
+        Err(e) => json_error(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+    }
+}
+
+// A SET GLOBAL only changes the path of sessions that have not SET their
+// own, so the header says what this session's path now is.
+fn with_search_path(
+    mut response: Response<ResponseBody>,
+    state: &AppState,
+    session: &str,
+) -> Response<ResponseBody> {
+    let path = state.settings(session).search_path.join(",");
+    if let Ok(value) = HeaderValue::from_str(&path) {
+        response.headers_mut().insert(SEARCH_PATH_HEADER, value);
+    }
+    response
+}
+
+// The result cache cannot be turned on while it has no memory to use.
+fn check_result_cache(setting: Setting, value: &SettingValue, budget: usize) -> anyhow::Result<()> {
+    if setting == Setting::ResultCache && *value == SettingValue::Bool(true) && budget == 0 {
+        anyhow::bail!("The server has no result cache to turn on");
+    }
+    Ok(())
+}
+
+// Changes what every session without its own SET runs with. The
+// server-wide settings take effect here as well.
+fn set_global(state: &AppState, setting: Setting, value: SettingValue) -> anyhow::Result<()> {
+    match (setting, &value) {
+        (Setting::LogLevel, SettingValue::Text(filter)) => {
+            if let Some(handle) = LOG_FILTER.get() {
+                handle
+                    .reload(EnvFilter::try_new(filter)?)
+                    .context("Changing the log filter failed")?;
+            }
+        }
+        // `parse_value` keeps it within a u32.
+        (Setting::RateLimit, SettingValue::Count(rate)) => {
+            let rate = *rate as u32;
+            state.admission.set_rate_limit((rate > 0).then_some(rate));
+        }
+        (Setting::ResultCacheSize, SettingValue::Count(bytes)) => {
+            state.result_cache.set_budget(*bytes as usize)
+        }
+        _ => {}
+    }
+    state.settings.lock().unwrap().set(setting, value)
+}
+
+#[derive(Debug, Serialize)]
+struct Reloaded {
+    changed: Vec<Changed>,
+    // Keys only read on start whose value in the file is not the one the
+    // server started with.
+    needs_restart: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Changed {
+    setting: &'static str,
+    from: String,
+    to: String,
+}
+
+// Reads the config file again and applies what it sets as SET GLOBAL would.
+// A setting no longer in the file keeps its value, and a file that does not
+// parse changes nothing.
+fn reload(state: &AppState) -> anyhow::Result<Reloaded> {
+    let Some(started_with) = &state.config_file else {
+        anyhow::bail!("The server was started without a config file");
+    };
+    let file = ConfigFile::read(&started_with.path)?;
+    let changed = apply_config(state, &file)?;
+    let needs_restart: Vec<&'static str> = RESTART_ONLY
+        .into_iter()
+        .filter(|key| file.restart_only.get(key) != started_with.restart_only.get(key))
+        .collect();
+    for change in &changed {
+        info!(from = %change.from, to = %change.to, "Reloaded {}", change.setting);
+    }
+    for key in &needs_restart {
+        warn!("{} changed in {:?}, which takes a restart", key, file.path);
+    }
+    info!(
+        changed = changed.len(),
+        needs_restart = needs_restart.len(),
+        "Reloaded {:?}",
+        file.path
+    );
+    Ok(Reloaded {
+        changed,
+        needs_restart,
+    })
+}
+
+// Sets what `file` sets over the server's global settings, once all of it
+// has been checked. Returns the settings whose value it changed.
+fn apply_config(state: &AppState, file: &ConfigFile) -> anyhow::Result<Vec<Changed>> {
+    let budget = file
+        .settings
+        .iter()
+        .find_map(|(setting, value)| match (setting, value) {
+            (Setting::ResultCacheSize, SettingValue::Count(bytes)) => Some(*bytes as usize),
+            _ => None,
+        })
+        .unwrap_or_else(|| state.result_cache.budget());
+    for (setting, value) in &file.settings {
+        check_result_cache(*setting, value, budget).with_context(|| format!("{:?}", file.path))?;
+    }
+    let mut changed = Vec::new();
+    for (setting, value) in &file.settings {
+        let from = state.settings.lock().unwrap().get(*setting);
+        if from == *value {
+            continue;
+        }
+        set_global(state, *setting, value.clone())?;
+        changed.push(Changed {
+            setting: setting.name(),
+            from: from.to_string(),
+            to: value.to_string(),
+        });
+    }
+    Ok(changed)
+}
+
+// Answers like a SELECT would: one row per setting, its name and value, or
+// a single column named after the one setting asked for.
+fn show_settings(state: &AppState, session: &str, name: Option<&str>) -> Response<ResponseBody> {
+    let settings = state.settings(session);
+    let (columns, rows): (Vec<String>, Vec<Vec<String>>) = match name.map(Setting::parse) {
+        None => (
+            vec!["NAME".into(), "SETTING".into()],
+            settings
+                .all()
+                .into_iter()
+                .map(|(name, value)| vec![name.to_string(), value])
+                .collect(),
+        ),
+        Some(Ok(setting)) => (
+            vec![setting.name().to_ascii_uppercase()],
+            vec![vec![settings.get(setting).to_string()]],
+        ),
+        Some(Err(e)) => return json_error(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+    };
+    let body = serde_json::json!({
+        "columns": columns,
+        "row_count": rows.len(),
+        "rows": rows,
+    });
+    json_response(StatusCode::OK, body.to_string())
+}
+
+// CREATE USER and DROP USER change the account file directly instead of
+// running in a transaction, and only an admin may issue them.
+async fn manage_users(state: &AppState, user: &str, stmt: Statement) -> Response<ResponseBody> {
+    if !state.users.get(user).is_some_and(|u| u.admin) {
+        error!("User {} tried to manage users", user);
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body("Permission denied: only an admin can manage users".into())
+            .unwrap();
+    }
+    let users = state.users.clone();
+    let result = match stmt {
+        Statement::CreateUser { name, password } => {
+            tokio::task::spawn_blocking(move || users.create_user(&name, &password))
+                .await
+                .context("CREATE USER did not finish")
+                .and_then(|r| r)
+        }
+        // Whoever is logged in as the dropped user is logged out, and what
+        // they were granted goes with them.
+        Statement::DropUser { name } => match users.drop_user(&name) {
+            Ok(()) => {
+                state.logins.revoke_user(&name);
+                forget_grants(state, &name.to_ascii_lowercase()).await
+            }
+            Err(e) => Err(e),
+        },
+        _ => Ok(()),
+    };
+    match result {
+        Ok(()) => empty_rows(),
+        Err(e) => {
+            error!("User management failed: {:#}", e);
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(format!("{:#}", e).into())
+                .unwrap()
+        }
+    }
+}
+
+// Tells the client of a failed statement inside BEGIN what became of its
+// transaction.
+fn aborted_suffix(tx_id: u64) -> String {
+    format!(
+        " (transaction {} rolled back; statements are refused until ROLLBACK)",
+        tx_id
+    )
+}
+
+// The one way a failed statement's transaction is rolled back: undoes its
+// changes, logs the abort and releases its locks.
+fn abort(state: &AppState, storage: &mut Storage, tx_id: u64) {
+    match recovery_manager::abort_transaction(storage, &state.logmgr, tx_id) {
+        Ok(undone) => info!(
+            "Transaction {} rolled back, {} updates undone",
+            tx_id, undone
+        ),
+        Err(e) => error!("Rollback of transaction {} failed: {:#}", tx_id, e),
+    }
+    state.locks.unlock_all(tx_id);
+}
+
+fn maybe_checkpoint(state: &AppState, storage: &mut Storage) {
+    match recovery_manager::maybe_checkpoint(storage, &state.logmgr) {
+        Ok(None) => {}
+        Ok(Some((lsn, removed))) => info!(
+            "Checkpoint written at lsn {}, {} WAL bytes truncated",
+            lsn, removed
+        ),
+        Err(e) => error!("Checkpoint failed: {:#}", e),
+    }
+}
+
+// Reads `name=<offset>` out of a query string.
+fn offset_param(query: Option<&str>, name: &str) -> Result<Option<u64>, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, v)| {
+            v.parse()
+                .map_err(|_| format!("{} must be a WAL offset, not {:?}", name, v))
+        })
+        .transpose()
+}
+
+// Where the log ends, with the catalog as of there, for a standby to catch
+// up to. Every change to pages or catalog is made under the storage lock, so
+// taking it, and writing out the log, lines the two up exactly.
+async fn replication_point(state: &AppState, query: Option<&str>) -> Response<ResponseBody> {
+    let known_end = match offset_param(query, "known_end") {
+        Ok(end) => end,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    let storage = state.storage.read().await;
+    if let Err(e) = state.logmgr.flush_all() {
+        error!("WAL flush for a standby failed: {:#}", e);
+        return json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("WAL flush failed: {:#}", e),
+        );
+    }
+    let wal_end = state.logmgr.end_offset();
+    let point = ReplicationPoint {
+        wal_base: state.logmgr.base_offset(),
+        wal_end,
+        catalog: (known_end != Some(wal_end)).then(|| storage.catalog.clone()),
+    };
+    drop(storage);
+    json_response(StatusCode::OK, serde_json::to_string(&point).unwrap())
+}
+
+// The log from `from` up to `to`, or to its end, as replication::read_wal
+// frames it. Log truncated since is 410 Gone: the standby asking for it
+// cannot catch up any more.
+async fn ship_wal(state: &AppState, query: Option<&str>) -> Response<ResponseBody> {
+    let (from, to) = match (offset_param(query, "from"), offset_param(query, "to")) {
+        (Ok(Some(from)), Ok(to)) => (from, to.unwrap_or(u64::MAX)),
+        (Ok(None), Ok(_)) => {
+            return json_error(StatusCode::BAD_REQUEST, "from is required".to_string());
+        }
+        (Err(e), _) | (_, Err(e)) => return json_error(StatusCode::BAD_REQUEST, e),
+    };
+    // Records still buffered are not in the file yet.
+    let to = to.min(state.logmgr.end_offset());
+    let path = state.logmgr.path().to_path_buf();
+    let read = tokio::task::spawn_blocking(move || replication::read_wal(&path, from, to)).await;
+    match read {
+        Ok(Ok(wal)) => Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/octet-stream")
+            .body(ResponseBody::Full(Some(Bytes::from(wal))))
+            .unwrap(),
+        Ok(Err(e)) if e.downcast_ref::<WalTruncated>().is_some() => {
+            json_error(StatusCode::GONE, e.to_string())
+        }
+        Ok(Err(e)) => {
+            error!("Reading the WAL for a standby failed: {:#}", e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("WAL read failed: {:#}", e),
+            )
+        }
+        Err(e) => {
+            error!("Reading the WAL for a standby did not finish: {}", e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "WAL read failed".to_string(),
+            )
+        }
+    }
+}
+
+// Stops a standby's replay and lets it take writes. The pages it replayed
+// are only in the primary's log, so a checkpoint puts them on disk before
+// anything else is written.
+async fn promote(state: &AppState, user: &str) -> Response<ResponseBody> {
+    if !state.users.get(user).is_some_and(|u| u.admin) {
+        error!("User {} tried to promote the server", user);
+        return json_error(
+            StatusCode::FORBIDDEN,
+            "Permission denied: only an admin can promote a standby".to_string(),
+        );
+    }
+    let Some(standby) = &state.standby else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "This server is not a standby".to_string(),
+        );
+    };
+    let mut storage = state.storage.write().await;
+    let promoted = standby.promote(&mut storage).and_then(|promoted| {
+        recovery_manager::checkpoint(&mut storage, &state.logmgr)?;
+        Ok(promoted)
+    });
+    match promoted {
+        Ok(true) => {
+            info!("Promoted from standby, now taking writes");
+            Response::builder()
+                .status(StatusCode::OK)
+                .body("Promoted".into())
+                .unwrap()
+        }
+        Ok(false) => json_error(
+            StatusCode::BAD_REQUEST,
+            "This server has already been promoted".to_string(),
+        ),
+        Err(e) => {
+            error!("Promotion failed: {:#}", e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Promotion failed: {:#}", e),
+            )
+        }
+    }
+}
+
+// Copies the database into `dir` as of a checkpoint. Writers wait from the
+// checkpoint until the copy is done, so no page changes under it; readers
+// only wait for the checkpoint.
+async fn take_backup(state: &AppState, user: &str, dir: &Path) -> Response<ResponseBody> {
+    if !state.users.get(user).is_some_and(|u| u.admin) {
+        error!("User {} tried to take a backup", user);
+        return json_error(
+            StatusCode::FORBIDDEN,
+            "Permission denied: only an admin can take a backup".to_string(),
+        );
+    }
+    if state.standby.as_ref().is_some_and(|s| !s.is_promoted()) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "A standby cannot be backed up; back up its primary".to_string(),
+        );
+    }
+    // A backup starts with a checkpoint, which is a write.
+    if state.read_only {
+        return write_refused(READ_ONLY_REFUSAL);
+    }
+    let mut storage = state.storage.write().await;
+    let prepared = backup::prepare(&mut storage, &state.logmgr);
+    let storage = storage.downgrade();
+    let taken = prepared.and_then(|(lsn, catalog)| {
+        backup::copy(&storage, &state.logmgr, lsn, &catalog, dir)
+    });
+    drop(storage);
+    match taken {
+        Ok(report) => {
+            info!(
+                lsn = report.lsn,
+                files = report.files,
+                bytes = report.bytes,
+                "Backed up to {:?}",
+                dir
+            );
+            json_response(StatusCode::OK, serde_json::to_string(&report).unwrap())
+        }
+        Err(e) => {
+            error!("Backup to {:?} failed: {:#}", dir, e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Backup failed: {:#}", e),
+            )
+        }
+    }
+}
+
+fn is_admin(state: &AppState, user: &str) -> bool {
+    state.users.get(user).is_some_and(|u| u.admin)
+}
+
+// Checks `stmts` against what `user` has been granted, before any of them
+// takes a lock. Admins may run anything. Returns the first one refused.
+async fn authorize(
+    state: &AppState,
+    user: &str,
+    stmts: &[Statement],
+) -> Result<(), (usize, PermissionDenied)> {
+    if is_admin(state, user) {
+        return Ok(());
+    }
+    let storage = state.storage.read().await;
+    for (i, stmt) in stmts.iter().enumerate() {
+        privileges::check(&storage.catalog, user, stmt).map_err(|denied| (i, denied))?;
+    }
+    Ok(())
+}
+
+fn permission_denied(denied: &PermissionDenied) -> Response<ResponseBody> {
+    error!("{}", denied);
+    let body = serde_json::json!({
+        "error": denied.to_string(),
+        "code": PERMISSION_DENIED,
+        "table": denied.table,
+        "privilege": denied.privilege.map(|p| p.to_string()),
+    });
+    json_response(StatusCode::FORBIDDEN, body.to_string())
+}
+
+// Takes back everything granted to a dropped user, so that an account made
+// later under the same name starts with nothing. A standby has the
+// primary's catalog and leaves it alone.
+async fn forget_grants(state: &AppState, user: &str) -> anyhow::Result<()> {
+    if state.is_standby() {
+        return Ok(());
+    }
+    let tx_id = state.txns.begin();
+    let mut storage = state.storage.write().await;
+    resume(&mut storage, tx_id, None);
+    let forgotten = state
+        .logmgr
+        .log_begin(tx_id)
+        .and_then(|_| storage.forget_user(user))
+        .and_then(|tables| {
+            state.logmgr.log_commit(tx_id)?;
+            Ok(tables)
+        });
+    match forgotten {
+        Ok(tables) => {
+            state.txns.commit(tx_id);
+            for table in &tables {
+                state.result_cache.invalidate(table);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            abort(state, &mut storage, tx_id);
+            Err(e.context(format!("Revoking what {} was granted failed", user)))
+        }
+    }
+}
+
+// Names every table in `stmt` by its catalog key, going by the session's
+// search path and temporary tables.
+async fn resolve(
+    state: &AppState,
+    session: &str,
+    settings: &Settings,
+    stmt: Statement,
+) -> anyhow::Result<Statement> {
+    let storage = state.storage.read().await;
+    let is_temp = |table: &str| state.sessions.has_temp_table(session, table);
+    resolve_names(&storage.catalog, &settings.search_path, is_temp, stmt)
+}
+
+// Reads a CREATE INDEX's keys before it takes its table to itself, a chunk
+// of rows at a time with storage shared, so writers get in between chunks.
+// The table is only intention locked meanwhile; the statement then locks
+// it as any DDL does and reads again just the rows changed since. Should
+// this fail, the statement reads every row itself, and fails there if it
+// has to.
+async fn backfill_index(
+    state: &AppState,
+    tx_id: u64,
+    table: &str,
+    columns: &[String],
+    index_name: &str,
+    lock_timeout: Duration,
+    cancel: &AtomicBool,
+) {
+    let res = Resource::Table(table.to_string());
+    if let Err(e) = state
+        .locks
+        .lock_with_timeout(tx_id, res, LockMode::IntentionShared, Some(lock_timeout))
+        .await
+    {
+        debug!("No backfill of index '{}': {:#}", index_name, e);
+        return;
+    }
+    let backfilled = async {
+        let rids = state
+            .storage
+            .write()
+            .await
+            .begin_index_build(tx_id, table, columns, index_name)?;
+        let mut keys = Vec::with_capacity(rids.len());
+        for chunk in rids.chunks(BACKFILL_CHUNK_ROWS) {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(Cancelled.into());
+            }
+            let storage = state.storage.clone().read_owned().await;
+            let (table, columns, chunk) = (table.to_string(), columns.to_vec(), chunk.to_vec());
+            let read = tokio::task::spawn_blocking(move || {
+                storage.read_index_keys(&table, &columns, &chunk)
+            });
+            keys.extend(read.await??);
+        }
+        state
+            .storage
+            .write()
+            .await
+            .add_index_keys(tx_id, table, index_name, keys)
+    };
+    if let Err(e) = backfilled.await {
+        debug!("Backfill of index '{}' stopped: {:#}", index_name, e);
+        state.storage.write().await.drop_index_builds(tx_id);
+    }
+}
+
+// Writers only take an intention lock on the table; the executor then locks
+// each row they touch. DDL and COPY still need the whole table to
+// themselves.
+fn lock_target(stmt: &Statement) -> Option<(Resource, LockMode)> {
+    match stmt {
+        Statement::Insert { table, .. } | Statement::Delete { table, .. } => {
+            Some((Resource::Table(table.clone()), LockMode::IntentionExclusive))
+        }
+        // No one else can see a temporary table.
+        Statement::CreateTable {
+            temporary: true, ..
+        }
+        | Statement::CreateTableAs {
+            temporary: true, ..
+        } => None,
+        // What it reads is read under its snapshot, like any SELECT.
+        Statement::CreateTable { name: table, .. }
+        | Statement::CreateTableAs { name: table, .. }
+        | Statement::CreateIndex { table, .. }
+        | Statement::Reindex { table, .. }
+        | Statement::Analyze { table }
+        | Statement::DropTable { table }
+        | Statement::AddCheck { table, .. }
+        | Statement::RenameTable { table, .. }
+        | Statement::Copy { table, .. }
+        | Statement::Grant { table, .. }
+        | Statement::Revoke { table, .. } => {
+            Some((Resource::Table(table.clone()), LockMode::Exclusive))
+        }
+        Statement::Explain { stmt, .. } => {
+            lock_target(stmt).map(|(res, _)| (res, LockMode::IntentionShared))
+        }
+        // Reads see a snapshot and take no locks. For SHOW LOCKS a lock would
+        // only show up in its own output.
+        Statement::Select { .. }
+        | Statement::UnionAll { .. }
+        | Statement::DeclareCursor { .. }
+        | Statement::Fetch { .. }
+        | Statement::CloseCursor { .. }
+        | Statement::ShowLocks
+        | Statement::ShowTables { .. }
+        | Statement::ShowGrants => None,
+        // CHECK runs as a writer, so it has storage to itself already, as
+        // do CHECKPOINT, FLUSH TABLES and VACUUM.
+        Statement::Check
+        | Statement::Checkpoint
+        | Statement::FlushTables { .. }
+        | Statement::Vacuum { .. } => None,
+        Statement::Begin | Statement::Commit | Statement::Rollback => None,
+        // The parent rows a deferred check finds are locked one by one.
+        Statement::SetConstraints { .. } => None,
+        Statement::CreateUser { .. } | Statement::DropUser { .. } => None,
+        Statement::Set { .. } | Statement::SetVariable { .. } | Statement::ShowSettings { .. } => {
+            None
+        }
+        // A sequence's counter has a lock of its own. A view has no rows to
+        // lock, and what it reads is locked by the statements reading it.
+        Statement::CreateSequence { .. } | Statement::DropSequence { .. } => None,
+        Statement::CreateView { .. } | Statement::DropView { .. } => None,
+        // Which table the index is on is only known from the catalog, and
+        // renaming it touches no rows; the storage lock keeps everyone else
+        // out of the catalog meanwhile.
+        Statement::RenameIndex { .. } => None,
+        // A schema has no rows either. The tables DROP SCHEMA CASCADE drops
+        // are only known from the catalog, like an index's.
+        Statement::CreateSchema { .. } | Statement::DropSchema { .. } => None,
+    }
+}
+
+// Reads a whole request body of at most `limit` bytes. A body that claims to
+// be larger is refused before any of it is read, one without a length once
+// it passes the limit. The error is the response to send instead. Endpoints
+// expecting bigger bodies pass their own limit.
+async fn collect_body(
+    req: Request<hyper::body::Incoming>,
+    limit: usize,
+) -> Result<Bytes, Response<ResponseBody>> {
+    use http_body_util::{BodyExt, Limited};
+    let declared = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit as u64) {
+        return Err(body_too_large(limit));
+    }
+    match Limited::new(req.into_body(), limit).collect().await {
+        Ok(collected) => Ok(collected.to_bytes()),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => Err(body_too_large(limit)),
+        Err(e) => {
+            error!("Failed to read request body: {:#}", e);
+            Err(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(format!("Body read error: {:#}", e).into())
+                .unwrap())
+        }
+    }
+}
+
+// 503 while every slot is taken, 429 for a session over its rate.
+fn refused(refused: Refused) -> Response<ResponseBody> {
+    let status = match refused {
+        Refused::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        Refused::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+    };
+    Response::builder()
+        .status(status)
+        .header("retry-after", refused.retry_after_secs())
+        .body(refused.to_string().into())
+        .unwrap()
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<ResponseBody> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+// The body every JSON endpoint fails with: `{"error": "..."}`.
+// Answers a statement with syntax errors, listing every one of them.
+fn parse_failed(diagnostics: &Diagnostics) -> Response<ResponseBody> {
+    let body = serde_json::json!({
+        "error": format!("Parse error: {}", diagnostics),
+        "diagnostics": diagnostics.0,
+    });
+    json_response(StatusCode::BAD_REQUEST, body.to_string())
+}
+
+fn json_error(status: StatusCode, message: String) -> Response<ResponseBody> {
+    json_response(status, serde_json::json!({ "error": message }).to_string())
+}
+
+fn body_too_large(limit: usize) -> Response<ResponseBody> {
+    error!("Refused a request body over {} bytes", limit);
+    let body = serde_json::json!({
+        "error": format!("Request body is larger than the {} byte limit", limit),
+        "limit_bytes": limit,
+    });
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header("content-type", "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+pub async fn run_server(
+    addr: SocketAddr,
+    storage: Storage,
+    wal_path: PathBuf,
+    config: ServerConfig,
+) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(config.log_filter.as_deref().unwrap_or(DEFAULT_LOG_FILTER))
+        .context("Invalid log filter")?;
+    // Whoever embeds the server, tests included, may have installed a
+    // subscriber already; theirs is kept.
+    let (filter, handle) = reload::Layer::new(filter);
+    if tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .try_init()
+        .is_err()
+    {
+        debug!("A tracing subscriber is already installed");
+    } else {
+        let _ = LOG_FILTER.set(handle);
+    }
+    info!("Server starting");
+    let listener = TcpListener::bind(addr).await.context("Bind failed")?;
+    serve_until(listener, storage, wal_path, config, shutdown_signal()).await
+}
+
+// Resolves on Ctrl-C, or on SIGTERM where there is such a thing.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Listening for Ctrl-C failed: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Listening for SIGTERM failed: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => info!("Ctrl-C received, shutting down"),
+        _ = terminate => info!("SIGTERM received, shutting down"),
+    }
+}
+
+// Reloads the config file on every SIGHUP until the server stops.
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<AppState>, mut stop: watch::Receiver<bool>) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Listening for SIGHUP failed: {}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("SIGHUP received, reloading the config file");
+                if let Err(e) = reload(&state) {
+                    error!("Reloading the config file failed: {:#}", e);
+                }
+            }
+            _ = stop.changed() => return,
+        }
+    }
+}
+
+// Every `interval`, analyzes the one table whose rows have changed the most
+// since its last ANALYZE, if that is the auto_analyze percent of them or
+// more. A table a transaction has locked exclusively waits for a later
+// round, as does everything on a server that takes no writes.
+async fn auto_analyze(state: Arc<AppState>, interval: Duration, mut stop: watch::Receiver<bool>) {
+    let start = tokio::time::Instant::now() + interval;
+    let mut ticker = tokio::time::interval_at(start, interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop.changed() => return,
+        }
+        let percent = state.settings.lock().unwrap().auto_analyze;
+        if percent == 0 || state.write_refusal().is_some() {
+            continue;
+        }
+        if stale_table(&state.storage.read().await.catalog, percent, &state.locks).is_none() {
+            continue;
+        }
+        // Looked for again: the catalog may have changed in between.
+        let mut storage = state.storage.write().await;
+        let Some(table) = stale_table(&storage.catalog, percent, &state.locks) else {
+            continue;
+        };
+        let started = Instant::now();
+        match storage.analyze(&table) {
+            Ok(_) => info!(
+                "Analyzed {} after its rows changed, in {} ms",
+                table,
+                started.elapsed().as_millis()
+            ),
+            Err(e) => error!("Analyzing {} failed: {:#}", table, e),
+        }
+    }
+}
+
+// The table whose churn is the largest share of its rows, if at least
+// `percent` of them.
+fn stale_table(catalog: &Catalog, percent: u32, locks: &LockManager) -> Option<String> {
+    let locked = locks.held_exclusively();
+    catalog
+        .tables
+        .values()
+        .filter(|t| !catalog.is_temp(&t.name))
+        .filter(|t| t.churn.total() >= AUTO_ANALYZE_MIN_ROWS)
+        .filter(|t| t.churn.total() * 100 >= t.stats.rows.max(1) * percent as u64)
+        .filter(|t| !locked.contains(&Resource::Table(t.name.clone())))
+        .max_by(|a, b| {
+            let share = |t: &TableInfo| t.churn.total() as f64 / t.stats.rows.max(1) as f64;
+            share(a).total_cmp(&share(b))
+        })
+        .map(|t| t.name.clone())
+}
+
+// Recovers `storage` from the WAL at `wal_path`, then answers requests on
+// `listener` until accepting fails.
+pub async fn serve(
+    listener: TcpListener,
+    storage: Storage,
+    wal_path: PathBuf,
+    config: ServerConfig,
+) -> anyhow::Result<()> {
+    serve_until(listener, storage, wal_path, config, std::future::pending()).await
+}
+
+// Like `serve`, but once `shutdown` resolves stops accepting, gives open
+// connections SHUTDOWN_GRACE_PERIOD to finish, and shuts down cleanly: open
+// transactions are rolled back and a checkpoint is taken after every dirty
+// page is written, so the next start has nothing to recover.
+pub async fn serve_until(
+    listener: TcpListener,
+    storage: Storage,
+    wal_path: PathBuf,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    if config.read_only && config.standby_of.is_some() {
+        anyhow::bail!("A standby is read-only already and cannot also be started read-only");
+    }
+    let logmgr = Arc::new(
+        LogManager::new(wal_path.clone())?
+            .with_flush_policy(config.flush_policy)
+            .with_history_window(config.history_window_bytes),
+    );
+    logmgr.spawn_flusher();
+    let mut storage = storage;
+    storage.attach_wal(logmgr.clone());
+    if backup::load_catalog(&mut storage, &wal_path)? {
+        info!("Loaded the catalog saved at the last checkpoint");
+    }
+    let txns = storage.txns.clone();
+    txns.advance_past(logmgr.max_tx_id());
+    let standby = config.standby_of.as_ref().map(|_| {
+        txns.advance_past(STANDBY_TX_IDS);
+        Arc::new(Standby::new())
+    });
+    let pool_stats = storage.buffer_pool.stats.clone();
+    let storage = Arc::new(RwLock::new(storage));
+    RecoveryManager::new(wal_path.clone(), storage.clone())
+        .recover()
+        .await
+        .context("Recovery failed")?;
+    info!("Recovery complete");
+    if config.read_only {
+        // What recovery changed goes to disk now; nothing logs it again.
+        storage
+            .write()
+            .await
+            .flush()
+            .context("Flushing recovered pages failed")?;
+        logmgr.set_read_only();
+        info!("Read-only: only reads will run, and nothing will be logged");
+    }
+
+    let users = Arc::new(UserStore::open(UserStore::path(&wal_path))?);
+    if let Some(password) = users.bootstrap(config.admin_password)? {
+        // Printed rather than logged, and only this once.
+        eprintln!(
+            "Created user '{}' with password {}",
+            BOOTSTRAP_ADMIN, password.0
+        );
+    }
+
+    let locks = Arc::new(LockManager::new().with_timeout(LOCK_TIMEOUT));
+    locks.spawn_deadlock_detector(DEADLOCK_CHECK_INTERVAL);
+    storage.write().await.attach_locks(locks.clone());
+    // A login lasts no longer than the session TTL, so temporary tables
+    // unused for that long belong to a session that has ended.
+    let session_ttl = config.session_ttl.unwrap_or(DEFAULT_SESSION_TTL);
+    let sessions = Arc::new(
+        SessionManager::new()
+            .with_idle_timeout(IDLE_TRANSACTION_TIMEOUT)
+            .with_temp_table_timeout(session_ttl),
+    );
+    sessions.spawn_sweeper(
+        storage.clone(),
+        logmgr.clone(),
+        locks.clone(),
+        SESSION_SWEEP_INTERVAL,
+    );
+    let audit = match &config.audit_log {
+        Some(path) => AuditLog::open(path)
+            .with_context(|| format!("Opening the audit log {:?} failed", path))?,
+        None => AuditLog::disabled(),
+    };
+    let (stop_tx, stop_rx) = watch::channel(false);
     let state = Arc::new(AppState {
-        storage: Arc::new(RwLock::new(storage)),
+        storage,
         logmgr,
         locks,
-        wal_path,
+        txns,
+        sessions,
+        users,
+        logins: Arc::new(Logins::new(session_ttl)),
+        metrics: Arc::new(Metrics::new()),
+        pool_stats,
+        metrics_require_login: config.metrics_require_login,
+        settings: Arc::new(Mutex::new(Settings {
+            query_timeout: config.query_timeout.unwrap_or(DEFAULT_QUERY_TIMEOUT),
+            lock_timeout: LOCK_TIMEOUT,
+            isolation: IsolationLevel::default(),
+            result_cache: config.result_cache_bytes.unwrap_or(0) > 0,
+            search_path: vec![DEFAULT_SCHEMA.to_string()],
+            log_level: config
+                .log_filter
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LOG_FILTER.to_string()),
+            rate_limit: config.rate_limit.unwrap_or(0),
+            result_cache_size: config.result_cache_bytes.unwrap_or(0),
+            work_mem: config.work_mem.unwrap_or(DEFAULT_WORK_MEM),
+            lock_retries: LOCK_RETRIES,
+            lock_retry_backoff: LOCK_RETRY_BACKOFF,
+            scan_error_policy: ScanErrorPolicy::default(),
+            auto_analyze: config.auto_analyze.unwrap_or(DEFAULT_AUTO_ANALYZE),
+        })),
+        max_body_bytes: config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES),
+        admission: Arc::new(Admission::new(
+            config
+                .max_running_queries
+                .unwrap_or(DEFAULT_MAX_RUNNING_QUERIES),
+            config.when_busy,
+            config.rate_limit,
+        )),
+        result_cache: Arc::new(ResultCache::new(config.result_cache_bytes.unwrap_or(0))),
+        queries: QueryRegistry::new(FINISHED_QUERIES_KEPT),
+        audit: Arc::new(audit),
+        standby,
+        read_only: config.read_only,
+        config_file: config.config_file,
+        stop: stop_rx.clone(),
     });
+    if let Some(file) = &state.config_file {
+        apply_config(&state, file).context("Applying the config file failed")?;
+        info!("Settings read from {:?}", file.path);
+        #[cfg(unix)]
+        tokio::spawn(reload_on_hangup(state.clone(), stop_rx.clone()));
+    }
+    tokio::spawn(auto_analyze(
+        state.clone(),
+        config
+            .auto_analyze_interval
+            .unwrap_or(DEFAULT_AUTO_ANALYZE_INTERVAL),
+        stop_rx.clone(),
+    ));
+    if let (Some(standby), Some(primary)) = (&state.standby, config.standby_of) {
+        tokio::spawn(standby.clone().run(
+            primary,
+            state.storage.clone(),
+            state.logmgr.clone(),
+            state.result_cache.clone(),
+            stop_rx.clone(),
+        ));
+    }
 
-    let listener = TcpListener::bind(addr).await.context("Bind failed")?;
-    info!("Listening on {}", addr);
+    info!("Listening on {}", listener.local_addr()?);
 
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
     loop {
-        let (stream, _) = listener.accept().await.context("Accept failed")?;
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted.context("Accept failed")?,
+            _ = &mut shutdown => break,
+            // Reaps finished connections so the set does not grow forever.
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
         let io = TokioIo::new(stream);
         let state = state.clone();
+        let mut stop = stop_rx.clone();
 
-        tokio::spawn(async move {
-            
+        connections.spawn(async move {
             let service = service_fn(move |req| handle_request(req, state.clone()));
-            if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
+            let conn = http1::Builder::new()
+                .serve_connection(io, service)
+                .with_upgrades();
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = stop.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
                 error!("Connection error: {:?}", e);
             }
         });
     }
+
+    drop(listener);
+    let _ = stop_tx.send(true);
+    info!("Waiting for {} connections to finish", connections.len());
+    let drained = tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        error!(
+            "{} connections still open after the grace period, closing them",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+    shut_down(&state).await
+}
+
+async fn shut_down(state: &AppState) -> anyhow::Result<()> {
+    let rolled_back = state
+        .sessions
+        .abort_all(&state.storage, &state.logmgr, &state.locks)
+        .await;
+    if !rolled_back.is_empty() {
+        info!("Rolled back open transactions {:?}", rolled_back);
+    }
+    let mut storage = state.storage.write().await;
+    // Nothing was logged to check a point in, so the next start recovers
+    // from wherever the last writable run left the log.
+    if state.read_only {
+        storage.flush().context("Shutdown flush failed")?;
+        info!("Shut down read-only, without a checkpoint");
+        return Ok(());
+    }
+    let lsn = recovery_manager::checkpoint(&mut storage, &state.logmgr)
+        .context("Shutdown checkpoint failed")?;
+    info!("Shut down cleanly at checkpoint lsn {}, catalog saved", lsn);
+    Ok(())
 }
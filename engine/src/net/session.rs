@@ -0,0 +1,571 @@
+use crate::net::settings::{Overrides, Setting, SettingValue};
+use crate::query::parser::Statement;
+use crate::query::random::Random;
+use crate::query::value::Value;
+use crate::storage::record::RID;
+use crate::storage::storage::{DeferredCheck, Storage, TableInfo};
+use crate::tx::lock_manager::LockManager;
+use crate::tx::log_manager::{LogManager, TxId};
+use crate::tx::mvcc::Snapshot;
+use crate::tx::recovery_manager;
+use anyhow::{Result, bail};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::RwLock, time::Instant};
+use tracing::{error, info};
+
+// A transaction opened with BEGIN that outlives the statement that started
+// it. Between statements it is parked here together with the state
+// `Storage` needs to resume or roll it back.
+#[derive(Debug)]
+pub struct OpenTransaction {
+    pub tx_id: TxId,
+    pub started: Instant,
+    pub last_active: Instant,
+    pub pending_rows: Vec<(String, RID)>,
+    // Its foreign key checks left for COMMIT, once it has SET CONSTRAINTS
+    // ALL DEFERRED.
+    pub deferred: Option<Vec<DeferredCheck>>,
+    pub snapshot: Snapshot,
+    // Tables its statements changed, for the commit to invalidate.
+    pub written: BTreeSet<String>,
+    // Its cursors by name, which go when it ends.
+    pub cursors: HashMap<String, Cursor>,
+}
+
+// A cursor DECLAREd in a transaction. Each FETCH runs its SELECT again under
+// the transaction's snapshot and passes over the rows fetched before, so
+// nothing is held between FETCHes but the snapshot and the transaction's
+// locks, which last as long as the cursor can.
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    pub select: Statement,
+    pub fetched: usize,
+    // Set once a FETCH came back short; every FETCH after it is empty.
+    pub done: bool,
+}
+
+impl Cursor {
+    pub fn new(select: Statement) -> Self {
+        Cursor {
+            select,
+            fetched: 0,
+            done: false,
+        }
+    }
+
+    // The rows to pass over and the most to send for a FETCH of `count`.
+    pub fn window(&self, count: u64) -> (usize, usize) {
+        match self.done {
+            true => (self.fetched, 0),
+            false => (self.fetched, usize::try_from(count).unwrap_or(usize::MAX)),
+        }
+    }
+
+    // Moves past the `sent` rows of a FETCH of `count`.
+    pub fn advance(&mut self, sent: usize, count: u64) {
+        self.fetched += sent;
+        self.done |= (sent as u64) < count;
+    }
+}
+
+// Where a session stands with BEGIN ... COMMIT. A transaction a failed
+// statement rolled back leaves the session aborted, refusing everything but
+// ROLLBACK, so that nothing meant for it runs in a transaction of its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TxState {
+    #[default]
+    Idle,
+    Open(TxId),
+    Aborted(TxId),
+}
+
+impl TxState {
+    pub fn name(self) -> &'static str {
+        match self {
+            TxState::Idle => "idle",
+            TxState::Open(_) => "open",
+            TxState::Aborted(_) => "aborted",
+        }
+    }
+
+    pub fn tx_id(self) -> Option<TxId> {
+        match self {
+            TxState::Idle => None,
+            TxState::Open(tx_id) | TxState::Aborted(tx_id) => Some(tx_id),
+        }
+    }
+
+    // Read back from its name and transaction id.
+    pub fn parse(name: &str, tx_id: Option<TxId>) -> Option<Self> {
+        match (name, tx_id) {
+            ("idle", _) => Some(TxState::Idle),
+            ("open", Some(tx_id)) => Some(TxState::Open(tx_id)),
+            ("aborted", Some(tx_id)) => Some(TxState::Aborted(tx_id)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    open: Option<OpenTransaction>,
+    // The transaction a running statement has checked out of `open`.
+    checked_out: Option<TxId>,
+    // The transaction a failed statement rolled back, until ROLLBACK.
+    failed: Option<TxId>,
+    // Why the session's last transaction was aborted behind its back,
+    // reported to its next statement.
+    aborted: Option<String>,
+    // Its temporary tables, by name, lent to the catalog while one of its
+    // statements runs.
+    temp: HashMap<String, TableInfo>,
+    // When a statement last had them.
+    temp_used: Option<Instant>,
+    // What it SET for itself.
+    settings: Overrides,
+    // RANDOM()'s generator, lent to each of its statements.
+    random: Random,
+    // What it SET its @variables to, by name in upper case.
+    variables: HashMap<String, Value>,
+    // When the login it belongs to runs out. A WebSocket session has none,
+    // it ends when the socket closes.
+    expires: Option<Instant>,
+    // What it said it was at login, which its statements are tagged with
+    // unless they bring their own.
+    tag: Option<String>,
+}
+
+pub struct SessionManager {
+    sessions: Mutex<HashMap<String, SessionState>>,
+    idle_timeout: Option<Duration>,
+    max_age: Option<Duration>,
+    temp_timeout: Option<Duration>,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        SessionManager {
+            sessions: Mutex::new(HashMap::new()),
+            idle_timeout: None,
+            max_age: None,
+            temp_timeout: None,
+        }
+    }
+
+    // Transactions idle between statements for longer than `timeout` are
+    // aborted by the sweeper.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    // Caps a transaction's total lifetime, however busy it is.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // Temporary tables no statement has had for `timeout` are dropped by
+    // the sweeper, their session taken to have timed out.
+    pub fn with_temp_table_timeout(mut self, timeout: Duration) -> Self {
+        self.temp_timeout = Some(timeout);
+        self
+    }
+
+    // Starts the state of a session logged in until `expires`.
+    pub fn open(&self, session: &str, expires: Instant) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.entry(session.to_string()).or_default().expires = Some(expires);
+    }
+
+    pub fn begin(&self, session: &str, tx_id: TxId, snapshot: Snapshot) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.entry(session.to_string()).or_default();
+        if let Some(open) = &state.open {
+            bail!("Transaction {} is already in progress", open.tx_id);
+        }
+        let now = Instant::now();
+        state.open = Some(OpenTransaction {
+            tx_id,
+            started: now,
+            last_active: now,
+            pending_rows: Vec::new(),
+            deferred: None,
+            snapshot,
+            written: BTreeSet::new(),
+            cursors: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    // Checks the session's transaction out for the length of a statement, so
+    // the sweeper cannot abort it halfway through.
+    pub fn take(&self, session: &str) -> Option<OpenTransaction> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.get_mut(session)?;
+        let open = state.open.take()?;
+        state.checked_out = Some(open.tx_id);
+        Some(open)
+    }
+
+    pub fn put_back(&self, session: &str, mut open: OpenTransaction) {
+        open.last_active = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.entry(session.to_string()).or_default();
+        state.checked_out = None;
+        state.open = Some(open);
+    }
+
+    // Takes the session's transaction for COMMIT or ROLLBACK, which end it.
+    pub fn end(&self, session: &str) -> Option<OpenTransaction> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.get_mut(session)?;
+        state.checked_out = None;
+        state.open.take()
+    }
+
+    // Whether the session is between BEGIN and its COMMIT or ROLLBACK, its
+    // transaction aborted or not.
+    pub fn in_transaction(&self, session: &str) -> bool {
+        self.tx_state(session) != TxState::Idle
+    }
+
+    // A statement that has the transaction checked out counts it as open.
+    pub fn tx_state(&self, session: &str) -> TxState {
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(session) {
+            Some(SessionState {
+                open: Some(open), ..
+            }) => TxState::Open(open.tx_id),
+            Some(SessionState {
+                checked_out: Some(tx_id),
+                ..
+            }) => TxState::Open(*tx_id),
+            Some(SessionState {
+                failed: Some(tx_id),
+                ..
+            }) => TxState::Aborted(*tx_id),
+            _ => TxState::Idle,
+        }
+    }
+
+    // Marks the session's transaction, rolled back by a failed statement,
+    // as aborted.
+    pub fn fail(&self, session: &str, tx_id: TxId) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.entry(session.to_string()).or_default();
+        state.checked_out = None;
+        state.failed = Some(tx_id);
+    }
+
+    // Ends an aborted transaction, returning its id.
+    pub fn clear_failed(&self, session: &str) -> Option<TxId> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions
+            .get_mut(session)
+            .and_then(|state| state.failed.take())
+    }
+
+    pub fn has_temp_tables(&self, session: &str) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session)
+            .is_some_and(|state| !state.temp.is_empty())
+    }
+
+    pub fn has_temp_table(&self, session: &str, table: &str) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session)
+            .is_some_and(|state| state.temp.contains_key(table))
+    }
+
+    // Lends the session's temporary tables to a statement, which gives them
+    // back through `put_back_temp` with whatever it changed.
+    pub fn take_temp(&self, session: &str) -> HashMap<String, TableInfo> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get_mut(session)
+            .map(|state| std::mem::take(&mut state.temp))
+            .unwrap_or_default()
+    }
+
+    pub fn put_back_temp(&self, session: &str, temp: HashMap<String, TableInfo>) {
+        if temp.is_empty() && !self.has_temp_tables(session) {
+            return;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.entry(session.to_string()).or_default();
+        state.temp = temp;
+        state.temp_used = Some(Instant::now());
+    }
+
+    // Drops what is kept for a session that will not be back, handing out
+    // its temporary tables for their pages to be freed. Its transaction, if
+    // one is open, must have been rolled back already.
+    pub fn forget(&self, session: &str) -> Vec<TableInfo> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(session)
+            .map(|state| state.temp.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    // Removes every session's temporary tables that no statement has had
+    // for the timeout.
+    pub fn expire_temp(&self, now: Instant) -> Vec<TableInfo> {
+        let Some(timeout) = self.temp_timeout else {
+            return Vec::new();
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut expired = Vec::new();
+        for state in sessions.values_mut() {
+            if state
+                .temp_used
+                .is_some_and(|used| now.duration_since(used) >= timeout)
+            {
+                expired.extend(std::mem::take(&mut state.temp).into_values());
+                state.temp_used = None;
+            }
+        }
+        expired
+    }
+
+    pub fn settings(&self, session: &str) -> Overrides {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session)
+            .map(|state| state.settings.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, session: &str, setting: Setting, value: SettingValue) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.entry(session.to_string()).or_default();
+        state.settings.insert(setting, value);
+    }
+
+    pub fn set_tag(&self, session: &str, tag: Option<String>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.entry(session.to_string()).or_default().tag = tag;
+    }
+
+    pub fn tag(&self, session: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(session).and_then(|state| state.tag.clone())
+    }
+
+    pub fn random(&self, session: &str) -> Random {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions
+            .entry(session.to_string())
+            .or_default()
+            .random
+            .clone()
+    }
+
+    pub fn set_variable(&self, session: &str, name: &str, value: Value) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions.entry(session.to_string()).or_default();
+        state.variables.insert(name.to_string(), value);
+    }
+
+    pub fn variables(&self, session: &str) -> HashMap<String, Value> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session)
+            .map(|state| state.variables.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn take_abort_notice(&self, session: &str) -> Option<String> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get_mut(session)
+            .and_then(|state| state.aborted.take())
+    }
+
+    // Removes every transaction past the idle timeout or the age cap and
+    // leaves a notice for its session.
+    pub fn expire(&self, now: Instant) -> Vec<OpenTransaction> {
+        let mut expired = Vec::new();
+        let mut sessions = self.sessions.lock().unwrap();
+        for state in sessions.values_mut() {
+            let Some(open) = &state.open else {
+                continue;
+            };
+            let reason = if self
+                .max_age
+                .is_some_and(|max| now.duration_since(open.started) >= max)
+            {
+                "transaction aborted due to max transaction age"
+            } else if self
+                .idle_timeout
+                .is_some_and(|idle| now.duration_since(open.last_active) >= idle)
+            {
+                "transaction aborted due to idle timeout"
+            } else {
+                continue;
+            };
+            state.aborted = Some(format!("{} (transaction {})", reason, open.tx_id));
+            expired.extend(state.open.take());
+        }
+        expired
+    }
+
+    // Removes every session whose login has run out, handing out its
+    // transaction, if one is open, and its temporary tables. Whatever one of
+    // its statements still has comes back as a session of its own, for the
+    // idle and temporary table timeouts.
+    pub fn expire_logins(&self, now: Instant) -> (Vec<OpenTransaction>, Vec<TableInfo>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut open = Vec::new();
+        let mut temp = Vec::new();
+        sessions.retain(|_, state| {
+            if state.expires.is_none_or(|expires| now < expires) {
+                return true;
+            }
+            open.extend(state.open.take());
+            temp.extend(std::mem::take(&mut state.temp).into_values());
+            false
+        });
+        (open, temp)
+    }
+
+    // Removes every open transaction, whatever its age.
+    pub fn take_all(&self) -> Vec<OpenTransaction> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions
+            .values_mut()
+            .filter_map(|state| state.open.take())
+            .collect()
+    }
+
+    // Rolls back everything `expire` hands out: undoes its changes and
+    // releases its locks.
+    pub async fn abort_expired(
+        &self,
+        storage: &RwLock<Storage>,
+        wal: &LogManager,
+        locks: &LockManager,
+    ) -> Vec<TxId> {
+        let expired = self.expire(Instant::now());
+        roll_back(expired, storage, wal, locks, "the session sweeper").await
+    }
+
+    // Frees the pages of the temporary tables `expire_temp` hands out.
+    pub async fn drop_expired_temp(&self, storage: &RwLock<Storage>) -> usize {
+        let expired = self.expire_temp(Instant::now());
+        drop_temp(expired, storage, "timed out sessions").await
+    }
+
+    // Forgets the sessions `expire_logins` hands out, rolling back their
+    // transactions and freeing their temporary tables.
+    pub async fn forget_expired(
+        &self,
+        storage: &RwLock<Storage>,
+        wal: &LogManager,
+        locks: &LockManager,
+    ) -> Vec<TxId> {
+        let (open, temp) = self.expire_logins(Instant::now());
+        let aborted = roll_back(open, storage, wal, locks, "the session sweeper").await;
+        drop_temp(temp, storage, "expired logins").await;
+        aborted
+    }
+
+    // Rolls back every open transaction, as on shutdown.
+    pub async fn abort_all(
+        &self,
+        storage: &RwLock<Storage>,
+        wal: &LogManager,
+        locks: &LockManager,
+    ) -> Vec<TxId> {
+        roll_back(self.take_all(), storage, wal, locks, "shutdown").await
+    }
+
+    // Sweeps for expired transactions every `interval` until the session
+    // manager is dropped.
+    pub fn spawn_sweeper(
+        self: &Arc<Self>,
+        storage: Arc<RwLock<Storage>>,
+        wal: Arc<LogManager>,
+        locks: Arc<LockManager>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(manager) = manager.upgrade() else {
+                    break;
+                };
+                manager.abort_expired(&storage, &wal, &locks).await;
+                manager.forget_expired(&storage, &wal, &locks).await;
+                manager.drop_expired_temp(&storage).await;
+            }
+        })
+    }
+}
+
+// Frees the pages of temporary tables whose sessions have ended. `of` says
+// which, for the log.
+async fn drop_temp(temp: Vec<TableInfo>, storage: &RwLock<Storage>, of: &str) -> usize {
+    if temp.is_empty() {
+        return 0;
+    }
+    let dropped = temp.len();
+    match storage.write().await.drop_temp_tables(temp) {
+        Ok(()) => info!("{} temporary tables of {} dropped", dropped, of),
+        Err(e) => error!("Dropping temporary tables failed: {:#}", e),
+    }
+    dropped
+}
+
+// Undoes each transaction's changes and releases its locks. `by` names who
+// aborted them, for the log.
+async fn roll_back(
+    open: Vec<OpenTransaction>,
+    storage: &RwLock<Storage>,
+    wal: &LogManager,
+    locks: &LockManager,
+    by: &str,
+) -> Vec<TxId> {
+    if open.is_empty() {
+        return Vec::new();
+    }
+    let mut storage = storage.write().await;
+    let mut aborted = Vec::new();
+    for open in open {
+        storage.set_transaction(Some(open.tx_id));
+        storage.pending_rows = open.pending_rows;
+        match recovery_manager::abort_transaction(&mut storage, wal, open.tx_id) {
+            Ok(undone) => info!(
+                "Transaction {} aborted by {}, {} updates undone",
+                open.tx_id, by, undone
+            ),
+            Err(e) => error!("Rollback of transaction {} failed: {:#}", open.tx_id, e),
+        }
+        storage.set_transaction(None);
+        locks.unlock_all(open.tx_id);
+        aborted.push(open.tx_id);
+    }
+    aborted
+}
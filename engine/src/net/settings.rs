@@ -0,0 +1,384 @@
+use crate::storage::storage::ScanErrorPolicy;
+use crate::tx::lock_manager::IsolationLevel;
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+// What SET and SHOW reach. The server keeps one value of each, which
+// `SET GLOBAL` changes; a session's own SET overrides it for that session's
+// statements until the session ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Setting {
+    // How long a statement may run, unless its request says otherwise.
+    QueryTimeout,
+    // How long a statement waits for a table lock before it gives up.
+    LockTimeout,
+    Isolation,
+    // Whether SELECTs may be answered from, and stored in, the result cache.
+    ResultCache,
+    // The schemas an unqualified table name is looked for in, in order.
+    SearchPath,
+    // What the server logs, in `RUST_LOG` syntax.
+    LogLevel,
+    // Requests per second allowed each session, 0 for no limit.
+    RateLimit,
+    // Memory for cached SELECT responses, in bytes.
+    ResultCacheSize,
+    // Memory one statement's sorts, aggregates and joins may hold, in bytes.
+    WorkMem,
+    // How many times a statement of its own transaction is run again after
+    // losing a lock conflict, 0 for never.
+    LockRetries,
+    // How long before the first of those runs; each one after waits twice
+    // as long as the last.
+    LockRetryBackoff,
+    // Whether a sequential scan fails on a damaged heap page or skips it.
+    ScanErrorPolicy,
+    // How many percent of a table's rows have to go in or out after its
+    // last ANALYZE for the server to analyze it again, 0 for never.
+    AutoAnalyze,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 13] = [
+        Setting::QueryTimeout,
+        Setting::LockTimeout,
+        Setting::Isolation,
+        Setting::ResultCache,
+        Setting::SearchPath,
+        Setting::LogLevel,
+        Setting::RateLimit,
+        Setting::ResultCacheSize,
+        Setting::WorkMem,
+        Setting::LockRetries,
+        Setting::LockRetryBackoff,
+        Setting::ScanErrorPolicy,
+        Setting::AutoAnalyze,
+    ];
+
+    pub fn parse(name: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|s| s.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("Unknown setting '{}'", name.to_ascii_lowercase()))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Setting::QueryTimeout => "query_timeout",
+            Setting::LockTimeout => "lock_timeout",
+            Setting::Isolation => "isolation",
+            Setting::ResultCache => "result_cache",
+            Setting::SearchPath => "search_path",
+            Setting::LogLevel => "log_level",
+            Setting::RateLimit => "rate_limit",
+            Setting::ResultCacheSize => "result_cache_size",
+            Setting::WorkMem => "work_mem",
+            Setting::LockRetries => "lock_retries",
+            Setting::LockRetryBackoff => "lock_retry_backoff",
+            Setting::ScanErrorPolicy => "scan_error_policy",
+            Setting::AutoAnalyze => "auto_analyze",
+        }
+    }
+
+    // Settings of the server as a whole rather than of the statements it
+    // runs, which a session cannot SET for itself.
+    pub fn server_wide(self) -> bool {
+        matches!(
+            self,
+            Setting::LogLevel
+                | Setting::RateLimit
+                | Setting::ResultCacheSize
+                | Setting::AutoAnalyze
+        )
+    }
+
+    // Checks a value as SET spells it: milliseconds for the timeouts and the
+    // retry backoff, `read committed` or `repeatable read`, on or off, schemas separated by
+    // commas, a log filter, whole numbers for the rate, cache size,
+    // work_mem, retries and auto_analyze, and `strict` or `skip_page`. A schema on the
+    // path need not exist yet.
+    pub fn parse_value(self, value: &str) -> Result<SettingValue> {
+        let value = value.trim().to_ascii_lowercase();
+        match self {
+            Setting::QueryTimeout | Setting::LockTimeout | Setting::LockRetryBackoff => match value.parse::<u64>() {
+                Ok(ms) if ms > 0 => Ok(SettingValue::Duration(Duration::from_millis(ms))),
+                _ => bail!(
+                    "{} is a number of milliseconds, at least 1, not '{}'",
+                    self.name(),
+                    value
+                ),
+            },
+            Setting::Isolation => match value.replace('_', " ").as_str() {
+                "read committed" => Ok(SettingValue::Isolation(IsolationLevel::ReadCommitted)),
+                "repeatable read" => Ok(SettingValue::Isolation(IsolationLevel::RepeatableRead)),
+                _ => bail!(
+                    "isolation is 'read committed' or 'repeatable read', not '{}'",
+                    value
+                ),
+            },
+            Setting::ResultCache => match value.as_str() {
+                "on" | "true" => Ok(SettingValue::Bool(true)),
+                "off" | "false" => Ok(SettingValue::Bool(false)),
+                _ => bail!("result_cache is on or off, not '{}'", value),
+            },
+            Setting::SearchPath => {
+                let schemas: Vec<String> = value
+                    .split(',')
+                    .map(|schema| schema.trim().to_ascii_uppercase())
+                    .collect();
+                if schemas.iter().any(String::is_empty) {
+                    bail!(
+                        "search_path is schemas separated by commas, not '{}'",
+                        value
+                    );
+                }
+                Ok(SettingValue::SearchPath(schemas))
+            }
+            Setting::LogLevel => match EnvFilter::try_new(&value) {
+                Ok(_) => Ok(SettingValue::Text(value)),
+                Err(e) => bail!("log_level is a log filter, not '{}': {}", value, e),
+            },
+            Setting::RateLimit => match value.parse::<u32>() {
+                Ok(rate) => Ok(SettingValue::Count(rate as u64)),
+                Err(_) => bail!(
+                    "rate_limit is requests per second, 0 for no limit, not '{}'",
+                    value
+                ),
+            },
+            Setting::ResultCacheSize => match value.parse::<u64>() {
+                Ok(bytes) => Ok(SettingValue::Count(bytes)),
+                Err(_) => bail!("result_cache_size is a number of bytes, not '{}'", value),
+            },
+            Setting::WorkMem => match value.parse::<u64>() {
+                Ok(bytes) if bytes > 0 => Ok(SettingValue::Count(bytes)),
+                _ => bail!("work_mem is a number of bytes, at least 1, not '{}'", value),
+            },
+            Setting::LockRetries => match value.parse::<u32>() {
+                Ok(retries) => Ok(SettingValue::Count(retries as u64)),
+                Err(_) => bail!(
+                    "lock_retries is a number of retries, 0 for none, not '{}'",
+                    value
+                ),
+            },
+            Setting::ScanErrorPolicy => match value.as_str() {
+                "strict" => Ok(SettingValue::ScanErrors(ScanErrorPolicy::Strict)),
+                "skip_page" => Ok(SettingValue::ScanErrors(ScanErrorPolicy::SkipPage)),
+                _ => bail!(
+                    "scan_error_policy is 'strict' or 'skip_page', not '{}'",
+                    value
+                ),
+            },
+            Setting::AutoAnalyze => match value.parse::<u32>() {
+                Ok(percent) => Ok(SettingValue::Count(percent as u64)),
+                Err(_) => bail!(
+                    "auto_analyze is a percentage of a table's rows, 0 for never, not '{}'",
+                    value
+                ),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingValue {
+    Duration(Duration),
+    Isolation(IsolationLevel),
+    Bool(bool),
+    SearchPath(Vec<String>),
+    Text(String),
+    Count(u64),
+    ScanErrors(ScanErrorPolicy),
+}
+
+// As SHOW prints it, which SET takes back.
+impl fmt::Display for SettingValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingValue::Duration(d) => write!(f, "{}", d.as_millis()),
+            SettingValue::Isolation(IsolationLevel::ReadCommitted) => f.write_str("read committed"),
+            SettingValue::Isolation(IsolationLevel::RepeatableRead) => {
+                f.write_str("repeatable read")
+            }
+            SettingValue::Bool(true) => f.write_str("on"),
+            SettingValue::Bool(false) => f.write_str("off"),
+            SettingValue::SearchPath(schemas) => f.write_str(&schemas.join(", ")),
+            SettingValue::Text(text) => f.write_str(text),
+            SettingValue::Count(n) => write!(f, "{}", n),
+            SettingValue::ScanErrors(ScanErrorPolicy::Strict) => f.write_str("strict"),
+            SettingValue::ScanErrors(ScanErrorPolicy::SkipPage) => f.write_str("skip_page"),
+        }
+    }
+}
+
+// What a session has SET for itself.
+pub type Overrides = BTreeMap<Setting, SettingValue>;
+
+// The value of every setting, as a statement runs with them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub query_timeout: Duration,
+    pub lock_timeout: Duration,
+    pub isolation: IsolationLevel,
+    pub result_cache: bool,
+    pub search_path: Vec<String>,
+    pub log_level: String,
+    pub rate_limit: u32,
+    pub result_cache_size: usize,
+    pub work_mem: usize,
+    pub lock_retries: u32,
+    pub lock_retry_backoff: Duration,
+    pub scan_error_policy: ScanErrorPolicy,
+    pub auto_analyze: u32,
+}
+
+impl Settings {
+    pub fn get(&self, setting: Setting) -> SettingValue {
+        match setting {
+            Setting::QueryTimeout => SettingValue::Duration(self.query_timeout),
+            Setting::LockTimeout => SettingValue::Duration(self.lock_timeout),
+            Setting::Isolation => SettingValue::Isolation(self.isolation),
+            Setting::ResultCache => SettingValue::Bool(self.result_cache),
+            Setting::SearchPath => SettingValue::SearchPath(self.search_path.clone()),
+            Setting::LogLevel => SettingValue::Text(self.log_level.clone()),
+            Setting::RateLimit => SettingValue::Count(self.rate_limit as u64),
+            Setting::ResultCacheSize => SettingValue::Count(self.result_cache_size as u64),
+            Setting::WorkMem => SettingValue::Count(self.work_mem as u64),
+            Setting::LockRetries => SettingValue::Count(self.lock_retries as u64),
+            Setting::LockRetryBackoff => SettingValue::Duration(self.lock_retry_backoff),
+            Setting::ScanErrorPolicy => SettingValue::ScanErrors(self.scan_error_policy),
+            Setting::AutoAnalyze => SettingValue::Count(self.auto_analyze as u64),
+        }
+    }
+
+    // `value` has to be of the setting's kind, as `Setting::parse_value`
+    // makes it.
+    pub fn set(&mut self, setting: Setting, value: SettingValue) -> Result<()> {
+        match (setting, value) {
+            (Setting::QueryTimeout, SettingValue::Duration(d)) => self.query_timeout = d,
+            (Setting::LockTimeout, SettingValue::Duration(d)) => self.lock_timeout = d,
+            (Setting::Isolation, SettingValue::Isolation(level)) => self.isolation = level,
+            (Setting::ResultCache, SettingValue::Bool(on)) => self.result_cache = on,
+            (Setting::SearchPath, SettingValue::SearchPath(schemas)) => self.search_path = schemas,
+            (Setting::LogLevel, SettingValue::Text(filter)) => self.log_level = filter,
+            (Setting::RateLimit, SettingValue::Count(rate)) => {
+                self.rate_limit = u32::try_from(rate)
+                    .map_err(|_| anyhow!("rate_limit is at most {}", u32::MAX))?
+            }
+            (Setting::ResultCacheSize, SettingValue::Count(bytes)) => {
+                self.result_cache_size = bytes as usize
+            }
+            (Setting::WorkMem, SettingValue::Count(bytes)) => self.work_mem = bytes as usize,
+            (Setting::LockRetries, SettingValue::Count(retries)) => {
+                self.lock_retries = u32::try_from(retries)
+                    .map_err(|_| anyhow!("lock_retries is at most {}", u32::MAX))?
+            }
+            (Setting::LockRetryBackoff, SettingValue::Duration(d)) => self.lock_retry_backoff = d,
+            (Setting::ScanErrorPolicy, SettingValue::ScanErrors(policy)) => {
+                self.scan_error_policy = policy
+            }
+            (Setting::AutoAnalyze, SettingValue::Count(percent)) => {
+                self.auto_analyze = u32::try_from(percent)
+                    .map_err(|_| anyhow!("auto_analyze is at most {}", u32::MAX))?
+            }
+            (setting, value) => bail!("{} cannot be {:?}", setting.name(), value),
+        }
+        Ok(())
+    }
+
+    // These settings with a session's overrides on top.
+    pub fn with(&self, overrides: &Overrides) -> Settings {
+        let mut settings = self.clone();
+        for (&setting, value) in overrides {
+            // Every override went through `parse_value`.
+            let _ = settings.set(setting, value.clone());
+        }
+        settings
+    }
+
+    // Every setting by name, in the order SHOW ALL lists them.
+    pub fn all(&self) -> Vec<(&'static str, String)> {
+        Setting::ALL
+            .into_iter()
+            .map(|s| (s.name(), self.get(s).to_string()))
+            .collect()
+    }
+}
+
+// Keys a config file may hold besides the settings. They are only read when
+// the server starts, so changing one takes a restart.
+pub const RESTART_ONLY: [&str; 6] = [
+    "listen",
+    "data_dir",
+    "page_size",
+    "pool_size",
+    "wal",
+    "max_columns",
+];
+
+// A config file, given to the server with `--config`: one `name = value` a
+// line, `#` starting a comment. Settings are spelled as SET takes them and
+// applied as SET GLOBAL would be, on start and again on every reload; the
+// restart-only keys stand in for the flags of the same name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub settings: Vec<(Setting, SettingValue)>,
+    pub restart_only: BTreeMap<&'static str, String>,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        Self::parse(path, &text)
+    }
+
+    // Every line is checked before anything is applied, so a file with a
+    // mistake in it changes nothing.
+    pub fn parse(path: &Path, text: &str) -> Result<Self> {
+        let mut file = ConfigFile {
+            path: path.to_path_buf(),
+            settings: Vec::new(),
+            restart_only: BTreeMap::new(),
+        };
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let at = || format!("{:?} line {}", path, n + 1);
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{}: expected name = value", at()))?;
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            let value = value.trim_matches(|c| c == '\'' || c == '"');
+            if let Some(&key) = RESTART_ONLY.iter().find(|&&key| key == name) {
+                if file.restart_only.insert(key, value.to_string()).is_some() {
+                    bail!("{}: {} is set twice", at(), key);
+                }
+                continue;
+            }
+            let setting = Setting::parse(&name).with_context(at)?;
+            if file.settings.iter().any(|(s, _)| *s == setting) {
+                bail!("{}: {} is set twice", at(), setting.name());
+            }
+            let value = setting.parse_value(value).with_context(at)?;
+            file.settings.push((setting, value));
+        }
+        Ok(file)
+    }
+
+    // The value for a restart-only key, by the flag it stands in for, with
+    // the key to blame if it does not parse.
+    pub fn get(&self, flag: &str) -> Option<(&'static str, String)> {
+        let key = flag.trim_start_matches("--").replace('-', "_");
+        self.restart_only
+            .iter()
+            .find(|(k, _)| **k == key)
+            .map(|(k, v)| (*k, v.clone()))
+    }
+}
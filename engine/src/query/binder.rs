@@ -1,24 +1,33 @@
-
-
-use crate::query::parser::{BinaryOp, Expr as RawExpr, Statement as RawStmt, Value as RawValue};
-use crate::storage::storage::Storage;
-use anyhow::{Context, Result, bail};
+use crate::query::datetime;
+use crate::query::memory::{DEFAULT_WORK_MEM, MemoryTracker};
+use crate::query::parser::{
+    BinaryOp, ColumnDef, Expr as RawExpr, OrderBy, Parser, Statement as RawStmt,
+};
+use crate::query::random::Random;
+pub use crate::query::value::{Collation, Value};
+use crate::storage::sequence::{NextVal, Sequence};
+use crate::storage::storage::{self, IndexKind, Storage};
+use crate::tx::log_manager::Lsn;
+use anyhow::{Context, Result, anyhow, bail};
 use std::collections::HashMap;
-
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct ColumnMeta {
     pub name: String,
     pub data_type: DataType,
+    pub collation: Collation,
     pub ordinal: usize,
 }
 
-
 #[derive(Debug, Clone)]
 pub struct TableMeta {
     pub name: String,
     pub columns: Vec<ColumnMeta>,
     pub col_index: HashMap<String, usize>,
+    // The storage catalog's version of the table when it was read, which a
+    // plan carries to check against before it runs.
+    pub version: u64,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -28,7 +37,7 @@ pub enum DataType {
 }
 
 impl DataType {
-    pub fn from_str(s: &str) -> Option<Self> {
+    pub fn parse(s: &str) -> Option<Self> {
         match &s.to_ascii_lowercase()[..] {
             "int" | "integer" => Some(DataType::Int),
             "varchar" | "text" | "string" => Some(DataType::Varchar),
@@ -37,32 +46,96 @@ impl DataType {
     }
 }
 
-
+#[derive(Default)]
 pub struct Catalog {
     pub tables: HashMap<String, TableMeta>,
+    // What RANDOM() and SETSEED reach: the session's, or one of the
+    // statement's own.
+    pub random: Random,
+    // What the statement's sorts, aggregates and joins may hold.
+    pub memory: Arc<MemoryTracker>,
+    // The session's variables, by name in upper case, which `@name`s are
+    // bound to.
+    pub variables: HashMap<String, Value>,
 }
 
 impl Catalog {
     pub fn new() -> Self {
         Catalog {
             tables: HashMap::new(),
+            random: Random::new(),
+            memory: MemoryTracker::new(DEFAULT_WORK_MEM),
+            variables: HashMap::new(),
+        }
+    }
+
+    pub fn with_random(mut self, random: Random) -> Self {
+        self.random = random;
+        self
+    }
+
+    pub fn with_variables(mut self, variables: HashMap<String, Value>) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    // A budget of `work_mem` bytes for the statement.
+    pub fn with_work_mem(mut self, work_mem: usize) -> Self {
+        self.memory = MemoryTracker::new(work_mem);
+        self
+    }
+
+    pub fn from_storage(catalog: &storage::Catalog) -> Self {
+        let mut tables = HashMap::new();
+        // Temporary tables come last, so one hides a table of its name.
+        for info in catalog.tables.values().chain(catalog.temp.values()) {
+            let mut col_index = HashMap::new();
+            let mut columns = Vec::new();
+            for (i, col) in info.columns.iter().enumerate() {
+                col_index.insert(col.name.to_ascii_lowercase(), i);
+                columns.push(ColumnMeta {
+                    name: col.name.clone(),
+                    data_type: match col.data_type {
+                        storage::DataType::Int => DataType::Int,
+                        storage::DataType::String => DataType::Varchar,
+                    },
+                    collation: col.collation,
+                    ordinal: i,
+                });
+            }
+            tables.insert(
+                info.name.to_ascii_lowercase(),
+                TableMeta {
+                    name: info.name.clone(),
+                    columns,
+                    col_index,
+                    version: info.version,
+                },
+            );
+        }
+        Catalog {
+            tables,
+            random: Random::new(),
+            memory: MemoryTracker::new(DEFAULT_WORK_MEM),
+            variables: HashMap::new(),
         }
     }
 
-    pub fn create_table(&mut self, name: &str, cols: &[(String, String)]) -> Result<()> {
+    pub fn create_table(&mut self, name: &str, cols: &[ColumnDef]) -> Result<()> {
         let key = name.to_ascii_lowercase();
         if self.tables.contains_key(&key) {
             bail!("Table '{}' already exists", name);
         }
         let mut col_index = HashMap::new();
         let mut columns = Vec::new();
-        for (i, (col_name, col_type)) in cols.iter().enumerate() {
-            let dt = DataType::from_str(col_type)
-                .with_context(|| format!("Unknown type '{}' for '{}'", col_type, col_name))?;
-            col_index.insert(col_name.to_ascii_lowercase(), i);
+        for (i, col) in cols.iter().enumerate() {
+            let dt = DataType::parse(&col.data_type)
+                .with_context(|| format!("Unknown type '{}' for '{}'", col.data_type, col.name))?;
+            col_index.insert(col.name.to_ascii_lowercase(), i);
             columns.push(ColumnMeta {
-                name: col_name.clone(),
+                name: col.name.clone(),
                 data_type: dt,
+                collation: col.collation,
                 ordinal: i,
             });
         }
@@ -72,6 +145,7 @@ impl Catalog {
                 name: name.to_string(),
                 columns,
                 col_index,
+                version: 0,
             },
         );
         Ok(())
@@ -81,10 +155,35 @@ impl Catalog {
         let key = name.to_ascii_lowercase();
         self.tables
             .get(&key)
-            .with_context(|| format!("Unknown table '{}'", name))
+            .with_context(|| UnknownName {
+                message: format!("Unknown table '{}'", name),
+                name: name.to_string(),
+            })
     }
 }
 
+// A table or column a statement names that the catalog does not have. Kept
+// apart from other bind errors so the name can be found in the statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownName {
+    pub message: String,
+    pub name: String,
+}
+
+impl UnknownName {
+    fn column(col: &str, table: &str) -> Self {
+        UnknownName {
+            message: format!("Unknown column '{}' in '{}'", col, table),
+            name: col.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for UnknownName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
 
 #[derive(Debug)]
 pub enum BoundStmt {
@@ -95,22 +194,65 @@ pub enum BoundStmt {
     CreateIndex {
         index_name: String,
         table: String,
-        column: String,
+        columns: Vec<String>,
         order: usize,
+        kind: IndexKind,
     },
     Insert {
         table: String,
         col_ordinals: Vec<usize>,
-        values: Vec<BoundExpr>,
+        rows: Vec<Vec<BoundExpr>>,
     },
+    Delete {
+        table: String,
+        filter: Option<BoundExpr>,
+    },
+    // With a grouping, the projections and sort keys read the rows it
+    // makes rather than the table's. The semi-joins go with the filter,
+    // before any grouping.
     Select {
         projections: Vec<BoundExpr>,
-        table: String,
+        table: Option<String>,
+        as_of: Option<Lsn>,
         filter: Option<BoundExpr>,
+        semi_joins: Vec<SemiJoin>,
+        grouping: Option<Grouping>,
+        order_by: Vec<SortKey>,
+    },
+    // Its ORDER BY reads the rows the SELECTs make.
+    UnionAll {
+        selects: Vec<BoundStmt>,
+        order_by: Vec<SortKey>,
+    },
+    Explain {
+        stmt: Box<BoundStmt>,
+        analyze: bool,
+    },
+    Reindex {
+        index_name: String,
+        table: String,
+    },
+    Analyze {
+        table: String,
+    },
+    DropTable {
+        table: String,
+    },
+    ShowLocks,
+    ShowTables {
+        schema: Option<String>,
+    },
+    ShowGrants,
+    Check,
+    Checkpoint,
+    FlushTables {
+        tables: Vec<String>,
+    },
+    Vacuum {
+        tables: Vec<String>,
     },
 }
 
-
 #[derive(Debug, Clone)]
 pub enum BoundExpr {
     Column {
@@ -118,6 +260,7 @@ pub enum BoundExpr {
         col: String,
         ordinal: usize,
         data_type: DataType,
+        collation: Collation,
     },
     Literal(Value),
     BinaryOp {
@@ -126,32 +269,291 @@ pub enum BoundExpr {
         right: Box<BoundExpr>,
         data_type: DataType,
     },
+    NextVal(NextVal),
+    CurrVal(Sequence),
+    Call {
+        function: Function,
+        args: Vec<BoundExpr>,
+    },
+    Random(Random),
+    SetSeed(Random, Box<BoundExpr>),
+}
+
+impl BoundExpr {
+    // What a client sees the expression's column called.
+    pub fn name(&self) -> String {
+        match self {
+            BoundExpr::Column { col, .. } => col.clone(),
+            BoundExpr::NextVal(_) => "nextval".to_string(),
+            BoundExpr::CurrVal(_) => "currval".to_string(),
+            BoundExpr::Call { function, .. } => function.name().to_ascii_lowercase(),
+            BoundExpr::Random(_) => "random".to_string(),
+            BoundExpr::SetSeed(..) => "setseed".to_string(),
+            BoundExpr::Literal(_) | BoundExpr::BinaryOp { .. } => "?column?".to_string(),
+        }
+    }
+
+    // What the expression's values compare by. Only a column declared
+    // with one has a collation; anything worked out compares as BINARY.
+    pub fn collation(&self) -> Collation {
+        match self {
+            BoundExpr::Column { collation, .. } => *collation,
+            _ => Collation::Binary,
+        }
+    }
+
+    pub fn data_type(&self) -> DataType {
+        match self {
+            BoundExpr::Column { data_type, .. } | BoundExpr::BinaryOp { data_type, .. } => {
+                data_type.clone()
+            }
+            BoundExpr::Literal(Value::Int(_))
+            | BoundExpr::NextVal(_)
+            | BoundExpr::CurrVal(_)
+            | BoundExpr::Random(_)
+            | BoundExpr::SetSeed(..) => DataType::Int,
+            BoundExpr::Call { function, args } => match function {
+                Function::Abs | Function::Mod | Function::DateTrunc | Function::Extract => {
+                    DataType::Int
+                }
+                Function::Min | Function::Max | Function::Coalesce | Function::NullIf => {
+                    args[0].data_type()
+                }
+                Function::Upper | Function::Lower | Function::TypeOf => DataType::Varchar,
+            },
+            BoundExpr::Literal(Value::String(_)) => DataType::Varchar,
+        }
+    }
 }
 
+// Functions that can give a different value each time they are called, on
+// the same row of the same data. Nothing may work one out ahead of the rows
+// it is called for, and a result calling one is never cached.
+pub const VOLATILE_FUNCTIONS: [&str; 4] = ["NEXTVAL", "CURRVAL", "RANDOM", "SETSEED"];
+
+// Functions of the values they are given and nothing else. MIN and MAX of
+// two values are the lesser and greater; of one, they are aggregates.
+// COALESCE and NULLIF are for NULLs, which nothing can be yet: COALESCE
+// gives its first argument, and NULLIF its first unless that would be NULL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Upper,
+    Lower,
+    Abs,
+    Mod,
+    Min,
+    Max,
+    TypeOf,
+    Coalesce,
+    NullIf,
+    DateTrunc,
+    Extract,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "UPPER" => Some(Function::Upper),
+            "LOWER" => Some(Function::Lower),
+            "ABS" => Some(Function::Abs),
+            "MOD" => Some(Function::Mod),
+            "MIN" => Some(Function::Min),
+            "MAX" => Some(Function::Max),
+            "TYPEOF" => Some(Function::TypeOf),
+            "COALESCE" => Some(Function::Coalesce),
+            "NULLIF" => Some(Function::NullIf),
+            "DATE_TRUNC" => Some(Function::DateTrunc),
+            "EXTRACT" => Some(Function::Extract),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Upper => "UPPER",
+            Function::Lower => "LOWER",
+            Function::Abs => "ABS",
+            Function::Mod => "MOD",
+            Function::Min => "MIN",
+            Function::Max => "MAX",
+            Function::TypeOf => "TYPEOF",
+            Function::Coalesce => "COALESCE",
+            Function::NullIf => "NULLIF",
+            Function::DateTrunc => "DATE_TRUNC",
+            Function::Extract => "EXTRACT",
+        }
+    }
+
+    // Checks how many arguments there are and of what types.
+    fn check(self, args: &[BoundExpr]) -> Result<()> {
+        let name = self.name();
+        let types: Vec<DataType> = args.iter().map(BoundExpr::data_type).collect();
+        match (self, types.as_slice()) {
+            (Function::Upper | Function::Lower, [DataType::Varchar])
+            | (Function::Abs, [DataType::Int])
+            | (Function::Mod, [DataType::Int, DataType::Int])
+            | (Function::TypeOf, [_]) => Ok(()),
+            // A unit written out is checked now rather than on every row.
+            (Function::DateTrunc | Function::Extract, [DataType::Varchar, DataType::Int]) => {
+                match &args[0] {
+                    BoundExpr::Literal(Value::String(unit)) => {
+                        datetime::check_unit(unit, self == Function::Extract)
+                    }
+                    _ => Ok(()),
+                }
+            }
+            (Function::Min | Function::Max | Function::NullIf, [a, b]) if a == b => Ok(()),
+            (Function::Coalesce, [first, rest @ ..]) if rest.iter().all(|t| t == first) => Ok(()),
+            (Function::Coalesce, [_, ..]) => bail!("COALESCE needs arguments of the same type"),
+            (Function::Coalesce, []) => bail!("COALESCE takes at least one argument"),
+            (Function::Upper | Function::Lower, [_]) => bail!("{} needs a TEXT argument", name),
+            (Function::Abs, [_]) => bail!("ABS needs an INT argument"),
+            (Function::Mod, [_, _]) => bail!("MOD needs INT arguments"),
+            (Function::Min | Function::Max | Function::NullIf, [_, _]) => {
+                bail!("{} needs two values of the same type", name)
+            }
+            (Function::DateTrunc, [_, _]) => bail!("DATE_TRUNC needs a TEXT unit and an INT time"),
+            (Function::Extract, [_, _]) => bail!("EXTRACT needs an INT time"),
+            (
+                Function::Mod
+                | Function::Min
+                | Function::Max
+                | Function::NullIf
+                | Function::DateTrunc
+                | Function::Extract,
+                _,
+            ) => bail!("{} takes two arguments", name),
+            _ => bail!("{} takes one argument", name),
+        }
+    }
+}
+
+// MIN and MAX are aggregates unless they are given two values.
+fn aggregate_of(name: &str, args: &[RawExpr]) -> Option<AggregateFunction> {
+    AggregateFunction::parse(name).filter(|function| {
+        !matches!(function, AggregateFunction::Min | AggregateFunction::Max) || args.len() != 2
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl AggregateFunction {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "COUNT" => Some(AggregateFunction::Count),
+            "SUM" => Some(AggregateFunction::Sum),
+            "MIN" => Some(AggregateFunction::Min),
+            "MAX" => Some(AggregateFunction::Max),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AggregateFunction::Count => "COUNT",
+            AggregateFunction::Sum => "SUM",
+            AggregateFunction::Min => "MIN",
+            AggregateFunction::Max => "MAX",
+        }
+    }
+}
+
+// An aggregate over the rows of a group; `arg` is None for COUNT(*).
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub function: AggregateFunction,
+    pub arg: Option<BoundExpr>,
+}
+
+// GROUP BY, or aggregates over the whole table when `keys` is empty. Each
+// group makes one row: its keys, then its aggregates.
 #[derive(Debug, Clone)]
-pub enum Value {
-    Int(i64),
-    String(String),
+pub struct Grouping {
+    pub keys: Vec<BoundExpr>,
+    pub aggregates: Vec<Aggregate>,
+}
+
+// A `[NOT] EXISTS` in a SELECT's WHERE. A row of the query is kept when
+// some row of `table` that passes `filter` has `inner_keys` equal to the
+// row's `outer_keys`, or with `anti`, when none does.
+#[derive(Debug, Clone)]
+pub struct SemiJoin {
+    pub table: String,
+    pub filter: Option<BoundExpr>,
+    pub outer_keys: Vec<BoundExpr>,
+    pub inner_keys: Vec<BoundExpr>,
+    pub anti: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SortKey {
+    pub expr: BoundExpr,
+    pub descending: bool,
+}
+
+// What a grouped SELECT can read after grouping: the GROUP BY expressions
+// as written, and the aggregates found so far.
+struct GroupScope<'t> {
+    table: Option<&'t str>,
+    group_by: Vec<RawExpr>,
+    keys: Vec<BoundExpr>,
+    aggregates: Vec<(RawExpr, Aggregate)>,
 }
 
 pub struct Binder<'a> {
     catalog: &'a mut Catalog,
-    storage: &'a mut Storage,
+    storage: StorageAccess<'a>,
+}
+
+// CREATE INDEX builds its index while it is bound; everything else only
+// looks storage up and can be bound by readers sharing it.
+enum StorageAccess<'a> {
+    Exclusive(&'a mut Storage),
+    Shared(&'a Storage),
 }
 
 impl<'a> Binder<'a> {
     pub fn new(catalog: &'a mut Catalog, storage: &'a mut Storage) -> Self {
-        Binder { catalog, storage }
+        Binder {
+            catalog,
+            storage: StorageAccess::Exclusive(storage),
+        }
+    }
+
+    pub fn shared(catalog: &'a mut Catalog, storage: &'a Storage) -> Self {
+        Binder {
+            catalog,
+            storage: StorageAccess::Shared(storage),
+        }
+    }
+
+    fn storage(&self) -> &Storage {
+        match &self.storage {
+            StorageAccess::Exclusive(storage) => storage,
+            StorageAccess::Shared(storage) => storage,
+        }
     }
 
     pub fn bind(&mut self, stmt: RawStmt) -> Result<BoundStmt> {
         use RawStmt::*;
+        let stmt = match stmt {
+            select @ (Select { .. } | UnionAll { .. }) => {
+                expand_views(&self.storage().catalog, select)?
+            }
+            stmt => stmt,
+        };
         match stmt {
-            CreateTable { name, columns } => {
+            CreateTable { name, columns, .. } => {
                 self.catalog.create_table(&name, &columns)?;
                 let cols = columns
                     .into_iter()
-                    .map(|(n, t)| (n, DataType::from_str(&t).unwrap()))
+                    .map(|c| (c.name, DataType::parse(&c.data_type).unwrap()))
                     .collect();
                 Ok(BoundStmt::CreateTable {
                     name,
@@ -161,23 +563,37 @@ impl<'a> Binder<'a> {
             CreateIndex {
                 index_name,
                 table,
-                column,
+                columns,
+                using,
             } => {
-                let order = 4;
-                self.storage
-                    .create_index(&table, &column, &index_name, order)
+                let kind = match using {
+                    Some(method) => IndexKind::parse(&method)?,
+                    None => IndexKind::default(),
+                };
+                let StorageAccess::Exclusive(storage) = &mut self.storage else {
+                    bail!("CREATE INDEX cannot be bound against shared storage");
+                };
+                storage
+                    .create_index_using(&table, &columns, &index_name, kind)
                     .context("Failed to create index")?;
+                let order = storage
+                    .get_indexes(&table)
+                    .iter()
+                    .find(|i| i.name == index_name)
+                    .map(|i| i.order)
+                    .context("Index missing from catalog after creation")?;
                 Ok(BoundStmt::CreateIndex {
                     index_name,
                     table,
-                    column,
+                    columns,
                     order,
+                    kind,
                 })
             }
             Insert {
                 table,
                 columns,
-                values,
+                rows,
             } => {
                 let meta = self.catalog.get_table(&table)?;
                 let mut ords = Vec::new();
@@ -186,68 +602,454 @@ impl<'a> Binder<'a> {
                     let &o = meta
                         .col_index
                         .get(&lc)
-                        .with_context(|| format!("Unknown column '{}' in '{}'", col, table))?;
+                        .with_context(|| UnknownName::column(&col, &table))?;
+                    if ords.contains(&o) {
+                        bail!("INSERT lists column '{}' twice", col);
+                    }
                     ords.push(o);
                 }
-                let mut bv = Vec::new();
-                for expr in values {
-                    bv.push(self.bind_expr(expr, &table)?);
+                // Nothing can be NULL and there are no defaults to fall back
+                // on.
+                if let Some(missing) = (0..meta.columns.len()).find(|o| !ords.contains(o)) {
+                    bail!(
+                        "INSERT into '{}' has no value for column '{}'; every column needs one",
+                        table,
+                        meta.columns[missing].name
+                    );
+                }
+                let mut bound_rows = Vec::with_capacity(rows.len());
+                for (i, values) in rows.into_iter().enumerate() {
+                    if values.len() != ords.len() {
+                        bail!(
+                            "INSERT row {} has {} values for {} columns",
+                            i + 1,
+                            values.len(),
+                            ords.len()
+                        );
+                    }
+                    let mut bv = Vec::new();
+                    for expr in values {
+                        // Values are worked out before there is a row.
+                        if let Some(col) = first_column(&expr) {
+                            bail!(
+                                "INSERT row {} refers to column '{}'; values cannot read columns",
+                                i + 1,
+                                col
+                            );
+                        }
+                        bv.push(self.bind_expr(expr, Some(&table))?);
+                    }
+                    bound_rows.push(bv);
                 }
                 Ok(BoundStmt::Insert {
                     table,
                     col_ordinals: ords,
-                    values: bv,
+                    rows: bound_rows,
                 })
             }
+            Delete { table, filter } => {
+                let table = self.catalog.get_table(&table)?.name.clone();
+                let filter = match filter {
+                    Some(f) => match self.bind_where(f, Some(&table))? {
+                        (filter, semi_joins) if semi_joins.is_empty() => filter,
+                        _ => bail!("DELETE cannot have EXISTS in its WHERE"),
+                    },
+                    None => None,
+                };
+                Ok(BoundStmt::Delete { table, filter })
+            }
             Select {
-                projections,
+                mut projections,
                 table,
+                as_of,
                 filter,
+                group_by,
+                order_by,
             } => {
-                let _ = self.catalog.get_table(&table)?;
+                if let Some(table) = &table {
+                    let meta = self.catalog.get_table(table)?;
+                    if projections == [RawExpr::Star] {
+                        projections = meta
+                            .columns
+                            .iter()
+                            .map(|c| RawExpr::Column(c.name.clone()))
+                            .collect();
+                    }
+                }
+                let group_by = group_by
+                    .into_iter()
+                    .map(|expr| by_position(expr, &projections, "GROUP BY"))
+                    .collect::<Result<Vec<_>>>()?;
+                let order_by = order_by
+                    .into_iter()
+                    .map(|OrderBy { expr, descending }| {
+                        let expr = by_position(expr, &projections, "ORDER BY")?;
+                        Ok(OrderBy { expr, descending })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let (bf, semi_joins) = match filter {
+                    Some(f) => self.bind_where(f, table.as_deref())?,
+                    None => (None, Vec::new()),
+                };
+                let grouped = !group_by.is_empty()
+                    || projections.iter().any(has_aggregate)
+                    || order_by.iter().any(|o| has_aggregate(&o.expr));
+                if !grouped {
+                    let mut bp = Vec::new();
+                    for expr in projections {
+                        bp.push(self.bind_expr(expr, table.as_deref())?);
+                    }
+                    let mut keys = Vec::new();
+                    for OrderBy { expr, descending } in order_by {
+                        let expr = self.bind_expr(expr, table.as_deref())?;
+                        keys.push(SortKey { expr, descending });
+                    }
+                    return Ok(BoundStmt::Select {
+                        projections: bp,
+                        table,
+                        as_of,
+                        filter: bf,
+                        semi_joins,
+                        grouping: None,
+                        order_by: keys,
+                    });
+                }
+
+                let mut scope = GroupScope {
+                    table: table.as_deref(),
+                    keys: Vec::new(),
+                    group_by,
+                    aggregates: Vec::new(),
+                };
+                for expr in &scope.group_by {
+                    scope.keys.push(self.bind_expr(expr.clone(), scope.table)?);
+                }
                 let mut bp = Vec::new();
                 for expr in projections {
-                    bp.push(self.bind_expr(expr.clone(), &table)?);
+                    bp.push(self.bind_grouped(expr, &mut scope)?);
                 }
-                let bf = if let Some(f) = filter {
-                    Some(self.bind_expr(f, &table)?)
-                } else {
-                    None
+                let mut keys = Vec::new();
+                for OrderBy { expr, descending } in order_by {
+                    let expr = self.bind_grouped(expr, &mut scope)?;
+                    keys.push(SortKey { expr, descending });
+                }
+                let grouping = Grouping {
+                    keys: scope.keys,
+                    aggregates: scope.aggregates.into_iter().map(|(_, a)| a).collect(),
                 };
                 Ok(BoundStmt::Select {
                     projections: bp,
                     table,
+                    as_of,
                     filter: bf,
+                    semi_joins,
+                    grouping: Some(grouping),
+                    order_by: keys,
                 })
             }
+            UnionAll { selects, order_by } => self.bind_union_all(selects, order_by),
+            Explain { stmt, analyze } => match *stmt {
+                // Analyzing an INSERT or DELETE would write its rows.
+                Insert { .. } | Delete { .. } if analyze => {
+                    bail!("EXPLAIN ANALYZE only supports SELECT")
+                }
+                stmt @ (Select { .. } | UnionAll { .. } | Insert { .. } | Delete { .. }) => {
+                    Ok(BoundStmt::Explain {
+                        stmt: Box::new(self.bind(stmt)?),
+                        analyze,
+                    })
+                }
+                _ => bail!("EXPLAIN only supports SELECT, INSERT and DELETE"),
+            },
+            Reindex { index_name, table } => {
+                self.catalog.get_table(&table)?;
+                if !self
+                    .storage()
+                    .get_indexes(&table)
+                    .iter()
+                    .any(|i| i.name == index_name)
+                {
+                    bail!("Index '{}' not found on '{}'", index_name, table);
+                }
+                Ok(BoundStmt::Reindex { index_name, table })
+            }
+            Analyze { table } => {
+                self.catalog.get_table(&table)?;
+                Ok(BoundStmt::Analyze { table })
+            }
+            DropTable { table } => {
+                let table = self.catalog.get_table(&table)?.name.clone();
+                Ok(BoundStmt::DropTable { table })
+            }
+            ShowLocks => Ok(BoundStmt::ShowLocks),
+            ShowTables { schema } => {
+                if let Some(schema) = &schema
+                    && !self.storage().catalog.has_schema(schema)
+                {
+                    bail!("Schema '{}' not found", schema);
+                }
+                Ok(BoundStmt::ShowTables { schema })
+            }
+            ShowGrants => Ok(BoundStmt::ShowGrants),
+            Check => Ok(BoundStmt::Check),
+            Checkpoint => Ok(BoundStmt::Checkpoint),
+            // Without a list, every table in the catalog.
+            FlushTables { tables } => Ok(BoundStmt::FlushTables {
+                tables: self.tables_or_all(tables)?,
+            }),
+            Vacuum { tables } => Ok(BoundStmt::Vacuum {
+                tables: self.tables_or_all(tables)?,
+            }),
+            stmt @ (Begin | Commit | Rollback | SetConstraints { .. }) => {
+                bail!("{:?} controls a transaction and cannot be planned", stmt)
+            }
+            DeclareCursor { .. } | Fetch { .. } | CloseCursor { .. } => {
+                bail!("Cursors are kept by the server's sessions and cannot be planned")
+            }
+            CreateUser { .. } | DropUser { .. } => {
+                bail!("User management is handled by the server and cannot be planned")
+            }
+            Set { .. } | ShowSettings { .. } => {
+                bail!("Settings are kept by the server and cannot be planned")
+            }
+            SetVariable { .. } => {
+                bail!("Variables are kept by the session and cannot be planned")
+            }
+            Grant { .. } | Revoke { .. } => {
+                bail!("GRANT and REVOKE change the catalog directly and cannot be planned")
+            }
+            CreateSequence { .. } | DropSequence { .. } => {
+                bail!("Sequences are changed in the catalog directly and cannot be planned")
+            }
+            CreateView { .. } | DropView { .. } => {
+                bail!("Views are changed in the catalog directly and cannot be planned")
+            }
+            CreateTableAs { .. } => {
+                bail!("CREATE TABLE AS runs its SELECT itself and cannot be planned")
+            }
+            AddCheck { .. } | RenameTable { .. } | RenameIndex { .. } => {
+                bail!("ALTER changes the catalog directly and cannot be planned")
+            }
+            CreateSchema { .. } | DropSchema { .. } => {
+                bail!("Schemas are changed in the catalog directly and cannot be planned")
+            }
+            Copy { .. } => {
+                bail!("COPY reads the rows sent after it and cannot be planned; use /copy")
+            }
+        }
+    }
+
+    // Every SELECT has to make as many columns as the first, of the same
+    // types, and the ORDER BY names the first's columns.
+    fn bind_union_all(
+        &mut self,
+        selects: Vec<RawStmt>,
+        order_by: Vec<OrderBy>,
+    ) -> Result<BoundStmt> {
+        let mut bound = Vec::new();
+        let mut columns: Option<Vec<BoundExpr>> = None;
+        for (i, select) in selects.into_iter().enumerate() {
+            let select = self.bind(select)?;
+            let BoundStmt::Select { projections, .. } = &select else {
+                bail!("UNION ALL only combines SELECTs");
+            };
+            match &columns {
+                None => columns = Some(projections.clone()),
+                Some(first) if first.len() != projections.len() => bail!(
+                    "Each SELECT of a UNION ALL needs {} columns, SELECT {} has {}",
+                    first.len(),
+                    i + 1,
+                    projections.len()
+                ),
+                Some(first) => {
+                    if let Some(column) = first
+                        .iter()
+                        .zip(projections)
+                        .position(|(a, b)| a.data_type() != b.data_type())
+                    {
+                        bail!(
+                            "Column {} of SELECT {} is not of the type it is in the first SELECT",
+                            column + 1,
+                            i + 1
+                        );
+                    }
+                }
+            }
+            bound.push(select);
+        }
+        let columns = columns.unwrap_or_default();
+        let mut keys = Vec::new();
+        for OrderBy { expr, descending } in order_by {
+            let ordinal = match &expr {
+                RawExpr::Literal(Value::Int(n)) => usize::try_from(*n)
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .filter(|&n| n < columns.len())
+                    .with_context(|| {
+                        format!(
+                            "ORDER BY position {} is not in the result, which has {} columns",
+                            n,
+                            columns.len()
+                        )
+                    })?,
+                RawExpr::Column(name) => columns
+                    .iter()
+                    .position(|c| c.name().eq_ignore_ascii_case(name))
+                    .with_context(|| format!("ORDER BY {} is not in the result", name))?,
+                _ => bail!("ORDER BY of a UNION ALL takes a column of its result or its position"),
+            };
+            let column = &columns[ordinal];
+            let expr = BoundExpr::Column {
+                table: String::new(),
+                col: column.name(),
+                ordinal,
+                data_type: column.data_type(),
+                collation: column.collation(),
+            };
+            keys.push(SortKey { expr, descending });
+        }
+        Ok(BoundStmt::UnionAll {
+            selects: bound,
+            order_by: keys,
+        })
+    }
+
+    // The tables a statement names, or every table when it names none.
+    fn tables_or_all(&self, tables: Vec<String>) -> Result<Vec<String>> {
+        if tables.is_empty() {
+            let mut all: Vec<_> = self
+                .catalog
+                .tables
+                .values()
+                .map(|t| t.name.clone())
+                .collect();
+            all.sort();
+            return Ok(all);
+        }
+        tables
+            .iter()
+            .map(|table| Ok(self.catalog.get_table(table)?.name.clone()))
+            .collect()
+    }
+
+    // A WHERE, less the `[NOT] EXISTS` among the conditions it ANDs
+    // together, which become semi-joins.
+    fn bind_where(
+        &self,
+        filter: RawExpr,
+        table: Option<&str>,
+    ) -> Result<(Option<BoundExpr>, Vec<SemiJoin>)> {
+        let mut rest = Vec::new();
+        let mut semi_joins = Vec::new();
+        for term in conjuncts(filter) {
+            match (term, table) {
+                (RawExpr::Exists { select, negated }, Some(table)) => {
+                    semi_joins.push(self.bind_exists(*select, negated, table)?)
+                }
+                (term, _) => rest.push(term),
+            }
+        }
+        let filter = and_all(rest)
+            .map(|f| self.bind_expr(f, table))
+            .transpose()?;
+        Ok((filter, semi_joins))
+    }
+
+    // EXISTS over a SELECT of a table, which rows of `outer` are matched to
+    // by the equalities its WHERE has between the two. Inside it, a column
+    // named without a table is the subquery's own. Its other conditions
+    // only pick which of its rows count.
+    fn bind_exists(&self, select: RawStmt, negated: bool, outer: &str) -> Result<SemiJoin> {
+        let RawStmt::Select {
+            projections,
+            table: Some(inner),
+            as_of,
+            filter,
+            group_by,
+            ..
+        } = expand_views(&self.storage().catalog, select)?
+        else {
+            bail!("EXISTS needs a SELECT with a FROM");
+        };
+        if as_of.is_some() {
+            bail!("EXISTS cannot read a table AS OF an LSN");
+        }
+        // Aggregates without GROUP BY make a row even of no rows.
+        if !group_by.is_empty() || projections.iter().any(has_aggregate) {
+            bail!("EXISTS cannot take a SELECT that groups or aggregates");
         }
+        self.catalog.get_table(&inner)?;
+        let mut conditions = Vec::new();
+        let mut outer_keys = Vec::new();
+        let mut inner_keys = Vec::new();
+        for term in filter.map(conjuncts).unwrap_or_default() {
+            match reads(&term, &inner, outer)? {
+                (_, false) => conditions.push(term),
+                (false, true) => bail!(
+                    "A condition of EXISTS that only reads '{}' belongs in the outer WHERE",
+                    outer
+                ),
+                (true, true) => {
+                    let (inner_side, outer_side) = correlation(term, &inner, outer)?;
+                    let inner_key = self.bind_expr(inner_side, Some(&inner))?;
+                    let outer_key = self.bind_expr(outer_side, Some(outer))?;
+                    if inner_key.data_type() != outer_key.data_type() {
+                        bail!(
+                            "EXISTS matches '{}' to '{}', which are not of one type",
+                            outer_key.name(),
+                            inner_key.name()
+                        );
+                    }
+                    inner_keys.push(inner_key);
+                    outer_keys.push(outer_key);
+                }
+            }
+        }
+        let filter = and_all(conditions)
+            .map(|f| self.bind_expr(f, Some(&inner)))
+            .transpose()?;
+        Ok(SemiJoin {
+            table: inner,
+            filter,
+            outer_keys,
+            inner_keys,
+            anti: negated,
+        })
     }
 
-    fn bind_expr(&self, expr: RawExpr, table: &str) -> Result<BoundExpr> {
+    fn bind_expr(&self, expr: RawExpr, table: Option<&str>) -> Result<BoundExpr> {
         use RawExpr::*;
         match expr {
             Column(c) => {
+                let Some(table) = table else {
+                    bail!("There is no column '{}' to read without FROM", c);
+                };
                 let meta = self.catalog.get_table(table)?;
                 let lc = c.to_ascii_lowercase();
                 let &o = meta
                     .col_index
                     .get(&lc)
-                    .with_context(|| format!("Unknown column '{}' in '{}'", c, table))?;
+                    .with_context(|| UnknownName::column(&c, table))?;
                 let dt = meta.columns[o].data_type.clone();
                 Ok(BoundExpr::Column {
                     table: table.to_string(),
                     col: c,
                     ordinal: o,
                     data_type: dt,
+                    collation: meta.columns[o].collation,
                 })
             }
-            Literal(rv) => {
-                let v = match rv {
-                    RawValue::Int(i) => Value::Int(i),
-                    RawValue::String(s) => Value::String(s),
-                };
-                Ok(BoundExpr::Literal(v))
-            }
+            QualifiedColumn { table: of, column } => match table {
+                Some(table) if names_table(&of, table) => {
+                    self.bind_expr(Column(column), Some(table))
+                }
+                _ => bail!("'{}' is not a table this query reads", of),
+            },
+            Literal(v) => Ok(BoundExpr::Literal(v)),
+            Variable(name) => match self.catalog.variables.get(&name) {
+                Some(value) => Ok(BoundExpr::Literal(value.clone())),
+                None => bail!("Variable '@{}' is not set", name),
+            },
             BinaryOp { left, op, right } => {
                 let l = self.bind_expr(*left, table)?;
                 let r = self.bind_expr(*right, table)?;
@@ -258,6 +1060,747 @@ impl<'a> Binder<'a> {
                     data_type: DataType::Int,
                 })
             }
+            Call { name, args } => {
+                self.bind_call(&name, args, &mut |arg| self.bind_expr(arg, table))
+            }
+            Star => bail!("'*' can only stand for the whole SELECT list"),
+            Exists { .. } => {
+                bail!("EXISTS can only be one of the conditions a SELECT's WHERE ANDs together")
+            }
+        }
+    }
+
+    // Binds `expr` against the rows grouping makes. It can read what the
+    // SELECT groups by, as written there, and aggregates of anything in the
+    // table.
+    fn bind_grouped(&self, expr: RawExpr, scope: &mut GroupScope) -> Result<BoundExpr> {
+        let table = scope.table.unwrap_or_default().to_string();
+        if let Some(i) = scope.group_by.iter().position(|key| *key == expr) {
+            let key = &scope.keys[i];
+            return Ok(BoundExpr::Column {
+                table,
+                col: key.name(),
+                ordinal: i,
+                data_type: key.data_type(),
+                collation: key.collation(),
+            });
+        }
+        match expr {
+            RawExpr::Call { name, args } => {
+                let Some(function) = aggregate_of(&name, &args) else {
+                    return self.bind_call(&name, args, &mut |arg| self.bind_grouped(arg, scope));
+                };
+                let call = RawExpr::Call {
+                    name: name.clone(),
+                    args: args.clone(),
+                };
+                let j = match scope.aggregates.iter().position(|(raw, _)| *raw == call) {
+                    Some(j) => j,
+                    None => {
+                        let arg = match (function, <[RawExpr; 1]>::try_from(args)) {
+                            (AggregateFunction::Count, Ok([RawExpr::Star])) => None,
+                            (_, Ok([arg])) => Some(self.bind_expr(arg, scope.table)?),
+                            _ => bail!("{} takes one argument", name),
+                        };
+                        if function == AggregateFunction::Sum
+                            && arg.as_ref().is_some_and(|a| a.data_type() != DataType::Int)
+                        {
+                            bail!("SUM needs an INT argument");
+                        }
+                        scope.aggregates.push((call, Aggregate { function, arg }));
+                        scope.aggregates.len() - 1
+                    }
+                };
+                let (data_type, collation) = match (function, &scope.aggregates[j].1.arg) {
+                    (AggregateFunction::Min | AggregateFunction::Max, Some(arg)) => {
+                        (arg.data_type(), arg.collation())
+                    }
+                    _ => (DataType::Int, Collation::Binary),
+                };
+                Ok(BoundExpr::Column {
+                    table,
+                    col: name.to_ascii_lowercase(),
+                    ordinal: scope.keys.len() + j,
+                    data_type,
+                    collation,
+                })
+            }
+            RawExpr::Column(c) => {
+                // An unknown column is reported as such first.
+                self.bind_expr(RawExpr::Column(c.clone()), scope.table)?;
+                bail!(
+                    "Column '{}' must appear in GROUP BY or be used in an aggregate",
+                    c
+                )
+            }
+            RawExpr::QualifiedColumn { table, column } => {
+                let qualified = RawExpr::QualifiedColumn {
+                    table: table.clone(),
+                    column: column.clone(),
+                };
+                self.bind_expr(qualified, scope.table)?;
+                bail!(
+                    "Column '{}.{}' must appear in GROUP BY or be used in an aggregate",
+                    table,
+                    column
+                )
+            }
+            RawExpr::BinaryOp { left, op, right } => Ok(BoundExpr::BinaryOp {
+                left: Box::new(self.bind_grouped(*left, scope)?),
+                op,
+                right: Box::new(self.bind_grouped(*right, scope)?),
+                data_type: DataType::Int,
+            }),
+            other @ (RawExpr::Literal(_) | RawExpr::Variable(_) | RawExpr::Exists { .. }) => {
+                self.bind_expr(other, scope.table)
+            }
+            RawExpr::Star => bail!("'*' can only stand for the whole SELECT list"),
+        }
+    }
+
+    // A `Function` checks its own arguments. RANDOM takes none and SETSEED
+    // an INT. NEXTVAL and CURRVAL each take the name of a sequence as a
+    // string. Aggregates are bound by `bind_grouped`, so one found here is
+    // where it cannot be.
+    fn bind_call(
+        &self,
+        name: &str,
+        args: Vec<RawExpr>,
+        bind_arg: &mut dyn FnMut(RawExpr) -> Result<BoundExpr>,
+    ) -> Result<BoundExpr> {
+        if aggregate_of(name, &args).is_some() {
+            bail!(
+                "{} cannot be used here; aggregates go in the SELECT list and ORDER BY",
+                name
+            );
+        }
+        if let Some(function) = Function::parse(name) {
+            let args = args.into_iter().map(bind_arg).collect::<Result<Vec<_>>>()?;
+            function.check(&args)?;
+            return Ok(BoundExpr::Call { function, args });
+        }
+        match name {
+            "RANDOM" if args.is_empty() => {
+                return Ok(BoundExpr::Random(self.catalog.random.clone()));
+            }
+            "RANDOM" => bail!("RANDOM takes no arguments"),
+            "SETSEED" => {
+                let Ok([seed]) = <[RawExpr; 1]>::try_from(args) else {
+                    bail!("SETSEED takes one argument");
+                };
+                let seed = bind_arg(seed)?;
+                if seed.data_type() != DataType::Int {
+                    bail!("SETSEED needs an INT argument");
+                }
+                return Ok(BoundExpr::SetSeed(
+                    self.catalog.random.clone(),
+                    Box::new(seed),
+                ));
+            }
+            "NEXTVAL" | "CURRVAL" => {}
+            _ => bail!("Unknown function '{}'", name),
+        }
+        let [RawExpr::Literal(Value::String(sequence))] = args.as_slice() else {
+            bail!("{} takes the name of a sequence as a string", name);
+        };
+        let storage = self.storage();
+        let sequence = storage.catalog.get_sequence(sequence)?.clone();
+        if name == "CURRVAL" {
+            return Ok(BoundExpr::CurrVal(sequence));
+        }
+        // Values are handed out by writers, which log what they reserve in
+        // their transaction.
+        let StorageAccess::Exclusive(_) = &self.storage else {
+            bail!("NEXTVAL cannot be bound against shared storage");
+        };
+        let log = storage.wal.clone().zip(storage.tx_id);
+        Ok(BoundExpr::NextVal(NextVal::new(sequence, log)))
+    }
+}
+
+// Rewrites every table and view `stmt` names into its key in the catalog.
+// A name with a schema is looked for there, one without in each schema of
+// `search_path` in turn, the first to have it winning; a temporary table,
+// which `is_temp` tells apart, hides them all. A name found nowhere is
+// given the first schema on the path that exists, which is also where
+// CREATE puts an unqualified table or view, and left for whoever looks it
+// up to report, as is a schema that does not exist.
+pub fn resolve_names(
+    catalog: &storage::Catalog,
+    search_path: &[String],
+    is_temp: impl Fn(&str) -> bool,
+    stmt: RawStmt,
+) -> Result<RawStmt> {
+    let creates_in = || {
+        search_path
+            .iter()
+            .find(|schema| catalog.has_schema(schema))
+            .ok_or_else(|| anyhow!("No schema on the search path exists to create in"))
+    };
+    let existing = |name: String| -> Result<String> {
+        if let Some((schema, bare)) = name.split_once('.') {
+            return Ok(storage::qualified_name(schema, bare));
+        }
+        if is_temp(&name) {
+            return Ok(name);
+        }
+        let found = search_path
+            .iter()
+            .map(|schema| storage::qualified_name(schema, &name))
+            .find(|key| catalog.tables.contains_key(key) || catalog.views.contains_key(key));
+        match (found, creates_in()) {
+            (Some(key), _) => Ok(key),
+            (None, Ok(schema)) => Ok(storage::qualified_name(schema, &name)),
+            (None, Err(_)) => Ok(name),
+        }
+    };
+    let new = |name: String| -> Result<String> {
+        match name.split_once('.') {
+            Some(_) => existing(name),
+            None => Ok(storage::qualified_name(creates_in()?, &name)),
+        }
+    };
+    Ok(match stmt {
+        RawStmt::Select {
+            projections,
+            table,
+            as_of,
+            filter,
+            group_by,
+            order_by,
+        } => {
+            let is_temp: &dyn Fn(&str) -> bool = &is_temp;
+            let resolve = |select| resolve_names(catalog, search_path, is_temp, select);
+            RawStmt::Select {
+                projections,
+                table: table.map(existing).transpose()?,
+                as_of,
+                filter: filter.map(|f| resolve_exists(f, &resolve)).transpose()?,
+                group_by,
+                order_by,
+            }
+        }
+        RawStmt::UnionAll { selects, order_by } => {
+            let is_temp: &dyn Fn(&str) -> bool = &is_temp;
+            RawStmt::UnionAll {
+                selects: selects
+                    .into_iter()
+                    .map(|select| resolve_names(catalog, search_path, is_temp, select))
+                    .collect::<Result<_>>()?,
+                order_by,
+            }
+        }
+        RawStmt::Explain { stmt, analyze } => RawStmt::Explain {
+            stmt: Box::new(resolve_names(catalog, search_path, is_temp, *stmt)?),
+            analyze,
+        },
+        RawStmt::DeclareCursor { name, select } => RawStmt::DeclareCursor {
+            name,
+            select: Box::new(resolve_names(catalog, search_path, is_temp, *select)?),
+        },
+        RawStmt::CreateTable {
+            name,
+            columns,
+            temporary,
+            foreign_keys,
+            checks,
+        } => {
+            if temporary && name.contains('.') {
+                bail!(
+                    "A temporary table is in no schema, so '{}' cannot be one",
+                    name
+                );
+            }
+            let foreign_keys = foreign_keys
+                .into_iter()
+                .map(|mut key| {
+                    key.parent = existing(key.parent)?;
+                    Ok(key)
+                })
+                .collect::<Result<_>>()?;
+            RawStmt::CreateTable {
+                name: match temporary {
+                    true => name,
+                    false => new(name)?,
+                },
+                columns,
+                temporary,
+                foreign_keys,
+                checks,
+            }
+        }
+        RawStmt::CreateTableAs {
+            name,
+            columns,
+            temporary,
+            select,
+        } => {
+            if temporary && name.contains('.') {
+                bail!(
+                    "A temporary table is in no schema, so '{}' cannot be one",
+                    name
+                );
+            }
+            RawStmt::CreateTableAs {
+                name: match temporary {
+                    true => name,
+                    false => new(name)?,
+                },
+                columns,
+                temporary,
+                select: Box::new(resolve_names(catalog, search_path, is_temp, *select)?),
+            }
+        }
+        RawStmt::CreateView {
+            name,
+            select,
+            query,
+        } => RawStmt::CreateView {
+            name: new(name)?,
+            select: Box::new(resolve_names(catalog, search_path, is_temp, *select)?),
+            query,
+        },
+        // The new name is in the table's schema.
+        RawStmt::RenameTable { table, new_name } => {
+            let table = existing(table)?;
+            let new_name = match is_temp(&table) {
+                true => new_name,
+                false => storage::qualified_name(storage::split_name(&table).0, &new_name),
+            };
+            RawStmt::RenameTable { table, new_name }
+        }
+        RawStmt::AddCheck { table, check } => RawStmt::AddCheck {
+            table: existing(table)?,
+            check,
+        },
+        RawStmt::CreateIndex {
+            index_name,
+            table,
+            columns,
+            using,
+        } => RawStmt::CreateIndex {
+            index_name,
+            table: existing(table)?,
+            columns,
+            using,
+        },
+        RawStmt::Insert {
+            table,
+            columns,
+            rows,
+        } => RawStmt::Insert {
+            table: existing(table)?,
+            columns,
+            rows,
+        },
+        RawStmt::Delete { table, filter } => RawStmt::Delete {
+            table: existing(table)?,
+            filter,
+        },
+        RawStmt::Copy {
+            table,
+            columns,
+            format,
+        } => RawStmt::Copy {
+            table: existing(table)?,
+            columns,
+            format,
+        },
+        RawStmt::Reindex { index_name, table } => RawStmt::Reindex {
+            index_name,
+            table: existing(table)?,
+        },
+        RawStmt::Analyze { table } => RawStmt::Analyze {
+            table: existing(table)?,
+        },
+        RawStmt::FlushTables { tables } => RawStmt::FlushTables {
+            tables: tables.into_iter().map(existing).collect::<Result<_>>()?,
+        },
+        RawStmt::Vacuum { tables } => RawStmt::Vacuum {
+            tables: tables.into_iter().map(existing).collect::<Result<_>>()?,
+        },
+        RawStmt::DropTable { table } => RawStmt::DropTable {
+            table: existing(table)?,
+        },
+        RawStmt::DropView { name } => RawStmt::DropView {
+            name: existing(name)?,
+        },
+        RawStmt::Grant {
+            privilege,
+            columns,
+            table,
+            user,
+        } => RawStmt::Grant {
+            privilege,
+            columns,
+            table: existing(table)?,
+            user,
+        },
+        RawStmt::Revoke {
+            privilege,
+            columns,
+            table,
+            user,
+        } => RawStmt::Revoke {
+            privilege,
+            columns,
+            table: existing(table)?,
+            user,
+        },
+        stmt => stmt,
+    })
+}
+
+// Rewrites a SELECT from a view into one from the table under it: the
+// view's filter is ANDed with the query's, and the query may only read the
+// columns the view lists. A view over a view is rewritten in turn until a
+// table is reached; one that leads back to itself is an error. A temporary
+// table hides a view of its name as it does a table.
+pub fn expand_views(catalog: &storage::Catalog, stmt: RawStmt) -> Result<RawStmt> {
+    let RawStmt::UnionAll { selects, order_by } = stmt else {
+        return expand(catalog, stmt, &mut Vec::new());
+    };
+    Ok(RawStmt::UnionAll {
+        selects: selects
+            .into_iter()
+            .map(|select| expand(catalog, select, &mut Vec::new()))
+            .collect::<Result<_>>()?,
+        order_by,
+    })
+}
+
+fn expand(catalog: &storage::Catalog, stmt: RawStmt, seen: &mut Vec<String>) -> Result<RawStmt> {
+    let RawStmt::Select {
+        projections,
+        table: Some(table),
+        as_of,
+        filter,
+        group_by,
+        order_by,
+    } = stmt
+    else {
+        return Ok(stmt);
+    };
+    let view = match catalog.is_temp(&table) {
+        true => None,
+        false => catalog.views.get(&table),
+    };
+    let Some(view) = view else {
+        return Ok(RawStmt::Select {
+            projections,
+            table: Some(table),
+            as_of,
+            filter,
+            group_by,
+            order_by,
+        });
+    };
+    if seen.contains(&view.name) {
+        seen.push(view.name.clone());
+        bail!("Views refer back to themselves: {}", seen.join(" -> "));
+    }
+    seen.push(view.name.clone());
+    let mut inner = Parser::parse_one(&view.query)
+        .map_err(|diagnostics| anyhow!("View '{}' no longer parses: {}", view.name, diagnostics))?;
+    // The query is kept as written, but the view reads the table its name
+    // was resolved to when the view was made, whatever the search path is.
+    if let RawStmt::Select { table, .. } = &mut inner {
+        *table = Some(view.table.clone());
+    }
+    let RawStmt::Select {
+        projections: listed,
+        table: Some(under),
+        filter: inner_filter,
+        ..
+    } = expand(catalog, inner, seen)?
+    else {
+        bail!("View '{}' does not select from a table", view.name);
+    };
+
+    // What the view shows, checked against the table as it is now.
+    let info = catalog
+        .get_table(&under)
+        .with_context(|| format!("View '{}' reads '{}', which is gone", view.name, under))?;
+    let columns: Vec<String> = match listed.as_slice() {
+        [RawExpr::Star] => info.columns.iter().map(|c| c.name.clone()).collect(),
+        _ => listed
+            .iter()
+            .map(|expr| match expr {
+                RawExpr::Column(c) => Ok(c.clone()),
+                _ => Err(anyhow!("View '{}' can only list columns", view.name)),
+            })
+            .collect::<Result<_>>()?,
+    };
+    let known = |c: &&String| info.columns.iter().any(|k| k.name.eq_ignore_ascii_case(c));
+    if let Some(missing) = columns.iter().find(|c| !known(c)) {
+        bail!(
+            "View '{}' uses column '{}', which '{}' no longer has",
+            view.name,
+            missing,
+            under
+        );
+    }
+
+    // The view's table is not the one a query of it names, so it cannot
+    // name one before a column yet.
+    let check = |expr: &RawExpr| match first_missing(expr, &columns) {
+        _ if names_a_table(expr) => Err(anyhow!(
+            "A query of view '{}' cannot name a table before a column or use EXISTS yet",
+            view.name
+        )),
+        Some(c) => Err(anyhow!(UnknownName::column(c, &view.name))),
+        None => Ok(()),
+    };
+    let projections = match projections.as_slice() {
+        [RawExpr::Star] => columns.iter().cloned().map(RawExpr::Column).collect(),
+        _ => projections,
+    };
+    projections.iter().try_for_each(check)?;
+    group_by.iter().try_for_each(check)?;
+    order_by.iter().try_for_each(|o| check(&o.expr))?;
+    if let Some(filter) = &filter {
+        check(filter)?;
+    }
+    let filter = match (inner_filter, filter) {
+        (Some(inner), Some(outer)) => Some(RawExpr::BinaryOp {
+            left: Box::new(inner),
+            op: BinaryOp::And,
+            right: Box::new(outer),
+        }),
+        (inner, outer) => inner.or(outer),
+    };
+    Ok(RawStmt::Select {
+        projections,
+        table: Some(under),
+        as_of,
+        filter,
+        group_by,
+        order_by,
+    })
+}
+
+// Resolves the tables of the EXISTS in a WHERE, the one place they are
+// bound.
+fn resolve_exists(expr: RawExpr, resolve: &dyn Fn(RawStmt) -> Result<RawStmt>) -> Result<RawExpr> {
+    Ok(match expr {
+        RawExpr::Exists { select, negated } => RawExpr::Exists {
+            select: Box::new(resolve(*select)?),
+            negated,
+        },
+        RawExpr::BinaryOp { left, op, right } => RawExpr::BinaryOp {
+            left: Box::new(resolve_exists(*left, resolve)?),
+            op,
+            right: Box::new(resolve_exists(*right, resolve)?),
+        },
+        other => other,
+    })
+}
+
+// Binds a CHECK constraint's condition against the columns of `table`, in
+// the order rows store them. It can read those and constants, nothing else.
+pub fn bind_check(table: &storage::TableInfo, condition: &str) -> Result<BoundExpr> {
+    let expr = Parser::new(condition)?.parse_condition()?;
+    bind_row_expr(table, expr)
+}
+
+fn bind_row_expr(table: &storage::TableInfo, expr: RawExpr) -> Result<BoundExpr> {
+    match expr {
+        RawExpr::Column(c) => {
+            let (ordinal, column) = table
+                .columns
+                .iter()
+                .enumerate()
+                .find(|(_, col)| col.name.eq_ignore_ascii_case(&c))
+                .with_context(|| UnknownName::column(&c, &table.name))?;
+            Ok(BoundExpr::Column {
+                table: table.name.clone(),
+                col: column.name.clone(),
+                ordinal,
+                data_type: match column.data_type {
+                    storage::DataType::Int => DataType::Int,
+                    storage::DataType::String => DataType::Varchar,
+                },
+                collation: column.collation,
+            })
+        }
+        RawExpr::Literal(v) => Ok(BoundExpr::Literal(v)),
+        RawExpr::BinaryOp { left, op, right } => Ok(BoundExpr::BinaryOp {
+            left: Box::new(bind_row_expr(table, *left)?),
+            op,
+            right: Box::new(bind_row_expr(table, *right)?),
+            data_type: DataType::Int,
+        }),
+        RawExpr::Call { name, .. } => bail!("A CHECK constraint cannot call {}", name),
+        RawExpr::Star => bail!("A CHECK constraint cannot use '*'"),
+        RawExpr::QualifiedColumn { .. } => {
+            bail!("A CHECK constraint names its table's columns without the table")
         }
+        RawExpr::Exists { .. } => bail!("A CHECK constraint cannot use EXISTS"),
+        RawExpr::Variable(name) => bail!("A CHECK constraint cannot use variable '@{}'", name),
+    }
+}
+
+// A number in GROUP BY or ORDER BY stands for that item of the SELECT
+// list, counting from 1.
+fn by_position(expr: RawExpr, projections: &[RawExpr], clause: &str) -> Result<RawExpr> {
+    let RawExpr::Literal(Value::Int(n)) = expr else {
+        return Ok(expr);
+    };
+    usize::try_from(n)
+        .ok()
+        .and_then(|n| projections.get(n.checked_sub(1)?))
+        .cloned()
+        .with_context(|| {
+            format!(
+                "{} position {} is not in the SELECT list, which has {} items",
+                clause,
+                n,
+                projections.len()
+            )
+        })
+}
+
+fn has_aggregate(expr: &RawExpr) -> bool {
+    match expr {
+        RawExpr::Call { name, args } => {
+            aggregate_of(name, args).is_some() || args.iter().any(has_aggregate)
+        }
+        RawExpr::BinaryOp { left, right, .. } => has_aggregate(left) || has_aggregate(right),
+        RawExpr::Column(_)
+        | RawExpr::QualifiedColumn { .. }
+        | RawExpr::Literal(_)
+        | RawExpr::Variable(_)
+        | RawExpr::Star
+        | RawExpr::Exists { .. } => false,
+    }
+}
+
+// Whether `expr` names a table before a column or has EXISTS.
+fn names_a_table(expr: &RawExpr) -> bool {
+    match expr {
+        RawExpr::QualifiedColumn { .. } | RawExpr::Exists { .. } => true,
+        RawExpr::Column(_) | RawExpr::Literal(_) | RawExpr::Variable(_) | RawExpr::Star => false,
+        RawExpr::BinaryOp { left, right, .. } => names_a_table(left) || names_a_table(right),
+        RawExpr::Call { args, .. } => args.iter().any(names_a_table),
+    }
+}
+
+// Whether `name`, as a query writes it before a column, is the table
+// `key` in the catalog, with its schema or without.
+pub fn names_table(name: &str, key: &str) -> bool {
+    let (schema, bare) = storage::split_name(key);
+    match name.split_once('.') {
+        Some((s, b)) => s.eq_ignore_ascii_case(schema) && b.eq_ignore_ascii_case(bare),
+        None => name.eq_ignore_ascii_case(bare),
+    }
+}
+
+// Which of the subquery's table `inner` and the query's `outer` an
+// expression in an EXISTS reads. A column without a table is the
+// subquery's.
+fn reads(expr: &RawExpr, inner: &str, outer: &str) -> Result<(bool, bool)> {
+    Ok(match expr {
+        RawExpr::Column(_) => (true, false),
+        RawExpr::QualifiedColumn { table, column } => {
+            match (names_table(table, inner), names_table(table, outer)) {
+                (true, true) => bail!(
+                    "'{}.{}' could be of either table; EXISTS cannot tell a table from itself yet",
+                    table,
+                    column
+                ),
+                (false, false) => bail!("'{}' is not a table this query reads", table),
+                sides => sides,
+            }
+        }
+        RawExpr::Literal(_) | RawExpr::Variable(_) | RawExpr::Star => (false, false),
+        RawExpr::BinaryOp { left, right, .. } => {
+            let (l_inner, l_outer) = reads(left, inner, outer)?;
+            let (r_inner, r_outer) = reads(right, inner, outer)?;
+            (l_inner || r_inner, l_outer || r_outer)
+        }
+        RawExpr::Call { args, .. } => {
+            let mut sides = (false, false);
+            for arg in args {
+                let (i, o) = reads(arg, inner, outer)?;
+                sides = (sides.0 || i, sides.1 || o);
+            }
+            sides
+        }
+        RawExpr::Exists { .. } => bail!("EXISTS cannot be inside another EXISTS yet"),
+    })
+}
+
+// The sides of a condition of EXISTS that reads both tables, the
+// subquery's first. It has to be an equality of something of each.
+//
+// No key is NULL yet. Once one can be, `=` matches nothing to it, so NOT
+// EXISTS keeps that row, where IS NOT DISTINCT FROM matches NULL to NULL.
+fn correlation(term: RawExpr, inner: &str, outer: &str) -> Result<(RawExpr, RawExpr)> {
+    let mismatch = || {
+        anyhow!(
+            "A condition of EXISTS reading both '{}' and '{}' has to be an equality between the two",
+            inner,
+            outer
+        )
+    };
+    let RawExpr::BinaryOp {
+        left,
+        op: BinaryOp::Eq | BinaryOp::IsNotDistinctFrom,
+        right,
+    } = term
+    else {
+        return Err(mismatch());
+    };
+    match (reads(&left, inner, outer)?, reads(&right, inner, outer)?) {
+        ((true, false), (false, true)) => Ok((*left, *right)),
+        ((false, true), (true, false)) => Ok((*right, *left)),
+        _ => Err(mismatch()),
+    }
+}
+
+// The conditions `expr` ANDs together.
+fn conjuncts(expr: RawExpr) -> Vec<RawExpr> {
+    match expr {
+        RawExpr::BinaryOp {
+            left,
+            op: BinaryOp::And,
+            right,
+        } => {
+            let mut terms = conjuncts(*left);
+            terms.extend(conjuncts(*right));
+            terms
+        }
+        term => vec![term],
+    }
+}
+
+fn and_all(terms: Vec<RawExpr>) -> Option<RawExpr> {
+    terms.into_iter().reduce(|left, right| RawExpr::BinaryOp {
+        left: Box::new(left),
+        op: BinaryOp::And,
+        right: Box::new(right),
+    })
+}
+
+// The first column `expr` reads that is not among `columns`.
+fn first_missing<'e>(expr: &'e RawExpr, columns: &[String]) -> Option<&'e str> {
+    match expr {
+        RawExpr::Column(c) | RawExpr::QualifiedColumn { column: c, .. } => {
+            (!columns.iter().any(|col| col.eq_ignore_ascii_case(c))).then_some(c)
+        }
+        RawExpr::Literal(_) | RawExpr::Variable(_) | RawExpr::Star | RawExpr::Exists { .. } => None,
+        RawExpr::BinaryOp { left, right, .. } => {
+            first_missing(left, columns).or_else(|| first_missing(right, columns))
+        }
+        RawExpr::Call { args, .. } => args.iter().find_map(|arg| first_missing(arg, columns)),
+    }
+}
+
+fn first_column(expr: &RawExpr) -> Option<&str> {
+    match expr {
+        RawExpr::Column(c) | RawExpr::QualifiedColumn { column: c, .. } => Some(c),
+        RawExpr::Literal(_) | RawExpr::Variable(_) | RawExpr::Star | RawExpr::Exists { .. } => None,
+        RawExpr::BinaryOp { left, right, .. } => first_column(left).or_else(|| first_column(right)),
+        RawExpr::Call { args, .. } => args.iter().find_map(first_column),
     }
 }
@@ -0,0 +1,109 @@
+use anyhow::{Result, anyhow, bail};
+
+// Times are INTs: seconds since 1970-01-01 00:00:00 UTC, and intervals are
+// INTs of seconds too, so `ts + INTERVAL '7' DAY` is plain addition and two
+// times compare as numbers do. Every calendar is worked out in UTC.
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+// The seconds in `INTERVAL '<count>' <unit>`. Months and years differ in
+// length, so they cannot be counted in seconds and are refused.
+pub fn interval(count: &str, unit: &str) -> Result<i64> {
+    let unit_seconds = match unit.to_ascii_uppercase().trim_end_matches('S') {
+        "SECOND" => 1,
+        "MINUTE" => MINUTE,
+        "HOUR" => HOUR,
+        "DAY" => DAY,
+        "WEEK" => WEEK,
+        "MONTH" | "YEAR" => bail!("An INTERVAL cannot be in {}s, which differ in length", unit),
+        _ => bail!("Unknown INTERVAL unit '{}'", unit),
+    };
+    let count: i64 = count
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("INTERVAL needs a whole number, not '{}'", count))?;
+    count
+        .checked_mul(unit_seconds)
+        .ok_or_else(|| anyhow!("INTERVAL '{}' {} is out of range", count, unit))
+}
+
+// DATE_TRUNC: the start of the second, minute, hour, day, week (from
+// Monday), month or year `time` falls in.
+pub fn trunc(unit: &str, time: i64) -> Result<i64> {
+    let days = time.div_euclid(DAY);
+    Ok(match &unit.to_ascii_uppercase()[..] {
+        "SECOND" => time,
+        "MINUTE" => time - time.rem_euclid(MINUTE),
+        "HOUR" => time - time.rem_euclid(HOUR),
+        "DAY" => time - time.rem_euclid(DAY),
+        // 1970-01-01 was a Thursday, three days after a Monday.
+        "WEEK" => return seconds(days - (days + 3).rem_euclid(7)),
+        "MONTH" => {
+            let (year, month, _) = civil(days);
+            return seconds(days_from_civil(year, month, 1));
+        }
+        "YEAR" => return seconds(days_from_civil(civil(days).0, 1, 1)),
+        _ => bail!("Unknown DATE_TRUNC unit '{}'", unit),
+    })
+}
+
+// EXTRACT: one field of `time`. DOW counts from Sunday as 0, DOY from
+// January 1st as 1, and EPOCH is `time` itself.
+pub fn extract(field: &str, time: i64) -> Result<i64> {
+    let days = time.div_euclid(DAY);
+    let (year, month, day) = civil(days);
+    Ok(match &field.to_ascii_uppercase()[..] {
+        "YEAR" => year,
+        "MONTH" => month,
+        "DAY" => day,
+        "HOUR" => time.rem_euclid(DAY) / HOUR,
+        "MINUTE" => time.rem_euclid(HOUR) / MINUTE,
+        "SECOND" => time.rem_euclid(MINUTE),
+        "DOW" => (days + 4).rem_euclid(7),
+        "DOY" => days - days_from_civil(year, 1, 1) + 1,
+        "EPOCH" => time,
+        _ => bail!("Unknown EXTRACT field '{}'", field),
+    })
+}
+
+// Whether `unit` is one DATE_TRUNC, or with `extract` EXTRACT, knows.
+pub fn check_unit(unit: &str, extract: bool) -> Result<()> {
+    match extract {
+        true => self::extract(unit, 0).map(|_| ()),
+        false => trunc(unit, 0).map(|_| ()),
+    }
+}
+
+fn seconds(days: i64) -> Result<i64> {
+    days.checked_mul(DAY)
+        .ok_or_else(|| anyhow!("Time out of range"))
+}
+
+// Year, month and day of the `days`th day after 1970-01-01, in the
+// proleptic Gregorian calendar; Howard Hinnant's `civil_from_days`.
+fn civil(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// The reverse of `civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
@@ -1,112 +1,226 @@
-
-
-use crate::index::bplustree::BPlusTree;
-use crate::query::binder::{BoundExpr, Catalog, Value};
+use crate::index::bplustree;
+use crate::index::hash_index::HashIndex;
+use crate::index::key::Key;
+use crate::net::row::Schema;
+use crate::query::binder::{
+    Aggregate, AggregateFunction, BoundExpr, Collation, Function, SortKey, Value,
+};
+use crate::query::datetime;
+use crate::query::memory::{DEFAULT_WORK_MEM, MemoryTracker, SpillReader, SpillWriter, row_bytes};
 use crate::query::parser::BinaryOp; 
-use crate::storage::record::RID;
-use crate::storage::storage::Storage;
-use anyhow::{Result, anyhow};
-use std::collections::VecDeque;
+use crate::query::physical_planner::PhysicalPlan;
+use crate::storage::check;
+use crate::storage::record::{Page, RID};
+use crate::storage::storage::{
+    IndexInfo, Privilege, ReadView, ScanErrorPolicy, SchemaChanged, Storage, TableInfo, split_name,
+};
+use crate::tx::history;
+use crate::tx::lock_manager::LockMode;
+use crate::tx::recovery_manager;
+use crate::tx::log_manager::Lsn;
+use anyhow::{Result, anyhow, bail};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque, btree_map};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tracing::warn;
 
 pub type Tuple = Vec<Value>;
 
-
 pub trait PhysicalOp {
-    
     fn open(&mut self) -> Result<()>;
     
     fn next(&mut self) -> Result<Option<Tuple>>;
     
     fn close(&mut self) -> Result<()>;
-}
 
+    // The heap row the last `next` came from, for a scan of a table's rows
+    // and a filter over one; None for anything else.
+    fn rid(&self) -> Option<RID> {
+        None
+    }
+}
 
 pub struct Executor<'a> {
     root: Box<dyn PhysicalOp + 'a>,
+    schema: Arc<Schema>,
+    affected: Option<usize>,
 }
 
 impl<'a> Executor<'a> {
     pub fn new(root: Box<dyn PhysicalOp + 'a>) -> Self {
-        Executor { root }
+        Executor {
+            root,
+            schema: Arc::default(),
+            affected: None,
+        }
+    }
+
+    // The columns of the rows, from the plan the operators were built from.
+    pub fn with_schema(mut self, schema: Schema) -> Self {
+        self.schema = Arc::new(schema);
+        self
+    }
+
+    pub fn schema(&self) -> &Arc<Schema> {
+        &self.schema
+    }
+
+    pub fn columns(&self) -> &[String] {
+        self.schema.names()
+    }
+
+    // Rows the statement writes once it has run, as the plan counted them.
+    pub fn with_affected(mut self, affected: Option<usize>) -> Self {
+        self.affected = affected;
+        self
     }
 
+    pub fn affected(&self) -> Option<usize> {
+        self.affected
+    }
     
     pub fn execute(&mut self) -> Result<Vec<Tuple>> {
-        self.root.open()?;
+        self.open()?;
         let mut rows = Vec::new();
-        while let Some(row) = self.root.next()? {
+        while let Some(row) = self.next_row()? {
             rows.push(row);
         }
-        self.root.close()?;
+        self.close()?;
         Ok(rows)
     }
-}
-
-
 
+    // Pulling rows one at a time lets a caller send them on before the
+    // whole result exists.
+    pub fn open(&mut self) -> Result<()> {
+        self.root.open()
+    }
 
+    pub fn next_row(&mut self) -> Result<Option<Tuple>> {
+        self.root.next()
+    }
 
+    pub fn close(&mut self) -> Result<()> {
+        self.root.close()
+    }
+}
 
+// Returns a table's rows in the order they were added, as its row list
+// keeps them, whatever pages they landed on and whatever other tables
+// wrote in between. The same rows always come back the same way.
+//
+// The pages those rows are on are known up front, so the buffer pool is
+// asked to read ahead of the scan a few pages at a time. Rows are decoded
+// straight from a copy of their page, made once for all the rows on it.
 pub struct SeqScanOp<'a> {
-    storage: &'a mut Storage,
-    catalog: &'a Catalog,
+    view: ReadView<'a>,
     table: String,
     predicate: Option<BoundExpr>,
-    
+
     rids: VecDeque<RID>,
+    last: Option<RID>,
+    page: Option<(u64, Page)>,
+    // Each page as the scan first comes to it, the next one it will, and
+    // the first one not read ahead yet.
+    pages: Vec<u64>,
+    next_page: usize,
+    read_until: usize,
 }
 
 impl<'a> SeqScanOp<'a> {
-    pub fn new(
-        storage: &'a mut Storage,
-        catalog: &'a Catalog,
-        table: String,
-        predicate: Option<BoundExpr>,
-    ) -> Self {
+    pub fn new(view: ReadView<'a>, table: String, predicate: Option<BoundExpr>) -> Self {
         SeqScanOp {
-            storage,
-            catalog,
+            view,
             table,
             predicate,
             rids: VecDeque::new(),
+            last: None,
+            page: None,
+            pages: Vec::new(),
+            next_page: 0,
+            read_until: 0,
         }
     }
+
+    // Called for every row; reads ahead when the row is on the next page
+    // and that page is past what was read ahead last time.
+    fn read_ahead(&mut self, page_no: u64) -> Result<()> {
+        if self.pages.get(self.next_page) != Some(&page_no) {
+            return Ok(());
+        }
+        if self.next_page >= self.read_until {
+            let pool = &self.view.storage.buffer_pool;
+            self.read_until = self.next_page + pool.prefetch(&self.pages[self.next_page..])?;
+        }
+        self.next_page += 1;
+        Ok(())
+    }
+
+    // Reads a heap page, or under skip_page passes over one that cannot be
+    // read or that CHECK would find wrong, with every row of it.
+    fn read_page(&mut self, page_no: u64) -> Result<Option<Page>> {
+        if self.view.scan_errors == ScanErrorPolicy::Strict {
+            return self.view.read_page(page_no).map(Some);
+        }
+        let problem = match self.view.read_page(page_no) {
+            Ok(page) => match check::heap_page_problems(page_no, &page).into_iter().next() {
+                None => return Ok(Some(page)),
+                Some((Some(slot), message)) => format!("slot {}: {}", slot, message),
+                Some((None, message)) => message,
+            },
+            Err(e) => format!("page cannot be read: {:#}", e),
+        };
+        warn!(
+            table = %self.table,
+            page = page_no,
+            "Skipping damaged page: {}",
+            problem
+        );
+        self.view.skipped_pages.fetch_add(1, Ordering::Relaxed);
+        self.rids.retain(|&(page, _)| page != page_no);
+        Ok(None)
+    }
 }
 
 impl<'a> PhysicalOp for SeqScanOp<'a> {
     fn open(&mut self) -> Result<()> {
-        
-        let table_meta = self.catalog.get_table(&self.table)?;
-
-        
-        for page_no in 0..self.storage.buffer_pool.pagefile.num_pages()? {
-            let frame = self.storage.buffer_pool.fetch_page(page_no)?;
-            let page = crate::storage::record::Page::from_bytes(
-                frame.data.clone(),
-                self.storage.page_size,
-            );
-
-            
-            for (slot_no, _slot_data) in page.iter_slots() {
-                self.rids.push_back((page_no, slot_no));
-            }
-
-            self.storage.buffer_pool.unpin_page(page_no, false);
-        }
+        let table = self.view.storage.catalog.get_table(&self.table)?;
+        self.rids = table.records.iter().copied().collect();
+        self.pages = self.rids.iter().map(|&(page_no, _)| page_no).collect();
+        self.pages.dedup();
+        self.last = None;
+        self.page = None;
+        self.next_page = 0;
+        self.read_until = 0;
         Ok(())
     }
 
     fn next(&mut self) -> Result<Option<Tuple>> {
         while let Some(rid) = self.rids.pop_front() {
-            let tuple_data = self.storage.fetch(rid)?;
-            let tuple = self.deserialize_tuple(&tuple_data)?;
+            self.view.check_cancelled()?;
+            self.read_ahead(rid.0)?;
+            self.view.lock_row_for_read(&self.table, rid)?;
+            let (page_no, slot) = rid;
+            let page = match self.page.take() {
+                Some((cached, page)) if cached == page_no => page,
+                _ => match self.read_page(page_no)? {
+                    Some(page) => page,
+                    None => continue,
+                },
+            };
+            let page = &self.page.insert((page_no, page)).1;
+            let Some(tuple_data) = self.view.visible_in(page, slot)? else {
+                continue;
+            };
+            let tuple = self.view.storage.deserialize_row(tuple_data)?;
 
-            
-            if let Some(pred) = &self.predicate {
-                if !eval_predicate(pred, &tuple)? {
-                    continue; 
-                }
+            if let Some(pred) = &self.predicate
+                && !eval_predicate(pred, &tuple)?
+            {
+                continue;
             }
+            self.last = Some(rid);
             return Ok(Some(tuple));
         }
         Ok(None)
@@ -114,98 +228,163 @@ impl<'a> PhysicalOp for SeqScanOp<'a> {
 
     fn close(&mut self) -> Result<()> {
         self.rids.clear();
+        self.page = None;
+        self.pages.clear();
         Ok(())
     }
+
+    fn rid(&self) -> Option<RID> {
+        self.last
+    }
 }
 
-impl<'a> SeqScanOp<'a> {
-    
-    fn deserialize_tuple(&self, data: &[u8]) -> Result<Tuple> {
-        let table_meta = self.catalog.get_table(&self.table)?;
-        let mut tuple = Vec::with_capacity(table_meta.columns.len());
-        let mut offset = 0;
-
-        for col in &table_meta.columns {
-            match col.data_type {
-                crate::query::binder::DataType::Int => {
-                    if offset + 8 > data.len() {
-                        return Err(anyhow!("Insufficient data for int column"));
-                    }
-                    let bytes: [u8; 8] = data[offset..offset + 8]
-                        .try_into()
-                        .map_err(|_| anyhow!("Failed to read int"))?;
-                    let val = i64::from_le_bytes(bytes);
-                    tuple.push(Value::Int(val));
-                    offset += 8;
-                }
-                crate::query::binder::DataType::Varchar => {
-                    if offset + 4 > data.len() {
-                        return Err(anyhow!("Insufficient data for varchar length"));
-                    }
-                    let len_bytes: [u8; 4] = data[offset..offset + 4]
-                        .try_into()
-                        .map_err(|_| anyhow!("Failed to read varchar length"))?;
-                    let len = u32::from_le_bytes(len_bytes) as usize;
-                    offset += 4;
-
-                    if offset + len > data.len() {
-                        return Err(anyhow!("Insufficient data for varchar content"));
-                    }
-                    let str_data = &data[offset..offset + len];
-                    let val = String::from_utf8(str_data.to_vec())
-                        .map_err(|_| anyhow!("Invalid UTF-8 in varchar"))?;
-                    tuple.push(Value::String(val));
-                    offset += len;
-                }
-            }
-        }
+// A table's rows as they were at an LSN, all rebuilt from the log when the
+// scan opens. It takes no row locks: nothing can change what it reads.
+pub struct AsOfScanOp<'a> {
+    view: ReadView<'a>,
+    table: String,
+    lsn: Lsn,
+    rows: VecDeque<Tuple>,
+}
 
-        Ok(tuple)
+impl<'a> AsOfScanOp<'a> {
+    pub fn new(view: ReadView<'a>, table: String, lsn: Lsn) -> Self {
+        AsOfScanOp {
+            view,
+            table,
+            lsn,
+            rows: VecDeque::new(),
+        }
     }
 }
 
+impl<'a> PhysicalOp for AsOfScanOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.rows = history::table_as_of(self.view.storage, &self.table, self.lsn)?.into();
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        self.view.check_cancelled()?;
+        Ok(self.rows.pop_front())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.rows.clear();
+        Ok(())
+    }
+}
 
 pub struct IndexScanOp<'a> {
-    storage: &'a mut Storage,
-    catalog: &'a Catalog,
-    bptree: BPlusTree,
-    predicate: BoundExpr,
-    pending: VecDeque<RID>,
+    view: ReadView<'a>,
+    index: IndexInfo,
+    ranges: Vec<(Key, Key)>,
+    index_only: bool,
+    // Whether the keys can be handed out without reading the rows.
+    keys_only: bool,
+    pending: VecDeque<(Key, RID)>,
+    last: Option<RID>,
 }
 
 impl<'a> IndexScanOp<'a> {
     pub fn new(
-        storage: &'a mut Storage,
-        catalog: &'a Catalog,
-        bptree: BPlusTree,
-        predicate: BoundExpr,
-    ) -> Result<Self> {
-        Ok(IndexScanOp {
-            storage,
-            catalog,
-            bptree,
-            predicate,
+        view: ReadView<'a>,
+        index: IndexInfo,
+        ranges: Vec<(Key, Key)>,
+        index_only: bool,
+    ) -> Self {
+        IndexScanOp {
+            view,
+            index,
+            ranges,
+            index_only,
+            keys_only: false,
             pending: VecDeque::new(),
-        })
+            last: None,
+        }
     }
 }
 
 impl<'a> PhysicalOp for IndexScanOp<'a> {
     fn open(&mut self) -> Result<()> {
-        
-        let rids = self.bptree.range_scan(&self.predicate)?;
+        let entries = bplustree::scan(self.view.storage, &self.index, &self.ranges)?;
+        self.pending = entries.into_iter().collect();
+        // Index entries carry no versions, so even an index-only scan has to
+        // look at the row when reading from a snapshot, or when the table has
+        // deleted rows VACUUM has not taken out of the index yet.
+        let table = self.view.storage.catalog.get_table(&self.index.table)?;
+        self.keys_only =
+            self.index_only && self.view.snapshot.is_none() && table.stats.dead_rows == 0;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        while let Some((key, rid)) = self.pending.pop_front() {
+            self.view.check_cancelled()?;
+            self.last = Some(rid);
+            if self.keys_only {
+                return Ok(Some(key.values()));
+            }
+            self.view.lock_row_for_read(&self.index.table, rid)?;
+            let Some(tuple_data) = self.view.fetch_visible(rid)? else {
+                continue;
+            };
+            if self.index_only {
+                return Ok(Some(key.values()));
+            }
+            return Ok(Some(self.view.storage.deserialize_row(&tuple_data)?));
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn rid(&self) -> Option<RID> {
+        self.last
+    }
+}
 
-        for rid in rids {
-            self.pending.push_back(rid);
+pub struct HashIndexScanOp<'a> {
+    view: ReadView<'a>,
+    index: IndexInfo,
+    keys: Vec<u64>,
+    pending: VecDeque<RID>,
+    last: Option<RID>,
+}
+
+impl<'a> HashIndexScanOp<'a> {
+    pub fn new(view: ReadView<'a>, index: IndexInfo, keys: Vec<u64>) -> Self {
+        HashIndexScanOp {
+            view,
+            index,
+            keys,
+            pending: VecDeque::new(),
+            last: None,
+        }
+    }
+}
+
+impl<'a> PhysicalOp for HashIndexScanOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.pending.clear();
+        for &key in &self.keys {
+            let rids = HashIndex::lookup(self.view.storage, &self.index, key)?;
+            self.pending.extend(rids);
         }
         Ok(())
     }
 
     fn next(&mut self) -> Result<Option<Tuple>> {
-        if let Some(rid) = self.pending.pop_front() {
-            let tuple_data = self.storage.fetch(rid)?;
-            let tuple = self.deserialize_tuple(&tuple_data)?;
-            return Ok(Some(tuple));
+        while let Some(rid) = self.pending.pop_front() {
+            self.view.check_cancelled()?;
+            self.view.lock_row_for_read(&self.index.table, rid)?;
+            if let Some(tuple_data) = self.view.fetch_visible(rid)? {
+                self.last = Some(rid);
+                return Ok(Some(self.view.storage.deserialize_row(&tuple_data)?));
+            }
         }
         Ok(None)
     }
@@ -214,155 +393,1685 @@ impl<'a> PhysicalOp for IndexScanOp<'a> {
         self.pending.clear();
         Ok(())
     }
+
+    fn rid(&self) -> Option<RID> {
+        self.last
+    }
 }
 
-impl<'a> IndexScanOp<'a> {
-    
-    fn deserialize_tuple(&self, data: &[u8]) -> Result<Tuple> {
-        
-        
-        let table_name = self.bptree.table_name();
-        let table_meta = self.catalog.get_table(table_name)?;
-        let mut tuple = Vec::with_capacity(table_meta.columns.len());
-        let mut offset = 0;
-
-        for col in &table_meta.columns {
-            match col.data_type {
-                crate::query::binder::DataType::Int => {
-                    if offset + 8 > data.len() {
-                        return Err(anyhow!("Insufficient data for int column"));
-                    }
-                    let bytes: [u8; 8] = data[offset..offset + 8]
-                        .try_into()
-                        .map_err(|_| anyhow!("Failed to read int"))?;
-                    let val = i64::from_le_bytes(bytes);
-                    tuple.push(Value::Int(val));
-                    offset += 8;
-                }
-                crate::query::binder::DataType::Varchar => {
-                    if offset + 4 > data.len() {
-                        return Err(anyhow!("Insufficient data for varchar length"));
-                    }
-                    let len_bytes: [u8; 4] = data[offset..offset + 4]
-                        .try_into()
-                        .map_err(|_| anyhow!("Failed to read varchar length"))?;
-                    let len = u32::from_le_bytes(len_bytes) as usize;
-                    offset += 4;
-
-                    if offset + len > data.len() {
-                        return Err(anyhow!("Insufficient data for varchar content"));
-                    }
-                    let str_data = &data[offset..offset + len];
-                    let val = String::from_utf8(str_data.to_vec())
-                        .map_err(|_| anyhow!("Invalid UTF-8 in varchar"))?;
-                    tuple.push(Value::String(val));
-                    offset += len;
-                }
-            }
+pub struct ReindexOp<'a> {
+    storage: &'a mut Storage,
+    table: String,
+    index: String,
+    done: bool,
+}
+
+impl<'a> ReindexOp<'a> {
+    pub fn new(storage: &'a mut Storage, table: String, index: String) -> Self {
+        ReindexOp {
+            storage,
+            table,
+            index,
+            done: false,
+        }
+    }
+}
+
+impl<'a> PhysicalOp for ReindexOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.done {
+            return Ok(None);
         }
+        self.done = true;
+        let start = Instant::now();
+        let keys = self.storage.reindex(&self.table, &self.index)?;
+        Ok(Some(vec![
+            Value::Int(keys as i64),
+            Value::Int(start.elapsed().as_millis() as i64),
+        ]))
+    }
 
-        Ok(tuple)
+    fn close(&mut self) -> Result<()> {
+        Ok(())
     }
 }
 
+pub struct AnalyzeOp<'a> {
+    storage: &'a mut Storage,
+    table: String,
+    done: bool,
+}
 
-pub struct FilterOp<'a> {
-    child: Box<dyn PhysicalOp + 'a>,
-    predicate: BoundExpr,
+impl<'a> AnalyzeOp<'a> {
+    pub fn new(storage: &'a mut Storage, table: String) -> Self {
+        AnalyzeOp {
+            storage,
+            table,
+            done: false,
+        }
+    }
 }
 
-impl<'a> FilterOp<'a> {
-    pub fn new(child: Box<dyn PhysicalOp + 'a>, predicate: BoundExpr) -> Self {
-        FilterOp { child, predicate }
+impl<'a> PhysicalOp for AnalyzeOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        let indexes = self.storage.analyze(&self.table)?;
+        Ok(Some(vec![Value::Int(indexes as i64)]))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
     }
 }
 
-impl<'a> PhysicalOp for FilterOp<'a> {
+pub struct DropTableOp<'a> {
+    storage: &'a mut Storage,
+    table: String,
+    done: bool,
+}
+
+impl<'a> DropTableOp<'a> {
+    pub fn new(storage: &'a mut Storage, table: String) -> Self {
+        DropTableOp {
+            storage,
+            table,
+            done: false,
+        }
+    }
+}
+
+impl<'a> PhysicalOp for DropTableOp<'a> {
     fn open(&mut self) -> Result<()> {
-        self.child.open()
+        self.done = false;
+        Ok(())
     }
 
     fn next(&mut self) -> Result<Option<Tuple>> {
-        while let Some(row) = self.child.next()? {
-            if eval_predicate(&self.predicate, &row)? {
-                return Ok(Some(row));
-            }
+        if !self.done {
+            self.done = true;
+            self.storage.drop_table(&self.table)?;
         }
         Ok(None)
     }
 
     fn close(&mut self) -> Result<()> {
-        self.child.close()
+        Ok(())
     }
 }
 
+// One row per problem found: (page, slot, object, problem), with -1 for a
+// page or slot the problem has none of. Nothing found, no rows.
+pub struct CheckOp<'a> {
+    storage: &'a mut Storage,
+    rows: Option<VecDeque<Tuple>>,
+}
 
-pub struct ProjectionOp<'a> {
-    child: Box<dyn PhysicalOp + 'a>,
-    exprs: Vec<BoundExpr>,
+impl<'a> CheckOp<'a> {
+    pub fn new(storage: &'a mut Storage) -> Self {
+        CheckOp {
+            storage,
+            rows: None,
+        }
+    }
 }
 
-impl<'a> ProjectionOp<'a> {
-    pub fn new(child: Box<dyn PhysicalOp + 'a>, exprs: Vec<BoundExpr>) -> Self {
-        ProjectionOp { child, exprs }
+impl<'a> PhysicalOp for CheckOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.rows = None;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.rows.is_none() {
+            let report = check::check(self.storage)?;
+            self.rows = Some(
+                report
+                    .problems
+                    .into_iter()
+                    .map(|p| {
+                        vec![
+                            Value::Int(p.page.map_or(-1, |page| page as i64)),
+                            Value::Int(p.slot.map_or(-1, i64::from)),
+                            Value::String(p.object),
+                            Value::String(p.message),
+                        ]
+                    })
+                    .collect(),
+            );
+        }
+        Ok(self.rows.as_mut().and_then(VecDeque::pop_front))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
     }
 }
 
-impl<'a> PhysicalOp for ProjectionOp<'a> {
+// One row: (lsn, pages, bytes, elapsed_ms) of the checkpoint taken. As after
+// one taken when the log has grown, the log it leaves unneeded is dropped.
+pub struct CheckpointOp<'a> {
+    storage: &'a mut Storage,
+    done: bool,
+}
+
+impl<'a> CheckpointOp<'a> {
+    pub fn new(storage: &'a mut Storage) -> Self {
+        CheckpointOp {
+            storage,
+            done: false,
+        }
+    }
+}
+
+impl<'a> PhysicalOp for CheckpointOp<'a> {
     fn open(&mut self) -> Result<()> {
-        self.child.open()
+        self.done = false;
+        Ok(())
     }
 
     fn next(&mut self) -> Result<Option<Tuple>> {
-        if let Some(row) = self.child.next()? {
-            let mut out = Vec::with_capacity(self.exprs.len());
-            for expr in &self.exprs {
-                out.push(eval_expr(expr, &row)?);
-            }
-            return Ok(Some(out));
+        if self.done {
+            return Ok(None);
         }
-        Ok(None)
+        self.done = true;
+        let Some(wal) = self.storage.wal.clone() else {
+            bail!("There is no WAL to checkpoint");
+        };
+        let report = recovery_manager::checkpoint_report(self.storage, &wal)?;
+        wal.truncate()?;
+        let bytes = report.pages * self.storage.page_size;
+        Ok(Some(vec![
+            Value::Int(report.lsn as i64),
+            Value::Int(report.pages as i64),
+            Value::Int(bytes as i64),
+            Value::Int(report.elapsed.as_millis() as i64),
+        ]))
     }
 
     fn close(&mut self) -> Result<()> {
-        self.child.close()
+        Ok(())
     }
 }
 
+// One row: (pages, bytes) written for the tables.
+pub struct FlushTablesOp<'a> {
+    storage: &'a mut Storage,
+    tables: Vec<String>,
+    done: bool,
+}
+
+impl<'a> FlushTablesOp<'a> {
+    pub fn new(storage: &'a mut Storage, tables: Vec<String>) -> Self {
+        FlushTablesOp {
+            storage,
+            tables,
+            done: false,
+        }
+    }
+}
 
+impl<'a> PhysicalOp for FlushTablesOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
+    }
 
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        let pages = self.storage.flush_tables(&self.tables)?;
+        let bytes = pages * self.storage.page_size;
+        Ok(Some(vec![Value::Int(pages as i64), Value::Int(bytes as i64)]))
+    }
 
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
 
+// One row per table: (table, rows, pages, skipped) as VACUUM left it.
+pub struct VacuumOp<'a> {
+    storage: &'a mut Storage,
+    tables: VecDeque<String>,
+}
 
-pub fn eval_expr(expr: &BoundExpr, row: &Tuple) -> Result<Value> {
-    Ok(match expr {
-        BoundExpr::Literal(v) => v.clone(),
-        BoundExpr::Column { ordinal, .. } => row[*ordinal].clone(),
-        BoundExpr::BinaryOp {
-            left, op, right, ..
-        } => {
-            let l = eval_expr(left, row)?;
-            let r = eval_expr(right, row)?;
-            eval_binop(&l, *op, &r)?
+impl<'a> VacuumOp<'a> {
+    pub fn new(storage: &'a mut Storage, tables: Vec<String>) -> Self {
+        VacuumOp {
+            storage,
+            tables: tables.into(),
         }
-    })
+    }
 }
 
+impl<'a> PhysicalOp for VacuumOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        let Some(table) = self.tables.pop_front() else {
+            return Ok(None);
+        };
+        let report = self.storage.vacuum(&table)?;
+        Ok(Some(vec![
+            Value::String(table),
+            Value::Int(report.rows as i64),
+            Value::Int(report.pages as i64),
+            Value::Int(report.skipped as i64),
+        ]))
+    }
 
-fn eval_predicate(pred: &BoundExpr, row: &Tuple) -> Result<bool> {
-    match eval_expr(pred, row)? {
-        Value::Int(i) => Ok(i != 0),
-        Value::String(s) => Ok(!s.is_empty()),
+    fn close(&mut self) -> Result<()> {
+        Ok(())
     }
 }
 
+// One row per holder and per waiter:
+// (resource, tx, mode, status, waited_ms). Holders report 0 ms.
+pub struct ShowLocksOp {
+    rows: VecDeque<Tuple>,
+}
 
-fn eval_binop(left: &Value, op: BinaryOp, right: &Value) -> Result<Value> {
-    match (left, right, op) {
-        (Value::Int(l), Value::Int(r), BinaryOp::Eq) => Ok(Value::Int((*l == *r) as i64)),
-        (Value::Int(l), Value::Int(r), BinaryOp::Lt) => Ok(Value::Int((*l < *r) as i64)),
-        (Value::Int(l), Value::Int(r), BinaryOp::Gt) => Ok(Value::Int((*l > *r) as i64)),
-        (Value::String(l), Value::String(r), BinaryOp::Eq) => Ok(Value::Int((l == r) as i64)),
-        _ => Err(anyhow!("Unsupported binary op or mismatched types")),
+impl ShowLocksOp {
+    pub fn new(storage: &Storage) -> Self {
+        let snapshot = storage
+            .locks
+            .as_ref()
+            .map(|locks| locks.snapshot())
+            .unwrap_or_default();
+        let mut rows = VecDeque::new();
+        for entry in snapshot {
+            let resource = format!("{:?}", entry.resource);
+            for (tx, mode) in entry.holders {
+                rows.push_back(vec![
+                    Value::String(resource.clone()),
+                    Value::Int(tx as i64),
+                    Value::String(format!("{:?}", mode)),
+                    Value::String("held".into()),
+                    Value::Int(0),
+                ]);
+            }
+            for waiter in entry.waiters {
+                rows.push_back(vec![
+                    Value::String(resource.clone()),
+                    Value::Int(waiter.tx as i64),
+                    Value::String(format!("{:?}", waiter.mode)),
+                    Value::String("waiting".into()),
+                    Value::Int(waiter.waited.as_millis() as i64),
+                ]);
+            }
+        }
+        ShowLocksOp { rows }
     }
 }
+
+impl PhysicalOp for ShowLocksOp {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        Ok(self.rows.pop_front())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+// One row per table and view, by name: (table, rows, pages, bytes, kind),
+// the kind being "table", "temporary" or "view". Tables have the counts the
+// catalog keeps and views none. The session's temporary tables are listed
+// in place of whatever they hide. With a schema, only what is in it is
+// listed, by its name within it; temporary tables are in none.
+pub struct ShowTablesOp {
+    rows: VecDeque<Tuple>,
+}
+
+impl ShowTablesOp {
+    pub fn new(storage: &Storage, schema: Option<&str>) -> Self {
+        let catalog = &storage.catalog;
+        let table = |t: &TableInfo, kind| {
+            let counts = [t.stats.rows, t.stats.page_count() as u64, t.stats.bytes];
+            // 0 for a table never analyzed, there being no NULL.
+            let churn = [
+                t.churn.inserted,
+                t.churn.deleted,
+                t.analyzed_at.unwrap_or(0),
+            ];
+            (t.name.clone(), counts, kind, churn)
+        };
+        let temp = catalog.temp.values().filter(|_| schema.is_none());
+        let mut listed: Vec<_> = catalog
+            .tables
+            .values()
+            .filter(|t| !catalog.is_temp(&t.name))
+            .map(|t| table(t, "table"))
+            .chain(temp.map(|t| table(t, "temporary")))
+            .chain(
+                catalog
+                    .views
+                    .values()
+                    .filter(|v| !catalog.is_temp(&v.name))
+                    .map(|v| (v.name.clone(), [0; 3], "view", [0; 3])),
+            )
+            .filter_map(|(name, counts, kind, churn)| match schema {
+                None => Some((name, counts, kind, churn)),
+                Some(schema) => {
+                    let (within, bare) = split_name(&name);
+                    (within == schema).then(|| (bare.to_string(), counts, kind, churn))
+                }
+            })
+            .collect();
+        listed.sort_by(|a, b| a.0.cmp(&b.0));
+        let rows = listed
+            .into_iter()
+            .map(|(name, counts, kind, churn)| {
+                let mut row = vec![Value::String(name)];
+                row.extend(counts.map(|count| Value::Int(count as i64)));
+                row.push(Value::String(kind.to_string()));
+                row.extend(churn.map(|count| Value::Int(count as i64)));
+                row
+            })
+            .collect();
+        ShowTablesOp { rows }
+    }
+}
+
+impl PhysicalOp for ShowTablesOp {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        Ok(self.rows.pop_front())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+// One row per privilege granted, by table and user:
+// (table, user, privilege, column), with an empty column for the whole
+// table.
+pub struct ShowGrantsOp {
+    rows: VecDeque<Tuple>,
+}
+
+impl ShowGrantsOp {
+    pub fn new(storage: &Storage) -> Self {
+        let mut tables: Vec<_> = storage.catalog.grants.iter().collect();
+        tables.sort_by(|a, b| a.0.cmp(b.0));
+        let mut rows = VecDeque::new();
+        for (table, users) in tables {
+            for (user, grants) in users {
+                let row = |privilege: &Privilege, column: &str| {
+                    vec![
+                        Value::String(table.clone()),
+                        Value::String(user.clone()),
+                        Value::String(privilege.to_string()),
+                        Value::String(column.to_string()),
+                    ]
+                };
+                rows.extend(grants.table.iter().map(|p| row(p, "")));
+                for (column, granted) in &grants.columns {
+                    rows.extend(granted.iter().map(|p| row(p, column)));
+                }
+            }
+        }
+        ShowGrantsOp { rows }
+    }
+}
+
+impl PhysicalOp for ShowGrantsOp {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        Ok(self.rows.pop_front())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+// One row: how many rows it deleted. Each is stamped with the statement's
+// transaction and stays in the heap until VACUUM takes its slot.
+//
+// The input only reads, so it is built over a view of storage and run to
+// the end before the first row is deleted: the rows to delete are those of
+// the table as the statement found it.
+pub struct DeleteOp<'a> {
+    storage: &'a mut Storage,
+    table: String,
+    input: PhysicalPlan,
+    memory: Arc<MemoryTracker>,
+    done: bool,
+}
+
+impl<'a> DeleteOp<'a> {
+    pub fn new(
+        storage: &'a mut Storage,
+        table: String,
+        input: PhysicalPlan,
+        memory: &Arc<MemoryTracker>,
+    ) -> Self {
+        DeleteOp {
+            storage,
+            table,
+            input,
+            memory: memory.clone(),
+            done: false,
+        }
+    }
+
+    // A damaged page fails the DELETE whatever scan_error_policy says: the
+    // rows on it may be ones it should delete.
+    fn targets(&mut self) -> Result<Vec<RID>> {
+        let view = ReadView {
+            scan_errors: ScanErrorPolicy::Strict,
+            ..ReadView::of(self.storage)
+        };
+        let mut input = build_read_operator_with(self.input.clone(), view, &self.memory)?;
+        input.open()?;
+        let mut rids = Vec::new();
+        while input.next()?.is_some() {
+            match input.rid() {
+                Some(rid) => rids.push(rid),
+                None => bail!("DELETE read a row that is not in {}", self.table),
+            }
+        }
+        input.close()?;
+        Ok(rids)
+    }
+}
+
+impl<'a> PhysicalOp for DeleteOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        let mut deleted = 0;
+        for rid in self.targets()? {
+            self.storage.check_cancelled()?;
+            // A row a cascade has deleted already is no longer visible.
+            if self.storage.fetch_visible(rid)?.is_none() {
+                continue;
+            }
+            self.storage.delete_row(&self.table, rid)?;
+            deleted += 1;
+        }
+        Ok(Some(vec![Value::Int(deleted)]))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct InsertOp<'a> {
+    storage: &'a mut Storage,
+    table: String,
+    col_ordinals: Vec<usize>,
+    rows: Vec<Vec<BoundExpr>>,
+    done: bool,
+}
+
+impl<'a> InsertOp<'a> {
+    pub fn new(
+        storage: &'a mut Storage,
+        table: String,
+        col_ordinals: Vec<usize>,
+        rows: Vec<Vec<BoundExpr>>,
+    ) -> Self {
+        InsertOp {
+            storage,
+            table,
+            col_ordinals,
+            rows,
+            done: false,
+        }
+    }
+}
+
+impl<'a> PhysicalOp for InsertOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+
+        let meta = self.storage.catalog.get_table(&self.table)?;
+        let columns = self
+            .col_ordinals
+            .iter()
+            .map(|&o| meta.columns[o].name.clone())
+            .collect::<Vec<_>>();
+        // Binding has made sure no value reads a column.
+        let no_row = Tuple::new();
+        for values in &self.rows {
+            self.storage.check_cancelled()?;
+            let mut row = Vec::with_capacity(values.len());
+            for expr in values {
+                row.push(eval_expr(expr, &no_row)?);
+            }
+            let rid = self.storage.insert_row(&self.table, &columns, row)?;
+            self.storage
+                .lock_row(&self.table, rid, LockMode::Exclusive)?;
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ExplainOp {
+    lines: VecDeque<String>,
+}
+
+impl ExplainOp {
+    pub fn new(plan: &PhysicalPlan, actual_rows: Option<u64>) -> Self {
+        ExplainOp {
+            lines: plan.explain_with(actual_rows).into(),
+        }
+    }
+}
+
+impl PhysicalOp for ExplainOp {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        Ok(self.lines.pop_front().map(|l| vec![Value::String(l)]))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct SingleRowOp {
+    done: bool,
+}
+
+impl SingleRowOp {
+    pub fn new() -> Self {
+        SingleRowOp { done: false }
+    }
+}
+
+impl PhysicalOp for SingleRowOp {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        Ok((!std::mem::replace(&mut self.done, true)).then(Tuple::new))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct FilterOp<'a> {
+    child: Box<dyn PhysicalOp + 'a>,
+    predicate: BoundExpr,
+}
+
+impl<'a> FilterOp<'a> {
+    pub fn new(child: Box<dyn PhysicalOp + 'a>, predicate: BoundExpr) -> Self {
+        FilterOp { child, predicate }
+    }
+}
+
+impl<'a> PhysicalOp for FilterOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.child.open()
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        while let Some(row) = self.child.next()? {
+            if eval_predicate(&self.predicate, &row)? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.child.close()
+    }
+
+    fn rid(&self) -> Option<RID> {
+        self.child.rid()
+    }
+}
+
+// Keeps the child's rows whose keys some row of the subquery has, or with
+// `anti`, none does. The subquery's rows, which are its keys, are read
+// into a set when it opens; each child row is then looked up once, so it
+// comes out at most once however many rows match it. The set has to fit in
+// the statement's memory.
+pub struct HashSemiJoinOp<'a> {
+    child: Box<dyn PhysicalOp + 'a>,
+    subquery: Box<dyn PhysicalOp + 'a>,
+    keys: Vec<BoundExpr>,
+    collations: Vec<Collation>,
+    anti: bool,
+    found: HashSet<Tuple>,
+    memory: Arc<MemoryTracker>,
+    held: usize,
+}
+
+impl<'a> HashSemiJoinOp<'a> {
+    pub fn new(
+        child: Box<dyn PhysicalOp + 'a>,
+        subquery: Box<dyn PhysicalOp + 'a>,
+        keys: Vec<BoundExpr>,
+        collations: Vec<Collation>,
+        anti: bool,
+        memory: Arc<MemoryTracker>,
+    ) -> Self {
+        HashSemiJoinOp {
+            child,
+            subquery,
+            keys,
+            collations,
+            anti,
+            found: HashSet::new(),
+            memory,
+            held: 0,
+        }
+    }
+
+    // Values that compare equal under their key's collation hash alike.
+    fn normalize(&self, row: Tuple) -> Tuple {
+        row.into_iter()
+            .zip(&self.collations)
+            .map(|(value, collation)| collation.key(value))
+            .collect()
+    }
+}
+
+impl<'a> PhysicalOp for HashSemiJoinOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.subquery.open()?;
+        while let Some(row) = self.subquery.next()? {
+            let key = self.normalize(row);
+            if !self.found.contains(&key) {
+                let bytes = row_bytes(&key);
+                self.memory.charge("HashSemiJoin", bytes)?;
+                self.held += bytes;
+                self.found.insert(key);
+            }
+        }
+        self.subquery.close()?;
+        self.child.open()
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        while let Some(row) = self.child.next()? {
+            let key = self
+                .keys
+                .iter()
+                .map(|k| eval_expr(k, &row))
+                .collect::<Result<Tuple>>()?;
+            if self.found.contains(&self.normalize(key)) != self.anti {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.found.clear();
+        self.memory.release(std::mem::take(&mut self.held));
+        self.child.close()
+    }
+}
+
+// A column that only one expression uses, and uses bare, is moved out of
+// the child's row rather than copied.
+pub struct ProjectionOp<'a> {
+    child: Box<dyn PhysicalOp + 'a>,
+    exprs: Vec<BoundExpr>,
+    moved: Vec<Option<usize>>,
+}
+
+impl<'a> ProjectionOp<'a> {
+    pub fn new(child: Box<dyn PhysicalOp + 'a>, exprs: Vec<BoundExpr>) -> Self {
+        let mut uses = HashMap::new();
+        for expr in &exprs {
+            count_columns(expr, &mut uses);
+        }
+        let moved = exprs
+            .iter()
+            .map(|expr| match expr {
+                BoundExpr::Column { ordinal, .. } if uses[ordinal] == 1 => Some(*ordinal),
+                _ => None,
+            })
+            .collect();
+        ProjectionOp {
+            child,
+            exprs,
+            moved,
+        }
+    }
+}
+
+// How many times each column of the row is read.
+fn count_columns(expr: &BoundExpr, uses: &mut HashMap<usize, usize>) {
+    match expr {
+        BoundExpr::Column { ordinal, .. } => *uses.entry(*ordinal).or_default() += 1,
+        BoundExpr::BinaryOp { left, right, .. } => {
+            count_columns(left, uses);
+            count_columns(right, uses);
+        }
+        BoundExpr::Call { args, .. } => args.iter().for_each(|arg| count_columns(arg, uses)),
+        BoundExpr::SetSeed(_, seed) => count_columns(seed, uses),
+        BoundExpr::Literal(_)
+        | BoundExpr::NextVal(_)
+        | BoundExpr::CurrVal(_)
+        | BoundExpr::Random(_) => {}
+    }
+}
+
+impl<'a> PhysicalOp for ProjectionOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.child.open()
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if let Some(mut row) = self.child.next()? {
+            let mut out = Vec::with_capacity(self.exprs.len());
+            for (expr, moved) in self.exprs.iter().zip(&self.moved) {
+                out.push(match moved {
+                    Some(ordinal) => std::mem::replace(&mut row[*ordinal], Value::Int(0)),
+                    None => eval_expr(expr, &row)?,
+                });
+            }
+            return Ok(Some(out));
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.child.close()
+    }
+}
+
+// Groups rows by the values of `keys` and hands out one row per group, in
+// the order of those values. Without keys every row is in one group, which
+// is there even when there are no rows. The groups have to fit in the
+// statement's memory.
+pub struct AggregateOp<'a> {
+    child: Box<dyn PhysicalOp + 'a>,
+    keys: Vec<BoundExpr>,
+    aggregates: Vec<Aggregate>,
+    rows: VecDeque<Tuple>,
+    memory: Arc<MemoryTracker>,
+    held: usize,
+}
+
+impl<'a> AggregateOp<'a> {
+    pub fn new(
+        child: Box<dyn PhysicalOp + 'a>,
+        keys: Vec<BoundExpr>,
+        aggregates: Vec<Aggregate>,
+        memory: Arc<MemoryTracker>,
+    ) -> Self {
+        AggregateOp {
+            child,
+            keys,
+            aggregates,
+            rows: VecDeque::new(),
+            memory,
+            held: 0,
+        }
+    }
+
+    fn accumulators(&self) -> Vec<Accumulator> {
+        self.aggregates
+            .iter()
+            .map(|a| Accumulator::new(a.function))
+            .collect()
+    }
+}
+
+impl<'a> PhysicalOp for AggregateOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.child.open()?;
+        // Groups go by their keys as the keys' collations tell them apart,
+        // and show the first spelling of a key that came.
+        let mut groups: BTreeMap<Tuple, (Tuple, Vec<Accumulator>)> = BTreeMap::new();
+        while let Some(row) = self.child.next()? {
+            let key = self
+                .keys
+                .iter()
+                .map(|k| eval_expr(k, &row))
+                .collect::<Result<Tuple>>()?;
+            let collated: Tuple = key
+                .iter()
+                .zip(&self.keys)
+                .map(|(value, k)| k.collation().key(value.clone()))
+                .collect();
+            let (_, group) = match groups.entry(collated) {
+                btree_map::Entry::Occupied(group) => group.into_mut(),
+                btree_map::Entry::Vacant(group) => {
+                    let bytes = row_bytes(group.key())
+                        + row_bytes(&key)
+                        + self.aggregates.len() * size_of::<Accumulator>();
+                    self.memory.charge("Aggregate", bytes)?;
+                    self.held += bytes;
+                    group.insert((key, self.accumulators()))
+                }
+            };
+            for (acc, aggregate) in group.iter_mut().zip(&self.aggregates) {
+                let (value, collation) = match &aggregate.arg {
+                    Some(arg) => (Some(eval_expr(arg, &row)?), arg.collation()),
+                    None => (None, Collation::Binary),
+                };
+                acc.add(value, collation)?;
+            }
+        }
+        if groups.is_empty() && self.keys.is_empty() {
+            groups.insert(Tuple::new(), (Tuple::new(), self.accumulators()));
+        }
+        self.rows.clear();
+        for (mut row, group) in groups.into_values() {
+            for (acc, aggregate) in group.into_iter().zip(&self.aggregates) {
+                row.push(acc.finish(aggregate.function)?);
+            }
+            self.rows.push_back(row);
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        Ok(self.rows.pop_front())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.rows.clear();
+        self.memory.release(std::mem::take(&mut self.held));
+        self.child.close()
+    }
+}
+
+// What one aggregate has made of a group's rows so far.
+enum Accumulator {
+    Count(i64),
+    // Wider than an INT, so only a total that ends up out of range is an
+    // error, not one that passes out of it on the way.
+    Sum(i128),
+    Min(Option<Value>),
+    Max(Option<Value>),
+}
+
+impl Accumulator {
+    fn new(function: AggregateFunction) -> Self {
+        match function {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Sum => Accumulator::Sum(0),
+            AggregateFunction::Min => Accumulator::Min(None),
+            AggregateFunction::Max => Accumulator::Max(None),
+        }
+    }
+
+    // `value` is None for COUNT(*), which has no argument. MIN and MAX
+    // compare by `collation`, keeping the first of values that tie.
+    fn add(&mut self, value: Option<Value>, collation: Collation) -> Result<()> {
+        match (self, value) {
+            (Accumulator::Count(n), _) => *n += 1,
+            (_, None) => {}
+            (Accumulator::Sum(total), Some(Value::Int(v))) => *total += v as i128,
+            (Accumulator::Sum(_), Some(other)) => {
+                bail!("SUM needs INT values, not {}", other.type_name())
+            }
+            (Accumulator::Min(least), Some(v)) => {
+                if least
+                    .as_ref()
+                    .is_none_or(|l| collation.compare(&v, l).is_lt())
+                {
+                    *least = Some(v);
+                }
+            }
+            (Accumulator::Max(most), Some(v)) => {
+                if most
+                    .as_ref()
+                    .is_none_or(|m| collation.compare(&v, m).is_gt())
+                {
+                    *most = Some(v);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // There is no NULL for MIN or MAX of no rows to be.
+    fn finish(self, function: AggregateFunction) -> Result<Value> {
+        match self {
+            Accumulator::Count(n) => Ok(Value::Int(n)),
+            Accumulator::Sum(total) => i64::try_from(total)
+                .map(Value::Int)
+                .map_err(|_| anyhow!("Integer out of range: SUM is {}", total)),
+            Accumulator::Min(v) | Accumulator::Max(v) => {
+                v.ok_or_else(|| anyhow!("{} of no rows has no value", function.name()))
+            }
+        }
+    }
+}
+
+// Sorts by the keys in turn, each ascending unless it says DESC. Rows
+// that tie on every key keep the order they came in.
+//
+// Rows that do not fit in the statement's memory are sorted a batch at a
+// time, each batch written out as a run of its rows behind their keys, and
+// the runs merged back together by those keys; a tie goes to the earlier
+// run, so the order stays stable.
+pub struct SortOp<'a> {
+    child: Box<dyn PhysicalOp + 'a>,
+    keys: Vec<SortKey>,
+    rows: VecDeque<Tuple>,
+    memory: Arc<MemoryTracker>,
+    held: usize,
+    merged: Option<MergeOp<'static>>,
+}
+
+impl<'a> SortOp<'a> {
+    pub fn new(
+        child: Box<dyn PhysicalOp + 'a>,
+        keys: Vec<SortKey>,
+        memory: Arc<MemoryTracker>,
+    ) -> Self {
+        SortOp {
+            child,
+            keys,
+            rows: VecDeque::new(),
+            memory,
+            held: 0,
+            merged: None,
+        }
+    }
+
+    fn sort(&self, batch: &mut [(Tuple, Tuple)]) {
+        batch.sort_by(|(a, _), (b, _)| compare_keys(&self.keys, a, b));
+    }
+
+    // Writes `batch` out sorted and gives back the memory it held.
+    fn spill(&mut self, batch: &mut Vec<(Tuple, Tuple)>) -> Result<SpillReader> {
+        self.sort(batch);
+        let mut run = SpillWriter::create(&self.memory)?;
+        for (mut key, row) in batch.drain(..) {
+            key.extend(row);
+            run.write(&key)?;
+        }
+        self.memory.release(std::mem::take(&mut self.held));
+        run.finish()
+    }
+}
+
+impl<'a> PhysicalOp for SortOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.child.open()?;
+        let (mut batch, mut runs) = (Vec::new(), Vec::new());
+        while let Some(row) = self.child.next()? {
+            let key = self
+                .keys
+                .iter()
+                .map(|k| eval_expr(&k.expr, &row))
+                .collect::<Result<Tuple>>()?;
+            let bytes = row_bytes(&key) + row_bytes(&row);
+            if !self.memory.reserve(bytes) {
+                if !batch.is_empty() {
+                    runs.push(self.spill(&mut batch)?);
+                }
+                self.memory.force(bytes);
+            }
+            self.held += bytes;
+            batch.push((key, row));
+        }
+        if runs.is_empty() {
+            self.sort(&mut batch);
+            self.rows = batch.into_iter().map(|(_, row)| row).collect();
+            return Ok(());
+        }
+        if !batch.is_empty() {
+            runs.push(self.spill(&mut batch)?);
+        }
+        // The runs' rows start with their keys, which the merge compares
+        // as they are rather than working them out again.
+        let keys = self
+            .keys
+            .iter()
+            .enumerate()
+            .map(|(ordinal, key)| SortKey {
+                expr: BoundExpr::Column {
+                    table: String::new(),
+                    col: key.expr.name(),
+                    ordinal,
+                    data_type: key.expr.data_type(),
+                    collation: key.expr.collation(),
+                },
+                descending: key.descending,
+            })
+            .collect();
+        let runs = runs
+            .into_iter()
+            .map(|run| Box::new(RunOp(Some(run))) as Box<dyn PhysicalOp>)
+            .collect();
+        let mut merged = MergeOp::new(runs, keys);
+        merged.open()?;
+        self.merged = Some(merged);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        let Some(merged) = &mut self.merged else {
+            return Ok(self.rows.pop_front());
+        };
+        Ok(merged.next()?.map(|mut row| row.split_off(self.keys.len())))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.rows.clear();
+        if let Some(mut merged) = self.merged.take() {
+            merged.close()?;
+        }
+        self.memory.release(std::mem::take(&mut self.held));
+        self.child.close()
+    }
+}
+
+// A sorted run a sort wrote out, read back in order.
+struct RunOp(Option<SpillReader>);
+
+impl PhysicalOp for RunOp {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        match &mut self.0 {
+            Some(run) => run.read_row(),
+            None => Ok(None),
+        }
+    }
+
+    // The run's file goes with it.
+    fn close(&mut self) -> Result<()> {
+        self.0 = None;
+        Ok(())
+    }
+}
+
+// How two rows' values of `keys` order, each key ascending unless it says
+// DESC.
+fn compare_keys(keys: &[SortKey], a: &Tuple, b: &Tuple) -> std::cmp::Ordering {
+    a.iter()
+        .zip(b)
+        .zip(keys)
+        .map(|((a, b), key)| {
+            let collation = key.expr.collation();
+            match key.descending {
+                true => collation.compare(b, a),
+                false => collation.compare(a, b),
+            }
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
+// The rows of each child in turn.
+pub struct AppendOp<'a> {
+    children: Vec<Box<dyn PhysicalOp + 'a>>,
+    current: usize,
+}
+
+impl<'a> AppendOp<'a> {
+    pub fn new(children: Vec<Box<dyn PhysicalOp + 'a>>) -> Self {
+        AppendOp {
+            children,
+            current: 0,
+        }
+    }
+}
+
+impl<'a> PhysicalOp for AppendOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.current = 0;
+        self.children.iter_mut().try_for_each(|child| child.open())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        while let Some(child) = self.children.get_mut(self.current) {
+            if let Some(row) = child.next()? {
+                return Ok(Some(row));
+            }
+            self.current += 1;
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.children.iter_mut().try_for_each(|child| child.close())
+    }
+}
+
+// Merges children that each come in the order of `keys` into that order,
+// holding the next row of each. A tie goes to the earlier child, so the
+// rows come out as sorting them all one child after another would.
+pub struct MergeOp<'a> {
+    children: Vec<Box<dyn PhysicalOp + 'a>>,
+    keys: Vec<SortKey>,
+    // Each child's next row with its values of the keys.
+    heads: Vec<Option<(Tuple, Tuple)>>,
+}
+
+impl<'a> MergeOp<'a> {
+    pub fn new(children: Vec<Box<dyn PhysicalOp + 'a>>, keys: Vec<SortKey>) -> Self {
+        MergeOp {
+            children,
+            keys,
+            heads: Vec::new(),
+        }
+    }
+
+    fn pull(&mut self, child: usize) -> Result<Option<(Tuple, Tuple)>> {
+        let Some(row) = self.children[child].next()? else {
+            return Ok(None);
+        };
+        let key = self
+            .keys
+            .iter()
+            .map(|k| eval_expr(&k.expr, &row))
+            .collect::<Result<Tuple>>()?;
+        Ok(Some((key, row)))
+    }
+}
+
+impl<'a> PhysicalOp for MergeOp<'a> {
+    fn open(&mut self) -> Result<()> {
+        self.heads.clear();
+        for child in 0..self.children.len() {
+            self.children[child].open()?;
+            let head = self.pull(child)?;
+            self.heads.push(head);
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        let mut first: Option<usize> = None;
+        for (child, head) in self.heads.iter().enumerate() {
+            let Some((key, _)) = head else {
+                continue;
+            };
+            let earlier = match first.and_then(|f| self.heads[f].as_ref()) {
+                Some((best, _)) => compare_keys(&self.keys, key, best).is_lt(),
+                None => true,
+            };
+            if earlier {
+                first = Some(child);
+            }
+        }
+        let Some(child) = first else {
+            return Ok(None);
+        };
+        let next = self.pull(child)?;
+        let (_, row) = std::mem::replace(&mut self.heads[child], next).unwrap();
+        Ok(Some(row))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.heads.clear();
+        self.children.iter_mut().try_for_each(|child| child.close())
+    }
+}
+
+// How deep an expression may nest when evaluated. Deeper than the parser
+// lets through, since the optimizer joins stacked filters into one
+// predicate.
+pub const MAX_EVAL_DEPTH: usize = 1000;
+
+pub fn eval_expr(expr: &BoundExpr, row: &Tuple) -> Result<Value> {
+    eval_nested(expr, row, 0)
+}
+
+fn eval_nested(expr: &BoundExpr, row: &Tuple, depth: usize) -> Result<Value> {
+    if depth > MAX_EVAL_DEPTH {
+        bail!(
+            "Expression too deeply nested, more than {} levels",
+            MAX_EVAL_DEPTH
+        );
+    }
+    let depth = depth + 1;
+    Ok(match expr {
+        BoundExpr::Literal(v) => v.clone(),
+        BoundExpr::Column { ordinal, .. } => row[*ordinal].clone(),
+        BoundExpr::BinaryOp {
+            left, op, right, ..
+        } => {
+            let l = eval_operand(left, row, depth)?;
+            let r = eval_operand(right, row, depth)?;
+            let collation = left.collation().of_comparison(right.collation());
+            eval_binop(&l, *op, &r, collation)?
+        }
+        BoundExpr::NextVal(nextval) => Value::Int(nextval.next()?),
+        BoundExpr::CurrVal(sequence) => Value::Int(sequence.current()?),
+        // The first argument is never NULL, so the rest are never needed.
+        BoundExpr::Call {
+            function: Function::Coalesce,
+            args,
+        } => eval_nested(&args[0], row, depth)?,
+        BoundExpr::Call { function, args } => {
+            let values = args
+                .iter()
+                .map(|arg| eval_nested(arg, row, depth))
+                .collect::<Result<Vec<_>>>()?;
+            eval_call(*function, args, values)?
+        }
+        BoundExpr::Random(random) => Value::Int(random.next()),
+        BoundExpr::SetSeed(random, seed) => match eval_nested(seed, row, depth)? {
+            Value::Int(seed) => {
+                random.seed(seed);
+                Value::Int(seed)
+            }
+            other => bail!("SETSEED needs an INT value, not {}", other.type_name()),
+        },
+    })
+}
+
+// The binder has checked the arguments' types; values of others are still
+// refused rather than trusted.
+fn eval_call(function: Function, args: &[BoundExpr], values: Vec<Value>) -> Result<Value> {
+    Ok(match (function, values.as_slice()) {
+        (Function::Upper, [Value::String(s)]) => Value::String(s.to_uppercase()),
+        (Function::Lower, [Value::String(s)]) => Value::String(s.to_lowercase()),
+        (Function::Upper | Function::Lower, [other]) => bail!(
+            "{} needs a TEXT value, not {}",
+            function.name(),
+            other.type_name()
+        ),
+        (Function::Abs, [Value::Int(n)]) => Value::Int(
+            n.checked_abs()
+                .ok_or_else(|| anyhow!("Integer out of range: ABS({})", n))?,
+        ),
+        (Function::Mod, [Value::Int(_), Value::Int(0)]) => bail!("Division by zero"),
+        // The remainder has the dividend's sign: MOD(-7, 2) is -1.
+        (Function::Mod, [Value::Int(l), Value::Int(r)]) => Value::Int(l.wrapping_rem(*r)),
+        (Function::Min | Function::Max, [l, r]) if l.same_type(r) => {
+            let collation = args[0].collation().of_comparison(args[1].collation());
+            let ordering = collation.compare(l, r);
+            let left = match function {
+                Function::Min => ordering.is_le(),
+                _ => ordering.is_ge(),
+            };
+            if left { l.clone() } else { r.clone() }
+        }
+        (Function::TypeOf, [value]) => Value::String(value.type_name().to_string()),
+        (Function::DateTrunc, [Value::String(unit), Value::Int(time)]) => {
+            Value::Int(datetime::trunc(unit, *time)?)
+        }
+        (Function::Extract, [Value::String(field), Value::Int(time)]) => {
+            Value::Int(datetime::extract(field, *time)?)
+        }
+        (Function::NullIf, [l, r]) if l.same_type(r) => {
+            let collation = args[0].collation().of_comparison(args[1].collation());
+            if collation.compare(l, r).is_eq() {
+                bail!("NULLIF({}, {}) would be NULL", l, r);
+            }
+            l.clone()
+        }
+        (function, values) => bail!(
+            "{} cannot take {}",
+            function.name(),
+            values
+                .iter()
+                .map(Value::type_name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    })
+}
+
+// A column or literal is used where it is instead of being copied, which
+// for TEXT saves an allocation every row.
+fn eval_operand<'r>(expr: &'r BoundExpr, row: &'r Tuple, depth: usize) -> Result<Cow<'r, Value>> {
+    Ok(match expr {
+        BoundExpr::Column { ordinal, .. } => Cow::Borrowed(&row[*ordinal]),
+        BoundExpr::Literal(v) => Cow::Borrowed(v),
+        _ => Cow::Owned(eval_nested(expr, row, depth)?),
+    })
+}
+
+pub fn eval_predicate(pred: &BoundExpr, row: &Tuple) -> Result<bool> {
+    Ok(eval_expr(pred, row)?.is_truthy())
+}
+
+fn eval_binop(left: &Value, op: BinaryOp, right: &Value, collation: Collation) -> Result<Value> {
+    let arithmetic = matches!(
+        op,
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div
+    );
+    // `Value` orders INT against TEXT, but SQL does not compare them.
+    if !arithmetic && !left.same_type(right) {
+        return Err(anyhow!(
+            "Cannot compare {} with {}",
+            left.type_name(),
+            right.type_name()
+        ));
+    }
+    let ordering = collation.compare(left, right);
+    let truthy = Value::is_truthy;
+    let result = match op {
+        BinaryOp::Eq | BinaryOp::IsNotDistinctFrom => ordering.is_eq(),
+        BinaryOp::NotEq | BinaryOp::IsDistinctFrom => ordering.is_ne(),
+        BinaryOp::Lt => ordering.is_lt(),
+        BinaryOp::LtEq => ordering.is_le(),
+        BinaryOp::Gt => ordering.is_gt(),
+        BinaryOp::GtEq => ordering.is_ge(),
+        BinaryOp::And => truthy(left) && truthy(right),
+        BinaryOp::Or => truthy(left) || truthy(right),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+            return eval_arithmetic(left, op, right);
+        }
+    };
+    Ok(Value::Int(result as i64))
+}
+
+// On INTs only. A result out of the range of an INT and division by zero
+// are errors rather than wrapping or panicking.
+fn eval_arithmetic(left: &Value, op: BinaryOp, right: &Value) -> Result<Value> {
+    let (Value::Int(l), Value::Int(r)) = (left, right) else {
+        return Err(anyhow!(
+            "Arithmetic needs INT values, not {} and {}",
+            left.type_name(),
+            right.type_name()
+        ));
+    };
+    let result = match op {
+        BinaryOp::Add => l.checked_add(*r),
+        BinaryOp::Sub => l.checked_sub(*r),
+        BinaryOp::Mul => l.checked_mul(*r),
+        _ if *r == 0 => return Err(anyhow!("Division by zero")),
+        _ => l.checked_div(*r),
+    };
+    let symbol = match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        _ => "/",
+    };
+    result
+        .map(Value::Int)
+        .ok_or_else(|| anyhow!("Integer out of range: {} {} {}", l, symbol, r))
+}
+
+pub fn build_operator<'a>(
+    plan: PhysicalPlan,
+    storage: &'a mut Storage,
+) -> Result<Box<dyn PhysicalOp + 'a>> {
+    build_operator_with(plan, storage, &MemoryTracker::new(DEFAULT_WORK_MEM))
+}
+
+// Builds `plan` with its sorts, aggregates and joins holding no more than
+// `memory` allows between them.
+pub fn build_operator_with<'a>(
+    plan: PhysicalPlan,
+    storage: &'a mut Storage,
+    memory: &Arc<MemoryTracker>,
+) -> Result<Box<dyn PhysicalOp + 'a>> {
+    use PhysicalPlan::*;
+    Ok(match plan {
+        Insert {
+            table_name,
+            col_ordinals,
+            rows,
+            schema_version,
+        } => {
+            check_version(storage, &table_name, schema_version)?;
+            Box::new(InsertOp::new(storage, table_name, col_ordinals, rows))
+        }
+        Delete {
+            table_name,
+            input,
+            schema_version,
+        } => {
+            check_version(storage, &table_name, schema_version)?;
+            Box::new(DeleteOp::new(storage, table_name, *input, memory))
+        }
+        Reindex {
+            table_name,
+            index_name,
+        } => Box::new(ReindexOp::new(storage, table_name, index_name)),
+        Analyze { table_name } => Box::new(AnalyzeOp::new(storage, table_name)),
+        DropTable { table_name } => Box::new(DropTableOp::new(storage, table_name)),
+        Check => Box::new(CheckOp::new(storage)),
+        Checkpoint => Box::new(CheckpointOp::new(storage)),
+        FlushTables { tables } => Box::new(FlushTablesOp::new(storage, tables)),
+        Vacuum { tables } => Box::new(VacuumOp::new(storage, tables)),
+        CreateTable { .. } => return Err(anyhow!("CREATE TABLE is not executable as an operator")),
+        read => build_read_operator_with(read, ReadView::of(storage), memory)?,
+    })
+}
+
+// Builds a plan that only reads, which several statements can run at once
+// against shared storage.
+pub fn build_read_operator<'a>(
+    plan: PhysicalPlan,
+    view: ReadView<'a>,
+) -> Result<Box<dyn PhysicalOp + 'a>> {
+    build_read_operator_with(plan, view, &MemoryTracker::new(DEFAULT_WORK_MEM))
+}
+
+pub fn build_read_operator_with<'a>(
+    plan: PhysicalPlan,
+    view: ReadView<'a>,
+    memory: &Arc<MemoryTracker>,
+) -> Result<Box<dyn PhysicalOp + 'a>> {
+    use PhysicalPlan::*;
+    Ok(match plan {
+        SeqScan {
+            table_name,
+            predicate,
+            schema_version,
+            ..
+        } => {
+            check_version(view.storage, &table_name, schema_version)?;
+            Box::new(SeqScanOp::new(view, table_name, predicate))
+        }
+        AsOfScan {
+            table_name,
+            lsn,
+            schema_version,
+        } => {
+            check_version(view.storage, &table_name, schema_version)?;
+            Box::new(AsOfScanOp::new(view, table_name, lsn))
+        }
+        IndexScan {
+            table_name,
+            index_name,
+            ranges,
+            index_only,
+            schema_version,
+            ..
+        } => {
+            check_version(view.storage, &table_name, schema_version)?;
+            let index = find_index(view.storage, &table_name, &index_name)?;
+            Box::new(IndexScanOp::new(view, index, ranges, index_only))
+        }
+        HashIndexScan {
+            table_name,
+            index_name,
+            keys,
+            schema_version,
+            ..
+        } => {
+            check_version(view.storage, &table_name, schema_version)?;
+            let index = find_index(view.storage, &table_name, &index_name)?;
+            Box::new(HashIndexScanOp::new(view, index, keys))
+        }
+        Filter {
+            input, predicate, ..
+        } => {
+            let child = build_read_operator_with(*input, view, memory)?;
+            Box::new(FilterOp::new(child, predicate))
+        }
+        Projection { input, exprs } => {
+            let child = build_read_operator_with(*input, view, memory)?;
+            Box::new(ProjectionOp::new(child, exprs))
+        }
+        HashSemiJoin {
+            input,
+            subquery,
+            keys,
+            collations,
+            anti,
+        } => {
+            let child = build_read_operator_with(*input, view.clone(), memory)?;
+            let subquery = build_read_operator_with(*subquery, view, memory)?;
+            Box::new(HashSemiJoinOp::new(
+                child,
+                subquery,
+                keys,
+                collations,
+                anti,
+                memory.clone(),
+            ))
+        }
+        Aggregate {
+            input,
+            keys,
+            aggregates,
+        } => {
+            let child = build_read_operator_with(*input, view, memory)?;
+            Box::new(AggregateOp::new(child, keys, aggregates, memory.clone()))
+        }
+        Sort { input, keys } => {
+            let child = build_read_operator_with(*input, view, memory)?;
+            Box::new(SortOp::new(child, keys, memory.clone()))
+        }
+        Append { inputs } => {
+            let children = inputs
+                .into_iter()
+                .map(|input| build_read_operator_with(input, view.clone(), memory))
+                .collect::<Result<_>>()?;
+            Box::new(AppendOp::new(children))
+        }
+        Merge { inputs, keys } => {
+            let children = inputs
+                .into_iter()
+                .map(|input| build_read_operator_with(input, view.clone(), memory))
+                .collect::<Result<_>>()?;
+            Box::new(MergeOp::new(children, keys))
+        }
+        SingleRow => Box::new(SingleRowOp::new()),
+        Explain { input, analyze } => {
+            // Runs the filter the column stats estimated, to show what it
+            // keeps beside the guess.
+            let actual = match input.estimated_filter() {
+                Some(filter) => Some(count_rows(build_read_operator_with(
+                    filter.clone(),
+                    view.clone(),
+                    memory,
+                )?)?),
+                None => None,
+            };
+            let mut op = ExplainOp::new(&input, actual);
+            if analyze {
+                let rows = count_rows(build_read_operator_with(*input, view.clone(), memory)?)?;
+                op.lines.push_back(format!("Rows: {}", rows));
+                op.lines.push_back(format!(
+                    "Memory: {} bytes at peak of {} work_mem, {} runs spilled",
+                    memory.peak(),
+                    memory.budget(),
+                    memory.spilled()
+                ));
+                if view.scan_errors == ScanErrorPolicy::SkipPage {
+                    let skipped = view.skipped_pages.load(Ordering::Relaxed);
+                    op.lines.push_back(format!("Skipped pages: {}", skipped));
+                }
+            }
+            Box::new(op)
+        }
+        ShowLocks => Box::new(ShowLocksOp::new(view.storage)),
+        ShowTables { schema } => Box::new(ShowTablesOp::new(view.storage, schema.as_deref())),
+        ShowGrants => Box::new(ShowGrantsOp::new(view.storage)),
+        other => {
+            return Err(anyhow!(
+                "{} writes and cannot run on shared storage",
+                other.explain()[0].trim()
+            ));
+        }
+    })
+}
+
+// Runs `op` to its end for the number of rows it makes.
+fn count_rows(mut op: Box<dyn PhysicalOp + '_>) -> Result<u64> {
+    op.open()?;
+    let mut rows = 0;
+    while op.next()?.is_some() {
+        rows += 1;
+    }
+    op.close()?;
+    Ok(rows)
+}
+
+// A plan holds column ordinals and index names from when it was made, so
+// it only runs against the tables as they were then.
+fn check_version(storage: &Storage, table_name: &str, version: u64) -> Result<()> {
+    match storage.catalog.get_table(table_name) {
+        Ok(info) if info.version == version => Ok(()),
+        _ => Err(SchemaChanged {
+            table: table_name.to_string(),
+        }
+        .into()),
+    }
+}
+
+fn find_index(storage: &Storage, table_name: &str, index_name: &str) -> Result<IndexInfo> {
+    storage
+        .get_indexes(table_name)
+        .into_iter()
+        .find(|i| i.name == index_name)
+        .ok_or_else(|| anyhow!("Index '{}' not found on '{}'", index_name, table_name))
+}
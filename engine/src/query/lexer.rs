@@ -1,12 +1,10 @@
-
-
+use crate::query::source::Span;
+use std::borrow::Cow;
 use std::iter::Peekable;
 use std::str::Chars;
 
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TokenKind {
-    
     Select,
     Insert,
     Update,
@@ -19,10 +17,66 @@ pub enum TokenKind {
     Table,
     Into,
     Values,
-    
+    Order,
+    By,
+    Group,
+    Limit,
+    Offset,
+    Join,
+    On,
+    As,
+    Is,
+    Collate,
+    Null,
+    True,
+    False,
+    In,
+    Between,
+    Like,
+    Not,
+    Distinct,
+    Having,
+    Union,
+    Index,
+    Using,
+    Explain,
+    Reindex,
+    Analyze,
+    Drop,
+    Show,
+    Set,
+    Locks,
+    Tables,
+    Check,
+    Begin,
+    Commit,
+    Rollback,
+    User,
+    Password,
+    Grant,
+    Grants,
+    Revoke,
+    All,
+    To,
+    Sequence,
+    Start,
+    Increment,
+    Temp,
+    View,
+    References,
+    Restrict,
+    Cascade,
+    Alter,
+    Add,
+    Asc,
+    Desc,
+    Copy,
+
     Identifier(String),
     IntLiteral(i64),
     StringLiteral(String),
+    // `@name`, a session variable, by its name in upper case.
+    Variable(String),
     
     Eq,    
     NotEq, 
@@ -36,6 +90,7 @@ pub enum TokenKind {
     Slash, 
     
     Comma,     
+    Dot,
     Semicolon, 
     LParen,    
     RParen,    
@@ -43,75 +98,176 @@ pub enum TokenKind {
     EOF,
 }
 
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
     pub kind: TokenKind,
-    pub line: usize,
-    pub col: usize,
+    pub span: Span,
 }
 
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LexError {
-    UnexpectedChar(char, usize, usize),
-    UnterminatedString(usize, usize),
-    InvalidNumber(String, usize, usize),
+    UnexpectedChar(char, Span),
+    UnterminatedString(Span),
+    UnterminatedIdentifier(Span),
+    InvalidNumber(String, Span),
 }
 
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnexpectedChar(_, span)
+            | LexError::UnterminatedString(span)
+            | LexError::UnterminatedIdentifier(span)
+            | LexError::InvalidNumber(_, span) => *span,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            LexError::UnexpectedChar(c, _) => format!("Unexpected character {:?}", c),
+            LexError::UnterminatedString(_) => "Unterminated string literal".to_string(),
+            LexError::UnterminatedIdentifier(_) => "Unterminated quoted identifier".to_string(),
+            LexError::InvalidNumber(n, _) => format!("Invalid number {}: out of range for INT", n),
+        }
+    }
+}
+
+// Every word the lexer reads as a keyword rather than a name. A keyword can
+// still name a table or column when double-quoted: `"order"`.
+pub const KEYWORDS: &[(&str, TokenKind)] = &[
+    ("ADD", TokenKind::Add),
+    ("ALL", TokenKind::All),
+    ("ALTER", TokenKind::Alter),
+    ("ANALYZE", TokenKind::Analyze),
+    ("AND", TokenKind::And),
+    ("AS", TokenKind::As),
+    ("ASC", TokenKind::Asc),
+    ("BEGIN", TokenKind::Begin),
+    ("BETWEEN", TokenKind::Between),
+    ("BY", TokenKind::By),
+    ("CASCADE", TokenKind::Cascade),
+    ("CHECK", TokenKind::Check),
+    ("COLLATE", TokenKind::Collate),
+    ("COMMIT", TokenKind::Commit),
+    ("COPY", TokenKind::Copy),
+    ("CREATE", TokenKind::Create),
+    ("DELETE", TokenKind::Delete),
+    ("DESC", TokenKind::Desc),
+    ("DISTINCT", TokenKind::Distinct),
+    ("DROP", TokenKind::Drop),
+    ("EXPLAIN", TokenKind::Explain),
+    ("FALSE", TokenKind::False),
+    ("FROM", TokenKind::From),
+    ("GRANT", TokenKind::Grant),
+    ("GRANTS", TokenKind::Grants),
+    ("GROUP", TokenKind::Group),
+    ("HAVING", TokenKind::Having),
+    ("IN", TokenKind::In),
+    ("INCREMENT", TokenKind::Increment),
+    ("INDEX", TokenKind::Index),
+    ("INSERT", TokenKind::Insert),
+    ("INTO", TokenKind::Into),
+    ("IS", TokenKind::Is),
+    ("JOIN", TokenKind::Join),
+    ("LIKE", TokenKind::Like),
+    ("LIMIT", TokenKind::Limit),
+    ("LOCKS", TokenKind::Locks),
+    ("NOT", TokenKind::Not),
+    ("NULL", TokenKind::Null),
+    ("OFFSET", TokenKind::Offset),
+    ("ON", TokenKind::On),
+    ("OR", TokenKind::Or),
+    ("ORDER", TokenKind::Order),
+    ("PASSWORD", TokenKind::Password),
+    ("REFERENCES", TokenKind::References),
+    ("REINDEX", TokenKind::Reindex),
+    ("RESTRICT", TokenKind::Restrict),
+    ("REVOKE", TokenKind::Revoke),
+    ("ROLLBACK", TokenKind::Rollback),
+    ("SELECT", TokenKind::Select),
+    ("SEQUENCE", TokenKind::Sequence),
+    ("SET", TokenKind::Set),
+    ("SHOW", TokenKind::Show),
+    ("START", TokenKind::Start),
+    ("TABLE", TokenKind::Table),
+    ("TABLES", TokenKind::Tables),
+    ("TEMP", TokenKind::Temp),
+    ("TEMPORARY", TokenKind::Temp),
+    ("TO", TokenKind::To),
+    ("TRUE", TokenKind::True),
+    ("UNION", TokenKind::Union),
+    ("UPDATE", TokenKind::Update),
+    ("USER", TokenKind::User),
+    ("USING", TokenKind::Using),
+    ("VALUES", TokenKind::Values),
+    ("VIEW", TokenKind::View),
+    ("WHERE", TokenKind::Where),
+];
+
+// The keyword `word` is, given in upper case.
+pub fn keyword(word: &str) -> Option<TokenKind> {
+    KEYWORDS
+        .binary_search_by(|(kw, _)| (*kw).cmp(word))
+        .ok()
+        .map(|i| KEYWORDS[i].1.clone())
+}
+
+// `name` as SQL has to spell it to mean that name: double-quoted when it is
+// a keyword or holds more than letters, digits and underscores.
+pub fn quote_identifier(name: &str) -> Cow<'_, str> {
+    let plain = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && keyword(&name.to_ascii_uppercase()).is_none();
+    match plain {
+        true => Cow::Borrowed(name),
+        false => Cow::Owned(format!("\"{}\"", name.replace('"', "\"\""))),
+    }
+}
 
 pub struct Lexer<'src> {
     input: Peekable<Chars<'src>>,
     src: &'src str,
-    
+    // Byte offset of the next character `input` gives.
     idx: usize,
-    line: usize,
-    col: usize,
+    finished: bool,
 }
 
 impl<'src> Lexer<'src> {
-    
     pub fn new(src: &'src str) -> Self {
         Lexer {
             input: src.chars().peekable(),
             src,
             idx: 0,
-            line: 1,
-            col: 1,
+            finished: false,
         }
     }
 
-    
     fn peek_char(&mut self) -> Option<char> {
         self.input.peek().copied()
     }
-
     
     fn next_char(&mut self) -> Option<char> {
         let c = self.input.next()?;
         self.idx += c.len_utf8();
-        if c == '\n' {
-            self.line += 1;
-            self.col = 1;
-        } else {
-            self.col += 1;
-        }
         Some(c)
     }
 
-    
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(start, self.idx)
+    }
+
     fn skip_whitespace_and_comments(&mut self) {
         loop {
-            
             while matches!(self.peek_char(), Some(c) if c.is_whitespace()) {
                 self.next_char();
             }
             
             if self.peek_char() == Some('-') {
-                
                 let mut iter = self.input.clone();
                 if iter.next() == Some('-') && iter.next() == Some('-') {
-                    
                     self.next_char();
                     self.next_char();
                     
@@ -128,59 +284,70 @@ impl<'src> Lexer<'src> {
         }
     }
 
-    
-    fn read_identifier_or_keyword(&mut self) -> String {
-        let start_idx = self.idx;
+    // Both readers are called with the first character still to come, so
+    // the text is a slice of the source from `start`.
+    fn read_identifier_or_keyword(&mut self, start: usize) -> String {
         while matches!(self.peek_char(), Some(c) if c.is_ascii_alphanumeric() || c == '_') {
             self.next_char();
         }
-        
-        self.src[start_idx..self.idx].to_ascii_uppercase()
+        self.src[start..self.idx].to_ascii_uppercase()
     }
 
-    
-    fn read_number(&mut self) -> Result<String, LexError> {
-        let start_idx = self.idx;
+    fn read_number(&mut self, start: usize) -> &'src str {
         while matches!(self.peek_char(), Some(c) if c.is_ascii_digit()) {
             self.next_char();
         }
-        Ok(self.src[start_idx..self.idx].to_string())
+        &self.src[start..self.idx]
     }
 
-    
-    fn read_string(&mut self) -> Result<String, LexError> {
-        
+    // Reads up to the closing `quote`, which is written twice to stand for
+    // itself. None if the text ends first.
+    fn read_quoted(&mut self, quote: char) -> Option<String> {
         let mut result = String::new();
         loop {
-            match self.next_char() {
-                Some('\'') => break,
-                Some(c) => result.push(c),
-                None => {
-                    return Err(LexError::UnterminatedString(self.line, self.col));
+            match self.next_char()? {
+                c if c == quote && self.peek_char() == Some(quote) => {
+                    self.next_char();
+                    result.push(quote);
                 }
+                c if c == quote => return Some(result),
+                c => result.push(c),
             }
         }
-        Ok(result)
     }
 
-    
     fn next_token(&mut self) -> Result<Token, LexError> {
         self.skip_whitespace_and_comments();
-        let (line, col) = (self.line, self.col);
-
-        let tok = match self.next_char() {
-            Some(c) => match c {
-                
-                ',' => TokenKind::Comma,
-                ';' => TokenKind::Semicolon,
-                '(' => TokenKind::LParen,
-                ')' => TokenKind::RParen,
-                '+' => TokenKind::Plus,
-                '-' => TokenKind::Minus,
-                '*' => TokenKind::Star,
-                '/' => TokenKind::Slash,
-                '=' => TokenKind::Eq,
-                '<' => {
+        let start = self.idx;
+        let kind = match self.peek_char() {
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.read_number(start);
+                match digits.parse::<i64>() {
+                    Ok(v) => TokenKind::IntLiteral(v),
+                    Err(_) => {
+                        return Err(LexError::InvalidNumber(
+                            digits.to_string(),
+                            self.span_from(start),
+                        ));
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let word = self.read_identifier_or_keyword(start);
+                keyword(&word).unwrap_or(TokenKind::Identifier(word))
+            }
+            _ => match self.next_char() {
+                Some(',') => TokenKind::Comma,
+                Some('.') => TokenKind::Dot,
+                Some(';') => TokenKind::Semicolon,
+                Some('(') => TokenKind::LParen,
+                Some(')') => TokenKind::RParen,
+                Some('+') => TokenKind::Plus,
+                Some('-') => TokenKind::Minus,
+                Some('*') => TokenKind::Star,
+                Some('/') => TokenKind::Slash,
+                Some('=') => TokenKind::Eq,
+                Some('<') => {
                     if self.peek_char() == Some('=') {
                         self.next_char();
                         TokenKind::LtEq
@@ -191,7 +358,7 @@ impl<'src> Lexer<'src> {
                         TokenKind::Lt
                     }
                 }
-                '>' => {
+                Some('>') => {
                     if self.peek_char() == Some('=') {
                         self.next_char();
                         TokenKind::GtEq
@@ -199,67 +366,31 @@ impl<'src> Lexer<'src> {
                         TokenKind::Gt
                     }
                 }
-                '\'' => {
-                    let s = self.read_string()?;
-                    return Ok(Token {
-                        kind: TokenKind::StringLiteral(s),
-                        line,
-                        col,
-                    });
-                }
-                c if c.is_ascii_digit() => {
-                    
-                    self.idx -= c.len_utf8();
-                    self.col -= 1;
-                    self.input.next_back(); 
-                    let num_str = self.read_number().map_err(|e| e)?;
-                    match num_str.parse::<i64>() {
-                        Ok(v) => {
-                            return Ok(Token {
-                                kind: TokenKind::IntLiteral(v),
-                                line,
-                                col,
-                            });
-                        }
-                        Err(_) => return Err(LexError::InvalidNumber(num_str, line, col)),
+                Some('\'') => match self.read_quoted('\'') {
+                    Some(s) => TokenKind::StringLiteral(s),
+                    None => return Err(LexError::UnterminatedString(self.span_from(start))),
+                },
+                // Quoting makes a name of anything, keywords included; its
+                // case is still folded like any other name's.
+                Some('@') => match self.peek_char() {
+                    Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                        TokenKind::Variable(self.read_identifier_or_keyword(start + 1))
                     }
+                    _ => return Err(LexError::UnexpectedChar('@', self.span_from(start))),
+                },
+                Some('"') => match self.read_quoted('"') {
+                    Some(name) => TokenKind::Identifier(name.to_ascii_uppercase()),
+                    None => return Err(LexError::UnterminatedIdentifier(self.span_from(start))),
+                },
+                Some(other) => {
+                    return Err(LexError::UnexpectedChar(other, self.span_from(start)));
                 }
-                c if c.is_ascii_alphabetic() || c == '_' => {
-                    
-                    self.idx -= c.len_utf8();
-                    self.col -= 1;
-                    self.input.next_back();
-                    let ident = self.read_identifier_or_keyword();
-                    
-                    return Ok(Token {
-                        kind: match ident.as_str() {
-                            "SELECT" => TokenKind::Select,
-                            "INSERT" => TokenKind::Insert,
-                            "UPDATE" => TokenKind::Update,
-                            "DELETE" => TokenKind::Delete,
-                            "FROM" => TokenKind::From,
-                            "WHERE" => TokenKind::Where,
-                            "AND" => TokenKind::And,
-                            "OR" => TokenKind::Or,
-                            "CREATE" => TokenKind::Create,
-                            "TABLE" => TokenKind::Table,
-                            "INTO" => TokenKind::Into,
-                            "VALUES" => TokenKind::Values,
-                            other => TokenKind::Identifier(other.to_string()),
-                        },
-                        line,
-                        col,
-                    });
-                }
-                other => return Err(LexError::UnexpectedChar(other, line, col)),
+                None => TokenKind::EOF,
             },
-            None => TokenKind::EOF,
         };
-
         Ok(Token {
-            kind: tok,
-            line,
-            col,
+            kind,
+            span: self.span_from(start),
         })
     }
 }
@@ -267,15 +398,13 @@ impl<'src> Lexer<'src> {
 impl<'src> Iterator for Lexer<'src> {
     type Item = Result<Token, LexError>;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.next_token() {
-            Ok(token) => {
-                if token.kind == TokenKind::EOF {
-                    Some(Ok(token))
-                } else {
-                    Some(Ok(token))
-                }
-            }
-            Err(e) => Some(Err(e)),
+        if self.finished {
+            return None;
         }
+        // Every error uses up the text it is about, so going on after one
+        // reads what follows instead of failing on the same text again.
+        let token = self.next_token();
+        self.finished = matches!(&token, Ok(token) if token.kind == TokenKind::EOF);
+        Some(token)
     }
 }
@@ -0,0 +1,193 @@
+use crate::query::value::Value;
+use crate::storage::storage::{decode_values, encode_values};
+use anyhow::{Context, Result};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+// Memory a statement's sorts, aggregates and joins may hold between them,
+// unless `work_mem` says otherwise.
+pub const DEFAULT_WORK_MEM: usize = 64 * 1024 * 1024;
+
+pub const OUT_OF_MEMORY: &str = "OUT_OF_MEMORY";
+
+// What a statement fails with when an operator that cannot spill needs
+// more memory than its budget has left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfMemory {
+    pub operator: &'static str,
+    pub budget: usize,
+}
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Out of memory: {} needs more than the {} bytes of work_mem",
+            self.operator, self.budget
+        )
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+// The memory one statement's operators hold, against its budget. A sort
+// that finds its rows do not fit writes them out and carries on; an
+// aggregate or a join, which cannot, fails with `OutOfMemory`. The counts
+// are estimates of what the rows take, not what the allocator hands out.
+#[derive(Debug)]
+pub struct MemoryTracker {
+    budget: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    spilled: AtomicU64,
+}
+
+impl MemoryTracker {
+    pub fn new(budget: usize) -> Arc<Self> {
+        Arc::new(Self::of(budget))
+    }
+
+    fn of(budget: usize) -> Self {
+        MemoryTracker {
+            budget,
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            spilled: AtomicU64::new(0),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    // Takes `bytes` if they fit in what is left.
+    pub fn reserve(&self, bytes: usize) -> bool {
+        if self.used.load(Ordering::Relaxed) + bytes > self.budget {
+            return false;
+        }
+        self.force(bytes);
+        true
+    }
+
+    // Takes `bytes` for `operator`, which cannot do without them.
+    pub fn charge(&self, operator: &'static str, bytes: usize) -> Result<()> {
+        if !self.reserve(bytes) {
+            return Err(OutOfMemory {
+                operator,
+                budget: self.budget,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    // Takes `bytes` whether or not they fit, for a single row bigger than
+    // the whole budget.
+    pub fn force(&self, bytes: usize) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(used, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // The most held at once so far.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    // Sorted runs written out to make room.
+    pub fn spilled(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for MemoryTracker {
+    fn default() -> Self {
+        MemoryTracker::of(DEFAULT_WORK_MEM)
+    }
+}
+
+// Roughly what a row takes in memory.
+pub fn row_bytes(row: &[Value]) -> usize {
+    size_of::<Vec<Value>>()
+        + row
+            .iter()
+            .map(|value| match value {
+                Value::String(s) => size_of::<Value>() + s.len(),
+                Value::Int(_) => size_of::<Value>(),
+            })
+            .sum::<usize>()
+}
+
+// Rows written out of memory to a file of their own, which is removed as
+// soon as it is made so nothing is left behind, even by a crash. Each row
+// is a u32 length and then its values as a heap page stores them.
+pub struct SpillWriter {
+    file: BufWriter<File>,
+}
+
+impl SpillWriter {
+    pub fn create(tracker: &MemoryTracker) -> Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "mydb-spill-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Creating spill file {:?}", path))?;
+        let _ = std::fs::remove_file(&path);
+        tracker.spilled.fetch_add(1, Ordering::Relaxed);
+        Ok(SpillWriter {
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub fn write(&mut self, row: &[Value]) -> Result<()> {
+        let mut buf = Vec::new();
+        encode_values(row, &mut buf);
+        self.file.write_all(&(buf.len() as u32).to_le_bytes())?;
+        self.file.write_all(&buf)?;
+        Ok(())
+    }
+
+    // The rows written, to read back from the first.
+    pub fn finish(self) -> Result<SpillReader> {
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(SpillReader {
+            file: BufReader::new(file),
+        })
+    }
+}
+
+pub struct SpillReader {
+    file: BufReader<File>,
+}
+
+impl SpillReader {
+    pub fn read_row(&mut self) -> Result<Option<Vec<Value>>> {
+        let mut len = [0; 4];
+        match self.file.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+        self.file.read_exact(&mut buf)?;
+        decode_values(&buf, 0).map(Some)
+    }
+}
@@ -1,20 +1,20 @@
-
-
 use crate::query::binder::BoundExpr;
 use crate::query::parser::BinaryOp;
 use crate::query::planner::LogicalPlan;
-use anyhow::Result;
+use anyhow::{Result, bail};
 
+// How deep a plan may nest before rewriting it is refused. The parser's
+// limits keep real queries far short of it; views stacked on views are
+// what gets closest.
+pub const MAX_PLAN_DEPTH: usize = 256;
 
 pub struct Optimizer;
 
 impl Optimizer {
-    
-    
     pub fn optimize(plan: LogicalPlan) -> Result<LogicalPlan> {
         let mut current = plan;
         loop {
-            let next = Self::rewrite(&current)?;
+            let next = Self::rewrite(&current, 0)?;
             if std::mem::discriminant(&next) == std::mem::discriminant(&current)
                 && format!("{:?}", next) == format!("{:?}", current)
             {
@@ -24,49 +24,109 @@ impl Optimizer {
         }
     }
 
-    
-    fn rewrite(plan: &LogicalPlan) -> Result<LogicalPlan> {
+    fn rewrite(plan: &LogicalPlan, depth: usize) -> Result<LogicalPlan> {
         use LogicalPlan::*;
 
-        
-        let rewritten = match plan {
-            CreateTable { .. } | CreateIndex { .. } | Insert { .. } => plan.clone(),
+        if depth > MAX_PLAN_DEPTH {
+            bail!(
+                "Query too deeply nested, more than {} plan levels",
+                MAX_PLAN_DEPTH
+            );
+        }
+        let depth = depth + 1;
 
+        let rewritten = match plan {
+            CreateTable { .. }
+            | CreateIndex { .. }
+            | Insert { .. }
+            | Reindex { .. }
+            | Analyze { .. }
+            | DropTable { .. }
+            | ShowLocks
+            | ShowTables { .. }
+            | ShowGrants
+            | Check
+            | Checkpoint
+            | FlushTables { .. }
+            | Vacuum { .. }
+            | SingleRow
+            | AsOfScan { .. } => plan.clone(),
             
             SeqScan { table, predicate } => SeqScan {
                 table: table.clone(),
                 predicate: predicate.clone(),
             },
-
             
+            Delete { table, input } => Delete {
+                table: table.clone(),
+                input: Box::new(Self::rewrite(input, depth)?),
+            },
+
             Filter { input, predicate } => {
-                let new_input = Self::rewrite(input)?;
+                let new_input = Self::rewrite(input, depth)?;
                 Filter {
                     input: Box::new(new_input),
                     predicate: predicate.clone(),
                 }
             }
-
             
             Projection { input, exprs } => {
-                let new_input = Self::rewrite(input)?;
+                let new_input = Self::rewrite(input, depth)?;
                 Projection {
                     input: Box::new(new_input),
                     exprs: exprs.clone(),
                 }
             }
+
+            SemiJoin {
+                input,
+                subquery,
+                keys,
+                collations,
+                anti,
+            } => SemiJoin {
+                input: Box::new(Self::rewrite(input, depth)?),
+                subquery: Box::new(Self::rewrite(subquery, depth)?),
+                keys: keys.clone(),
+                collations: collations.clone(),
+                anti: *anti,
+            },
+
+            Aggregate {
+                input,
+                keys,
+                aggregates,
+            } => Aggregate {
+                input: Box::new(Self::rewrite(input, depth)?),
+                keys: keys.clone(),
+                aggregates: aggregates.clone(),
+            },
+
+            Sort { input, keys } => Sort {
+                input: Box::new(Self::rewrite(input, depth)?),
+                keys: keys.clone(),
+            },
+
+            UnionAll { inputs } => UnionAll {
+                inputs: inputs
+                    .iter()
+                    .map(|input| Self::rewrite(input, depth))
+                    .collect::<Result<_>>()?,
+            },
+
+            Explain { input, analyze } => Explain {
+                input: Box::new(Self::rewrite(input, depth)?),
+                analyze: *analyze,
+            },
         };
 
-        
         Ok(Self::apply_rules(rewritten))
     }
-
     
     fn apply_rules(plan: LogicalPlan) -> LogicalPlan {
         use LogicalPlan::*;
 
         match plan {
-            
             Filter { input, predicate } => {
                 if let Filter {
                     input: inner,
@@ -85,6 +145,17 @@ impl Optimizer {
                     };
                 }
                 
+                if let SeqScan {
+                    table,
+                    predicate: None,
+                } = *input.clone()
+                {
+                    return SeqScan {
+                        table,
+                        predicate: Some(predicate),
+                    };
+                }
+
                 if let Projection {
                     input: proj_input,
                     exprs,
@@ -101,7 +172,6 @@ impl Optimizer {
                 Filter { input, predicate }
             }
 
-            
             Projection { input, exprs } => {
                 if let Projection {
                     input: inner,
@@ -116,7 +186,6 @@ impl Optimizer {
                 Projection { input, exprs }
             }
 
-            
             other => other,
         }
     }
@@ -0,0 +1,146 @@
+use crate::net::row::ColumnType;
+use crate::query::binder::{BoundExpr, BoundStmt, DataType as BoundType};
+use crate::query::value::Value;
+use crate::storage::storage::{DataType, Storage};
+use anyhow::{Result, bail};
+
+// `?` stands for a value passed beside the statement. The values go into
+// the text as literals before it is parsed, a string quoted the way the
+// lexer reads it back, so a value is only ever read as a value.
+
+// Where the `?`s of `sql` are, as byte offsets, leaving out any inside a
+// string, a quoted name or a comment.
+pub fn placeholders(sql: &str) -> Vec<usize> {
+    let mut found = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '?' => found.push(i),
+            '\'' | '"' => {
+                // A doubled quote stands for itself and the text goes on.
+                while let Some((_, d)) = chars.next() {
+                    if d == c && chars.next_if(|&(_, e)| e == c).is_none() {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.next_if(|&(_, d)| d == '-').is_some() => {
+                while chars.next_if(|&(_, d)| d != '\n').is_some() {}
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+// `sql` with its `?`s replaced by `params`, in order.
+pub fn bind_params(sql: &str, params: &[Value]) -> Result<String> {
+    let at = placeholders(sql);
+    if at.len() != params.len() {
+        bail!(
+            "The statement has {} placeholders but {} values were given",
+            at.len(),
+            params.len()
+        );
+    }
+    let mut bound = String::with_capacity(sql.len());
+    let mut from = 0;
+    for (i, value) in at.into_iter().zip(params) {
+        bound.push_str(&sql[from..i]);
+        bound.push_str(&literal(value));
+        from = i + 1;
+    }
+    bound.push_str(&sql[from..]);
+    Ok(bound)
+}
+
+// A negative number goes in parentheses, so that after a minus it does not
+// start a comment.
+pub fn literal(value: &Value) -> String {
+    match value {
+        Value::Int(i64::MIN) => format!("({} - 1)", i64::MIN + 1),
+        Value::Int(n) if *n < 0 => format!("({})", n),
+        Value::Int(n) => n.to_string(),
+        Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+    }
+}
+
+// The whole numbers of `bound` that are compared with something of a known
+// type, or inserted into a column, with that type. Describing a statement
+// stands a number in for each `?` and finds here what it has to be.
+pub fn literal_types(bound: &BoundStmt, storage: &Storage) -> Vec<(i64, ColumnType)> {
+    let mut found = Vec::new();
+    match bound {
+        BoundStmt::Select {
+            projections,
+            filter,
+            semi_joins,
+            ..
+        } => {
+            for expr in projections.iter().chain(filter) {
+                compared(expr, &mut found);
+            }
+            for join in semi_joins {
+                for expr in join.filter.iter() {
+                    compared(expr, &mut found);
+                }
+            }
+        }
+        BoundStmt::UnionAll { selects, .. } => {
+            for select in selects {
+                found.extend(literal_types(select, storage));
+            }
+        }
+        BoundStmt::Insert {
+            table,
+            col_ordinals,
+            rows,
+        } => {
+            let Ok(info) = storage.catalog.get_table(table) else {
+                return found;
+            };
+            for row in rows {
+                for (expr, &ordinal) in row.iter().zip(col_ordinals) {
+                    if let (BoundExpr::Literal(Value::Int(n)), Some(column)) =
+                        (expr, info.columns.get(ordinal))
+                    {
+                        let column_type = match column.data_type {
+                            DataType::Int => ColumnType::Int,
+                            DataType::String => ColumnType::Text,
+                        };
+                        found.push((*n, column_type));
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    found
+}
+
+fn compared(expr: &BoundExpr, found: &mut Vec<(i64, ColumnType)>) {
+    match expr {
+        BoundExpr::BinaryOp { left, right, .. } => {
+            for (side, other) in [(left, right), (right, left)] {
+                if let BoundExpr::Literal(Value::Int(n)) = side.as_ref()
+                    && !matches!(other.as_ref(), BoundExpr::Literal(_))
+                {
+                    let column_type = match other.data_type() {
+                        BoundType::Int => ColumnType::Int,
+                        BoundType::Varchar => ColumnType::Text,
+                    };
+                    found.push((*n, column_type));
+                }
+            }
+            compared(left, found);
+            compared(right, found);
+        }
+        BoundExpr::Call { args, .. } => {
+            for arg in args {
+                compared(arg, found);
+            }
+        }
+        BoundExpr::SetSeed(_, arg) => compared(arg, found),
+        _ => {}
+    }
+}
@@ -1,47 +1,275 @@
-
-
-use crate::query::lexer::{Lexer, Token, TokenKind};
-use anyhow::{Result, anyhow, bail};
-
+use crate::net::auth::Secret;
+use crate::query::datetime;
+use crate::query::lexer::{LexError, Lexer, Token, TokenKind};
+use crate::query::source::{SourceError, Span};
+pub use crate::query::value::{Collation, Value};
+use crate::storage::storage::{CheckConstraint, ForeignKey, OnDelete, Privilege};
+use anyhow::Result;
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
     CreateTable {
         name: String,
-        columns: Vec<(String, String)>,
+        columns: Vec<ColumnDef>,
+        // CREATE TEMP TABLE: kept by the session that made it, not the
+        // catalog.
+        temporary: bool,
+        foreign_keys: Vec<ForeignKey>,
+        checks: Vec<CheckConstraint>,
+    },
+    // `CREATE [TEMP] TABLE <name> [(<column>, ...)] AS SELECT ...`: a table
+    // with the SELECT's columns, filled with its rows. The list renames the
+    // columns; without one they are called what the SELECT calls them.
+    CreateTableAs {
+        name: String,
+        columns: Vec<String>,
+        temporary: bool,
+        select: Box<Statement>,
+    },
+    // `ALTER TABLE <table> ADD CHECK (...)`, which the rows already there
+    // have to pass.
+    AddCheck {
+        table: String,
+        check: CheckConstraint,
+    },
+    // `ALTER TABLE <table> RENAME TO <new_name>`.
+    RenameTable {
+        table: String,
+        new_name: String,
+    },
+    // `ALTER INDEX <index_name> RENAME TO <new_name>`, whichever table the
+    // index is on.
+    RenameIndex {
+        index_name: String,
+        new_name: String,
     },
     CreateIndex {
         index_name: String,
         table: String,
-        column: String,
+        columns: Vec<String>,
+        using: Option<String>,
     },
     Insert {
         table: String,
         columns: Vec<String>,
-        values: Vec<Expr>,
+        rows: Vec<Vec<Expr>>,
     },
+    // `DELETE FROM <table> [WHERE <condition>];`, every row without a
+    // condition.
+    Delete {
+        table: String,
+        filter: Option<Expr>,
+    },
+    // `COPY <table> [(columns)] FROM STDIN [BINARY];`, whose rows follow
+    // the statement rather than being part of it. No columns means all of
+    // them, in table order.
+    Copy {
+        table: String,
+        columns: Vec<String>,
+        format: CopyFormat,
+    },
+    // Without FROM there is a single row, with no columns to read. A
+    // number in GROUP BY or ORDER BY stands for that item of the SELECT
+    // list, counting from 1. `as_of` reads the table as it was at that
+    // LSN.
     Select {
         projections: Vec<Expr>,
-        table: String,
+        table: Option<String>,
+        as_of: Option<u64>,
         filter: Option<Expr>,
+        group_by: Vec<Expr>,
+        order_by: Vec<OrderBy>,
+    },
+    // `SELECT ... UNION ALL SELECT ...`: the rows of each SELECT, one after
+    // the other, all with the columns of the first. The ORDER BY after the
+    // last SELECT sorts the lot, by a column of the result or its position.
+    UnionAll {
+        selects: Vec<Statement>,
+        order_by: Vec<OrderBy>,
+    },
+    // With `analyze` the statement is run as well, to show how many rows
+    // it made and the memory it took.
+    Explain {
+        stmt: Box<Statement>,
+        analyze: bool,
+    },
+    Reindex {
+        index_name: String,
+        table: String,
+    },
+    Analyze {
+        table: String,
+    },
+    DropTable {
+        table: String,
+    },
+    CreateSequence {
+        name: String,
+        start: i64,
+        increment: i64,
+    },
+    DropSequence {
+        name: String,
+    },
+    // `query` is the SELECT as written, which is what the catalog keeps.
+    CreateView {
+        name: String,
+        select: Box<Statement>,
+        query: String,
+    },
+    DropView {
+        name: String,
+    },
+    // `CREATE SCHEMA <name>;`. What is made in it is named
+    // `<name>.<table>`.
+    CreateSchema {
+        name: String,
+    },
+    // `DROP SCHEMA <name> [RESTRICT | CASCADE];`. Without CASCADE the
+    // schema has to be empty; with it, its tables and views go too.
+    DropSchema {
+        name: String,
+        cascade: bool,
+    },
+    ShowLocks,
+    // `SHOW TABLES [IN <schema>];`
+    ShowTables {
+        schema: Option<String>,
+    },
+    Check,
+    // `CHECKPOINT;`: every dirty page to disk, then a checkpoint record.
+    Checkpoint,
+    // `FLUSH TABLES [<table>, ...];`: the dirty pages of the tables and
+    // their indexes to disk, or of every table without a list.
+    FlushTables {
+        tables: Vec<String>,
+    },
+    // `VACUUM [<table>, ...];`: takes back the slots of the rows deleted
+    // from the tables, or from every table without a list.
+    Vacuum {
+        tables: Vec<String>,
+    },
+    CreateUser {
+        name: String,
+        password: Secret,
+    },
+    DropUser {
+        name: String,
+    },
+    // Column-level when `columns` is not empty.
+    Grant {
+        privilege: Privilege,
+        columns: Vec<String>,
+        table: String,
+        user: String,
+    },
+    Revoke {
+        privilege: Privilege,
+        columns: Vec<String>,
+        table: String,
+        user: String,
+    },
+    ShowGrants,
+    // `SET [GLOBAL] <name> = <value>;`, with the value as written: a
+    // number, a word or a string. GLOBAL sets the server's value rather
+    // than the session's.
+    Set {
+        name: String,
+        value: String,
+        global: bool,
+    },
+    // `SET CONSTRAINTS ALL DEFERRED;` inside BEGIN ... COMMIT leaves the
+    // foreign keys of the rows inserted after it to be checked at COMMIT;
+    // `SET CONSTRAINTS ALL IMMEDIATE;` checks those rows now and goes back
+    // to checking each row as it goes in.
+    SetConstraints {
+        deferred: bool,
+    },
+    // `SET @<name> = <value>;` gives the session's variable a number or a
+    // string, which `@<name>` then stands for in its statements.
+    SetVariable {
+        name: String,
+        value: Value,
+    },
+    // `SHOW <name>;`, or every setting for `SHOW ALL;`.
+    ShowSettings {
+        name: Option<String>,
+    },
+    Begin,
+    Commit,
+    Rollback,
+    // `DECLARE CURSOR <name> FOR <select>;`, or `DECLARE <name> CURSOR FOR
+    // <select>;`, inside BEGIN ... COMMIT. The cursor lasts until the
+    // transaction ends or it is closed.
+    DeclareCursor {
+        name: String,
+        select: Box<Statement>,
+    },
+    // `FETCH [<count> | ALL] FROM <cursor>;`: the cursor's next `count`
+    // rows, one without a count. Once they run out it gives none.
+    Fetch {
+        cursor: String,
+        count: u64,
+    },
+    // `CLOSE <cursor>;`
+    CloseCursor {
+        name: String,
     },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Column(String),
+    // `<table>.<column>`, the table as the statement names it.
+    QualifiedColumn {
+        table: String,
+        column: String,
+    },
+    // `SELECT *`: every column, in the order the table declares them.
+    Star,
     Literal(Value),
     BinaryOp {
         left: Box<Expr>,
         op: BinaryOp,
         right: Box<Expr>,
     },
+    // A function such as NEXTVAL('s'), by its name in upper case.
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    // `[NOT] EXISTS (SELECT ...)`: whether the subquery finds a row.
+    Exists {
+        select: Box<Statement>,
+        negated: bool,
+    },
+    // `@<name>`, a session variable, by its name in upper case. Binding
+    // puts its value in its place.
+    Variable(String),
 }
 
+// How the rows after a COPY are written: lines of tab-separated fields,
+// or length-prefixed binary fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CopyFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+// A column as CREATE TABLE declares it, with its type as written.
 #[derive(Debug, Clone, PartialEq)]
-pub enum Value {
-    Int(i64),
-    String(String),
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: String,
+    pub collation: Collation,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBy {
+    pub expr: Expr,
+    pub descending: bool,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -52,34 +280,230 @@ pub enum BinaryOp {
     LtEq,
     Gt,
     GtEq,
+    // `IS [NOT] DISTINCT FROM`. With no NULL they are `<>` and `=`; once
+    // there is one, NULL is not distinct from NULL, and distinct from any
+    // other value, where `=` would be unknown.
+    IsDistinctFrom,
+    IsNotDistinctFrom,
     And,
     Or,
+    Add,
+    Sub,
+    Mul,
+    Div,
 }
 
+// Every syntax error in a piece of SQL, in the order they appear.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics(pub Vec<SourceError>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let [only] = self.0.as_slice() {
+            return write!(f, "{}", only);
+        }
+        write!(f, "{} syntax errors", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+// How deeply an expression may nest, counting parentheses, operators and
+// EXISTS subqueries. Binding, planning and evaluating all recurse as deep
+// again, so this keeps each of them well inside a thread's stack.
+pub const MAX_EXPR_DEPTH: usize = 200;
+
+// The most text and tokens one statement may have. Checked before it is
+// parsed.
+pub const MAX_STATEMENT_BYTES: usize = 8 * 1024 * 1024;
+pub const MAX_STATEMENT_TOKENS: usize = 1_000_000;
+
+// What the parser refuses before it gets to the grammar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    pub max_depth: usize,
+    pub max_statement_bytes: usize,
+    pub max_statement_tokens: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_depth: MAX_EXPR_DEPTH,
+            max_statement_bytes: MAX_STATEMENT_BYTES,
+            max_statement_tokens: MAX_STATEMENT_TOKENS,
+        }
+    }
+}
 
 pub struct Parser {
+    // Kept to quote in errors.
+    src: String,
     tokens: Vec<Token>,
     pos: usize,
+    limits: ParseLimits,
+    // How deep the expression being read is nested so far.
+    depth: usize,
+    // Tokens' worth of expression BETWEEN and IN have copied in this statement.
+    copied: usize,
 }
 
 impl Parser {
-    
     pub fn new(src: &str) -> Result<Self> {
         let mut tokens = Vec::new();
         for item in Lexer::new(src) {
-            
-            let tok = item.map_err(|e| anyhow!("Lex error: {:?}", e))?;
+            let tok = item.map_err(|e| SourceError::new(src, e.span(), e.message()))?;
             tokens.push(tok);
         }
-        Ok(Parser { tokens, pos: 0 })
+        Ok(Parser {
+            src: src.to_string(),
+            tokens,
+            pos: 0,
+            limits: ParseLimits::default(),
+            depth: 0,
+            copied: 0,
+        })
+    }
+
+    pub fn with_limits(mut self, limits: ParseLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Parses every statement of `src`. After an error it skips to the next
+    // `;` and goes on, so one pass finds every statement with a mistake in
+    // it; each is reported once, at its first. A statement the lexer choked
+    // on is reported for that alone.
+    pub fn parse_script(src: &str) -> Result<Vec<Statement>, Diagnostics> {
+        Self::parse_script_with(src, ParseLimits::default())
+    }
+
+    pub fn parse_script_with(
+        src: &str,
+        limits: ParseLimits,
+    ) -> Result<Vec<Statement>, Diagnostics> {
+        let (statements, diagnostics) = Self::parse_spanned(src, limits);
+        match diagnostics.is_empty() {
+            true => Ok(statements.into_iter().map(|(_, stmt)| stmt).collect()),
+            false => Err(Diagnostics(diagnostics)),
+        }
+    }
+
+    // Like `parse_script`, for where exactly one statement is allowed.
+    pub fn parse_one(src: &str) -> Result<Statement, Diagnostics> {
+        Self::parse_one_with(src, ParseLimits::default())
+    }
+
+    pub fn parse_one_with(src: &str, limits: ParseLimits) -> Result<Statement, Diagnostics> {
+        let (mut statements, diagnostics) = Self::parse_spanned(src, limits);
+        if !diagnostics.is_empty() {
+            return Err(Diagnostics(diagnostics));
+        }
+        let error = match statements.len() {
+            1 => return Ok(statements.remove(0).1),
+            0 => SourceError::new(
+                src,
+                Span::new(src.len(), src.len()),
+                "Expected a statement, found EOF",
+            ),
+            _ => SourceError::new(
+                src,
+                statements[1].0,
+                "Expected a single statement, found another",
+            ),
+        };
+        Err(Diagnostics(vec![error]))
+    }
+
+    fn parse_spanned(src: &str, limits: ParseLimits) -> (Vec<(Span, Statement)>, Vec<SourceError>) {
+        let (mut tokens, mut lex_errors) = (Vec::new(), Vec::new());
+        for item in Lexer::new(src) {
+            match item {
+                Ok(token) => tokens.push(token),
+                Err(e) => lex_errors.push(e),
+            }
+        }
+        let mut parser = Parser {
+            src: src.to_string(),
+            tokens,
+            pos: 0,
+            limits,
+            depth: 0,
+            copied: 0,
+        };
+        let (mut statements, mut diagnostics) = (Vec::new(), Vec::new());
+        while parser.peek().kind != TokenKind::EOF {
+            let span = parser.peek().span;
+            let parsed = parser.parse_statement();
+            if parsed.is_err() {
+                parser.skip_statement();
+            }
+            // Lex errors before the end of this statement's text belong to
+            // it; the ones of earlier statements are gone already. The last
+            // statement runs to the end of the text.
+            let end = match parser.peek().kind {
+                TokenKind::EOF => src.len(),
+                _ => parser.consumed_to(),
+            };
+            let (within, rest) = lex_errors
+                .into_iter()
+                .partition(|e: &LexError| e.span().start < end);
+            lex_errors = rest;
+            if !within.is_empty() {
+                diagnostics.extend(
+                    within
+                        .into_iter()
+                        .map(|e| SourceError::new(src, e.span(), e.message())),
+                );
+            } else {
+                match parsed {
+                    Ok(stmt) => statements.push((span, stmt)),
+                    Err(e) => diagnostics.push(e.downcast().unwrap_or_else(|e| {
+                        SourceError::new(src, span, format!("{:#}", e))
+                    })),
+                }
+            }
+        }
+        diagnostics.extend(
+            lex_errors
+                .into_iter()
+                .map(|e| SourceError::new(src, e.span(), e.message())),
+        );
+        (statements, diagnostics)
     }
 
+    // Passes over the rest of a statement that failed, up to and including
+    // its `;`.
+    fn skip_statement(&mut self) {
+        loop {
+            match self.peek().kind {
+                TokenKind::EOF => return,
+                TokenKind::Semicolon => {
+                    self.bump();
+                    return;
+                }
+                _ => {
+                    self.bump();
+                }
+            }
+        }
+    }
+
+    // The end of the last token parsed.
+    fn consumed_to(&self) -> usize {
+        match self.pos.min(self.tokens.len()) {
+            0 => 0,
+            pos => self.tokens[pos - 1].span.end,
+        }
+    }
+
+    // The lexer always ends with EOF, which is what lies past the end.
     fn peek(&self) -> &Token {
-        self.tokens.get(self.pos).unwrap_or(&Token {
-            kind: TokenKind::EOF,
-            line: 0,
-            col: 0,
-        })
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
     }
 
     fn bump(&mut self) -> Token {
@@ -89,60 +513,334 @@ impl Parser {
     }
 
     fn expect(&mut self, kind: TokenKind) -> Result<()> {
-        let t = self.peek();
-        if t.kind == kind {
+        if self.peek().kind == kind {
             self.bump();
             Ok(())
         } else {
-            bail!(
-                "Expected {:?} at {}:{}, found {:?}",
-                kind,
-                t.line,
-                t.col,
-                t.kind
-            );
+            Err(self.unexpected(&format!("{:?}", kind)))
+        }
+    }
+
+    // An error pointing at the next token, which is not what was expected.
+    fn unexpected(&self, expected: &str) -> anyhow::Error {
+        let token = self.peek();
+        SourceError::new(
+            &self.src,
+            token.span,
+            format!("Expected {}, found {:?}", expected, token.kind),
+        )
+        .into()
+    }
+
+    // A table or view, which may be named with its schema as
+    // `<schema>.<name>`; the dot is kept in the name.
+    fn table_name(&mut self, what: &str) -> Result<String> {
+        let name = self.identifier(what)?;
+        if !self.accept(TokenKind::Dot) {
+            return Ok(name);
+        }
+        Ok(format!("{}.{}", name, self.identifier(what)?))
+    }
+
+    fn identifier(&mut self, what: &str) -> Result<String> {
+        match &self.peek().kind {
+            TokenKind::Identifier(id) => {
+                let id = id.clone();
+                self.bump();
+                Ok(id)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    // Takes the next token if it is `kind`.
+    fn accept(&mut self, kind: TokenKind) -> bool {
+        let found = self.peek().kind == kind;
+        if found {
+            self.bump();
+        }
+        found
+    }
+
+    // Refuses the statement starting at the next token if it runs past the
+    // limits on its size, counting up to its `;`.
+    fn check_size(&self) -> Result<()> {
+        let start = self.peek().span;
+        let (mut tokens, mut end) = (0, start.end);
+        for token in &self.tokens[self.pos.min(self.tokens.len() - 1)..] {
+            if token.kind == TokenKind::EOF {
+                break;
+            }
+            tokens += 1;
+            end = token.span.end;
+            if token.kind == TokenKind::Semicolon {
+                break;
+            }
+        }
+        let bytes = end - start.start;
+        let message = if tokens > self.limits.max_statement_tokens {
+            format!(
+                "Statement too long: {} tokens, at most {}",
+                tokens, self.limits.max_statement_tokens
+            )
+        } else if bytes > self.limits.max_statement_bytes {
+            format!(
+                "Statement too long: {} bytes, at most {}",
+                bytes, self.limits.max_statement_bytes
+            )
+        } else {
+            return Ok(());
+        };
+        Err(SourceError::new(&self.src, start, message).into())
+    }
+
+    // Goes one level deeper into an expression, failing past the limit
+    // rather than recursing on until the stack runs out.
+    fn descend(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > self.limits.max_depth {
+            return Err(SourceError::new(
+                &self.src,
+                self.peek().span,
+                format!(
+                    "Expression too deeply nested, more than {} levels",
+                    self.limits.max_depth
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // Counts `size` more tokens' worth of expression copied. BETWEEN and IN
+    // copy their operand, so chaining them doubles the expression each time.
+    fn copy(&mut self, size: usize) -> Result<()> {
+        self.copied += size;
+        if self.copied > self.limits.max_statement_tokens {
+            return Err(SourceError::new(
+                &self.src,
+                self.peek().span,
+                format!(
+                    "Statement too long once BETWEEN and IN are expanded, more than {} tokens",
+                    self.limits.max_statement_tokens
+                ),
+            )
+            .into());
         }
+        Ok(())
+    }
+
+    // The kind of the token after the next one.
+    fn peek_second(&self) -> &TokenKind {
+        &self.tokens[(self.pos + 1).min(self.tokens.len() - 1)].kind
     }
 
-    
     pub fn parse_statement(&mut self) -> Result<Statement> {
+        self.check_size()?;
+        self.depth = 0;
+        self.copied = 0;
         match &self.peek().kind {
-            TokenKind::Create => {
-                
-                if let Some(tok) = self.tokens.get(self.pos + 1) {
-                    if let TokenKind::Identifier(ref s) = tok.kind {
-                        if s.eq_ignore_ascii_case("INDEX") {
-                            return self.parse_create_index();
-                        }
-                    }
+            TokenKind::Create => match self.peek_second() {
+                TokenKind::Index => self.parse_create_index(),
+                TokenKind::User => self.parse_create_user(),
+                TokenKind::Sequence => self.parse_create_sequence(),
+                TokenKind::View => self.parse_create_view(),
+                // SCHEMA is only a word here, so it is not a keyword.
+                TokenKind::Identifier(word) if word == "SCHEMA" => {
+                    self.bump();
+                    self.bump();
+                    let name = self.identifier("schema name")?;
+                    self.expect(TokenKind::Semicolon)?;
+                    Ok(Statement::CreateSchema { name })
                 }
-                self.parse_create_table()
-            }
+                _ => self.parse_create_table(),
+            },
             TokenKind::Insert => self.parse_insert(),
+            TokenKind::Delete => self.parse_delete(),
             TokenKind::Select => self.parse_select(),
-            other => bail!("Unexpected token {:?} at start of statement", other),
+            TokenKind::Alter => self.parse_alter_table(),
+            TokenKind::Copy => self.parse_copy(),
+            TokenKind::Explain => {
+                self.bump();
+                // `EXPLAIN ANALYZE t;` explains analyzing t.
+                let analyze = self.peek().kind == TokenKind::Analyze
+                    && !matches!(self.peek_second(), TokenKind::Identifier(_));
+                if analyze {
+                    self.bump();
+                }
+                if self.peek().kind == TokenKind::Explain {
+                    return Err(self.unexpected("a statement to explain"));
+                }
+                let stmt = Box::new(self.parse_statement()?);
+                Ok(Statement::Explain { stmt, analyze })
+            }
+            TokenKind::Reindex => self.parse_reindex(),
+            TokenKind::Analyze => {
+                self.bump();
+                let table = self.table_name("table name")?;
+                self.expect(TokenKind::Semicolon)?;
+                Ok(Statement::Analyze { table })
+            }
+            TokenKind::Begin | TokenKind::Commit | TokenKind::Rollback => {
+                let stmt = match self.bump().kind {
+                    TokenKind::Begin => Statement::Begin,
+                    TokenKind::Commit => Statement::Commit,
+                    _ => Statement::Rollback,
+                };
+                self.expect(TokenKind::Semicolon)?;
+                Ok(stmt)
+            }
+            TokenKind::Show => {
+                self.bump();
+                if self.accept(TokenKind::Tables) {
+                    let schema = match self.accept(TokenKind::In) {
+                        true => Some(self.identifier("schema name")?),
+                        false => None,
+                    };
+                    self.expect(TokenKind::Semicolon)?;
+                    return Ok(Statement::ShowTables { schema });
+                }
+                let stmt = match &self.peek().kind {
+                    TokenKind::Locks => Statement::ShowLocks,
+                    TokenKind::Grants => Statement::ShowGrants,
+                    TokenKind::All => Statement::ShowSettings { name: None },
+                    TokenKind::Identifier(name) => Statement::ShowSettings {
+                        name: Some(name.clone()),
+                    },
+                    _ => return Err(self.unexpected("GRANTS, LOCKS, TABLES, ALL or a setting")),
+                };
+                self.bump();
+                self.expect(TokenKind::Semicolon)?;
+                Ok(stmt)
+            }
+            TokenKind::Check => {
+                self.bump();
+                self.expect(TokenKind::Semicolon)?;
+                Ok(Statement::Check)
+            }
+            // CHECKPOINT, FLUSH and VACUUM are only words here.
+            TokenKind::Identifier(word) if word == "CHECKPOINT" => {
+                self.bump();
+                self.expect(TokenKind::Semicolon)?;
+                Ok(Statement::Checkpoint)
+            }
+            TokenKind::Identifier(word) if word == "FLUSH" => {
+                self.bump();
+                self.expect(TokenKind::Tables)?;
+                let mut tables = Vec::new();
+                if self.peek().kind != TokenKind::Semicolon {
+                    tables = self.parse_list(|parser| parser.table_name("table name"))?;
+                }
+                self.expect(TokenKind::Semicolon)?;
+                Ok(Statement::FlushTables { tables })
+            }
+            TokenKind::Identifier(word) if word == "VACUUM" => {
+                self.bump();
+                let mut tables = Vec::new();
+                if self.peek().kind != TokenKind::Semicolon {
+                    tables = self.parse_list(|parser| parser.table_name("table name"))?;
+                }
+                self.expect(TokenKind::Semicolon)?;
+                Ok(Statement::Vacuum { tables })
+            }
+            // DECLARE, CURSOR, FETCH and CLOSE are only words here.
+            TokenKind::Identifier(word) if word == "DECLARE" => self.parse_declare_cursor(),
+            TokenKind::Identifier(word) if word == "FETCH" => self.parse_fetch(),
+            TokenKind::Identifier(word) if word == "CLOSE" => {
+                self.bump();
+                let name = self.identifier("cursor name")?;
+                self.expect(TokenKind::Semicolon)?;
+                Ok(Statement::CloseCursor { name })
+            }
+            TokenKind::Set => self.parse_set(),
+            TokenKind::Grant | TokenKind::Revoke => self.parse_grant(),
+            TokenKind::Drop => {
+                self.bump();
+                if self.accept(TokenKind::User) {
+                    let name = self.identifier("user name")?;
+                    self.expect(TokenKind::Semicolon)?;
+                    return Ok(Statement::DropUser { name });
+                }
+                if self.accept(TokenKind::Sequence) {
+                    let name = self.identifier("sequence name")?;
+                    self.expect(TokenKind::Semicolon)?;
+                    return Ok(Statement::DropSequence { name });
+                }
+                if self.accept(TokenKind::View) {
+                    let name = self.table_name("view name")?;
+                    self.expect(TokenKind::Semicolon)?;
+                    return Ok(Statement::DropView { name });
+                }
+                if self.accept_word("SCHEMA") {
+                    let name = self.identifier("schema name")?;
+                    let cascade = self.accept(TokenKind::Cascade);
+                    if !cascade {
+                        self.accept(TokenKind::Restrict);
+                    }
+                    self.expect(TokenKind::Semicolon)?;
+                    return Ok(Statement::DropSchema { name, cascade });
+                }
+                self.expect(TokenKind::Table)?;
+                let table = self.table_name("table name")?;
+                self.expect(TokenKind::Semicolon)?;
+                Ok(Statement::DropTable { table })
+            }
+            _ => Err(self.unexpected("a statement")),
         }
     }
 
+    // `CREATE [TEMP | TEMPORARY] TABLE <name> (<column> <type>
+    // [COLLATE BINARY | NOCASE]
+    // [REFERENCES <table>(<column>) [ON DELETE RESTRICT | CASCADE]]
+    // [CHECK (<condition>)], ..., [CHECK (<condition>)]);`, or CREATE TABLE
+    // AS SELECT.
     fn parse_create_table(&mut self) -> Result<Statement> {
         self.expect(TokenKind::Create)?;
+        let temporary = self.accept(TokenKind::Temp);
         self.expect(TokenKind::Table)?;
-        let name = match self.bump().kind {
-            TokenKind::Identifier(id) => id,
-            _ => bail!("Expected table name"),
-        };
+        let name = self.table_name("table name")?;
+        if self.peek().kind == TokenKind::As || self.names_columns() {
+            return self.parse_create_table_as(name, temporary);
+        }
         self.expect(TokenKind::LParen)?;
         let mut cols = Vec::new();
+        let mut foreign_keys = Vec::new();
+        let mut checks = Vec::new();
         loop {
-            let col_name = match self.bump().kind {
-                TokenKind::Identifier(id) => id,
-                _ => bail!("Expected column name"),
-            };
-            let col_type = match self.bump().kind {
-                TokenKind::Identifier(tp) => tp,
-                _ => bail!("Expected type name"),
-            };
-            cols.push((col_name, col_type));
+            // A CHECK of its own may read any of the columns.
+            if self.accept(TokenKind::Check) {
+                checks.push(self.parse_check(format!("{}_CHECK", name))?);
+                if self.accept(TokenKind::Comma) {
+                    continue;
+                }
+                break;
+            }
+            let col_name = self.identifier("column name")?;
+            let col_type = self.identifier("type name")?;
+            let mut collation = Collation::Binary;
+            if self.accept(TokenKind::Collate) {
+                collation = match &self.peek().kind {
+                    TokenKind::Identifier(name) => Collation::parse(name),
+                    _ => None,
+                }
+                .ok_or_else(|| self.unexpected("BINARY or NOCASE"))?;
+                self.bump();
+            }
+            loop {
+                if self.accept(TokenKind::References) {
+                    foreign_keys.push(self.parse_references(&name, &col_name)?);
+                } else if self.accept(TokenKind::Check) {
+                    let check_name = format!("{}_{}_CHECK", name, col_name);
+                    checks.push(self.parse_check(check_name)?);
+                } else {
+                    break;
+                }
+            }
+            cols.push(ColumnDef {
+                name: col_name,
+                data_type: col_type,
+                collation,
+            });
             if self.peek().kind == TokenKind::Comma {
                 self.bump();
             } else {
@@ -154,67 +852,416 @@ impl Parser {
         Ok(Statement::CreateTable {
             name,
             columns: cols,
+            temporary,
+            foreign_keys,
+            checks,
         })
     }
 
-    fn parse_create_index(&mut self) -> Result<Statement> {
-        self.expect(TokenKind::Create)?;
-        
-        if let TokenKind::Identifier(ref s) = self.peek().kind {
-            if s.eq_ignore_ascii_case("INDEX") {
-                self.bump();
+    // `ALTER TABLE <table> ADD CHECK (<condition>);`, or RENAME TO for a
+    // table or an index. RENAME is only a word here, so it is not a keyword.
+    fn parse_alter_table(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Alter)?;
+        if self.accept(TokenKind::Index) {
+            let index_name = self.identifier("index name")?;
+            let new_name = self.parse_rename_to("RENAME")?;
+            return Ok(Statement::RenameIndex {
+                index_name,
+                new_name,
+            });
+        }
+        self.expect(TokenKind::Table)?;
+        let table = self.table_name("table name")?;
+        if self.peek().kind != TokenKind::Add {
+            let new_name = self.parse_rename_to("ADD or RENAME")?;
+            return Ok(Statement::RenameTable { table, new_name });
+        }
+        self.expect(TokenKind::Add)?;
+        self.expect(TokenKind::Check)?;
+        let check = self.parse_check(format!("{}_CHECK", table))?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::AddCheck { table, check })
+    }
+
+    // GLOBAL is only a word here, so it is not a keyword. ON and OFF read
+    // like any other word, TRUE and FALSE too. Words may be listed with
+    // commas, for a search path, and are then kept joined by `, `.
+    fn parse_set(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Set)?;
+        if self.accept_word("CONSTRAINTS") {
+            self.expect(TokenKind::All)?;
+            let deferred = if self.accept_word("DEFERRED") {
+                true
+            } else if self.accept_word("IMMEDIATE") {
+                false
             } else {
-                bail!("Expected INDEX");
+                return Err(self.unexpected("DEFERRED or IMMEDIATE"));
+            };
+            self.expect(TokenKind::Semicolon)?;
+            return Ok(Statement::SetConstraints { deferred });
+        }
+        if let TokenKind::Variable(name) = self.peek().kind.clone() {
+            self.bump();
+            if !self.accept(TokenKind::Eq) {
+                self.expect(TokenKind::To)?;
             }
-        } else {
-            bail!("Expected INDEX");
+            if !matches!(
+                self.peek().kind,
+                TokenKind::IntLiteral(_) | TokenKind::Minus | TokenKind::StringLiteral(_)
+            ) {
+                return Err(self.unexpected("a number or a string"));
+            }
+            let Expr::Literal(value) = self.parse_primary()? else {
+                unreachable!("a number or a string parses as a literal");
+            };
+            self.expect(TokenKind::Semicolon)?;
+            return Ok(Statement::SetVariable { name, value });
+        }
+        let global = self.accept_word("GLOBAL");
+        let name = self.identifier("setting name")?;
+        if !self.accept(TokenKind::Eq) {
+            self.expect(TokenKind::To)?;
+        }
+        let mut value = match self.peek().kind.clone() {
+            TokenKind::IntLiteral(n) => n.to_string(),
+            TokenKind::Identifier(word) | TokenKind::StringLiteral(word) => word,
+            TokenKind::On => "on".to_string(),
+            TokenKind::True => "true".to_string(),
+            TokenKind::False => "false".to_string(),
+            _ => return Err(self.unexpected("a value")),
+        };
+        self.bump();
+        while self.accept(TokenKind::Comma) {
+            value.push_str(", ");
+            value.push_str(&self.identifier("a value")?);
+        }
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::Set {
+            name,
+            value,
+            global,
+        })
+    }
+
+    // `RENAME TO <name>;`, where `expected` is what else could have come.
+    fn parse_rename_to(&mut self, expected: &str) -> Result<String> {
+        if !self.accept_word("RENAME") {
+            return Err(self.unexpected(expected));
+        }
+        self.expect(TokenKind::To)?;
+        let name = self.identifier("new name")?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(name)
+    }
+
+    // STDIN and BINARY are only words here, so they are not keywords.
+    fn parse_copy(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Copy)?;
+        let table = self.table_name("table name")?;
+        let mut columns = Vec::new();
+        if self.accept(TokenKind::LParen) {
+            columns = self.parse_list(|parser| parser.identifier("column name"))?;
+            self.expect(TokenKind::RParen)?;
+        }
+        self.expect(TokenKind::From)?;
+        if !self.accept_word("STDIN") {
+            return Err(self.unexpected("STDIN"));
+        }
+        let format = match self.accept_word("BINARY") {
+            true => CopyFormat::Binary,
+            false => CopyFormat::Text,
+        };
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::Copy {
+            table,
+            columns,
+            format,
+        })
+    }
+
+    // Takes the next token if it is the name `word`, given in upper case.
+    fn accept_word(&mut self, word: &str) -> bool {
+        let found = matches!(&self.peek().kind, TokenKind::Identifier(id) if id == word);
+        if found {
+            self.bump();
+        }
+        found
+    }
+
+    // The parenthesised condition after CHECK, which the catalog keeps as
+    // written.
+    fn parse_check(&mut self, name: String) -> Result<CheckConstraint> {
+        self.expect(TokenKind::LParen)?;
+        let start = self.peek().span.start;
+        self.parse_expr()?;
+        let end = self.consumed_to();
+        self.expect(TokenKind::RParen)?;
+        Ok(CheckConstraint {
+            name,
+            condition: self.src[start..end].to_string(),
+        })
+    }
+
+    // A condition on its own, as a CHECK constraint keeps it.
+    pub fn parse_condition(&mut self) -> Result<Expr> {
+        let expr = self.parse_expr()?;
+        if self.peek().kind != TokenKind::EOF {
+            return Err(self.unexpected("the end of the condition"));
         }
-        let index_name = match self.bump().kind {
-            TokenKind::Identifier(id) => id,
-            _ => bail!("Expected index name"),
+        Ok(expr)
+    }
+
+    // What follows REFERENCES. The key is named after the column it is on.
+    fn parse_references(&mut self, table: &str, column: &str) -> Result<ForeignKey> {
+        let parent = self.table_name("table name")?;
+        self.expect(TokenKind::LParen)?;
+        let parent_column = self.identifier("column name")?;
+        self.expect(TokenKind::RParen)?;
+        let mut on_delete = OnDelete::default();
+        if self.accept(TokenKind::On) {
+            self.expect(TokenKind::Delete)?;
+            on_delete = match self.peek().kind {
+                TokenKind::Restrict => OnDelete::Restrict,
+                TokenKind::Cascade => OnDelete::Cascade,
+                _ => return Err(self.unexpected("RESTRICT or CASCADE")),
+            };
+            self.bump();
+        }
+        Ok(ForeignKey {
+            name: format!("{}_{}_FKEY", table, column),
+            column: column.to_string(),
+            parent,
+            parent_column,
+            on_delete,
+        })
+    }
+
+    fn parse_create_user(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Create)?;
+        self.expect(TokenKind::User)?;
+        let name = self.identifier("user name")?;
+        self.expect(TokenKind::Password)?;
+        let password = match &self.peek().kind {
+            TokenKind::StringLiteral(s) => Secret(s.clone()),
+            _ => return Err(self.unexpected("password string")),
         };
-        
-        if let TokenKind::Identifier(ref s) = self.peek().kind {
-            if s.eq_ignore_ascii_case("ON") {
+        self.bump();
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::CreateUser { name, password })
+    }
+
+    // `CREATE SEQUENCE <name> [START <n>] [INCREMENT <n>];`, counting from 1
+    // by 1 unless told otherwise.
+    fn parse_create_sequence(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Create)?;
+        self.expect(TokenKind::Sequence)?;
+        let name = self.identifier("sequence name")?;
+        let start = match self.accept(TokenKind::Start) {
+            true => self.integer("start value")?,
+            false => 1,
+        };
+        let increment = match self.accept(TokenKind::Increment) {
+            true => self.integer("increment")?,
+            false => 1,
+        };
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::CreateSequence {
+            name,
+            start,
+            increment,
+        })
+    }
+
+    // Whether a parenthesised list of bare column names comes next, as
+    // before CREATE TABLE ... AS, rather than column definitions with types.
+    fn names_columns(&self) -> bool {
+        let kind = |ahead: usize| self.tokens.get(self.pos + ahead).map(|t| &t.kind);
+        kind(0) == Some(&TokenKind::LParen)
+            && matches!(kind(1), Some(TokenKind::Identifier(_)))
+            && matches!(kind(2), Some(TokenKind::Comma | TokenKind::RParen))
+    }
+
+    fn parse_create_table_as(&mut self, name: String, temporary: bool) -> Result<Statement> {
+        let mut columns = Vec::new();
+        if self.accept(TokenKind::LParen) {
+            columns = self.parse_list(|parser| parser.identifier("column name"))?;
+            self.expect(TokenKind::RParen)?;
+        }
+        self.expect(TokenKind::As)?;
+        if self.peek().kind != TokenKind::Select {
+            return Err(self.unexpected("SELECT"));
+        }
+        let select = self.parse_select()?;
+        Ok(Statement::CreateTableAs {
+            name,
+            columns,
+            temporary,
+            select: Box::new(select),
+        })
+    }
+
+    // `CREATE VIEW <name> AS SELECT ...;`
+    fn parse_create_view(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Create)?;
+        self.expect(TokenKind::View)?;
+        let name = self.table_name("view name")?;
+        self.expect(TokenKind::As)?;
+        if self.peek().kind != TokenKind::Select {
+            return Err(self.unexpected("SELECT"));
+        }
+        let start = self.peek().span.start;
+        let select = self.parse_select()?;
+        let end = self.tokens[self.pos - 1].span.end;
+        Ok(Statement::CreateView {
+            name,
+            select: Box::new(select),
+            query: self.src[start..end].to_string(),
+        })
+    }
+
+    fn parse_declare_cursor(&mut self) -> Result<Statement> {
+        self.bump();
+        let name = match self.accept_word("CURSOR") {
+            true => self.identifier("cursor name")?,
+            false => {
+                let name = self.identifier("cursor name")?;
+                if !self.accept_word("CURSOR") {
+                    return Err(self.unexpected("CURSOR"));
+                }
+                name
+            }
+        };
+        if !self.accept_word("FOR") {
+            return Err(self.unexpected("FOR"));
+        }
+        if self.peek().kind != TokenKind::Select {
+            return Err(self.unexpected("SELECT"));
+        }
+        let select = self.parse_select()?;
+        Ok(Statement::DeclareCursor {
+            name,
+            select: Box::new(select),
+        })
+    }
+
+    fn parse_fetch(&mut self) -> Result<Statement> {
+        self.bump();
+        let count = match self.peek().kind {
+            TokenKind::All => {
                 self.bump();
-            } else {
-                bail!("Expected ON");
+                u64::MAX
             }
-        } else {
-            bail!("Expected ON");
+            TokenKind::IntLiteral(count) if count > 0 => {
+                self.bump();
+                count as u64
+            }
+            TokenKind::From => 1,
+            _ => return Err(self.unexpected("a positive row count, ALL or FROM")),
+        };
+        self.expect(TokenKind::From)?;
+        let cursor = self.identifier("cursor name")?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::Fetch { cursor, count })
+    }
+
+    fn integer(&mut self, what: &str) -> Result<i64> {
+        let negative = self.accept(TokenKind::Minus);
+        match self.peek().kind {
+            TokenKind::IntLiteral(v) => {
+                self.bump();
+                Ok(if negative { -v } else { v })
+            }
+            _ => Err(self.unexpected(what)),
         }
-        let table = match self.bump().kind {
-            TokenKind::Identifier(id) => id,
-            _ => bail!("Expected table name"),
+    }
+
+    // `GRANT <privilege> [(<column>, ...)] ON <table> TO <user>;`, or REVOKE
+    // with FROM instead of TO.
+    fn parse_grant(&mut self) -> Result<Statement> {
+        let grant = self.bump().kind == TokenKind::Grant;
+        let privilege = match self.peek().kind {
+            TokenKind::Select => Privilege::Select,
+            TokenKind::Insert => Privilege::Insert,
+            TokenKind::All => Privilege::All,
+            _ => return Err(self.unexpected("SELECT, INSERT or ALL")),
         };
+        self.bump();
+        let mut columns = Vec::new();
+        if privilege != Privilege::All && self.accept(TokenKind::LParen) {
+            loop {
+                columns.push(self.identifier("column name")?);
+                if !self.accept(TokenKind::Comma) {
+                    break;
+                }
+            }
+            self.expect(TokenKind::RParen)?;
+        }
+        self.expect(TokenKind::On)?;
+        let table = self.table_name("table name")?;
+        self.expect(match grant {
+            true => TokenKind::To,
+            false => TokenKind::From,
+        })?;
+        let user = self.identifier("user name")?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(match grant {
+            true => Statement::Grant {
+                privilege,
+                columns,
+                table,
+                user,
+            },
+            false => Statement::Revoke {
+                privilege,
+                columns,
+                table,
+                user,
+            },
+        })
+    }
+
+    fn parse_reindex(&mut self) -> Result<Statement> {
+        self.bump();
+        let index_name = self.identifier("index name")?;
+        self.expect(TokenKind::On)?;
+        let table = self.table_name("table name")?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::Reindex { index_name, table })
+    }
+
+    fn parse_create_index(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Create)?;
+        self.expect(TokenKind::Index)?;
+        let index_name = self.identifier("index name")?;
+        self.expect(TokenKind::On)?;
+        let table = self.table_name("table name")?;
         self.expect(TokenKind::LParen)?;
-        let column = match self.bump().kind {
-            TokenKind::Identifier(id) => id,
-            _ => bail!("Expected column name"),
-        };
+        let mut columns = vec![self.identifier("column name")?];
+        while self.accept(TokenKind::Comma) {
+            columns.push(self.identifier("column name")?);
+        }
         self.expect(TokenKind::RParen)?;
+        let using = match self.accept(TokenKind::Using) {
+            true => Some(self.identifier("index method")?),
+            false => None,
+        };
         self.expect(TokenKind::Semicolon)?;
         Ok(Statement::CreateIndex {
             index_name,
             table,
-            column,
+            columns,
+            using,
         })
     }
 
     fn parse_insert(&mut self) -> Result<Statement> {
         self.expect(TokenKind::Insert)?;
         self.expect(TokenKind::Into)?;
-        let table = match self.bump().kind {
-            TokenKind::Identifier(id) => id,
-            _ => bail!("Expected table name"),
-        };
+        let table = self.table_name("table name")?;
         self.expect(TokenKind::LParen)?;
         let mut cols = Vec::new();
         loop {
-            match &self.bump().kind {
-                TokenKind::Identifier(id) => cols.push(id.clone()),
-                _ => bail!("Expected column name"),
-            }
+            cols.push(self.identifier("column name")?);
             if self.peek().kind == TokenKind::Comma {
                 self.bump();
             } else {
@@ -223,28 +1270,87 @@ impl Parser {
         }
         self.expect(TokenKind::RParen)?;
         self.expect(TokenKind::Values)?;
-        self.expect(TokenKind::LParen)?;
-        let mut vals = Vec::new();
+        let mut rows = Vec::new();
         loop {
-            vals.push(self.parse_expr()?);
+            self.expect(TokenKind::LParen)?;
+            let mut vals = Vec::new();
+            loop {
+                vals.push(self.parse_expr()?);
+                if self.peek().kind == TokenKind::Comma {
+                    self.bump();
+                } else {
+                    break;
+                }
+            }
+            self.expect(TokenKind::RParen)?;
+            rows.push(vals);
             if self.peek().kind == TokenKind::Comma {
                 self.bump();
             } else {
                 break;
             }
         }
-        self.expect(TokenKind::RParen)?;
         self.expect(TokenKind::Semicolon)?;
         Ok(Statement::Insert {
             table,
             columns: cols,
-            values: vals,
+            rows,
         })
     }
 
+    fn parse_delete(&mut self) -> Result<Statement> {
+        self.expect(TokenKind::Delete)?;
+        self.expect(TokenKind::From)?;
+        let table = self.table_name("table name")?;
+        let filter = match self.accept(TokenKind::Where) {
+            true => Some(self.parse_expr()?),
+            false => None,
+        };
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::Delete { table, filter })
+    }
+
     fn parse_select(&mut self) -> Result<Statement> {
+        let first = self.parse_select_body()?;
+        if self.peek().kind != TokenKind::Union {
+            self.expect(TokenKind::Semicolon)?;
+            return Ok(first);
+        }
+        let mut selects = vec![first];
+        while self.peek().kind == TokenKind::Union {
+            if let Some(Statement::Select { order_by, .. }) = selects.last()
+                && !order_by.is_empty()
+            {
+                return Err(self.unexpected("; after an ORDER BY, which only the last SELECT has"));
+            }
+            self.bump();
+            self.expect(TokenKind::All)?;
+            selects.push(self.parse_select_body()?);
+        }
+        // An ORDER BY read with the last SELECT belongs to all of them.
+        let order_by = match selects.last_mut() {
+            Some(Statement::Select { order_by, .. }) if !order_by.is_empty() => {
+                std::mem::take(order_by)
+            }
+            _ if self.accept(TokenKind::Order) => {
+                self.expect(TokenKind::By)?;
+                self.parse_order_by()?
+            }
+            _ => Vec::new(),
+        };
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Statement::UnionAll { selects, order_by })
+    }
+
+    // A SELECT up to where its statement could end.
+    fn parse_select_body(&mut self) -> Result<Statement> {
         self.expect(TokenKind::Select)?;
         let mut projections = Vec::new();
+        if self.accept(TokenKind::Star) {
+            projections.push(Expr::Star);
+            self.expect(TokenKind::From)?;
+            return self.parse_from(projections);
+        }
         loop {
             projections.push(self.parse_expr()?);
             if self.peek().kind == TokenKind::Comma {
@@ -253,10 +1359,28 @@ impl Parser {
                 break;
             }
         }
+        if matches!(
+            self.peek().kind,
+            TokenKind::Semicolon | TokenKind::Union | TokenKind::Order | TokenKind::RParen
+        ) {
+            return Ok(Statement::Select {
+                projections,
+                table: None,
+                as_of: None,
+                filter: None,
+                group_by: Vec::new(),
+                order_by: Vec::new(),
+            });
+        }
         self.expect(TokenKind::From)?;
-        let table = match self.bump().kind {
-            TokenKind::Identifier(id) => id,
-            _ => bail!("Expected table name"),
+        self.parse_from(projections)
+    }
+
+    fn parse_from(&mut self, projections: Vec<Expr>) -> Result<Statement> {
+        let table = Some(self.table_name("table name")?);
+        let as_of = match self.accept(TokenKind::As) {
+            true => Some(self.parse_as_of()?),
+            false => None,
         };
         let filter = if self.peek().kind == TokenKind::Where {
             self.bump();
@@ -264,25 +1388,102 @@ impl Parser {
         } else {
             None
         };
-        self.expect(TokenKind::Semicolon)?;
+        let mut group_by = Vec::new();
+        if self.accept(TokenKind::Group) {
+            self.expect(TokenKind::By)?;
+            group_by = self.parse_list(Self::parse_expr)?;
+        }
+        let mut order_by = Vec::new();
+        if self.accept(TokenKind::Order) {
+            self.expect(TokenKind::By)?;
+            order_by = self.parse_order_by()?;
+        }
         Ok(Statement::Select {
             projections,
             table,
+            as_of,
             filter,
+            group_by,
+            order_by,
         })
     }
 
+    // The keys after ORDER BY.
+    fn parse_order_by(&mut self) -> Result<Vec<OrderBy>> {
+        self.parse_list(|parser| {
+            let expr = parser.parse_expr()?;
+            let descending = parser.accept(TokenKind::Desc);
+            if !descending {
+                parser.accept(TokenKind::Asc);
+            }
+            Ok(OrderBy { expr, descending })
+        })
+    }
+
+    // `OF LSN <n>`, after the AS.
+    fn parse_as_of(&mut self) -> Result<u64> {
+        if !self.accept_word("OF") {
+            return Err(self.unexpected("OF"));
+        }
+        if !self.accept_word("LSN") {
+            return Err(self.unexpected("LSN"));
+        }
+        match self.peek().kind {
+            TokenKind::IntLiteral(v) => {
+                self.bump();
+                Ok(v as u64)
+            }
+            _ => Err(self.unexpected("an LSN")),
+        }
+    }
+
+    // One or more items separated by commas.
+    fn parse_list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let mut items = vec![item(self)?];
+        while self.accept(TokenKind::Comma) {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
     fn parse_expr(&mut self) -> Result<Expr> {
         self.parse_binary_op(0)
     }
 
+    // Each call, and each operator it chains onto what it has read, nests
+    // the expression one level deeper.
     fn parse_binary_op(&mut self, min_prec: u8) -> Result<Expr> {
+        let (outer, start) = (self.depth, self.pos + self.copied);
+        self.descend()?;
         let mut left = self.parse_primary()?;
-        while let Some((op, prec)) = self.peek_op_prec() {
+        loop {
+            if min_prec <= 10 && self.peek().kind == TokenKind::Between {
+                self.descend()?;
+                self.copy(self.pos + self.copied - start)?;
+                left = self.parse_between(left)?;
+                continue;
+            }
+            if min_prec <= 10
+                && (self.peek().kind == TokenKind::In
+                    || self.peek().kind == TokenKind::Not && *self.peek_second() == TokenKind::In)
+            {
+                self.descend()?;
+                left = self.parse_in(left, self.pos + self.copied - start)?;
+                continue;
+            }
+            if min_prec <= 10 && self.peek().kind == TokenKind::Is {
+                self.descend()?;
+                left = self.parse_is(left)?;
+                continue;
+            }
+            let Some((op, prec)) = self.peek_op_prec() else {
+                break;
+            };
             if prec < min_prec {
                 break;
             }
             self.bump();
+            self.descend()?;
             let right = self.parse_binary_op(prec + 1)?;
             left = Expr::BinaryOp {
                 left: Box::new(left),
@@ -290,9 +1491,77 @@ impl Parser {
                 right: Box::new(right),
             };
         }
+        self.depth = outer;
         Ok(left)
     }
 
+    fn parse_between(&mut self, operand: Expr) -> Result<Expr> {
+        self.bump();
+        let low = self.parse_binary_op(11)?;
+        self.expect(TokenKind::And)?;
+        let high = self.parse_binary_op(11)?;
+        Ok(Expr::BinaryOp {
+            left: Box::new(Expr::BinaryOp {
+                left: Box::new(operand.clone()),
+                op: BinaryOp::GtEq,
+                right: Box::new(low),
+            }),
+            op: BinaryOp::And,
+            right: Box::new(Expr::BinaryOp {
+                left: Box::new(operand),
+                op: BinaryOp::LtEq,
+                right: Box::new(high),
+            }),
+        })
+    }
+
+    // `a [NOT] IN (x, y, ...)`, binding as tightly as `=`, read as `a = x OR
+    // a = y ...`, or with NOT as `a <> x AND a <> y ...`. Each value after
+    // the first copies `a`, which is `size` tokens long, and nests the
+    // expression one level deeper.
+    fn parse_in(&mut self, operand: Expr, size: usize) -> Result<Expr> {
+        let (op, join) = match self.accept(TokenKind::Not) {
+            true => (BinaryOp::NotEq, BinaryOp::And),
+            false => (BinaryOp::Eq, BinaryOp::Or),
+        };
+        self.expect(TokenKind::In)?;
+        self.expect(TokenKind::LParen)?;
+        let term = |value| Expr::BinaryOp {
+            left: Box::new(operand.clone()),
+            op,
+            right: Box::new(value),
+        };
+        let mut expr = term(self.parse_expr()?);
+        while self.accept(TokenKind::Comma) {
+            self.descend()?;
+            self.copy(size)?;
+            expr = Expr::BinaryOp {
+                left: Box::new(expr),
+                op: join,
+                right: Box::new(term(self.parse_expr()?)),
+            };
+        }
+        self.expect(TokenKind::RParen)?;
+        Ok(expr)
+    }
+
+    // `a IS [NOT] DISTINCT FROM b`, binding as tightly as `=`.
+    fn parse_is(&mut self, left: Expr) -> Result<Expr> {
+        self.bump();
+        let op = match self.accept(TokenKind::Not) {
+            true => BinaryOp::IsNotDistinctFrom,
+            false => BinaryOp::IsDistinctFrom,
+        };
+        self.expect(TokenKind::Distinct)?;
+        self.expect(TokenKind::From)?;
+        let right = self.parse_binary_op(11)?;
+        Ok(Expr::BinaryOp {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        })
+    }
+
     fn peek_op_prec(&self) -> Option<(BinaryOp, u8)> {
         use BinaryOp::*;
         match self.peek().kind {
@@ -304,6 +1573,10 @@ impl Parser {
             TokenKind::GtEq => Some((GtEq, 10)),
             TokenKind::And => Some((And, 5)),
             TokenKind::Or => Some((Or, 4)),
+            TokenKind::Plus => Some((Add, 20)),
+            TokenKind::Minus => Some((Sub, 20)),
+            TokenKind::Star => Some((Mul, 30)),
+            TokenKind::Slash => Some((Div, 30)),
             _ => None,
         }
     }
@@ -313,25 +1586,136 @@ impl Parser {
             TokenKind::Identifier(id) => {
                 let c = id.clone();
                 self.bump();
-                Ok(Expr::Column(c))
+                if c == "EXISTS" && self.peek().kind == TokenKind::LParen {
+                    return self.parse_exists(false);
+                }
+                if c == "EXTRACT" && self.peek().kind == TokenKind::LParen {
+                    return self.parse_extract();
+                }
+                if c == "INTERVAL"
+                    && let TokenKind::StringLiteral(count) = &self.peek().kind
+                {
+                    let count = count.clone();
+                    return self.parse_interval(&count);
+                }
+                if self.accept(TokenKind::Dot) {
+                    return self.parse_qualified(c);
+                }
+                if !self.accept(TokenKind::LParen) {
+                    return Ok(Expr::Column(c));
+                }
+                let mut args = Vec::new();
+                // COUNT(*)
+                if self.accept(TokenKind::Star) {
+                    self.expect(TokenKind::RParen)?;
+                    return Ok(Expr::Call {
+                        name: c.to_ascii_uppercase(),
+                        args: vec![Expr::Star],
+                    });
+                }
+                if !self.accept(TokenKind::RParen) {
+                    loop {
+                        args.push(self.parse_expr()?);
+                        if !self.accept(TokenKind::Comma) {
+                            break;
+                        }
+                    }
+                    self.expect(TokenKind::RParen)?;
+                }
+                Ok(Expr::Call {
+                    name: c.to_ascii_uppercase(),
+                    args,
+                })
+            }
+            // NOT only comes before EXISTS; there is no other use for it
+            // yet.
+            TokenKind::Not => {
+                self.bump();
+                if !self.accept_word("EXISTS") {
+                    return Err(self.unexpected("EXISTS after NOT"));
+                }
+                self.parse_exists(true)
             }
             TokenKind::IntLiteral(v) => {
                 let i = *v;
                 self.bump();
                 Ok(Expr::Literal(Value::Int(i)))
             }
+            // Where an operand is expected, a minus makes a number
+            // negative; between two it subtracts.
+            TokenKind::Minus => {
+                self.bump();
+                match self.peek().kind {
+                    TokenKind::IntLiteral(v) => {
+                        self.bump();
+                        Ok(Expr::Literal(Value::Int(-v)))
+                    }
+                    _ => Err(self.unexpected("a number after '-'")),
+                }
+            }
             TokenKind::StringLiteral(s) => {
                 let s2 = s.clone();
                 self.bump();
                 Ok(Expr::Literal(Value::String(s2)))
             }
+            TokenKind::Variable(name) => {
+                let name = name.clone();
+                self.bump();
+                Ok(Expr::Variable(name))
+            }
             TokenKind::LParen => {
                 self.bump();
                 let e = self.parse_expr()?;
                 self.expect(TokenKind::RParen)?;
                 Ok(e)
             }
-            other => bail!("Unexpected token in expression: {:?}", other),
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+
+    // The parenthesised SELECT after EXISTS.
+    fn parse_exists(&mut self, negated: bool) -> Result<Expr> {
+        self.expect(TokenKind::LParen)?;
+        let select = self.parse_select_body()?;
+        self.expect(TokenKind::RParen)?;
+        Ok(Expr::Exists {
+            select: Box::new(select),
+            negated,
+        })
+    }
+
+    // `EXTRACT(<field> FROM <time>)`, read as the call
+    // `EXTRACT('<field>', <time>)`.
+    fn parse_extract(&mut self) -> Result<Expr> {
+        self.expect(TokenKind::LParen)?;
+        let field = self.identifier("a field such as YEAR")?;
+        self.expect(TokenKind::From)?;
+        let time = self.parse_expr()?;
+        self.expect(TokenKind::RParen)?;
+        Ok(Expr::Call {
+            name: "EXTRACT".to_string(),
+            args: vec![Expr::Literal(Value::String(field)), time],
+        })
+    }
+
+    // `INTERVAL '<count>' <unit>`, which is its length in seconds.
+    fn parse_interval(&mut self, count: &str) -> Result<Expr> {
+        let span = self.bump().span;
+        let unit = self.identifier("a unit such as DAY")?;
+        let seconds = datetime::interval(count, &unit)
+            .map_err(|e| SourceError::new(&self.src, span, e.to_string()))?;
+        Ok(Expr::Literal(Value::Int(seconds)))
+    }
+
+    // The rest of `<table>.<column>` once the first name and its dot are
+    // read; the table may itself be `<schema>.<name>`.
+    fn parse_qualified(&mut self, first: String) -> Result<Expr> {
+        let mut table = first;
+        let mut column = self.identifier("column name")?;
+        if self.accept(TokenKind::Dot) {
+            table = format!("{}.{}", table, column);
+            column = self.identifier("column name")?;
         }
+        Ok(Expr::QualifiedColumn { table, column })
     }
 }
@@ -1,86 +1,470 @@
-
-
-use crate::query::binder::{BoundExpr, DataType};
+use crate::index::bplustree::index_ranges;
+use crate::index::key::Key;
+use crate::net::row::{ColumnType, Schema};
+use crate::query::binder::{Aggregate, BoundExpr, Collation, DataType, SortKey};
 use crate::query::parser::BinaryOp;
 use crate::query::planner::LogicalPlan;
-use crate::storage::storage::Storage;
+use crate::query::value::Value;
+use crate::storage::column_stats::ColumnStats;
+use crate::storage::storage::{IndexKind, Storage, TableInfo};
+use crate::tx::log_manager::Lsn;
 use anyhow::{Result, bail};
+use std::collections::BTreeMap;
 
+// What a predicate is taken to keep when the column stats cannot tell.
+const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
 
-
-
-
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum PhysicalPlan {
-    
     CreateTable {
         table_name: String,
         columns: Vec<(String, DataType)>,
     },
 
-    
-    
-
-    
+    // Each node that reads or writes a table carries the version of it the
+    // statement was bound against.
     Insert {
         table_name: String,
         col_ordinals: Vec<usize>,
-        values: Vec<BoundExpr>,
+        rows: Vec<Vec<BoundExpr>>,
+        schema_version: u64,
+    },
+
+    // Reads its input to the end, then deletes each row it returned. The
+    // input is a scan of the table, by index where SELECT would use one,
+    // under a filter.
+    Delete {
+        table_name: String,
+        input: Box<PhysicalPlan>,
+        schema_version: u64,
     },
 
-    
     SeqScan {
         table_name: String,
         predicate: Option<BoundExpr>,
+        // The rows the catalog counts in the table, which the scan reads.
+        estimated_rows: u64,
+        schema_version: u64,
     },
 
-    
+    // The rows as of `lsn`, which are read and rebuilt when the scan opens.
+    AsOfScan {
+        table_name: String,
+        lsn: Lsn,
+        schema_version: u64,
+    },
+
+    SingleRow,
+
+    // Reads each range of keys in turn, in key order: one for a range or
+    // an equality, one for each value of an IN list, or each list of
+    // values of the columns of a composite index.
     IndexScan {
         table_name: String,
         index_name: String,
-        predicate: BoundExpr, 
+        columns: Vec<String>,
+        ranges: Vec<(Key, Key)>,
+        index_only: bool,
+        schema_version: u64,
+    },
+
+    HashIndexScan {
+        table_name: String,
+        index_name: String,
+        column: String,
+        keys: Vec<u64>,
+        schema_version: u64,
     },
 
-    
     Filter {
         input: Box<PhysicalPlan>,
         predicate: BoundExpr,
+        // The rows the column stats expect it to keep, once the table has
+        // been analyzed.
+        estimated_rows: Option<u64>,
     },
 
-    
     Projection {
         input: Box<PhysicalPlan>,
         exprs: Vec<BoundExpr>,
     },
+
+    // Reads the subquery into a set of its rows when it opens, then keeps
+    // the input rows whose keys are in it, or with `anti`, are not. Each
+    // input row comes out at most once.
+    HashSemiJoin {
+        input: Box<PhysicalPlan>,
+        subquery: Box<PhysicalPlan>,
+        keys: Vec<BoundExpr>,
+        collations: Vec<Collation>,
+        anti: bool,
+    },
+
+    Aggregate {
+        input: Box<PhysicalPlan>,
+        keys: Vec<BoundExpr>,
+        aggregates: Vec<Aggregate>,
+    },
+
+    // Reads its whole input before handing out the first row.
+    Sort {
+        input: Box<PhysicalPlan>,
+        keys: Vec<SortKey>,
+    },
+
+    // The rows of each input in turn.
+    Append {
+        inputs: Vec<PhysicalPlan>,
+    },
+
+    // Inputs that each come in the order of `keys` already, merged into
+    // that order holding one row of each rather than sorted whole.
+    Merge {
+        inputs: Vec<PhysicalPlan>,
+        keys: Vec<SortKey>,
+    },
+
+    Explain {
+        input: Box<PhysicalPlan>,
+        analyze: bool,
+    },
+
+    Reindex {
+        table_name: String,
+        index_name: String,
+    },
+
+    Analyze {
+        table_name: String,
+    },
+
+    DropTable {
+        table_name: String,
+    },
+
+    ShowLocks,
+
+    ShowTables {
+        schema: Option<String>,
+    },
+
+    ShowGrants,
+
+    Check,
+
+    Checkpoint,
+
+    FlushTables {
+        tables: Vec<String>,
+    },
+
+    Vacuum {
+        tables: Vec<String>,
+    },
 }
 
+impl PhysicalPlan {
+    pub fn explain(&self) -> Vec<String> {
+        self.explain_with(None)
+    }
 
+    // With the rows the estimated filter actually kept, when it was run to
+    // find out.
+    pub fn explain_with(&self, actual_rows: Option<u64>) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.explain_into(0, actual_rows, &mut lines);
+        lines
+    }
 
+    // The filter whose rows the column stats estimated, if any.
+    pub fn estimated_filter(&self) -> Option<&PhysicalPlan> {
+        use PhysicalPlan::*;
+        match self {
+            Filter {
+                estimated_rows: Some(_),
+                ..
+            } => Some(self),
+            Filter { input, .. }
+            | Delete { input, .. }
+            | Projection { input, .. }
+            | HashSemiJoin { input, .. }
+            | Aggregate { input, .. }
+            | Sort { input, .. } => input.estimated_filter(),
+            _ => None,
+        }
+    }
 
+    // The columns the plan produces, named as a client labels them. A scan
+    // is always under a projection and names nothing itself.
+    pub fn schema(&self) -> Schema {
+        use ColumnType::{Int, Text};
+        use PhysicalPlan::*;
+        let columns: &[(&str, ColumnType)] = match self {
+            Projection { exprs, .. } => {
+                let typed = exprs.iter().map(|e| {
+                    let data_type = match e.data_type() {
+                        DataType::Int => Int,
+                        DataType::Varchar => Text,
+                    };
+                    (e.name(), data_type)
+                });
+                return Schema::typed(typed.collect());
+            }
+            Filter { input, .. } | HashSemiJoin { input, .. } | Sort { input, .. } => {
+                return input.schema();
+            }
+            // Every input has the first's columns.
+            Append { inputs } | Merge { inputs, .. } => {
+                return inputs.first().map(PhysicalPlan::schema).unwrap_or_default();
+            }
+            Explain { .. } => &[("plan", Text)],
+            Reindex { .. } => &[("keys", Int), ("elapsed_ms", Int)],
+            Analyze { .. } => &[("indexes", Int)],
+            ShowLocks => &[
+                ("resource", Text),
+                ("tx", Int),
+                ("mode", Text),
+                ("status", Text),
+                ("waited_ms", Int),
+            ],
+            ShowTables { .. } => &[
+                ("table", Text),
+                ("rows", Int),
+                ("pages", Int),
+                ("bytes", Int),
+                ("kind", Text),
+                ("inserted", Int),
+                ("deleted", Int),
+                ("analyzed_at", Int),
+            ],
+            ShowGrants => &[
+                ("table", Text),
+                ("user", Text),
+                ("privilege", Text),
+                ("column", Text),
+            ],
+            Checkpoint => &[
+                ("lsn", Int),
+                ("pages", Int),
+                ("bytes", Int),
+                ("elapsed_ms", Int),
+            ],
+            FlushTables { .. } => &[("pages", Int), ("bytes", Int)],
+            Delete { .. } => &[("deleted", Int)],
+            Vacuum { .. } => &[
+                ("table", Text),
+                ("rows", Int),
+                ("pages", Int),
+                ("skipped", Int),
+            ],
+            Check => &[
+                ("page", Int),
+                ("slot", Int),
+                ("object", Text),
+                ("problem", Text),
+            ],
+            CreateTable { .. }
+            | Insert { .. }
+            | SeqScan { .. }
+            | AsOfScan { .. }
+            | SingleRow
+            | IndexScan { .. }
+            | HashIndexScan { .. }
+            | Aggregate { .. }
+            | DropTable { .. } => &[],
+        };
+        let columns = columns.iter().map(|&(name, t)| (name.to_string(), t));
+        Schema::typed(columns.collect())
+    }
 
+    // How many rows the statement writes, for statements that write rows.
+    pub fn affected(&self) -> Option<usize> {
+        match self {
+            PhysicalPlan::Insert { rows, .. } => Some(rows.len()),
+            _ => None,
+        }
+    }
+
+    fn explain_into(&self, depth: usize, actual: Option<u64>, lines: &mut Vec<String>) {
+        use PhysicalPlan::*;
+        let indent = "  ".repeat(depth);
+        match self {
+            CreateTable { table_name, .. } => {
+                lines.push(format!("{}CreateTable {}", indent, table_name))
+            }
+            Insert {
+                table_name, rows, ..
+            } => lines.push(format!(
+                "{}Insert into {} ({} rows)",
+                indent,
+                table_name,
+                rows.len()
+            )),
+            Delete {
+                table_name, input, ..
+            } => {
+                lines.push(format!("{}Delete from {}", indent, table_name));
+                input.explain_into(depth + 1, None, lines);
+            }
+            SeqScan {
+                table_name,
+                estimated_rows,
+                ..
+            } => lines.push(format!(
+                "{}SeqScan on {} (~{} rows)",
+                indent, table_name, estimated_rows
+            )),
+            AsOfScan {
+                table_name, lsn, ..
+            } => lines.push(format!(
+                "{}AsOfScan on {} at LSN {}",
+                indent, table_name, lsn
+            )),
+            SingleRow => lines.push(format!("{}SingleRow", indent)),
+            IndexScan {
+                table_name,
+                index_name,
+                columns,
+                ranges,
+                index_only,
+                ..
+            } => lines.push(format!(
+                "{}{} on {} using {} ({}{})",
+                indent,
+                if *index_only {
+                    "IndexOnlyScan"
+                } else {
+                    "IndexScan"
+                },
+                table_name,
+                index_name,
+                columns.join(", "),
+                probes(ranges.len())
+            )),
+            HashIndexScan {
+                table_name,
+                index_name,
+                column,
+                keys,
+                ..
+            } => lines.push(format!(
+                "{}HashIndexScan on {} using {} ({}{})",
+                indent,
+                table_name,
+                index_name,
+                column,
+                probes(keys.len())
+            )),
+            Filter {
+                input,
+                estimated_rows,
+                ..
+            } => {
+                lines.push(match (estimated_rows, actual) {
+                    (Some(estimated), Some(actual)) => {
+                        format!("{}Filter (~{} rows, {} actual)", indent, estimated, actual)
+                    }
+                    (Some(estimated), None) => format!("{}Filter (~{} rows)", indent, estimated),
+                    (None, _) => format!("{}Filter", indent),
+                });
+                input.explain_into(depth + 1, actual, lines);
+            }
+            Projection { input, exprs } => {
+                lines.push(format!("{}Projection ({} exprs)", indent, exprs.len()));
+                input.explain_into(depth + 1, actual, lines);
+            }
+            HashSemiJoin {
+                input,
+                subquery,
+                keys,
+                anti,
+                ..
+            } => {
+                let join = match anti {
+                    true => "HashAntiJoin",
+                    false => "HashSemiJoin",
+                };
+                lines.push(format!("{}{} ({} keys)", indent, join, keys.len()));
+                input.explain_into(depth + 1, actual, lines);
+                subquery.explain_into(depth + 1, actual, lines);
+            }
+            Aggregate {
+                input,
+                keys,
+                aggregates,
+            } => {
+                lines.push(format!(
+                    "{}Aggregate ({} keys, {} aggregates)",
+                    indent,
+                    keys.len(),
+                    aggregates.len()
+                ));
+                input.explain_into(depth + 1, actual, lines);
+            }
+            Sort { input, keys } => {
+                lines.push(format!("{}Sort ({} keys)", indent, keys.len()));
+                input.explain_into(depth + 1, actual, lines);
+            }
+            Append { inputs } => {
+                lines.push(format!("{}Append ({} inputs)", indent, inputs.len()));
+                for input in inputs {
+                    input.explain_into(depth + 1, actual, lines);
+                }
+            }
+            Merge { inputs, keys } => {
+                lines.push(format!(
+                    "{}Merge ({} inputs, {} keys)",
+                    indent,
+                    inputs.len(),
+                    keys.len()
+                ));
+                for input in inputs {
+                    input.explain_into(depth + 1, actual, lines);
+                }
+            }
+            Explain { input, .. } => input.explain_into(depth, actual, lines),
+            Reindex {
+                table_name,
+                index_name,
+            } => lines.push(format!(
+                "{}Reindex {} on {}",
+                indent, index_name, table_name
+            )),
+            Analyze { table_name } => lines.push(format!("{}Analyze {}", indent, table_name)),
+            DropTable { table_name } => lines.push(format!("{}DropTable {}", indent, table_name)),
+            ShowLocks => lines.push(format!("{}ShowLocks", indent)),
+            ShowTables { schema: None } => lines.push(format!("{}ShowTables", indent)),
+            ShowTables {
+                schema: Some(schema),
+            } => lines.push(format!("{}ShowTables in {}", indent, schema)),
+            ShowGrants => lines.push(format!("{}ShowGrants", indent)),
+            Check => lines.push(format!("{}Check", indent)),
+            Checkpoint => lines.push(format!("{}Checkpoint", indent)),
+            FlushTables { tables } => {
+                lines.push(format!("{}FlushTables {}", indent, tables.join(", ")))
+            }
+            Vacuum { tables } => lines.push(format!("{}Vacuum {}", indent, tables.join(", "))),
+        }
+    }
+}
 
 pub struct PhysicalPlanner<'a> {
     catalog: &'a crate::query::binder::Catalog,
-    storage: &'a mut Storage,
+    storage: &'a Storage,
 }
 
 impl<'a> PhysicalPlanner<'a> {
-    
-    pub fn new(catalog: &'a crate::query::binder::Catalog, storage: &'a mut Storage) -> Self {
+    pub fn new(catalog: &'a crate::query::binder::Catalog, storage: &'a Storage) -> Self {
         PhysicalPlanner { catalog, storage }
     }
 
-    
     pub fn create_physical_plan(&mut self, logical: LogicalPlan) -> Result<PhysicalPlan> {
-        
         self.plan_node(logical)
     }
 
     fn plan_node(&mut self, node: LogicalPlan) -> Result<PhysicalPlan> {
         use LogicalPlan::*;
         match node {
-            
             CreateTable {
                 table_name,
                 columns,
@@ -90,85 +474,435 @@ impl<'a> PhysicalPlanner<'a> {
             }),
 
             CreateIndex { .. } => {
-                
-                
                 bail!("CreateIndex should have been handled at bind time");
             }
 
             Insert {
                 table_name,
                 col_ordinals,
-                values,
+                rows,
             } => Ok(PhysicalPlan::Insert {
+                schema_version: self.version_of(&table_name),
                 table_name,
                 col_ordinals,
-                values,
+                rows,
+            }),
+
+            Delete { table, input } => Ok(PhysicalPlan::Delete {
+                schema_version: self.version_of(&table),
+                table_name: table,
+                input: Box::new(self.plan_node(*input)?),
             }),
 
-            
             SeqScan { table, predicate } => {
-                if let Some(pred) = predicate.clone() {
-                    if let Some((col, _op, _lit)) = Self::extract_eq_pred(&pred) {
-                        
-                        for idx in self.storage.get_indexes(&table) {
-                            if idx.column == col {
-                                return Ok(PhysicalPlan::IndexScan {
-                                    table_name: table.clone(),
-                                    index_name: idx.name.clone(),
-                                    predicate: pred,
-                                });
-                            }
-                        }
-                    }
+                let info = self.storage.catalog.get_table(&table).ok();
+                let kept = match (info, &predicate) {
+                    (Some(info), Some(pred)) => estimate(info, pred),
+                    _ => None,
+                };
+                // An index reads a page for each row it finds, a scan each of
+                // the table's pages once.
+                let pages = info.map_or(0, |t| t.stats.page_count() as u64);
+                if let Some(pred) = predicate.clone()
+                    && kept.is_none_or(|rows| rows <= pages)
+                    && let Some(scan) = self.choose_index(&table, &pred)
+                {
+                    return Ok(PhysicalPlan::Filter {
+                        input: Box::new(scan),
+                        predicate: pred,
+                        estimated_rows: kept,
+                    });
                 }
-                
+
                 let mut plan = PhysicalPlan::SeqScan {
                     table_name: table.clone(),
                     predicate: None,
+                    estimated_rows: info.map_or(0, |t| t.stats.rows),
+                    schema_version: self.version_of(&table),
                 };
                 if let Some(pred) = predicate {
                     plan = PhysicalPlan::Filter {
                         input: Box::new(plan),
                         predicate: pred,
+                        estimated_rows: kept,
                     };
                 }
                 Ok(plan)
             }
 
+            AsOfScan { table, lsn } => Ok(PhysicalPlan::AsOfScan {
+                schema_version: self.version_of(&table),
+                table_name: table,
+                lsn,
+            }),
+
+            SingleRow => Ok(PhysicalPlan::SingleRow),
+
             Filter { input, predicate } => {
                 let child = self.plan_node(*input)?;
                 Ok(PhysicalPlan::Filter {
                     input: Box::new(child),
                     predicate,
+                    estimated_rows: None,
                 })
             }
 
-            Projection { input, exprs } => {
-                let child = self.plan_node(*input)?;
+            Projection { input, mut exprs } => {
+                let mut child = self.plan_node(*input)?;
+                if self.make_index_only(&mut child, &exprs)? {
+                    exprs.iter_mut().for_each(remap_to_key);
+                }
                 Ok(PhysicalPlan::Projection {
                     input: Box::new(child),
                     exprs,
                 })
             }
+
+            SemiJoin {
+                input,
+                subquery,
+                keys,
+                collations,
+                anti,
+            } => Ok(PhysicalPlan::HashSemiJoin {
+                input: Box::new(self.plan_node(*input)?),
+                subquery: Box::new(self.plan_node(*subquery)?),
+                keys,
+                collations,
+                anti,
+            }),
+
+            Aggregate {
+                input,
+                keys,
+                aggregates,
+            } => Ok(PhysicalPlan::Aggregate {
+                input: Box::new(self.plan_node(*input)?),
+                keys,
+                aggregates,
+            }),
+
+            // Inputs that are each in order already only need merging.
+            Sort { input, keys } => {
+                let LogicalPlan::UnionAll { inputs } = *input else {
+                    return Ok(PhysicalPlan::Sort {
+                        input: Box::new(self.plan_node(*input)?),
+                        keys,
+                    });
+                };
+                let inputs = inputs
+                    .into_iter()
+                    .map(|input| self.plan_node(input))
+                    .collect::<Result<Vec<_>>>()?;
+                if inputs
+                    .iter()
+                    .all(|input| sorted_by(&self.ordering(input), &keys))
+                {
+                    return Ok(PhysicalPlan::Merge { inputs, keys });
+                }
+                Ok(PhysicalPlan::Sort {
+                    input: Box::new(PhysicalPlan::Append { inputs }),
+                    keys,
+                })
+            }
+
+            UnionAll { inputs } => Ok(PhysicalPlan::Append {
+                inputs: inputs
+                    .into_iter()
+                    .map(|input| self.plan_node(input))
+                    .collect::<Result<_>>()?,
+            }),
+
+            Explain { input, analyze } => Ok(PhysicalPlan::Explain {
+                input: Box::new(self.plan_node(*input)?),
+                analyze,
+            }),
+
+            Reindex { index_name, table } => Ok(PhysicalPlan::Reindex {
+                table_name: table,
+                index_name,
+            }),
+
+            Analyze { table } => Ok(PhysicalPlan::Analyze { table_name: table }),
+
+            DropTable { table } => Ok(PhysicalPlan::DropTable { table_name: table }),
+
+            ShowLocks => Ok(PhysicalPlan::ShowLocks),
+            ShowTables { schema } => Ok(PhysicalPlan::ShowTables { schema }),
+            ShowGrants => Ok(PhysicalPlan::ShowGrants),
+
+            Check => Ok(PhysicalPlan::Check),
+            Checkpoint => Ok(PhysicalPlan::Checkpoint),
+            FlushTables { tables } => Ok(PhysicalPlan::FlushTables { tables }),
+            Vacuum { tables } => Ok(PhysicalPlan::Vacuum { tables }),
         }
     }
 
-    
-    fn extract_eq_pred(expr: &BoundExpr) -> Option<(String, BinaryOp, BoundExpr)> {
-        if let BoundExpr::BinaryOp {
-            left,
-            op: BinaryOp::Eq,
-            right,
-            ..
-        } = expr
-        {
-            if let BoundExpr::Column { ref col, .. } = **left {
-                return Some((col.clone(), BinaryOp::Eq, (**right).clone()));
-            }
-            if let BoundExpr::Column { ref col, .. } = **right {
-                return Some((col.clone(), BinaryOp::Eq, (**left).clone()));
+    fn version_of(&self, table: &str) -> u64 {
+        self.catalog.get_table(table).map_or(0, |t| t.version)
+    }
+
+    // The columns the plan's rows come out in the order of, as ordinals of
+    // its output and whether descending, most significant first. An index
+    // scan reads its keys in ascending order, and a filter, semi-join or
+    // projection keeps the order of what it reads.
+    fn ordering(&self, plan: &PhysicalPlan) -> Vec<(usize, bool)> {
+        match plan {
+            PhysicalPlan::IndexScan {
+                index_only: true, ..
+            } => vec![(0, false)],
+            PhysicalPlan::IndexScan {
+                table_name,
+                columns,
+                ..
+            } => match self.catalog.get_table(table_name) {
+                Ok(meta) => columns
+                    .iter()
+                    .map_while(|column| meta.col_index.get(&column.to_ascii_lowercase()))
+                    .map(|&ordinal| (ordinal, false))
+                    .collect(),
+                Err(_) => Vec::new(),
+            },
+            PhysicalPlan::Filter { input, .. } | PhysicalPlan::HashSemiJoin { input, .. } => {
+                self.ordering(input)
             }
+            PhysicalPlan::Projection { input, exprs } => self
+                .ordering(input)
+                .into_iter()
+                .map_while(|(ordinal, descending)| {
+                    let output = exprs.iter().position(
+                        |e| matches!(e, BoundExpr::Column { ordinal: o, .. } if *o == ordinal),
+                    )?;
+                    Some((output, descending))
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // An index whose columns the predicate pins to keys it holds: a hash
+    // index when that is a list of values, else a B-tree. A literal
+    // compared with a column is taken as the column's keys hold values, so
+    // one of another type, which no key can be, leaves the table to be
+    // scanned, and the filter above says what it makes of that.
+    fn choose_index(&self, table: &str, pred: &BoundExpr) -> Option<PhysicalPlan> {
+        let meta = self.storage.catalog.get_table(table).ok()?;
+        let indexes: Vec<_> = self
+            .storage
+            .get_indexes(table)
+            .into_iter()
+            .filter_map(|idx| {
+                let columns = idx.key_columns(meta).ok()?;
+                Some((index_ranges(&columns, pred)?, idx))
+            })
+            .collect();
+        let hash = indexes.iter().find_map(|(ranges, idx)| {
+            let keys: Option<Vec<u64>> = ranges
+                .iter()
+                .map(|range| match range {
+                    (Key::Int(lo), Key::Int(hi)) if lo == hi => Some(*lo as u64),
+                    _ => None,
+                })
+                .collect();
+            let keys = keys.filter(|_| idx.kind == IndexKind::Hash)?;
+            Some(PhysicalPlan::HashIndexScan {
+                table_name: table.to_string(),
+                index_name: idx.name.clone(),
+                column: idx.column.clone(),
+                keys,
+                schema_version: self.version_of(table),
+            })
+        });
+        hash.or_else(|| {
+            indexes
+                .into_iter()
+                .find(|(_, idx)| idx.kind == IndexKind::BTree)
+                .map(|(ranges, idx)| PhysicalPlan::IndexScan {
+                    table_name: table.to_string(),
+                    index_name: idx.name.clone(),
+                    columns: idx.columns().cloned().collect(),
+                    ranges,
+                    index_only: false,
+                    schema_version: self.version_of(table),
+                })
+        })
+    }
+
+    fn make_index_only(&self, plan: &mut PhysicalPlan, exprs: &[BoundExpr]) -> Result<bool> {
+        let (scan, filter) = match plan {
+            PhysicalPlan::Filter {
+                input, predicate, ..
+            } => (input.as_mut(), Some(predicate)),
+            other => (other, None),
+        };
+        let PhysicalPlan::IndexScan {
+            table_name,
+            columns,
+            index_only,
+            ..
+        } = scan
+        else {
+            return Ok(false);
+        };
+        let [column] = columns.as_slice() else {
+            return Ok(false);
+        };
+
+        // A NOCASE key only holds the value in lower case.
+        let meta = self.catalog.get_table(table_name)?;
+        let Some(&key_ordinal) = meta.col_index.get(&column.to_ascii_lowercase()) else {
+            return Ok(false);
+        };
+        if meta.columns[key_ordinal].collation != Collation::Binary {
+            return Ok(false);
+        }
+        let covered = exprs.iter().all(|e| only_references(e, key_ordinal))
+            && filter
+                .as_ref()
+                .is_none_or(|p| only_references(p, key_ordinal));
+        if !covered {
+            return Ok(false);
+        }
+
+        *index_only = true;
+        if let Some(predicate) = filter {
+            remap_to_key(predicate);
+        }
+        Ok(true)
+    }
+}
+
+// The rows of `table` that `predicate` keeps, going by its column stats;
+// None before the table has been analyzed.
+fn estimate(table: &TableInfo, predicate: &BoundExpr) -> Option<u64> {
+    if table.column_stats.is_empty() {
+        return None;
+    }
+    let fraction = selectivity(&table.column_stats, predicate).clamp(0.0, 1.0);
+    Some((table.stats.rows as f64 * fraction).round() as u64)
+}
+
+// The fraction of rows `predicate` keeps: equality and ranges on a column
+// against a literal go by that column's stats, AND and OR combine their
+// sides as if independent, and anything else is a guess.
+fn selectivity(stats: &BTreeMap<String, ColumnStats>, predicate: &BoundExpr) -> f64 {
+    let BoundExpr::BinaryOp {
+        left, op, right, ..
+    } = predicate
+    else {
+        return DEFAULT_SELECTIVITY;
+    };
+    match op {
+        BinaryOp::And => return selectivity(stats, left) * selectivity(stats, right),
+        BinaryOp::Or => {
+            let (l, r) = (selectivity(stats, left), selectivity(stats, right));
+            return l + r - l * r;
+        }
+        _ => {}
+    }
+    let (column, value, op) = match (left.as_ref(), right.as_ref()) {
+        (BoundExpr::Column { .. }, BoundExpr::Literal(v)) => (left.as_ref(), v, *op),
+        (BoundExpr::Literal(v), BoundExpr::Column { .. }) => {
+            let flipped = match op {
+                BinaryOp::Lt => BinaryOp::Gt,
+                BinaryOp::LtEq => BinaryOp::GtEq,
+                BinaryOp::Gt => BinaryOp::Lt,
+                BinaryOp::GtEq => BinaryOp::LtEq,
+                other => *other,
+            };
+            (right.as_ref(), v, flipped)
+        }
+        _ => return DEFAULT_SELECTIVITY,
+    };
+    let BoundExpr::Column { col, collation, .. } = column else {
+        return DEFAULT_SELECTIVITY;
+    };
+    let Some(column_stats) = stats
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(col))
+        .map(|(_, s)| s)
+    else {
+        return DEFAULT_SELECTIVITY;
+    };
+    match (op, value) {
+        (BinaryOp::Eq | BinaryOp::IsNotDistinctFrom, _) => column_stats.equal(value, *collation),
+        (BinaryOp::NotEq | BinaryOp::IsDistinctFrom, _) => {
+            1.0 - column_stats.equal(value, *collation)
+        }
+        (_, Value::Int(v)) => {
+            let (lo, hi) = match op {
+                BinaryOp::Lt => (i64::MIN, v.saturating_sub(1)),
+                BinaryOp::LtEq => (i64::MIN, *v),
+                BinaryOp::Gt => (v.saturating_add(1), i64::MAX),
+                BinaryOp::GtEq => (*v, i64::MAX),
+                _ => return DEFAULT_SELECTIVITY,
+            };
+            column_stats.between(lo, hi).unwrap_or(DEFAULT_SELECTIVITY)
+        }
+        (_, Value::String(s)) => {
+            let Value::String(key) = collation.key(Value::String(s.clone())) else {
+                return DEFAULT_SELECTIVITY;
+            };
+            let keeps = |v: &str| match op {
+                BinaryOp::Lt => v < key.as_str(),
+                BinaryOp::LtEq => v <= key.as_str(),
+                BinaryOp::Gt => v > key.as_str(),
+                BinaryOp::GtEq => v >= key.as_str(),
+                _ => true,
+            };
+            column_stats.compare(keeps).unwrap_or(DEFAULT_SELECTIVITY)
+        }
+    }
+}
+
+// How many times an index scan looks keys up, when it is more than once.
+fn probes(count: usize) -> String {
+    match count {
+        1 => String::new(),
+        n => format!(", {} probes", n),
+    }
+}
+
+// Whether rows in `ordering` are in the order of `keys`, each a column of
+// the rows.
+fn sorted_by(ordering: &[(usize, bool)], keys: &[SortKey]) -> bool {
+    keys.len() <= ordering.len()
+        && keys
+            .iter()
+            .zip(ordering)
+            .all(|(key, &(ordinal, descending))| {
+                matches!(key.expr, BoundExpr::Column { ordinal: o, .. } if o == ordinal)
+                    && key.descending == descending
+            })
+}
+
+fn only_references(expr: &BoundExpr, ordinal: usize) -> bool {
+    match expr {
+        BoundExpr::Column { ordinal: o, .. } => *o == ordinal,
+        BoundExpr::Literal(_)
+        | BoundExpr::NextVal(_)
+        | BoundExpr::CurrVal(_)
+        | BoundExpr::Random(_) => true,
+        BoundExpr::BinaryOp { left, right, .. } => {
+            only_references(left, ordinal) && only_references(right, ordinal)
+        }
+        BoundExpr::Call { args, .. } => args.iter().all(|arg| only_references(arg, ordinal)),
+        BoundExpr::SetSeed(_, seed) => only_references(seed, ordinal),
+    }
+}
+
+fn remap_to_key(expr: &mut BoundExpr) {
+    match expr {
+        BoundExpr::Column { ordinal, .. } => *ordinal = 0,
+        BoundExpr::Literal(_)
+        | BoundExpr::NextVal(_)
+        | BoundExpr::CurrVal(_)
+        | BoundExpr::Random(_) => {}
+        BoundExpr::BinaryOp { left, right, .. } => {
+            remap_to_key(left);
+            remap_to_key(right);
         }
-        None
+        BoundExpr::Call { args, .. } => args.iter_mut().for_each(remap_to_key),
+        BoundExpr::SetSeed(_, seed) => remap_to_key(seed),
     }
 }
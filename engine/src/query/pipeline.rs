@@ -0,0 +1,605 @@
+use crate::net::row::{ColumnType, Schema};
+use crate::query::{
+    binder::{
+        Binder, BoundExpr, BoundStmt, Catalog as BinderCatalog, DataType as BoundType,
+        VOLATILE_FUNCTIONS,
+    },
+    executor::{Executor, build_operator_with, build_read_operator_with},
+    optimizer::Optimizer,
+    params::literal_types,
+    parser::{Expr, Statement},
+    physical_planner::{PhysicalPlan, PhysicalPlanner},
+    planner::Planner as LogicalPlanner,
+};
+use crate::storage::storage::{
+    CheckConstraint, ColumnInfo, DataType, Grants, IndexKind, Privilege, ReadView, Storage,
+    ViewInfo,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use std::time::Instant;
+use tracing::Span;
+
+// The steps every statement goes through once parsed, whoever runs it: the
+// server for its clients, `Database` in-process.
+
+// Stores how long a step took, since `started`, in the current span.
+pub fn record_elapsed(field: &'static str, started: Instant) {
+    Span::current().record(field, started.elapsed().as_micros() as u64);
+}
+
+// A SELECT calling NEXTVAL reserves values in the WAL, so it is not one.
+pub fn is_read_only(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Select { .. } => !calls(stmt, "NEXTVAL"),
+        Statement::UnionAll { selects, .. } => selects.iter().all(is_read_only),
+        Statement::DeclareCursor { select, .. } => is_read_only(select),
+        Statement::Explain { .. }
+        | Statement::Fetch { .. }
+        | Statement::ShowLocks
+        | Statement::ShowTables { .. }
+        | Statement::ShowGrants => true,
+        _ => false,
+    }
+}
+
+// What a standby refuses. Transaction control and user management are
+// still its own business.
+pub fn changes_data(stmt: &Statement) -> bool {
+    matches!(
+        stmt,
+        Statement::Insert { .. } | Statement::Copy { .. } | Statement::Delete { .. }
+    ) || is_ddl(stmt)
+        || calls(stmt, "NEXTVAL")
+}
+
+// Whether a SELECT, UNION ALL, INSERT or DELETE calls `function`, by its
+// name in upper case.
+fn calls(stmt: &Statement, function: &str) -> bool {
+    has_expr(
+        stmt,
+        &|expr| matches!(expr, Expr::Call { name, .. } if name == function),
+    )
+}
+
+// Whether a SELECT, UNION ALL, INSERT or DELETE reads a session variable.
+pub fn uses_variables(stmt: &Statement) -> bool {
+    has_expr(stmt, &|expr| matches!(expr, Expr::Variable(_)))
+}
+
+// Whether an expression of a SELECT, UNION ALL, INSERT or DELETE, or one
+// inside it, is one `found` picks out.
+fn has_expr(stmt: &Statement, found: &dyn Fn(&Expr) -> bool) -> bool {
+    fn in_expr(expr: &Expr, found: &dyn Fn(&Expr) -> bool) -> bool {
+        found(expr)
+            || match expr {
+                Expr::Column(_)
+                | Expr::QualifiedColumn { .. }
+                | Expr::Literal(_)
+                | Expr::Variable(_)
+                | Expr::Star => false,
+                Expr::BinaryOp { left, right, .. } => in_expr(left, found) || in_expr(right, found),
+                Expr::Call { args, .. } => args.iter().any(|arg| in_expr(arg, found)),
+                Expr::Exists { select, .. } => has_expr(select, found),
+            }
+    }
+    match stmt {
+        Statement::Select {
+            projections,
+            filter,
+            group_by,
+            order_by,
+            ..
+        } => projections
+            .iter()
+            .chain(filter)
+            .chain(group_by)
+            .chain(order_by.iter().map(|o| &o.expr))
+            .any(|expr| in_expr(expr, found)),
+        Statement::UnionAll { selects, order_by } => {
+            selects.iter().any(|select| has_expr(select, found))
+                || order_by.iter().any(|o| in_expr(&o.expr, found))
+        }
+        Statement::Insert { rows, .. } => rows.iter().flatten().any(|expr| in_expr(expr, found)),
+        Statement::Delete { filter, .. } => filter.iter().any(|expr| in_expr(expr, found)),
+        _ => false,
+    }
+}
+
+// The SELECTs under EXISTS in a SELECT or the SELECTs of a UNION ALL,
+// those inside them included.
+pub fn subqueries(stmt: &Statement) -> Vec<&Statement> {
+    fn in_expr<'a>(expr: &'a Expr, found: &mut Vec<&'a Statement>) {
+        match expr {
+            Expr::Exists { select, .. } => {
+                found.push(select);
+                found.extend(subqueries(select));
+            }
+            Expr::BinaryOp { left, right, .. } => {
+                in_expr(left, found);
+                in_expr(right, found);
+            }
+            Expr::Call { args, .. } => args.iter().for_each(|arg| in_expr(arg, found)),
+            Expr::Column(_)
+            | Expr::QualifiedColumn { .. }
+            | Expr::Literal(_)
+            | Expr::Variable(_)
+            | Expr::Star => {}
+        }
+    }
+    let mut found = Vec::new();
+    match stmt {
+        Statement::Select {
+            projections,
+            filter,
+            group_by,
+            order_by,
+            ..
+        } => projections
+            .iter()
+            .chain(filter)
+            .chain(group_by)
+            .chain(order_by.iter().map(|o| &o.expr))
+            .for_each(|expr| in_expr(expr, &mut found)),
+        Statement::UnionAll { selects, .. } => selects
+            .iter()
+            .for_each(|select| found.extend(subqueries(select))),
+        Statement::Explain { stmt, .. } => found.extend(subqueries(stmt)),
+        Statement::DeclareCursor { select, .. } | Statement::CreateTableAs { select, .. } => {
+            found.extend(subqueries(select))
+        }
+        _ => {}
+    }
+    found
+}
+
+pub fn calls_volatile(stmt: &Statement) -> bool {
+    VOLATILE_FUNCTIONS
+        .iter()
+        .any(|function| calls(stmt, function))
+}
+
+// What a read-only server runs. Anything not known to leave both the data
+// and the accounts alone is refused, statements added later included.
+pub fn runs_read_only(stmt: &Statement) -> bool {
+    is_read_only(stmt)
+        || matches!(
+            stmt,
+            Statement::Check
+                | Statement::Begin
+                | Statement::Commit
+                | Statement::Rollback
+                | Statement::SetConstraints { .. }
+                | Statement::CloseCursor { .. }
+                | Statement::Set { .. }
+                | Statement::ShowSettings { .. }
+        )
+}
+
+// The table a SELECT, INSERT or table DDL works on, when it names one. A
+// UNION ALL reads more than one.
+pub fn table_of(stmt: &Statement) -> Option<&str> {
+    match stmt {
+        Statement::Select { table, .. } => table.as_deref(),
+        Statement::Explain { stmt, .. } => table_of(stmt),
+        Statement::DeclareCursor { select, .. } => table_of(select),
+        Statement::Grant { .. } | Statement::Revoke { .. } => None,
+        _ => written_table(stmt),
+    }
+}
+
+// The table a statement changes, whose cached results its commit makes
+// stale.
+pub fn written_table(stmt: &Statement) -> Option<&str> {
+    match stmt {
+        Statement::Insert { table, .. }
+        | Statement::Delete { table, .. }
+        | Statement::Copy { table, .. }
+        | Statement::CreateTable { name: table, .. }
+        | Statement::CreateTableAs { name: table, .. }
+        | Statement::CreateIndex { table, .. }
+        | Statement::Reindex { table, .. }
+        | Statement::Analyze { table }
+        | Statement::DropTable { table }
+        | Statement::AddCheck { table, .. }
+        | Statement::RenameTable { table, .. }
+        | Statement::Grant { table, .. }
+        | Statement::Revoke { table, .. } => Some(table),
+        _ => None,
+    }
+}
+
+pub fn is_ddl(stmt: &Statement) -> bool {
+    matches!(
+        stmt,
+        Statement::CreateTable { .. }
+            | Statement::CreateTableAs { .. }
+            | Statement::CreateIndex { .. }
+            | Statement::Reindex { .. }
+            | Statement::Analyze { .. }
+            | Statement::Vacuum { .. }
+            | Statement::DropTable { .. }
+            | Statement::Grant { .. }
+            | Statement::Revoke { .. }
+            | Statement::CreateSequence { .. }
+            | Statement::DropSequence { .. }
+            | Statement::CreateView { .. }
+            | Statement::DropView { .. }
+            | Statement::AddCheck { .. }
+            | Statement::RenameTable { .. }
+            | Statement::RenameIndex { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::DropSchema { .. }
+    )
+}
+
+// CREATE TABLE, ALTER TABLE, CREATE INDEX, ALTER INDEX, GRANT, REVOKE and
+// the schema, sequence and view statements go straight to storage instead of
+// through the planner, as does SET CONSTRAINTS. Returns None for every other
+// statement.
+// A table is created with ALL granted to `owner`, when there is one.
+pub fn run_ddl(storage: &mut Storage, stmt: &Statement, owner: Option<&str>) -> Option<Result<()>> {
+    match stmt {
+        Statement::CreateTable {
+            name,
+            columns,
+            temporary,
+            foreign_keys,
+            checks,
+        } => {
+            let infos = columns
+                .iter()
+                .map(|c| ColumnInfo {
+                    name: c.name.clone(),
+                    data_type: if c.data_type.eq_ignore_ascii_case("INT") {
+                        DataType::Int
+                    } else {
+                        DataType::String
+                    },
+                    collation: c.collation,
+                })
+                .collect();
+            if *temporary {
+                let created = match foreign_keys.first() {
+                    Some(key) => Err(anyhow!(
+                        "Foreign key '{}' cannot involve a temporary table",
+                        key.name
+                    )),
+                    None => storage
+                        .create_temp_table(name.clone(), infos)
+                        .and_then(|()| add_checks(storage, name, checks)),
+                };
+                return Some(created.context("CREATE TEMP TABLE failed"));
+            }
+            let created = storage.create_table(name.clone(), infos).and_then(|()| {
+                for key in foreign_keys {
+                    storage.add_foreign_key(name, key.clone())?;
+                }
+                add_checks(storage, name, checks)?;
+                grant_owner(storage, name, owner)
+            });
+            Some(created.context("CREATE TABLE failed"))
+        }
+        Statement::CreateTableAs {
+            name,
+            columns,
+            temporary,
+            select,
+        } => Some(
+            create_table_as(storage, name, columns, *temporary, select, owner)
+                .context("CREATE TABLE AS failed"),
+        ),
+        Statement::Grant {
+            privilege,
+            columns,
+            table,
+            user,
+        }
+        | Statement::Revoke {
+            privilege,
+            columns,
+            table,
+            user,
+        } => {
+            let grant = matches!(stmt, Statement::Grant { .. });
+            let changed = change_grants(storage, grant, *privilege, columns, table, user);
+            Some(changed.with_context(|| match grant {
+                true => "GRANT failed",
+                false => "REVOKE failed",
+            }))
+        }
+        Statement::CreateIndex {
+            index_name,
+            table,
+            columns,
+            using,
+        } => {
+            let kind = using
+                .as_deref()
+                .map_or(Ok(IndexKind::default()), IndexKind::parse);
+            Some(
+                kind.and_then(|kind| storage.create_index_using(table, columns, index_name, kind))
+                    .map(|_| ())
+                    .context("CREATE INDEX failed"),
+            )
+        }
+        Statement::CreateSequence {
+            name,
+            start,
+            increment,
+        } => Some(
+            storage
+                .create_sequence(name.clone(), *start, *increment)
+                .context("CREATE SEQUENCE failed"),
+        ),
+        Statement::DropSequence { name } => {
+            Some(storage.drop_sequence(name).context("DROP SEQUENCE failed"))
+        }
+        Statement::CreateView {
+            name,
+            select,
+            query,
+        } => Some(create_view(storage, name, select, query).context("CREATE VIEW failed")),
+        Statement::DropView { name } => Some(storage.drop_view(name).context("DROP VIEW failed")),
+        Statement::AddCheck { table, check } => Some(
+            storage
+                .add_check(table, check.clone())
+                .context("ALTER TABLE failed"),
+        ),
+        Statement::RenameTable { table, new_name } => Some(
+            storage
+                .rename_table(table, new_name)
+                .context("ALTER TABLE failed"),
+        ),
+        Statement::RenameIndex {
+            index_name,
+            new_name,
+        } => Some(
+            storage
+                .rename_index(index_name, new_name)
+                .context("ALTER INDEX failed"),
+        ),
+        Statement::CreateSchema { name } => {
+            Some(storage.create_schema(name).context("CREATE SCHEMA failed"))
+        }
+        Statement::DropSchema { name, cascade } => Some(
+            storage
+                .drop_schema(name, *cascade)
+                .context("DROP SCHEMA failed"),
+        ),
+        Statement::SetConstraints { deferred } => Some(storage.set_constraints(*deferred)),
+        _ => None,
+    }
+}
+
+// The view's SELECT is bound once here, so one that could not be read
+// through is not kept.
+fn create_view(storage: &mut Storage, name: &str, select: &Statement, query: &str) -> Result<()> {
+    let Statement::Select {
+        projections,
+        table: Some(table),
+        group_by,
+        order_by,
+        ..
+    } = select
+    else {
+        bail!("A view has to select from a table or another view");
+    };
+    if !group_by.is_empty() || !order_by.is_empty() {
+        bail!("A view cannot have GROUP BY or ORDER BY");
+    }
+    if storage.catalog.is_temp(table) {
+        bail!("A view cannot read temporary table '{}'", table);
+    }
+    if projections != &[Expr::Star] && !projections.iter().all(|e| matches!(e, Expr::Column(_))) {
+        bail!("A view can only list columns");
+    }
+    if calls(select, "NEXTVAL") {
+        bail!("A view cannot call NEXTVAL");
+    }
+    if uses_variables(select) {
+        bail!("A view cannot use a variable");
+    }
+    if !subqueries(select).is_empty() {
+        bail!("A view cannot use EXISTS yet");
+    }
+    let mut bind_catalog = BinderCatalog::from_storage(&storage.catalog);
+    Binder::shared(&mut bind_catalog, storage).bind(select.clone())?;
+    storage.create_view(ViewInfo {
+        name: name.to_string(),
+        table: table.clone(),
+        query: query.to_string(),
+    })
+}
+
+fn grant_owner(storage: &mut Storage, table: &str, owner: Option<&str>) -> Result<()> {
+    let Some(owner) = owner else {
+        return Ok(());
+    };
+    let mut grants = Grants::default();
+    grants.grant(Privilege::All, &[]);
+    storage.set_grants(table, &owner.to_ascii_lowercase(), grants)
+}
+
+// The columns are worked out from the bound SELECT before the table is
+// created, so the SELECT cannot read the table it fills, and a name already
+// taken fails before anything runs. The executor has storage to itself while
+// it runs, so the rows are collected before they go in.
+fn create_table_as(
+    storage: &mut Storage,
+    name: &str,
+    names: &[String],
+    temporary: bool,
+    select: &Statement,
+    owner: Option<&str>,
+) -> Result<()> {
+    let mut bind_catalog = BinderCatalog::from_storage(&storage.catalog);
+    let projections = match Binder::shared(&mut bind_catalog, storage).bind(select.clone())? {
+        BoundStmt::Select { projections, .. } => projections,
+        BoundStmt::UnionAll { mut selects, .. } => match selects.swap_remove(0) {
+            BoundStmt::Select { projections, .. } => projections,
+            _ => bail!("A UNION ALL has to be of SELECTs"),
+        },
+        _ => bail!("CREATE TABLE AS needs a SELECT"),
+    };
+    if !names.is_empty() && names.len() != projections.len() {
+        bail!(
+            "{} column names given for the {} columns the SELECT makes",
+            names.len(),
+            projections.len()
+        );
+    }
+    let columns: Vec<ColumnInfo> = projections
+        .iter()
+        .enumerate()
+        .map(|(i, expr)| ColumnInfo {
+            name: names.get(i).cloned().unwrap_or_else(|| column_name(expr, i)),
+            data_type: match expr.data_type() {
+                BoundType::Int => DataType::Int,
+                BoundType::Varchar => DataType::String,
+            },
+            collation: expr.collation(),
+        })
+        .collect();
+    for (i, column) in columns.iter().enumerate() {
+        if columns[..i].iter().any(|c| c.name == column.name) {
+            bail!(
+                "Column '{}' comes out of the SELECT twice; name the columns with \
+                 CREATE TABLE {} (<column>, ...) AS",
+                column.name,
+                name
+            );
+        }
+    }
+    let names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+    match temporary {
+        true => storage.create_temp_table(name.to_string(), columns)?,
+        false => {
+            storage.create_table(name.to_string(), columns)?;
+            grant_owner(storage, name, owner)?;
+        }
+    }
+    let mut bind_catalog = BinderCatalog::from_storage(&storage.catalog)
+        .with_random(storage.random.clone())
+        .with_variables(storage.variables.clone())
+        .with_work_mem(storage.work_mem);
+    let rows = create_executor_from_statement(select.clone(), storage, &mut bind_catalog)?
+        .execute()?;
+    storage.bulk_insert(name, &names, rows.into_iter().map(Ok))?;
+    Ok(())
+}
+
+// What a table made from a SELECT calls a column the SELECT did not take
+// from a table: the function it calls, or COLUMN<n> by its position.
+fn column_name(expr: &BoundExpr, i: usize) -> String {
+    match expr {
+        BoundExpr::Column { col, .. } => col.clone(),
+        BoundExpr::Literal(_) | BoundExpr::BinaryOp { .. } => format!("COLUMN{}", i + 1),
+        _ => expr.name().to_ascii_uppercase(),
+    }
+}
+
+fn add_checks(storage: &mut Storage, table: &str, checks: &[CheckConstraint]) -> Result<()> {
+    for check in checks {
+        storage.add_check(table, check.clone())?;
+    }
+    Ok(())
+}
+
+fn change_grants(
+    storage: &mut Storage,
+    grant: bool,
+    privilege: Privilege,
+    columns: &[String],
+    table: &str,
+    user: &str,
+) -> Result<()> {
+    let info = storage.catalog.get_table(table)?;
+    let columns = columns
+        .iter()
+        .map(|name| {
+            info.columns
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(name))
+                .map(|c| c.name.clone())
+                .ok_or_else(|| anyhow!("Column '{}' not found in '{}'", name, table))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let user = user.to_ascii_lowercase();
+    let mut grants = storage
+        .catalog
+        .grants(table, &user)
+        .cloned()
+        .unwrap_or_default();
+    match grant {
+        true => grants.grant(privilege, &columns),
+        false => grants.revoke(privilege, &columns),
+    }
+    storage.set_grants(table, &user, grants)
+}
+
+pub fn create_executor_from_statement<'a>(
+    stmt: Statement,
+    storage: &'a mut Storage,
+    bind_catalog: &'a mut BinderCatalog,
+) -> Result<Executor<'a>> {
+    let started = Instant::now();
+    let bound = Binder::new(bind_catalog, storage)
+        .bind(stmt)
+        .context("Bind failed")?;
+    record_elapsed("bind_us", started);
+    let started = Instant::now();
+    let phys = plan(bound, bind_catalog, storage)?;
+    let (schema, affected) = (phys.schema(), phys.affected());
+    let root = build_operator_with(phys, storage, &bind_catalog.memory)
+        .context("Building operators failed")?;
+    record_elapsed("plan_us", started);
+    Ok(Executor::new(root)
+        .with_schema(schema)
+        .with_affected(affected))
+}
+
+pub fn create_read_executor<'a>(
+    stmt: Statement,
+    view: ReadView<'a>,
+    bind_catalog: &'a mut BinderCatalog,
+) -> Result<Executor<'a>> {
+    let started = Instant::now();
+    let bound = Binder::shared(bind_catalog, view.storage)
+        .bind(stmt)
+        .context("Bind failed")?;
+    record_elapsed("bind_us", started);
+    let started = Instant::now();
+    let phys = plan(bound, bind_catalog, view.storage)?;
+    let schema = phys.schema();
+    let root = build_read_operator_with(phys, view, &bind_catalog.memory)
+        .context("Building operators failed")?;
+    record_elapsed("plan_us", started);
+    Ok(Executor::new(root).with_schema(schema))
+}
+
+// The columns `stmt` would make, bound and planned but not run, with the
+// whole numbers in it that `literal_types` finds a type for.
+pub fn describe_statement(
+    stmt: Statement,
+    storage: &Storage,
+) -> Result<(Schema, Vec<(i64, ColumnType)>)> {
+    let mut bind_catalog =
+        BinderCatalog::from_storage(&storage.catalog).with_variables(storage.variables.clone());
+    let bound = Binder::shared(&mut bind_catalog, storage)
+        .bind(stmt)
+        .context("Bind failed")?;
+    let literals = literal_types(&bound, storage);
+    let schema = plan(bound, &bind_catalog, storage)?.schema();
+    Ok((schema, literals))
+}
+
+fn plan(bound: BoundStmt, bind_catalog: &BinderCatalog, storage: &Storage) -> Result<PhysicalPlan> {
+    let mut lp = LogicalPlanner::new(&bind_catalog.tables);
+    let logical = lp.plan(bound).context("Logical planning failed")?;
+
+    let optimized = Optimizer::optimize(logical).context("Optimize failed")?;
+
+    let mut pp = PhysicalPlanner::new(bind_catalog, storage);
+    pp.create_physical_plan(optimized)
+        .context("Physical planning failed")
+}
@@ -1,7 +1,8 @@
-
-
-use crate::query::binder::{BoundExpr, BoundStmt, DataType, TableMeta};
-use crate::storage::storage::Storage;
+use crate::query::binder::{
+    Aggregate, BoundExpr, BoundStmt, Collation, DataType, Grouping, SemiJoin, SortKey, TableMeta,
+};
+use crate::storage::storage::IndexKind;
+use crate::tx::log_manager::Lsn;
 use anyhow::{Result, anyhow, bail};
 use std::collections::HashMap;
 
@@ -14,18 +15,33 @@ pub enum LogicalPlan {
     CreateIndex {
         index_name: String,
         table: String,
-        column: String,
+        columns: Vec<String>,
         order: usize,
+        kind: IndexKind,
     },
     Insert {
         table_name: String,
         col_ordinals: Vec<usize>,
-        values: Vec<BoundExpr>,
+        rows: Vec<Vec<BoundExpr>>,
+    },
+    // Deletes the rows of `table` its input reads, which is a scan of the
+    // table with the statement's WHERE.
+    Delete {
+        table: String,
+        input: Box<LogicalPlan>,
     },
     SeqScan {
         table: String,
         predicate: Option<BoundExpr>,
     },
+    // The table as it was at `lsn`, rebuilt from the log. No index is as
+    // it was then, so a filter on it stays a filter.
+    AsOfScan {
+        table: String,
+        lsn: Lsn,
+    },
+    // What a SELECT without FROM reads: one row with no columns.
+    SingleRow,
     Filter {
         input: Box<LogicalPlan>,
         predicate: BoundExpr,
@@ -34,16 +50,66 @@ pub enum LogicalPlan {
         input: Box<LogicalPlan>,
         exprs: Vec<BoundExpr>,
     },
+    // The input rows whose `keys` some row of the subquery equals, or with
+    // `anti`, none does. The subquery's rows are its keys, each compared
+    // with the input's by the collation of the two.
+    SemiJoin {
+        input: Box<LogicalPlan>,
+        subquery: Box<LogicalPlan>,
+        keys: Vec<BoundExpr>,
+        collations: Vec<Collation>,
+        anti: bool,
+    },
+    // One row per group: the keys, then the aggregates.
+    Aggregate {
+        input: Box<LogicalPlan>,
+        keys: Vec<BoundExpr>,
+        aggregates: Vec<Aggregate>,
+    },
+    Sort {
+        input: Box<LogicalPlan>,
+        keys: Vec<SortKey>,
+    },
+    // The rows of each input in turn.
+    UnionAll {
+        inputs: Vec<LogicalPlan>,
+    },
+    Explain {
+        input: Box<LogicalPlan>,
+        analyze: bool,
+    },
+    Reindex {
+        index_name: String,
+        table: String,
+    },
+    Analyze {
+        table: String,
+    },
+    DropTable {
+        table: String,
+    },
+    ShowLocks,
+    ShowTables {
+        schema: Option<String>,
+    },
+    ShowGrants,
+    Check,
+    Checkpoint,
+    FlushTables {
+        tables: Vec<String>,
+    },
+    Vacuum {
+        tables: Vec<String>,
+    },
 }
 
 pub struct Planner<'a> {
     catalog: &'a HashMap<String, TableMeta>,
-    storage: &'a mut Storage,
 }
 
 impl<'a> Planner<'a> {
-    pub fn new(catalog: &'a HashMap<String, TableMeta>, storage: &'a mut Storage) -> Self {
-        Planner { catalog, storage }
+    pub fn new(catalog: &'a HashMap<String, TableMeta>) -> Self {
+        Planner { catalog }
     }
 
     pub fn plan(&mut self, stmt: BoundStmt) -> Result<LogicalPlan> {
@@ -56,18 +122,20 @@ impl<'a> Planner<'a> {
             CreateIndex {
                 index_name,
                 table,
-                column,
+                columns,
                 order,
+                kind,
             } => Ok(LogicalPlan::CreateIndex {
                 index_name,
                 table,
-                column,
+                columns,
                 order,
+                kind,
             }),
             Insert {
                 table,
                 col_ordinals,
-                values,
+                rows,
             } => {
                 let key = table.to_ascii_lowercase();
                 if !self.catalog.contains_key(&key) {
@@ -76,32 +144,84 @@ impl<'a> Planner<'a> {
                 Ok(LogicalPlan::Insert {
                     table_name: table,
                     col_ordinals,
-                    values,
+                    rows,
                 })
             }
+            Delete { table, filter } => Ok(LogicalPlan::Delete {
+                input: Box::new(LogicalPlan::SeqScan {
+                    table: table.clone(),
+                    predicate: filter,
+                }),
+                table,
+            }),
             Select {
                 projections,
                 table,
+                as_of,
                 filter,
-            } => self.plan_select(table, projections, filter),
+                semi_joins,
+                grouping,
+                order_by,
+            } => {
+                let rows = self.plan_from(table, as_of, filter, semi_joins)?;
+                Ok(plan_select(rows, projections, grouping, order_by))
+            }
+            UnionAll { selects, order_by } => {
+                let inputs = selects
+                    .into_iter()
+                    .map(|select| self.plan(select))
+                    .collect::<Result<_>>()?;
+                let plan = LogicalPlan::UnionAll { inputs };
+                if order_by.is_empty() {
+                    return Ok(plan);
+                }
+                Ok(LogicalPlan::Sort {
+                    input: Box::new(plan),
+                    keys: order_by,
+                })
+            }
+            Explain { stmt, analyze } => Ok(LogicalPlan::Explain {
+                input: Box::new(self.plan(*stmt)?),
+                analyze,
+            }),
+            Reindex { index_name, table } => Ok(LogicalPlan::Reindex { index_name, table }),
+            Analyze { table } => Ok(LogicalPlan::Analyze { table }),
+            DropTable { table } => Ok(LogicalPlan::DropTable { table }),
+            ShowLocks => Ok(LogicalPlan::ShowLocks),
+            ShowTables { schema } => Ok(LogicalPlan::ShowTables { schema }),
+            ShowGrants => Ok(LogicalPlan::ShowGrants),
+            Check => Ok(LogicalPlan::Check),
+            Checkpoint => Ok(LogicalPlan::Checkpoint),
+            FlushTables { tables } => Ok(LogicalPlan::FlushTables { tables }),
+            Vacuum { tables } => Ok(LogicalPlan::Vacuum { tables }),
         }
     }
 
-    fn plan_select(
+    // The rows a SELECT reads and keeps, before any grouping.
+    fn plan_from(
         &mut self,
-        table: String,
-        projections: Vec<BoundExpr>,
+        table: Option<String>,
+        as_of: Option<Lsn>,
         filter: Option<BoundExpr>,
+        semi_joins: Vec<SemiJoin>,
     ) -> Result<LogicalPlan> {
-        let key = table.to_ascii_lowercase();
-        
-        let _ = self
-            .catalog
-            .get(&key)
-            .ok_or_else(|| anyhow!("Unknown table '{}'", table))?;
-        let mut plan = LogicalPlan::SeqScan {
-            table: table.clone(),
-            predicate: None,
+        let mut plan = match table {
+            None => LogicalPlan::SingleRow,
+            Some(table) => {
+                let key = table.to_ascii_lowercase();
+
+                let _ = self
+                    .catalog
+                    .get(&key)
+                    .ok_or_else(|| anyhow!("Unknown table '{}'", table))?;
+                match as_of {
+                    Some(lsn) => LogicalPlan::AsOfScan { table, lsn },
+                    None => LogicalPlan::SeqScan {
+                        table: table.clone(),
+                        predicate: None,
+                    },
+                }
+            }
         };
         if let Some(pred) = filter {
             plan = LogicalPlan::Filter {
@@ -109,10 +229,52 @@ impl<'a> Planner<'a> {
                 predicate: pred,
             };
         }
-        plan = LogicalPlan::Projection {
+        for semi_join in semi_joins {
+            let collations = semi_join
+                .outer_keys
+                .iter()
+                .zip(&semi_join.inner_keys)
+                .map(|(outer, inner)| outer.collation().of_comparison(inner.collation()))
+                .collect();
+            let subquery =
+                self.plan_from(Some(semi_join.table), None, semi_join.filter, Vec::new())?;
+            plan = LogicalPlan::SemiJoin {
+                input: Box::new(plan),
+                subquery: Box::new(LogicalPlan::Projection {
+                    input: Box::new(subquery),
+                    exprs: semi_join.inner_keys,
+                }),
+                keys: semi_join.outer_keys,
+                collations,
+                anti: semi_join.anti,
+            };
+        }
+        Ok(plan)
+    }
+}
+
+// What a SELECT makes of the rows it keeps.
+fn plan_select(
+    mut plan: LogicalPlan,
+    projections: Vec<BoundExpr>,
+    grouping: Option<Grouping>,
+    order_by: Vec<SortKey>,
+) -> LogicalPlan {
+    if let Some(Grouping { keys, aggregates }) = grouping {
+        plan = LogicalPlan::Aggregate {
             input: Box::new(plan),
-            exprs: projections,
+            keys,
+            aggregates,
         };
-        Ok(plan)
+    }
+    if !order_by.is_empty() {
+        plan = LogicalPlan::Sort {
+            input: Box::new(plan),
+            keys: order_by,
+        };
+    }
+    LogicalPlan::Projection {
+        input: Box::new(plan),
+        exprs: projections,
     }
 }
@@ -0,0 +1,262 @@
+use crate::query::binder::{expand_views, names_table};
+use crate::query::parser::{Expr, Statement};
+use crate::query::pipeline::subqueries;
+use crate::storage::storage::{Catalog, Privilege};
+use std::fmt;
+
+// Who may run a statement, going by the grants in the catalog. The server
+// checks every statement before it takes any lock; admins are not checked
+// at all, and `Database` runs without users.
+
+// The code a refusal carries in its JSON body.
+pub const PERMISSION_DENIED: &str = "PERMISSION_DENIED";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionDenied {
+    pub user: String,
+    // What was missing. Statements only an admin may run have neither.
+    pub table: Option<String>,
+    pub privilege: Option<Privilege>,
+    pub statement: &'static str,
+}
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.table, self.privilege) {
+            (Some(table), Some(privilege)) => write!(
+                f,
+                "Permission denied: {} needs {} on {} for {}",
+                self.user, privilege, table, self.statement
+            ),
+            _ => write!(
+                f,
+                "Permission denied: only an admin can run {}",
+                self.statement
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+// Whether `user`, who is not an admin, may run `stmt`. A table that does
+// not exist is left for binding to report.
+pub fn check(catalog: &Catalog, user: &str, stmt: &Statement) -> Result<(), PermissionDenied> {
+    let requires = |table: &str, privilege: Privilege, columns: &[&str], statement| {
+        needs(catalog, user, table, privilege, columns, statement)
+    };
+    let admin_only = |statement| {
+        Err(PermissionDenied {
+            user: user.to_ascii_lowercase(),
+            table: None,
+            privilege: None,
+            statement,
+        })
+    };
+    match stmt {
+        // Views have no grants of their own: reading through one needs what
+        // reading the table under it does. A subquery needs SELECT on its
+        // table as one of its own would.
+        Statement::Select { .. } => {
+            subqueries(stmt)
+                .into_iter()
+                .try_for_each(|select| check(catalog, user, select))?;
+            let Ok(Statement::Select {
+                projections,
+                table: Some(table),
+                filter,
+                group_by,
+                order_by,
+                ..
+            }) = expand_views(catalog, stmt.clone())
+            else {
+                return Ok(());
+            };
+            let mut columns = Vec::new();
+            if projections == [Expr::Star] {
+                if let Some(info) = catalog.tables.get(&table) {
+                    columns.extend(info.columns.iter().map(|c| c.name.as_str()));
+                }
+            } else {
+                for expr in &projections {
+                    referenced(expr, &table, false, &mut columns);
+                }
+            }
+            let keys = group_by.iter().chain(order_by.iter().map(|o| &o.expr));
+            for expr in filter.iter().chain(keys) {
+                referenced(expr, &table, false, &mut columns);
+            }
+            requires(&table, Privilege::Select, &columns, "SELECT")
+        }
+        Statement::UnionAll { selects, .. } => selects
+            .iter()
+            .try_for_each(|select| check(catalog, user, select)),
+        Statement::Insert { table, columns, .. } => {
+            let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+            requires(table, Privilege::Insert, &columns, "INSERT")
+        }
+        Statement::Copy { table, columns, .. } => {
+            let mut columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+            if columns.is_empty()
+                && let Some(info) = catalog.tables.get(table)
+            {
+                columns.extend(info.columns.iter().map(|c| c.name.as_str()));
+            }
+            requires(table, Privilege::Insert, &columns, "COPY")
+        }
+        Statement::Explain { stmt, .. } => check(catalog, user, stmt),
+        // What a cursor reads is checked once, when it is declared.
+        Statement::DeclareCursor { select, .. } => check(catalog, user, select),
+        Statement::Fetch { .. } | Statement::CloseCursor { .. } => Ok(()),
+        Statement::CreateIndex { table, .. } => {
+            requires(table, Privilege::All, &[], "CREATE INDEX")
+        }
+        Statement::Reindex { table, .. } => requires(table, Privilege::All, &[], "REINDEX"),
+        Statement::Analyze { table } => requires(table, Privilege::All, &[], "ANALYZE"),
+        Statement::Delete { table, .. } => requires(table, Privilege::All, &[], "DELETE"),
+        // Without a list it goes through every table.
+        Statement::Vacuum { tables } if tables.is_empty() => admin_only("VACUUM"),
+        Statement::Vacuum { tables } => tables
+            .iter()
+            .try_for_each(|table| requires(table, Privilege::All, &[], "VACUUM")),
+        Statement::DropTable { table } => requires(table, Privilege::All, &[], "DROP TABLE"),
+        Statement::AddCheck { table, .. } | Statement::RenameTable { table, .. } => {
+            requires(table, Privilege::All, &[], "ALTER TABLE")
+        }
+        // Needs ALL on every table with an index of that name.
+        Statement::RenameIndex { index_name, .. } => catalog
+            .indexes
+            .iter()
+            .filter(|(_, indexes)| indexes.iter().any(|i| &i.name == index_name))
+            .try_for_each(|(table, _)| requires(table, Privilege::All, &[], "ALTER INDEX")),
+        Statement::Grant { .. } => admin_only("GRANT"),
+        Statement::Revoke { .. } => admin_only("REVOKE"),
+        Statement::CreateUser { .. } => admin_only("CREATE USER"),
+        Statement::DropUser { .. } => admin_only("DROP USER"),
+        // A session's own settings are its own business.
+        Statement::Set { global: true, .. } => admin_only("SET GLOBAL"),
+        Statement::Set { .. } | Statement::ShowSettings { .. } | Statement::SetVariable { .. } => {
+            Ok(())
+        }
+        // Sequences have no grants of their own; anyone may draw from one.
+        Statement::CreateSequence { .. } => admin_only("CREATE SEQUENCE"),
+        Statement::DropSequence { .. } => admin_only("DROP SEQUENCE"),
+        Statement::CreateView { .. } => admin_only("CREATE VIEW"),
+        // Copying rows out of a table is reading them.
+        Statement::CreateTableAs { select, .. } => check(catalog, user, select),
+        // Schemas have no grants: anyone may make a table in any of them.
+        Statement::CreateSchema { .. } => admin_only("CREATE SCHEMA"),
+        Statement::DropSchema { .. } => admin_only("DROP SCHEMA"),
+        Statement::DropView { .. } => admin_only("DROP VIEW"),
+        // It reads every page, whoever may see them.
+        Statement::Check => admin_only("CHECK"),
+        Statement::Checkpoint => admin_only("CHECKPOINT"),
+        Statement::FlushTables { .. } => admin_only("FLUSH TABLES"),
+        // Whoever creates a table is granted ALL on it. A foreign key tells
+        // whoever inserts what the parent holds, so it needs SELECT there.
+        Statement::CreateTable { foreign_keys, .. } => foreign_keys.iter().try_for_each(|key| {
+            requires(
+                &key.parent,
+                Privilege::Select,
+                &[key.parent_column.as_str()],
+                "CREATE TABLE",
+            )
+        }),
+        Statement::ShowLocks
+        | Statement::ShowTables { .. }
+        | Statement::ShowGrants
+        | Statement::Begin
+        | Statement::Commit
+        | Statement::Rollback
+        | Statement::SetConstraints { .. } => Ok(()),
+    }
+}
+
+// Whether `user` may read or write every column of `table`, as an export or
+// an import does.
+pub fn check_table(
+    catalog: &Catalog,
+    user: &str,
+    table: &str,
+    privilege: Privilege,
+    statement: &'static str,
+) -> Result<(), PermissionDenied> {
+    let columns = match catalog.tables.get(table) {
+        Some(info) => info.columns.iter().map(|c| c.name.as_str()).collect(),
+        None => Vec::new(),
+    };
+    needs(catalog, user, table, privilege, &columns, statement)
+}
+
+fn needs(
+    catalog: &Catalog,
+    user: &str,
+    table: &str,
+    privilege: Privilege,
+    columns: &[&str],
+    statement: &'static str,
+) -> Result<(), PermissionDenied> {
+    let Some(info) = catalog.tables.get(table) else {
+        return Ok(());
+    };
+    // Names as the catalog spells them; unknown ones are binding's to report
+    // too.
+    let columns: Vec<String> = columns
+        .iter()
+        .filter_map(|name| {
+            info.columns
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(name))
+                .map(|c| c.name.clone())
+        })
+        .collect();
+    let user = user.to_ascii_lowercase();
+    match catalog.grants(table, &user) {
+        Some(grants) if grants.allows(privilege, &columns) => Ok(()),
+        _ => Err(PermissionDenied {
+            user,
+            table: Some(table.to_string()),
+            privilege: Some(privilege),
+            statement,
+        }),
+    }
+}
+
+// The columns of `table` that `expr` reads. Within a subquery, a column
+// named without a table is the subquery's own.
+fn referenced<'a>(expr: &'a Expr, table: &str, in_subquery: bool, columns: &mut Vec<&'a str>) {
+    match expr {
+        Expr::Column(name) if !in_subquery => columns.push(name),
+        Expr::QualifiedColumn { table: of, column } if names_table(of, table) => {
+            columns.push(column)
+        }
+        Expr::Column(_)
+        | Expr::QualifiedColumn { .. }
+        | Expr::Literal(_)
+        | Expr::Variable(_)
+        | Expr::Star => {}
+        Expr::BinaryOp { left, right, .. } => {
+            referenced(left, table, in_subquery, columns);
+            referenced(right, table, in_subquery, columns);
+        }
+        Expr::Call { args, .. } => args
+            .iter()
+            .for_each(|arg| referenced(arg, table, in_subquery, columns)),
+        Expr::Exists { select, .. } => {
+            let Statement::Select {
+                projections,
+                filter,
+                group_by,
+                order_by,
+                ..
+            } = select.as_ref()
+            else {
+                return;
+            };
+            let keys = group_by.iter().chain(order_by.iter().map(|o| &o.expr));
+            for expr in projections.iter().chain(filter).chain(keys) {
+                referenced(expr, table, true, columns);
+            }
+        }
+    }
+}
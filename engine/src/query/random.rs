@@ -0,0 +1,50 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+};
+
+// RANDOM()'s numbers, one generator a session, shared by its statements so
+// each goes on where the last left off. It starts from a seed of its own,
+// and SETSEED starts it over from a given one, after which the same calls
+// give the same numbers again. Not fit for anything secret.
+#[derive(Clone)]
+pub struct Random(Arc<Mutex<u64>>);
+
+impl Random {
+    pub fn new() -> Self {
+        // A fresh `RandomState` has keys the process has not used yet.
+        Random::seeded(RandomState::new().build_hasher().finish() as i64)
+    }
+
+    pub fn seeded(seed: i64) -> Self {
+        Random(Arc::new(Mutex::new(seed as u64)))
+    }
+
+    pub fn seed(&self, seed: i64) {
+        *self.0.lock().unwrap() = seed as u64;
+    }
+
+    // The next number, from 0 up to the largest INT. SplitMix64.
+    pub fn next(&self) -> i64 {
+        let mut state = self.0.lock().unwrap();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        ((z ^ (z >> 31)) >> 1) as i64
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Random {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Random")
+    }
+}
@@ -0,0 +1,99 @@
+use crate::query::binder::UnknownName;
+use crate::query::lexer::{Lexer, TokenKind};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Where a token sits in the statement, as byte offsets into its text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    // Line and column the span starts at, both counted from 1 and the column
+    // in characters.
+    pub fn position(&self, src: &str) -> (usize, usize) {
+        let before = &src[..self.start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+        )
+    }
+
+    // The line the span starts on with carets under it, the way rustc
+    // quotes code:
+    //
+    //   |
+    // 1 | SELECT nmae FROM users;
+    //   |        ^^^^
+    pub fn excerpt(&self, src: &str) -> String {
+        let (line, col) = self.position(src);
+        let line_start = src[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[self.start..]
+            .find('\n')
+            .map_or(src.len(), |i| self.start + i);
+        let text = src[line_start..line_end].trim_end_matches('\r');
+        // A span running onto later lines is underlined to the end of this
+        // one; an empty one, like the end of input, still gets a caret.
+        let width = src[self.start..self.end.clamp(self.start, line_end)]
+            .chars()
+            .count()
+            .max(1);
+        let gutter = " ".repeat(line.to_string().len());
+        format!(
+            "{gutter} |\n{line} | {text}\n{gutter} | {}{}",
+            " ".repeat(col - 1),
+            "^".repeat(width)
+        )
+    }
+}
+
+// A statement that could not be read, with the place it went wrong.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceError {
+    pub message: String,
+    pub line: usize,
+    pub col: usize,
+    pub excerpt: String,
+}
+
+impl SourceError {
+    pub fn new(src: &str, span: Span, message: impl Into<String>) -> Self {
+        let (line, col) = span.position(src);
+        SourceError {
+            message: message.into(),
+            line,
+            col,
+            excerpt: span.excerpt(src),
+        }
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}:{}\n{}",
+            self.message, self.line, self.col, self.excerpt
+        )
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+// Quotes the statement where a bind error names a table or column it does
+// not have. The tree the binder works on keeps no positions, so this points
+// at the first identifier in `src` spelled as the name is.
+pub fn excerpt_for(src: &str, error: &anyhow::Error) -> Option<String> {
+    let unknown = error.downcast_ref::<UnknownName>()?;
+    Lexer::new(src)
+        .map_while(Result::ok)
+        .find(|token| matches!(&token.kind, TokenKind::Identifier(id) if id.eq_ignore_ascii_case(&unknown.name)))
+        .map(|token| token.span.excerpt(src))
+}
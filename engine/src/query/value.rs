@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+
+// A value as the engine handles it everywhere: in literals the parser reads,
+// rows storage keeps and index keys. Values of different types are never
+// equal, and order by type first, every INT before every TEXT, so that any
+// set of values sorts the same way each time. There is no NULL yet; when
+// there is, it goes first and equals itself here, as IS NOT DISTINCT FROM
+// has it, so GROUP BY keeps NULLs together and index keys match them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    Int(i64),
+    String(String),
+}
+
+impl Value {
+    // The type as a column declares it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "INT",
+            Value::String(_) => "TEXT",
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            Value::String(_) => None,
+        }
+    }
+
+    pub fn same_type(&self, other: &Value) -> bool {
+        self.type_rank() == other.type_rank()
+    }
+
+    // How a value reads as a condition: zero and the empty string are false.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Int(i) => *i != 0,
+            Value::String(s) => !s.is_empty(),
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            Value::Int(_) => 0,
+            Value::String(_) => 1,
+        }
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Int(l), Value::Int(r)) => l.cmp(r),
+            (Value::String(l), Value::String(r)) => l.cmp(r),
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// As SQL would write it, for messages.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::String(s) => write!(f, "'{}'", s.replace('\'', "''")),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+// How a TEXT column's values compare, as `COLLATE` declares it. NOCASE
+// takes ASCII letters of either case for the same, as SQLite's does; other
+// characters, and every other type, compare as `Value` orders them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Collation {
+    #[default]
+    Binary,
+    NoCase,
+}
+
+impl Collation {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "BINARY" => Some(Collation::Binary),
+            "NOCASE" => Some(Collation::NoCase),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Collation::Binary => "BINARY",
+            Collation::NoCase => "NOCASE",
+        }
+    }
+
+    // What a comparison of two values goes by: NOCASE when either side
+    // has it, so `name = 'alice'` on a NOCASE column finds 'Alice'.
+    pub fn of_comparison(self, other: Collation) -> Collation {
+        match (self, other) {
+            (Collation::Binary, Collation::Binary) => Collation::Binary,
+            _ => Collation::NoCase,
+        }
+    }
+
+    pub fn compare(self, left: &Value, right: &Value) -> Ordering {
+        match (self, left, right) {
+            (Collation::NoCase, Value::String(l), Value::String(r)) => fold(l).cmp(fold(r)),
+            _ => left.cmp(right),
+        }
+    }
+
+    // `value` as the collation tells values apart: two values compare
+    // equal exactly when their keys are equal.
+    pub fn key(self, value: Value) -> Value {
+        match (self, value) {
+            (Collation::NoCase, Value::String(s)) => Value::String(s.to_ascii_lowercase()),
+            (_, value) => value,
+        }
+    }
+}
+
+fn fold(s: &str) -> impl Iterator<Item = u8> + '_ {
+    s.bytes().map(|b| b.to_ascii_lowercase())
+}
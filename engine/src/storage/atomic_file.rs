@@ -0,0 +1,126 @@
+use crate::storage::failpoint;
+use anyhow::{Context, Result, bail};
+use byteorder::{ByteOrder, LittleEndian};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+// Small files rewritten whole: the WAL manifest, the checkpoint master
+// record, the accounts, a backup's catalog and an import's resume state.
+// Each is written to a temporary file, synced and renamed over the old
+// one, so a crash leaves either the old file or the new, never half of
+// one. Each ends with the CRC32 of what comes before it and `FOOTER_MAGIC`,
+// and the file it replaced is kept beside it, so one that fails its
+// checksum can be read from the generation before.
+
+const FOOTER_MAGIC: &[u8; 4] = b"MYDC";
+const FOOTER_LEN: usize = 8;
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Where the generation before the current one is kept.
+pub fn previous_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".prev")
+}
+
+pub fn atomic_write(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut contents = Vec::with_capacity(bytes.len() + FOOTER_LEN);
+    contents.extend_from_slice(bytes);
+    contents.extend_from_slice(&crc32fast::hash(bytes).to_le_bytes());
+    contents.extend_from_slice(FOOTER_MAGIC);
+    let mut file = File::create(&tmp).with_context(|| format!("creating {:?}", tmp))?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    // Copied rather than renamed aside, so there is a current file at
+    // every moment.
+    if path.exists() {
+        let previous = previous_path(path);
+        fs::copy(path, &previous).with_context(|| format!("copying {:?}", path))?;
+        File::open(&previous)?.sync_all()?;
+    }
+    failpoint::hit(failpoint::ATOMIC_RENAME)?;
+    fs::rename(&tmp, path).with_context(|| format!("renaming {:?} to {:?}", tmp, path))?;
+    sync_parent(path)
+}
+
+// What `atomic_write` last wrote to `path`, or None when there is no file.
+// A file that fails its checksum is read from the generation before it if
+// that one passes. A file without a footer predates them and is read as
+// it is.
+pub fn atomic_read(path: &Path) -> Result<Option<Vec<u8>>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {:?}", path)),
+    };
+    if let Some(payload) = verified(bytes) {
+        return Ok(Some(payload));
+    }
+    let previous = previous_path(path);
+    match fs::read(&previous).ok().and_then(verified) {
+        Some(payload) => {
+            warn!(file = ?path, "File failed its checksum; read the previous generation");
+            Ok(Some(payload))
+        }
+        None => bail!(
+            "{:?} fails its checksum and has no good previous generation",
+            path
+        ),
+    }
+}
+
+// Removes `path` together with the generation before it and any
+// temporary file a crash left.
+pub fn atomic_remove(path: &Path) -> Result<()> {
+    for file in [
+        path.to_path_buf(),
+        previous_path(path),
+        with_suffix(path, ".tmp"),
+    ] {
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to remove {:?}", file));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn verified(mut bytes: Vec<u8>) -> Option<Vec<u8>> {
+    let Some(at) = bytes.len().checked_sub(FOOTER_LEN) else {
+        return Some(bytes);
+    };
+    if &bytes[at + 4..] != FOOTER_MAGIC {
+        return Some(bytes);
+    }
+    let crc = LittleEndian::read_u32(&bytes[at..at + 4]);
+    bytes.truncate(at);
+    (crc32fast::hash(&bytes) == crc).then_some(bytes)
+}
+
+// A rename is only durable once the directory holding it is synced. Only
+// Unix lets a directory be opened to sync it; elsewhere the rename itself
+// is.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("syncing {:?}", dir))
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use crate::net::auth::UserStore;
+use crate::storage::atomic_file::{atomic_read, atomic_write};
+use crate::storage::storage::{Catalog, Storage, TableStats};
+use crate::tx::log_manager::{LogManager, Lsn, Manifest, MasterRecord, segment_path, with_suffix};
+use crate::tx::mvcc::{RowHeader, TxStatus};
+use crate::tx::recovery_manager;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// A backup is laid out as a data directory with the WAL where the server
+// looks for it by default, so `mydb server --data-dir <backup>` opens it
+// as it is.
+pub const DATA_FILE: &str = "data.db";
+pub const WAL_FILE: &str = "wal.log";
+
+// What `POST /backup` made.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupReport {
+    pub dir: PathBuf,
+    // The checkpoint the backup was taken at: the transactions that
+    // committed before it are in the backup, and no others.
+    pub lsn: Lsn,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// The catalog is not kept in the data file: every checkpoint saves it next
+// to the WAL, and a backup takes it along there.
+pub fn catalog_path(wal: &Path) -> PathBuf {
+    with_suffix(wal, ".catalog")
+}
+
+// Takes a checkpoint, so the data file has every change logged so far, and
+// the catalog as committed transactions left it. Writers have to be kept
+// out from here until `copy` is done.
+pub fn prepare(storage: &mut Storage, wal: &LogManager) -> Result<(Lsn, Catalog)> {
+    let lsn = recovery_manager::checkpoint(storage, wal).context("Checkpoint failed")?;
+    Ok((lsn, committed_catalog(storage)?))
+}
+
+// Rows of transactions still open are left out of the row lists: recovery
+// rolls those transactions back in the backup.
+fn committed_catalog(storage: &mut Storage) -> Result<Catalog> {
+    let mut catalog = storage.catalog.clone();
+    for table in catalog.tables.values_mut() {
+        let mut stats = TableStats::default();
+        let mut kept = Vec::with_capacity(table.records.len());
+        for &rid in &table.records {
+            let raw = storage.fetch(rid)?;
+            let header = RowHeader::read(&raw);
+            if storage.txns.status(header.xmin) == TxStatus::Active {
+                continue;
+            }
+            stats.add(rid, raw.len());
+            // A delete still in progress is rolled back with the rest.
+            if storage.txns.is_deleted(&header) {
+                stats.dead_rows += 1;
+            }
+            kept.push(rid);
+        }
+        table.records = kept;
+        table.stats = stats;
+    }
+    Ok(catalog)
+}
+
+// Copies the data file, the log that goes with it and the accounts into
+// `dir`, which must be empty or not exist yet. Nothing may write a page
+// meanwhile; readers can carry on.
+pub fn copy(
+    storage: &Storage,
+    wal: &LogManager,
+    lsn: Lsn,
+    catalog: &Catalog,
+    dir: &Path,
+) -> Result<BackupReport> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        bail!("{:?} is not empty", dir);
+    }
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    let mut report = BackupReport {
+        dir: dir.to_path_buf(),
+        lsn,
+        files: 1,
+        bytes: 0,
+    };
+
+    // Page by page, through the same file handle the buffer pool writes
+    // with.
+    let data_file = dir.join(DATA_FILE);
+    let pagefile = &storage.buffer_pool.pagefile;
+    let mut out = BufWriter::new(
+        File::create(&data_file).with_context(|| format!("Failed to create {:?}", data_file))?,
+    );
+    for page_no in 0..pagefile.num_pages()? {
+        let page = pagefile.read_page(page_no)?;
+        out.write_all(&page)?;
+        report.bytes += page.len() as u64;
+    }
+    out.into_inner()?.sync_all()?;
+
+    wal.flush_all()?;
+    let (from, to) = (wal.path(), dir.join(WAL_FILE));
+    let mut files = vec![
+        (Manifest::path(from), Manifest::path(&to)),
+        (MasterRecord::path(from), MasterRecord::path(&to)),
+        (UserStore::path(from), UserStore::path(&to)),
+    ];
+    files.extend(
+        wal.manifest()
+            .segments()
+            .map(|segment| (segment_path(from, segment), segment_path(&to, segment))),
+    );
+    for (from, to) in files {
+        if from.exists() {
+            report.bytes += fs::copy(&from, &to)
+                .with_context(|| format!("Failed to copy {:?} to {:?}", from, to))?;
+            report.files += 1;
+        }
+    }
+
+    report.bytes += save_catalog(catalog, &to)?;
+    report.files += 1;
+    Ok(report)
+}
+
+// Leaves `catalog` next to `wal` for `load_catalog` to find, and returns
+// its size.
+pub fn save_catalog(catalog: &Catalog, wal: &Path) -> Result<u64> {
+    let bytes = serde_json::to_vec(catalog)?;
+    atomic_write(&catalog_path(wal), &bytes)?;
+    Ok(bytes.len() as u64)
+}
+
+// Reads the catalog saved next to `wal`, by a checkpoint or a backup, for
+// recovery to bring up to date with the log after it. Returns whether
+// there was one.
+pub fn load_catalog(storage: &mut Storage, wal: &Path) -> Result<bool> {
+    let path = catalog_path(wal);
+    let Some(bytes) = atomic_read(&path)? else {
+        return Ok(false);
+    };
+    storage.catalog =
+        serde_json::from_slice(&bytes).with_context(|| format!("Malformed catalog {:?}", path))?;
+    Ok(true)
+}
@@ -1,9 +1,21 @@
 
+use crate::storage::failpoint;
 use crate::storage::pagefile::PageFile;
-use std::collections::{HashMap, VecDeque};
+use crate::tx::log_manager::{LogManager, Lsn};
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 
+// Pages a sequential scan has read ahead by default, the one it is on
+// included.
+pub const READ_AHEAD_PAGES: usize = 8;
+// Scans whose read-ahead the pool keeps at once; past that the oldest pages
+// read ahead are dropped.
+const READ_AHEAD_SCANS: usize = 4;
+
 pub struct Frame {
     pub page_no: u64,
     pub data: Vec<u8>,
@@ -11,15 +23,102 @@ pub struct Frame {
     pub pin_count: usize,
     
     pub ref_bit: bool,
+    pub lsn: Lsn,
 }
 
 
 pub struct BufferPool {
-    pool: HashMap<u64, Frame>,
+    pub pool: HashMap<u64, Frame>,
     capacity: usize,
     eviction_queue: VecDeque<u64>,
     clock_hand: usize,
     pub pagefile: PageFile,
+    pub wal: Option<Arc<LogManager>>,
+    pub stats: Arc<PoolStats>,
+    read_ahead: Mutex<ReadAhead>,
+    read_ahead_pages: usize,
+}
+
+// Pages read ahead of a sequential scan that have not been asked for yet.
+// They are kept apart from the frames, so reading ahead never evicts a
+// page anyone is using, and only pages that are not in a frame are read:
+// a page only changes through its frame, so the copy here stays what is on
+// disk until a frame takes it.
+#[derive(Default)]
+struct ReadAhead {
+    pages: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+}
+
+impl ReadAhead {
+    fn insert(&mut self, page_no: u64, data: Vec<u8>, limit: usize) {
+        if self.pages.insert(page_no, data).is_none() {
+            self.order.push_back(page_no);
+        }
+        while self.pages.len() > limit {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.pages.remove(&oldest);
+        }
+    }
+
+    fn take(&mut self, page_no: u64) -> Option<Vec<u8>> {
+        let data = self.pages.remove(&page_no)?;
+        self.order.retain(|&p| p != page_no);
+        Some(data)
+    }
+}
+
+// Page requests served from memory and from disk, and those served from
+// pages read ahead, with how many were. Shared so they can be read without
+// locking the pool.
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub prefetched: AtomicU64,
+    pub prefetch_hits: AtomicU64,
+}
+
+// The pages one statement had the pool read from disk, when it asked for
+// them or ahead of its scan, and the requests it found already in memory,
+// in a frame or read ahead. A page a scan read ahead counts once for each.
+// Readers share the pool, so the counts go with the thread the statement
+// runs on rather than with the pool.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageCounts {
+    pub read: u64,
+    pub cached: u64,
+}
+
+thread_local! {
+    static PAGE_COUNTS: Cell<Option<PageCounts>> = const { Cell::new(None) };
+}
+
+impl PageCounts {
+    // Runs `f`, counting the pages it asks for on this thread. Counting
+    // inside another `track` adds to the outer counts as well.
+    pub fn track<T>(f: impl FnOnce() -> T) -> (T, PageCounts) {
+        let outer = PAGE_COUNTS.replace(Some(PageCounts::default()));
+        let result = f();
+        let counts = PAGE_COUNTS.take().unwrap_or_default();
+        PAGE_COUNTS.set(outer.map(|outer| PageCounts {
+            read: outer.read + counts.read,
+            cached: outer.cached + counts.cached,
+        }));
+        (result, counts)
+    }
+
+    fn count(read: u64, cached: u64) {
+        PAGE_COUNTS.with(|counts| {
+            if let Some(mut now) = counts.get() {
+                now.read += read;
+                now.cached += cached;
+                counts.set(Some(now));
+            }
+        });
+    }
 }
 
 impl BufferPool {
@@ -31,23 +130,90 @@ impl BufferPool {
             eviction_queue: VecDeque::new(),
             clock_hand: 0,
             pagefile,
+            wal: None,
+            stats: Arc::new(PoolStats::default()),
+            read_ahead: Mutex::new(ReadAhead::default()),
+            read_ahead_pages: READ_AHEAD_PAGES,
         })
     }
 
+    // How many pages a sequential scan reads at a time; 0 or 1 turns reading
+    // ahead off.
+    pub fn set_read_ahead(&mut self, pages: usize) {
+        self.read_ahead_pages = pages;
+        *self.read_ahead.get_mut().unwrap() = ReadAhead::default();
+    }
+
+    // Reads ahead of a scan that will want `pages` in that order: the first
+    // of them that are neither in a frame nor already read ahead are read,
+    // runs of neighbouring pages in one go. Returns how many of `pages` that
+    // covered, for the scan to call again once it is past them.
+    pub fn prefetch(&self, pages: &[u64]) -> io::Result<usize> {
+        let window = &pages[..pages.len().min(self.read_ahead_pages)];
+        if window.len() < 2 {
+            return Ok(window.len());
+        }
+        let mut wanted: Vec<u64> = {
+            let ahead = self.read_ahead.lock().unwrap();
+            window
+                .iter()
+                .copied()
+                .filter(|p| !self.pool.contains_key(p) && !ahead.pages.contains_key(p))
+                .collect()
+        };
+        wanted.sort_unstable();
+        wanted.dedup();
+
+        // The disk is read with the lock let go, so readers of other pages
+        // are not held up behind it.
+        let mut read = Vec::new();
+        for run in wanted.chunk_by(|a, b| a + 1 == *b) {
+            let pages = self.pagefile.read_pages(run[0], run.len())?;
+            read.extend(run.iter().copied().zip(pages));
+        }
+        self.stats
+            .prefetched
+            .fetch_add(read.len() as u64, Ordering::Relaxed);
+        PageCounts::count(read.len() as u64, 0);
+        let limit = self.read_ahead_pages * READ_AHEAD_SCANS;
+        let mut ahead = self.read_ahead.lock().unwrap();
+        for (page_no, data) in read {
+            ahead.insert(page_no, data, limit);
+        }
+        Ok(window.len())
+    }
+
     
     pub fn fetch_page(&mut self, page_no: u64) -> io::Result<&mut Frame> {
         
-        if !self.pool.contains_key(&page_no) {
+        if self.pool.contains_key(&page_no) {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            PageCounts::count(0, 1);
+        } else {
             if self.pool.len() == self.capacity {
                 self.evict_one()?;
             }
-            let buf = self.pagefile.read_page(page_no)?;
+            // A page read ahead moves into the frame, so the pool never holds
+            // two copies of it.
+            let buf = match self.read_ahead.get_mut().unwrap().take(page_no) {
+                Some(buf) => {
+                    self.stats.prefetch_hits.fetch_add(1, Ordering::Relaxed);
+                    PageCounts::count(0, 1);
+                    buf
+                }
+                None => {
+                    self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                    PageCounts::count(1, 0);
+                    self.pagefile.read_page(page_no)?
+                }
+            };
             let frame = Frame {
                 page_no,
                 data: buf,
                 is_dirty: false,
                 pin_count: 0,
                 ref_bit: false,
+                lsn: 0,
             };
             self.pool.insert(page_no, frame);
             self.eviction_queue.push_back(page_no);
@@ -60,7 +226,29 @@ impl BufferPool {
         Ok(frame)
     }
 
-    
+    // A copy of the page for readers sharing the pool. Only an exclusive
+    // holder can change the pool, so a page that is not cached is read from
+    // disk without being added, unless a scan read it ahead.
+    pub fn read_page(&self, page_no: u64) -> io::Result<Vec<u8>> {
+        match self.pool.get(&page_no) {
+            Some(frame) => {
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                PageCounts::count(0, 1);
+                Ok(frame.data.clone())
+            }
+            None => {
+                if let Some(data) = self.read_ahead.lock().unwrap().pages.get(&page_no) {
+                    self.stats.prefetch_hits.fetch_add(1, Ordering::Relaxed);
+                    PageCounts::count(0, 1);
+                    return Ok(data.clone());
+                }
+                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                PageCounts::count(1, 0);
+                self.pagefile.read_page(page_no)
+            }
+        }
+    }
+
     pub fn unpin_page(&mut self, page_no: u64, is_dirty: bool) {
         if let Some(frame) = self.pool.get_mut(&page_no) {
             if frame.pin_count > 0 {
@@ -72,22 +260,77 @@ impl BufferPool {
         }
     }
 
-    
-    pub fn flush_all(&mut self) -> io::Result<()> {
+    pub fn set_page_lsn(&mut self, page_no: u64, lsn: Lsn) {
+        if let Some(frame) = self.pool.get_mut(&page_no) {
+            frame.lsn = frame.lsn.max(lsn);
+        }
+    }
+
+    pub fn dirty_pages(&self) -> Vec<(u64, Lsn)> {
+        let mut pages: Vec<_> = self
+            .pool
+            .values()
+            .filter(|f| f.is_dirty)
+            .map(|f| (f.page_no, f.lsn))
+            .collect();
+        pages.sort();
+        pages
+    }
+
+    pub fn free_page(&mut self, page_no: u64) -> io::Result<()> {
+        self.read_ahead.get_mut().unwrap().take(page_no);
+        if let Some(frame) = self.pool.get(&page_no) {
+            if frame.pin_count > 0 {
+                return Err(io::Error::other(format!(
+                    "Cannot free pinned page {}",
+                    page_no
+                )));
+            }
+            self.pool.remove(&page_no);
+            if let Some(pos) = self.eviction_queue.iter().position(|&p| p == page_no) {
+                self.eviction_queue.remove(pos);
+                if pos < self.clock_hand {
+                    self.clock_hand -= 1;
+                }
+            }
+        }
+        self.pagefile.free_page(page_no);
+        Ok(())
+    }
+
+    // Returns how many dirty pages it wrote.
+    pub fn flush_all(&mut self) -> io::Result<usize> {
+        failpoint::hit(failpoint::POOL_FLUSH)?;
+        self.flush_where(|_| true)
+    }
+
+    // Writes those of `pages` that are dirty, as `flush_all` writes every
+    // one, and returns how many that was.
+    pub fn flush_pages(&mut self, pages: &BTreeSet<u64>) -> io::Result<usize> {
+        self.flush_where(|page_no| pages.contains(&page_no))
+    }
+
+    fn flush_where(&mut self, wanted: impl Fn(u64) -> bool) -> io::Result<usize> {
+        let mut written = 0;
         for frame in self.pool.values_mut() {
-            if frame.is_dirty {
+            if frame.is_dirty && wanted(frame.page_no) {
+                flush_wal_to(&self.wal, frame.lsn)?;
                 self.pagefile.write_page(frame.page_no, &frame.data)?;
                 frame.is_dirty = false;
+                written += 1;
             }
         }
         self.pagefile.sync_all()?;
-        Ok(())
+        Ok(written)
     }
 
     
     fn evict_one(&mut self) -> io::Result<()> {
         let len = self.eviction_queue.len();
-        for _ in 0..len {
+        if self.clock_hand >= len {
+            self.clock_hand = 0;
+        }
+        for _ in 0..2 * len {
             let page_no = self.eviction_queue[self.clock_hand];
             let frame = self.pool.get_mut(&page_no).unwrap();
             if frame.pin_count == 0 {
@@ -98,10 +341,14 @@ impl BufferPool {
                 } else {
                     
                     if frame.is_dirty {
+                        flush_wal_to(&self.wal, frame.lsn)?;
                         self.pagefile.write_page(page_no, &frame.data)?;
                     }
                     self.pool.remove(&page_no);
                     self.eviction_queue.remove(self.clock_hand);
+                    if self.clock_hand >= self.eviction_queue.len() {
+                        self.clock_hand = 0;
+                    }
                     return Ok(());
                 }
             } else {
@@ -109,9 +356,14 @@ impl BufferPool {
                 self.clock_hand = (self.clock_hand + 1) % len;
             }
         }
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "No page available for eviction",
-        ))
+        Err(io::Error::other("No page available for eviction"))
+    }
+}
+
+// A dirty page may only reach disk once every log record that touched it has.
+fn flush_wal_to(wal: &Option<Arc<LogManager>>, lsn: Lsn) -> io::Result<()> {
+    match wal {
+        Some(wal) if lsn > wal.flushed_lsn() => wal.flush(lsn).map_err(io::Error::other),
+        _ => Ok(()),
     }
 }
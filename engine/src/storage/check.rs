@@ -0,0 +1,429 @@
+use crate::index::bplustree::BPlusTree;
+use crate::index::hash_index::HashIndex;
+use crate::index::key::{self, Key};
+use crate::query::value::Value;
+use crate::storage::record::{Page as RecordPage, RID};
+use crate::storage::storage::{
+    ColumnInfo, DataType, IndexInfo, IndexKind, Storage, TableInfo, TableStats, decode_row,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// One thing found wrong and where: `object` is what the page belongs to, a
+// table, an index, the free list, or the file itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Problem {
+    pub page: Option<u64>,
+    pub slot: Option<u16>,
+    pub object: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(page) = self.page {
+            write!(f, "page {} ", page)?;
+        }
+        if let Some(slot) = self.slot {
+            write!(f, "slot {} ", slot)?;
+        }
+        write!(f, "({}): {}", self.object, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckReport {
+    // Pages in the file, and how many of them were read as heap pages.
+    pub pages: u64,
+    pub heap_pages: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, page: Option<u64>, slot: Option<u16>, object: &str, message: String) {
+        self.problems.push(Problem {
+            page,
+            slot,
+            object: object.to_string(),
+            message,
+        });
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        let problems = match self.problems.len() {
+            0 => "no problems".to_string(),
+            1 => "1 problem".to_string(),
+            n => format!("{} problems", n),
+        };
+        write!(
+            f,
+            "{} pages, {} heap pages checked: {}",
+            self.pages, self.heap_pages, problems
+        )
+    }
+}
+
+// Checks everything the catalog knows about: the heap pages its rows and the
+// free list name, each row against its table's schema, each table's stats
+// against its rows, and each index against the rows it points at.
+pub fn check(storage: &mut Storage) -> Result<CheckReport> {
+    let mut report = CheckReport {
+        pages: storage.buffer_pool.pagefile.num_pages()?,
+        ..CheckReport::default()
+    };
+
+    let mut heap = BTreeSet::new();
+    for table in storage.catalog.tables.values() {
+        heap.extend(table.records.iter().map(|(page_no, _)| *page_no));
+    }
+    heap.extend(storage.free_list.entries().map(|(page_no, _)| page_no));
+    for page_no in heap {
+        if let Some(page) = read_heap_page(storage, page_no, "heap", &mut report) {
+            report.heap_pages += 1;
+            check_heap_page(page_no, &page, &mut report);
+        }
+    }
+
+    let free: Vec<(u64, usize)> = storage.free_list.entries().collect();
+    for (page_no, recorded) in free {
+        if page_no >= report.pages {
+            continue;
+        }
+        let page =
+            RecordPage::from_bytes(storage.buffer_pool.read_page(page_no)?, storage.page_size);
+        if let Some(actual) = (page.free_space_off() as usize).checked_sub(page.payload_start())
+            && actual != recorded
+        {
+            report.push(
+                Some(page_no),
+                None,
+                "free list",
+                format!(
+                    "records {} free bytes but the page has {}",
+                    recorded, actual
+                ),
+            );
+        }
+    }
+
+    let mut tables: Vec<TableInfo> = storage.catalog.tables.values().cloned().collect();
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+    for table in tables {
+        let object = format!("table {}", table.name);
+        let mut counted = TableStats::default();
+        for &rid in &table.records {
+            let Some(data) = read_row(storage, rid, &object, &mut report) else {
+                continue;
+            };
+            counted.add_stored(rid, &data, &storage.txns);
+            if let Err(message) = decode_row(&data)
+                .map_err(|e| format!("row does not deserialize: {:#}", e))
+                .and_then(|row| matches_schema(&table, &row))
+            {
+                report.push(Some(rid.0), Some(rid.1), &object, message);
+            }
+        }
+        if counted != table.stats {
+            let message = format!(
+                "stats count {}, the heap holds {}",
+                describe(&table.stats),
+                describe(&counted)
+            );
+            report.push(None, None, &object, message);
+        }
+        let mut indexes = storage.catalog.get_indexes(&table.name);
+        indexes.sort_by(|a, b| a.name.cmp(&b.name));
+        for index in indexes {
+            check_index(storage, &table, &index, &mut report);
+        }
+    }
+    Ok(report)
+}
+
+// Checks a data file without a catalog to go by, so only what the pages say
+// about themselves: a page is taken for a heap page when its header carries
+// its own page number and a free space offset.
+pub fn check_file(path: &Path, page_size: usize) -> Result<CheckReport> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let len = file.metadata()?.len();
+    let mut report = CheckReport {
+        pages: len.div_ceil(page_size as u64),
+        ..CheckReport::default()
+    };
+    if len % page_size as u64 != 0 {
+        report.push(
+            Some(len / page_size as u64),
+            None,
+            "file",
+            format!(
+                "file is {} bytes, not a whole number of {}-byte pages",
+                len, page_size
+            ),
+        );
+    }
+    for page_no in 0..len / page_size as u64 {
+        let mut data = vec![0; page_size];
+        file.read_exact(&mut data)
+            .with_context(|| format!("Failed to read page {}", page_no))?;
+        let page = RecordPage::from_bytes(data, page_size);
+        if page.page_id() == page_no && page.is_initialized() {
+            report.heap_pages += 1;
+            check_heap_page(page_no, &page, &mut report);
+        }
+    }
+    Ok(report)
+}
+
+// The header, the slot directory against the payload area, and every live
+// slot as a row, whatever table it is in.
+fn check_heap_page(page_no: u64, page: &RecordPage, report: &mut CheckReport) {
+    for (slot, message) in heap_page_problems(page_no, page) {
+        report.push(Some(page_no), slot, "heap", message);
+    }
+}
+
+// What `check_heap_page` finds wrong with a heap page, by slot where there is
+// one. A sequential scan that skips damaged pages asks the same of each page
+// it reads, so what it skips is what CHECK reports.
+pub fn heap_page_problems(page_no: u64, page: &RecordPage) -> Vec<(Option<u16>, String)> {
+    let mut problems = Vec::new();
+    let page_size = page.page_size;
+    if page.page_id() != page_no {
+        problems.push((None, format!("header names page {}", page.page_id())));
+    }
+    let payload_start = page.payload_start();
+    let free_off = page.free_space_off() as usize;
+    if payload_start > page_size {
+        problems.push((
+            None,
+            format!("{} slots do not fit in the page", page.slot_count()),
+        ));
+        return problems;
+    }
+    if free_off < payload_start || free_off > page_size {
+        problems.push((
+            None,
+            format!(
+                "free space starts at {}, outside the page's {}..{}",
+                free_off, payload_start, page_size
+            ),
+        ));
+        return problems;
+    }
+
+    let mut used = Vec::new();
+    for slot in 0..page.slot_count() {
+        let Some((off, len)) = page.slot_entry(slot).filter(|(_, len)| *len > 0) else {
+            continue;
+        };
+        if off < free_off || off + len > page_size {
+            problems.push((
+                Some(slot),
+                format!(
+                    "slot covers bytes {}..{}, outside the payload area {}..{}",
+                    off,
+                    off + len,
+                    free_off,
+                    page_size
+                ),
+            ));
+            continue;
+        }
+        used.push((off, off + len, slot));
+        if let Err(e) = page
+            .get_tuple(slot)
+            .map_or(Ok(()), |data| decode_row(data).map(|_| ()))
+        {
+            problems.push((Some(slot), format!("row does not deserialize: {:#}", e)));
+        }
+    }
+    used.sort();
+    for pair in used.windows(2) {
+        let ((_, end, slot), (start, _, next)) = (pair[0], pair[1]);
+        if start < end {
+            problems.push((Some(next), format!("slot overlaps slot {}", slot)));
+        }
+    }
+    problems
+}
+
+fn read_heap_page(
+    storage: &Storage,
+    page_no: u64,
+    object: &str,
+    report: &mut CheckReport,
+) -> Option<RecordPage> {
+    if page_no >= report.pages {
+        let message = format!("page is past the end of the file ({} pages)", report.pages);
+        report.push(Some(page_no), None, object, message);
+        return None;
+    }
+    match storage.buffer_pool.read_page(page_no) {
+        Ok(data) => Some(RecordPage::from_bytes(data, storage.page_size)),
+        Err(e) => {
+            report.push(
+                Some(page_no),
+                None,
+                object,
+                format!("page cannot be read: {}", e),
+            );
+            None
+        }
+    }
+}
+
+// The bytes a RID names, if it names a live slot.
+fn read_row(
+    storage: &Storage,
+    rid: RID,
+    object: &str,
+    report: &mut CheckReport,
+) -> Option<Vec<u8>> {
+    let (page_no, slot) = rid;
+    let page = read_heap_page(storage, page_no, object, report)?;
+    let message = match page.slot_entry(slot) {
+        None => format!("slot does not exist, the page has {}", page.slot_count()),
+        Some((_, 0)) => "slot is empty".to_string(),
+        Some((off, len)) if off + len > storage.page_size => {
+            "slot runs past the end of the page".to_string()
+        }
+        Some(_) => return page.get_tuple(slot).map(<[u8]>::to_vec),
+    };
+    report.push(Some(page_no), Some(slot), object, message);
+    None
+}
+
+fn describe(stats: &TableStats) -> String {
+    format!(
+        "{} rows in {} pages, {} bytes, {} dead",
+        stats.rows,
+        stats.page_count(),
+        stats.bytes,
+        stats.dead_rows
+    )
+}
+
+fn matches_schema(table: &TableInfo, row: &[Value]) -> Result<(), String> {
+    if row.len() != table.columns.len() {
+        return Err(format!(
+            "row has {} values but the table has {} columns",
+            row.len(),
+            table.columns.len()
+        ));
+    }
+    for (column, value) in table.columns.iter().zip(row) {
+        let declared = match column.data_type {
+            DataType::Int => "INT",
+            DataType::String => "TEXT",
+        };
+        if value.type_name() != declared {
+            return Err(format!(
+                "column {} holds {} but is declared {}",
+                column.name,
+                value.type_name(),
+                declared
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The tree or the buckets first, then every entry against its row.
+fn check_index(
+    storage: &mut Storage,
+    table: &TableInfo,
+    index: &IndexInfo,
+    report: &mut CheckReport,
+) {
+    let object = format!("index {}", index.name);
+    let ordinals = match index.key_ordinals(table) {
+        Ok(ordinals) => ordinals,
+        Err(e) => {
+            report.push(None, None, &object, format!("{:#}", e));
+            return;
+        }
+    };
+    let columns: Vec<ColumnInfo> = ordinals.iter().map(|&o| table.columns[o].clone()).collect();
+    let entries = match (index.kind, key::int_keyed(&columns)) {
+        (IndexKind::BTree, true) => {
+            let mut tree = BPlusTree::<i64>::open(storage, index);
+            tree.check()
+                .and_then(|_| tree.range_scan_keys(i64::MIN, i64::MAX))
+                .map(|entries| {
+                    entries
+                        .into_iter()
+                        .map(|(k, rid)| (Key::Int(k), rid))
+                        .collect()
+                })
+        }
+        (IndexKind::BTree, false) => {
+            let mut tree = BPlusTree::<Vec<u8>>::open(storage, index);
+            tree.check()
+                .and_then(|_| tree.range_scan_keys(Vec::new(), vec![key::AFTER]))
+                .map(|entries| {
+                    entries
+                        .into_iter()
+                        .map(|(k, rid)| (Key::Bytes(k), rid))
+                        .collect()
+                })
+        }
+        (IndexKind::Hash, _) => HashIndex::open(storage, index).check().map(|entries| {
+            entries
+                .into_iter()
+                .map(|(k, rid)| (Key::Int(k as i64), rid))
+                .collect::<Vec<_>>()
+        }),
+    };
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.push(Some(index.root_page), None, &object, format!("{:#}", e));
+            return;
+        }
+    };
+    for (key, rid) in entries {
+        let Some(data) = read_row(storage, rid, &object, report) else {
+            continue;
+        };
+        let values: Option<Vec<Value>> = decode_row(&data)
+            .ok()
+            .and_then(|row| ordinals.iter().map(|&o| row.get(o).cloned()).collect());
+        let found = values.as_ref().and_then(|values| Key::of(&columns, values));
+        if found.as_ref() != Some(&key) {
+            let found = match values {
+                Some(values) => values
+                    .iter()
+                    .map(Value::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                None => "nothing".to_string(),
+            };
+            let names: Vec<&str> = index.columns().map(String::as_str).collect();
+            report.push(
+                Some(rid.0),
+                Some(rid.1),
+                &object,
+                format!(
+                    "entry for key {} points at a row whose {} is {}",
+                    key,
+                    names.join(", "),
+                    found
+                ),
+            );
+        }
+    }
+}
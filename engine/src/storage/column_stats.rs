@@ -0,0 +1,147 @@
+use crate::query::value::{Collation, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Buckets in an INT column's histogram.
+pub const BUCKETS: usize = 32;
+// The values a TEXT column keeps counts for, most common first.
+pub const MOST_COMMON: usize = 16;
+
+// What ANALYZE found in one column, for the planner to guess how many rows
+// a predicate keeps. It is not kept up to date as rows come and go; the
+// next ANALYZE replaces it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ColumnStats {
+    // Equi-depth: `bounds` has one more entry than there are buckets, and
+    // about as many rows fall between each pair as between any other.
+    Histogram {
+        rows: u64,
+        distinct: u64,
+        bounds: Vec<i64>,
+    },
+    // The most common values with how many rows have each, as the column's
+    // collation tells them apart. With few distinct values, that is all of
+    // them.
+    MostCommon {
+        rows: u64,
+        distinct: u64,
+        values: Vec<(String, u64)>,
+    },
+}
+
+impl ColumnStats {
+    // None for a column with no rows to go by.
+    pub fn build(values: Vec<Value>, collation: Collation) -> Option<Self> {
+        let rows = values.len() as u64;
+        let mut ints = Vec::with_capacity(values.len());
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for value in values {
+            match collation.key(value) {
+                Value::Int(v) => ints.push(v),
+                Value::String(s) => *counts.entry(s).or_default() += 1,
+            }
+        }
+        if !ints.is_empty() {
+            ints.sort_unstable();
+            let last = ints.len() - 1;
+            let bounds = (0..=BUCKETS).map(|i| ints[i * last / BUCKETS]).collect();
+            ints.dedup();
+            return Some(ColumnStats::Histogram {
+                rows,
+                distinct: ints.len() as u64,
+                bounds,
+            });
+        }
+        if counts.is_empty() {
+            return None;
+        }
+        let distinct = counts.len() as u64;
+        let mut values: Vec<(String, u64)> = counts.into_iter().collect();
+        values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        values.truncate(MOST_COMMON);
+        Some(ColumnStats::MostCommon {
+            rows,
+            distinct,
+            values,
+        })
+    }
+
+    // The fraction of rows equal to `value`.
+    pub fn equal(&self, value: &Value, collation: Collation) -> f64 {
+        match (self, collation.key(value.clone())) {
+            (
+                ColumnStats::Histogram {
+                    distinct, bounds, ..
+                },
+                Value::Int(v),
+            ) => {
+                let (Some(&min), Some(&max)) = (bounds.first(), bounds.last()) else {
+                    return 0.0;
+                };
+                if v < min || v > max {
+                    return 0.0;
+                }
+                // A value common enough to fill whole buckets shows up as
+                // both ends of each of them.
+                let whole = bounds.windows(2).filter(|b| b[0] == v && b[1] == v);
+                let filled = whole.count() as f64 / BUCKETS as f64;
+                filled.max(1.0 / *distinct as f64)
+            }
+            (
+                ColumnStats::MostCommon {
+                    rows,
+                    distinct,
+                    values,
+                },
+                Value::String(s),
+            ) => {
+                if let Some((_, count)) = values.iter().find(|(v, _)| *v == s) {
+                    return *count as f64 / *rows as f64;
+                }
+                // The rows the list leaves out, shared evenly between the
+                // values it leaves out.
+                let listed: u64 = values.iter().map(|(_, count)| count).sum();
+                let unlisted = *distinct - values.len() as u64;
+                if unlisted == 0 {
+                    return 0.0;
+                }
+                (*rows - listed) as f64 / *rows as f64 / unlisted as f64
+            }
+            _ => 0.0,
+        }
+    }
+
+    // The fraction of rows from `lo` to `hi`, both included. Rows are taken
+    // to spread evenly over the values within a bucket.
+    pub fn between(&self, lo: i64, hi: i64) -> Option<f64> {
+        let ColumnStats::Histogram { bounds, .. } = self else {
+            return None;
+        };
+        if lo > hi {
+            return Some(0.0);
+        }
+        let mut fraction = 0.0;
+        for bucket in bounds.windows(2) {
+            let (start, end) = (bucket[0] as i128, bucket[1] as i128);
+            let covered = (end.min(hi as i128) - start.max(lo as i128) + 1).max(0);
+            fraction += covered as f64 / (end - start + 1) as f64;
+        }
+        Some((fraction / BUCKETS as f64).min(1.0))
+    }
+
+    // The fraction of rows a string comparison keeps, from the listed
+    // values; the rows the list leaves out are taken to pass one time in
+    // three.
+    pub fn compare(&self, keeps: impl Fn(&str) -> bool) -> Option<f64> {
+        let ColumnStats::MostCommon { rows, values, .. } = self else {
+            return None;
+        };
+        let listed: u64 = values.iter().map(|(_, count)| count).sum();
+        let kept: u64 = values
+            .iter()
+            .filter(|(v, _)| keeps(v))
+            .map(|(_, count)| count)
+            .sum();
+        Some((kept as f64 + (rows - listed) as f64 / 3.0) / *rows as f64)
+    }
+}
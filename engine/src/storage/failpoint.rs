@@ -0,0 +1,115 @@
+use std::io;
+
+// Named places on the write path where a test can make the engine fail, to
+// see what a failed write or a killed process leaves on disk. Without the
+// `failpoints` feature `hit` does nothing and there is nothing to arm.
+
+// Before a page is written to the data file, and before the file is synced.
+pub const PAGE_WRITE: &str = "pagefile.write";
+pub const PAGE_SYNC: &str = "pagefile.sync";
+// Before the buffer pool writes out its dirty pages.
+pub const POOL_FLUSH: &str = "buffer_pool.flush";
+// Before the log manager writes and syncs buffered records.
+pub const WAL_FLUSH: &str = "wal.flush";
+// Before a small file written whole is renamed over the old one.
+pub const ATOMIC_RENAME: &str = "atomic_file.rename";
+
+pub const ALL: [&str; 5] = [PAGE_WRITE, PAGE_SYNC, POOL_FLUSH, WAL_FLUSH, ATOMIC_RENAME];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // The one operation fails; the ones after it go ahead.
+    Error,
+    // The process dies: the operation fails, and so does every write after
+    // it at any point, until `reset`. Nothing reaches disk from then on.
+    Crash,
+}
+
+#[cfg(feature = "failpoints")]
+pub use armed::{arm, crashed, hits, reset};
+
+#[cfg(feature = "failpoints")]
+mod armed {
+    use super::Action;
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::sync::Mutex;
+
+    struct State {
+        // Operations left to let through at each armed point, and what
+        // happens to the one after them.
+        armed: BTreeMap<&'static str, (u64, Action)>,
+        hits: BTreeMap<String, u64>,
+        crashed: bool,
+    }
+
+    // One process, one set of points: tests that arm them cannot run
+    // alongside each other.
+    static STATE: Mutex<State> = Mutex::new(State {
+        armed: BTreeMap::new(),
+        hits: BTreeMap::new(),
+        crashed: false,
+    });
+
+    fn state() -> std::sync::MutexGuard<'static, State> {
+        STATE.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Lets `after` operations through `name`, then fails the next one.
+    pub fn arm(name: &'static str, after: u64, action: Action) {
+        state().armed.insert(name, (after, action));
+    }
+
+    // Disarms every point, forgets the counts and brings the process back
+    // to life.
+    pub fn reset() {
+        let mut state = state();
+        state.armed.clear();
+        state.hits.clear();
+        state.crashed = false;
+    }
+
+    pub fn crashed() -> bool {
+        state().crashed
+    }
+
+    // How many times `name` was reached since the last `reset`.
+    pub fn hits(name: &str) -> u64 {
+        state().hits.get(name).copied().unwrap_or(0)
+    }
+
+    pub(super) fn hit(name: &str) -> io::Result<()> {
+        let mut state = state();
+        *state.hits.entry(name.to_string()).or_default() += 1;
+        if state.crashed {
+            return Err(io::Error::other(format!("Simulated crash ({})", name)));
+        }
+        let Some((left, action)) = state.armed.get_mut(name) else {
+            return Ok(());
+        };
+        if *left > 0 {
+            *left -= 1;
+            return Ok(());
+        }
+        let action = *action;
+        state.armed.remove(name);
+        match action {
+            Action::Error => Err(io::Error::other(format!("Injected failure at {}", name))),
+            Action::Crash => {
+                state.crashed = true;
+                Err(io::Error::other(format!("Simulated crash at {}", name)))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "failpoints")]
+pub fn hit(name: &str) -> io::Result<()> {
+    armed::hit(name)
+}
+
+#[cfg(not(feature = "failpoints"))]
+#[inline(always)]
+pub fn hit(_name: &str) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result, anyhow, bail};
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+// Page 0 of a data file `mydb init` made says what the file is; page 1 is
+// kept back for a catalog in the file, and holds none: the catalog is
+// saved next to the WAL with each checkpoint, as it has to be for files
+// without a header too. Heap and index pages come after them, which also
+// keeps page 0, the B+tree's "no next leaf", from ever being a leaf.
+pub const HEADER_PAGE: u64 = 0;
+pub const CATALOG_PAGE: u64 = 1;
+
+const MAGIC: &[u8; 8] = b"MYDBDATA";
+const CATALOG_MAGIC: &[u8; 8] = b"MYDBCATL";
+pub const FORMAT_VERSION: u32 = 1;
+
+// Magic, version, page size and the catalog's first page.
+const HEADER_LEN: usize = 8 + 4 + 4 + 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    pub version: u32,
+    pub page_size: usize,
+    pub catalog_page: u64,
+}
+
+impl FileHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut page = vec![0; self.page_size];
+        page[..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut page[8..12], self.version);
+        LittleEndian::write_u32(&mut page[12..16], self.page_size as u32);
+        LittleEndian::write_u64(&mut page[16..24], self.catalog_page);
+        page
+    }
+
+    // The header of the data file at `path`: None when there is no file or
+    // it is empty, an error when it holds something else.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let Some(buf) = read_prefix(path)? else {
+            return Ok(None);
+        };
+        let header = Self::decode(&buf).ok_or_else(|| {
+            anyhow!(
+                "{:?} is not a mydb data file, or predates `mydb init`",
+                path
+            )
+        })?;
+        if header.version != FORMAT_VERSION {
+            bail!(
+                "{:?} has format version {}, this build reads version {}",
+                path,
+                header.version,
+                FORMAT_VERSION
+            );
+        }
+        Ok(Some(header))
+    }
+
+    // Like `read`, but takes a header of any version, and None also stands
+    // for a file without one, as those written before `mydb init` are.
+    pub fn read_any(path: &Path) -> Result<Option<Self>> {
+        Ok(read_prefix(path)?.and_then(|buf| Self::decode(&buf)))
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN || &buf[..8] != MAGIC {
+            return None;
+        }
+        Some(FileHeader {
+            version: LittleEndian::read_u32(&buf[8..12]),
+            page_size: LittleEndian::read_u32(&buf[12..16]) as usize,
+            catalog_page: LittleEndian::read_u64(&buf[16..24]),
+        })
+    }
+}
+
+// The first HEADER_LEN bytes of the file at `path`, fewer if it is shorter:
+// None when there is no file or it is empty.
+fn read_prefix(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {:?}", path)),
+    };
+    let mut buf = Vec::with_capacity(HEADER_LEN);
+    (&mut file)
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut buf)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    Ok((!buf.is_empty()).then_some(buf))
+}
+
+// Writes the header page and the page kept back after it to a new data
+// file, replacing whatever was at `path`.
+pub fn create_data_file(path: &Path, page_size: usize) -> Result<FileHeader> {
+    let header = FileHeader {
+        version: FORMAT_VERSION,
+        page_size,
+        catalog_page: CATALOG_PAGE,
+    };
+    // Only its magic, followed by a zero length.
+    let mut catalog = vec![0; page_size];
+    catalog[..8].copy_from_slice(CATALOG_MAGIC);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    file.write_all(&header.encode())?;
+    file.write_all(&catalog)?;
+    file.sync_all()
+        .with_context(|| format!("Failed to sync {:?}", path))?;
+    Ok(header)
+}
@@ -7,6 +7,14 @@ pub struct FreeList {
     free_map: HashMap<u64, usize>,
     
     pages: Vec<u64>,
+    // Deleted rows still on each registered page, for VACUUM to find them.
+    dead: HashMap<u64, usize>,
+}
+
+impl Default for FreeList {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FreeList {
@@ -15,6 +23,7 @@ impl FreeList {
         FreeList {
             free_map: HashMap::new(),
             pages: Vec::new(),
+            dead: HashMap::new(),
         }
     }
 
@@ -29,20 +38,47 @@ impl FreeList {
     
     pub fn remove(&mut self, page_no: u64) {
         self.free_map.remove(&page_no);
+        self.dead.remove(&page_no);
         if let Some(idx) = self.pages.iter().position(|&p| p == page_no) {
             self.pages.swap_remove(idx);
         }
     }
 
     
-    pub fn choose_page(&self, min_bytes: usize) -> Option<u64> {
-        for &page_no in &self.pages {
-            if let Some(&free) = self.free_map.get(&page_no) {
-                if free >= min_bytes {
-                    return Some(page_no);
-                }
-            }
+    // Every page with the free bytes it was last registered with.
+    pub fn entries(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.pages
+            .iter()
+            .filter_map(|page_no| Some((*page_no, *self.free_map.get(page_no)?)))
+    }
+
+    
+    // The pages registered with at least `min_bytes` free. What was
+    // registered may be out of date, so each is only worth trying.
+    pub fn candidates(&self, min_bytes: usize) -> Vec<u64> {
+        self.entries()
+            .filter(|&(_, free)| free >= min_bytes)
+            .map(|(page_no, _)| page_no)
+            .collect()
+    }
+
+    pub fn set_dead(&mut self, page_no: u64, rows: usize) {
+        if self.free_map.contains_key(&page_no) {
+            self.dead.insert(page_no, rows);
+        }
+    }
+
+    pub fn add_dead(&mut self, page_no: u64) {
+        if self.free_map.contains_key(&page_no) {
+            *self.dead.entry(page_no).or_default() += 1;
         }
-        None
+    }
+
+    // The deleted rows on a page, or None for a page the list does not
+    // have, which could hold any number.
+    pub fn dead_rows(&self, page_no: u64) -> Option<usize> {
+        self.free_map
+            .contains_key(&page_no)
+            .then(|| self.dead.get(&page_no).copied().unwrap_or(0))
     }
 }
@@ -0,0 +1,55 @@
+use crate::index::key::Key;
+use crate::storage::record::RID;
+use crate::tx::log_manager::TxId;
+use std::collections::{HashMap, HashSet};
+
+// How many rows a build reads under one hold of shared storage, letting
+// writers in between.
+pub const BACKFILL_CHUNK_ROWS: usize = 1024;
+
+// A CREATE INDEX that reads its table's keys while writers carry on. It is
+// not in the catalog, so nothing reads through it and an insert does not
+// add to it; instead every row the table gains or loses from the moment it
+// starts is noted in its side log. Finishing reads those rows again with
+// the table locked, so the keys read beforehand only stand for the rows
+// nothing touched since.
+#[derive(Debug)]
+pub struct IndexBuild {
+    pub tx_id: TxId,
+    pub table: String,
+    pub index: String,
+    keys: HashMap<RID, Key>,
+    changed: HashSet<RID>,
+}
+
+impl IndexBuild {
+    pub fn new(tx_id: TxId, table: &str, index: &str) -> Self {
+        IndexBuild {
+            tx_id,
+            table: table.to_string(),
+            index: index.to_string(),
+            keys: HashMap::new(),
+            changed: HashSet::new(),
+        }
+    }
+
+    pub fn is_for(&self, table: &str, index: &str) -> bool {
+        self.table == table && self.index == index
+    }
+
+    // A row added to or taken from the table.
+    pub fn note(&mut self, rid: RID) {
+        self.changed.insert(rid);
+    }
+
+    pub fn add_keys(&mut self, keys: impl IntoIterator<Item = (RID, Key)>) {
+        self.keys.extend(keys);
+    }
+
+    // The keys read for rows nothing has changed since; any other row of the
+    // table has to be read again.
+    pub fn finish(mut self) -> HashMap<RID, Key> {
+        self.keys.retain(|rid, _| !self.changed.contains(rid));
+        self.keys
+    }
+}
@@ -0,0 +1,130 @@
+use crate::query::value::Value;
+use crate::storage::atomic_file::atomic_read;
+use crate::storage::backup::{DATA_FILE, WAL_FILE, catalog_path};
+use crate::storage::format::{FORMAT_VERSION, FileHeader};
+use crate::storage::record::RID;
+use crate::storage::storage::{Catalog, Storage, decode_row};
+use crate::tx::recovery_manager::inspect_log;
+use anyhow::{Context, Result, anyhow, bail};
+use std::path::Path;
+
+// Databases written by earlier builds, read for `mydb migrate` to copy into
+// the current format. Whatever a format did differently is decoded here.
+// Nothing here writes: the data file is opened without a WAL and its pages
+// are only read.
+
+// Data files from before `mydb init` have no header page. They are read as
+// this version, with the page size the caller says they have.
+pub const HEADERLESS: u32 = 0;
+
+const POOL_PAGES: usize = 64;
+
+pub struct LegacyDatabase {
+    pub version: u32,
+    pub page_size: usize,
+    storage: Storage,
+}
+
+impl LegacyDatabase {
+    // Opens the data directory `dir`, laid out as a backup is. `page_size`
+    // is needed for a file without a header, and checked against one with.
+    // A database the log says needs recovery is refused: recovering it
+    // would write to it.
+    pub fn open(dir: &Path, page_size: Option<usize>) -> Result<Self> {
+        let data_file = dir.join(DATA_FILE);
+        let wal = dir.join(WAL_FILE);
+        if !data_file.is_file() {
+            bail!("No data file in {:?}", dir);
+        }
+        let (version, page_size) = match FileHeader::read_any(&data_file)? {
+            Some(header) if header.version > FORMAT_VERSION => bail!(
+                "{:?} has format version {}, newer than this build's {}",
+                data_file,
+                header.version,
+                FORMAT_VERSION
+            ),
+            Some(header) => {
+                if let Some(given) = page_size.filter(|&given| given != header.page_size) {
+                    bail!(
+                        "{:?} has {}-byte pages, not {}",
+                        data_file,
+                        header.page_size,
+                        given
+                    );
+                }
+                (header.version, header.page_size)
+            }
+            None => (HEADERLESS, page_size.unwrap_or(4096)),
+        };
+
+        let log = inspect_log(&wal)?;
+        if log.needs_recovery {
+            bail!(
+                "{:?} needs WAL recovery first; start a server on it and migrate a backup \
+                 taken with `mydb backup` instead",
+                dir
+            );
+        }
+        let mut storage = Storage::new(
+            data_file
+                .to_str()
+                .context("Data directory is not valid UTF-8")?,
+            page_size,
+            POOL_PAGES,
+        )?;
+        storage.catalog = read_catalog(&wal)?;
+        // Every transaction the log knows of has ended, so each one a row
+        // names is over, and a tombstone stands for a delete that committed.
+        storage.txns.advance_past(log.max_tx_id);
+        Ok(LegacyDatabase {
+            version,
+            page_size,
+            storage,
+        })
+    }
+
+    pub fn catalog(&self) -> &Catalog {
+        &self.storage.catalog
+    }
+
+    // The rows `table` lists, deleted ones included, in the order a scan
+    // returns them.
+    pub fn records(&self, table: &str) -> Result<Vec<RID>> {
+        Ok(self.storage.catalog.get_table(table)?.records.clone())
+    }
+
+    // The values of the row at `rid`, None if it was deleted.
+    pub fn row(&mut self, rid: RID) -> Result<Option<Vec<Value>>> {
+        let data = self.storage.fetch(rid)?;
+        if !self.storage.is_visible(&data) {
+            return Ok(None);
+        }
+        self.decode_row(&data)
+            .map(Some)
+            .with_context(|| format!("Failed to decode the row at {:?}", rid))
+    }
+
+    // Every version so far lays rows out as this build does; one that did
+    // not would get its own decoder here.
+    fn decode_row(&self, data: &[u8]) -> Result<Vec<Value>> {
+        match self.version {
+            HEADERLESS..=FORMAT_VERSION => decode_row(data),
+            version => bail!("No row decoder for format version {}", version),
+        }
+    }
+}
+
+// The data file does not keep the catalog, so it is read from the copy a
+// checkpoint or a backup leaves next to the WAL. Fields added since it was
+// written take their defaults.
+fn read_catalog(wal: &Path) -> Result<Catalog> {
+    let path = catalog_path(wal);
+    let bytes = atomic_read(&path)?.ok_or_else(|| {
+        anyhow!(
+            "No catalog at {:?}; the data file does not keep one, so migrate a backup \
+             taken with `mydb backup` or a database shut down cleanly",
+            path
+        )
+    })?;
+    serde_json::from_slice(&bytes).with_context(|| format!("Malformed catalog {:?}", path))
+}
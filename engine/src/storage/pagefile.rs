@@ -1,6 +1,7 @@
 
+use crate::storage::failpoint;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::path::Path;
 
 
@@ -10,6 +11,7 @@ use std::path::Path;
 pub struct PageFile {
     file: File,
     page_size: usize,
+    free_pages: Vec<u64>,
 }
 
 impl PageFile {
@@ -19,19 +21,36 @@ impl PageFile {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path)?;
-        Ok(PageFile { file, page_size })
+        Ok(PageFile {
+            file,
+            page_size,
+            free_pages: Vec::new(),
+        })
+    }
+
+    // Fails if the file has gone away or can no longer be written to.
+    pub fn check(&self) -> io::Result<()> {
+        if self.file.metadata()?.permissions().readonly() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Data file is read-only",
+            ));
+        }
+        Ok(())
     }
 
     
-    pub fn read_page(&mut self, page_no: u64) -> io::Result<Vec<u8>> {
+    // Reads at an offset instead of through the file cursor, so readers
+    // sharing the file do not need to take turns.
+    pub fn read_page(&self, page_no: u64) -> io::Result<Vec<u8>> {
         let offset = page_no
             .checked_mul(self.page_size as u64)
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Page number overflow"))?;
 
         let mut buf = vec![0u8; self.page_size];
-        self.file.seek(SeekFrom::Start(offset))?;
-        let n = self.file.read(&mut buf)?;
+        let n = read_at(&self.file, &mut buf, offset)?;
         if n != self.page_size {
             return Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
@@ -41,6 +60,31 @@ impl PageFile {
         Ok(buf)
     }
 
+    // `count` pages from `first` on, in one read. Fails like `read_page` if
+    // any of them is past the end of the file.
+    pub fn read_pages(&self, first: u64, count: usize) -> io::Result<Vec<Vec<u8>>> {
+        let offset = first
+            .checked_mul(self.page_size as u64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Page number overflow"))?;
+
+        let len = count * self.page_size;
+        let mut buf = vec![0u8; len];
+        let mut read = 0;
+        while read < len {
+            match read_at(&self.file, &mut buf[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if read != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Expected {} bytes, read {} bytes", len, read),
+            ));
+        }
+        Ok(buf.chunks(self.page_size).map(<[u8]>::to_vec).collect())
+    }
+
     
     
     pub fn write_page(&mut self, page_no: u64, buf: &[u8]) -> io::Result<()> {
@@ -59,6 +103,7 @@ impl PageFile {
             .checked_mul(self.page_size as u64)
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Page number overflow"))?;
 
+        failpoint::hit(failpoint::PAGE_WRITE)?;
         self.file.seek(SeekFrom::Start(offset))?;
         self.file.write_all(buf)?;
         self.file.sync_data()?; 
@@ -66,22 +111,55 @@ impl PageFile {
     }
 
     
-    pub fn num_pages(&mut self) -> io::Result<u64> {
+    pub fn num_pages(&self) -> io::Result<u64> {
         let metadata = self.file.metadata()?;
         let len = metadata.len();
-        Ok((len + self.page_size as u64 - 1) / self.page_size as u64)
+        Ok(len.div_ceil(self.page_size as u64))
     }
 
     
     pub fn allocate_page(&mut self) -> io::Result<u64> {
-        let new_page_no = self.num_pages()?;
+        let new_page_no = match self.free_pages.pop() {
+            Some(page_no) => page_no,
+            None => self.num_pages()?,
+        };
         let zero_buf = vec![0u8; self.page_size];
         self.write_page(new_page_no, &zero_buf)?;
         Ok(new_page_no)
     }
 
-    
+    // Grows the file with zeroed pages until it holds `page_no`. A page the
+    // log has changes for may never have reached the file before a crash.
+    pub fn extend_to(&mut self, page_no: u64) -> io::Result<()> {
+        let zero_buf = vec![0u8; self.page_size];
+        for new_page_no in self.num_pages()?..=page_no {
+            self.write_page(new_page_no, &zero_buf)?;
+        }
+        Ok(())
+    }
+
+    pub fn free_page(&mut self, page_no: u64) {
+        if !self.free_pages.contains(&page_no) {
+            self.free_pages.push(page_no);
+        }
+    }
+
+    pub fn free_page_count(&self) -> usize {
+        self.free_pages.len()
+    }
+
     pub fn sync_all(&self) -> io::Result<()> {
+        failpoint::hit(failpoint::PAGE_SYNC)?;
         self.file.sync_all()
     }
 }
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
@@ -1,6 +1,7 @@
 
+use crate::tx::log_manager::Lsn;
 use anyhow::{Result, anyhow};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::Cursor;
 
 pub type RID = (u64, u16);
@@ -11,8 +12,10 @@ pub struct Page {
 }
 
 impl Page {
-    const HEADER_SIZE: usize = 8 + 2 + 2; 
+    const HEADER_SIZE: usize = 8 + 2 + 2 + 8; 
     pub const SLOT_ENTRY_SIZE: usize = 2 + 2; 
+    // LSN of the last logged change applied to the page.
+    pub const LSN_RANGE: std::ops::Range<usize> = 12..20;
 
     pub fn new(page_id: u64, page_size: usize) -> Self {
         let mut data = vec![0; page_size];
@@ -38,17 +41,17 @@ impl Page {
         self.data
     }
 
-    fn page_id(&self) -> u64 {
+    pub fn page_id(&self) -> u64 {
         let mut rdr = Cursor::new(&self.data[0..8]);
         rdr.read_u64::<LittleEndian>().unwrap()
     }
 
-    fn slot_count(&self) -> u16 {
+    pub fn slot_count(&self) -> u16 {
         let mut rdr = Cursor::new(&self.data[8..10]);
         rdr.read_u16::<LittleEndian>().unwrap()
     }
 
-    fn free_space_off(&self) -> u16 {
+    pub fn free_space_off(&self) -> u16 {
         let mut rdr = Cursor::new(&self.data[10..12]);
         rdr.read_u16::<LittleEndian>().unwrap()
     }
@@ -65,6 +68,29 @@ impl Page {
             .unwrap();
     }
 
+    pub fn lsn_of(data: &[u8]) -> Lsn {
+        LittleEndian::read_u64(&data[Self::LSN_RANGE])
+    }
+
+    pub fn stamp_lsn(data: &mut [u8], lsn: Lsn) {
+        LittleEndian::write_u64(&mut data[Self::LSN_RANGE], lsn);
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.free_space_off() != 0
+    }
+
+    // Whether the header is that of heap page `page_no`, its slots and
+    // free space within the page. A page an index has taken over since
+    // fails this.
+    pub fn is_heap_page(&self, page_no: u64) -> bool {
+        let free_off = self.free_space_off() as usize;
+        self.page_id() == page_no
+            && self.is_initialized()
+            && free_off <= self.page_size
+            && self.payload_start() <= free_off
+    }
+
     pub fn slot_dir_offset(&self) -> usize {
         Self::HEADER_SIZE
     }
@@ -78,31 +104,59 @@ impl Page {
         free_off - self.payload_start()
     }
 
+    // The longest tuple an empty page has room for, with its slot entry.
+    pub fn max_tuple_size(page_size: usize) -> usize {
+        page_size - Self::HEADER_SIZE - Self::SLOT_ENTRY_SIZE
+    }
+
+    // A slot VACUUM emptied is taken again before a new one is added.
     pub fn insert_tuple(&mut self, tuple: &[u8]) -> Result<RID> {
         let tuple_len = tuple.len();
-        let needed = tuple_len + Self::SLOT_ENTRY_SIZE;
+        // The slot entry records the length in 16 bits, which must not wrap.
+        let recorded_len = u16::try_from(tuple_len)
+            .map_err(|_| anyhow!("A tuple of {} bytes is too long for a slot", tuple_len))?;
+        let reused = (0..self.slot_count()).find(|&slot| self.slot_entry(slot).unwrap().1 == 0);
+        let needed = match reused {
+            Some(_) => tuple_len,
+            None => tuple_len + Self::SLOT_ENTRY_SIZE,
+        };
         if needed > self.free_space() {
             return Err(anyhow!("Not enough free space"));
         }
         
         let free_off = self.free_space_off() as usize;
         let new_free_off = free_off - tuple_len;
+        let recorded_start = u16::try_from(new_free_off)
+            .map_err(|_| anyhow!("Offset {} is past what a slot can address", new_free_off))?;
         
         let start = new_free_off;
         let end = free_off;
         self.data[start..end].copy_from_slice(tuple);
         
-        let slot_no = self.slot_count();
+        let slot_no = reused.unwrap_or(self.slot_count());
         let entry_off = self.slot_dir_offset() + (slot_no as usize) * Self::SLOT_ENTRY_SIZE;
-        (&mut self.data[entry_off..entry_off + 2]).write_u16::<LittleEndian>(start as u16)?;
-        (&mut self.data[entry_off + 2..entry_off + 4])
-            .write_u16::<LittleEndian>(tuple_len as u16)?;
-        
-        self.set_slot_count(slot_no + 1);
+        (&mut self.data[entry_off..entry_off + 2]).write_u16::<LittleEndian>(recorded_start)?;
+        (&mut self.data[entry_off + 2..entry_off + 4]).write_u16::<LittleEndian>(recorded_len)?;
+
+        if reused.is_none() {
+            self.set_slot_count(slot_no + 1);
+        }
         self.set_free_space_off(new_free_off as u16);
         Ok((self.page_id(), slot_no))
     }
 
+    // Offset and length a slot records, unchecked against the page.
+    pub fn slot_entry(&self, slot_no: u16) -> Option<(usize, usize)> {
+        if slot_no >= self.slot_count() {
+            return None;
+        }
+        let entry_off = self.slot_dir_offset() + (slot_no as usize) * Self::SLOT_ENTRY_SIZE;
+        Some((
+            LittleEndian::read_u16(&self.data[entry_off..entry_off + 2]) as usize,
+            LittleEndian::read_u16(&self.data[entry_off + 2..entry_off + 4]) as usize,
+        ))
+    }
+
     pub fn get_tuple(&self, slot_no: u16) -> Option<&[u8]> {
         if slot_no >= self.slot_count() {
             return None;
@@ -114,6 +168,17 @@ impl Page {
         Some(&self.data[off..off + len])
     }
 
+    // The stored bytes of a tuple, for changes that keep its length.
+    pub fn tuple_mut(&mut self, slot_no: u16) -> Option<&mut [u8]> {
+        if slot_no >= self.slot_count() {
+            return None;
+        }
+        let entry_off = self.slot_dir_offset() + (slot_no as usize) * Self::SLOT_ENTRY_SIZE;
+        let off = LittleEndian::read_u16(&self.data[entry_off..entry_off + 2]) as usize;
+        let len = LittleEndian::read_u16(&self.data[entry_off + 2..entry_off + 4]) as usize;
+        Some(&mut self.data[off..off + len])
+    }
+
     pub fn delete_tuple(&mut self, slot_no: u16) -> Result<()> {
         if slot_no >= self.slot_count() {
             return Err(anyhow!("Invalid slot number"));
@@ -124,6 +189,31 @@ impl Page {
         Ok(())
     }
 
+    // Moves the tuples together at the end of the page, so what the
+    // deleted ones took is free space again. Every tuple keeps its slot;
+    // empty slots at the end of the directory go.
+    pub fn compact(&mut self) {
+        let tuples: Vec<(u16, Vec<u8>)> = self
+            .iter_slots()
+            .map(|(slot, tuple)| (slot, tuple.to_vec()))
+            .collect();
+        let count = tuples.last().map_or(0, |&(slot, _)| slot + 1);
+        let mut free_off = self.page_size;
+        let mut entries = vec![(0, 0); count as usize];
+        for (slot, tuple) in tuples {
+            free_off -= tuple.len();
+            self.data[free_off..free_off + tuple.len()].copy_from_slice(&tuple);
+            entries[slot as usize] = (free_off as u16, tuple.len() as u16);
+        }
+        for (slot, (off, len)) in entries.into_iter().enumerate() {
+            let entry_off = self.slot_dir_offset() + slot * Self::SLOT_ENTRY_SIZE;
+            LittleEndian::write_u16(&mut self.data[entry_off..entry_off + 2], off);
+            LittleEndian::write_u16(&mut self.data[entry_off + 2..entry_off + 4], len);
+        }
+        self.set_slot_count(count);
+        self.set_free_space_off(free_off as u16);
+    }
+
     pub fn iter_slots(&self) -> impl Iterator<Item = (u16, &[u8])> + '_ {
         (0..self.slot_count()).filter_map(move |slot_no| {
             if let Some(tuple_data) = self.get_tuple(slot_no) {
@@ -0,0 +1,178 @@
+use crate::tx::log_manager::{DdlPayload, LogManager, TxId};
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// How many values one WAL record reserves ahead of being handed out.
+pub const RESERVE_CHUNK: i64 = 32;
+
+// A counter NEXTVAL draws from. Values are reserved in the WAL a chunk at a
+// time before any of them is handed out, so after a crash the sequence
+// carries on past the last reservation: it may skip values, never repeat
+// one. The counter has a lock of its own and does not roll back with the
+// transaction that drew from it, so concurrent statements never see the
+// same value whatever tables they lock.
+//
+// Clones share the counter; the catalog keeps one and statements bound to
+// the sequence hold the others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SequenceInfo", into = "SequenceInfo")]
+pub struct Sequence {
+    pub name: String,
+    pub start: i64,
+    pub increment: i64,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // What the next value follows; None until the first one.
+    last: Option<i64>,
+    // Up to where the WAL covers values handed out.
+    reserved: Option<i64>,
+    // The last value this server handed out, for CURRVAL.
+    current: Option<i64>,
+}
+
+// A sequence as the WAL and a backup keep it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SequenceInfo {
+    pub name: String,
+    pub start: i64,
+    pub increment: i64,
+    #[serde(default)]
+    pub reserved: Option<i64>,
+}
+
+impl From<SequenceInfo> for Sequence {
+    // Values up to the reservation may have been handed out before it was
+    // written, so the sequence goes on after it.
+    fn from(info: SequenceInfo) -> Self {
+        Sequence {
+            name: info.name,
+            start: info.start,
+            increment: info.increment,
+            state: Arc::new(Mutex::new(State {
+                last: info.reserved,
+                reserved: info.reserved,
+                current: None,
+            })),
+        }
+    }
+}
+
+impl From<Sequence> for SequenceInfo {
+    fn from(sequence: Sequence) -> Self {
+        sequence.info()
+    }
+}
+
+impl Sequence {
+    pub fn new(name: String, start: i64, increment: i64) -> Result<Self> {
+        if increment == 0 {
+            bail!("Sequence '{}' cannot have an increment of 0", name);
+        }
+        Ok(SequenceInfo {
+            name,
+            start,
+            increment,
+            reserved: None,
+        }
+        .into())
+    }
+
+    pub fn info(&self) -> SequenceInfo {
+        SequenceInfo {
+            name: self.name.clone(),
+            start: self.start,
+            increment: self.increment,
+            reserved: self.state.lock().unwrap().reserved,
+        }
+    }
+
+    // Hands out the next value. When it is past the reservation, `reserve`
+    // is called with the end of a new chunk first, and nothing is handed
+    // out unless that succeeds.
+    pub fn next(&self, reserve: impl FnOnce(i64) -> Result<()>) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let value = match state.last {
+            None => Some(self.start),
+            Some(last) => last.checked_add(self.increment),
+        }
+        .ok_or_else(|| anyhow!("Sequence '{}' has run out of values", self.name))?;
+        if !state.reserved.is_some_and(|end| self.covers(end, value)) {
+            let end = value.saturating_add(self.increment.saturating_mul(RESERVE_CHUNK - 1));
+            reserve(end)?;
+            state.reserved = Some(end);
+        }
+        state.last = Some(value);
+        state.current = Some(value);
+        Ok(value)
+    }
+
+    // The value NEXTVAL last handed out on this server.
+    pub fn current(&self) -> Result<i64> {
+        self.state.lock().unwrap().current.ok_or_else(|| {
+            anyhow!(
+                "CURRVAL of '{}' is not known until NEXTVAL has handed out a value",
+                self.name
+            )
+        })
+    }
+
+    // Takes in a reservation read back from the WAL.
+    pub fn reserved_to(&self, end: i64) {
+        let mut state = self.state.lock().unwrap();
+        if !state.reserved.is_some_and(|r| self.covers(r, end)) {
+            state.reserved = Some(end);
+            state.last = Some(end);
+        }
+    }
+
+    // Whether a reservation up to `end` includes `value`.
+    fn covers(&self, end: i64, value: i64) -> bool {
+        match self.increment > 0 {
+            true => value <= end,
+            false => value >= end,
+        }
+    }
+}
+
+// NEXTVAL bound to its sequence and to the transaction whose log records
+// the reservations it makes.
+#[derive(Clone)]
+pub struct NextVal {
+    pub sequence: Sequence,
+    log: Option<(Arc<LogManager>, TxId)>,
+}
+
+impl fmt::Debug for NextVal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NextVal({})", self.sequence.name)
+    }
+}
+
+impl NextVal {
+    pub fn new(sequence: Sequence, log: Option<(Arc<LogManager>, TxId)>) -> Self {
+        NextVal { sequence, log }
+    }
+
+    // A reservation is flushed before any of its values leaves, since a
+    // value can be seen before its transaction commits.
+    pub fn next(&self) -> Result<i64> {
+        self.sequence.next(|end| {
+            let Some((wal, tx_id)) = &self.log else {
+                return Ok(());
+            };
+            let lsn = wal.log_ddl(
+                *tx_id,
+                &DdlPayload::ReserveSequence {
+                    name: self.sequence.name.clone(),
+                    end,
+                },
+            )?;
+            wal.flush(lsn)
+        })
+    }
+}
@@ -1,49 +1,435 @@
-
-
-use crate::index::node_serializer::{LeafNodeSerializer, NodeHeader, NodeType};
+use crate::index::bloom::BloomStats;
+use crate::index::bplustree::{self, BPlusTree};
+use crate::index::hash_index::HashIndex;
+use crate::index::key::{self, Key};
+use crate::index::node_serializer::IndexKey;
+use crate::query::binder::{Value, bind_check};
+use crate::query::executor::eval_predicate;
+use crate::query::memory::DEFAULT_WORK_MEM;
+use crate::query::random::Random;
+pub use crate::query::value::Collation;
 use crate::storage::buffer_pool::BufferPool;
+use crate::storage::column_stats::ColumnStats;
 use crate::storage::free_list::FreeList;
+use crate::storage::index_build::IndexBuild;
 use crate::storage::pagefile::PageFile;
 use crate::storage::record::{Page as RecordPage, RID};
-use anyhow::{Result, anyhow};
-use std::collections::HashMap;
+use crate::storage::sequence::Sequence;
+use crate::tx::lock_manager::{IsolationLevel, LockManager, LockMode, Resource};
+use crate::tx::log_manager::{DdlPayload, LogManager, Lsn, TxId, UpdatePayload};
+use crate::tx::mvcc::{ROW_HEADER_SIZE, RowHeader, Snapshot, TxStatus, TxStatusTable};
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IndexInfo {
     pub name: String,
     pub table: String,
     pub column: String,
+    // The columns after `column` of an index on several, in key order.
+    #[serde(default)]
+    pub more_columns: Vec<String>,
     pub order: usize,
     pub root_page: u64,
+    pub kind: IndexKind,
+    pub bloom_page: Option<u64>,
+}
+
+impl IndexInfo {
+    pub fn columns(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.column).chain(&self.more_columns)
+    }
+
+    // Where the key's columns are in the rows of `table`, in key order.
+    pub fn key_ordinals(&self, table: &TableInfo) -> Result<Vec<usize>> {
+        self.columns()
+            .map(|name| {
+                table
+                    .columns
+                    .iter()
+                    .position(|c| c.name.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| anyhow!("Unknown column '{}' in '{}'", name, table.name))
+            })
+            .collect()
+    }
+
+    // How `table` declares the key's columns, in key order.
+    pub fn key_columns(&self, table: &TableInfo) -> Result<Vec<ColumnInfo>> {
+        Ok(self
+            .key_ordinals(table)?
+            .into_iter()
+            .map(|ordinal| table.columns[ordinal].clone())
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IndexKind {
+    #[default]
+    BTree,
+    Hash,
 }
 
+impl IndexKind {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "BTREE" => Ok(IndexKind::BTree),
+            "HASH" => Ok(IndexKind::Hash),
+            other => Err(anyhow!("Unknown index method '{}'", other)),
+        }
+    }
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ColumnInfo {
     pub name: String,
     pub data_type: DataType,
+    // Only ever NOCASE on a TEXT column.
+    #[serde(default)]
+    pub collation: Collation,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DataType {
     Int,
     String,
 }
 
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TableInfo {
     pub name: String,
     pub columns: Vec<ColumnInfo>,
+    // Every row version in the heap, in the order they were added, which is
+    // the order a sequential scan returns them in. Tables share heap pages,
+    // so this is not page order: a row may go on an earlier page with room.
+    // Whatever changes the list keeps that order.
     pub records: Vec<RID>,
+    #[serde(default)]
+    pub stats: TableStats,
+    // By column name, as the last ANALYZE left them; empty before one.
+    #[serde(default)]
+    pub column_stats: BTreeMap<String, ColumnStats>,
+    #[serde(default)]
+    pub foreign_keys: Vec<ForeignKey>,
+    #[serde(default)]
+    pub checks: Vec<CheckConstraint>,
+    // The catalog's schema version when the table's definition last
+    // changed. A plan made before then is stale.
+    #[serde(default)]
+    pub version: u64,
+    // Rows gone in or out since the last ANALYZE, and when that ran, in
+    // milliseconds since the Unix epoch; None before the first one.
+    #[serde(default)]
+    pub churn: Churn,
+    #[serde(default)]
+    pub analyzed_at: Option<u64>,
+}
+
+// A condition every row of a table has to meet, kept as written and bound
+// against the table's columns when a row is checked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckConstraint {
+    pub name: String,
+    pub condition: String,
+}
+
+// `user_id INT REFERENCES users(id)`: every value of `column` has to be
+// found in `parent_column` of `parent`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForeignKey {
+    pub name: String,
+    pub column: String,
+    pub parent: String,
+    pub parent_column: String,
+    #[serde(default)]
+    pub on_delete: OnDelete,
+}
+
+// What deleting a row of the parent does to the rows referring to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnDelete {
+    // The delete fails while any are left.
+    #[default]
+    Restrict,
+    // They are deleted with it.
+    Cascade,
+}
+
+impl fmt::Display for OnDelete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OnDelete::Restrict => "RESTRICT",
+            OnDelete::Cascade => "CASCADE",
+        })
+    }
+}
+
+// The code a violation carries in the server's JSON body.
+pub const FOREIGN_KEY_VIOLATION: &str = "FOREIGN_KEY_VIOLATION";
+
+// What a statement fails with when a row would break a foreign key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignKeyViolation {
+    pub constraint: String,
+    // The table whose row was being inserted or deleted.
+    pub table: String,
+    pub message: String,
+}
+
+impl fmt::Display for ForeignKeyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ForeignKeyViolation {}
+
+// A row inserted under SET CONSTRAINTS ALL DEFERRED whose foreign key found
+// no parent row, to be looked at again before the transaction commits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeferredCheck {
+    pub table: String,
+    pub rid: RID,
+    pub constraint: String,
+}
+
+// The code a stale plan's error carries in the server's JSON body.
+pub const SCHEMA_CHANGED: &str = "SCHEMA_CHANGED";
+
+// What a statement fails with when a table it was planned against changed
+// before it ran. Nothing was done, so it can be run again as it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaChanged {
+    pub table: String,
+}
+
+impl fmt::Display for SchemaChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Table '{}' changed after the statement was planned; run it again",
+            self.table
+        )
+    }
+}
+
+impl std::error::Error for SchemaChanged {}
+
+// A named SELECT, kept as written and bound again each time it is read
+// through, so it sees the tables under it as they are then.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ViewInfo {
+    pub name: String,
+    // What it selects from: a table or another view.
+    pub table: String,
+    pub query: String,
+}
+
+// Counted as rows come and go rather than by a scan, so they are always at
+// hand. Deleting only stamps a row, so a deleted version counts until VACUUM
+// takes it out of the heap; ANALYZE recounts from the pages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub rows: u64,
+    // Bytes the rows take in the heap, headers included.
+    pub bytes: u64,
+    // Heap pages holding the table's rows, with how many each holds.
+    pub pages: BTreeMap<u64, u64>,
+    // Of `rows`, those a transaction that has not rolled back deleted.
+    #[serde(default)]
+    pub dead_rows: u64,
+}
+
+impl TableStats {
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn add(&mut self, rid: RID, bytes: usize) {
+        self.rows += 1;
+        self.bytes += bytes as u64;
+        *self.pages.entry(rid.0).or_default() += 1;
+    }
+
+    // Adds the stored row `data`, dead or alive.
+    pub fn add_stored(&mut self, rid: RID, data: &[u8], txns: &TxStatusTable) {
+        self.add(rid, data.len());
+        if RowHeader::is_tombstoned(data)
+            && txns.status(RowHeader::read(data).xmax) != TxStatus::Aborted
+        {
+            self.dead_rows += 1;
+        }
+    }
+}
+
+// Rows inserted and deleted, by transactions that have not rolled back, for
+// the server to tell how far a table has drifted from the statistics the
+// last ANALYZE left. A table's rows all count as inserted until its first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Churn {
+    pub inserted: u64,
+    pub deleted: u64,
+}
+
+impl Churn {
+    pub fn total(&self) -> u64 {
+        self.inserted + self.deleted
+    }
+}
+
+// What VACUUM did to a table: the deleted rows whose slots it took back,
+// the pages it compacted doing so, and the pages with such rows it left
+// because a transaction still open had changed them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VacuumReport {
+    pub rows: usize,
+    pub pages: usize,
+    pub skipped: usize,
+}
+
+// What GRANT hands out. ALL covers the other two as well as changing the
+// table: DELETE, DROP TABLE, CREATE INDEX, REINDEX, ANALYZE and VACUUM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Privilege {
+    Select,
+    Insert,
+    All,
+}
+
+impl Privilege {
+    // The privileges granting this one hands out.
+    fn implied(self) -> &'static [Privilege] {
+        match self {
+            Privilege::Select => &[Privilege::Select],
+            Privilege::Insert => &[Privilege::Insert],
+            Privilege::All => &[Privilege::Select, Privilege::Insert, Privilege::All],
+        }
+    }
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Privilege::Select => "SELECT",
+            Privilege::Insert => "INSERT",
+            Privilege::All => "ALL",
+        })
+    }
+}
+
+// What one user has been granted on one table: privileges on the whole of
+// it, and SELECT or INSERT on single columns.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grants {
+    pub table: BTreeSet<Privilege>,
+    pub columns: BTreeMap<String, BTreeSet<Privilege>>,
+}
+
+impl Grants {
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty() && self.columns.is_empty()
+    }
+
+    // Whether `privilege` on `columns` was granted, on the whole table or
+    // on each of them.
+    pub fn allows(&self, privilege: Privilege, columns: &[String]) -> bool {
+        self.table.contains(&privilege)
+            || (!columns.is_empty()
+                && columns.iter().all(|column| {
+                    self.columns
+                        .get(column)
+                        .is_some_and(|granted| granted.contains(&privilege))
+                }))
+    }
+
+    // Grants `privilege` on the whole table when `columns` is empty.
+    pub fn grant(&mut self, privilege: Privilege, columns: &[String]) {
+        if columns.is_empty() {
+            self.table.extend(privilege.implied());
+        }
+        for column in columns {
+            let granted = self.columns.entry(column.clone()).or_default();
+            granted.extend(privilege.implied());
+        }
+    }
+
+    // Revoking from the whole table takes the privilege off its columns as
+    // well. Whoever loses SELECT or INSERT no longer has ALL.
+    pub fn revoke(&mut self, privilege: Privilege, columns: &[String]) {
+        let revoke = |granted: &mut BTreeSet<Privilege>| {
+            for p in privilege.implied() {
+                granted.remove(p);
+            }
+            granted.remove(&Privilege::All);
+        };
+        if columns.is_empty() {
+            revoke(&mut self.table);
+            self.columns.values_mut().for_each(revoke);
+        } else {
+            for column in columns {
+                if let Some(granted) = self.columns.get_mut(column) {
+                    revoke(granted);
+                }
+            }
+        }
+        self.columns.retain(|_, granted| !granted.is_empty());
+    }
+}
+
+// Columns a table may have unless `Storage::max_columns` says otherwise.
+pub const DEFAULT_MAX_COLUMNS: usize = 1024;
+
+// The schema a name without one is made in, which is always there. Its
+// tables and views are keyed in the catalog by their bare names; those of
+// any other schema as `<schema>.<name>`.
+pub const DEFAULT_SCHEMA: &str = "PUBLIC";
+
+// The catalog key of `name` in `schema`.
+pub fn qualified_name(schema: &str, name: &str) -> String {
+    match schema == DEFAULT_SCHEMA {
+        true => name.to_string(),
+        false => format!("{}.{}", schema, name),
+    }
 }
 
+// The schema a catalog key is in, and the name within it.
+pub fn split_name(key: &str) -> (&str, &str) {
+    key.split_once('.').unwrap_or((DEFAULT_SCHEMA, key))
+}
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Catalog {
     pub tables: HashMap<String, TableInfo>,
     pub indexes: HashMap<String, Vec<IndexInfo>>,
+    // By table, then by user.
+    #[serde(default)]
+    pub grants: HashMap<String, BTreeMap<String, Grants>>,
+    #[serde(default)]
+    pub sequences: BTreeMap<String, Sequence>,
+    #[serde(default)]
+    pub views: BTreeMap<String, ViewInfo>,
+    // Those made with CREATE SCHEMA; the default one is not listed.
+    #[serde(default)]
+    pub schemas: BTreeSet<String>,
+    // The temporary tables of the session whose statement is running. The
+    // session keeps them between statements, so they are never saved or
+    // logged with the rest, and no other session sees them. They hide a
+    // table of the same name.
+    #[serde(skip)]
+    pub temp: HashMap<String, TableInfo>,
+    // Goes up with every change to a table's definition, so no two versions
+    // of any table share a number, not even across DROP and CREATE.
+    #[serde(default)]
+    pub schema_version: u64,
+    // Where in the log the checkpoint it was saved at starts. The catalog is
+    // as the log stood there, so recovery redoes catalog changes from there.
+    #[serde(default)]
+    pub checkpoint_offset: Option<u64>,
 }
 
 impl Catalog {
@@ -51,9 +437,34 @@ impl Catalog {
         Catalog {
             tables: HashMap::new(),
             indexes: HashMap::new(),
+            grants: HashMap::new(),
+            sequences: BTreeMap::new(),
+            views: BTreeMap::new(),
+            schemas: BTreeSet::new(),
+            temp: HashMap::new(),
+            schema_version: 0,
+            checkpoint_offset: None,
         }
     }
 
+    // NEXTVAL and CURRVAL name a sequence in a string, which keeps its case,
+    // so the name is matched without it.
+    pub fn get_sequence(&self, name: &str) -> Result<&Sequence> {
+        self.sequences
+            .values()
+            .find(|s| s.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("Sequence '{}' not found", name))
+    }
+
+    pub fn has_schema(&self, name: &str) -> bool {
+        name == DEFAULT_SCHEMA || self.schemas.contains(name)
+    }
+
+    // What `user` was granted on `table`, if anything.
+    pub fn grants(&self, table: &str, user: &str) -> Option<&Grants> {
+        self.grants.get(table).and_then(|users| users.get(user))
+    }
+
     pub fn create_table(&mut self, name: String, columns: Vec<ColumnInfo>) -> Result<()> {
         if self.tables.contains_key(&name) {
             return Err(anyhow!("Table '{}' already exists", name));
@@ -62,21 +473,51 @@ impl Catalog {
             name: name.clone(),
             columns,
             records: Vec::new(),
+            stats: TableStats::default(),
+            column_stats: BTreeMap::new(),
+            foreign_keys: Vec::new(),
+            checks: Vec::new(),
+            version: self.next_version(),
+            churn: Churn::default(),
+            analyzed_at: None,
         };
         self.tables.insert(name, table);
         Ok(())
     }
 
+    fn next_version(&mut self) -> u64 {
+        self.schema_version += 1;
+        self.schema_version
+    }
+
+    // Marks a change to the definition of `name`, which plans made against
+    // it do not know about.
+    pub fn touch(&mut self, name: &str) {
+        let version = self.next_version();
+        if let Ok(table) = self.get_table_mut(name) {
+            table.version = version;
+        }
+    }
+
     pub fn get_table(&self, name: &str) -> Result<&TableInfo> {
-        self.tables
+        self.temp
             .get(name)
+            .or_else(|| self.tables.get(name))
             .ok_or_else(|| anyhow!("Table '{}' not found", name))
     }
 
     pub fn get_table_mut(&mut self, name: &str) -> Result<&mut TableInfo> {
-        self.tables
-            .get_mut(name)
-            .ok_or_else(|| anyhow!("Table '{}' not found", name))
+        match self.temp.get_mut(name) {
+            Some(table) => Ok(table),
+            None => self
+                .tables
+                .get_mut(name)
+                .ok_or_else(|| anyhow!("Table '{}' not found", name)),
+        }
+    }
+
+    pub fn is_temp(&self, name: &str) -> bool {
+        self.temp.contains_key(name)
     }
 
     pub fn create_index(
@@ -86,32 +527,187 @@ impl Catalog {
         index_name: String,
         order: usize,
         root_page: u64,
+        kind: IndexKind,
     ) {
         let info = IndexInfo {
             name: index_name,
             table: table.clone(),
             column,
+            more_columns: Vec::new(),
             order,
             root_page,
+            kind,
+            bloom_page: None,
         };
+        self.touch(&table);
         self.indexes.entry(table).or_default().push(info);
     }
 
+    // A temporary table has none; those under its name are another table's.
     pub fn get_indexes(&self, table: &str) -> Vec<IndexInfo> {
+        if self.is_temp(table) {
+            return Vec::new();
+        }
         self.indexes.get(table).cloned().unwrap_or_default()
     }
+
+    pub fn set_index_root(&mut self, table: &str, index_name: &str, root_page: u64) {
+        if let Some(idx) = self
+            .indexes
+            .get_mut(table)
+            .and_then(|v| v.iter_mut().find(|i| i.name == index_name))
+        {
+            idx.root_page = root_page;
+        }
+    }
+
+    pub fn set_index_bloom(&mut self, table: &str, index_name: &str, bloom_page: Option<u64>) {
+        if let Some(idx) = self
+            .indexes
+            .get_mut(table)
+            .and_then(|v| v.iter_mut().find(|i| i.name == index_name))
+        {
+            idx.bloom_page = bloom_page;
+        }
+    }
+}
+
+// What a statement fails with once its cancel flag is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("statement was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// What a sequential scan does with a heap page that cannot be read or fails
+// the checks CHECK makes of it: fail the statement, or pass over the page's
+// rows, log it and count it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScanErrorPolicy {
+    #[default]
+    Strict,
+    SkipPage,
 }
 
+// What a read-only statement scans storage through. The storage is shared
+// with other readers, so the transaction, snapshot and cancel flag a writer
+// sets on `Storage` itself travel here instead.
+#[derive(Clone)]
+pub struct ReadView<'a> {
+    pub storage: &'a Storage,
+    pub tx_id: Option<TxId>,
+    pub snapshot: Option<Snapshot>,
+    pub cancel: Option<Arc<AtomicBool>>,
+    pub isolation: IsolationLevel,
+    pub scan_errors: ScanErrorPolicy,
+    // The pages the statement's scans have passed over.
+    pub skipped_pages: Arc<AtomicU64>,
+}
+
+impl<'a> ReadView<'a> {
+    // Reads as whatever transaction `storage` is currently set up for.
+    pub fn of(storage: &'a Storage) -> Self {
+        ReadView {
+            storage,
+            tx_id: storage.tx_id,
+            snapshot: storage.snapshot.clone(),
+            cancel: storage.cancel.clone(),
+            isolation: storage.isolation,
+            scan_errors: storage.scan_errors,
+            skipped_pages: storage.skipped_pages.clone(),
+        }
+    }
+
+    pub fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn lock_row_for_read(&self, table: &str, rid: RID) -> Result<()> {
+        if self.isolation == IsolationLevel::RepeatableRead {
+            self.storage
+                .lock_row_for(self.tx_id, table, rid, LockMode::Shared)?;
+        }
+        Ok(())
+    }
+
+    pub fn fetch_visible(&self, rid: RID) -> Result<Option<Vec<u8>>> {
+        let (page_no, slot) = rid;
+        let page = self.read_page(page_no)?;
+        Ok(self.visible_in(&page, slot)?.map(<[u8]>::to_vec))
+    }
+
+    // A copy of a heap page, which a scan reads every row on it from
+    // rather than copying it again for each.
+    pub fn read_page(&self, page_no: u64) -> Result<RecordPage> {
+        let data = self.storage.buffer_pool.read_page(page_no)?;
+        Ok(RecordPage::from_bytes(data, self.storage.page_size))
+    }
+
+    // The row in `slot` of `page`, if the snapshot sees it.
+    pub fn visible_in<'p>(&self, page: &'p RecordPage, slot: u16) -> Result<Option<&'p [u8]>> {
+        self.storage.heap_fetches.fetch_add(1, Ordering::Relaxed);
+        let rec = page.get_tuple(slot).ok_or_else(|| anyhow!("Not found"))?;
+        let visible = match &self.snapshot {
+            _ if rec.len() < ROW_HEADER_SIZE => false,
+            Some(snapshot) => snapshot.is_visible(&RowHeader::read(rec)),
+            None => {
+                !RowHeader::is_tombstoned(rec)
+                    || !self.storage.txns.is_deleted(&RowHeader::read(rec))
+            }
+        };
+        Ok(visible.then_some(rec))
+    }
+}
 
 pub struct Storage {
     pub buffer_pool: BufferPool,
     pub free_list: FreeList,
     pub page_size: usize,
     pub catalog: Catalog,
+    pub heap_fetches: AtomicU64,
+    pub bloom_stats: HashMap<u64, BloomStats>,
+    pub wal: Option<Arc<LogManager>>,
+    pub tx_id: Option<TxId>,
+    pub pending_rows: Vec<(String, RID)>,
+    // The transaction's deferred foreign key checks once it has SET
+    // CONSTRAINTS ALL DEFERRED; None while each row is checked as it goes in.
+    pub deferred: Option<Vec<DeferredCheck>>,
+    pub locks: Option<Arc<LockManager>>,
+    pub isolation: IsolationLevel,
+    pub txns: Arc<TxStatusTable>,
+    pub snapshot: Option<Snapshot>,
+    pub cancel: Option<Arc<AtomicBool>>,
+    // RANDOM()'s generator, lent by the session whose statement runs.
+    pub random: Random,
+    // The memory that statement's sorts, aggregates and joins may hold.
+    pub work_mem: usize,
+    // That session's variables, lent the same way.
+    pub variables: HashMap<String, Value>,
+    pub scan_errors: ScanErrorPolicy,
+    pub skipped_pages: Arc<AtomicU64>,
+    // The most columns CREATE TABLE takes.
+    pub max_columns: usize,
+    // CREATE INDEXes reading their tables while writers carry on.
+    index_builds: Vec<IndexBuild>,
 }
 
 impl Storage {
     pub fn new(path: &str, page_size: usize, pool_size: usize) -> Result<Self> {
+        // Offsets within a page are kept in 16 bits.
+        if u16::try_from(page_size).is_err() {
+            return Err(anyhow!(
+                "A page of {} bytes is larger than its slots can address",
+                page_size
+            ));
+        }
         let pf = PageFile::open(path, page_size)?;
         let bp = BufferPool::new(pf, pool_size)?;
         let fl = FreeList::new();
@@ -120,170 +716,2330 @@ impl Storage {
             free_list: fl,
             page_size,
             catalog: Catalog::new(),
+            heap_fetches: AtomicU64::new(0),
+            bloom_stats: HashMap::new(),
+            wal: None,
+            tx_id: None,
+            pending_rows: Vec::new(),
+            deferred: None,
+            locks: None,
+            isolation: IsolationLevel::default(),
+            txns: Arc::new(TxStatusTable::new()),
+            snapshot: None,
+            cancel: None,
+            random: Random::new(),
+            work_mem: DEFAULT_WORK_MEM,
+            variables: HashMap::new(),
+            scan_errors: ScanErrorPolicy::default(),
+            skipped_pages: Arc::default(),
+            max_columns: DEFAULT_MAX_COLUMNS,
+            index_builds: Vec::new(),
         })
     }
 
-    
-    pub fn insert(&mut self, data: &[u8]) -> Result<RID> {
-        let needed = data.len() + RecordPage::SLOT_ENTRY_SIZE;
-        let page_no = if let Some(pn) = self.free_list.choose_page(needed) {
-            pn
-        } else {
-            let pn = self.buffer_pool.pagefile.allocate_page()?;
-            let page = RecordPage::new(pn, self.page_size);
-            self.free_list.register(pn, page.free_space());
-            pn
-        };
+    pub fn attach_wal(&mut self, wal: Arc<LogManager>) {
+        self.buffer_pool.wal = Some(wal.clone());
+        self.wal = Some(wal);
+    }
 
-        let frame = self.buffer_pool.fetch_page(page_no)?;
-        let mut page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
-        let rid = page.insert_tuple(data)?;
-        frame.data = page.to_bytes();
-        let free = RecordPage::from_bytes(frame.data.clone(), self.page_size).free_space();
-        self.buffer_pool.unpin_page(page_no, true);
-        self.free_list.register(page_no, free);
-        Ok(rid)
+    pub fn attach_locks(&mut self, locks: Arc<LockManager>) {
+        self.locks = Some(locks);
     }
 
-    
-    pub fn insert_row(
-        &mut self,
-        table_name: &str,
-        columns: &[String],
-        values: Vec<crate::query::binder::Value>,
+    // Row locks are only taken inside a transaction with a lock manager
+    // attached; they are released by the transaction's `unlock_all`. The
+    // table's intention lock comes first, so DDL waiting for the whole table
+    // sees the row lock.
+    pub fn lock_row(&self, table: &str, rid: RID, mode: LockMode) -> Result<()> {
+        self.lock_row_for(self.tx_id, table, rid, mode)
+    }
+
+    pub fn lock_row_for_read(&self, table: &str, rid: RID) -> Result<()> {
+        if self.isolation == IsolationLevel::RepeatableRead {
+            self.lock_row(table, rid, LockMode::Shared)?;
+        }
+        Ok(())
+    }
+
+    fn lock_row_for(
+        &self,
+        tx_id: Option<TxId>,
+        table: &str,
+        rid: RID,
+        mode: LockMode,
     ) -> Result<()> {
-        let _ = self.catalog.get_table(table_name)?;
-        if columns.len() != values.len() {
-            return Err(anyhow!("Column/value count mismatch"));
+        if let (Some(locks), Some(tx_id)) = (&self.locks, tx_id) {
+            locks.try_lock(tx_id, Resource::Table(table.to_string()), mode.intention())?;
+            locks.try_lock(tx_id, Resource::Row(table.to_string(), rid), mode)?;
         }
-        let row_data = self.serialize_row(&values)?;
-        let rid = self.insert(&row_data)?;
-        let table = self.catalog.get_table_mut(table_name)?;
-        table.records.push(rid);
         Ok(())
     }
 
-    
-    pub fn scan_table(
-        &mut self,
-        table_name: &str,
-    ) -> Result<Vec<Vec<crate::query::binder::Value>>> {
-        let rids = self.catalog.get_table(table_name)?.records.clone();
-        let mut rows = Vec::new();
-        for rid in rids {
-            let raw = self.fetch(rid)?;
-            let vals = self.deserialize_row(&raw)?;
-            rows.push(vals);
+    pub fn set_transaction(&mut self, tx_id: Option<TxId>) {
+        self.tx_id = tx_id;
+        self.pending_rows.clear();
+        self.deferred = None;
+        self.snapshot = None;
+    }
+
+    // Called by operators between rows, so a cancelled statement stops
+    // within a row of the flag being set.
+    pub fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(Cancelled.into()),
+            _ => Ok(()),
         }
-        Ok(rows)
     }
 
-    pub fn create_table(&mut self, name: String, cols: Vec<ColumnInfo>) -> Result<()> {
-        self.catalog.create_table(name, cols)
+    // Scans only filter rows by visibility once a snapshot is taken; without
+    // one they return every row in the heap whose deletion has not
+    // committed.
+    pub fn take_snapshot(&mut self) {
+        self.snapshot = Some(self.txns.snapshot(self.tx_id));
     }
 
-    fn serialize_row(&self, values: &[crate::query::binder::Value]) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
-        buf.extend_from_slice(&(values.len() as u32).to_le_bytes());
-        for v in values {
-            match v {
-                crate::query::binder::Value::Int(i) => {
-                    buf.push(0);
-                    buf.extend_from_slice(&i.to_le_bytes());
-                }
-                crate::query::binder::Value::String(s) => {
-                    buf.push(1);
-                    let b = s.as_bytes();
-                    buf.extend_from_slice(&(b.len() as u32).to_le_bytes());
-                    buf.extend_from_slice(b);
-                }
+    pub fn is_visible(&self, data: &[u8]) -> bool {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.is_visible(&RowHeader::read(data)),
+            None => {
+                !RowHeader::is_tombstoned(data) || !self.txns.is_deleted(&RowHeader::read(data))
             }
         }
-        Ok(buf)
     }
 
-    fn deserialize_row(&self, data: &[u8]) -> Result<Vec<crate::query::binder::Value>> {
-        let mut cursor = 0;
-        if data.len() < 4 {
-            return Err(anyhow!("Invalid row data"));
-        }
-        let count = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
-        cursor += 4;
-        let mut vals = Vec::with_capacity(count);
-        for _ in 0..count {
-            let tag = data[cursor];
-            cursor += 1;
-            match tag {
-                0 => {
-                    let i = i64::from_le_bytes(data[cursor..cursor + 8].try_into().unwrap());
-                    vals.push(crate::query::binder::Value::Int(i));
-                    cursor += 8;
-                }
-                1 => {
-                    let len =
-                        u32::from_le_bytes(data[cursor..cursor + 4].try_into().unwrap()) as usize;
-                    cursor += 4;
-                    let s = String::from_utf8(data[cursor..cursor + len].to_vec())?;
-                    vals.push(crate::query::binder::Value::String(s));
-                    cursor += len;
-                }
-                _ => return Err(anyhow!("Invalid tag")),
-            }
+    pub fn fetch_visible(&mut self, rid: RID) -> Result<Option<Vec<u8>>> {
+        let data = self.fetch(rid)?;
+        Ok(self.is_visible(&data).then_some(data))
+    }
+
+    // Deleting only stamps the row with the deleting transaction; the version
+    // stays in the heap for snapshots that still see it.
+    pub fn delete_row(&mut self, table_name: &str, rid: RID) -> Result<()> {
+        let tx_id = self
+            .tx_id
+            .ok_or_else(|| anyhow!("Deleting a row needs a transaction"))?;
+        if !self.catalog.get_table(table_name)?.records.contains(&rid) {
+            return Err(anyhow!("Row {:?} is not in '{}'", rid, table_name));
         }
-        Ok(vals)
+        self.lock_row(table_name, rid, LockMode::Exclusive)?;
+
+        let row = self.stamp_deleted(table_name, rid, tx_id)?;
+        self.delete_referring(table_name, &row)
     }
 
-    pub fn fetch(&mut self, rid: RID) -> Result<Vec<u8>> {
+    // Stamps the row with the deleting transaction and returns its values.
+    fn stamp_deleted(&mut self, table_name: &str, rid: RID, tx_id: TxId) -> Result<Vec<Value>> {
         let (page_no, slot) = rid;
         let frame = self.buffer_pool.fetch_page(page_no)?;
-        let page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
-        let rec = page.get_tuple(slot).ok_or_else(|| anyhow!("Not found"))?;
+        let mut page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
         self.buffer_pool.unpin_page(page_no, false);
-        Ok(rec.to_vec())
+        let tuple = page
+            .tuple_mut(slot)
+            .filter(|t| t.len() >= ROW_HEADER_SIZE)
+            .ok_or_else(|| anyhow!("Not found"))?;
+        let mut header = RowHeader::read(tuple);
+        if header.tombstone && self.txns.status(header.xmax) != TxStatus::Aborted {
+            return Err(anyhow!(
+                "Row {:?} was already deleted by transaction {}",
+                rid,
+                header.xmax
+            ));
+        }
+        let row = self.deserialize_row(tuple)?;
+        header.xmax = tx_id;
+        header.tombstone = true;
+        header.write(tuple);
+        let temp = self.catalog.is_temp(table_name);
+        match temp {
+            true => {
+                self.write_unlogged_page(page_no, page.to_bytes())?;
+                self.txns.wrote_unlogged(tx_id);
+            }
+            false => self.write_heap_page(page_no, page.to_bytes())?,
+        }
+        self.free_list.add_dead(page_no);
+        let table = self.catalog.get_table_mut(table_name)?;
+        table.stats.dead_rows += 1;
+        table.churn.deleted += 1;
+        // A rollback puts the row back as it was, and the count with it.
+        if !temp {
+            self.pending_rows.push((table_name.to_string(), rid));
+        }
+        Ok(row)
     }
 
-    pub fn flush(&mut self) -> Result<()> {
-        self.buffer_pool.flush_all()?;
+    // Deals with the rows whose foreign keys refer to a row just deleted
+    // from `table`, as each key says. The row is already stamped, so one
+    // that refers to itself is not found again.
+    fn delete_referring(&mut self, table: &str, row: &[Value]) -> Result<()> {
+        if self.catalog.is_temp(table) {
+            return Ok(());
+        }
+        for (child, key) in self.referring_keys(table) {
+            let value = &row[self.column_ordinal(table, &key.parent_column)?];
+            let rids = self.rows_with(&child, &key.column, value)?;
+            if rids.is_empty() {
+                continue;
+            }
+            match key.on_delete {
+                OnDelete::Restrict => {
+                    return Err(ForeignKeyViolation {
+                        message: format!(
+                            "Deleting from '{}' breaks foreign key '{}': {} row(s) of '{}' refer to {} {}",
+                            table,
+                            key.name,
+                            rids.len(),
+                            child,
+                            key.parent_column,
+                            value
+                        ),
+                        constraint: key.name,
+                        table: table.to_string(),
+                    }
+                    .into());
+                }
+                OnDelete::Cascade => {
+                    for rid in rids {
+                        self.delete_row(&child, rid)?;
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
-    
-    pub fn create_index(
-        &mut self,
-        table_name: &str,
-        column: &str,
-        index_name: &str,
-        order: usize,
-    ) -> Result<u64> {
-        self.catalog.get_table(table_name)?;
-        let root = self.buffer_pool.pagefile.allocate_page()?;
+    // A row of `table`, in column order, has to meet each of its CHECK
+    // constraints.
+    fn check_conditions(&self, table: &str, row: &[Value]) -> Result<()> {
+        let info = self.catalog.get_table(table)?;
+        for check in &info.checks {
+            let condition = bind_check(info, &check.condition)?;
+            if !eval_predicate(&condition, &row.to_vec())? {
+                return Err(anyhow!(
+                    "Row breaks CHECK constraint '{}' of '{}': {}",
+                    check.name,
+                    table,
+                    check.condition
+                ));
+            }
+        }
+        Ok(())
+    }
 
-        let hdr = NodeHeader {
-            node_type: NodeType::Leaf,
-            key_count: 0,
-            parent: 0,
-        };
-        let buf = LeafNodeSerializer { order }.serialize(&hdr, &[], &[], 0, self.page_size);
+    // Every value a new row of `table` gives a foreign key column has to be
+    // in the parent. The parent row found is locked shared until the
+    // transaction ends, so no other can delete it meanwhile. While checks
+    // are deferred, a key whose parent is missing is returned for the caller
+    // to queue with the row's RID instead of failing the row.
+    fn check_references(&mut self, table: &str, row: &[Value]) -> Result<Vec<String>> {
+        let keys = self.catalog.get_table(table)?.foreign_keys.clone();
+        let mut missing = Vec::new();
+        for key in keys {
+            let value = &row[self.column_ordinal(table, &key.column)?];
+            let found = self.rows_with(&key.parent, &key.parent_column, value)?;
+            match found.first() {
+                Some(&rid) => self.lock_row(&key.parent, rid, LockMode::Shared)?,
+                None if self.deferred.is_some() => missing.push(key.name),
+                None => return Err(missing_parent(table, &key, value).into()),
+            }
+        }
+        Ok(missing)
+    }
 
-        {
-            let frame = self.buffer_pool.fetch_page(root)?;
-            frame.data.copy_from_slice(&buf);
-            self.buffer_pool.unpin_page(root, true);
+    fn defer_checks(&mut self, table: &str, rid: RID, constraints: Vec<String>) {
+        if let Some(deferred) = &mut self.deferred {
+            deferred.extend(constraints.into_iter().map(|constraint| DeferredCheck {
+                table: table.to_string(),
+                rid,
+                constraint,
+            }));
         }
-        let free = self.page_size.saturating_sub(buf.len());
-        self.free_list.register(root, free);
+    }
 
-        self.catalog.create_index(
-            table_name.to_string(),
-            column.to_string(),
-            index_name.to_string(),
-            order,
-            root,
-        );
-        Ok(root)
+    // SET CONSTRAINTS: ALL DEFERRED starts queueing the checks of rows whose
+    // parent is missing, ALL IMMEDIATE checks what was queued and stops.
+    pub fn set_constraints(&mut self, deferred: bool) -> Result<()> {
+        if self.tx_id.is_none() {
+            return Err(anyhow!(
+                "SET CONSTRAINTS can only run inside a transaction block"
+            ));
+        }
+        match deferred {
+            true => {
+                self.deferred.get_or_insert_with(Vec::new);
+                Ok(())
+            }
+            false => self.check_deferred(),
+        }
     }
 
-    pub fn get_indexes(&self, table: &str) -> Vec<IndexInfo> {
-        self.catalog.get_indexes(table)
+    // Looks again at each deferred check, as COMMIT has to before it is
+    // logged, and fails with all the rows whose parent is still missing.
+    // A row since deleted by the transaction needs no parent. Parents found
+    // are locked shared, as they would have been when the row went in.
+    pub fn check_deferred(&mut self) -> Result<()> {
+        let Some(checks) = self.deferred.take() else {
+            return Ok(());
+        };
+        let mut violations = Vec::new();
+        for check in checks {
+            let info = self.catalog.get_table(&check.table)?;
+            let Some(key) = info
+                .foreign_keys
+                .iter()
+                .find(|key| key.name == check.constraint)
+                .cloned()
+            else {
+                continue;
+            };
+            let ordinal = self.column_ordinal(&check.table, &key.column)?;
+            let data = self.fetch(check.rid)?;
+            if !self.is_current(&data) {
+                continue;
+            }
+            let value = decode_column(&data, ordinal)?;
+            let found = self.rows_with(&key.parent, &key.parent_column, &value)?;
+            match found.first() {
+                Some(&rid) => self.lock_row(&key.parent, rid, LockMode::Shared)?,
+                None => violations.push(missing_parent(&check.table, &key, &value)),
+            }
+        }
+        let Some(first) = violations.first() else {
+            return Ok(());
+        };
+        let messages: Vec<_> = violations.iter().map(|v| v.message.as_str()).collect();
+        Err(ForeignKeyViolation {
+            message: format!(
+                "{} deferred foreign key check(s) failed: {}",
+                violations.len(),
+                messages.join("; ")
+            ),
+            constraint: first.constraint.clone(),
+            table: first.table.clone(),
+        }
+        .into())
     }
+
+    // The foreign keys of other tables, or of `table` itself, that refer to
+    // `table`, with the table each belongs to.
+    fn referring_keys(&self, table: &str) -> Vec<(String, ForeignKey)> {
+        let mut keys: Vec<_> = self
+            .catalog
+            .tables
+            .values()
+            .flat_map(|t| t.foreign_keys.iter().map(move |key| (&t.name, key)))
+            .filter(|(_, key)| key.parent == table)
+            .map(|(child, key)| (child.clone(), key.clone()))
+            .collect();
+        keys.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        keys
+    }
+
+    // The rows of `table` whose `column` holds `value`, looked up through an
+    // index on the column when there is one. Only rows a constraint has to
+    // count are kept: see `is_current`.
+    fn rows_with(&mut self, table: &str, column: &str, value: &Value) -> Result<Vec<RID>> {
+        if self.catalog.is_temp(table) {
+            return Err(anyhow!(
+                "'{}' is hidden by a temporary table, so its foreign keys cannot be checked",
+                table
+            ));
+        }
+        let ordinal = self.column_ordinal(table, column)?;
+        let index = self
+            .catalog
+            .get_indexes(table)
+            .into_iter()
+            .find(|idx| idx.more_columns.is_empty() && idx.column.eq_ignore_ascii_case(column));
+        let declared = self.catalog.get_table(table)?.columns[ordinal].clone();
+        let key = Key::of(&[declared], std::slice::from_ref(value));
+        let candidates = match (index, key) {
+            (Some(idx), Some(key)) => self.index_lookup(&idx, key)?,
+            _ => self.catalog.get_table(table)?.records.clone(),
+        };
+        let mut found = Vec::new();
+        for rid in candidates {
+            let data = self.fetch(rid)?;
+            if self.is_current(&data) && decode_column(&data, ordinal)? == *value {
+                found.push(rid);
+            }
+        }
+        Ok(found)
+    }
+
+    // Whether a constraint has to count the row, whatever the statement's
+    // snapshot: it was inserted by this transaction or a committed one, and
+    // no transaction but an aborted one has deleted it. A delete still in
+    // progress elsewhere leaves the row counted; locking it then conflicts
+    // with that transaction.
+    fn is_current(&self, data: &[u8]) -> bool {
+        let header = RowHeader::read(data);
+        let own = |tx| self.tx_id == Some(tx);
+        let inserted = own(header.xmin) || self.txns.status(header.xmin) == TxStatus::Committed;
+        let deleted = header.tombstone
+            && (own(header.xmax) || self.txns.status(header.xmax) == TxStatus::Committed);
+        inserted && !deleted
+    }
+
+    // Puts back the before image of `update`. `lsn` is the compensation
+    // record logged for it, so redo can tell whether the page has it.
+    // A page past the end of the file was lost with the rest of a crash
+    // before it was written; the before image goes on a zeroed one.
+    pub fn undo_update(&mut self, update: &UpdatePayload, lsn: Lsn) -> Result<()> {
+        self.buffer_pool.pagefile.extend_to(update.page_no)?;
+        let start = update.offset as usize;
+        let end = start + update.before.len();
+        let frame = self.buffer_pool.fetch_page(update.page_no)?;
+        if end > frame.data.len() {
+            self.buffer_pool.unpin_page(update.page_no, false);
+            return Err(anyhow!(
+                "Before image {}..{} is outside page {}",
+                start,
+                end,
+                update.page_no
+            ));
+        }
+        frame.data[start..end].copy_from_slice(&update.before);
+        RecordPage::stamp_lsn(&mut frame.data, lsn);
+        let page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
+        self.buffer_pool.unpin_page(update.page_no, true);
+        self.buffer_pool.set_page_lsn(update.page_no, lsn);
+        self.sync_free_space(update.page_no, &page);
+        Ok(())
+    }
+
+    // Puts the after image of a change a primary logged at `lsn` on the page,
+    // unless the page already has it. The primary allocates pages without
+    // logging them, so a page past the end of the file starts out zeroed.
+    // Returns whether the page changed.
+    pub fn redo_update(&mut self, update: &UpdatePayload, lsn: Lsn) -> Result<bool> {
+        self.buffer_pool.pagefile.extend_to(update.page_no)?;
+        let start = update.offset as usize;
+        let end = start + update.after.len();
+        let frame = self.buffer_pool.fetch_page(update.page_no)?;
+        if end > frame.data.len() {
+            self.buffer_pool.unpin_page(update.page_no, false);
+            return Err(anyhow!(
+                "After image {}..{} is outside page {}",
+                start,
+                end,
+                update.page_no
+            ));
+        }
+        if RecordPage::lsn_of(&frame.data) >= lsn {
+            self.buffer_pool.unpin_page(update.page_no, false);
+            return Ok(false);
+        }
+        frame.data[start..end].copy_from_slice(&update.after);
+        RecordPage::stamp_lsn(&mut frame.data, lsn);
+        // The change is already in the primary's log; nothing in this
+        // server's log has to reach disk before the page does.
+        self.buffer_pool.unpin_page(update.page_no, true);
+        Ok(true)
+    }
+
+    // Heap pages are restored from the WAL, but the catalog's row lists and
+    // the indexes are not logged, so drop the undone rows from the former and
+    // rebuild the latter from what is left in the heap. A row the
+    // transaction deleted is back on its page; one it inserted is gone.
+    pub fn discard_pending_rows(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending_rows);
+        let mut tables: Vec<String> = Vec::new();
+        // A row listed twice was inserted and then deleted.
+        let mut gone = HashSet::new();
+        for (table, rid) in pending {
+            let stored = self
+                .read_tuple(rid, |rec| Ok(rec.len() >= ROW_HEADER_SIZE))
+                .unwrap_or(false);
+            if let Ok(info) = self.catalog.get_table_mut(&table) {
+                let churn = &mut info.churn;
+                if stored || !gone.insert(rid) {
+                    churn.deleted = churn.deleted.saturating_sub(1);
+                } else {
+                    churn.inserted = churn.inserted.saturating_sub(1);
+                    info.records.retain(|r| *r != rid);
+                }
+            }
+            self.note_row(&table, rid);
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+        for table in tables {
+            // What the undone rows took is no longer on their pages.
+            let stats = self.count_rows(&table)?;
+            self.catalog.get_table_mut(&table)?.stats = stats;
+            for index in self.catalog.get_indexes(&table) {
+                self.reindex(&table, &index.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    // After a recovery that redid or undid anything: the row lists came back
+    // from the log without the sizes the stats are counted from, and index
+    // pages are not logged at all, so both are worked out from the heap.
+    pub fn rebuild_after_recovery(&mut self) -> Result<()> {
+        let tables: Vec<String> = self.catalog.tables.keys().cloned().collect();
+        for table in tables {
+            let stats = self.count_rows(&table)?;
+            self.catalog.get_table_mut(&table)?.stats = stats;
+            for index in self.catalog.get_indexes(&table) {
+                self.reindex(&table, &index.name)?;
+            }
+        }
+        self.flush()?;
+        Ok(())
+    }
+
+    // Counts the table's rows from its heap pages, as its stats should have
+    // them.
+    pub fn count_rows(&self, table_name: &str) -> Result<TableStats> {
+        let mut stats = TableStats::default();
+        // Rows come mostly in page order, so only the last page is kept.
+        let mut last: Option<(u64, RecordPage)> = None;
+        for &rid in &self.catalog.get_table(table_name)?.records {
+            if last.as_ref().is_none_or(|(page_no, _)| *page_no != rid.0) {
+                let data = self.buffer_pool.read_page(rid.0)?;
+                last = Some((rid.0, RecordPage::from_bytes(data, self.page_size)));
+            }
+            let (_, page) = last.as_ref().unwrap();
+            let data = page
+                .get_tuple(rid.1)
+                .ok_or_else(|| anyhow!("Row {:?} of '{}' not found", rid, table_name))?;
+            stats.add_stored(rid, data, &self.txns);
+        }
+        Ok(stats)
+    }
+    
+    // Each page the free list offers is tried in turn, and a new page is
+    // allocated once none has room after all.
+    pub fn insert(&mut self, data: &[u8]) -> Result<RID> {
+        let needed = data.len() + RecordPage::SLOT_ENTRY_SIZE;
+        for page_no in self.free_list.candidates(needed) {
+            if let Some(rid) = self.insert_into(page_no, data)? {
+                return Ok(rid);
+            }
+        }
+        let page_no = self.buffer_pool.pagefile.allocate_page()?;
+        let page = RecordPage::new(page_no, self.page_size);
+        self.free_list.register(page_no, page.free_space());
+        self.write_heap_page(page_no, page.to_bytes())?;
+        self.insert_into(page_no, data)?
+            .ok_or_else(|| anyhow!("A row of {} bytes does not fit on a page", data.len()))
+    }
+
+    // Puts the row on `page_no` if the page itself has room, whatever the
+    // free list said. A page fetched dirty may have changed since its entry
+    // was registered, and one without room has less than it says, so
+    // either has its entry set from the page header.
+    fn insert_into(&mut self, page_no: u64, data: &[u8]) -> Result<Option<RID>> {
+        let frame = self.buffer_pool.fetch_page(page_no)?;
+        let dirty = frame.is_dirty;
+        let mut page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
+        self.buffer_pool.unpin_page(page_no, false);
+        let fits = page.is_heap_page(page_no)
+            && page.free_space() >= data.len() + RecordPage::SLOT_ENTRY_SIZE;
+        if dirty || !fits {
+            self.sync_free_space(page_no, &page);
+        }
+        if !fits {
+            return Ok(None);
+        }
+        let rid = page.insert_tuple(data)?;
+        let free = page.free_space();
+        self.write_heap_page(page_no, page.to_bytes())?;
+        self.free_list.register(page_no, free);
+        Ok(Some(rid))
+    }
+
+    // Sets the free list's entry for a page from its header and the rows
+    // on it. A page that is not a heap page, or no longer one, is taken off
+    // the list.
+    fn sync_free_space(&mut self, page_no: u64, page: &RecordPage) {
+        match page.is_heap_page(page_no) {
+            true => {
+                self.free_list.register(page_no, page.free_space());
+                let dead = page
+                    .iter_slots()
+                    .filter(|(_, tuple)| RowHeader::is_tombstoned(tuple))
+                    .count();
+                self.free_list.set_dead(page_no, dead);
+            }
+            false => self.free_list.remove(page_no),
+        }
+    }
+
+    // Heap pages are logged physically: every changed byte range becomes an
+    // update record carrying its before and after image. The page LSN itself
+    // is never part of an image; it is stamped once the records exist.
+    fn write_heap_page(&mut self, page_no: u64, mut data: Vec<u8>) -> Result<()> {
+        let frame = self.buffer_pool.fetch_page(page_no)?;
+        let mut last_lsn = None;
+        if let (Some(wal), Some(tx_id)) = (&self.wal, self.tx_id) {
+            let lsn_range = RecordPage::LSN_RANGE;
+            let mut ranges =
+                changed_ranges(&frame.data[..lsn_range.start], &data[..lsn_range.start]);
+            ranges.extend(
+                changed_ranges(&frame.data[lsn_range.end..], &data[lsn_range.end..])
+                    .into_iter()
+                    .map(|(start, end)| (start + lsn_range.end, end + lsn_range.end)),
+            );
+            for (start, end) in ranges {
+                let update = UpdatePayload {
+                    page_no,
+                    offset: start as u32,
+                    before: frame.data[start..end].to_vec(),
+                    after: data[start..end].to_vec(),
+                };
+                match wal.log_update(tx_id, update.encode()) {
+                    Ok(lsn) => last_lsn = Some(lsn),
+                    Err(e) => {
+                        self.buffer_pool.unpin_page(page_no, false);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        if let Some(lsn) = last_lsn {
+            RecordPage::stamp_lsn(&mut data, lsn);
+        }
+        frame.data = data;
+        self.buffer_pool.unpin_page(page_no, true);
+        if let Some(lsn) = last_lsn {
+            self.buffer_pool.set_page_lsn(page_no, lsn);
+        }
+        Ok(())
+    }
+    
+    // A temporary table's rows are not logged, so they are not undone
+    // either: one its transaction rolls back stays in the heap, stamped with
+    // a transaction no snapshot sees.
+    // A temporary table's rows go on pages of its own, which the free list
+    // never hands out, so no one else's rows are written next to them
+    // unlogged and the pages can be given back whole with the table.
+    fn insert_temp(&mut self, table_name: &str, data: &[u8]) -> Result<RID> {
+        let last = self
+            .catalog
+            .get_table(table_name)?
+            .stats
+            .pages
+            .keys()
+            .next_back()
+            .copied();
+        if let Some(tx_id) = self.tx_id {
+            self.txns.wrote_unlogged(tx_id);
+        }
+        if let Some(page_no) = last {
+            let frame = self.buffer_pool.fetch_page(page_no)?;
+            let mut page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
+            self.buffer_pool.unpin_page(page_no, false);
+            if let Ok(rid) = page.insert_tuple(data) {
+                self.write_unlogged_page(page_no, page.to_bytes())?;
+                return Ok(rid);
+            }
+        }
+        let page_no = self.buffer_pool.pagefile.allocate_page()?;
+        let mut page = RecordPage::new(page_no, self.page_size);
+        let rid = page.insert_tuple(data)?;
+        self.write_unlogged_page(page_no, page.to_bytes())?;
+        Ok(rid)
+    }
+
+    fn write_unlogged_page(&mut self, page_no: u64, data: Vec<u8>) -> Result<()> {
+        let frame = self.buffer_pool.fetch_page(page_no)?;
+        frame.data = data;
+        self.buffer_pool.unpin_page(page_no, true);
+        Ok(())
+    }
+
+    pub fn insert_row(
+        &mut self,
+        table_name: &str,
+        columns: &[String],
+        values: Vec<Value>,
+    ) -> Result<RID> {
+        if columns.len() != values.len() {
+            return Err(anyhow!("Column/value count mismatch"));
+        }
+        let values = self.in_column_order(table_name, columns, values)?;
+        self.check_conditions(table_name, &values)?;
+        let missing = self.check_references(table_name, &values)?;
+        let row_data = self.serialize_row(&values)?;
+        let temp = self.catalog.is_temp(table_name);
+        let rid = match temp {
+            true => self.insert_temp(table_name, &row_data)?,
+            false => self.insert(&row_data)?,
+        };
+        if !temp {
+            self.log_ddl(&DdlPayload::AddRows {
+                table: table_name.to_string(),
+                rows: vec![rid],
+            })?;
+        }
+        let table = self.catalog.get_table_mut(table_name)?;
+        table.records.push(rid);
+        table.stats.add(rid, row_data.len());
+        table.churn.inserted += 1;
+        self.note_row(table_name, rid);
+        if self.tx_id.is_some() && !temp {
+            self.pending_rows.push((table_name.to_string(), rid));
+        }
+        self.defer_checks(table_name, rid, missing);
+        self.insert_index_entries(table_name, &values, rid)?;
+        Ok(rid)
+    }
+
+    // Adds many rows at once, for COPY, to a table the caller has locked
+    // whole. Rows are checked as `insert_row` checks them but packed into
+    // fresh pages, each written and logged once when it is full, and the
+    // table's indexes are built again from scratch at the end instead of
+    // taking the rows one by one. Returns how many rows went in.
+    pub fn bulk_insert(
+        &mut self,
+        table_name: &str,
+        columns: &[String],
+        rows: impl IntoIterator<Item = Result<Vec<Value>>>,
+    ) -> Result<usize> {
+        let table = self.catalog.get_table(table_name)?;
+        // A table referring to itself looks rows up through its indexes,
+        // which would not have the ones before; a temporary table is not
+        // logged in the first place.
+        let self_referring = table.foreign_keys.iter().any(|k| k.parent == table.name);
+        if self_referring || self.catalog.is_temp(table_name) {
+            let mut count = 0;
+            for row in rows {
+                self.check_cancelled()?;
+                self.insert_row(table_name, columns, row?)
+                    .with_context(|| format!("Row {} failed", count + 1))?;
+                count += 1;
+            }
+            return Ok(count);
+        }
+
+        let mut page: Option<RecordPage> = None;
+        let mut on_page = Vec::new();
+        let mut count = 0;
+        for row in rows {
+            self.check_cancelled()?;
+            let (data, missing) = self
+                .checked_row(table_name, columns, row?)
+                .with_context(|| format!("Row {} failed", count + 1))?;
+            let needed = data.len() + RecordPage::SLOT_ENTRY_SIZE;
+            if page.as_ref().is_none_or(|p| needed > p.free_space()) {
+                if let Some(full) = page.take() {
+                    self.write_bulk_page(table_name, full, &mut on_page)?;
+                }
+                let page_no = self.buffer_pool.pagefile.allocate_page()?;
+                page = Some(RecordPage::new(page_no, self.page_size));
+            }
+            let rid = page.as_mut().unwrap().insert_tuple(&data)?;
+            on_page.push((rid, data.len()));
+            self.defer_checks(table_name, rid, missing);
+            count += 1;
+        }
+        if let Some(last) = page {
+            self.write_bulk_page(table_name, last, &mut on_page)?;
+        }
+        for index in self.catalog.get_indexes(table_name) {
+            self.reindex(table_name, &index.name)?;
+        }
+        Ok(count)
+    }
+
+    // A row as `insert_row` would store it, once it has passed its checks,
+    // with the foreign keys whose checks were deferred.
+    fn checked_row(
+        &mut self,
+        table_name: &str,
+        columns: &[String],
+        values: Vec<Value>,
+    ) -> Result<(Vec<u8>, Vec<String>)> {
+        if columns.len() != values.len() {
+            return Err(anyhow!("Column/value count mismatch"));
+        }
+        let values = self.in_column_order(table_name, columns, values)?;
+        self.check_conditions(table_name, &values)?;
+        let missing = self.check_references(table_name, &values)?;
+        Ok((self.serialize_row(&values)?, missing))
+    }
+
+    // Writes a page `bulk_insert` has filled and adds its rows to the
+    // table.
+    fn write_bulk_page(
+        &mut self,
+        table_name: &str,
+        page: RecordPage,
+        rows: &mut Vec<(RID, usize)>,
+    ) -> Result<()> {
+        let page_no = page.page_id();
+        let free = page.free_space();
+        self.write_heap_page(page_no, page.to_bytes())?;
+        self.free_list.register(page_no, free);
+        self.log_ddl(&DdlPayload::AddRows {
+            table: table_name.to_string(),
+            rows: rows.iter().map(|&(rid, _)| rid).collect(),
+        })?;
+        let table = self.catalog.get_table_mut(table_name)?;
+        for &(rid, len) in rows.iter() {
+            table.records.push(rid);
+            table.stats.add(rid, len);
+        }
+        table.churn.inserted += rows.len() as u64;
+        for &(rid, _) in rows.iter() {
+            self.note_row(table_name, rid);
+        }
+        if self.tx_id.is_some() {
+            let table = table_name.to_string();
+            let rids = rows.iter().map(|&(rid, _)| (table.clone(), rid));
+            self.pending_rows.extend(rids);
+        }
+        rows.clear();
+        Ok(())
+    }
+
+    // Rows are stored in the order the table declares its columns, whatever
+    // order `columns` names them in. Nothing can be NULL and there are no
+    // defaults, so every column needs a value.
+    fn in_column_order(
+        &self,
+        table_name: &str,
+        columns: &[String],
+        values: Vec<Value>,
+    ) -> Result<Vec<Value>> {
+        let declared = &self.catalog.get_table(table_name)?.columns;
+        let mut row: Vec<Option<Value>> = vec![None; declared.len()];
+        for (name, value) in columns.iter().zip(values) {
+            let ordinal = declared
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow!("Unknown column '{}' in '{}'", name, table_name))?;
+            if row[ordinal].replace(value).is_some() {
+                return Err(anyhow!("Column '{}' is given two values", name));
+            }
+        }
+        row.into_iter()
+            .zip(declared)
+            .map(|(value, column)| {
+                value.ok_or_else(|| {
+                    anyhow!("No value for column '{}' of '{}'", column.name, table_name)
+                })
+            })
+            .collect()
+    }
+
+    fn insert_index_entries(&mut self, table_name: &str, row: &[Value], rid: RID) -> Result<()> {
+        for idx in self.catalog.get_indexes(table_name) {
+            let key = self
+                .key_layout(table_name, idx.columns())?
+                .of_row(row)
+                .ok_or_else(|| unkeyable(&idx.name))?;
+            let root = match (idx.kind, key) {
+                (IndexKind::Hash, key) => {
+                    let key = key.int().ok_or_else(|| unkeyable(&idx.name))?;
+                    HashIndex::open(self, &idx).insert(key as u64, rid)?;
+                    continue;
+                }
+                (IndexKind::BTree, Key::Int(key)) => {
+                    let mut tree = BPlusTree::<i64>::open(self, &idx);
+                    tree.insert(key, rid)?;
+                    tree.root_page()
+                }
+                (IndexKind::BTree, Key::Bytes(key)) => {
+                    let mut tree = BPlusTree::<Vec<u8>>::open(self, &idx);
+                    tree.insert(key, rid)?;
+                    tree.root_page()
+                }
+            };
+            if root != idx.root_page {
+                self.catalog.set_index_root(table_name, &idx.name, root);
+            }
+        }
+        Ok(())
+    }
+
+    // The rows `key` points at in the index.
+    fn index_lookup(&mut self, idx: &IndexInfo, key: Key) -> Result<Vec<RID>> {
+        let entries: Vec<RID> = match (idx.kind, key) {
+            (IndexKind::Hash, key) => {
+                let key = key.int().ok_or_else(|| unkeyable(&idx.name))?;
+                return HashIndex::open(self, idx).get(key as u64);
+            }
+            (IndexKind::BTree, Key::Int(key)) => BPlusTree::<i64>::open(self, idx)
+                .range_scan_keys(key, key)?
+                .into_iter()
+                .map(|(_, rid)| rid)
+                .collect(),
+            (IndexKind::BTree, Key::Bytes(key)) => BPlusTree::<Vec<u8>>::open(self, idx)
+                .range_scan_keys(key.clone(), key)?
+                .into_iter()
+                .map(|(_, rid)| rid)
+                .collect(),
+        };
+        Ok(entries)
+    }
+
+    fn column_ordinal(&self, table_name: &str, column: &str) -> Result<usize> {
+        self.catalog
+            .get_table(table_name)?
+            .columns
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(column))
+            .ok_or_else(|| anyhow!("Unknown column '{}' in '{}'", column, table_name))
+    }
+
+    // Where the key of an index on `columns` of the table is in its rows,
+    // and how the table declares them.
+    fn key_layout<'c>(
+        &self,
+        table_name: &str,
+        columns: impl IntoIterator<Item = &'c String>,
+    ) -> Result<KeyLayout> {
+        let declared = &self.catalog.get_table(table_name)?.columns;
+        let mut layout = KeyLayout::default();
+        for column in columns {
+            let ordinal = self.column_ordinal(table_name, column)?;
+            layout.ordinals.push(ordinal);
+            layout.columns.push(declared[ordinal].clone());
+        }
+        Ok(layout)
+    }
+
+    // Whether the index's tree is keyed by INTs rather than bytes.
+    fn int_keyed(&self, info: &IndexInfo) -> Result<bool> {
+        Ok(key::int_keyed(
+            &self.key_layout(&info.table, info.columns())?.columns,
+        ))
+    }
+
+    // The pages of the index, whatever it is keyed by.
+    fn index_pages(&mut self, info: &IndexInfo) -> Result<Vec<u64>> {
+        match (info.kind, self.int_keyed(info)?) {
+            (IndexKind::Hash, _) => HashIndex::open(self, info).pages(),
+            (IndexKind::BTree, true) => BPlusTree::<i64>::open(self, info).pages(),
+            (IndexKind::BTree, false) => BPlusTree::<Vec<u8>>::open(self, info).pages(),
+        }
+    }
+
+    pub fn scan_table(&mut self, table_name: &str) -> Result<Vec<Vec<Value>>> {
+        let rids = self.catalog.get_table(table_name)?.records.clone();
+        let mut rows = Vec::new();
+        for rid in rids {
+            let raw = self.fetch(rid)?;
+            let vals = self.deserialize_row(&raw)?;
+            rows.push(vals);
+        }
+        Ok(rows)
+    }
+
+    pub fn create_table(&mut self, name: String, cols: Vec<ColumnInfo>) -> Result<()> {
+        self.check_schema(&name)?;
+        if self.catalog.tables.contains_key(&name) {
+            return Err(anyhow!("Table '{}' already exists", name));
+        }
+        if self.catalog.views.contains_key(&name) {
+            return Err(anyhow!("'{}' is already the name of a view", name));
+        }
+        self.check_columns(&cols)?;
+        self.log_ddl(&DdlPayload::CreateTable {
+            name: name.clone(),
+            columns: cols.clone(),
+        })?;
+        self.catalog.create_table(name, cols)
+    }
+
+    // A temporary table is made without a word to the WAL, and without
+    // grants: only its session can name it.
+    pub fn create_temp_table(&mut self, name: String, cols: Vec<ColumnInfo>) -> Result<()> {
+        if self.catalog.is_temp(&name) {
+            return Err(anyhow!("Temporary table '{}' already exists", name));
+        }
+        self.check_columns(&cols)?;
+        let table = TableInfo {
+            name: name.clone(),
+            columns: cols,
+            records: Vec::new(),
+            stats: TableStats::default(),
+            column_stats: BTreeMap::new(),
+            foreign_keys: Vec::new(),
+            checks: Vec::new(),
+            version: self.catalog.next_version(),
+            churn: Churn::default(),
+            analyzed_at: None,
+        };
+        self.catalog.temp.insert(name, table);
+        Ok(())
+    }
+
+    // Gives back the pages of temporary tables whose session has ended.
+    pub fn drop_temp_tables(&mut self, tables: impl IntoIterator<Item = TableInfo>) -> Result<()> {
+        for table in tables {
+            for &page_no in table.stats.pages.keys() {
+                self.buffer_pool.free_page(page_no)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Forgets the table and its indexes. Heap pages are shared between
+    // tables through the free list, so they are not reclaimed here; a
+    // temporary table's are its own and are.
+    pub fn drop_table(&mut self, name: &str) -> Result<()> {
+        if let Some(table) = self.catalog.temp.remove(name) {
+            return self.drop_temp_tables([table]);
+        }
+        self.check_unused(name)?;
+        let table = self.catalog.get_table(name)?.clone();
+        self.log_ddl(&DdlPayload::DropTable {
+            table,
+            indexes: self.catalog.get_indexes(name),
+            grants: self.catalog.grants.get(name).cloned().unwrap_or_default(),
+        })?;
+        self.forget_table(name);
+        Ok(())
+    }
+
+    pub fn create_sequence(&mut self, name: String, start: i64, increment: i64) -> Result<()> {
+        if self.catalog.get_sequence(&name).is_ok() {
+            return Err(anyhow!("Sequence '{}' already exists", name));
+        }
+        let sequence = Sequence::new(name.clone(), start, increment)?;
+        self.log_ddl(&DdlPayload::CreateSequence {
+            sequence: sequence.info(),
+        })?;
+        self.catalog.sequences.insert(name, sequence);
+        Ok(())
+    }
+
+    pub fn drop_sequence(&mut self, name: &str) -> Result<()> {
+        let sequence = self.catalog.get_sequence(name)?.clone();
+        self.log_ddl(&DdlPayload::DropSequence {
+            sequence: sequence.info(),
+        })?;
+        self.catalog.sequences.remove(&sequence.name);
+        Ok(())
+    }
+
+    pub fn create_view(&mut self, view: ViewInfo) -> Result<()> {
+        self.check_schema(&view.name)?;
+        if self.catalog.views.contains_key(&view.name) {
+            return Err(anyhow!("View '{}' already exists", view.name));
+        }
+        if self.catalog.tables.contains_key(&view.name) {
+            return Err(anyhow!("'{}' is already the name of a table", view.name));
+        }
+        self.log_ddl(&DdlPayload::CreateView { view: view.clone() })?;
+        self.catalog.views.insert(view.name.clone(), view);
+        Ok(())
+    }
+
+    pub fn drop_view(&mut self, name: &str) -> Result<()> {
+        let view = self
+            .catalog
+            .views
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("View '{}' not found", name))?;
+        self.check_unused(name)?;
+        self.log_ddl(&DdlPayload::DropView { view })?;
+        self.catalog.views.remove(name);
+        Ok(())
+    }
+
+    // The schema a table or view is to be made in has to be there.
+    fn check_schema(&self, key: &str) -> Result<()> {
+        let (schema, _) = split_name(key);
+        match self.catalog.has_schema(schema) {
+            true => Ok(()),
+            false => Err(anyhow!("Schema '{}' not found", schema)),
+        }
+    }
+
+    pub fn create_schema(&mut self, name: &str) -> Result<()> {
+        if self.catalog.has_schema(name) {
+            return Err(anyhow!("Schema '{}' already exists", name));
+        }
+        self.log_ddl(&DdlPayload::CreateSchema {
+            name: name.to_string(),
+        })?;
+        self.catalog.schemas.insert(name.to_string());
+        Ok(())
+    }
+
+    // Without `cascade` the schema has to be empty. With it, its views and
+    // tables are dropped one by one, each logged as its own DROP; one still
+    // in use by another of them waits for that one to go first.
+    pub fn drop_schema(&mut self, name: &str, cascade: bool) -> Result<()> {
+        if name == DEFAULT_SCHEMA {
+            return Err(anyhow!("Schema '{}' cannot be dropped", name));
+        }
+        if !self.catalog.schemas.contains(name) {
+            return Err(anyhow!("Schema '{}' not found", name));
+        }
+        let in_schema = |key: &&String| split_name(key).0 == name;
+        let mut left: Vec<(String, bool)> = self
+            .catalog
+            .views
+            .keys()
+            .filter(in_schema)
+            .map(|view| (view.clone(), true))
+            .chain(
+                self.catalog
+                    .tables
+                    .keys()
+                    .filter(in_schema)
+                    .map(|table| (table.clone(), false)),
+            )
+            .collect();
+        if !cascade && let Some((first, _)) = left.first() {
+            return Err(anyhow!(
+                "Schema '{}' still holds '{}'; DROP SCHEMA {} CASCADE drops it too",
+                name,
+                first,
+                name
+            ));
+        }
+        while !left.is_empty() {
+            let count = left.len();
+            let mut failed = None;
+            for (key, is_view) in std::mem::take(&mut left) {
+                let dropped = match is_view {
+                    true => self.drop_view(&key),
+                    false => self.drop_table(&key),
+                };
+                if let Err(e) = dropped {
+                    left.push((key, is_view));
+                    failed = Some(e);
+                }
+            }
+            if let Some(e) = failed
+                && left.len() == count
+            {
+                return Err(e);
+            }
+        }
+        self.log_ddl(&DdlPayload::DropSchema {
+            name: name.to_string(),
+        })?;
+        self.catalog.schemas.remove(name);
+        Ok(())
+    }
+
+    // Gives `table` a foreign key. The parent has to be a table of its own
+    // with a column of the same type; a temporary table can be neither end.
+    pub fn add_foreign_key(&mut self, table: &str, key: ForeignKey) -> Result<()> {
+        if self.catalog.is_temp(table) || self.catalog.is_temp(&key.parent) {
+            return Err(anyhow!(
+                "Foreign key '{}' cannot involve a temporary table",
+                key.name
+            ));
+        }
+        let column_type = |name: &str, column: &str| -> Result<DataType> {
+            let info = self.catalog.get_table(name)?;
+            info.columns
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(column))
+                .map(|c| c.data_type.clone())
+                .ok_or_else(|| anyhow!("Unknown column '{}' in '{}'", column, name))
+        };
+        if column_type(table, &key.column)? != column_type(&key.parent, &key.parent_column)? {
+            return Err(anyhow!(
+                "Foreign key '{}' joins columns of different types",
+                key.name
+            ));
+        }
+        let info = self.catalog.get_table(table)?;
+        if info.foreign_keys.iter().any(|k| k.name == key.name) {
+            return Err(anyhow!("Foreign key '{}' already exists", key.name));
+        }
+        self.log_ddl(&DdlPayload::AddForeignKey {
+            table: table.to_string(),
+            key: key.clone(),
+        })?;
+        self.catalog.get_table_mut(table)?.foreign_keys.push(key);
+        self.catalog.touch(table);
+        Ok(())
+    }
+
+    // Gives `table` a CHECK constraint, once every row it has passes it. The
+    // name is numbered when the table already has a constraint by it.
+    pub fn add_check(&mut self, table: &str, mut check: CheckConstraint) -> Result<()> {
+        let info = self.catalog.get_table(table)?;
+        let condition = bind_check(info, &check.condition)?;
+        let base = check.name.clone();
+        let mut n = 1;
+        while info.checks.iter().any(|c| c.name == check.name) {
+            n += 1;
+            check.name = format!("{}{}", base, n);
+        }
+        for rid in info.records.clone() {
+            let data = self.fetch(rid)?;
+            if !self.is_current(&data) {
+                continue;
+            }
+            if !eval_predicate(&condition, &self.deserialize_row(&data)?)? {
+                return Err(anyhow!(
+                    "A row of '{}' already breaks CHECK constraint '{}': {}",
+                    table,
+                    check.name,
+                    check.condition
+                ));
+            }
+        }
+        if !self.catalog.is_temp(table) {
+            self.log_ddl(&DdlPayload::AddCheck {
+                table: table.to_string(),
+                check: check.clone(),
+            })?;
+        }
+        self.catalog.get_table_mut(table)?.checks.push(check);
+        self.catalog.touch(table);
+        Ok(())
+    }
+
+    // Renames a table with everything filed under its name: its indexes,
+    // grants and the foreign keys that refer to it. A view keeps its query
+    // as written, so a table a view reads from keeps its name.
+    pub fn rename_table(&mut self, name: &str, new_name: &str) -> Result<()> {
+        if self.catalog.is_temp(name) {
+            if self.catalog.is_temp(new_name) {
+                return Err(anyhow!("Temporary table '{}' already exists", new_name));
+            }
+            let mut table = self.catalog.temp.remove(name).unwrap();
+            table.name = new_name.to_string();
+            self.catalog.temp.insert(new_name.to_string(), table);
+            self.catalog.touch(new_name);
+            return Ok(());
+        }
+        self.catalog.get_table(name)?;
+        if self.catalog.tables.contains_key(new_name) {
+            return Err(anyhow!("Table '{}' already exists", new_name));
+        }
+        if self.catalog.views.contains_key(new_name) {
+            return Err(anyhow!("'{}' is already the name of a view", new_name));
+        }
+        if let Some(view) = self.catalog.views.values().find(|v| v.table == name) {
+            return Err(anyhow!(
+                "'{}' is used by view '{}', which has to be dropped first",
+                name,
+                view.name
+            ));
+        }
+        self.log_ddl(&DdlPayload::RenameTable {
+            from: name.to_string(),
+            to: new_name.to_string(),
+        })?;
+        self.move_table(name, new_name);
+        Ok(())
+    }
+
+    fn move_table(&mut self, from: &str, to: &str) {
+        let Some(mut table) = self.catalog.tables.remove(from) else {
+            return;
+        };
+        table.name = to.to_string();
+        self.catalog.tables.insert(to.to_string(), table);
+        self.catalog.touch(to);
+        if let Some(mut indexes) = self.catalog.indexes.remove(from) {
+            for index in &mut indexes {
+                index.table = to.to_string();
+            }
+            self.catalog.indexes.insert(to.to_string(), indexes);
+        }
+        if let Some(grants) = self.catalog.grants.remove(from) {
+            self.catalog.grants.insert(to.to_string(), grants);
+        }
+        for info in self.catalog.tables.values_mut() {
+            for key in info.foreign_keys.iter_mut().filter(|k| k.parent == from) {
+                key.parent = to.to_string();
+            }
+        }
+        for (table, _) in self.pending_rows.iter_mut().filter(|(t, _)| t == from) {
+            *table = to.to_string();
+        }
+        for check in self.deferred.iter_mut().flatten().filter(|c| c.table == from) {
+            check.table = to.to_string();
+        }
+    }
+
+    // Index names are only unique within a table, so one on two tables has
+    // to be dropped from one of them first.
+    pub fn rename_index(&mut self, name: &str, new_name: &str) -> Result<()> {
+        let tables: Vec<String> = self
+            .catalog
+            .indexes
+            .iter()
+            .filter(|(_, indexes)| indexes.iter().any(|i| i.name == name))
+            .map(|(table, _)| table.clone())
+            .collect();
+        let table = match tables.as_slice() {
+            [] => return Err(anyhow!("Index '{}' not found", name)),
+            [table] => table.clone(),
+            _ => return Err(anyhow!("Index '{}' is on more than one table", name)),
+        };
+        if self.get_indexes(&table).iter().any(|i| i.name == new_name) {
+            return Err(anyhow!("Index '{}' already exists", new_name));
+        }
+        self.log_ddl(&DdlPayload::RenameIndex {
+            table: table.clone(),
+            from: name.to_string(),
+            to: new_name.to_string(),
+        })?;
+        self.put_index_name(&table, name, new_name);
+        Ok(())
+    }
+
+    fn put_index_name(&mut self, table: &str, from: &str, to: &str) {
+        let indexes = self.catalog.indexes.get_mut(table).into_iter().flatten();
+        for index in indexes.filter(|i| i.name == from) {
+            index.name = to.to_string();
+        }
+        self.catalog.touch(table);
+    }
+
+    // A table or view cannot go while a view still reads from it, nor a
+    // table while another's foreign key refers to it.
+    fn check_unused(&self, name: &str) -> Result<()> {
+        if let Some(view) = self.catalog.views.values().find(|v| v.table == name) {
+            return Err(anyhow!(
+                "'{}' is used by view '{}', which has to be dropped first",
+                name,
+                view.name
+            ));
+        }
+        match self
+            .referring_keys(name)
+            .into_iter()
+            .find(|(child, _)| child != name)
+        {
+            Some((child, key)) => Err(anyhow!(
+                "'{}' is referred to by foreign key '{}' of '{}', which has to be dropped first",
+                name,
+                key.name,
+                child
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn forget_table(&mut self, name: &str) {
+        self.catalog.tables.remove(name);
+        self.catalog.indexes.remove(name);
+        self.catalog.grants.remove(name);
+        self.pending_rows.retain(|(table, _)| table != name);
+        if let Some(deferred) = &mut self.deferred {
+            deferred.retain(|check| check.table != name);
+        }
+    }
+
+    // Replaces what `user` was granted on `table` with `grants`, which
+    // GRANT and REVOKE work out from what is there.
+    pub fn set_grants(&mut self, table: &str, user: &str, grants: Grants) -> Result<()> {
+        if self.catalog.is_temp(table) {
+            return Err(anyhow!(
+                "Temporary table '{}' belongs to its session and has no grants",
+                table
+            ));
+        }
+        self.catalog.get_table(table)?;
+        let before = self
+            .catalog
+            .grants(table, user)
+            .cloned()
+            .unwrap_or_default();
+        if before == grants {
+            return Ok(());
+        }
+        self.log_ddl(&DdlPayload::Grant {
+            table: table.to_string(),
+            user: user.to_string(),
+            before,
+            after: grants.clone(),
+        })?;
+        self.put_grants(table, user, grants);
+        Ok(())
+    }
+
+    // Revokes everything `user` was granted, for when the account goes.
+    // Returns the tables it was granted on.
+    pub fn forget_user(&mut self, user: &str) -> Result<Vec<String>> {
+        let mut tables: Vec<_> = self
+            .catalog
+            .grants
+            .iter()
+            .filter(|(_, users)| users.contains_key(user))
+            .map(|(table, _)| table.clone())
+            .collect();
+        tables.sort();
+        for table in &tables {
+            self.set_grants(table, user, Grants::default())?;
+        }
+        Ok(tables)
+    }
+
+    fn put_grants(&mut self, table: &str, user: &str, grants: Grants) {
+        let users = self.catalog.grants.entry(table.to_string()).or_default();
+        if grants.is_empty() {
+            users.remove(user);
+        } else {
+            users.insert(user.to_string(), grants);
+        }
+        if users.is_empty() {
+            self.catalog.grants.remove(table);
+        }
+    }
+
+    // Logged ahead of the catalog change it describes, in the transaction
+    // the change is made in. Without a WAL or a transaction there is nothing
+    // to recover it for.
+    fn log_ddl(&self, ddl: &DdlPayload) -> Result<()> {
+        if let (Some(wal), Some(tx_id)) = (&self.wal, self.tx_id) {
+            wal.log_ddl(tx_id, ddl)?;
+        }
+        Ok(())
+    }
+
+    // Makes a committed DDL change again during recovery. The catalog is
+    // rebuilt from the log, so an entry may already be there or already
+    // gone.
+    pub fn redo_ddl(&mut self, ddl: &DdlPayload) {
+        match ddl {
+            DdlPayload::CreateTable { name, columns } => {
+                let _ = self.catalog.create_table(name.clone(), columns.clone());
+            }
+            DdlPayload::CreateIndex { index, .. } => {
+                let indexes = self.catalog.indexes.entry(index.table.clone()).or_default();
+                indexes.retain(|i| i.name != index.name);
+                indexes.push(index.clone());
+                self.catalog.touch(&index.table);
+            }
+            DdlPayload::DropTable { table, .. } => self.forget_table(&table.name),
+            DdlPayload::Grant {
+                table, user, after, ..
+            } => self.put_grants(table, user, after.clone()),
+            DdlPayload::CreateSequence { sequence } => {
+                self.catalog
+                    .sequences
+                    .insert(sequence.name.clone(), sequence.clone().into());
+            }
+            DdlPayload::DropSequence { sequence } => {
+                self.catalog.sequences.remove(&sequence.name);
+            }
+            DdlPayload::ReserveSequence { name, end } => {
+                if let Some(sequence) = self.catalog.sequences.get(name) {
+                    sequence.reserved_to(*end);
+                }
+            }
+            DdlPayload::CreateView { view } => {
+                self.catalog.views.insert(view.name.clone(), view.clone());
+            }
+            DdlPayload::DropView { view } => {
+                self.catalog.views.remove(&view.name);
+            }
+            DdlPayload::AddForeignKey { table, key } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    info.foreign_keys.retain(|k| k.name != key.name);
+                    info.foreign_keys.push(key.clone());
+                }
+                self.catalog.touch(table);
+            }
+            DdlPayload::AddCheck { table, check } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    info.checks.retain(|c| c.name != check.name);
+                    info.checks.push(check.clone());
+                }
+                self.catalog.touch(table);
+            }
+            DdlPayload::RenameTable { from, to } => self.move_table(from, to),
+            DdlPayload::RenameIndex { table, from, to } => self.put_index_name(table, from, to),
+            DdlPayload::CreateSchema { name } => {
+                self.catalog.schemas.insert(name.clone());
+            }
+            DdlPayload::DropSchema { name } => {
+                self.catalog.schemas.remove(name);
+            }
+            DdlPayload::AddRows { table, rows } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    info.records.extend(rows);
+                }
+            }
+            DdlPayload::RemoveRows { table, rows } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    info.records.retain(|rid| !rows.contains(rid));
+                }
+            }
+        }
+    }
+
+    // Takes back a DDL change whose transaction did not commit. An index
+    // also gives back the pages its build allocated.
+    pub fn undo_ddl(&mut self, ddl: &DdlPayload) -> Result<()> {
+        match ddl {
+            DdlPayload::CreateTable { name, .. } => self.forget_table(name),
+            DdlPayload::CreateIndex { index, pages } => {
+                if let Some(indexes) = self.catalog.indexes.get_mut(&index.table) {
+                    indexes.retain(|i| i.name != index.name);
+                }
+                self.catalog.touch(&index.table);
+                for &page in pages {
+                    self.free_list.remove(page);
+                    self.buffer_pool.free_page(page)?;
+                }
+            }
+            DdlPayload::DropTable {
+                table,
+                indexes,
+                grants,
+            } => {
+                self.catalog
+                    .tables
+                    .insert(table.name.clone(), table.clone());
+                self.catalog
+                    .indexes
+                    .insert(table.name.clone(), indexes.clone());
+                if !grants.is_empty() {
+                    self.catalog
+                        .grants
+                        .insert(table.name.clone(), grants.clone());
+                }
+                self.catalog.touch(&table.name);
+            }
+            DdlPayload::Grant {
+                table,
+                user,
+                before,
+                ..
+            } => self.put_grants(table, user, before.clone()),
+            DdlPayload::CreateSequence { sequence } => {
+                self.catalog.sequences.remove(&sequence.name);
+            }
+            // Put back with what it had reserved by then, so it does not
+            // start over.
+            DdlPayload::DropSequence { sequence } => {
+                self.catalog
+                    .sequences
+                    .insert(sequence.name.clone(), sequence.clone().into());
+            }
+            // Values are not given back.
+            DdlPayload::ReserveSequence { .. } => {}
+            DdlPayload::CreateView { view } => {
+                self.catalog.views.remove(&view.name);
+            }
+            DdlPayload::DropView { view } => {
+                self.catalog.views.insert(view.name.clone(), view.clone());
+            }
+            DdlPayload::AddForeignKey { table, key } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    info.foreign_keys.retain(|k| k.name != key.name);
+                }
+                self.catalog.touch(table);
+            }
+            DdlPayload::AddCheck { table, check } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    info.checks.retain(|c| c.name != check.name);
+                }
+                self.catalog.touch(table);
+            }
+            DdlPayload::RenameTable { from, to } => self.move_table(to, from),
+            DdlPayload::RenameIndex { table, from, to } => self.put_index_name(table, to, from),
+            DdlPayload::CreateSchema { name } => {
+                self.catalog.schemas.remove(name);
+            }
+            DdlPayload::DropSchema { name } => {
+                self.catalog.schemas.insert(name.clone());
+            }
+            DdlPayload::AddRows { table, rows } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    info.records.retain(|rid| !rows.contains(rid));
+                }
+            }
+            // Only those not back already, as undo may come twice.
+            DdlPayload::RemoveRows { table, rows } => {
+                if let Some(info) = self.catalog.tables.get_mut(table) {
+                    let missing: Vec<RID> = rows
+                        .iter()
+                        .filter(|rid| !info.records.contains(rid))
+                        .copied()
+                        .collect();
+                    info.records.extend(missing);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A row has to fit on one page: nothing spills a large value onto
+    // overflow pages.
+    fn serialize_row(&self, values: &[Value]) -> Result<Vec<u8>> {
+        let mut buf = vec![0; ROW_HEADER_SIZE];
+        RowHeader {
+            xmin: self.tx_id.unwrap_or(0),
+            ..RowHeader::default()
+        }
+        .write(&mut buf);
+        encode_values(values, &mut buf);
+        let limit = RecordPage::max_tuple_size(self.page_size);
+        if buf.len() > limit {
+            return Err(anyhow!(
+                "A row of {} bytes is over the {}-byte limit of {}-byte pages; values this \
+                 large need overflow pages, which are not supported, so use a larger page \
+                 size or keep the value outside the database",
+                buf.len(),
+                limit,
+                self.page_size
+            ));
+        }
+        Ok(buf)
+    }
+
+    fn check_columns(&self, cols: &[ColumnInfo]) -> Result<()> {
+        if cols.len() > self.max_columns {
+            return Err(anyhow!(
+                "A table can have at most {} columns, not {}",
+                self.max_columns,
+                cols.len()
+            ));
+        }
+        check_collations(cols)
+    }
+
+    pub fn deserialize_row(&self, data: &[u8]) -> Result<Vec<Value>> {
+        decode_row(data)
+    }
+
+    pub fn fetch(&mut self, rid: RID) -> Result<Vec<u8>> {
+        self.read_tuple(rid, |rec| Ok(rec.to_vec()))
+    }
+
+    // Column `ordinal` of row `rid` of `table`, read from the page without
+    // decoding the rest of the row.
+    pub fn fetch_column(&mut self, rid: RID, table: &TableInfo, ordinal: usize) -> Result<Value> {
+        Self::check_ordinals(table, &[ordinal])?;
+        self.read_tuple(rid, |rec| decode_column(rec, ordinal))
+    }
+
+    // The columns at `ordinals` of row `rid`, in the order asked for.
+    pub fn fetch_columns(
+        &mut self,
+        rid: RID,
+        table: &TableInfo,
+        ordinals: &[usize],
+    ) -> Result<Vec<Value>> {
+        Self::check_ordinals(table, ordinals)?;
+        self.read_tuple(rid, |rec| decode_columns(rec, ordinals))
+    }
+
+    fn check_ordinals(table: &TableInfo, ordinals: &[usize]) -> Result<()> {
+        match ordinals.iter().find(|&&o| o >= table.columns.len()) {
+            Some(ordinal) => Err(anyhow!(
+                "Table '{}' has {} columns, not a column {}",
+                table.name,
+                table.columns.len(),
+                ordinal
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Hands `read` the bytes of row `rid` where they lie in the page.
+    fn read_tuple<T>(&mut self, rid: RID, read: impl FnOnce(&[u8]) -> Result<T>) -> Result<T> {
+        let (page_no, slot) = rid;
+        self.heap_fetches.fetch_add(1, Ordering::Relaxed);
+        let frame = self.buffer_pool.fetch_page(page_no)?;
+        let page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
+        // Unpinned before a missing slot returns, or every miss would keep
+        // the page in the pool for good.
+        self.buffer_pool.unpin_page(page_no, false);
+        let rec = page.get_tuple(slot).ok_or_else(|| anyhow!("Not found"))?;
+        read(rec)
+    }
+
+    // Returns how many dirty pages it wrote.
+    pub fn flush(&mut self) -> Result<usize> {
+        Ok(self.buffer_pool.flush_all()?)
+    }
+
+    // Writes the dirty pages holding the tables' rows and indexes, as a
+    // checkpoint would but without logging one, and returns how many.
+    pub fn flush_tables(&mut self, tables: &[String]) -> Result<usize> {
+        let mut pages = BTreeSet::new();
+        for table in tables {
+            let info = self.catalog.get_table(table)?;
+            pages.extend(info.records.iter().map(|rid| rid.0));
+            for index in self.catalog.get_indexes(table) {
+                pages.extend(self.index_pages(&index)?);
+                pages.extend(index.bloom_page);
+            }
+        }
+        Ok(self.buffer_pool.flush_pages(&pages)?)
+    }
+
+    pub fn create_index(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        index_name: &str,
+        order: Option<usize>,
+    ) -> Result<u64> {
+        self.create_index_on(table_name, &[column.to_string()], index_name, order)
+    }
+
+    // A B-tree index on `columns`, keyed by them in that order.
+    pub fn create_index_on(
+        &mut self,
+        table_name: &str,
+        columns: &[String],
+        index_name: &str,
+        order: Option<usize>,
+    ) -> Result<u64> {
+        let layout = self.validate_new_index(table_name, columns, index_name)?;
+        let max_order = match key::int_keyed(&layout.columns) {
+            true => bplustree::max_order::<i64>(self.page_size),
+            false => bplustree::max_order::<Vec<u8>>(self.page_size),
+        };
+        let order = order.unwrap_or(max_order);
+        if !(bplustree::MIN_ORDER..=max_order).contains(&order) {
+            return Err(anyhow!(
+                "Index order {} is out of range {}..={} for page size {}",
+                order,
+                bplustree::MIN_ORDER,
+                max_order,
+                self.page_size
+            ));
+        }
+        let info = IndexInfo {
+            name: index_name.to_string(),
+            table: table_name.to_string(),
+            column: layout.columns[0].name.clone(),
+            more_columns: layout.columns[1..].iter().map(|c| c.name.clone()).collect(),
+            order,
+            root_page: 0,
+            kind: IndexKind::BTree,
+            bloom_page: None,
+        };
+        let entries = self.index_entries(&info, &layout)?;
+        let (root_page, bloom_page) = self.build_index(&info, entries)?;
+        self.add_index(IndexInfo {
+            root_page,
+            bloom_page,
+            ..info
+        })?;
+        Ok(root_page)
+    }
+
+    pub fn create_hash_index(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        index_name: &str,
+    ) -> Result<u64> {
+        let layout = self.validate_new_index(table_name, &[column.to_string()], index_name)?;
+        if !key::int_keyed(&layout.columns) {
+            return Err(anyhow!(
+                "A hash index is only on one INT column, '{}' is not",
+                layout.columns[0].name
+            ));
+        }
+        let mut info = IndexInfo {
+            name: index_name.to_string(),
+            table: table_name.to_string(),
+            column: layout.columns[0].name.clone(),
+            more_columns: Vec::new(),
+            order: HashIndex::bucket_capacity(self.page_size),
+            root_page: 0,
+            kind: IndexKind::Hash,
+            bloom_page: None,
+        };
+        let entries = self.index_entries(&info, &layout)?;
+        info.root_page = self.build_index(&info, entries)?.0;
+        let root_page = info.root_page;
+        self.add_index(info)?;
+        Ok(root_page)
+    }
+
+    // Puts a freshly built index in the catalog. Index pages are not logged,
+    // so they go to disk before the log names them; the record carries them
+    // so that undo can free them again.
+    fn add_index(&mut self, info: IndexInfo) -> Result<()> {
+        let pages = self.index_pages(&info)?;
+        if self.wal.is_some() && self.tx_id.is_some() {
+            self.flush()?;
+        }
+        self.log_ddl(&DdlPayload::CreateIndex {
+            index: info.clone(),
+            pages,
+        })?;
+        self.catalog.touch(&info.table);
+        self.catalog
+            .indexes
+            .entry(info.table.clone())
+            .or_default()
+            .push(info);
+        Ok(())
+    }
+
+    pub fn create_index_using(
+        &mut self,
+        table_name: &str,
+        columns: &[String],
+        index_name: &str,
+        kind: IndexKind,
+    ) -> Result<u64> {
+        match (kind, columns) {
+            (IndexKind::BTree, _) => self.create_index_on(table_name, columns, index_name, None),
+            (IndexKind::Hash, [column]) => self.create_hash_index(table_name, column, index_name),
+            (IndexKind::Hash, _) => Err(anyhow!(
+                "A hash index is only on one INT column, '{}' is on {}",
+                index_name,
+                columns.len()
+            )),
+        }
+    }
+
+    pub fn reindex(&mut self, table_name: &str, index_name: &str) -> Result<usize> {
+        let info = self
+            .catalog
+            .get_indexes(table_name)
+            .into_iter()
+            .find(|i| i.name == index_name)
+            .ok_or_else(|| anyhow!("Index '{}' not found on '{}'", index_name, table_name))?;
+        let layout = self.key_layout(table_name, info.columns())?;
+        let old_pages = self.index_pages(&info)?;
+
+        let entries = self.index_entries(&info, &layout)?;
+        let keys = entries.len();
+        let (root, bloom_page) = self.build_index(&info, entries)?;
+        self.catalog.set_index_root(table_name, index_name, root);
+        self.catalog
+            .set_index_bloom(table_name, index_name, bloom_page);
+
+        for page in old_pages {
+            self.free_list.remove(page);
+            self.buffer_pool.free_page(page)?;
+        }
+        Ok(keys)
+    }
+
+    // Recounts the table's stats, gathers its column stats and rebuilds its
+    // bloom filters, returning how many indexes got one.
+    pub fn analyze(&mut self, table_name: &str) -> Result<usize> {
+        let stats = self.count_rows(table_name)?;
+        let columns = self.catalog.get_table(table_name)?.columns.clone();
+        let mut values: Vec<Vec<Value>> = vec![Vec::new(); columns.len()];
+        for row in self.scan_table(table_name)? {
+            for (column, value) in values.iter_mut().zip(row) {
+                column.push(value);
+            }
+        }
+        let column_stats = columns
+            .iter()
+            .zip(values)
+            .filter_map(|(column, values)| {
+                let stats = ColumnStats::build(values, column.collation)?;
+                Some((column.name.clone(), stats))
+            })
+            .collect();
+        let table = self.catalog.get_table_mut(table_name)?;
+        table.stats = stats;
+        table.column_stats = column_stats;
+        table.churn = Churn::default();
+        table.analyzed_at = Some(unix_millis());
+        let mut analyzed = 0;
+        for info in self.catalog.get_indexes(table_name) {
+            if info.kind != IndexKind::BTree {
+                continue;
+            }
+            let bloom_page = match self.int_keyed(&info)? {
+                true => BPlusTree::<i64>::open(self, &info).rebuild_bloom()?,
+                false => BPlusTree::<Vec<u8>>::open(self, &info).rebuild_bloom()?,
+            };
+            self.bloom_stats.remove(&bloom_page);
+            self.catalog
+                .set_index_bloom(table_name, &info.name, Some(bloom_page));
+            analyzed += 1;
+        }
+        Ok(analyzed)
+    }
+
+    // Takes back the slots of the table's rows no snapshot can see any
+    // more, deleted by a transaction that committed before every snapshot
+    // open or still to come. Only pages the free list counts deleted rows
+    // on, or does not know, are read. A page a transaction still in
+    // progress has changed is left alone: undoing that transaction would
+    // copy its bytes back where they were before the page was compacted.
+    pub fn vacuum(&mut self, table_name: &str) -> Result<VacuumReport> {
+        let mut slots: BTreeMap<u64, Vec<u16>> = BTreeMap::new();
+        for &(page_no, slot) in &self.catalog.get_table(table_name)?.records {
+            slots.entry(page_no).or_default().push(slot);
+        }
+        let temp = self.catalog.is_temp(table_name);
+        let open_since = match (&self.wal, temp) {
+            (Some(wal), false) => wal.oldest_open_lsn(self.tx_id),
+            _ => None,
+        };
+        let horizon = self.txns.oldest_xmin();
+        let mut report = VacuumReport::default();
+        let mut reclaimed = HashSet::new();
+        for (page_no, slots) in slots {
+            self.check_cancelled()?;
+            if self.free_list.dead_rows(page_no) == Some(0) {
+                continue;
+            }
+            let frame = self.buffer_pool.fetch_page(page_no)?;
+            let lsn = RecordPage::lsn_of(&frame.data);
+            let mut page = RecordPage::from_bytes(frame.data.clone(), self.page_size);
+            self.buffer_pool.unpin_page(page_no, false);
+            let dead: Vec<u16> = slots
+                .into_iter()
+                .filter(|&slot| {
+                    page.get_tuple(slot).is_some_and(|tuple| {
+                        let header = RowHeader::read(tuple);
+                        RowHeader::is_tombstoned(tuple)
+                            && self.txns.is_deleted(&header)
+                            && header.xmax < horizon
+                    })
+                })
+                .collect();
+            if dead.is_empty() {
+                continue;
+            }
+            if open_since.is_some_and(|open| lsn >= open) {
+                report.skipped += 1;
+                continue;
+            }
+            for &slot in &dead {
+                page.delete_tuple(slot)?;
+            }
+            page.compact();
+            if !temp {
+                self.sync_free_space(page_no, &page);
+            }
+            match temp {
+                true => self.write_unlogged_page(page_no, page.to_bytes())?,
+                false => self.write_heap_page(page_no, page.to_bytes())?,
+            }
+            report.rows += dead.len();
+            report.pages += 1;
+            reclaimed.extend(dead.into_iter().map(|slot| (page_no, slot)));
+        }
+        if reclaimed.is_empty() {
+            return Ok(report);
+        }
+        if !temp {
+            self.log_ddl(&DdlPayload::RemoveRows {
+                table: table_name.to_string(),
+                rows: reclaimed.iter().copied().collect(),
+            })?;
+        }
+        let table = self.catalog.get_table_mut(table_name)?;
+        table.records.retain(|rid| !reclaimed.contains(rid));
+        let stats = self.count_rows(table_name)?;
+        self.catalog.get_table_mut(table_name)?.stats = stats;
+        for index in self.catalog.get_indexes(table_name) {
+            self.reindex(table_name, &index.name)?;
+        }
+        Ok(report)
+    }
+
+    pub fn index_stats(&self, table_name: &str, index_name: &str) -> Result<BloomStats> {
+        let info = self
+            .catalog
+            .get_indexes(table_name)
+            .into_iter()
+            .find(|i| i.name == index_name)
+            .ok_or_else(|| anyhow!("Index '{}' not found on '{}'", index_name, table_name))?;
+        Ok(info
+            .bloom_page
+            .and_then(|page| self.bloom_stats.get(&page).copied())
+            .unwrap_or_default())
+    }
+
+    fn build_index(
+        &mut self,
+        info: &IndexInfo,
+        mut entries: Vec<(Key, RID)>,
+    ) -> Result<(u64, Option<u64>)> {
+        match info.kind {
+            IndexKind::BTree => {
+                entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                if let Some(w) = entries.windows(2).find(|w| w[0].0 == w[1].0) {
+                    let columns: Vec<String> = info.columns().map(|c| format!("'{}'", c)).collect();
+                    return Err(anyhow!(
+                        "Duplicate key {} in column{} {} cannot be indexed by '{}'",
+                        w[0].0,
+                        if columns.len() == 1 { "" } else { "s" },
+                        columns.join(", "),
+                        info.name
+                    ));
+                }
+                match self.int_keyed(info)? {
+                    true => self.load_tree(info, entries, Key::int),
+                    false => self.load_tree(info, entries, Key::bytes),
+                }
+            }
+            IndexKind::Hash => {
+                let mut index = HashIndex::create(self)?;
+                for (key, rid) in entries {
+                    let key = key.int().ok_or_else(|| unkeyable(&info.name))?;
+                    index.insert(key as u64, rid)?;
+                }
+                Ok((index.meta_page(), None))
+            }
+        }
+    }
+
+    // Bulk loads a B-tree keyed by `K` with `entries`, sorted by key.
+    fn load_tree<K: IndexKey>(
+        &mut self,
+        info: &IndexInfo,
+        entries: Vec<(Key, RID)>,
+        key: fn(Key) -> Option<K>,
+    ) -> Result<(u64, Option<u64>)> {
+        let entries = entries
+            .into_iter()
+            .map(|(k, rid)| Some((key(k)?, rid)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| unkeyable(&info.name))?;
+        let tree = BPlusTree::bulk_load(self, info.order, info.table.clone(), &entries)?;
+        Ok((tree.root_page(), tree.bloom_page()))
+    }
+
+    // Checks an index on `columns` can be made, and returns where its key is
+    // in the table's rows. A hash index checks further that it is on one INT
+    // column.
+    fn validate_new_index(
+        &self,
+        table_name: &str,
+        columns: &[String],
+        index_name: &str,
+    ) -> Result<KeyLayout> {
+        if self.catalog.is_temp(table_name) {
+            return Err(anyhow!(
+                "Temporary table '{}' cannot be indexed",
+                table_name
+            ));
+        }
+        let layout = self.key_layout(table_name, columns)?;
+        if layout.columns.is_empty() {
+            return Err(anyhow!("Index '{}' has no columns", index_name));
+        }
+        if let Some((i, column)) = layout
+            .columns
+            .iter()
+            .enumerate()
+            .find(|(i, c)| layout.columns[..*i].iter().any(|d| d.name == c.name))
+        {
+            return Err(anyhow!(
+                "Index '{}' names column '{}' twice, as its key column {}",
+                index_name,
+                column.name,
+                i + 1
+            ));
+        }
+        if self
+            .catalog
+            .get_indexes(table_name)
+            .iter()
+            .any(|i| i.name == index_name)
+        {
+            return Err(anyhow!("Index '{}' already exists", index_name));
+        }
+        Ok(layout)
+    }
+
+    // The keys of the table's rows for `info`. A build of it this
+    // transaction started has read most of them already.
+    fn index_entries(&mut self, info: &IndexInfo, layout: &KeyLayout) -> Result<Vec<(Key, RID)>> {
+        let rids = self.catalog.get_table(&info.table)?.records.clone();
+        let build = self
+            .index_builds
+            .iter()
+            .position(|b| Some(b.tx_id) == self.tx_id && b.is_for(&info.table, &info.name));
+        let mut known = match build {
+            Some(i) => self.index_builds.swap_remove(i).finish(),
+            None => HashMap::new(),
+        };
+        let mut entries = Vec::with_capacity(rids.len());
+        for rid in rids {
+            let key = match known.remove(&rid) {
+                Some(key) => key,
+                None => self
+                    .read_tuple(rid, |rec| Ok(layout.of_tuple(rec)))?
+                    .ok_or_else(|| unkeyable(&info.name))?,
+            };
+            entries.push((key, rid));
+        }
+        Ok(entries)
+    }
+
+    // Starts a CREATE INDEX for transaction `tx_id` that reads the table
+    // while writers carry on. It is checked as `create_index` checks it, the
+    // table's row changes are noted from here on, and the rows it has now
+    // are returned for `read_index_keys`. The index is made by CREATE INDEX
+    // in the same transaction once the table is locked.
+    pub fn begin_index_build(
+        &mut self,
+        tx_id: TxId,
+        table_name: &str,
+        columns: &[String],
+        index_name: &str,
+    ) -> Result<Vec<RID>> {
+        self.validate_new_index(table_name, columns, index_name)?;
+        if self
+            .index_builds
+            .iter()
+            .any(|b| b.is_for(table_name, index_name))
+        {
+            return Err(anyhow!("Index '{}' is already being built", index_name));
+        }
+        self.index_builds
+            .push(IndexBuild::new(tx_id, table_name, index_name));
+        Ok(self.catalog.get_table(table_name)?.records.clone())
+    }
+
+    // Reads the keys of `rids` for a build, with storage shared. A row gone
+    // by now, or whose slot has another row, is left for the build to read
+    // again when it finishes, if it is still in the table then.
+    pub fn read_index_keys(
+        &self,
+        table_name: &str,
+        columns: &[String],
+        rids: &[RID],
+    ) -> Result<Vec<(RID, Key)>> {
+        let layout = self.key_layout(table_name, columns)?;
+        let mut keys = Vec::with_capacity(rids.len());
+        let mut last: Option<(u64, RecordPage)> = None;
+        for &rid in rids {
+            if last.as_ref().is_none_or(|(page_no, _)| *page_no != rid.0) {
+                let data = self.buffer_pool.read_page(rid.0)?;
+                last = Some((rid.0, RecordPage::from_bytes(data, self.page_size)));
+            }
+            let (_, page) = last.as_ref().unwrap();
+            let key = page
+                .get_tuple(rid.1)
+                .and_then(|tuple| layout.of_tuple(tuple));
+            keys.extend(key.map(|key| (rid, key)));
+        }
+        Ok(keys)
+    }
+
+    pub fn add_index_keys(
+        &mut self,
+        tx_id: TxId,
+        table_name: &str,
+        index_name: &str,
+        keys: Vec<(RID, Key)>,
+    ) -> Result<()> {
+        self.index_builds
+            .iter_mut()
+            .find(|b| b.tx_id == tx_id && b.is_for(table_name, index_name))
+            .ok_or_else(|| anyhow!("Index '{}' is not being built", index_name))?
+            .add_keys(keys);
+        Ok(())
+    }
+
+    // Forgets the builds `tx_id` started, which never reach the catalog
+    // once it rolls back.
+    pub fn drop_index_builds(&mut self, tx_id: TxId) {
+        self.index_builds.retain(|b| b.tx_id != tx_id);
+    }
+
+    // Notes a row added to or taken from `table_name` for its builds.
+    fn note_row(&mut self, table_name: &str, rid: RID) {
+        for build in &mut self.index_builds {
+            if build.table == table_name {
+                build.note(rid);
+            }
+        }
+    }
+
+    pub fn get_indexes(&self, table: &str) -> Vec<IndexInfo> {
+        self.catalog.get_indexes(table)
+    }
+}
+
+// A collation only says how TEXT compares.
+// What inserting a row into `table` whose `key` holds `value` breaks when
+// the parent has no such row.
+fn missing_parent(table: &str, key: &ForeignKey, value: &Value) -> ForeignKeyViolation {
+    ForeignKeyViolation {
+        message: format!(
+            "Inserting into '{}' breaks foreign key '{}': '{}' has no {} {}",
+            table, key.name, key.parent, key.parent_column, value
+        ),
+        constraint: key.name.clone(),
+        table: table.to_string(),
+    }
+}
+
+// Where an index's key columns are in its table's rows, and how the table
+// declares them, in key order.
+#[derive(Default)]
+struct KeyLayout {
+    ordinals: Vec<usize>,
+    columns: Vec<ColumnInfo>,
+}
+
+impl KeyLayout {
+    fn of_row(&self, row: &[Value]) -> Option<Key> {
+        let values: Option<Vec<Value>> =
+            self.ordinals.iter().map(|&o| row.get(o).cloned()).collect();
+        Key::of(&self.columns, &values?)
+    }
+
+    // The key of a row as storage keeps it, header and all.
+    fn of_tuple(&self, tuple: &[u8]) -> Option<Key> {
+        let values = decode_columns(tuple, &self.ordinals).ok()?;
+        Key::of(&self.columns, &values)
+    }
+}
+
+// A row whose values do not make a key of the index, which rows of the
+// declared types always do.
+fn unkeyable(index: &str) -> anyhow::Error {
+    anyhow!("A row does not fit the key of index '{}'", index)
+}
+
+fn check_collations(cols: &[ColumnInfo]) -> Result<()> {
+    match cols
+        .iter()
+        .find(|c| c.data_type == DataType::Int && c.collation != Collation::Binary)
+    {
+        Some(col) => Err(anyhow!(
+            "COLLATE {} needs a TEXT column, '{}' is INT",
+            col.collation.name(),
+            col.name
+        )),
+        None => Ok(()),
+    }
+}
+
+const RANGE_MERGE_GAP: usize = 16;
+
+fn changed_ranges(old: &[u8], new: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < new.len() {
+        if old.get(i) == Some(&new[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < new.len() && old.get(i) != Some(&new[i]) {
+            i += 1;
+        }
+        match ranges.last_mut() {
+            Some(last) if start - last.1 <= RANGE_MERGE_GAP => last.1 = i,
+            _ => ranges.push((start, i)),
+        }
+    }
+    ranges
+}
+
+// A row's values as stored after its header: a u32 count, then each value
+// as a tag byte and its bytes, 0 for an INT's 8 and 1 for TEXT's u32 length
+// and UTF-8. Results sent in the binary format use the same encoding.
+pub fn encode_values(values: &[Value], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(values.len() as u32).to_le_bytes());
+    for v in values {
+        match v {
+            Value::Int(i) => {
+                buf.push(0);
+                buf.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::String(s) => {
+                buf.push(1);
+                let b = s.as_bytes();
+                buf.extend_from_slice(&(b.len() as u32).to_le_bytes());
+                buf.extend_from_slice(b);
+            }
+        }
+    }
+}
+
+// Bytes that do not make a whole row are an error rather than a panic,
+// so a damaged page can be reported instead of taking the server down.
+pub fn decode_row(data: &[u8]) -> Result<Vec<Value>> {
+    decode_values(data, ROW_HEADER_SIZE)
+}
+
+// The values `encode_values` wrote at `start` of `data`.
+pub fn decode_values(data: &[u8], start: usize) -> Result<Vec<Value>> {
+    let mut cursor = start;
+    let count = u32::from_le_bytes(take(data, &mut cursor, 4)?.try_into().unwrap()) as usize;
+    let mut vals = Vec::with_capacity(count.min(data.len()));
+    for _ in 0..count {
+        vals.push(decode_value(data, &mut cursor)?);
+    }
+    Ok(vals)
+}
+
+// For `TableInfo::analyzed_at`.
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// Value `ordinal` of a stored row. The values before it are stepped over
+// by their lengths, and the ones after it not looked at.
+pub fn decode_column(data: &[u8], ordinal: usize) -> Result<Value> {
+    let offsets = value_offsets(data, ordinal)?;
+    decode_value(data, &mut offsets[ordinal].clone())
+}
+
+// The values at `ordinals` of a stored row, in that order, each decoded
+// once however often it is asked for.
+pub fn decode_columns(data: &[u8], ordinals: &[usize]) -> Result<Vec<Value>> {
+    let Some(&last) = ordinals.iter().max() else {
+        return Ok(Vec::new());
+    };
+    let offsets = value_offsets(data, last)?;
+    ordinals
+        .iter()
+        .map(|&o| decode_value(data, &mut offsets[o].clone()))
+        .collect()
+}
+
+// Where each of a stored row's values up to `last` starts.
+fn value_offsets(data: &[u8], last: usize) -> Result<Vec<usize>> {
+    let mut cursor = ROW_HEADER_SIZE;
+    let count = u32::from_le_bytes(take(data, &mut cursor, 4)?.try_into().unwrap()) as usize;
+    if last >= count {
+        return Err(anyhow!("Row has {} values, not a value {}", count, last));
+    }
+    let mut offsets = Vec::with_capacity(last + 1);
+    for _ in 0..=last {
+        offsets.push(cursor);
+        let len = match take(data, &mut cursor, 1)?[0] {
+            0 => 8,
+            1 => u32::from_le_bytes(take(data, &mut cursor, 4)?.try_into().unwrap()) as usize,
+            tag => return Err(anyhow!("Invalid tag {}", tag)),
+        };
+        take(data, &mut cursor, len)?;
+    }
+    Ok(offsets)
+}
+
+fn decode_value(data: &[u8], cursor: &mut usize) -> Result<Value> {
+    match take(data, cursor, 1)?[0] {
+        0 => Ok(Value::Int(i64::from_le_bytes(
+            take(data, cursor, 8)?.try_into().unwrap(),
+        ))),
+        1 => {
+            let len = u32::from_le_bytes(take(data, cursor, 4)?.try_into().unwrap()) as usize;
+            Ok(Value::String(String::from_utf8(
+                take(data, cursor, len)?.to_vec(),
+            )?))
+        }
+        tag => Err(anyhow!("Invalid tag {}", tag)),
+    }
+}
+
+fn take<'a>(data: &'a [u8], cursor: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let bytes = data
+        .get(*cursor..*cursor + len)
+        .ok_or_else(|| anyhow!("Invalid row data: truncated at byte {}", cursor))?;
+    *cursor += len;
+    Ok(bytes)
 }
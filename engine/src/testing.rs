@@ -0,0 +1,211 @@
+use crate::database::{Database, DatabaseConfig};
+use crate::net::client::{DbValue, QueryResult};
+use crate::query::binder::{Binder, Catalog, Value};
+use crate::query::executor::{Executor, Tuple, build_operator};
+use crate::query::optimizer::Optimizer;
+use crate::query::parser::Parser;
+use crate::query::physical_planner::PhysicalPlanner;
+use crate::query::planner::Planner;
+use crate::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Small enough that a test's few hundred rows span pages and a pool of
+// POOL_SIZE has to evict some of them.
+pub const PAGE_SIZE: usize = 1024;
+pub const POOL_SIZE: usize = 8;
+
+// Tells apart the directories of the tests of one process.
+static NEXT_DIR: AtomicU64 = AtomicU64::new(1);
+
+/// The whole engine for an integration test: storage, WAL, locks and the
+/// query pipeline, run through an embedded `Database` in a directory of its
+/// own under the system's temporary directory. Statements that fail panic
+/// with the SQL that failed, and the directory is removed once the
+/// `TestDb` is dropped.
+///
+/// ```
+/// use engine::testing::TestDb;
+///
+/// let mut db = TestDb::new();
+/// db.execute("CREATE TABLE t (id INT, name TEXT);");
+/// db.execute("INSERT INTO t (id, name) VALUES (1, 'a'), (2, 'b');");
+/// db.assert_row_set(
+///     "SELECT name, id FROM t;",
+///     [vec!["b".into(), 2.into()], vec!["a".into(), 1.into()]],
+/// );
+/// ```
+pub struct TestDb {
+    config: DatabaseConfig,
+    // Only None while it is being reopened.
+    db: Option<Database>,
+}
+
+impl TestDb {
+    pub fn new() -> Self {
+        Self::with_config(|_| {})
+    }
+
+    // Opens with PAGE_SIZE and POOL_SIZE and whatever `configure` changes.
+    pub fn with_config(configure: impl FnOnce(&mut DatabaseConfig)) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "mydb_test_{}_{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = DatabaseConfig {
+            page_size: PAGE_SIZE,
+            pool_size: POOL_SIZE,
+            ..DatabaseConfig::new(dir)
+        };
+        configure(&mut config);
+        let db = Database::open(config.clone()).expect("Failed to open the test database");
+        TestDb {
+            config,
+            db: Some(db),
+        }
+    }
+
+    // Where its files are, for a test to look at what reached disk.
+    pub fn config(&self) -> &DatabaseConfig {
+        &self.config
+    }
+
+    pub fn db(&mut self) -> &mut Database {
+        self.db.as_mut().unwrap()
+    }
+
+    pub fn try_execute(&mut self, sql: &str) -> Result<QueryResult> {
+        self.db().execute(sql)
+    }
+
+    pub fn execute(&mut self, sql: &str) -> QueryResult {
+        self.try_execute(sql)
+            .unwrap_or_else(|e| panic!("{:?} failed: {:#}", sql, e))
+    }
+
+    pub fn rows(&mut self, sql: &str) -> Vec<Vec<DbValue>> {
+        self.execute(sql)
+            .rows
+            .into_iter()
+            .map(|row| row.into_values())
+            .collect()
+    }
+
+    // The rows `sql` returns are `expected`, in that order.
+    pub fn assert_rows(&mut self, sql: &str, expected: impl IntoIterator<Item = Vec<DbValue>>) {
+        let expected: Vec<_> = expected.into_iter().collect();
+        assert_eq!(self.rows(sql), expected, "{}", sql);
+    }
+
+    // The rows `sql` returns are `expected`, in any order, each as many
+    // times as it is there.
+    pub fn assert_row_set(&mut self, sql: &str, expected: impl IntoIterator<Item = Vec<DbValue>>) {
+        let rows = self.rows(sql);
+        let mut missing: Vec<_> = expected.into_iter().collect();
+        let mut extra = Vec::new();
+        for row in rows {
+            match missing.iter().position(|r| *r == row) {
+                Some(i) => {
+                    missing.swap_remove(i);
+                }
+                None => extra.push(row),
+            }
+        }
+        assert!(
+            missing.is_empty() && extra.is_empty(),
+            "{}: missing {:?}, not expected {:?}",
+            sql,
+            missing,
+            extra
+        );
+    }
+
+    // Closes the database as a clean shutdown does and opens it again over
+    // the same files, from the catalog the closing checkpoint saved.
+    pub fn restart(&mut self) {
+        self.db.take().unwrap().close().expect("Failed to close");
+        self.reopen();
+    }
+
+    // Drops the database as if the process had been killed, leaving the
+    // data file behind the log, and opens it again, which recovers.
+    pub fn kill(&mut self) {
+        self.db.take().unwrap().kill();
+        self.reopen();
+    }
+
+    fn reopen(&mut self) {
+        let db = Database::open(self.config.clone()).expect("Failed to reopen the test database");
+        self.db = Some(db);
+    }
+}
+
+impl Default for TestDb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        drop(self.db.take());
+        let _ = std::fs::remove_dir_all(&self.config.dir);
+    }
+}
+
+// Runs `sql` straight against `storage`, through the same binder, planner
+// and executor a session uses but outside any transaction, for tests that
+// build their tables with `Storage` itself.
+pub fn run(storage: &mut Storage, sql: &str) -> Result<Vec<Tuple>> {
+    let stmt = Parser::new(sql)?.parse_statement()?;
+    let mut catalog = Catalog::from_storage(&storage.catalog);
+    let bound = Binder::new(&mut catalog, storage).bind(stmt)?;
+    let logical = Planner::new(&catalog.tables).plan(bound)?;
+    let optimized = Optimizer::optimize(logical)?;
+    let phys = PhysicalPlanner::new(&catalog, storage).create_physical_plan(optimized)?;
+    let root = build_operator(phys, storage)?;
+    Executor::new(root).execute()
+}
+
+// A fresh `path` with table T (ID INT, NAME TEXT) and `rows` rows in it,
+// ids from 0 and names "name<id>".
+pub fn table_with_rows(path: &str, rows: i64) -> Storage {
+    let _ = std::fs::remove_file(path);
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    let column = |name: &str, data_type| ColumnInfo {
+        name: name.into(),
+        data_type,
+        collation: Collation::Binary,
+    };
+    storage
+        .create_table(
+            "T".into(),
+            vec![
+                column("ID", DataType::Int),
+                column("NAME", DataType::String),
+            ],
+        )
+        .unwrap();
+    let names = vec!["ID".to_string(), "NAME".to_string()];
+    for id in 0..rows {
+        storage
+            .insert_row(
+                "T",
+                &names,
+                vec![Value::Int(id), Value::String(format!("name{}", id))],
+            )
+            .unwrap();
+    }
+    storage
+}
+
+// A directory under the system's temporary directory for the test `name`,
+// with nothing left in it from an earlier run.
+pub fn fresh_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mydb_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
@@ -0,0 +1,121 @@
+use crate::query::binder::Value;
+use crate::storage::record::Page as RecordPage;
+use crate::storage::storage::Storage;
+use crate::tx::log_manager::{
+    CompensationPayload, DdlPayload, LogRecordType, Lsn, TxId, UpdatePayload,
+};
+use crate::tx::mvcc::{ROW_HEADER_SIZE, RowHeader, Snapshot};
+use crate::tx::wal_reader::WalReader;
+use anyhow::{Result, anyhow, bail};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// The rows of `table` as a reader would have seen them once the record at
+// `lsn` was written, in the order a scan returns them now.
+//
+// Nothing is replayed forwards: the table's pages are read as they are and
+// every change logged after `lsn` is taken back out of them, newest first,
+// by putting its before image back. What is left is then read with a
+// snapshot that sees only the transactions committed by `lsn`. The log has
+// to reach back past `lsn`, and the rows are the ones the table lists now;
+// the table cannot have been created, dropped or renamed since.
+pub fn table_as_of(storage: &Storage, table: &str, lsn: Lsn) -> Result<Vec<Vec<Value>>> {
+    let wal = storage
+        .wal
+        .as_ref()
+        .ok_or_else(|| anyhow!("AS OF reads the WAL, and this database has none"))?;
+    wal.flush_all()?;
+    let info = storage.catalog.get_table(table)?;
+
+    let mut reader = WalReader::open(wal.path())?;
+    let mut first = None;
+    let mut last = 0;
+    let mut seen: HashSet<TxId> = HashSet::new();
+    let mut committed: HashSet<TxId> = HashSet::new();
+    let mut undo: Vec<UpdatePayload> = Vec::new();
+    while let Some(record) = reader.next_record()? {
+        let hdr = &record.header;
+        first.get_or_insert(hdr.lsn);
+        last = hdr.lsn;
+        if hdr.tx_id != 0 {
+            seen.insert(hdr.tx_id);
+        }
+        if hdr.lsn <= lsn {
+            if hdr.typ == LogRecordType::Commit {
+                committed.insert(hdr.tx_id);
+            }
+            continue;
+        }
+        match hdr.typ {
+            LogRecordType::Update => undo.push(UpdatePayload::decode(&record.payload)?),
+            LogRecordType::Compensation => {
+                undo.push(CompensationPayload::decode(&record.payload)?.update)
+            }
+            typ if typ.is_ddl() => {
+                let replaced = match DdlPayload::decode(&record.payload)? {
+                    DdlPayload::CreateTable { name, .. } => name == info.name,
+                    DdlPayload::DropTable { table, .. } => table.name == info.name,
+                    DdlPayload::RenameTable { from, to } => from == info.name || to == info.name,
+                    _ => false,
+                };
+                if replaced {
+                    bail!(
+                        "Table '{}' was created, dropped or renamed after LSN {}",
+                        info.name,
+                        lsn
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+    match first {
+        Some(first) if lsn + 1 < first => bail!(
+            "LSN {} is older than the log kept, which starts at LSN {}",
+            lsn,
+            first
+        ),
+        _ if lsn > last => bail!(
+            "LSN {} has not been written yet; the log is at {}",
+            lsn,
+            last
+        ),
+        _ => {}
+    }
+
+    let pages: BTreeSet<u64> = info.records.iter().map(|&(page_no, _)| page_no).collect();
+    let mut images: HashMap<u64, Vec<u8>> = HashMap::new();
+    for &page_no in &pages {
+        images.insert(page_no, storage.buffer_pool.read_page(page_no)?);
+    }
+    for update in undo.iter().rev() {
+        if let Some(page) = images.get_mut(&update.page_no) {
+            let offset = update.offset as usize;
+            page[offset..offset + update.before.len()].copy_from_slice(&update.before);
+        }
+    }
+
+    // Every transaction the log still mentions either committed by `lsn`
+    // or is left out; any older one committed before the log starts.
+    let snapshot = Snapshot {
+        tx: None,
+        horizon: TxId::MAX,
+        active: seen.difference(&committed).copied().collect(),
+        aborted: HashSet::new(),
+    };
+    let pages: HashMap<u64, RecordPage> = images
+        .into_iter()
+        .map(|(page_no, data)| (page_no, RecordPage::from_bytes(data, storage.page_size)))
+        .collect();
+    let mut rows = Vec::new();
+    for (page_no, slot) in &info.records {
+        // A slot taken since is empty, or gone, as of `lsn`.
+        let Some(rec) = pages[page_no].get_tuple(*slot) else {
+            continue;
+        };
+        if rec.len() < ROW_HEADER_SIZE || !snapshot.is_visible(&RowHeader::read(rec)) {
+            continue;
+        }
+        rows.push(storage.deserialize_row(rec)?);
+    }
+    Ok(rows)
+}
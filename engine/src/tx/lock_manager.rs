@@ -1,9 +1,17 @@
 
 
+use crate::storage::record::RID;
+use anyhow::anyhow;
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    sync::Mutex,
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
 };
+use tracing::warn;
 
 
 pub type TxId = u64;
@@ -12,7 +20,18 @@ pub type TxId = u64;
 pub enum Resource {
     Table(String),
     Page(u64),
-    
+    Row(String, RID),
+}
+
+
+// Under ReadCommitted readers only take the table-level lock; under
+// RepeatableRead they also share-lock every row they read until the
+// transaction ends.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
 }
 
 
@@ -20,15 +39,124 @@ pub enum Resource {
 pub enum LockMode {
     Shared,    
     Exclusive, 
+    // Taken on a table before locking rows in it, so a table-level lock
+    // only has to look at the table's holders to see conflicting row locks.
+    IntentionShared,
+    IntentionExclusive,
+}
+
+impl LockMode {
+    pub fn compatible(self, other: LockMode) -> bool {
+        use LockMode::*;
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (Shared, Shared) | (IntentionExclusive, IntentionExclusive) => true,
+            (Shared, IntentionExclusive) | (IntentionExclusive, Shared) => false,
+        }
+    }
+
+    // Whether holding `self` already grants everything `other` would.
+    pub fn covers(self, other: LockMode) -> bool {
+        self == other || self == LockMode::Exclusive || other == LockMode::IntentionShared
+    }
+
+    // The mode to take on the table before locking one of its rows in `self`.
+    pub fn intention(self) -> LockMode {
+        match self {
+            LockMode::Shared | LockMode::IntentionShared => LockMode::IntentionShared,
+            LockMode::Exclusive | LockMode::IntentionExclusive => LockMode::IntentionExclusive,
+        }
+    }
+}
+
+
+// How lock waits are kept from deadlocking. `Detect` lets everyone wait and
+// relies on `resolve_deadlock` breaking cycles; `WaitDie` only lets a
+// transaction wait for younger ones and fails the rest straight away.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DeadlockPolicy {
+    #[default]
+    Detect,
+    WaitDie,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockError {
+    DeadlockVictim {
+        tx: TxId,
+    },
+    LockTimeout {
+        resource: Resource,
+        held_by: Vec<TxId>,
+    },
+    WouldBlock {
+        resource: Resource,
+        held_by: Vec<TxId>,
+    },
+    Retry {
+        tx: TxId,
+        resource: Resource,
+        older: Vec<TxId>,
+    },
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::DeadlockVictim { tx } => {
+                write!(f, "transaction {} was aborted as a deadlock victim", tx)
+            }
+            LockError::LockTimeout { resource, held_by } => {
+                write!(
+                    f,
+                    "timed out waiting for {:?} held by {:?}",
+                    resource, held_by
+                )
+            }
+            LockError::WouldBlock { resource, held_by } => {
+                write!(f, "{:?} is locked by {:?}", resource, held_by)
+            }
+            LockError::Retry {
+                tx,
+                resource,
+                older,
+            } => write!(
+                f,
+                "transaction {} must retry: {:?} is held by older transactions {:?}",
+                tx, resource, older
+            ),
+        }
+    }
 }
 
+impl std::error::Error for LockError {}
+
 
 #[derive(Debug)]
 struct LockRequest {
+    id: u64,
     tx: TxId,
     mode: LockMode,
+    enqueued: Instant,
     
-    waker: tokio::sync::oneshot::Sender<()>,
+    waker: tokio::sync::oneshot::Sender<Result<(), LockError>>,
+}
+
+// A point-in-time view of one resource, for debugging blocked statements.
+#[derive(Debug, Clone)]
+pub struct LockSnapshot {
+    pub resource: Resource,
+    pub holders: Vec<(TxId, LockMode)>,
+    pub waiters: Vec<WaiterSnapshot>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WaiterSnapshot {
+    pub tx: TxId,
+    pub mode: LockMode,
+    pub waited: Duration,
 }
 
 
@@ -49,17 +177,62 @@ impl LockState {
     }
 
     
+    // Locks the requester already holds never conflict with its own request.
     fn can_grant(&self, req: &LockRequest) -> bool {
-        if self.holders.is_empty() {
-            return true;
+        self.holders
+            .iter()
+            .filter(|&&(t, _)| t != req.tx)
+            .all(|&(_, m)| req.mode.compatible(m))
+    }
+
+    fn grant(&mut self, tx: TxId, mode: LockMode) {
+        if !self.holders.contains(&(tx, mode)) {
+            self.holders.push((tx, mode));
         }
-        match req.mode {
-            LockMode::Shared => {
-                
-                self.holders.iter().all(|&(_, m)| m == LockMode::Shared)
+    }
+
+    fn holds(&self, tx: TxId, mode: LockMode) -> bool {
+        self.holders.iter().any(|&(t, m)| t == tx && m.covers(mode))
+    }
+
+    // New requests go straight through only if nobody is queued ahead of
+    // them, so a stream of readers cannot starve a waiting writer.
+    fn can_grant_now(&self, req: &LockRequest) -> bool {
+        self.holds(req.tx, req.mode) || (self.queue.is_empty() && self.can_grant(req))
+    }
+
+    // Transactions `req` would have to wait for: conflicting holders plus
+    // everyone queued ahead of it.
+    fn blockers(&self, req: &LockRequest) -> Vec<TxId> {
+        let holders = self
+            .holders
+            .iter()
+            .filter(|&&(t, m)| t != req.tx && !req.mode.compatible(m))
+            .map(|&(t, _)| t);
+        let queued = self.queue.iter().map(|r| r.tx).filter(|&t| t != req.tx);
+        let mut blockers: Vec<_> = holders.chain(queued).collect();
+        blockers.sort_unstable();
+        blockers.dedup();
+        blockers
+    }
+
+    fn holder_ids(&self) -> Vec<TxId> {
+        self.holders.iter().map(|&(t, _)| t).collect()
+    }
+
+    // Grants queued requests from the front for as long as they are
+    // compatible, returning the wakers to notify once the table is unlocked.
+    fn grant_waiters(&mut self) -> Vec<tokio::sync::oneshot::Sender<Result<(), LockError>>> {
+        let mut wakers = Vec::new();
+        while let Some(req) = self.queue.front() {
+            if !self.can_grant(req) {
+                break;
             }
-            LockMode::Exclusive => false, 
+            let req = self.queue.pop_front().unwrap();
+            self.grant(req.tx, req.mode);
+            wakers.push(req.waker);
         }
+        wakers
     }
 }
 
@@ -67,20 +240,58 @@ impl LockState {
 pub struct LockManager {
     
     table: Mutex<HashMap<Resource, LockState>>,
+    next_request: AtomicU64,
+    waits: AtomicU64,
+    timeout: Option<Duration>,
+    policy: DeadlockPolicy,
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LockManager {
     pub fn new() -> Self {
         LockManager {
             table: Mutex::new(HashMap::new()),
+            next_request: AtomicU64::new(0),
+            waits: AtomicU64::new(0),
+            timeout: None,
+            policy: DeadlockPolicy::default(),
         }
     }
 
+    // Under `DeadlockPolicy::WaitDie` there is no need for
+    // `spawn_deadlock_detector`.
+    pub fn with_policy(mut self, policy: DeadlockPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Waiters give up with `LockError::LockTimeout` after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     
     
     pub async fn lock(&self, tx: TxId, res: Resource, mode: LockMode) -> anyhow::Result<()> {
+        self.lock_with_timeout(tx, res, mode, self.timeout).await
+    }
+
+    pub async fn lock_with_timeout(
+        &self,
+        tx: TxId,
+        res: Resource,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
         
-        let (tx_wake, rx_wake) = tokio::sync::oneshot::channel();
+        let (tx_wake, mut rx_wake) = tokio::sync::oneshot::channel();
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
 
         
         let should_wait = {
@@ -88,76 +299,235 @@ impl LockManager {
             let state = tbl.entry(res.clone()).or_insert_with(LockState::new);
 
             let req = LockRequest {
+                id,
                 tx,
                 mode,
+                enqueued: Instant::now(),
                 waker: tx_wake,
             };
 
-            if state.can_grant(&req) {
+            if state.can_grant_now(&req) {
                 
-                state.holders.push((tx, mode));
+                state.grant(tx, mode);
                 
                 
-                let _ = req.waker.send(());
+                let _ = req.waker.send(Ok(()));
                 false 
             } else {
+                if self.policy == DeadlockPolicy::WaitDie {
+                    let older: Vec<_> = state
+                        .blockers(&req)
+                        .into_iter()
+                        .filter(|&t| t < tx)
+                        .collect();
+                    if !older.is_empty() {
+                        return Err(LockError::Retry {
+                            tx,
+                            resource: res,
+                            older,
+                        }
+                        .into());
+                    }
+                }
                 
                 state.queue.push_back(req);
+                self.waits.fetch_add(1, Ordering::Relaxed);
                 true 
             }
         }; 
 
         
         if should_wait {
-            let _ = rx_wake.await;
+            let outcome = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, &mut rx_wake).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => match self.cancel_request(&res, id) {
+                        Some(err) => return Err(err.into()),
+                        // Granted or aborted just as the deadline passed.
+                        None => rx_wake.await,
+                    },
+                },
+                None => rx_wake.await,
+            };
+            outcome.map_err(|_| anyhow!("lock request of transaction {} was dropped", tx))??;
         }
 
         Ok(())
     }
 
-    
-    
-    pub fn unlock_all(&self, tx: TxId) {
+    // Grants the lock only if that needs no waiting; used by the executor,
+    // which cannot suspend mid-statement.
+    pub fn try_lock(&self, tx: TxId, res: Resource, mode: LockMode) -> Result<(), LockError> {
         let mut tbl = self.table.lock().unwrap();
-        let resources: Vec<_> = tbl.keys().cloned().collect();
+        let state = tbl.entry(res.clone()).or_insert_with(LockState::new);
+        let (waker, _) = tokio::sync::oneshot::channel();
+        let req = LockRequest {
+            id: self.next_request.fetch_add(1, Ordering::Relaxed),
+            tx,
+            mode,
+            enqueued: Instant::now(),
+            waker,
+        };
+        if state.can_grant_now(&req) {
+            state.grant(tx, mode);
+            Ok(())
+        } else {
+            Err(LockError::WouldBlock {
+                resource: res,
+                held_by: state.holder_ids(),
+            })
+        }
+    }
 
-        for res in resources {
-            if let Some(state) = tbl.get_mut(&res) {
-                
-                state.holders.retain(|&(holder_tx, _)| holder_tx != tx);
+    // How many lock requests have had to queue behind another transaction.
+    pub fn waits(&self) -> u64 {
+        self.waits.load(Ordering::Relaxed)
+    }
 
-                
-                let mut to_wake = Vec::new();
-                let i = 0;
-                while i < state.queue.len() {
-                    
-                    if state.holders.is_empty()
-                        || (state.queue[i].mode == LockMode::Shared
-                            && state.holders.iter().all(|&(_, m)| m == LockMode::Shared))
-                    {
-                        let req = state.queue.remove(i).unwrap();
-                        state.holders.push((req.tx, req.mode));
-                        to_wake.push(req.waker);
-                        
-                        if req.mode == LockMode::Exclusive {
-                            break;
-                        }
-                        
-                    } else {
-                        
-                        break;
-                    }
+    // Resources are ordered by their debug form so repeated snapshots line
+    // up; waiters keep their queue order.
+    pub fn snapshot(&self) -> Vec<LockSnapshot> {
+        let now = Instant::now();
+        let tbl = self.table.lock().unwrap();
+        let mut snapshot: Vec<_> = tbl
+            .iter()
+            .map(|(res, state)| LockSnapshot {
+                resource: res.clone(),
+                holders: state.holders.clone(),
+                waiters: state
+                    .queue
+                    .iter()
+                    .map(|req| WaiterSnapshot {
+                        tx: req.tx,
+                        mode: req.mode,
+                        waited: now.duration_since(req.enqueued),
+                    })
+                    .collect(),
+            })
+            .collect();
+        drop(tbl);
+        snapshot.sort_by_cached_key(|entry| format!("{:?}", entry.resource));
+        snapshot
+    }
+
+    // Resources some transaction holds an exclusive lock on.
+    pub fn held_exclusively(&self) -> Vec<Resource> {
+        let tbl = self.table.lock().unwrap();
+        tbl.iter()
+            .filter(|(_, state)| {
+                state
+                    .holders
+                    .iter()
+                    .any(|&(_, mode)| mode == LockMode::Exclusive)
+            })
+            .map(|(res, _)| res.clone())
+            .collect()
+    }
+
+    // Takes a timed-out request off the queue. Anything queued behind it may
+    // now be grantable, so the grant logic runs again.
+    fn cancel_request(&self, res: &Resource, id: u64) -> Option<LockError> {
+        let (err, wakers) = {
+            let mut tbl = self.table.lock().unwrap();
+            let state = tbl.get_mut(res)?;
+            let pos = state.queue.iter().position(|req| req.id == id)?;
+            state.queue.remove(pos);
+            let err = LockError::LockTimeout {
+                resource: res.clone(),
+                held_by: state.holder_ids(),
+            };
+            (err, state.grant_waiters())
+        };
+        for waker in wakers {
+            let _ = waker.send(Ok(()));
+        }
+        Some(err)
+    }
+
+    // Fails every pending request of `tx` with `err`. Locks it already holds
+    // stay held until the transaction itself calls `unlock_all`.
+    pub fn abort_waiter(&self, tx: TxId, err: LockError) -> bool {
+        let mut aborted = Vec::new();
+        let mut granted = Vec::new();
+        {
+            let mut tbl = self.table.lock().unwrap();
+            for state in tbl.values_mut() {
+                let before = aborted.len();
+                while let Some(pos) = state.queue.iter().position(|req| req.tx == tx) {
+                    aborted.push(state.queue.remove(pos).unwrap().waker);
+                }
+                if aborted.len() > before {
+                    granted.extend(state.grant_waiters());
                 }
+            }
+        }
+        for waker in granted {
+            let _ = waker.send(Ok(()));
+        }
+        let any = !aborted.is_empty();
+        for waker in aborted {
+            let _ = waker.send(Err(err.clone()));
+        }
+        any
+    }
 
-                
-                if !to_wake.is_empty() {
-                    drop(tbl);
-                    for w in to_wake {
-                        let _ = w.send(());
-                    }
-                    tbl = self.table.lock().unwrap();
+    // Breaks one wait-for cycle, if there is one, by aborting its youngest
+    // transaction.
+    pub fn resolve_deadlock(&self) -> Option<TxId> {
+        let cycle = self.detect_deadlock()?;
+        let victim = cycle.into_iter().max()?;
+        self.abort_waiter(victim, LockError::DeadlockVictim { tx: victim })
+            .then_some(victim)
+    }
+
+    // Runs deadlock detection every `interval` until the lock manager is
+    // dropped.
+    pub fn spawn_deadlock_detector(
+        self: &Arc<Self>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(manager) = manager.upgrade() else {
+                    break;
+                };
+                while let Some(victim) = manager.resolve_deadlock() {
+                    warn!("Deadlock detected, aborting transaction {}", victim);
                 }
             }
+        })
+    }
+
+    
+    
+    pub fn unlock_all(&self, tx: TxId) {
+        let mut wakers = Vec::new();
+        {
+            let mut tbl = self.table.lock().unwrap();
+            tbl.retain(|_, state| {
+                state.holders.retain(|&(holder_tx, _)| holder_tx != tx);
+                wakers.extend(state.grant_waiters());
+                !state.holders.is_empty() || !state.queue.is_empty()
+            });
+        }
+        // Woken tasks may immediately call back into the lock manager.
+        for waker in wakers {
+            let _ = waker.send(Ok(()));
+        }
+    }
+
+    // Gives everything `from` holds to `to` at once, so a long job that
+    // commits as it goes keeps its locks from one transaction to the next
+    // without a waiter slipping in between.
+    pub fn hand_over(&self, from: TxId, to: TxId) {
+        let mut tbl = self.table.lock().unwrap();
+        for state in tbl.values_mut() {
+            for holder in state.holders.iter_mut().filter(|(tx, _)| *tx == from) {
+                holder.0 = to;
+            }
         }
     }
 
@@ -167,10 +537,17 @@ impl LockManager {
         let tbl = self.table.lock().unwrap();
         
         let mut graph: HashMap<TxId, HashSet<TxId>> = HashMap::new();
-        for (res, state) in tbl.iter() {
+        for state in tbl.values() {
             for req in state.queue.iter() {
                 let waiting = req.tx;
-                let holders: Vec<_> = state.holders.iter().map(|&(t, _)| t).collect();
+                // A transaction upgrading its own lock only waits for the
+                // others holding it.
+                let holders: Vec<_> = state
+                    .holders
+                    .iter()
+                    .map(|&(t, _)| t)
+                    .filter(|&t| t != waiting)
+                    .collect();
                 let entry = graph.entry(waiting).or_default();
                 for h in holders {
                     entry.insert(h);
@@ -213,10 +590,10 @@ impl LockManager {
         }
 
         for &u in graph.keys() {
-            if !visited.contains(&u) {
-                if let Some(cycle) = dfs(u, &graph, &mut visited, &mut on_stack, &mut stack) {
-                    return Some(cycle);
-                }
+            if !visited.contains(&u)
+                && let Some(cycle) = dfs(u, &graph, &mut visited, &mut on_stack, &mut stack)
+            {
+                return Some(cycle);
             }
         }
         None
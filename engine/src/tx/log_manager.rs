@@ -1,19 +1,110 @@
+use crate::storage::atomic_file::{atomic_read, atomic_write};
+use crate::storage::failpoint;
+use crate::storage::record::RID;
+use crate::storage::sequence::SequenceInfo;
+use crate::storage::storage::{
+    CheckConstraint, ColumnInfo, ForeignKey, Grants, IndexInfo, TableInfo, ViewInfo,
+};
+use crate::tx::wal_reader::WalReader;
 
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow, bail};
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
-    io::{BufWriter, Write},
-    path::PathBuf,
+    collections::{BTreeMap, HashMap, VecDeque},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
+use tracing::{error, warn};
 
 
 pub type Lsn = u64;
 
 pub type TxId = u64;
 
+// Every WAL segment starts with the logical offset of its first record.
+// Offsets handed out by the log manager are logical, so they stay valid when
+// old segments are deleted.
+pub const WAL_HEADER_SIZE: u64 = 8;
+
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+// When commit and abort records reach disk. Only `PerCommit` makes a commit
+// durable before it returns; the others trade the last few commits before a
+// crash for fewer fsyncs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    #[default]
+    PerCommit,
+    // Flushed by the task `LogManager::spawn_flusher` starts.
+    Interval(Duration),
+    // Flushed once this many bytes of records are buffered.
+    OnBufferFull(u64),
+}
+
+// Called after every fsync of the log.
+pub type SyncHook = Arc<dyn Fn() + Send + Sync>;
+
+pub fn read_wal_base(file: &mut File) -> Result<u64> {
+    let mut buf = [0u8; WAL_HEADER_SIZE as usize];
+    file.read_exact(&mut buf).context("reading WAL header")?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn segment_path(wal_path: &Path, segment: u64) -> PathBuf {
+    with_suffix(wal_path, &format!(".{:06}", segment))
+}
+
+// Segments `oldest_segment..=active_segment` make up the log; anything older
+// has been truncated or archived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Manifest {
+    pub oldest_segment: u64,
+    pub active_segment: u64,
+}
+
+impl Manifest {
+    pub fn path(wal_path: &Path) -> PathBuf {
+        with_suffix(wal_path, ".manifest")
+    }
+
+    pub fn read(wal_path: &Path) -> Result<Option<Self>> {
+        let path = Self::path(wal_path);
+        let Some(buf) = atomic_read(&path)? else {
+            return Ok(None);
+        };
+        if buf.len() != 16 {
+            bail!("Malformed WAL manifest {:?} of {} bytes", path, buf.len());
+        }
+        Ok(Some(Manifest {
+            oldest_segment: LittleEndian::read_u64(&buf[0..8]),
+            active_segment: LittleEndian::read_u64(&buf[8..16]),
+        }))
+    }
+
+    fn write(&self, wal_path: &Path) -> Result<()> {
+        let mut buf = Vec::with_capacity(16);
+        buf.extend_from_slice(&self.oldest_segment.to_le_bytes());
+        buf.extend_from_slice(&self.active_segment.to_le_bytes());
+        atomic_write(&Self::path(wal_path), &buf)
+    }
+
+    pub fn segments(&self) -> std::ops::RangeInclusive<u64> {
+        self.oldest_segment..=self.active_segment
+    }
+}
+
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LogRecordType {
@@ -21,7 +112,343 @@ pub enum LogRecordType {
     Commit,
     Abort,
     Update,
-    
+    Checkpoint,
+    Compensation,
+    CreateTable,
+    CreateIndex,
+    DropTable,
+    Grant,
+    CreateSequence,
+    DropSequence,
+    ReserveSequence,
+    CreateView,
+    DropView,
+    AddForeignKey,
+    AddCheck,
+    RenameTable,
+    RenameIndex,
+    CreateSchema,
+    DropSchema,
+    AddRows,
+    RemoveRows,
+}
+
+impl LogRecordType {
+    pub fn is_ddl(self) -> bool {
+        matches!(
+            self,
+            LogRecordType::CreateTable
+                | LogRecordType::CreateIndex
+                | LogRecordType::DropTable
+                | LogRecordType::Grant
+                | LogRecordType::CreateSequence
+                | LogRecordType::DropSequence
+                | LogRecordType::ReserveSequence
+                | LogRecordType::CreateView
+                | LogRecordType::DropView
+                | LogRecordType::AddForeignKey
+                | LogRecordType::AddCheck
+                | LogRecordType::RenameTable
+                | LogRecordType::RenameIndex
+                | LogRecordType::CreateSchema
+                | LogRecordType::DropSchema
+                | LogRecordType::AddRows
+                | LogRecordType::RemoveRows
+        )
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdatePayload {
+    pub page_no: u64,
+    pub offset: u32,
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+}
+
+impl UpdatePayload {
+    pub const HEADER_SIZE: usize = 12;
+
+    pub fn encode(&self) -> Vec<u8> {
+        debug_assert_eq!(self.before.len(), self.after.len());
+        let mut buf = Vec::with_capacity(Self::HEADER_SIZE + 2 * self.after.len());
+        buf.extend_from_slice(&self.page_no.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.before);
+        buf.extend_from_slice(&self.after);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::HEADER_SIZE || !(buf.len() - Self::HEADER_SIZE).is_multiple_of(2) {
+            bail!("Malformed update payload of {} bytes", buf.len());
+        }
+        let half = (buf.len() - Self::HEADER_SIZE) / 2;
+        let images = &buf[Self::HEADER_SIZE..];
+        Ok(UpdatePayload {
+            page_no: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            offset: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            before: images[..half].to_vec(),
+            after: images[half..].to_vec(),
+        })
+    }
+}
+
+// What a DDL statement logs before it touches the catalog, so that redo
+// can put the change back once its transaction has committed and undo can
+// take it out again otherwise. Catalog entries are few and small, so they
+// go in the log as JSON rather than a layout of their own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DdlPayload {
+    CreateTable {
+        name: String,
+        columns: Vec<ColumnInfo>,
+    },
+    // `pages` are the ones the build allocated, which undo frees.
+    CreateIndex {
+        index: IndexInfo,
+        pages: Vec<u64>,
+    },
+    // The whole entry, so undo can put it back as it was.
+    DropTable {
+        table: TableInfo,
+        indexes: Vec<IndexInfo>,
+        #[serde(default)]
+        grants: BTreeMap<String, Grants>,
+    },
+    // GRANT or REVOKE: what `user` had on `table` before and after.
+    Grant {
+        table: String,
+        user: String,
+        before: Grants,
+        after: Grants,
+    },
+    CreateSequence {
+        sequence: SequenceInfo,
+    },
+    // As it was when dropped, so undo can put it back.
+    DropSequence {
+        sequence: SequenceInfo,
+    },
+    // Values of `name` up to `end` may be handed out. A reservation holds
+    // whether or not its transaction commits, as the values it covers may
+    // already be in use.
+    ReserveSequence {
+        name: String,
+        end: i64,
+    },
+    CreateView {
+        view: ViewInfo,
+    },
+    DropView {
+        view: ViewInfo,
+    },
+    // A foreign key of `table`, added as the table is created.
+    AddForeignKey {
+        table: String,
+        key: ForeignKey,
+    },
+    AddCheck {
+        table: String,
+        check: CheckConstraint,
+    },
+    // Undo renames it back.
+    RenameTable {
+        from: String,
+        to: String,
+    },
+    RenameIndex {
+        table: String,
+        from: String,
+        to: String,
+    },
+    CreateSchema {
+        name: String,
+    },
+    // Only once its tables and views were dropped, each on its own.
+    DropSchema {
+        name: String,
+    },
+    // Rows stored for `table`, in the order it lists them. The heap pages
+    // do not say which table a row is of, so the row lists are rebuilt from
+    // these.
+    AddRows {
+        table: String,
+        rows: Vec<RID>,
+    },
+    // Rows whose slots VACUUM took back.
+    RemoveRows {
+        table: String,
+        rows: Vec<RID>,
+    },
+}
+
+impl DdlPayload {
+    pub fn record_type(&self) -> LogRecordType {
+        match self {
+            DdlPayload::CreateTable { .. } => LogRecordType::CreateTable,
+            DdlPayload::CreateIndex { .. } => LogRecordType::CreateIndex,
+            DdlPayload::DropTable { .. } => LogRecordType::DropTable,
+            DdlPayload::Grant { .. } => LogRecordType::Grant,
+            DdlPayload::CreateSequence { .. } => LogRecordType::CreateSequence,
+            DdlPayload::DropSequence { .. } => LogRecordType::DropSequence,
+            DdlPayload::ReserveSequence { .. } => LogRecordType::ReserveSequence,
+            DdlPayload::CreateView { .. } => LogRecordType::CreateView,
+            DdlPayload::DropView { .. } => LogRecordType::DropView,
+            DdlPayload::AddForeignKey { .. } => LogRecordType::AddForeignKey,
+            DdlPayload::AddCheck { .. } => LogRecordType::AddCheck,
+            DdlPayload::RenameTable { .. } => LogRecordType::RenameTable,
+            DdlPayload::RenameIndex { .. } => LogRecordType::RenameIndex,
+            DdlPayload::CreateSchema { .. } => LogRecordType::CreateSchema,
+            DdlPayload::DropSchema { .. } => LogRecordType::DropSchema,
+            DdlPayload::AddRows { .. } => LogRecordType::AddRows,
+            DdlPayload::RemoveRows { .. } => LogRecordType::RemoveRows,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("catalog entries always serialize")
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        serde_json::from_slice(buf).context("Malformed DDL payload")
+    }
+}
+
+// A compensation log record (CLR) is written for every update undone. It is
+// redo-only: `update.after` is the before image it put back, and `undo_next`
+// is the next record of the transaction still to be undone (0 when nothing
+// is left), so an undo interrupted by a crash resumes where it stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompensationPayload {
+    pub undo_next: Lsn,
+    pub update: UpdatePayload,
+}
+
+impl CompensationPayload {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.undo_next.to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.update.encode());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            bail!("Malformed compensation payload of {} bytes", buf.len());
+        }
+        Ok(CompensationPayload {
+            undo_next: LittleEndian::read_u64(&buf[0..8]),
+            update: UpdatePayload::decode(&buf[8..])?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveTx {
+    pub tx_id: TxId,
+    pub last_lsn: Lsn,
+    // Byte offset in the WAL of the transaction's first record, so undo can
+    // start there instead of at the beginning of the file.
+    pub first_offset: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckpointPayload {
+    pub dirty_pages: Vec<(u64, Lsn)>,
+    pub active_txns: Vec<ActiveTx>,
+    // Highest transaction id written to the log so far. The records that
+    // carried it may be truncated away, but ids must never be reused.
+    pub max_tx_id: TxId,
+}
+
+impl CheckpointPayload {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf =
+            Vec::with_capacity(16 + 16 * self.dirty_pages.len() + 24 * self.active_txns.len());
+        buf.extend_from_slice(&(self.dirty_pages.len() as u32).to_le_bytes());
+        for &(page_no, lsn) in &self.dirty_pages {
+            buf.extend_from_slice(&page_no.to_le_bytes());
+            buf.extend_from_slice(&lsn.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.active_txns.len() as u32).to_le_bytes());
+        for tx in &self.active_txns {
+            buf.extend_from_slice(&tx.tx_id.to_le_bytes());
+            buf.extend_from_slice(&tx.last_lsn.to_le_bytes());
+            buf.extend_from_slice(&tx.first_offset.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.max_tx_id.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let malformed = || anyhow!("Malformed checkpoint payload of {} bytes", buf.len());
+        let mut pos = 0;
+        let read_u64 = |pos: &mut usize| -> Result<u64> {
+            let v = buf.get(*pos..*pos + 8).ok_or_else(malformed)?;
+            *pos += 8;
+            Ok(LittleEndian::read_u64(v))
+        };
+        let read_count = |pos: &mut usize| -> Result<usize> {
+            let v = buf.get(*pos..*pos + 4).ok_or_else(malformed)?;
+            *pos += 4;
+            Ok(LittleEndian::read_u32(v) as usize)
+        };
+
+        let mut payload = CheckpointPayload::default();
+        for _ in 0..read_count(&mut pos)? {
+            payload
+                .dirty_pages
+                .push((read_u64(&mut pos)?, read_u64(&mut pos)?));
+        }
+        for _ in 0..read_count(&mut pos)? {
+            payload.active_txns.push(ActiveTx {
+                tx_id: read_u64(&mut pos)?,
+                last_lsn: read_u64(&mut pos)?,
+                first_offset: read_u64(&mut pos)?,
+            });
+        }
+        payload.max_tx_id = read_u64(&mut pos)?;
+        if pos != buf.len() {
+            return Err(malformed());
+        }
+        Ok(payload)
+    }
+}
+
+// The master record lives next to the WAL and points at the most recent
+// checkpoint, so recovery can seek straight to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MasterRecord {
+    pub checkpoint_lsn: Lsn,
+    pub offset: u64,
+}
+
+impl MasterRecord {
+    pub fn path(wal_path: &Path) -> PathBuf {
+        with_suffix(wal_path, ".master")
+    }
+
+    pub fn read(wal_path: &Path) -> Result<Option<Self>> {
+        let path = Self::path(wal_path);
+        let Some(buf) = atomic_read(&path)? else {
+            return Ok(None);
+        };
+        if buf.len() != 16 {
+            bail!("Malformed master record {:?} of {} bytes", path, buf.len());
+        }
+        Ok(Some(MasterRecord {
+            checkpoint_lsn: LittleEndian::read_u64(&buf[0..8]),
+            offset: LittleEndian::read_u64(&buf[8..16]),
+        }))
+    }
+
+    fn write(&self, wal_path: &Path) -> Result<()> {
+        let mut buf = Vec::with_capacity(16);
+        buf.extend_from_slice(&self.checkpoint_lsn.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        atomic_write(&Self::path(wal_path), &buf)
+    }
 }
 
 
@@ -43,27 +470,84 @@ pub struct LogRecord {
 }
 
 impl LogRecord {
+    fn encoded_len(&self) -> u64 {
+        (4 + 8 + 8 + 8 + 1 + 4 + self.payload.len() + CRC_SIZE) as u64
+    }
+
     
     
     fn serialize(&self) -> Vec<u8> {
         
         let header_size = 8 + 8 + 8 + 1 + 4;
-        let total_size = header_size + self.payload.len();
-        let mut buf = Vec::with_capacity(4 + total_size);
-        buf.extend_from_slice(&(total_size as u32).to_le_bytes());
-        buf.extend_from_slice(&self.header.lsn.to_le_bytes());
-        buf.extend_from_slice(&self.header.prev_lsn.unwrap_or(0).to_le_bytes());
-        buf.extend_from_slice(&self.header.tx_id.to_le_bytes());
-        buf.push(self.header.typ as u8);
-        buf.extend_from_slice(&self.header.payload_len.to_le_bytes());
-        buf.extend_from_slice(&self.payload);
+        let mut body = Vec::with_capacity(header_size + self.payload.len());
+        body.extend_from_slice(&self.header.lsn.to_le_bytes());
+        body.extend_from_slice(&self.header.prev_lsn.unwrap_or(0).to_le_bytes());
+        body.extend_from_slice(&self.header.tx_id.to_le_bytes());
+        body.push(self.header.typ as u8);
+        body.extend_from_slice(&self.header.payload_len.to_le_bytes());
+        body.extend_from_slice(&self.payload);
+        let mut buf = Vec::with_capacity(4 + body.len() + CRC_SIZE);
+        write_frame(&mut buf, &body);
         buf
     }
 }
 
+// Appends `body` to `buf` framed the way `read_frame` expects it.
+pub fn write_frame(buf: &mut Vec<u8>, body: &[u8]) {
+    let start = buf.len();
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(body);
+    let crc = crc32fast::hash(&buf[start..]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+}
+
+const CRC_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameRead {
+    Record(Vec<u8>),
+    End,
+    // A short read or a checksum mismatch: whatever follows is the remains
+    // of a write that never completed.
+    Torn,
+}
+
+// Each record on disk is `len | body | crc32(len | body)`.
+pub fn read_frame(file: &mut File) -> Result<FrameRead> {
+    let remaining = file
+        .metadata()?
+        .len()
+        .saturating_sub(file.stream_position()?);
+    if remaining == 0 {
+        return Ok(FrameRead::End);
+    }
+    let mut len_buf = [0u8; 4];
+    if remaining < 4 {
+        return Ok(FrameRead::Torn);
+    }
+    file.read_exact(&mut len_buf)?;
+    let len = u32::from_le_bytes(len_buf) as u64;
+    if remaining < 4 + len + CRC_SIZE as u64 {
+        return Ok(FrameRead::Torn);
+    }
+    let mut body = vec![0u8; len as usize];
+    file.read_exact(&mut body)?;
+    let mut crc_buf = [0u8; CRC_SIZE];
+    file.read_exact(&mut crc_buf)?;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len_buf);
+    hasher.update(&body);
+    if hasher.finalize() != u32::from_le_bytes(crc_buf) {
+        return Ok(FrameRead::Torn);
+    }
+    Ok(FrameRead::Record(body))
+}
+
 
 pub struct LogManager {
     inner: Arc<Mutex<LogManagerInner>>,
+    path: PathBuf,
+    archive_dir: Option<PathBuf>,
 }
 
 struct LogManagerInner {
@@ -76,31 +560,147 @@ struct LogManagerInner {
     
     flushed_lsn: Lsn,
     
-    buffer: Vec<LogRecord>,
+    buffer: VecDeque<LogRecord>,
+
+    path: PathBuf,
+
+    segment_size: u64,
+
+    manifest: Manifest,
+
+    active_len: u64,
+
+    base: u64,
+
+    end_offset: u64,
+
+    first_offset: HashMap<TxId, u64>,
+
+    // The first record of each transaction in progress, as far as this
+    // run of the log manager has seen it.
+    first_lsn: HashMap<TxId, Lsn>,
+
+    checkpoint_offset: u64,
+
+    // Bytes of log kept behind the end for AS OF queries, even once
+    // recovery no longer needs them.
+    history_window: u64,
+
+    max_tx_id: TxId,
+
+    flush_policy: FlushPolicy,
+
+    buffered_bytes: u64,
+
+    bytes_written: u64,
+
+    sync_hook: Option<SyncHook>,
+
+    // Set by `set_read_only`.
+    read_only: bool,
+
+    // The last checkpoint taken since it opened, and how long flushing the
+    // pool and logging it took.
+    last_checkpoint: Option<(Lsn, Duration)>,
 }
 
 impl LogManager {
     
     pub fn new(path: PathBuf) -> Result<Self> {
-        let file = OpenOptions::new()
-            .create(true)
+        let manifest = match Manifest::read(&path)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest {
+                    oldest_segment: 1,
+                    active_segment: 1,
+                };
+                create_segment(&path, 1, WAL_HEADER_SIZE)?;
+                manifest.write(&path)?;
+                manifest
+            }
+        };
+        let active = segment_path(&path, manifest.active_segment);
+        let mut file = OpenOptions::new()
             .append(true)
             .read(true)
-            .open(&path)
-            .with_context(|| format!("opening WAL file at {:?}", path))?;
+            .open(&active)
+            .with_context(|| format!("opening WAL segment at {:?}", active))?;
+        let active_base = read_wal_base(&mut file)?;
+        let base = segment_base(&path, manifest.oldest_segment)?;
+        let checkpoint_offset = MasterRecord::read(&path)?.map_or(base, |m| m.offset);
+        // Nothing before the checkpoint is read again, so the search for a
+        // torn tail can start there too.
+        if checkpoint_offset > active_base {
+            file.seek(SeekFrom::Start(
+                checkpoint_offset - active_base + WAL_HEADER_SIZE,
+            ))?;
+        }
+        let active_len = discard_torn_tail(&mut file, &active, active_base)?;
+        let end_offset = active_base + active_len - WAL_HEADER_SIZE;
+        let resumed = ResumeState::scan(&path, checkpoint_offset)?;
         let writer = BufWriter::new(file);
         let inner = LogManagerInner {
             writer,
-            next_lsn: 1,
-            last_lsn: HashMap::new(),
-            flushed_lsn: 0,
-            buffer: Vec::new(),
+            next_lsn: resumed.max_lsn + 1,
+            last_lsn: resumed.last_lsn,
+            flushed_lsn: resumed.max_lsn,
+            buffer: VecDeque::new(),
+            path: path.clone(),
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            manifest,
+            active_len,
+            base,
+            end_offset,
+            first_offset: resumed.first_offset,
+            first_lsn: HashMap::new(),
+            checkpoint_offset,
+            history_window: 0,
+            max_tx_id: resumed.max_tx_id,
+            flush_policy: FlushPolicy::default(),
+            buffered_bytes: 0,
+            bytes_written: 0,
+            sync_hook: None,
+            read_only: false,
+            last_checkpoint: None,
         };
         Ok(LogManager {
             inner: Arc::new(Mutex::new(inner)),
+            path,
+            archive_dir: None,
         })
     }
 
+    // Once the active segment reaches this many bytes the next record goes
+    // into a fresh one.
+    pub fn with_segment_size(self, bytes: u64) -> Self {
+        self.inner.lock().unwrap().segment_size = bytes.max(WAL_HEADER_SIZE + 1);
+        self
+    }
+
+    pub fn with_flush_policy(self, policy: FlushPolicy) -> Self {
+        self.inner.lock().unwrap().flush_policy = policy;
+        self
+    }
+
+    pub fn with_sync_hook(self, hook: SyncHook) -> Self {
+        self.inner.lock().unwrap().sync_hook = Some(hook);
+        self
+    }
+
+    // Truncation keeps at least the last `bytes` of the log, so AS OF
+    // queries can look back that far.
+    pub fn with_history_window(self, bytes: u64) -> Self {
+        self.inner.lock().unwrap().history_window = bytes;
+        self
+    }
+
+    // Records truncated off the front of the log are moved here instead of
+    // being discarded.
+    pub fn with_archive_dir(mut self, dir: PathBuf) -> Self {
+        self.archive_dir = Some(dir);
+        self
+    }
+
     
     pub fn log_begin(&self, tx_id: TxId) -> Result<Lsn> {
         self.append_record(tx_id, LogRecordType::Begin, Vec::new())
@@ -109,27 +709,127 @@ impl LogManager {
     
     pub fn log_commit(&self, tx_id: TxId) -> Result<Lsn> {
         let lsn = self.append_record(tx_id, LogRecordType::Commit, Vec::new())?;
-        self.flush(lsn)?;
+        self.flush_for_commit(lsn)?;
+        self.end_transaction(tx_id);
         Ok(lsn)
     }
 
     
     pub fn log_abort(&self, tx_id: TxId) -> Result<Lsn> {
         let lsn = self.append_record(tx_id, LogRecordType::Abort, Vec::new())?;
-        self.flush(lsn)?;
+        self.flush_for_commit(lsn)?;
+        self.end_transaction(tx_id);
+        Ok(lsn)
+    }
+
+    fn flush_for_commit(&self, lsn: Lsn) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.flush_policy {
+            FlushPolicy::PerCommit => inner.flush_to(lsn),
+            FlushPolicy::Interval(_) | FlushPolicy::OnBufferFull(_) => Ok(()),
+        }
+    }
+
+    fn end_transaction(&self, tx_id: TxId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_lsn.remove(&tx_id);
+        inner.first_offset.remove(&tx_id);
+        inner.first_lsn.remove(&tx_id);
+    }
+
+    // Writes a checkpoint record carrying the dirty page table and every
+    // transaction that has not yet committed or aborted, then points the
+    // master record at it. Callers flush the buffer pool first so that redo
+    // can start at the checkpoint.
+    pub fn log_checkpoint(&self, dirty_pages: Vec<(u64, Lsn)>) -> Result<Lsn> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.read_only {
+            bail!("The WAL is read-only; no checkpoint was taken");
+        }
+        let pending = inner.next_lsn - 1;
+        inner.flush_to(pending)?;
+
+        let mut active_txns: Vec<ActiveTx> = inner
+            .last_lsn
+            .iter()
+            .map(|(&tx_id, &last_lsn)| ActiveTx {
+                tx_id,
+                last_lsn,
+                first_offset: inner.first_offset.get(&tx_id).copied().unwrap_or(0),
+            })
+            .collect();
+        active_txns.sort_by_key(|tx| tx.tx_id);
+        let payload = CheckpointPayload {
+            dirty_pages,
+            active_txns,
+            max_tx_id: inner.max_tx_id,
+        }
+        .encode();
+
+        let lsn = inner.next_lsn;
+        inner.next_lsn += 1;
+        let offset = inner.end_offset;
+        let record = LogRecord {
+            header: LogRecordHeader {
+                lsn,
+                prev_lsn: None,
+                tx_id: 0,
+                typ: LogRecordType::Checkpoint,
+                payload_len: payload.len() as u32,
+            },
+            payload,
+        };
+        inner.buffered_bytes += record.encoded_len();
+        inner.buffer.push_back(record);
+        inner.flush_to(lsn)?;
+        MasterRecord {
+            checkpoint_lsn: lsn,
+            offset,
+        }
+        .write(&self.path)?;
+        inner.checkpoint_offset = offset;
         Ok(lsn)
     }
 
     
+    // From here on nothing is appended. Transactions that only read still
+    // begin and end, without a record; anything else, checkpoints included,
+    // is refused.
+    pub fn set_read_only(&self) {
+        self.inner.lock().unwrap().read_only = true;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.inner.lock().unwrap().read_only
+    }
+
     pub fn log_update(&self, tx_id: TxId, payload: Vec<u8>) -> Result<Lsn> {
         self.append_record(tx_id, LogRecordType::Update, payload)
     }
 
+    pub fn log_compensation(&self, tx_id: TxId, payload: Vec<u8>) -> Result<Lsn> {
+        self.append_record(tx_id, LogRecordType::Compensation, payload)
+    }
+
+    pub fn log_ddl(&self, tx_id: TxId, ddl: &DdlPayload) -> Result<Lsn> {
+        self.append_record(tx_id, ddl.record_type(), ddl.encode())
+    }
+
     
     fn append_record(&self, tx_id: TxId, typ: LogRecordType, payload: Vec<u8>) -> Result<Lsn> {
         let mut inner = self.inner.lock().unwrap();
+        if inner.read_only {
+            return match typ {
+                LogRecordType::Begin | LogRecordType::Commit | LogRecordType::Abort => {
+                    Ok(inner.next_lsn - 1)
+                }
+                _ => Err(anyhow!("The WAL is read-only; {:?} was not logged", typ)),
+            };
+        }
         let lsn = inner.next_lsn;
         let prev = inner.last_lsn.insert(tx_id, lsn);
+        inner.first_lsn.entry(tx_id).or_insert(lsn);
+        inner.max_tx_id = inner.max_tx_id.max(tx_id);
         let header = LogRecordHeader {
             lsn,
             prev_lsn: prev,
@@ -138,44 +838,387 @@ impl LogManager {
             payload_len: payload.len() as u32,
         };
         let record = LogRecord { header, payload };
-        inner.buffer.push(record);
+        inner.buffered_bytes += record.encoded_len();
+        inner.buffer.push_back(record);
         inner.next_lsn += 1;
+        if let FlushPolicy::OnBufferFull(limit) = inner.flush_policy
+            && inner.buffered_bytes >= limit
+        {
+            inner.flush_to(lsn)?;
+        }
         Ok(lsn)
     }
 
     
     pub fn flush(&self, target_lsn: Lsn) -> Result<()> {
+        self.inner.lock().unwrap().flush_to(target_lsn)
+    }
+
+    
+    // Writes out everything appended so far.
+    pub fn flush_all(&self) -> Result<()> {
         let mut inner = self.inner.lock().unwrap();
-        
-        let mut to_write = Vec::new();
-        while let Some(rec) = inner.buffer.first() {
-            if rec.header.lsn <= target_lsn {
-                to_write.push(inner.buffer.remove(0));
-            } else {
+        let last = inner.next_lsn - 1;
+        inner.flush_to(last)
+    }
+
+    // Under `FlushPolicy::Interval`, flushes the log on that interval until
+    // the log manager is dropped. Other policies need no background task.
+    pub fn spawn_flusher(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let FlushPolicy::Interval(interval) = self.inner.lock().unwrap().flush_policy else {
+            return None;
+        };
+        let wal = Arc::downgrade(self);
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(wal) = wal.upgrade() else {
+                    break;
+                };
+                if let Err(e) = wal.flush_all() {
+                    error!("Background WAL flush failed: {:#}", e);
+                }
+            }
+        }))
+    }
+
+    pub fn flushed_lsn(&self) -> Lsn {
+        let inner = self.inner.lock().unwrap();
+        inner.flushed_lsn
+    }
+
+    // The highest transaction id in the log, including ids from before the
+    // last checkpoint. New transactions must start above it.
+    pub fn max_tx_id(&self) -> TxId {
+        self.inner.lock().unwrap().max_tx_id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn last_lsn(&self, tx_id: TxId) -> Option<Lsn> {
+        let inner = self.inner.lock().unwrap();
+        inner.last_lsn.get(&tx_id).copied()
+    }
+
+    pub fn first_offset(&self, tx_id: TxId) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner.first_offset.get(&tx_id).copied()
+    }
+
+    // The first LSN of the oldest transaction in progress but `except`, or
+    // 0 for one resumed from before a restart. A page stamped since then
+    // may hold its changes, which undo would copy back byte for byte.
+    pub fn oldest_open_lsn(&self, except: Option<TxId>) -> Option<Lsn> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .last_lsn
+            .keys()
+            .filter(|&&tx_id| Some(tx_id) != except)
+            .map(|tx_id| inner.first_lsn.get(tx_id).copied().unwrap_or(0))
+            .min()
+    }
+
+    // Where the latest checkpoint's record starts.
+    pub fn checkpoint_offset(&self) -> u64 {
+        self.inner.lock().unwrap().checkpoint_offset
+    }
+
+    pub fn bytes_since_checkpoint(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.end_offset - inner.checkpoint_offset
+    }
+
+    // Noted by `recovery_manager::checkpoint`, for /metrics.
+    pub fn checkpoint_took(&self, lsn: Lsn, elapsed: Duration) {
+        self.inner.lock().unwrap().last_checkpoint = Some((lsn, elapsed));
+    }
+
+    pub fn last_checkpoint(&self) -> Option<(Lsn, Duration)> {
+        self.inner.lock().unwrap().last_checkpoint
+    }
+
+    // Bytes written to the log since this log manager opened it.
+    pub fn bytes_written(&self) -> u64 {
+        self.inner.lock().unwrap().bytes_written
+    }
+
+    // Fails if the active segment can no longer be written to.
+    pub fn check_writable(&self) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        let metadata = inner
+            .writer
+            .get_ref()
+            .metadata()
+            .context("reading WAL segment metadata")?;
+        if metadata.permissions().readonly() {
+            bail!("WAL segment is read-only");
+        }
+        Ok(())
+    }
+
+    // Offset just past the last record written out, where the next one goes.
+    pub fn end_offset(&self) -> u64 {
+        self.inner.lock().unwrap().end_offset
+    }
+
+    // Moves LSN allocation past `lsn`, e.g. the last change a standby
+    // replayed, so the pages it stamped never look newer than what this log
+    // writes next.
+    pub fn advance_lsn_past(&self, lsn: Lsn) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_lsn = inner.next_lsn.max(lsn + 1);
+    }
+
+    pub fn base_offset(&self) -> u64 {
+        self.inner.lock().unwrap().base
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        self.inner.lock().unwrap().manifest
+    }
+
+    // Drops every segment that recovery can no longer need: those lying
+    // entirely before the latest checkpoint and before the first record of
+    // every still-active transaction, and older than the history window.
+    // The active segment is never dropped. Returns the number of bytes
+    // removed.
+    pub fn truncate(&self) -> Result<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let history = inner.end_offset.saturating_sub(inner.history_window);
+        let keep_from = inner
+            .last_lsn
+            .keys()
+            .filter_map(|tx| inner.first_offset.get(tx))
+            .fold(inner.checkpoint_offset.min(history), |min, &off| {
+                min.min(off)
+            });
+
+        let mut oldest = inner.manifest.oldest_segment;
+        let mut new_base = inner.base;
+        while oldest < inner.manifest.active_segment {
+            let next_base = segment_base(&self.path, oldest + 1)?;
+            if next_base > keep_from {
+                break;
+            }
+            oldest += 1;
+            new_base = next_base;
+        }
+        if oldest == inner.manifest.oldest_segment {
+            return Ok(0);
+        }
+
+        let dropped = inner.manifest.oldest_segment..oldest;
+        inner.manifest.oldest_segment = oldest;
+        inner.manifest.write(&self.path)?;
+        for segment in dropped {
+            let from = segment_path(&self.path, segment);
+            match &self.archive_dir {
+                Some(dir) => {
+                    fs::create_dir_all(dir).with_context(|| format!("creating {:?}", dir))?;
+                    let to = dir.join(from.file_name().unwrap_or_default());
+                    fs::rename(&from, &to)
+                        .or_else(|_| fs::copy(&from, &to).and_then(|_| fs::remove_file(&from)))
+                        .with_context(|| format!("archiving {:?} to {:?}", from, to))?;
+                }
+                None => fs::remove_file(&from)
+                    .with_context(|| format!("removing WAL segment {:?}", from))?,
+            }
+        }
+        let removed = new_base - inner.base;
+        inner.base = new_base;
+        Ok(removed)
+    }
+}
+
+fn create_segment(wal_path: &Path, segment: u64, base: u64) -> Result<File> {
+    let path = segment_path(wal_path, segment);
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .read(true)
+        .open(&path)
+        .with_context(|| format!("creating WAL segment {:?}", path))?;
+    file.write_all(&base.to_le_bytes())?;
+    file.sync_data()?;
+    Ok(file)
+}
+
+// What a log manager needs to carry on from an existing log: where LSNs
+// stopped, which transaction ids are taken, and the transactions that were
+// still running.
+struct ResumeState {
+    max_lsn: Lsn,
+    max_tx_id: TxId,
+    last_lsn: HashMap<TxId, Lsn>,
+    first_offset: HashMap<TxId, u64>,
+}
+
+impl ResumeState {
+    fn scan(path: &Path, from: u64) -> Result<Self> {
+        let mut state = ResumeState {
+            max_lsn: 0,
+            max_tx_id: 0,
+            last_lsn: HashMap::new(),
+            first_offset: HashMap::new(),
+        };
+        let mut reader = WalReader::open(path)?;
+        reader.seek(from)?;
+        loop {
+            let offset = reader.position()?;
+            let Some(record) = reader.next_record()? else {
                 break;
+            };
+            let hdr = &record.header;
+            state.max_lsn = state.max_lsn.max(hdr.lsn);
+            state.max_tx_id = state.max_tx_id.max(hdr.tx_id);
+            match hdr.typ {
+                LogRecordType::Checkpoint => {
+                    let payload = CheckpointPayload::decode(&record.payload)?;
+                    state.max_tx_id = state.max_tx_id.max(payload.max_tx_id);
+                    for tx in payload.active_txns {
+                        state.last_lsn.entry(tx.tx_id).or_insert(tx.last_lsn);
+                        state
+                            .first_offset
+                            .entry(tx.tx_id)
+                            .or_insert(tx.first_offset);
+                    }
+                }
+                LogRecordType::Commit | LogRecordType::Abort => {
+                    state.last_lsn.remove(&hdr.tx_id);
+                    state.first_offset.remove(&hdr.tx_id);
+                }
+                LogRecordType::Begin
+                | LogRecordType::Update
+                | LogRecordType::Compensation
+                | LogRecordType::CreateTable
+                | LogRecordType::CreateIndex
+                | LogRecordType::DropTable
+                | LogRecordType::Grant
+                | LogRecordType::CreateSequence
+                | LogRecordType::DropSequence
+                | LogRecordType::ReserveSequence
+                | LogRecordType::CreateView
+                | LogRecordType::DropView
+                | LogRecordType::AddForeignKey
+                | LogRecordType::AddCheck
+                | LogRecordType::RenameTable
+                | LogRecordType::RenameIndex
+                | LogRecordType::CreateSchema
+                | LogRecordType::DropSchema
+                | LogRecordType::AddRows
+                | LogRecordType::RemoveRows => {
+                    state.last_lsn.insert(hdr.tx_id, hdr.lsn);
+                    state.first_offset.entry(hdr.tx_id).or_insert(offset);
+                }
             }
         }
+        Ok(state)
+    }
+}
+
+// Cuts the active segment back to its last intact record, so new records are
+// never appended after garbage that readers would stop at. Returns the
+// segment's length afterwards.
+fn discard_torn_tail(file: &mut File, path: &Path, base: u64) -> Result<u64> {
+    loop {
+        let pos = file.stream_position()?;
+        match read_frame(file)? {
+            FrameRead::Record(_) => {}
+            FrameRead::End => return Ok(pos),
+            FrameRead::Torn => {
+                warn!(
+                    "Torn WAL record in {:?} at offset {}, discarding {} bytes",
+                    path,
+                    base + pos - WAL_HEADER_SIZE,
+                    file.metadata()?.len() - pos
+                );
+                file.set_len(pos)?;
+                file.sync_data()?;
+                file.seek(SeekFrom::Start(pos))?;
+                return Ok(pos);
+            }
+        }
+    }
+}
+
+fn segment_base(wal_path: &Path, segment: u64) -> Result<u64> {
+    let path = segment_path(wal_path, segment);
+    let mut file = File::open(&path).with_context(|| format!("opening WAL segment {:?}", path))?;
+    read_wal_base(&mut file)
+}
+
+impl LogManagerInner {
+    fn flush_to(&mut self, target_lsn: Lsn) -> Result<()> {
+        // Before anything leaves the buffer, so a failed flush can be retried.
+        failpoint::hit(failpoint::WAL_FLUSH)?;
+        let count = self
+            .buffer
+            .iter()
+            .take_while(|rec| rec.header.lsn <= target_lsn)
+            .count();
+        let to_write: Vec<LogRecord> = self.buffer.drain(..count).collect();
         
+        // Nothing new to write: whatever is on disk has been synced already.
+        if to_write.is_empty() {
+            self.flushed_lsn = self.flushed_lsn.max(target_lsn.min(self.next_lsn - 1));
+            return Ok(());
+        }
         for rec in to_write.iter() {
+            self.buffered_bytes -= rec.encoded_len();
             let bytes = rec.serialize();
-            inner
-                .writer
+            self.writer
                 .write_all(&bytes)
                 .with_context(|| format!("writing WAL record lsn={}", rec.header.lsn))?;
+            // A transaction that has already ended, its records flushed
+            // after the commit under a lazy policy, needs no entry.
+            if self.last_lsn.contains_key(&rec.header.tx_id) {
+                self.first_offset
+                    .entry(rec.header.tx_id)
+                    .or_insert(self.end_offset);
+            }
+            self.end_offset += bytes.len() as u64;
+            self.bytes_written += bytes.len() as u64;
+            self.active_len += bytes.len() as u64;
+            if self.active_len >= self.segment_size {
+                self.roll_segment()?;
+            }
         }
-        inner.writer.flush().context("flushing WAL BufWriter")?;
-        inner
-            .writer
+        self.sync()?;
+        // Never past the last record that exists, or records appended later
+        // would count as flushed without ever being written.
+        self.flushed_lsn = self.flushed_lsn.max(target_lsn.min(self.next_lsn - 1));
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush().context("flushing WAL BufWriter")?;
+        self.writer
             .get_ref()
             .sync_data()
             .context("fsync WAL file")?;
-        inner.flushed_lsn = target_lsn;
+        if let Some(hook) = &self.sync_hook {
+            hook();
+        }
         Ok(())
     }
 
-    
-    pub fn flushed_lsn(&self) -> Lsn {
-        let inner = self.inner.lock().unwrap();
-        inner.flushed_lsn
+    // The new segment is created before the manifest points at it, so a
+    // crash in between leaves only an unused empty file behind.
+    fn roll_segment(&mut self) -> Result<()> {
+        self.sync()?;
+        let next = self.manifest.active_segment + 1;
+        let stale = segment_path(&self.path, next);
+        if stale.exists() {
+            fs::remove_file(&stale)?;
+        }
+        let file = create_segment(&self.path, next, self.end_offset)?;
+        self.manifest.active_segment = next;
+        self.manifest.write(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.active_len = WAL_HEADER_SIZE;
+        Ok(())
     }
 }
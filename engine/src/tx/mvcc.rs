@@ -0,0 +1,230 @@
+use crate::tx::log_manager::TxId;
+use byteorder::{ByteOrder, LittleEndian};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+// Every stored row starts with the ids of the transactions that created and
+// deleted it. 0 stands for "nobody": rows written outside a transaction are
+// visible to everyone, and a live row has no deleter.
+pub const ROW_HEADER_SIZE: usize = 16;
+
+// DELETE sets the top bit of the deleter's id as well, so a scan can pass
+// over a live row by looking at that one byte.
+const TOMBSTONE: u64 = 1 << 63;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RowHeader {
+    pub xmin: TxId,
+    pub xmax: TxId,
+    pub tombstone: bool,
+}
+
+impl RowHeader {
+    pub fn read(data: &[u8]) -> Self {
+        let xmax = LittleEndian::read_u64(&data[8..16]);
+        RowHeader {
+            xmin: LittleEndian::read_u64(&data[0..8]),
+            xmax: xmax & !TOMBSTONE,
+            tombstone: xmax & TOMBSTONE != 0,
+        }
+    }
+
+    pub fn write(&self, data: &mut [u8]) {
+        LittleEndian::write_u64(&mut data[0..8], self.xmin);
+        let tombstone = if self.tombstone { TOMBSTONE } else { 0 };
+        LittleEndian::write_u64(&mut data[8..16], self.xmax | tombstone);
+    }
+
+    // Whether the stored row `data` has been deleted by anyone, aborted
+    // deleters included, without reading the rest of its header.
+    pub fn is_tombstoned(data: &[u8]) -> bool {
+        data.len() >= ROW_HEADER_SIZE && data[15] & 0x80 != 0
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TxStatus {
+    Active,
+    Committed,
+    Aborted,
+}
+
+// Commit status of every transaction handed out by `begin`. Only active and
+// aborted transactions are stored; any id below `next` without an entry has
+// committed. Ids from before a restart count as committed too, since
+// recovery has already rolled back the losers.
+pub struct TxStatusTable {
+    inner: Mutex<TxStatusInner>,
+}
+
+struct TxStatusInner {
+    next: TxId,
+    statuses: HashMap<TxId, TxStatus>,
+    // For each active transaction that has taken a snapshot, the oldest id
+    // its first one did not see as committed.
+    xmins: HashMap<TxId, TxId>,
+    // Transactions that wrote rows a rollback leaves in place, those of
+    // temporary tables.
+    unlogged: HashSet<TxId>,
+}
+
+impl Default for TxStatusTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxStatusTable {
+    pub fn new() -> Self {
+        TxStatusTable {
+            inner: Mutex::new(TxStatusInner {
+                next: 1,
+                statuses: HashMap::new(),
+                xmins: HashMap::new(),
+                unlogged: HashSet::new(),
+            }),
+        }
+    }
+
+    // Ids are allocated under the same lock snapshots are taken under, so a
+    // snapshot never misses a transaction that began before it.
+    pub fn begin(&self) -> TxId {
+        let mut inner = self.inner.lock().unwrap();
+        let tx = inner.next;
+        inner.next += 1;
+        inner.statuses.insert(tx, TxStatus::Active);
+        tx
+    }
+
+    // Moves id allocation past `tx`, e.g. the highest id found in the WAL on
+    // startup, so a restarted server never hands out an id twice.
+    pub fn advance_past(&self, tx: TxId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next = inner.next.max(tx + 1);
+    }
+
+    // Records a transaction begun on a primary whose log a standby replays.
+    // Its commit or abort arrives later through `commit` and `abort`.
+    pub fn begin_replayed(&self, tx: TxId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next = inner.next.max(tx + 1);
+        inner.statuses.insert(tx, TxStatus::Active);
+    }
+
+    pub fn commit(&self, tx: TxId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.statuses.remove(&tx);
+        inner.xmins.remove(&tx);
+        inner.unlogged.remove(&tx);
+    }
+
+    pub fn abort(&self, tx: TxId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.statuses.insert(tx, TxStatus::Aborted);
+        inner.xmins.remove(&tx);
+    }
+
+    pub fn wrote_unlogged(&self, tx: TxId) {
+        self.inner.lock().unwrap().unlogged.insert(tx);
+    }
+
+    // Drops an aborted transaction once its rollback has undone everything
+    // it wrote, so that no row carries its id and snapshots need not list
+    // it. One that wrote unlogged rows stays aborted for good.
+    pub fn forget(&self, tx: TxId) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.unlogged.contains(&tx) && inner.statuses.get(&tx) == Some(&TxStatus::Aborted) {
+            inner.statuses.remove(&tx);
+        }
+    }
+
+    pub fn status(&self, tx: TxId) -> TxStatus {
+        let inner = self.inner.lock().unwrap();
+        match inner.statuses.get(&tx) {
+            Some(&status) => status,
+            None if tx < inner.next => TxStatus::Committed,
+            None => TxStatus::Active,
+        }
+    }
+
+    pub fn active_count(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .statuses
+            .values()
+            .filter(|&&status| status == TxStatus::Active)
+            .count()
+    }
+
+    pub fn snapshot(&self, tx: Option<TxId>) -> Snapshot {
+        let mut inner = self.inner.lock().unwrap();
+        let mut active = HashSet::new();
+        let mut aborted = HashSet::new();
+        for (&id, &status) in &inner.statuses {
+            match status {
+                TxStatus::Active if Some(id) != tx => {
+                    active.insert(id);
+                }
+                TxStatus::Aborted => {
+                    aborted.insert(id);
+                }
+                _ => {}
+            }
+        }
+        if let Some(tx) = tx {
+            let xmin = active.iter().copied().fold(inner.next, TxId::min);
+            inner.xmins.entry(tx).or_insert(xmin);
+        }
+        Snapshot {
+            tx,
+            horizon: inner.next,
+            active,
+            aborted,
+        }
+    }
+
+    // Every transaction below this id has ended, and every snapshot taken
+    // or still to be taken sees those that committed.
+    pub fn oldest_xmin(&self) -> TxId {
+        let inner = self.inner.lock().unwrap();
+        let active = inner
+            .statuses
+            .iter()
+            .filter(|&(_, &status)| status == TxStatus::Active)
+            .map(|(&id, _)| id);
+        active
+            .chain(inner.xmins.values().copied())
+            .fold(inner.next, TxId::min)
+    }
+
+    // Whether the row's deletion has committed, which is all a reader
+    // without a snapshot goes by.
+    pub fn is_deleted(&self, header: &RowHeader) -> bool {
+        header.tombstone && self.status(header.xmax) == TxStatus::Committed
+    }
+}
+
+// The set of transactions whose changes a reader sees: every id below
+// `horizon` that was neither active nor aborted when the snapshot was taken,
+// plus the reader's own.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub tx: Option<TxId>,
+    pub horizon: TxId,
+    pub active: HashSet<TxId>,
+    pub aborted: HashSet<TxId>,
+}
+
+impl Snapshot {
+    pub fn sees(&self, tx: TxId) -> bool {
+        tx == 0
+            || Some(tx) == self.tx
+            || (tx < self.horizon && !self.active.contains(&tx) && !self.aborted.contains(&tx))
+    }
+
+    pub fn is_visible(&self, header: &RowHeader) -> bool {
+        self.sees(header.xmin) && !(header.tombstone && self.sees(header.xmax))
+    }
+}
@@ -1,33 +1,32 @@
 
 
+use crate::storage::backup;
+use crate::storage::record::Page as RecordPage;
 use crate::storage::storage::Storage;
-use crate::tx::log_manager::{LogManager, LogRecordType, Lsn, TxId};
-use anyhow::{Context, Result};
+use crate::tx::log_manager::{
+    CheckpointPayload, CompensationPayload, DdlPayload, LogManager, LogRecordType, Lsn,
+    MasterRecord, TxId, UpdatePayload,
+};
+use crate::tx::wal_reader::{RecoveryLogRecord, WalReader};
+use anyhow::{Context, Result, anyhow};
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
-    io::{Read, Seek},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::sync::RwLock; 
 
 
-#[derive(Debug)]
-pub struct LogRecordHeader {
-    pub lsn: Lsn,
-    pub prev_lsn: Option<Lsn>,
-    pub tx_id: TxId,
-    pub typ: LogRecordType,
-    pub payload_len: u32,
-}
+type AnalysisResult = (
+    HashSet<u64>,
+    HashMap<TxId, Option<bool>>,
+    HashMap<TxId, Lsn>,
+    HashMap<TxId, u64>,
+    HashMap<TxId, Lsn>,
+);
 
 
-#[derive(Debug)]
-pub struct RecoveryLogRecord {
-    pub header: LogRecordHeader,
-    pub payload: Vec<u8>,
-}
 
 
 pub struct RecoveryManager {
@@ -42,196 +41,511 @@ impl RecoveryManager {
 
     
     pub async fn recover(&self) -> Result<()> {
-        
-        
-        let mut file = File::open(&self.wal_path)
-            .with_context(|| format!("opening WAL file for recovery: {:?}", self.wal_path))?;
-        
-        let (dirty_pages, tx_status, tx_last_lsn) = self.analysis_pass(&mut file)?;
-        
-        self.redo_pass(&mut file, &dirty_pages).await?; 
-        
-        self.undo_pass(&tx_status, &tx_last_lsn).await?; 
-        Ok(())
+        let mut storage = self.storage.write().await;
+        recover_storage(&self.wal_path, &mut storage)
     }
+}
 
-    
-    
-    
-    
-    fn analysis_pass(
-        &self,
-        file: &mut File,
-    ) -> Result<(
-        HashSet<u64>,
-        HashMap<TxId, Option<bool>>,
-        HashMap<TxId, Lsn>,
-    )> {
-        let mut dirty_pages = HashSet::new();
-        let mut tx_status: HashMap<TxId, Option<bool>> = HashMap::new();
-        let mut tx_last_lsn: HashMap<TxId, Lsn> = HashMap::new();
-        file.rewind()?;
-        loop {
-            
-            let mut len_buf = [0u8; 4];
-            if file.read_exact(&mut len_buf).is_err() {
-                break;
+// What `RecoveryManager::recover` does, for a caller that has the storage to
+// itself already.
+pub fn recover_storage(wal_path: &Path, storage: &mut Storage) -> Result<()> {
+    let mut file = WalReader::open(wal_path)
+        .with_context(|| format!("opening WAL file for recovery: {:?}", wal_path))?;
+
+    // The catalog saved with a checkpoint is as the log stood there, so
+    // recovery starts at that checkpoint even if the master record was
+    // moved on to a later one before the catalog could be saved with it.
+    let start = match storage.catalog.checkpoint_offset {
+        Some(offset) => offset,
+        None => MasterRecord::read(wal_path)?.map_or(file.base(), |m| m.offset),
+    };
+    let (dirty_pages, tx_status, tx_last_lsn, tx_first_offset, at_checkpoint) =
+        analysis_pass(&mut file, start)?;
+
+    // Before redo, which may give their rows' slots to others.
+    undo_rolled_back_catalog(
+        storage,
+        wal_path,
+        &tx_status,
+        &tx_first_offset,
+        &at_checkpoint,
+    )?;
+
+    redo_pass(storage, &mut file, start, &dirty_pages, &tx_status)?;
+
+    undo_pass(
+        storage,
+        wal_path,
+        &tx_status,
+        &tx_last_lsn,
+        &tx_first_offset,
+    )?;
+    if !tx_status.is_empty() || !dirty_pages.is_empty() {
+        storage.rebuild_after_recovery()?;
+    }
+    Ok(())
+}
+
+// What the log says about the data file, read without changing either.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogState {
+    // Opening the database would redo or undo something: a change was
+    // logged after the last checkpoint, or a transaction open at it or
+    // begun since has not ended.
+    pub needs_recovery: bool,
+    pub max_tx_id: TxId,
+}
+
+pub fn inspect_log(wal_path: &Path) -> Result<LogState> {
+    let mut file =
+        WalReader::open(wal_path).with_context(|| format!("opening WAL file: {:?}", wal_path))?;
+    let start = MasterRecord::read(wal_path)?.map_or(file.base(), |m| m.offset);
+    file.seek(start)?;
+    let mut state = LogState::default();
+    let mut open = HashSet::new();
+    while let Some(record) = file.next_record()? {
+        let hdr = &record.header;
+        state.max_tx_id = state.max_tx_id.max(hdr.tx_id);
+        match hdr.typ {
+            LogRecordType::Checkpoint => {
+                let checkpoint = CheckpointPayload::decode(&record.payload)
+                    .with_context(|| format!("decoding checkpoint at lsn {}", hdr.lsn))?;
+                state.max_tx_id = state.max_tx_id.max(checkpoint.max_tx_id);
+                state.needs_recovery |= !checkpoint.dirty_pages.is_empty();
+                open.extend(checkpoint.active_txns.iter().map(|tx| tx.tx_id));
             }
-            let rec_size = u32::from_le_bytes(len_buf) as usize;
-            let mut rec_buf = vec![0u8; rec_size];
-            file.read_exact(&mut rec_buf)?;
-            let record = Self::deserialize_record(&rec_buf)?;
-            let hdr = &record.header;
-            
-            tx_last_lsn.insert(hdr.tx_id, hdr.lsn);
-            match hdr.typ {
-                LogRecordType::Begin => {
-                    tx_status.insert(hdr.tx_id, None);
-                }
-                LogRecordType::Update => {
-                    
-                    let page_no = u64::from_le_bytes(record.payload[0..8].try_into().unwrap());
-                    dirty_pages.insert(page_no);
-                }
-                LogRecordType::Commit => {
-                    tx_status.insert(hdr.tx_id, Some(true));
-                }
-                LogRecordType::Abort => {
-                    tx_status.insert(hdr.tx_id, Some(false));
-                }
+            LogRecordType::Begin => {
+                open.insert(hdr.tx_id);
             }
+            LogRecordType::Commit | LogRecordType::Abort => {
+                open.remove(&hdr.tx_id);
+            }
+            _ => state.needs_recovery = true,
         }
-        Ok((dirty_pages, tx_status, tx_last_lsn))
     }
+    state.needs_recovery |= !open.is_empty();
+    Ok(state)
+}
 
-    
-    async fn redo_pass(&self, file: &mut File, dirty_pages: &HashSet<u64>) -> Result<()> {
+// Starts at the latest checkpoint, if any: its dirty page and active
+// transaction tables stand in for everything logged before it. The
+// transactions open at the first checkpoint met are returned with the last
+// record each had logged by then, which the saved catalog includes.
+fn analysis_pass(file: &mut WalReader, start: u64) -> Result<AnalysisResult> {
+    let mut dirty_pages = HashSet::new();
+    let mut tx_status: HashMap<TxId, Option<bool>> = HashMap::new();
+    let mut tx_last_lsn: HashMap<TxId, Lsn> = HashMap::new();
+    let mut tx_first_offset: HashMap<TxId, u64> = HashMap::new();
+    let mut at_checkpoint: Option<HashMap<TxId, Lsn>> = None;
+    file.seek(start)?;
+    loop {
+        let offset = file.position()?;
+        let Some(record) = file.next_record()? else {
+            break;
+        };
+        let hdr = &record.header;
+        if hdr.typ == LogRecordType::Checkpoint {
+            let checkpoint = CheckpointPayload::decode(&record.payload)
+                .with_context(|| format!("decoding checkpoint at lsn {}", hdr.lsn))?;
+            dirty_pages.extend(checkpoint.dirty_pages.iter().map(|&(page_no, _)| page_no));
+            at_checkpoint.get_or_insert_with(|| {
+                let active = checkpoint.active_txns.iter();
+                active.map(|tx| (tx.tx_id, tx.last_lsn)).collect()
+            });
+            for tx in checkpoint.active_txns {
+                tx_status.entry(tx.tx_id).or_insert(None);
+                tx_last_lsn.entry(tx.tx_id).or_insert(tx.last_lsn);
+                tx_first_offset.entry(tx.tx_id).or_insert(tx.first_offset);
+            }
+            continue;
+        }
         
-        file.rewind()?;
-        while let Some(record) = Self::next_record(file)? {
-            if record.header.typ == LogRecordType::Update {
-                let payload = &record.payload;
-                let page_no = u64::from_le_bytes(payload[0..8].try_into().unwrap());
-                if !dirty_pages.contains(&page_no) {
-                    continue; 
-                }
-                
-                let offset = u32::from_le_bytes(payload[8..12].try_into().unwrap()) as u64;
-                let after = &payload[12..];
-
+        tx_last_lsn.insert(hdr.tx_id, hdr.lsn);
+        tx_first_offset.entry(hdr.tx_id).or_insert(offset);
+        match hdr.typ {
+            LogRecordType::Begin => {
+                tx_status.insert(hdr.tx_id, None);
+            }
+            LogRecordType::Update => {
                 
-                let mut storage = self.storage.write().await; 
+                let update = UpdatePayload::decode(&record.payload)
+                    .with_context(|| format!("decoding update at lsn {}", hdr.lsn))?;
+                dirty_pages.insert(update.page_no);
+            }
+            LogRecordType::Compensation => {
+                let clr = CompensationPayload::decode(&record.payload)
+                    .with_context(|| format!("decoding compensation at lsn {}", hdr.lsn))?;
+                dirty_pages.insert(clr.update.page_no);
+            }
+            LogRecordType::Commit => {
+                tx_status.insert(hdr.tx_id, Some(true));
+            }
+            LogRecordType::Abort => {
+                tx_status.insert(hdr.tx_id, Some(false));
+            }
+            LogRecordType::CreateTable
+            | LogRecordType::CreateIndex
+            | LogRecordType::DropTable
+            | LogRecordType::Grant
+            | LogRecordType::CreateSequence
+            | LogRecordType::DropSequence
+            | LogRecordType::ReserveSequence
+            | LogRecordType::CreateView
+            | LogRecordType::DropView
+            | LogRecordType::AddForeignKey
+            | LogRecordType::AddCheck
+            | LogRecordType::RenameTable
+            | LogRecordType::RenameIndex
+            | LogRecordType::CreateSchema
+            | LogRecordType::DropSchema
+            | LogRecordType::AddRows
+            | LogRecordType::RemoveRows => {}
+            LogRecordType::Checkpoint => unreachable!(),
+        }
+    }
+    Ok((
+        dirty_pages,
+        tx_status,
+        tx_last_lsn,
+        tx_first_offset,
+        at_checkpoint.unwrap_or_default(),
+    ))
+}
 
-                
-                let mut page = storage.buffer_pool.pagefile.read_page(page_no)?;
-                page[offset as usize..offset as usize + after.len()].copy_from_slice(after);
-                storage.buffer_pool.pagefile.write_page(page_no, &page)?;
+fn redo_pass(
+    storage: &mut Storage,
+    file: &mut WalReader,
+    start: u64,
+    dirty_pages: &HashSet<u64>,
+    tx_status: &HashMap<TxId, Option<bool>>,
+) -> Result<()> {
+    file.seek(start)?;
+    while let Some(record) = file.next_record()? {
+        // The catalog is only told about DDL that committed; what the rest
+        // allocated is handed back by undo. Sequence reservations hold
+        // either way.
+        if record.header.typ.is_ddl() {
+            if tx_status.get(&record.header.tx_id) == Some(&Some(true))
+                || record.header.typ == LogRecordType::ReserveSequence
+            {
+                let ddl = DdlPayload::decode(&record.payload)
+                    .with_context(|| format!("decoding DDL at lsn {}", record.header.lsn))?;
+                storage.redo_ddl(&ddl);
+            }
+            continue;
+        }
+        // Compensation records are redone like any other change, so a
+        // half-finished rollback is repeated exactly as far as it got.
+        let update = match record.header.typ {
+            LogRecordType::Update => Some(UpdatePayload::decode(&record.payload)?),
+            LogRecordType::Compensation => {
+                Some(CompensationPayload::decode(&record.payload)?.update)
+            }
+            _ => None,
+        };
+        if let Some(update) = update {
+            if !dirty_pages.contains(&update.page_no) {
+                continue; 
+            }
+            
+            let offset = update.offset as usize;
+            let after = &update.after;
 
-                
+            // A crash can come after a new page's change was logged but
+            // before the page reached the file, which then ends short of it.
+            storage.buffer_pool.pagefile.extend_to(update.page_no)?;
+            let mut page = storage.buffer_pool.pagefile.read_page(update.page_no)?;
+            // Already on disk: the page was flushed after this change.
+            if RecordPage::lsn_of(&page) >= record.header.lsn {
+                continue;
             }
+            page[offset..offset + after.len()].copy_from_slice(after);
+            RecordPage::stamp_lsn(&mut page, record.header.lsn);
+            storage
+                .buffer_pool
+                .pagefile
+                .write_page(update.page_no, &page)?;
         }
-        Ok(())
     }
+    Ok(())
+}
 
-    
-    async fn undo_pass(
-        
-        &self,
-        tx_status: &HashMap<TxId, Option<bool>>,
-        tx_last_lsn: &HashMap<TxId, Lsn>,
-    ) -> Result<()> {
-        for (&tx, status) in tx_status.iter() {
-            if status.is_none() {
-                
-                let mut lsn = tx_last_lsn[&tx];
-                while lsn > 0 {
-                    let record = self.fetch_record(lsn)?;
-                    if record.header.typ == LogRecordType::Update {
-                        
-                        let payload = &record.payload;
-                        let page_no = u64::from_le_bytes(payload[0..8].try_into().unwrap());
-                        let offset = u32::from_le_bytes(payload[8..12].try_into().unwrap()) as u64;
-                        
-                        let half = (payload.len() - 12) / 2;
-                        let before = &payload[12..12 + half];
-
-                        
-                        let mut storage = self.storage.write().await; 
-
-                        
-                        let mut page = storage.buffer_pool.pagefile.read_page(page_no)?;
-                        page[offset as usize..offset as usize + before.len()]
-                            .copy_from_slice(before);
-                        storage.buffer_pool.pagefile.write_page(page_no, &page)?;
-
-                        
-                    }
-                    
-                    lsn = record.header.prev_lsn.unwrap_or(0);
-                }
-                
-                let log_manager = LogManager::new(self.wal_path.clone())?;
-                log_manager.log_abort(tx)?;
+fn undo_pass(
+    storage: &mut Storage,
+    wal_path: &Path,
+    tx_status: &HashMap<TxId, Option<bool>>,
+    tx_last_lsn: &HashMap<TxId, Lsn>,
+    tx_first_offset: &HashMap<TxId, u64>,
+) -> Result<()> {
+    let losers: HashSet<TxId> = tx_status
+        .iter()
+        .filter(|(_, status)| status.is_none())
+        .map(|(&tx, _)| tx)
+        .collect();
+    let Some(from) = losers.iter().map(|tx| tx_first_offset[tx]).min() else {
+        return Ok(());
+    };
+    let log_manager = match storage.wal.clone() {
+        Some(wal) => wal,
+        None => Arc::new(LogManager::new(wal_path.to_path_buf())?),
+    };
+    // One pass finds every record of every loser; undo then seeks
+    // straight to each record it follows.
+    let mut reader = WalReader::open(wal_path)?;
+    let index = LsnIndex::build(&mut reader, from, &losers)?;
+    let mut losers: Vec<TxId> = losers.into_iter().collect();
+    losers.sort();
+    for tx in losers {
+        undo_transaction(
+            storage,
+            &log_manager,
+            &mut reader,
+            &index,
+            tx,
+            tx_last_lsn[&tx],
+        )?;
+        log_manager.log_abort(tx)?;
+    }
+    storage.flush()?;
+    Ok(())
+}
+
+// Where each record of a set of transactions sits in the WAL, so undo can
+// seek to the records it follows instead of rescanning the log.
+#[derive(Debug, Default)]
+pub struct LsnIndex {
+    offsets: HashMap<Lsn, u64>,
+}
+
+impl LsnIndex {
+    // Reads the log once from `from`, which must be at or before the first
+    // record of every transaction in `txs`.
+    pub fn build(reader: &mut WalReader, from: u64, txs: &HashSet<TxId>) -> Result<Self> {
+        let mut offsets = HashMap::new();
+        reader.seek(from)?;
+        loop {
+            let offset = reader.position()?;
+            let Some(record) = reader.next_record()? else {
+                break;
+            };
+            if txs.contains(&record.header.tx_id) {
+                offsets.insert(record.header.lsn, offset);
             }
         }
-        Ok(())
+        Ok(LsnIndex { offsets })
     }
 
-    
-    fn next_record(file: &mut File) -> Result<Option<RecoveryLogRecord>> {
-        let mut len_buf = [0u8; 4];
-        if file.read_exact(&mut len_buf).is_err() {
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    fn fetch(&self, reader: &mut WalReader, lsn: Lsn) -> Result<Option<RecoveryLogRecord>> {
+        let Some(&offset) = self.offsets.get(&lsn) else {
             return Ok(None);
-        }
-        let rec_size = u32::from_le_bytes(len_buf) as usize;
-        let mut rec_buf = vec![0u8; rec_size];
-        file.read_exact(&mut rec_buf)?;
-        Ok(Some(Self::deserialize_record(&rec_buf)?))
+        };
+        reader.seek(offset)?;
+        Ok(reader
+            .next_record()?
+            .filter(|record| record.header.lsn == lsn))
     }
+}
 
-    
-    
-    fn fetch_record(&self, target_lsn: Lsn) -> Result<RecoveryLogRecord> {
-        let mut file = File::open(&self.wal_path)?;
-        while let Some(record) = Self::next_record(&mut file)? {
-            if record.header.lsn == target_lsn {
-                return Ok(record);
+// Walks one transaction's records backwards through `prev_lsn`, restoring the
+// before image of every update and logging a compensation record for it.
+// Used both for online aborts and for losers found during recovery. A
+// compensation record met on the way means an earlier rollback already got
+// that far, so the walk jumps to its `undo_next`. Each record followed is
+// read once, at the offset `index` has for it.
+pub fn undo_transaction(
+    storage: &mut Storage,
+    wal: &LogManager,
+    reader: &mut WalReader,
+    index: &LsnIndex,
+    tx_id: TxId,
+    last_lsn: Lsn,
+) -> Result<usize> {
+    let mut undone = 0;
+    let mut lsn = last_lsn;
+    while lsn > 0 {
+        let record = index
+            .fetch(reader, lsn)?
+            .ok_or_else(|| anyhow!("LSN {} of transaction {} not found in WAL", lsn, tx_id))?;
+        let prev = record.header.prev_lsn.unwrap_or(0);
+        match record.header.typ {
+            LogRecordType::Update => {
+                let update = UpdatePayload::decode(&record.payload)?;
+                compensate(storage, wal, tx_id, &update, prev)
+                    .with_context(|| format!("undoing lsn {}", lsn))?;
+                undone += 1;
+                lsn = prev;
+            }
+            LogRecordType::Compensation => {
+                lsn = CompensationPayload::decode(&record.payload)?.undo_next;
+            }
+            // Taking a catalog change back twice does no harm, so a rollback
+            // cut short by a crash can simply do it again.
+            typ if typ.is_ddl() => {
+                storage
+                    .undo_ddl(&DdlPayload::decode(&record.payload)?)
+                    .with_context(|| format!("undoing lsn {}", lsn))?;
+                lsn = prev;
             }
+            _ => lsn = prev,
         }
-        anyhow::bail!("LSN {} not found in WAL", target_lsn);
     }
+    Ok(undone)
+}
 
-    
-    fn deserialize_record(buf: &[u8]) -> Result<RecoveryLogRecord> {
-        
-        let mut pos = 0;
-        let read_u64 = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
-        let lsn = read_u64(&buf[pos..pos + 8]);
-        pos += 8;
-        let prev = read_u64(&buf[pos..pos + 8]);
-        pos += 8;
-        let tx_id = read_u64(&buf[pos..pos + 8]);
-        pos += 8;
-        let typ = match buf[pos] {
-            0 => LogRecordType::Begin,
-            1 => LogRecordType::Commit,
-            2 => LogRecordType::Abort,
-            3 => LogRecordType::Update,
-            _ => unreachable!(),
-        };
-        pos += 1;
-        let payload_len = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
-        pos += 4;
-        let payload = buf[pos..pos + payload_len].to_vec();
-        Ok(RecoveryLogRecord {
-            header: LogRecordHeader {
-                lsn,
-                prev_lsn: if prev == 0 { None } else { Some(prev) },
-                tx_id,
-                typ,
-                payload_len: payload_len as u32,
-            },
-            payload,
-        })
+// Transactions open at the checkpoint and rolled back since: the catalog
+// saved with it has what they changed up to there, and the rollback that
+// took that out again did so in memory only. Their pages were restored by
+// the rollback already, so only the catalog changes are taken back, from
+// the last one logged before the checkpoint.
+fn undo_rolled_back_catalog(
+    storage: &mut Storage,
+    wal_path: &Path,
+    tx_status: &HashMap<TxId, Option<bool>>,
+    tx_first_offset: &HashMap<TxId, u64>,
+    at_checkpoint: &HashMap<TxId, Lsn>,
+) -> Result<()> {
+    let rolled_back: HashSet<TxId> = at_checkpoint
+        .keys()
+        .filter(|tx| tx_status.get(tx) == Some(&Some(false)))
+        .copied()
+        .collect();
+    let Some(from) = rolled_back.iter().map(|tx| tx_first_offset[tx]).min() else {
+        return Ok(());
+    };
+    let mut reader = WalReader::open(wal_path)?;
+    let index = LsnIndex::build(&mut reader, from, &rolled_back)?;
+    for tx in rolled_back {
+        undo_catalog_changes(storage, &mut reader, &index, tx, at_checkpoint[&tx])?;
+    }
+    Ok(())
+}
+
+fn undo_catalog_changes(
+    storage: &mut Storage,
+    reader: &mut WalReader,
+    index: &LsnIndex,
+    tx_id: TxId,
+    last_lsn: Lsn,
+) -> Result<()> {
+    let mut lsn = last_lsn;
+    while lsn > 0 {
+        let record = index
+            .fetch(reader, lsn)?
+            .ok_or_else(|| anyhow!("LSN {} of transaction {} not found in WAL", lsn, tx_id))?;
+        if record.header.typ.is_ddl() {
+            let ddl = match DdlPayload::decode(&record.payload)? {
+                // The rollback gave its pages back, and they may be in use
+                // again by now.
+                DdlPayload::CreateIndex { index, .. } => DdlPayload::CreateIndex {
+                    index,
+                    pages: Vec::new(),
+                },
+                ddl => ddl,
+            };
+            storage
+                .undo_ddl(&ddl)
+                .with_context(|| format!("undoing lsn {}", lsn))?;
+        }
+        lsn = record.header.prev_lsn.unwrap_or(0);
+    }
+    Ok(())
+}
+
+// Undoes a single update: logs the compensation record first, then restores
+// the before image under its LSN. `undo_next` is the record to undo after
+// this one.
+pub fn compensate(
+    storage: &mut Storage,
+    wal: &LogManager,
+    tx_id: TxId,
+    update: &UpdatePayload,
+    undo_next: Lsn,
+) -> Result<Lsn> {
+    let clr = CompensationPayload {
+        undo_next,
+        update: UpdatePayload {
+            page_no: update.page_no,
+            offset: update.offset,
+            before: update.after.clone(),
+            after: update.before.clone(),
+        },
+    };
+    let lsn = wal.log_compensation(tx_id, clr.encode())?;
+    storage.undo_update(update, lsn)?;
+    Ok(lsn)
+}
+
+pub fn abort_transaction(storage: &mut Storage, wal: &LogManager, tx_id: TxId) -> Result<usize> {
+    storage.txns.abort(tx_id);
+    storage.drop_index_builds(tx_id);
+    let mut undone = 0;
+    if let Some(last_lsn) = wal.last_lsn(tx_id) {
+        wal.flush(last_lsn)?;
+        let from_offset = wal
+            .first_offset(tx_id)
+            .ok_or_else(|| anyhow!("No flushed WAL records for transaction {}", tx_id))?;
+        let mut reader = WalReader::open(wal.path())
+            .with_context(|| format!("opening WAL file for undo: {:?}", wal.path()))?;
+        let index = LsnIndex::build(&mut reader, from_offset, &HashSet::from([tx_id]))?;
+        undone = undo_transaction(storage, wal, &mut reader, &index, tx_id, last_lsn)?;
+    }
+    storage.discard_pending_rows()?;
+    wal.log_abort(tx_id)?;
+    storage.txns.forget(tx_id);
+    Ok(undone)
+}
+
+// How much log may pile up after a checkpoint before the next is taken.
+pub const CHECKPOINT_BYTES: u64 = 4 * 1024 * 1024;
+
+// Takes a checkpoint once CHECKPOINT_BYTES of log have been written since
+// the last, and drops the log it makes unnecessary. Returns the
+// checkpoint's lsn and the bytes dropped, if one was taken; never on a
+// read-only log.
+pub fn maybe_checkpoint(storage: &mut Storage, wal: &LogManager) -> Result<Option<(Lsn, u64)>> {
+    if wal.is_read_only() || wal.bytes_since_checkpoint() < CHECKPOINT_BYTES {
+        return Ok(None);
     }
+    let lsn = checkpoint(storage, wal)?;
+    Ok(Some((lsn, wal.truncate()?)))
+}
+
+// Flushes every dirty page and then logs a checkpoint, so a later recovery
+// only has to read the WAL from here on.
+pub fn checkpoint(storage: &mut Storage, wal: &LogManager) -> Result<Lsn> {
+    Ok(checkpoint_report(storage, wal)?.lsn)
+}
+
+// What a checkpoint wrote and how long it took.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckpointReport {
+    pub lsn: Lsn,
+    pub pages: usize,
+    pub elapsed: Duration,
+}
+
+pub fn checkpoint_report(storage: &mut Storage, wal: &LogManager) -> Result<CheckpointReport> {
+    let started = Instant::now();
+    let pages = storage.flush()?;
+    let lsn = wal.log_checkpoint(storage.buffer_pool.dirty_pages())?;
+    // Saved before any of the log ahead of the checkpoint can be dropped;
+    // until it is, recovery starts at the checkpoint the last one was saved
+    // at.
+    storage.catalog.checkpoint_offset = Some(wal.checkpoint_offset());
+    backup::save_catalog(&storage.catalog, wal.path())?;
+    let elapsed = started.elapsed();
+    wal.checkpoint_took(lsn, elapsed);
+    Ok(CheckpointReport {
+        lsn,
+        pages,
+        elapsed,
+    })
 }
@@ -0,0 +1,219 @@
+use crate::tx::log_manager::{
+    FrameRead, LogRecordType, Lsn, Manifest, TxId, WAL_HEADER_SIZE, read_frame, read_wal_base,
+    segment_path,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+// lsn, prev lsn, tx id, type, payload length.
+const RECORD_HEADER_SIZE: usize = 8 + 8 + 8 + 1 + 4;
+
+#[derive(Debug)]
+pub struct LogRecordHeader {
+    pub lsn: Lsn,
+    pub prev_lsn: Option<Lsn>,
+    pub tx_id: TxId,
+    pub typ: LogRecordType,
+    pub payload_len: u32,
+}
+
+
+#[derive(Debug)]
+pub struct RecoveryLogRecord {
+    pub header: LogRecordHeader,
+    pub payload: Vec<u8>,
+}
+
+
+// Reads the log as one stream of records across all live segments.
+pub struct WalReader {
+    wal_path: PathBuf,
+    segments: Vec<(u64, u64)>,
+    current: usize,
+    file: File,
+    ended: bool,
+    records_read: u64,
+    torn_at: Option<u64>,
+}
+
+impl WalReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let manifest =
+            Manifest::read(path)?.ok_or_else(|| anyhow!("No WAL manifest for {:?}", path))?;
+        let mut segments = Vec::new();
+        for segment in manifest.segments() {
+            let mut file = File::open(segment_path(path, segment))?;
+            segments.push((segment, read_wal_base(&mut file)?));
+        }
+        let file = Self::open_segment(path, segments[0].0)?;
+        Ok(WalReader {
+            wal_path: path.to_path_buf(),
+            segments,
+            current: 0,
+            file,
+            ended: false,
+            records_read: 0,
+            torn_at: None,
+        })
+    }
+
+    fn open_segment(path: &Path, segment: u64) -> Result<File> {
+        let path = segment_path(path, segment);
+        let mut file =
+            File::open(&path).with_context(|| format!("opening WAL segment {:?}", path))?;
+        file.seek(SeekFrom::Start(WAL_HEADER_SIZE))?;
+        Ok(file)
+    }
+
+    // Logical offset of the oldest record still on disk.
+    pub fn base(&self) -> u64 {
+        self.segments[0].1
+    }
+
+    pub fn seek(&mut self, offset: u64) -> Result<()> {
+        if offset < self.base() {
+            bail!(
+                "WAL offset {} has been truncated (log starts at {})",
+                offset,
+                self.base()
+            );
+        }
+        let current = self
+            .segments
+            .iter()
+            .rposition(|&(_, base)| base <= offset)
+            .unwrap();
+        let (segment, base) = self.segments[current];
+        if current != self.current {
+            self.file = Self::open_segment(&self.wal_path, segment)?;
+        }
+        self.file
+            .seek(SeekFrom::Start(offset - base + WAL_HEADER_SIZE))?;
+        self.current = current;
+        self.ended = false;
+        Ok(())
+    }
+
+    // Number of records returned so far.
+    pub fn records_read(&self) -> u64 {
+        self.records_read
+    }
+
+    // Offset of the torn record the log was found to end in, if any.
+    pub fn torn_at(&self) -> Option<u64> {
+        self.torn_at
+    }
+
+    pub fn position(&mut self) -> Result<u64> {
+        Ok(self.file.stream_position()? - WAL_HEADER_SIZE + self.segments[self.current].1)
+    }
+
+    // Stops at the first torn record: everything after it, in this segment
+    // or later ones, is treated as never written.
+    pub fn next_record(&mut self) -> Result<Option<RecoveryLogRecord>> {
+        match self.next_frame()? {
+            Some(body) => Ok(Some(deserialize_record(&body)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Like `next_record`, but leaves the record as the bytes it was written
+    // as, for shipping it elsewhere.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if self.ended {
+                return Ok(None);
+            }
+            let offset = self.position()?;
+            match read_frame(&mut self.file)? {
+                FrameRead::Record(body) => {
+                    self.records_read += 1;
+                    return Ok(Some(body));
+                }
+                FrameRead::End if self.current + 1 < self.segments.len() => {
+                    self.current += 1;
+                    self.file = Self::open_segment(&self.wal_path, self.segments[self.current].0)?;
+                }
+                FrameRead::End => self.ended = true,
+                FrameRead::Torn => {
+                    warn!("WAL ends in a torn record at offset {}", offset);
+                    self.torn_at = Some(offset);
+                    self.ended = true;
+                }
+            }
+        }
+    }
+}
+
+// Parses the body of one frame as returned by `read_frame`.
+pub fn deserialize_record(buf: &[u8]) -> Result<RecoveryLogRecord> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        bail!(
+            "WAL record of {} bytes is shorter than its header",
+            buf.len()
+        );
+    }
+    let mut pos = 0;
+    let read_u64 = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
+    let lsn = read_u64(&buf[pos..pos + 8]);
+    pos += 8;
+    let prev = read_u64(&buf[pos..pos + 8]);
+    pos += 8;
+    let tx_id = read_u64(&buf[pos..pos + 8]);
+    pos += 8;
+    let typ = match buf[pos] {
+        0 => LogRecordType::Begin,
+        1 => LogRecordType::Commit,
+        2 => LogRecordType::Abort,
+        3 => LogRecordType::Update,
+        4 => LogRecordType::Checkpoint,
+        5 => LogRecordType::Compensation,
+        6 => LogRecordType::CreateTable,
+        7 => LogRecordType::CreateIndex,
+        8 => LogRecordType::DropTable,
+        9 => LogRecordType::Grant,
+        10 => LogRecordType::CreateSequence,
+        11 => LogRecordType::DropSequence,
+        12 => LogRecordType::ReserveSequence,
+        13 => LogRecordType::CreateView,
+        14 => LogRecordType::DropView,
+        15 => LogRecordType::AddForeignKey,
+        16 => LogRecordType::AddCheck,
+        17 => LogRecordType::RenameTable,
+        18 => LogRecordType::RenameIndex,
+        19 => LogRecordType::CreateSchema,
+        20 => LogRecordType::DropSchema,
+        21 => LogRecordType::AddRows,
+        22 => LogRecordType::RemoveRows,
+        other => bail!("Unknown WAL record type {} at lsn {}", other, lsn),
+    };
+    pos += 1;
+    let payload_len = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+    pos += 4;
+    let payload = buf
+        .get(pos..pos + payload_len)
+        .ok_or_else(|| {
+            anyhow!(
+                "WAL record at lsn {} claims {} payload bytes but has {}",
+                lsn,
+                payload_len,
+                buf.len() - pos
+            )
+        })?
+        .to_vec();
+    Ok(RecoveryLogRecord {
+        header: LogRecordHeader {
+            lsn,
+            prev_lsn: if prev == 0 { None } else { Some(prev) },
+            tx_id,
+            typ,
+            payload_len: payload_len as u32,
+        },
+        payload,
+    })
+}
@@ -0,0 +1,295 @@
+use engine::cli::args::{BackupArgs, DumpArgs, ServerArgs, ShellArgs};
+use engine::cli::shell::{DEFAULT_MAX_WIDTH, Format};
+use engine::net::admission::WhenBusy;
+use engine::net::server::{
+    DEFAULT_AUTO_ANALYZE, DEFAULT_LOG_FILTER, DEFAULT_MAX_BODY_BYTES, DEFAULT_MAX_RUNNING_QUERIES,
+    DEFAULT_QUERY_TIMEOUT, DEFAULT_SESSION_TTL,
+};
+use engine::storage::storage::DEFAULT_MAX_COLUMNS;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|a| a.to_string()).collect()
+}
+
+fn server(list: &[&str], env: &[(&str, &str)]) -> anyhow::Result<ServerArgs> {
+    let env: HashMap<String, String> = env
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    ServerArgs::parse_with_env(&args(list), |key| env.get(key).cloned())
+}
+
+#[test]
+fn test_server_args_defaults_flags_and_env() {
+    let defaults = server(&[], &[]).unwrap();
+    assert_eq!(
+        defaults.listen,
+        "127.0.0.1:3000".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(defaults.data_file(), PathBuf::from("./data.db"));
+    assert_eq!(defaults.wal, PathBuf::from("./wal.log"));
+    assert_eq!((defaults.page_size, defaults.pool_size), (4096, 10));
+    assert_eq!(defaults.session_ttl, DEFAULT_SESSION_TTL);
+    assert!(!defaults.metrics_login);
+    assert_eq!(defaults.query_timeout, DEFAULT_QUERY_TIMEOUT);
+    assert_eq!(defaults.max_body_bytes, DEFAULT_MAX_BODY_BYTES);
+    assert_eq!(defaults.log_level, DEFAULT_LOG_FILTER);
+    assert_eq!(defaults.max_queries, DEFAULT_MAX_RUNNING_QUERIES);
+    assert_eq!(defaults.when_busy, WhenBusy::Reject);
+    assert_eq!(defaults.rate_limit, None);
+    assert_eq!(defaults.standby_of, None);
+    assert_eq!(defaults.standby_user, "admin");
+
+    let parsed = server(
+        &[
+            "--listen",
+            "0.0.0.0:5432",
+            "--data-dir",
+            "./db",
+            "--page-size=8192",
+            "--pool-size",
+            "1024",
+        ],
+        &[("MYDB_PAGE_SIZE", "1024"), ("MYDB_WAL", "/logs/wal.log")],
+    )
+    .unwrap();
+    assert_eq!(parsed.listen, "0.0.0.0:5432".parse::<SocketAddr>().unwrap());
+    assert_eq!(parsed.data_file(), PathBuf::from("./db/data.db"));
+    // Flags win over the environment, which wins over the defaults.
+    assert_eq!(parsed.page_size, 8192);
+    assert_eq!(parsed.pool_size, 1024);
+    assert_eq!(parsed.wal, PathBuf::from("/logs/wal.log"));
+
+    let parsed = server(
+        &["--data-dir", "./db", "--metrics-login=true"],
+        &[("MYDB_SESSION_TTL", "60")],
+    )
+    .unwrap();
+    assert_eq!(parsed.wal, PathBuf::from("./db/wal.log"));
+    assert_eq!(parsed.session_ttl, Duration::from_secs(60));
+    assert!(parsed.metrics_login);
+
+    let parsed = server(
+        &["--max-queries", "4", "--when-busy=queue"],
+        &[("MYDB_RATE_LIMIT", "20")],
+    )
+    .unwrap();
+    assert_eq!(parsed.max_queries, 4);
+    assert_eq!(parsed.when_busy, WhenBusy::Queue);
+    assert_eq!(parsed.rate_limit, Some(20));
+    let unlimited = server(&["--rate-limit", "0"], &[]).unwrap();
+    assert_eq!(unlimited.rate_limit, None);
+
+    let standby = server(
+        &["--standby-of", "http://primary:3000/"],
+        &[("MYDB_STANDBY_USER", "replica")],
+    )
+    .unwrap();
+    assert_eq!(standby.standby_of.as_deref(), Some("http://primary:3000"));
+    assert_eq!(standby.standby_user, "replica");
+    assert_eq!(standby.result_cache_bytes, 0);
+    let cached = server(&[], &[("MYDB_RESULT_CACHE", "1048576")]).unwrap();
+    assert_eq!(cached.result_cache_bytes, 1 << 20);
+    assert_eq!(cached.work_mem, 64 << 20);
+    let small = server(&["--work-mem", "65536"], &[]).unwrap();
+    assert_eq!(small.work_mem, 1 << 16);
+    assert!(server(&[], &[("MYDB_WORK_MEM", "0")]).is_err());
+    assert_eq!(cached.history_window_bytes, 0);
+    let kept = server(&["--history-window", "1048576"], &[]).unwrap();
+    assert_eq!(kept.history_window_bytes, 1 << 20);
+    assert!(!cached.read_only);
+    assert!(server(&["--read-only"], &[]).unwrap().read_only);
+    assert!(
+        server(&[], &[("MYDB_READ_ONLY", "true")])
+            .unwrap()
+            .read_only
+    );
+    assert_eq!(cached.audit_log, None);
+    let audited = server(&[], &[("MYDB_AUDIT_LOG", "/var/log/mydb/audit.log")]).unwrap();
+    assert_eq!(
+        audited.audit_log,
+        Some(PathBuf::from("/var/log/mydb/audit.log"))
+    );
+    assert_eq!(cached.auto_analyze, DEFAULT_AUTO_ANALYZE);
+    let steady = server(&[], &[("MYDB_AUTO_ANALYZE", "0")]).unwrap();
+    assert_eq!(steady.auto_analyze, 0);
+    assert!(server(&["--auto-analyze", "-5"], &[]).is_err());
+    assert_eq!(cached.max_columns, DEFAULT_MAX_COLUMNS);
+    let narrow = server(&["--max-columns", "16"], &[]).unwrap();
+    assert_eq!(narrow.max_columns, 16);
+
+    let log_level = |list: &[&str]| {
+        server(list, &[("RUST_LOG", "engine=debug")])
+            .unwrap()
+            .log_level
+    };
+    assert_eq!(log_level(&[]), "engine=debug");
+    assert_eq!(log_level(&["--log-level", "warn"]), "warn");
+}
+
+#[test]
+fn test_server_args_config_file() {
+    let path = "test_args_config.conf";
+    std::fs::write(
+        path,
+        "# Read on start\npage_size = 8192\nwal = /logs/wal.log\nquery_timeout = 5000 # ms\n",
+    )
+    .unwrap();
+    // The file's keys go over the flags and variables they stand in for.
+    let parsed = server(
+        &["--config", path, "--page-size", "1024", "--pool-size", "64"],
+        &[("MYDB_WAL", "other.log")],
+    )
+    .unwrap();
+    assert_eq!((parsed.page_size, parsed.pool_size), (8192, 64));
+    assert_eq!(parsed.wal, PathBuf::from("/logs/wal.log"));
+    // Settings are left to the server, in their own units.
+    let config = parsed.config.unwrap();
+    assert_eq!(config.settings.len(), 1);
+    assert_eq!(config.settings[0].1.to_string(), "5000");
+    assert_eq!(
+        server(&[], &[("MYDB_CONFIG", path)]).unwrap().page_size,
+        8192
+    );
+
+    let err = |text: &str| {
+        std::fs::write(path, text).unwrap();
+        format!("{:#}", server(&["--config", path], &[]).unwrap_err())
+    };
+    assert!(err("page_size = 5000\n").contains("power of two"));
+    assert!(err("pool_size = lots\n").contains("pool_size"));
+    assert!(err("\nport = 1\n").contains("line 2: Unknown setting 'port'"));
+    assert!(err("query_timeout\n").contains("expected name = value"));
+    assert!(err("wal = a\nwal = b\n").contains("set twice"));
+    std::fs::remove_file(path).unwrap();
+    assert!(
+        format!("{:#}", server(&["--config", path], &[]).unwrap_err())
+            .contains("Failed to read config file")
+    );
+}
+
+#[test]
+fn test_server_args_reject_bad_values() {
+    let err = |list: &[&str], env: &[(&str, &str)]| server(list, env).unwrap_err().to_string();
+    assert!(err(&["--page-size", "5000"], &[]).contains("power of two"));
+    assert!(err(&["--page-size", "65536"], &[]).contains("between"));
+    assert!(err(&["--pool-size", "0"], &[]).contains("at least 1"));
+    assert!(err(&["--session-ttl", "0"], &[]).contains("Session TTL"));
+    assert!(err(&["--query-timeout", "0"], &[]).contains("Query timeout"));
+    assert!(err(&["--max-body=0"], &[]).contains("body size"));
+    assert!(err(&["--log-level", "engine=loud"], &[]).contains("Invalid log level"));
+    assert!(err(&["--max-queries", "0"], &[]).contains("At least 1 query"));
+    assert!(err(&[], &[("MYDB_MAX_COLUMNS", "0")]).contains("at least 1 column"));
+    assert!(err(&["--when-busy", "wait"], &[]).contains("expected reject or queue"));
+    assert!(err(&["--standby-of", "primary:3000"], &[]).contains("Primary URL"));
+    assert!(
+        err(&["--read-only", "--standby-of", "http://primary:3000"], &[])
+            .contains("cannot be combined")
+    );
+    assert!(err(&[], &[("MYDB_POOL_SIZE", "lots")]).contains("MYDB_POOL_SIZE"));
+    assert!(err(&["--listen", "localhost"], &[]).contains("--listen"));
+    assert!(err(&["--wal"], &[]).contains("needs a value"));
+    assert!(err(&["--port", "1"], &[]).contains("Unknown option --port"));
+    assert!(err(&["--data-dir", "Cargo.toml"], &[]).contains("not a directory"));
+}
+
+#[test]
+fn test_shell_args_url() {
+    let none = |_: &str| None;
+    assert_eq!(
+        ShellArgs::parse_with_env(&[], none).unwrap().url,
+        "http://127.0.0.1:3000"
+    );
+    let env = |key: &str| (key == "MYDB_URL").then(|| "http://db:5432/".to_string());
+    assert_eq!(
+        ShellArgs::parse_with_env(&[], env).unwrap().url,
+        "http://db:5432"
+    );
+    let parsed = ShellArgs::parse_with_env(&args(&["--url", "https://x:1"]), env).unwrap();
+    assert_eq!(parsed.url, "https://x:1");
+    assert!(ShellArgs::parse_with_env(&args(&["--url", "x:1"]), none).is_err());
+
+    assert_eq!(parsed.max_width, DEFAULT_MAX_WIDTH);
+    let parsed = ShellArgs::parse_with_env(&args(&["--max-width", "12"]), none).unwrap();
+    assert_eq!(parsed.max_width, 12);
+    assert!(ShellArgs::parse_with_env(&args(&["--max-width", "0"]), none).is_err());
+
+    assert_eq!(parsed.format, Format::Table { max_width: 12 });
+    let env = |key: &str| (key == "MYDB_FORMAT").then(|| "json".to_string());
+    assert_eq!(
+        ShellArgs::parse_with_env(&[], env).unwrap().format,
+        Format::Json
+    );
+    let parsed = ShellArgs::parse_with_env(&args(&["--format", "csv"]), env).unwrap();
+    assert_eq!(parsed.format, Format::Csv);
+    assert!(ShellArgs::parse_with_env(&args(&["--format", "yaml"]), none).is_err());
+
+    assert_eq!(parsed.file, None);
+    let parsed = ShellArgs::parse_with_env(
+        &args(&["--file", "schema.sql", "--single-transaction"]),
+        none,
+    )
+    .unwrap();
+    assert_eq!(parsed.file, Some(PathBuf::from("schema.sql")));
+    assert!(parsed.single_transaction);
+    let parsed =
+        ShellArgs::parse_with_env(&args(&["--single-transaction=false", "--file=a.sql"]), none)
+            .unwrap();
+    assert!(!parsed.single_transaction);
+    assert!(ShellArgs::parse_with_env(&args(&["--single-transaction"]), none).is_err());
+
+    assert_eq!(parsed.command, None);
+    let parsed =
+        ShellArgs::parse_with_env(&args(&["-c", "SELECT 1;", "--single-transaction"]), none)
+            .unwrap();
+    assert_eq!(parsed.command.as_deref(), Some("SELECT 1;"));
+    assert!(parsed.single_transaction);
+    assert!(
+        ShellArgs::parse_with_env(&args(&["-c", "SELECT 1;", "--file", "a.sql"]), none).is_err()
+    );
+
+    assert_eq!(parsed.user, None);
+    let env = |key: &str| (key == "MYDB_USER").then(|| "alice".to_string());
+    let parsed = ShellArgs::parse_with_env(&[], env).unwrap();
+    assert_eq!(parsed.user.as_deref(), Some("alice"));
+    let parsed = ShellArgs::parse_with_env(&args(&["--user", "bob"]), env).unwrap();
+    assert_eq!(parsed.user.as_deref(), Some("bob"));
+}
+
+#[test]
+fn test_dump_args() {
+    let none = |_: &str| None;
+    let parsed = DumpArgs::parse_with_env(&[], none).unwrap();
+    assert_eq!(parsed.url, "http://127.0.0.1:3000");
+    assert!(parsed.tables.is_empty());
+    let env = |key: &str| (key == "MYDB_USER").then(|| "alice".to_string());
+    let parsed = DumpArgs::parse_with_env(
+        &args(&["--table", "a", "--table=b", "--url", "http://db/"]),
+        env,
+    )
+    .unwrap();
+    assert_eq!(parsed.tables, ["a", "b"]);
+    assert_eq!(parsed.url, "http://db");
+    assert_eq!(parsed.user.as_deref(), Some("alice"));
+    assert!(!parsed.schema_only);
+    let parsed = DumpArgs::parse_with_env(&args(&["--schema-only"]), none).unwrap();
+    assert!(parsed.schema_only);
+    assert!(DumpArgs::parse_with_env(&args(&["--schema-only=maybe"]), none).is_err());
+    assert!(DumpArgs::parse_with_env(&args(&["--format", "csv"]), none).is_err());
+}
+
+#[test]
+fn test_backup_args() {
+    let none = |_: &str| None;
+    let err = BackupArgs::parse_with_env(&[], none).unwrap_err();
+    assert!(err.to_string().contains("--to is required"));
+    let parsed = BackupArgs::parse_with_env(&args(&["--to", "/backups/today"]), none).unwrap();
+    assert_eq!(parsed.dir, PathBuf::from("/backups/today"));
+    assert_eq!(parsed.url, "http://127.0.0.1:3000");
+    assert_eq!(parsed.user, None);
+    assert!(BackupArgs::parse_with_env(&args(&["--to", "b", "--url", "db:3000"]), none).is_err());
+}
@@ -0,0 +1,148 @@
+use engine::query::binder::Value;
+use engine::query::parser::Parser;
+use engine::query::pipeline::run_ddl;
+use engine::storage::atomic_file::atomic_remove;
+use engine::storage::backup::catalog_path;
+use engine::storage::storage::Storage;
+use engine::testing::run;
+use engine::tx::log_manager::{LogManager, Lsn, Manifest, MasterRecord, segment_path};
+use engine::tx::recovery_manager::{abort_transaction, checkpoint};
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+fn ids_as_of(storage: &mut Storage, lsn: Lsn) -> Vec<i64> {
+    let sql = format!("SELECT ID FROM T AS OF LSN {};", lsn);
+    run(storage, &sql)
+        .unwrap()
+        .into_iter()
+        .map(|row| match row[0] {
+            Value::Int(id) => id,
+            _ => panic!("expected int"),
+        })
+        .collect()
+}
+
+fn remove_files(db: &str, wal_path: &str) {
+    let _ = remove_file(db);
+    let path = Path::new(wal_path);
+    if let Ok(Some(manifest)) = Manifest::read(path) {
+        for segment in manifest.segments() {
+            let _ = remove_file(segment_path(path, segment));
+        }
+    }
+    atomic_remove(&Manifest::path(path)).unwrap();
+    atomic_remove(&MasterRecord::path(path)).unwrap();
+    atomic_remove(&catalog_path(path)).unwrap();
+}
+
+fn setup(db: &str, wal: &Arc<LogManager>) -> Storage {
+    let mut storage = Storage::new(db, 4096, 16).unwrap();
+    storage.attach_wal(wal.clone());
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let create = Parser::new("CREATE TABLE T (ID INT, NAME VARCHAR);")
+        .unwrap()
+        .parse_statement()
+        .unwrap();
+    run_ddl(&mut storage, &create, None).unwrap().unwrap();
+    wal.log_commit(1).unwrap();
+    storage
+}
+
+// Inserts `ids` in transaction `tx`, leaving it open.
+fn insert(storage: &mut Storage, wal: &LogManager, tx: u64, ids: &[i64]) {
+    storage.set_transaction(Some(tx));
+    wal.log_begin(tx).unwrap();
+    for id in ids {
+        let sql = format!("INSERT INTO T (ID, NAME) VALUES ({}, 'n{}');", id, id);
+        run(storage, &sql).unwrap();
+    }
+}
+
+#[test]
+fn test_as_of_sees_what_was_committed_by_then() {
+    let (db, wal_path) = ("test_as_of.db", "test_as_of.wal");
+    remove_files(db, wal_path);
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = setup(db, &wal);
+
+    insert(&mut storage, &wal, 2, &[1, 2]);
+    let first = wal.log_commit(2).unwrap();
+    // Rolled back: its slot goes to the next insert.
+    insert(&mut storage, &wal, 3, &[3]);
+    abort_transaction(&mut storage, &wal, 3).unwrap();
+    insert(&mut storage, &wal, 4, &[4, 5]);
+    let in_flight = wal.last_lsn(4).unwrap();
+    let second = wal.log_commit(4).unwrap();
+    // Still open as the queries run.
+    insert(&mut storage, &wal, 5, &[6]);
+
+    assert_eq!(ids_as_of(&mut storage, first), vec![1, 2]);
+    assert_eq!(ids_as_of(&mut storage, in_flight), vec![1, 2]);
+    assert_eq!(ids_as_of(&mut storage, second), vec![1, 2, 4, 5]);
+    let sql = format!("SELECT NAME FROM T AS OF LSN {} WHERE ID > 2;", second);
+    let rows = run(&mut storage, &sql).unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            vec![Value::String("n4".into())],
+            vec![Value::String("n5".into())]
+        ]
+    );
+
+    let sql = format!("EXPLAIN SELECT ID FROM T AS OF LSN {} WHERE ID = 1;", first);
+    let plan = run(&mut storage, &sql).unwrap();
+    let plan = format!("{:?}", plan);
+    assert!(
+        plan.contains(&format!("AsOfScan on T at LSN {}", first)),
+        "{}",
+        plan
+    );
+
+    let err = run(&mut storage, "SELECT ID FROM T AS OF LSN 100000;").unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("has not been written yet"),
+        "{:#}",
+        err
+    );
+    drop(storage);
+    remove_files(db, wal_path);
+}
+
+#[test]
+fn test_as_of_reads_back_only_as_far_as_the_log_is_kept() {
+    for window in [0, 1 << 20] {
+        let (db, wal_path) = ("test_as_of_window.db", "test_as_of_window.wal");
+        remove_files(db, wal_path);
+        let wal = Arc::new(
+            LogManager::new(PathBuf::from(wal_path))
+                .unwrap()
+                .with_segment_size(1024)
+                .with_history_window(window),
+        );
+        let mut storage = setup(db, &wal);
+        insert(&mut storage, &wal, 2, &[1]);
+        let first = wal.log_commit(2).unwrap();
+        insert(&mut storage, &wal, 3, &(2..40).collect::<Vec<_>>());
+        wal.log_commit(3).unwrap();
+        storage.set_transaction(None);
+        checkpoint(&mut storage, &wal).unwrap();
+        wal.truncate().unwrap();
+
+        let sql = format!("SELECT ID FROM T AS OF LSN {};", first);
+        match window {
+            0 => {
+                let err = run(&mut storage, &sql).unwrap_err();
+                assert!(
+                    format!("{:#}", err).contains("older than the log kept"),
+                    "{:#}",
+                    err
+                );
+            }
+            _ => assert_eq!(ids_as_of(&mut storage, first), vec![1]),
+        }
+        drop(storage);
+        remove_files(db, wal_path);
+    }
+}
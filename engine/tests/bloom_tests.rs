@@ -0,0 +1,102 @@
+use engine::index::bloom::BloomStats;
+use engine::index::bplustree::BPlusTree;
+use engine::query::binder::Value;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use engine::testing::run;
+use std::fs::remove_file;
+
+fn table_with_ids(path: &str, ids: impl Iterator<Item = i64>) -> Storage {
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    let names = vec!["ID".to_string()];
+    for id in ids {
+        storage
+            .insert_row("T", &names, vec![Value::Int(id)])
+            .unwrap();
+    }
+    storage
+}
+
+#[test]
+fn test_bloom_filter_skips_missing_keys() {
+    let path = "test_bloom_skips.db";
+    let mut storage = table_with_ids(path, (0..1000).map(|i| i * 2));
+    storage.create_index("T", "ID", "T_ID", Some(4)).unwrap();
+    let info = storage.get_indexes("T").remove(0);
+    assert!(info.bloom_page.is_some());
+
+    for i in 0..1000 {
+        let mut tree = BPlusTree::<i64>::open(&mut storage, &info);
+        assert_eq!(tree.get(i * 2 + 1).unwrap(), None);
+    }
+    let stats = storage.index_stats("T", "T_ID").unwrap();
+    assert_eq!(stats.hits + stats.skips, 1000);
+    assert!(stats.skips > 950, "{:?}", stats);
+
+    for i in 0..1000 {
+        let mut tree = BPlusTree::<i64>::open(&mut storage, &info);
+        assert!(tree.get(i * 2).unwrap().is_some());
+    }
+    let after = storage.index_stats("T", "T_ID").unwrap();
+    assert_eq!(after.hits, stats.hits + 1000);
+    assert_eq!(after.skips, stats.skips);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_bloom_filter_tracks_inserts_and_analyze() {
+    let path = "test_bloom_inserts.db";
+    let mut storage = table_with_ids(path, std::iter::empty());
+    storage.create_index("T", "ID", "T_ID", Some(4)).unwrap();
+    let names = vec!["ID".to_string()];
+    for id in -300..300 {
+        storage
+            .insert_row("T", &names, vec![Value::Int(id * 3)])
+            .unwrap();
+    }
+
+    let info = storage.get_indexes("T").remove(0);
+    for id in -300..300 {
+        let mut tree = BPlusTree::<i64>::open(&mut storage, &info);
+        assert!(tree.get(id * 3).unwrap().is_some(), "lost key {}", id * 3);
+    }
+
+    let rows = run(&mut storage, "ANALYZE t;").unwrap();
+    assert!(matches!(rows[0][0], Value::Int(1)));
+    assert_eq!(
+        storage.index_stats("T", "T_ID").unwrap(),
+        BloomStats::default()
+    );
+    let info = storage.get_indexes("T").remove(0);
+    for id in -300..300 {
+        let mut tree = BPlusTree::<i64>::open(&mut storage, &info);
+        assert!(tree.get(id * 3).unwrap().is_some());
+        assert_eq!(tree.get(id * 3 + 1).unwrap(), None);
+    }
+    let stats = storage.index_stats("T", "T_ID").unwrap();
+    assert!(stats.skips > 550, "{:?}", stats);
+    assert!(run(&mut storage, "ANALYZE missing;").is_err());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_tree_without_filter_still_searches() {
+    let path = "test_bloom_absent.db";
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    let mut tree = BPlusTree::<u64>::create(&mut storage, 4, "T".into()).unwrap();
+    assert_eq!(tree.bloom_page(), None);
+    tree.insert(7, (1, 0)).unwrap();
+    assert_eq!(tree.get(7).unwrap(), Some((1, 0)));
+    assert_eq!(tree.get(8).unwrap(), None);
+    assert!(storage.bloom_stats.is_empty());
+    remove_file(path).unwrap();
+}
@@ -0,0 +1,148 @@
+use engine::index::bplustree::{BPlusTree, max_order};
+use engine::index::bplustree_search::BPlusTreeSearch;
+use engine::index::node_serializer::IndexKey;
+use engine::query::binder::Value;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use std::fs::remove_file;
+
+#[test]
+fn test_parent_pointers_after_internal_splits() {
+    let path = "test_bplustree_parent_pointers.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    let mut tree = BPlusTree::create(&mut storage, 3, "T".into()).unwrap();
+
+    let keys: Vec<u64> = (0..500).map(|i| (i * 7919) % 1000).collect();
+    for (i, &key) in keys.iter().enumerate() {
+        tree.insert(key, (key, (i % 100) as u16)).unwrap();
+        if i % 50 == 0 {
+            tree.check().unwrap();
+        }
+    }
+
+    assert_eq!(tree.check().unwrap(), keys.len());
+    for &key in &keys {
+        assert_eq!(tree.get(key).unwrap().unwrap().0, key);
+    }
+    assert_eq!(tree.get(1001).unwrap(), None);
+    remove_file(path).unwrap();
+}
+
+fn lookup_depth(path: &str, order: Option<usize>) -> (usize, usize) {
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    storage.create_index("T", "ID", "T_ID", order).unwrap();
+    let names = vec!["ID".to_string()];
+    for id in 0..2000 {
+        storage
+            .insert_row("T", &names, vec![Value::Int(id)])
+            .unwrap();
+    }
+
+    let info = storage.get_indexes("T").remove(0);
+    let depth = BPlusTreeSearch::new(&storage, info.order)
+        .search_path(info.root_page, &1234i64)
+        .unwrap()
+        .len();
+    assert_eq!(
+        BPlusTree::<i64>::open(&mut storage, &info).check().unwrap(),
+        2000
+    );
+    remove_file(path).unwrap();
+    (info.order, depth)
+}
+
+#[test]
+fn test_default_order_fills_page() {
+    let (order, depth) = lookup_depth("test_bplustree_default_order.db", None);
+    assert_eq!(order, max_order::<i64>(4096));
+    assert!(order > 200, "order {}", order);
+
+    let (_, tiny_depth) = lookup_depth("test_bplustree_tiny_order.db", Some(4));
+    assert!(depth < tiny_depth, "{} vs {}", depth, tiny_depth);
+    assert_eq!(depth, 2);
+}
+
+#[test]
+fn test_order_override_is_validated() {
+    let path = "test_bplustree_order_override.db";
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    assert!(storage.create_index("T", "ID", "A", Some(2)).is_err());
+    assert!(storage.create_index("T", "ID", "B", Some(10_000)).is_err());
+    assert!(storage.create_index("T", "ID", "C", Some(3)).is_ok());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_i64_encoding_preserves_order() {
+    let values = [i64::MIN, -300, -1, 0, 1, 255, 256, i64::MAX];
+    let encoded: Vec<Vec<u8>> = values
+        .iter()
+        .map(|v| {
+            let mut out = Vec::new();
+            v.encode(&mut out);
+            out
+        })
+        .collect();
+    assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+    for (v, bytes) in values.iter().zip(&encoded) {
+        assert_eq!(i64::decode(bytes).unwrap(), (*v, 8));
+    }
+}
+
+#[test]
+fn test_byte_string_keys_split_on_page_size() {
+    let path = "test_bplustree_byte_keys.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    let mut tree = BPlusTree::<Vec<u8>>::create(&mut storage, 100, "T".into()).unwrap();
+    let key = |i: u64| {
+        let mut k = format!("key-{:05}-", (i * 7919) % 400).into_bytes();
+        k.resize(10 + (i as usize % 7) * 60, b'x');
+        k
+    };
+    for i in 0..400 {
+        tree.insert(key(i), (i, 0)).unwrap();
+    }
+    assert_eq!(tree.check().unwrap(), 400);
+    for i in 0..400 {
+        assert_eq!(tree.get(key(i)).unwrap(), Some((i, 0)));
+    }
+    assert!(tree.insert(vec![0; 4096], (0, 0)).is_err());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_tuple_keys() {
+    let path = "test_bplustree_tuple_keys.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    let mut tree = BPlusTree::<(i64, Vec<u8>)>::create(&mut storage, 4, "T".into()).unwrap();
+    for i in 0..100i64 {
+        let name = format!("n{}", i % 10).into_bytes();
+        tree.insert((i / 10 - 5, name), (i as u64, 0)).unwrap();
+    }
+    assert_eq!(tree.check().unwrap(), 100);
+    let lo = (-5, Vec::new());
+    let hi = (-4, b"n3".to_vec());
+    let hits = tree.range_scan_keys(lo, hi).unwrap();
+    assert_eq!(hits.len(), 14);
+    assert!(hits.windows(2).all(|w| w[0].0 < w[1].0));
+    remove_file(path).unwrap();
+}
@@ -0,0 +1,216 @@
+use byteorder::{ByteOrder, LittleEndian};
+use engine::cli::check::{CheckArgs, run_check};
+use engine::database::Database;
+use engine::query::executor::{Executor, SeqScanOp};
+use engine::storage::check::{CheckReport, Problem, check, check_file};
+use engine::storage::storage::{ReadView, ScanErrorPolicy, Storage};
+use engine::testing::{fresh_dir, table_with_rows};
+use std::fs::remove_file;
+use std::sync::atomic::Ordering;
+
+fn problem_at(report: &CheckReport, page: u64, slot: Option<u16>) -> &Problem {
+    report
+        .problems
+        .iter()
+        .find(|p| p.page == Some(page) && p.slot == slot)
+        .unwrap_or_else(|| panic!("no problem at page {} slot {:?} in\n{}", page, slot, report))
+}
+
+#[test]
+fn test_check_statement_finds_nothing_wrong_with_a_healthy_database() {
+    let dir = fresh_dir("check_healthy");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    let values: Vec<String> = (0..2000).map(|i| format!("({}, 'n{}')", i, i)).collect();
+    db.execute(&format!(
+        "INSERT INTO t (id, name) VALUES {};",
+        values.join(", ")
+    ))
+    .unwrap();
+    db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+    db.execute("CREATE INDEX t_id_hash ON t (id) USING HASH;")
+        .unwrap();
+
+    let result = db.execute("CHECK;").unwrap();
+    assert_eq!(result.columns(), vec!["page", "slot", "object", "problem"]);
+    assert!(result.rows.is_empty(), "{:?}", result.rows);
+    db.close().unwrap();
+
+    let report = check_file(&dir.join("data.db"), 4096).unwrap();
+    assert!(report.is_ok(), "{}", report);
+    assert!(report.heap_pages > 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_check_reports_damaged_rows_with_their_coordinates() {
+    let path = "test_check_damaged.db";
+    let mut storage = table_with_rows(path, 100);
+    storage.create_index("T", "ID", "T_ID", None).unwrap();
+    let (page_no, _) = storage.catalog.get_table("T").unwrap().records[0];
+    assert!(check(&mut storage).unwrap().is_ok());
+
+    // Point slot 1 at slot 0's bytes: the slots overlap, and the index entry
+    // for key 1 now finds a row whose ID is 0.
+    let frame = storage.buffer_pool.fetch_page(page_no).unwrap();
+    let slot0 = LittleEndian::read_u16(&frame.data[20..22]);
+    let len0 = LittleEndian::read_u16(&frame.data[22..24]);
+    LittleEndian::write_u16(&mut frame.data[24..26], slot0);
+    LittleEndian::write_u16(&mut frame.data[26..28], len0);
+    // Slot 2 loses its declared type: ID becomes a string tag.
+    let slot2 = LittleEndian::read_u16(&frame.data[28..30]) as usize;
+    frame.data[slot2 + 16 + 4] = 1;
+    storage.buffer_pool.unpin_page(page_no, true);
+    storage.free_list.register(page_no, 7);
+    storage
+        .catalog
+        .get_table_mut("T")
+        .unwrap()
+        .records
+        .push((page_no, 999));
+
+    let report = check(&mut storage).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(
+        problem_at(&report, page_no, Some(1)).message,
+        "slot overlaps slot 0"
+    );
+    assert!(
+        report.problems.iter().any(|p| p.object == "index T_ID"
+            && p.slot == Some(1)
+            && p.message
+                .starts_with("entry for key 1 points at a row whose ID is")),
+        "{}",
+        report
+    );
+    assert!(
+        report
+            .problems
+            .iter()
+            .any(|p| p.object == "table T" && p.slot == Some(2)),
+        "{}",
+        report
+    );
+    assert!(
+        report.problems.iter().any(|p| p.object == "free list"
+            && p.page == Some(page_no)
+            && p.message.starts_with("records 7 free bytes")),
+        "{}",
+        report
+    );
+    assert_eq!(
+        problem_at(&report, page_no, Some(999)).message,
+        format!(
+            "slot does not exist, the page has {}",
+            slot_count(&mut storage, page_no)
+        )
+    );
+    remove_file(path).unwrap();
+}
+
+fn slot_count(storage: &mut Storage, page_no: u64) -> u16 {
+    let frame = storage.buffer_pool.fetch_page(page_no).unwrap();
+    let count = LittleEndian::read_u16(&frame.data[8..10]);
+    storage.buffer_pool.unpin_page(page_no, false);
+    count
+}
+
+#[test]
+fn test_check_file_reports_a_bad_header_and_a_torn_file() {
+    let path = "test_check_file.db";
+    let mut storage = table_with_rows(path, 10);
+    let (page_no, _) = storage.catalog.get_table("T").unwrap().records[0];
+    storage.flush().unwrap();
+    drop(storage);
+    let args = CheckArgs::parse(&[path.to_string(), "--json".to_string()]).unwrap();
+    let mut out = Vec::new();
+    assert!(run_check(&args, &mut out).unwrap().is_ok());
+
+    // The free space offset now lands inside the slot directory.
+    let mut data = std::fs::read(path).unwrap();
+    let at = page_no as usize * 4096;
+    LittleEndian::write_u16(&mut data[at + 10..at + 12], 24);
+    data.extend_from_slice(&[0; 100]);
+    std::fs::write(path, &data).unwrap();
+
+    let mut out = Vec::new();
+    let report = run_check(&args, &mut out).unwrap();
+    let parsed: CheckReport = serde_json::from_slice(&out).unwrap();
+    assert_eq!(parsed, report);
+    assert_eq!(
+        problem_at(&report, page_no, None).message,
+        "free space starts at 24, outside the page's 60..4096"
+    );
+    assert_eq!(report.problems[0].object, "file");
+
+    let text = CheckArgs {
+        json: false,
+        ..args
+    };
+    let mut out = Vec::new();
+    run_check(&text, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(&format!("page {} (heap): free space starts at 24", page_no)));
+    assert!(out.trim_end().ends_with("2 problems"));
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_check_compares_table_stats_with_the_heap() {
+    let path = "test_check_stats.db";
+    let mut storage = table_with_rows(path, 50);
+    let counted = storage.count_rows("T").unwrap();
+    assert_eq!(storage.catalog.get_table("T").unwrap().stats, counted);
+    assert_eq!(counted.rows, 50);
+
+    storage.catalog.get_table_mut("T").unwrap().stats.rows = 7;
+    let report = check(&mut storage).unwrap();
+    assert_eq!(report.problems.len(), 1, "{}", report);
+    assert_eq!(report.problems[0].object, "table T");
+    assert!(
+        report.problems[0]
+            .message
+            .starts_with("stats count 7 rows in 1 pages"),
+        "{}",
+        report
+    );
+    storage.analyze("T").unwrap();
+    assert!(check(&mut storage).unwrap().is_ok());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_skip_page_scans_pass_over_the_pages_check_reports() {
+    let path = "test_check_skip_page.db";
+    let mut storage = table_with_rows(path, 500);
+    let records = storage.catalog.get_table("T").unwrap().records.clone();
+    let page_no = records[records.len() / 2].0;
+    let on_page = records.iter().filter(|rid| rid.0 == page_no).count();
+    assert!(on_page < records.len());
+
+    // Slot 0's first value gets a type tag no value has.
+    let frame = storage.buffer_pool.fetch_page(page_no).unwrap();
+    let slot0 = LittleEndian::read_u16(&frame.data[20..22]) as usize;
+    frame.data[slot0 + 16 + 4] = 0xff;
+    storage.buffer_pool.unpin_page(page_no, true);
+    let report = check(&mut storage).unwrap();
+    let problem = problem_at(&report, page_no, Some(0));
+    assert_eq!(problem.object, "heap");
+    assert!(problem.message.starts_with("row does not deserialize"));
+
+    let scan = |policy| {
+        let view = ReadView {
+            scan_errors: policy,
+            ..ReadView::of(&storage)
+        };
+        let skipped = view.skipped_pages.clone();
+        let rows = Executor::new(Box::new(SeqScanOp::new(view, "T".into(), None))).execute();
+        (rows, skipped.load(Ordering::Relaxed))
+    };
+    let (rows, skipped) = scan(ScanErrorPolicy::SkipPage);
+    assert_eq!(rows.unwrap().len(), records.len() - on_page);
+    assert_eq!(skipped, 1);
+    // Strict, the scan stops at the damaged page as it always has.
+    assert!(scan(ScanErrorPolicy::Strict).0.is_err());
+    remove_file(path).unwrap();
+}
@@ -0,0 +1,312 @@
+use engine::query::binder::Value;
+use engine::storage::atomic_file::{atomic_read, atomic_remove, atomic_write, previous_path};
+use engine::storage::backup::catalog_path;
+use engine::storage::check::check;
+use engine::storage::failpoint::{self, Action};
+use engine::storage::record::RID;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage, TableStats};
+use engine::tx::log_manager::{LogManager, Manifest, MasterRecord, TxId, segment_path};
+use engine::tx::recovery_manager::{RecoveryManager, abort_transaction, checkpoint};
+use std::collections::{HashMap, HashSet};
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+// The failpoints are the process's, so the tests here take turns.
+static FAILPOINTS: Mutex<()> = Mutex::const_new(());
+
+const SEEDS: u64 = 8;
+const TRANSACTIONS: TxId = 12;
+
+// xorshift64: the workload and where it crashes follow from the seed alone.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+// What the workload was told happened to its rows.
+#[derive(Default)]
+struct Outcome {
+    rows: Vec<(RID, i64, TxId)>,
+    committed: HashSet<TxId>,
+    // The commit failed, so its record may or may not have reached the log.
+    in_doubt: HashSet<TxId>,
+    // Transactions rolled back because one of their writes failed.
+    failed: Vec<TxId>,
+}
+
+fn columns() -> Vec<ColumnInfo> {
+    vec![
+        ColumnInfo {
+            name: "ID".into(),
+            data_type: DataType::Int,
+            collation: Collation::Binary,
+        },
+        ColumnInfo {
+            name: "NAME".into(),
+            data_type: DataType::String,
+            collation: Collation::Binary,
+        },
+    ]
+}
+
+fn remove_files(db: &str, wal_path: &str) {
+    let _ = remove_file(db);
+    let path = Path::new(wal_path);
+    if let Ok(Some(manifest)) = Manifest::read(path) {
+        for segment in manifest.segments() {
+            let _ = remove_file(segment_path(path, segment));
+        }
+    }
+    atomic_remove(&Manifest::path(path)).unwrap();
+    atomic_remove(&MasterRecord::path(path)).unwrap();
+    atomic_remove(&catalog_path(path)).unwrap();
+}
+
+// A small buffer pool, so pages are written out mid-transaction as well as
+// at checkpoints. The failpoints count from after the table is created.
+fn workload(
+    db: &str,
+    wal_path: &str,
+    seed: u64,
+    arm: Option<(&'static str, u64, Action)>,
+) -> Outcome {
+    remove_files(db, wal_path);
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = Storage::new(db, 4096, 4).unwrap();
+    storage.attach_wal(wal.clone());
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    storage.create_table("T".into(), columns()).unwrap();
+    wal.log_commit(1).unwrap();
+
+    failpoint::reset();
+    if let Some((point, after, action)) = arm {
+        failpoint::arm(point, after, action);
+    }
+    let mut rng = Rng::new(seed);
+    let mut outcome = Outcome::default();
+    let mut next_id = 0;
+    for tx in 2..2 + TRANSACTIONS {
+        storage.set_transaction(Some(tx));
+        let result = transaction(&mut storage, &wal, &mut rng, tx, &mut next_id, &mut outcome);
+        match result {
+            Ok(()) => {}
+            Err(_) if failpoint::crashed() => break,
+            Err(_) => {
+                outcome.failed.push(tx);
+                abort_transaction(&mut storage, &wal, tx).unwrap();
+            }
+        }
+        if rng.below(4) == 0 && checkpoint(&mut storage, &wal).is_err() && failpoint::crashed() {
+            break;
+        }
+    }
+    // Whatever is still buffered goes with the process.
+    outcome
+}
+
+// Inserts a few rows, then commits or rolls back; the one the crash finds
+// open is left open. Only one transaction is ever open at a time: undo puts
+// back the page bytes it found, which would take another transaction's
+// insert on the same page back out with it.
+fn transaction(
+    storage: &mut Storage,
+    wal: &LogManager,
+    rng: &mut Rng,
+    tx: TxId,
+    next_id: &mut i64,
+    outcome: &mut Outcome,
+) -> anyhow::Result<()> {
+    let names = vec!["ID".to_string(), "NAME".to_string()];
+    wal.log_begin(tx)?;
+    for _ in 0..1 + rng.below(20) {
+        let id = *next_id;
+        *next_id += 1;
+        let name = format!("row-{}-{}", id, "x".repeat(rng.below(400) as usize));
+        let rid = storage.insert_row("T", &names, vec![Value::Int(id), Value::String(name)])?;
+        outcome.rows.push((rid, id, tx));
+    }
+    match rng.below(3) {
+        0 => {
+            abort_transaction(storage, wal, tx)?;
+        }
+        _ => {
+            outcome.in_doubt.insert(tx);
+            wal.log_commit(tx)?;
+            outcome.in_doubt.remove(&tx);
+            outcome.committed.insert(tx);
+        }
+    }
+    Ok(())
+}
+
+// Recovers what the workload left on disk and checks every committed row is
+// there, no row of any other transaction is, and a transaction whose commit
+// failed kept all of its rows or none.
+async fn recover_and_verify(db: &str, wal_path: &str, outcome: &Outcome, run: &str) {
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 4).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap_or_else(|e| panic!("{}: recovery failed: {:#}", run, e));
+    let mut storage = storage.write().await;
+
+    // A slot given back by a rollback can be taken again; the last insert
+    // into it is the one that counts.
+    let mut latest: HashMap<RID, (i64, TxId)> = HashMap::new();
+    for &(rid, id, tx) in &outcome.rows {
+        latest.insert(rid, (id, tx));
+    }
+    let mut survivors = Vec::new();
+    let mut stats = TableStats::default();
+    let mut in_doubt: HashMap<TxId, (usize, usize)> = HashMap::new();
+    for (&rid, &(id, tx)) in &latest {
+        // An empty slot is one a rollback gave back.
+        let found = match storage.fetch(rid) {
+            Ok(raw) if !raw.is_empty() => {
+                let row = storage.deserialize_row(&raw).unwrap();
+                assert!(
+                    matches!(row[0], Value::Int(v) if v == id),
+                    "{}: {:?} holds {:?}, not row {}",
+                    run,
+                    rid,
+                    row,
+                    id
+                );
+                survivors.push(rid);
+                stats.add(rid, raw.len());
+                true
+            }
+            _ => false,
+        };
+        if outcome.committed.contains(&tx) {
+            assert!(found, "{}: committed row {} of tx {} is gone", run, id, tx);
+        } else if outcome.in_doubt.contains(&tx) {
+            let (kept, all) = in_doubt.entry(tx).or_default();
+            *kept += found as usize;
+            *all += 1;
+        } else {
+            assert!(
+                !found,
+                "{}: row {} of uncommitted tx {} survived",
+                run, id, tx
+            );
+        }
+    }
+    for (tx, (kept, all)) in in_doubt {
+        assert!(
+            kept == 0 || kept == all,
+            "{}: tx {} kept {} of {} rows",
+            run,
+            tx,
+            kept,
+            all
+        );
+    }
+
+    // The catalog is not kept on disk, and once a checkpoint is past the
+    // CREATE TABLE recovery does not see it either: it and the row list are
+    // put back from what survived before the pages are checked.
+    if storage.catalog.get_table("T").is_err() {
+        storage.catalog.create_table("T".into(), columns()).unwrap();
+    }
+    survivors.sort();
+    let table = storage.catalog.get_table_mut("T").unwrap();
+    table.records = survivors;
+    table.stats = stats;
+    let report = check(&mut storage).unwrap();
+    assert!(report.is_ok(), "{}: {}", run, report);
+}
+
+#[tokio::test]
+async fn test_a_crash_at_any_write_keeps_exactly_the_committed_rows() {
+    let _turn = FAILPOINTS.lock().await;
+    let (db, wal_path) = ("test_crash.db", "test_crash.wal");
+    let mut crashes = 0;
+    for seed in 1..=SEEDS {
+        for point in failpoint::ALL {
+            // A run through without crashing counts the writes there are to
+            // crash at.
+            workload(db, wal_path, seed, None);
+            let hits = failpoint::hits(point);
+            if hits == 0 {
+                continue;
+            }
+            let after = Rng::new(seed ^ hits).below(hits);
+            let outcome = workload(db, wal_path, seed, Some((point, after, Action::Crash)));
+            assert!(failpoint::crashed());
+            failpoint::reset();
+            let run = format!("seed {} crash at {} #{}", seed, point, after + 1);
+            recover_and_verify(db, wal_path, &outcome, &run).await;
+            crashes += 1;
+        }
+    }
+    assert!(crashes >= SEEDS as usize * 2);
+    remove_files(db, wal_path);
+}
+
+#[tokio::test]
+async fn test_a_failed_page_write_rolls_back_only_its_transaction() {
+    let _turn = FAILPOINTS.lock().await;
+    let (db, wal_path) = ("test_crash_error.db", "test_crash_error.wal");
+    for seed in 1..=SEEDS {
+        workload(db, wal_path, seed, None);
+        let hits = failpoint::hits(failpoint::PAGE_WRITE);
+        let after = Rng::new(seed ^ hits).below(hits);
+        let arm = (failpoint::PAGE_WRITE, after, Action::Error);
+        let outcome = workload(db, wal_path, seed, Some(arm));
+        assert!(!failpoint::crashed());
+        assert!(outcome.failed.len() <= 1);
+        for tx in &outcome.failed {
+            assert!(!outcome.committed.contains(tx));
+        }
+        failpoint::reset();
+        let run = format!("seed {} failed write #{}", seed, after + 1);
+        recover_and_verify(db, wal_path, &outcome, &run).await;
+    }
+    remove_files(db, wal_path);
+}
+
+#[tokio::test]
+async fn test_a_crash_before_an_atomic_rename_keeps_the_old_file() {
+    let _turn = FAILPOINTS.lock().await;
+    let path = Path::new("test_crash_atomic.meta");
+    atomic_remove(path).unwrap();
+    atomic_write(path, b"first").unwrap();
+
+    failpoint::arm(failpoint::ATOMIC_RENAME, 0, Action::Crash);
+    assert!(atomic_write(path, b"second").is_err());
+    assert!(failpoint::crashed());
+    failpoint::reset();
+    assert_eq!(atomic_read(path).unwrap().unwrap(), b"first");
+
+    // A current file that fails its checksum is read from the one before.
+    atomic_write(path, b"second").unwrap();
+    atomic_write(path, b"third").unwrap();
+    let mut bytes = std::fs::read(path).unwrap();
+    bytes[0] ^= 0xFF;
+    std::fs::write(path, &bytes).unwrap();
+    assert_eq!(atomic_read(path).unwrap().unwrap(), b"second");
+
+    let mut bytes = std::fs::read(previous_path(path)).unwrap();
+    bytes[0] ^= 0xFF;
+    std::fs::write(previous_path(path), &bytes).unwrap();
+    let err = atomic_read(path).unwrap_err();
+    assert!(err.to_string().contains("fails its checksum"), "{}", err);
+
+    atomic_remove(path).unwrap();
+    assert!(atomic_read(path).unwrap().is_none());
+    assert!(!previous_path(path).exists());
+}
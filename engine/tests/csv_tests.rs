@@ -0,0 +1,59 @@
+use engine::cli::utils::import_csv;
+use engine::net::csv_io::{BadCsv, RowError};
+use engine::query::binder::Value;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use std::fs::{remove_file, write};
+
+fn users(path: &str) -> Storage {
+    let _ = remove_file(path);
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    storage
+        .create_table(
+            "USERS".into(),
+            vec![
+                ColumnInfo {
+                    name: "ID".into(),
+                    data_type: DataType::Int,
+                    collation: Collation::Binary,
+                },
+                ColumnInfo {
+                    name: "NAME".into(),
+                    data_type: DataType::String,
+                    collation: Collation::Binary,
+                },
+            ],
+        )
+        .unwrap();
+    storage
+}
+
+#[test]
+fn test_import_reads_fields_as_the_table_declares_them() {
+    let mut storage = users("test_csv_types.db");
+    let file = "test_csv_types.csv";
+    // `007` is an INT for ID but stays text for NAME.
+    write(file, "name,id\n007,7\nbob,x\n").unwrap();
+    let report = import_csv(&mut storage, "users", file).unwrap();
+    assert_eq!(report.rows_imported, 1);
+    assert_eq!(
+        report.errors,
+        vec![RowError {
+            line: 3,
+            column: Some("ID".into()),
+            error: "Column 'ID': \"x\" is not an integer".into()
+        }]
+    );
+    let rows = storage.scan_table("USERS").unwrap();
+    assert_eq!(rows.len(), 1);
+    assert!(matches!(rows[0][0], Value::Int(7)));
+    assert!(matches!(&rows[0][1], Value::String(s) if s == "007"));
+
+    // A header that does not match the table is refused before any row.
+    write(file, "id,email\n8,b@example.com\n").unwrap();
+    let err = import_csv(&mut storage, "users", file).unwrap_err();
+    assert!(err.downcast_ref::<BadCsv>().is_some(), "{:#}", err);
+    assert_eq!(storage.scan_table("USERS").unwrap().len(), 1);
+
+    remove_file(file).unwrap();
+    remove_file("test_csv_types.db").unwrap();
+}
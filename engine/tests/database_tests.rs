@@ -0,0 +1,1401 @@
+use engine::database::{Database, DatabaseConfig};
+use engine::net::client::{DbError, DbValue};
+use engine::net::copy;
+use engine::net::row::ColumnType;
+use engine::query::binder::Value;
+use engine::testing::fresh_dir;
+use std::sync::Arc;
+
+fn ids(db: &mut Database) -> Vec<i64> {
+    let mut ids: Vec<i64> = db
+        .execute("SELECT id FROM t;")
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| match row[0] {
+            DbValue::Int(i) => i,
+            _ => panic!("expected int"),
+        })
+        .collect();
+    ids.sort();
+    ids
+}
+
+// The first value of each row `sql` returns.
+fn first_column(db: &mut Database, sql: &str) -> Vec<DbValue> {
+    let rows = db.execute(sql).unwrap().rows;
+    rows.into_iter().map(|row| row[0].clone()).collect()
+}
+
+// Each row `sql` returns, its values written out and joined by spaces.
+fn rows_as_text(db: &mut Database, sql: &str) -> Vec<String> {
+    let rows = db.execute(sql).unwrap().rows;
+    rows.iter()
+        .map(|row| {
+            let values: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+            values.join(" ")
+        })
+        .collect()
+}
+
+// The rows of a result that is all INTs.
+fn int_rows(db: &mut Database, sql: &str) -> Vec<Vec<i64>> {
+    let rows = db.execute(sql).unwrap().rows;
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .map(|v| match v {
+                    DbValue::Int(i) => *i,
+                    other => panic!("{}: {:?}", sql, other),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// The lines of `sql`'s plan, without their indentation.
+fn explain(db: &mut Database, sql: &str) -> Vec<String> {
+    let plan = rows_as_text(db, &format!("EXPLAIN {}", sql));
+    plan.iter().map(|line| line.trim().to_string()).collect()
+}
+
+#[test]
+fn test_rows_are_read_by_column_name() {
+    let dir = fresh_dir("db_rows");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("INSERT INTO t (id, name) VALUES (7, 'g');")
+        .unwrap();
+
+    let result = db.execute("SELECT id, UPPER(name) FROM t;").unwrap();
+    let row = &result.rows[0];
+    assert_eq!(row.get::<i64>("id").unwrap(), 7);
+    assert_eq!(row.get::<i64>("ID").unwrap(), 7);
+    assert_eq!(row.get::<String>("upper").unwrap(), "G");
+    let upper = row.get_opt::<String>("upper").unwrap();
+    assert_eq!(upper.as_deref(), Some("G"));
+    let named: Vec<_> = row.named().map(|(n, v)| (n, v.to_string())).collect();
+    assert_eq!(named, [("ID", "7".to_string()), ("upper", "G".to_string())]);
+
+    // Every row shares the result's columns, which know their types.
+    assert!(Arc::ptr_eq(row.schema(), &result.schema));
+    assert_eq!(result.schema.column_type(0), Some(ColumnType::Int));
+    assert_eq!(result.schema.column_type(1), Some(ColumnType::Text));
+
+    let wrong = row.get::<i64>("upper").unwrap_err();
+    assert_eq!(wrong.to_string(), "Column 'upper' is TEXT, not INT");
+    let missing = row.get::<i64>("nope").unwrap_err();
+    assert_eq!(missing.to_string(), "No column 'nope' in the result");
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_errors_are_typed_like_the_clients() {
+    let dir = fresh_dir("db_errors");
+    let mut db = Database::open(&dir).unwrap();
+    let parse = db.execute("SELEC 1;").unwrap_err();
+    assert!(matches!(parse.downcast_ref(), Some(DbError::Parse { .. })));
+    let bind = db.execute("SELECT id FROM missing;").unwrap_err();
+    assert!(matches!(bind.downcast_ref(), Some(DbError::Bind(_))));
+    let commit = db.commit().unwrap_err();
+    assert!(matches!(commit.downcast_ref(), Some(DbError::Execution(_))));
+    drop(db);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_explicit_transactions_commit_and_roll_back() {
+    let dir = fresh_dir("db_transactions");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT);").unwrap();
+
+    db.begin().unwrap();
+    db.execute("INSERT INTO t (id) VALUES (1);").unwrap();
+    db.execute("INSERT INTO t (id) VALUES (2);").unwrap();
+    assert!(db.in_transaction());
+    assert!(db.execute("CREATE TABLE u (id INT);").is_err());
+    db.commit().unwrap();
+    assert_eq!(ids(&mut db), vec![1, 2]);
+
+    db.execute("BEGIN;").unwrap();
+    db.execute("INSERT INTO t (id) VALUES (3);").unwrap();
+    db.execute("ROLLBACK;").unwrap();
+    assert_eq!(ids(&mut db), vec![1, 2]);
+
+    // A failed statement takes its transaction with it.
+    db.begin().unwrap();
+    db.execute("INSERT INTO t (id) VALUES (4);").unwrap();
+    assert!(db.execute("INSERT INTO missing (id) VALUES (5);").is_err());
+    assert!(!db.in_transaction());
+    assert_eq!(ids(&mut db), vec![1, 2]);
+    drop(db);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_show_tables_counts_rows_as_they_come_and_go() {
+    let dir = fresh_dir("db_show_tables");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("CREATE TABLE empty (id INT);").unwrap();
+    let values: Vec<String> = (0..500).map(|i| format!("({}, 'n{}')", i, i)).collect();
+    db.execute(&format!(
+        "INSERT INTO t (id, name) VALUES {};",
+        values.join(", ")
+    ))
+    .unwrap();
+    let show = |db: &mut Database| {
+        let result = db.execute("SHOW TABLES;").unwrap();
+        assert_eq!(
+            result.columns(),
+            vec![
+                "table",
+                "rows",
+                "pages",
+                "bytes",
+                "kind",
+                "inserted",
+                "deleted",
+                "analyzed_at"
+            ]
+        );
+        result.rows
+    };
+    let before = show(&mut db);
+    assert_eq!(before[0][..2], [DbValue::from("EMPTY"), DbValue::Int(0)]);
+    assert_eq!(before[1][..2], [DbValue::from("T"), DbValue::Int(500)]);
+    assert!(matches!(before[1][2], DbValue::Int(pages) if pages > 1));
+
+    db.execute("BEGIN;").unwrap();
+    db.execute("INSERT INTO t (id, name) VALUES (500, 'n500');")
+        .unwrap();
+    assert_eq!(show(&mut db)[1][1], DbValue::Int(501));
+    db.execute("ROLLBACK;").unwrap();
+    assert_eq!(show(&mut db), before);
+
+    let plan = explain(&mut db, "SELECT id FROM t;");
+    let line = "SeqScan on T (~500 rows)".to_string();
+    assert!(plan.contains(&line), "{:?}", plan);
+    // Rows count as inserted until ANALYZE, which starts the count over.
+    assert_eq!(before[1][5..], [500, 0, 0].map(DbValue::Int));
+    db.execute("ANALYZE t;").unwrap();
+    let after = show(&mut db);
+    assert_eq!(after[0], before[0]);
+    assert_eq!(after[1][..5], before[1][..5]);
+    assert_eq!(after[1][5..7], [DbValue::Int(0), DbValue::Int(0)]);
+    assert!(matches!(after[1][7], DbValue::Int(ms) if ms > 0));
+    db.execute("DELETE FROM t WHERE id < 10;").unwrap();
+    assert_eq!(show(&mut db)[1][5..7], [DbValue::Int(0), DbValue::Int(10)]);
+    assert!(db.execute("CHECK;").unwrap().rows.is_empty());
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_analyze_estimates_filters_and_picks_the_scan() {
+    let dir = fresh_dir("db_analyze_estimates");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, status TEXT);").unwrap();
+    db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+    let values: Vec<String> = (0..1000)
+        .map(|i| {
+            let status = match i % 10 {
+                0 => "idle",
+                1 | 2 => "gone",
+                _ => "active",
+            };
+            format!("({}, '{}')", i, status)
+        })
+        .collect();
+    db.execute(&format!(
+        "INSERT INTO t (id, status) VALUES {};",
+        values.join(", ")
+    ))
+    .unwrap();
+    let uses = |plan: &[String], scan: &str| plan.iter().any(|l| l.starts_with(scan));
+
+    // Without stats any usable index is taken, and nothing is estimated.
+    let plan = explain(&mut db, "SELECT id FROM t WHERE id > 10;");
+    assert!(plan.contains(&"Filter".to_string()), "{:?}", plan);
+    assert!(uses(&plan, "IndexOnlyScan"), "{:?}", plan);
+
+    db.execute("ANALYZE t;").unwrap();
+    let plan = explain(&mut db, "SELECT id FROM t WHERE id = 5;");
+    let line = "Filter (~1 rows, 1 actual)".to_string();
+    assert!(plan.contains(&line), "{:?}", plan);
+    assert!(uses(&plan, "IndexOnlyScan"), "{:?}", plan);
+
+    // Nearly every row: reading them all through the index costs more than
+    // reading the table.
+    let plan = explain(&mut db, "SELECT id FROM t WHERE id > 10;");
+    assert!(uses(&plan, "SeqScan"), "{:?}", plan);
+    let filter = plan.iter().find(|l| l.starts_with("Filter")).unwrap();
+    let estimated: u64 = filter["Filter (~".len()..filter.find(" rows").unwrap()]
+        .parse()
+        .unwrap();
+    assert!((950..=1000).contains(&estimated), "{}", filter);
+    assert!(filter.ends_with(", 989 actual)"), "{}", filter);
+
+    // A TEXT column with few values knows each one's count.
+    for (sql, line) in [
+        ("status = 'active'", "Filter (~700 rows, 700 actual)"),
+        ("'gone' = status", "Filter (~200 rows, 200 actual)"),
+        ("status = 'IDLE'", "Filter (~0 rows, 0 actual)"),
+        ("status <> 'idle'", "Filter (~900 rows, 900 actual)"),
+    ] {
+        let plan = explain(&mut db, &format!("SELECT id FROM t WHERE {};", sql));
+        assert!(plan.contains(&line.to_string()), "{}: {:?}", sql, plan);
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_insert_values_are_evaluated() {
+    let dir = fresh_dir("db_insert_exprs");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, flag INT);").unwrap();
+    db.execute("INSERT INTO t (id, flag) VALUES (-1, 2 > 1), ((2), 'a' = 'b');")
+        .unwrap();
+    let flagged = db
+        .execute("SELECT id, flag FROM t WHERE flag = 1;")
+        .unwrap();
+    assert_eq!(flagged.rows, vec![vec![DbValue::Int(-1), DbValue::Int(1)]]);
+    assert_eq!(ids(&mut db), vec![-1, 2]);
+
+    // There is no row for a value to read a column from.
+    let err = db
+        .execute("INSERT INTO t (id, flag) VALUES (3, 1), (4, id = 4);")
+        .unwrap_err();
+    match err.downcast_ref() {
+        Some(DbError::Bind(message)) => assert!(
+            message.contains("INSERT row 2 refers to column 'ID'"),
+            "{}",
+            message
+        ),
+        other => panic!("{:?}", other),
+    }
+    let err = db
+        .execute("INSERT INTO t (id, flag) VALUES (5, 1 = 'x');")
+        .unwrap_err();
+    assert!(err.to_string().contains("Cannot compare"), "{}", err);
+    assert_eq!(ids(&mut db), vec![-1, 2]);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_insert_columns_in_any_order() {
+    let dir = fresh_dir("db_insert_order");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT, age INT);")
+        .unwrap();
+    db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+    db.execute("INSERT INTO t (id, name, age) VALUES (1, 'ann', 30);")
+        .unwrap();
+    db.execute("INSERT INTO t (name, age, id) VALUES ('bob', 40, 2);")
+        .unwrap();
+    db.execute("INSERT INTO t (age, id, name) VALUES (50, 3, 'cy'), (60, 4, 'di');")
+        .unwrap();
+
+    let bob = db
+        .execute("SELECT id, name, age FROM t WHERE id = 2;")
+        .unwrap();
+    assert_eq!(
+        bob.rows,
+        vec![vec![
+            DbValue::Int(2),
+            DbValue::Text("bob".to_string()),
+            DbValue::Int(40)
+        ]]
+    );
+    let rows = db.execute("SELECT name FROM t WHERE age = 60;").unwrap();
+    assert_eq!(rows.rows, vec![vec![DbValue::Text("di".to_string())]]);
+    assert_eq!(ids(&mut db), vec![1, 2, 3, 4]);
+
+    // With no NULLs or defaults, a column left out has nothing to hold.
+    for (sql, expected) in [
+        (
+            "INSERT INTO t (name, id) VALUES ('ed', 5);",
+            "has no value for column 'AGE'",
+        ),
+        (
+            "INSERT INTO t (id, name, id) VALUES (5, 'ed', 6);",
+            "lists column 'ID' twice",
+        ),
+    ] {
+        match db.execute(sql).unwrap_err().downcast_ref() {
+            Some(DbError::Bind(message)) => assert!(message.contains(expected), "{}", message),
+            other => panic!("{}: {:?}", sql, other),
+        }
+    }
+    assert_eq!(ids(&mut db), vec![1, 2, 3, 4]);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_views_read_through_to_their_tables() {
+    let dir = fresh_dir("db_views");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE people (name TEXT, age INT);")
+        .unwrap();
+    db.execute("INSERT INTO people (name, age) VALUES ('al', 12), ('bo', 18), ('cy', 40);")
+        .unwrap();
+    db.execute("CREATE VIEW adults AS SELECT * FROM people WHERE age >= 18;")
+        .unwrap();
+    db.execute("CREATE VIEW names AS SELECT name FROM adults;")
+        .unwrap();
+
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT name FROM adults;"),
+        ["bo", "cy"]
+    );
+    // The query's filter goes under the view's.
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT name, age FROM adults WHERE age < 30;"),
+        ["bo 18"]
+    );
+    let names = db.execute("SELECT * FROM names;").unwrap();
+    assert_eq!(names.columns(), vec!["NAME"]);
+    assert_eq!(
+        names.rows,
+        vec![vec![DbValue::from("bo")], vec![DbValue::from("cy")]]
+    );
+    let plan = explain(&mut db, "SELECT * FROM names;");
+    assert!(format!("{:?}", plan).contains("PEOPLE"), "{:?}", plan);
+
+    let kinds: Vec<_> = db
+        .execute("SHOW TABLES;")
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| (row[0].to_string(), row[4].to_string()))
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            ("ADULTS".to_string(), "view".to_string()),
+            ("NAMES".to_string(), "view".to_string()),
+            ("PEOPLE".to_string(), "table".to_string()),
+        ]
+    );
+
+    for (sql, expected) in [
+        ("SELECT age FROM names;", "Unknown column 'AGE' in 'NAMES'"),
+        ("DROP TABLE people;", "used by view 'ADULTS'"),
+        ("DROP VIEW adults;", "used by view 'NAMES'"),
+        ("CREATE VIEW bad AS SELECT nope FROM people;", "NOPE"),
+        ("CREATE TABLE adults (id INT);", "name of a view"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+
+    db.execute("DROP VIEW names;").unwrap();
+    db.execute("DROP VIEW adults;").unwrap();
+    db.execute("DROP TABLE people;").unwrap();
+    assert!(db.execute("SELECT name FROM adults;").is_err());
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_foreign_keys_are_checked_on_insert() {
+    let dir = fresh_dir("db_foreign_keys");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE users (id INT, name TEXT);")
+        .unwrap();
+    db.execute("CREATE INDEX users_id ON users (id);").unwrap();
+    db.execute("CREATE TABLE orders (id INT, user_id INT REFERENCES users(id));")
+        .unwrap();
+    db.execute("INSERT INTO users (id, name) VALUES (1, 'al'), (2, 'bo');")
+        .unwrap();
+    db.execute("INSERT INTO orders (id, user_id) VALUES (10, 1), (11, 2);")
+        .unwrap();
+
+    let error = db
+        .execute("INSERT INTO orders (id, user_id) VALUES (12, 3);")
+        .unwrap_err();
+    match error.downcast_ref::<DbError>() {
+        Some(DbError::ForeignKeyViolation {
+            constraint, table, ..
+        }) => assert_eq!(
+            (constraint.as_str(), table.as_str()),
+            ("ORDERS_USER_ID_FKEY", "ORDERS")
+        ),
+        other => panic!("{:?}", other),
+    }
+    // The failed statement left nothing behind, and a parent row added in
+    // the same transaction counts.
+    db.execute("BEGIN;").unwrap();
+    db.execute("INSERT INTO users (id, name) VALUES (3, 'cy');")
+        .unwrap();
+    db.execute("INSERT INTO orders (id, user_id) VALUES (12, 3);")
+        .unwrap();
+    db.execute("COMMIT;").unwrap();
+    assert_eq!(db.execute("SELECT id FROM orders;").unwrap().rows.len(), 3);
+
+    for (sql, expected) in [
+        ("DROP TABLE users;", "foreign key 'ORDERS_USER_ID_FKEY'"),
+        ("CREATE TABLE bad (u TEXT REFERENCES users(id));", "types"),
+        ("CREATE TABLE bad (u INT REFERENCES users(nope));", "NOPE"),
+        ("CREATE TABLE bad (u INT REFERENCES nobody(id));", "NOBODY"),
+        ("CREATE TEMP TABLE t (u INT REFERENCES users(x));", "temp"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    assert!(db.execute("SELECT * FROM bad;").is_err());
+
+    db.execute("DROP TABLE orders;").unwrap();
+    db.execute("DROP TABLE users;").unwrap();
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_check_constraints_reject_rows() {
+    let dir = fresh_dir("db_checks");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute(
+        "CREATE TABLE t (age INT CHECK (age >= 0), price INT, qty INT, \
+         CHECK (price * qty < 1000000));",
+    )
+    .unwrap();
+    db.execute("INSERT INTO t (age, price, qty) VALUES (30, 100, 10);")
+        .unwrap();
+
+    let insert = "INSERT INTO t (age, price, qty) VALUES";
+    for (sql, expected) in [
+        (format!("{} (-1, 1, 1);", insert), "age >= 0"),
+        (format!("{} (1, 1000, 1000);", insert), "T_CHECK"),
+        ("CREATE TABLE bad (a INT CHECK (b > 0));".into(), "B"),
+        ("ALTER TABLE t ADD CHECK (nope = 1);".into(), "NOPE"),
+        ("ALTER TABLE t ADD CHECK (qty > 10);".into(), "breaks"),
+    ] {
+        let error = format!("{:#}", db.execute(&sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    assert!(db.execute("SELECT * FROM bad;").is_err());
+    assert_eq!(db.execute("SELECT age FROM t;").unwrap().rows.len(), 1);
+
+    // Once the rows there are fine the constraint is taken, and it holds
+    // for every row inserted after.
+    db.execute("ALTER TABLE t ADD CHECK (qty > 5);").unwrap();
+    let error = db
+        .execute("INSERT INTO t (age, price, qty) VALUES (1, 1, 5);")
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("qty > 5"));
+    db.execute("INSERT INTO t (age, price, qty) VALUES (1, 1, 6);")
+        .unwrap();
+    assert_eq!(db.execute("SELECT age FROM t;").unwrap().rows.len(), 2);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_order_by_and_group_by_take_expressions() {
+    let dir = fresh_dir("db_order_group");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE items (name TEXT, country TEXT, price INT, qty INT);")
+        .unwrap();
+    db.execute(
+        "INSERT INTO items (name, country, price, qty) VALUES \
+         ('a', 'fr', 10, 1), ('b', 'de', 2, 20), ('c', 'FR', 5, 5), ('d', 'de', 1, 1);",
+    )
+    .unwrap();
+
+    let sorted = rows_as_text(
+        &mut db,
+        "SELECT name FROM items ORDER BY price * qty DESC, name;",
+    );
+    assert_eq!(sorted, ["b", "c", "a", "d"]);
+    // A position stands for that item of the SELECT list.
+    let sorted = rows_as_text(&mut db, "SELECT name, qty FROM items ORDER BY 2, 1 DESC;");
+    assert_eq!(sorted, ["d 1", "a 1", "c 5", "b 20"]);
+
+    let grouped = "SELECT UPPER(country), COUNT(*), SUM(price * qty) FROM items \
+                   GROUP BY UPPER(country) ORDER BY SUM(price * qty) DESC;";
+    let result = db.execute(grouped).unwrap();
+    assert_eq!(result.columns(), ["upper", "count", "sum"]);
+    assert_eq!(rows_as_text(&mut db, grouped), ["DE 2 41", "FR 2 35"]);
+    let total = rows_as_text(
+        &mut db,
+        "SELECT COUNT(*), MAX(name) FROM items WHERE qty > 1;",
+    );
+    assert_eq!(total, ["2 c"]);
+
+    for (sql, expected) in [
+        ("SELECT name FROM items ORDER BY 2;", "position 2"),
+        ("SELECT name FROM items ORDER BY nope;", "NOPE"),
+        ("SELECT name FROM items GROUP BY country;", "GROUP BY"),
+        ("SELECT name FROM items WHERE COUNT(*) > 1;", "COUNT"),
+        ("SELECT SUM(name) FROM items;", "INT"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_copy_loads_text_and_binary_rows() {
+    let dir = fresh_dir("db_copy");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT CHECK (name <> 'bad'));")
+        .unwrap();
+    db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+
+    let text = "1\tone\n2\ttab\\there\r\n";
+    let copied = db.copy("COPY t FROM STDIN;", text.as_bytes()).unwrap();
+    assert_eq!(copied.affected, Some(2));
+    let rows: Vec<Vec<Value>> = (3..1000)
+        .map(|id| vec![Value::String(format!("n{}", id)), Value::Int(id)])
+        .collect();
+    let mut binary = Vec::new();
+    copy::write_binary(&mut binary, &rows).unwrap();
+    let copied = db
+        .copy("COPY t (name, id) FROM STDIN BINARY;", &binary[..])
+        .unwrap();
+    assert_eq!(copied.affected, Some(997));
+    assert_eq!(ids(&mut db), (1..1000).collect::<Vec<_>>());
+    // The index was built again over every row, the first two included.
+    let found = db.execute("SELECT name FROM t WHERE id = 2;").unwrap();
+    let expected = DbValue::Text("tab\there".to_string());
+    assert_eq!(found.rows, vec![vec![expected]]);
+    let found = db.execute("SELECT name FROM t WHERE id = 777;").unwrap();
+    assert_eq!(found.rows, vec![vec![DbValue::Text("n777".to_string())]]);
+
+    // One bad row and none of them go in.
+    for (rows, expected) in [
+        ("1000\tk\nsix\tsix\n", "Row 2"),
+        ("1000\tk\n6\n", "Expected 2 fields"),
+        ("1000\tbad\n", "name <> 'bad'"),
+        ("1000\t\\x\n", "Unknown escape"),
+    ] {
+        let error = db.copy("COPY t FROM STDIN;", rows.as_bytes()).unwrap_err();
+        let error = format!("{:#}", error);
+        assert!(error.contains(expected), "{:?}: {}", rows, error);
+    }
+    let error = db.copy("COPY t FROM STDIN BINARY;", &binary[..5]);
+    let error = error.unwrap_err();
+    assert!(format!("{:#}", error).contains("ends inside it"));
+    // Pages already written are rolled back with the rest.
+    let mut rows: Vec<Vec<Value>> = (1000..3000)
+        .map(|id| vec![Value::Int(id), Value::String("k".to_string())])
+        .collect();
+    rows.push(vec![Value::Int(3000), Value::String("bad".to_string())]);
+    let mut binary = Vec::new();
+    copy::write_binary(&mut binary, &rows).unwrap();
+    let error = db.copy("COPY t FROM STDIN BINARY;", &binary[..]);
+    let error = error.unwrap_err();
+    assert!(format!("{:#}", error).contains("Row 2001"));
+    assert!(db.execute("COPY t FROM STDIN;").is_err());
+    assert_eq!(ids(&mut db).len(), 999);
+    let found = db.execute("SELECT name FROM t WHERE id = 1000;").unwrap();
+    assert!(found.rows.is_empty());
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_seq_scan_returns_rows_in_insertion_order() {
+    let dir = fresh_dir("db_scan_order");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE a (id INT, pad TEXT);").unwrap();
+    db.execute("CREATE TABLE b (id INT, pad TEXT);").unwrap();
+    // Rows of both tables share pages, and of varied sizes some go back
+    // to earlier pages that still have room.
+    for id in 0..300 {
+        for table in ["a", "b"] {
+            let pad = "x".repeat(id as usize % 7 * 20);
+            let values = format!("({}, '{}')", id, pad);
+            let sql = format!("INSERT INTO {} (id, pad) VALUES {};", table, values);
+            db.execute(&sql).unwrap();
+        }
+    }
+    let expected: Vec<DbValue> = (0..300).map(DbValue::Int).collect();
+    assert_eq!(first_column(&mut db, "SELECT id FROM a;"), expected);
+    assert_eq!(first_column(&mut db, "SELECT id FROM b;"), expected);
+    // A rolled back row leaves the rest where they were.
+    let insert = |table| format!("INSERT INTO {} (id, pad) VALUES (300, '');", table);
+    db.begin().unwrap();
+    db.execute(&insert("a")).unwrap();
+    db.rollback().unwrap();
+    db.execute(&insert("b")).unwrap();
+    assert_eq!(first_column(&mut db, "SELECT id FROM a;"), expected);
+    let last = first_column(&mut db, "SELECT id FROM b;").pop();
+    assert_eq!(last, Some(DbValue::Int(300)));
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_columns_can_be_projected_more_than_once() {
+    let dir = fresh_dir("db_projection_reuse");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT, city TEXT);")
+        .unwrap();
+    db.execute("INSERT INTO t (id, name, city) VALUES (1, 'ann', 'oslo'), (2, 'bo', 'rome');")
+        .unwrap();
+    // A column read once bare is moved out of the scanned row; the others
+    // have to find it still there.
+    let result = db
+        .execute("SELECT city, name, id, name, UPPER(name), id + 1 FROM t WHERE city <> 'rome';")
+        .unwrap();
+    assert_eq!(
+        result.rows,
+        vec![vec![
+            DbValue::from("oslo"),
+            DbValue::from("ann"),
+            DbValue::Int(1),
+            DbValue::from("ann"),
+            DbValue::from("ANN"),
+            DbValue::Int(2),
+        ]]
+    );
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_is_distinct_from_compares_like_equality() {
+    let dir = fresh_dir("db_distinct_from");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("INSERT INTO t (id, name) VALUES (1, 'a'), (2, 'b'), (3, 'a');")
+        .unwrap();
+    let ints = |values: &[i64]| values.iter().copied().map(DbValue::Int).collect::<Vec<_>>();
+    // With no NULL, the truth table is that of `<>` and `=`.
+    for (sql, expected) in [
+        ("SELECT id IS DISTINCT FROM 2 FROM t;", [1, 0, 1]),
+        ("SELECT id IS NOT DISTINCT FROM 2 FROM t;", [0, 1, 0]),
+        ("SELECT name IS DISTINCT FROM 'a' FROM t;", [0, 1, 0]),
+        ("SELECT name IS NOT DISTINCT FROM 'a' FROM t;", [1, 0, 1]),
+    ] {
+        assert_eq!(first_column(&mut db, sql), ints(&expected), "{}", sql);
+    }
+    // It binds as tightly as `=`, so AND joins two of them.
+    let both = "id IS DISTINCT FROM 1 AND name IS NOT DISTINCT FROM 'a'";
+    let sql = format!("SELECT id FROM t WHERE {};", both);
+    assert_eq!(first_column(&mut db, &sql), ints(&[3]));
+    let sql = "SELECT id FROM t WHERE id + 1 IS NOT DISTINCT FROM 3;";
+    assert_eq!(first_column(&mut db, sql), ints(&[2]));
+
+    db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+    let sql = "SELECT id FROM t WHERE id IS NOT DISTINCT FROM 2;";
+    let plan = explain(&mut db, sql);
+    assert!(format!("{:?}", plan).contains("using T_ID"), "{:?}", plan);
+    assert_eq!(first_column(&mut db, sql), ints(&[2]));
+
+    let mixed = db.execute("SELECT id FROM t WHERE id IS DISTINCT FROM 'a';");
+    assert!(format!("{:#}", mixed.unwrap_err()).contains("Cannot compare INT with TEXT"));
+    let error = db.execute("SELECT id FROM t WHERE id IS 2;").unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(DbError::Parse { .. })));
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_nocase_columns_compare_sort_and_group_without_case() {
+    let dir = fresh_dir("db_nocase");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE p (id INT, name TEXT COLLATE NOCASE, code TEXT);")
+        .unwrap();
+    for (id, name) in [(1, "Alice"), (2, "alice"), (3, "Bob"), (4, "ALICE")] {
+        let values = format!("({}, '{}', '{}')", id, name, name);
+        let sql = format!("INSERT INTO p (id, name, code) VALUES {};", values);
+        db.execute(&sql).unwrap();
+    }
+    // Either side being NOCASE makes a comparison NOCASE; what a function
+    // works out has no collation. Sorting keeps rows that tie without case
+    // in the order they were read.
+    for (condition, expected) in [
+        ("WHERE name = 'alice'", vec![1, 2, 4]),
+        ("WHERE code = 'alice'", vec![2]),
+        ("WHERE name > 'ALICE'", vec![3]),
+        ("WHERE code = name", vec![1, 2, 3, 4]),
+        ("WHERE UPPER(name) = 'alice'", vec![]),
+        ("ORDER BY name DESC", vec![3, 1, 2, 4]),
+        ("ORDER BY code", vec![4, 1, 3, 2]),
+    ] {
+        let sql = format!("SELECT id FROM p {};", condition);
+        let rows = db.execute(&sql).unwrap().rows;
+        let ids: Vec<DbValue> = rows.into_iter().map(|row| row[0].clone()).collect();
+        let expected: Vec<DbValue> = expected.into_iter().map(DbValue::Int).collect();
+        assert_eq!(ids, expected, "{}", sql);
+    }
+
+    let grouped = db.execute("SELECT name, COUNT(*) FROM p GROUP BY name;");
+    let expected = vec![
+        vec![DbValue::from("Alice"), DbValue::Int(3)],
+        vec![DbValue::from("Bob"), DbValue::Int(1)],
+    ];
+    assert_eq!(grouped.unwrap().rows, expected);
+    let grouped = db.execute("SELECT COUNT(*) FROM p GROUP BY code;");
+    assert_eq!(grouped.unwrap().rows.len(), 4);
+    let extremes = db.execute("SELECT MIN(name), MAX(name), MAX(code) FROM p;");
+    let expected = vec![
+        DbValue::from("Alice"),
+        DbValue::from("Bob"),
+        DbValue::from("alice"),
+    ];
+    assert_eq!(extremes.unwrap().rows, vec![expected]);
+
+    // CHECK conditions compare by the column's collation too.
+    let check = db.execute("ALTER TABLE p ADD CHECK (name <> 'root');");
+    check.unwrap();
+    let root = db.execute("INSERT INTO p (id, name, code) VALUES (5, 'ROOT', 'x');");
+    assert!(root.is_err());
+
+    let error = db.execute("CREATE TABLE q (id INT COLLATE NOCASE);");
+    let error = format!("{:#}", error.unwrap_err());
+    assert!(error.contains("needs a TEXT column, 'ID' is INT"));
+    let error = db.execute("CREATE TABLE q (name TEXT COLLATE FRENCH);");
+    let error = error.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(DbError::Parse { .. })));
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_integer_arithmetic_errors_out_of_range() {
+    let dir = fresh_dir("db_int_range");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE n (id INT, v INT);").unwrap();
+    // The least INT has no literal, so it is worked out. SUM reads the rows
+    // in this order and passes i64::MAX on the way to 0.
+    let max = "9223372036854775807";
+    for (id, v) in [(1, max), (3, "1"), (2, "-9223372036854775807 - 1")] {
+        let sql = format!("INSERT INTO n (id, v) VALUES ({}, {});", id, v);
+        db.execute(&sql).unwrap();
+    }
+    for (expr, id, expected) in [
+        ("v + 0", 1, i64::MAX),
+        ("v * -1", 1, -i64::MAX),
+        ("v / 1", 2, i64::MIN),
+        ("v + 1", 2, i64::MIN + 1),
+    ] {
+        let sql = format!("SELECT {} FROM n WHERE id = {};", expr, id);
+        assert_eq!(first_column(&mut db, &sql), [DbValue::Int(expected)]);
+    }
+    for (expr, id, expected) in [
+        ("v + 1", 1, "out of range: 9223372036854775807 + 1"),
+        ("v - 1", 2, "out of range: -9223372036854775808 - 1"),
+        ("v * 2", 1, "out of range: 9223372036854775807 * 2"),
+        ("v / -1", 2, "out of range: -9223372036854775808 / -1"),
+        ("v / 0", 1, "Division by zero"),
+        ("9223372036854775808", 1, "out of range for INT"),
+    ] {
+        let sql = format!("SELECT {} FROM n WHERE id = {};", expr, id);
+        let error = format!("{:#}", db.execute(&sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", expr, error);
+    }
+
+    let sum = db.execute("SELECT SUM(v) FROM n;").unwrap();
+    assert_eq!(sum.rows, vec![vec![DbValue::Int(0)]]);
+    let sum = db.execute("SELECT SUM(v) FROM n WHERE v > 0;").unwrap_err();
+    assert!(format!("{:#}", sum).contains("SUM is 9223372036854775808"));
+    let sql = format!("INSERT INTO n (id, v) VALUES (4, {} + 1);", max);
+    assert!(db.execute(&sql).is_err());
+    let count = db.execute("SELECT COUNT(*) FROM n;").unwrap();
+    assert_eq!(count.rows, vec![vec![DbValue::Int(3)]]);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rename_table_and_index() {
+    let dir = fresh_dir("db_rename");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE users (id INT, name TEXT);")
+        .unwrap();
+    db.execute("CREATE INDEX users_id ON users (id);").unwrap();
+    db.execute("CREATE TABLE orders (id INT, user_id INT REFERENCES users(id));")
+        .unwrap();
+    db.execute("CREATE TABLE seen (id INT);").unwrap();
+    db.execute("CREATE VIEW everyone AS SELECT * FROM seen;")
+        .unwrap();
+    db.execute("INSERT INTO users (id, name) VALUES (1, 'al'), (2, 'bo');")
+        .unwrap();
+
+    db.execute("ALTER TABLE users RENAME TO people;").unwrap();
+    db.execute("ALTER INDEX users_id RENAME TO people_id;")
+        .unwrap();
+    assert!(db.execute("SELECT * FROM users;").is_err());
+    let names = db.execute("SELECT name FROM people WHERE id = 2;").unwrap();
+    assert_eq!(names.rows, vec![vec![DbValue::Text("bo".to_string())]]);
+    let plan = db
+        .execute("EXPLAIN SELECT name FROM people WHERE id = 2;")
+        .unwrap();
+    assert!(format!("{:?}", plan.rows).contains("PEOPLE_ID"));
+    // The foreign key follows its parent to the new name.
+    db.execute("INSERT INTO orders (id, user_id) VALUES (10, 1);")
+        .unwrap();
+    assert!(
+        db.execute("INSERT INTO orders (id, user_id) VALUES (11, 3);")
+            .is_err()
+    );
+
+    for (sql, expected) in [
+        ("ALTER TABLE people RENAME TO orders;", "already exists"),
+        ("ALTER TABLE people RENAME TO everyone;", "name of a view"),
+        ("ALTER TABLE seen RENAME TO gone;", "view 'EVERYONE'"),
+        ("ALTER TABLE nope RENAME TO other;", "not found"),
+        ("ALTER INDEX users_id RENAME TO other;", "not found"),
+        ("ALTER TABLE people DROP TO other;", "ADD or RENAME"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    assert_eq!(db.execute("SELECT id FROM seen;").unwrap().rows.len(), 0);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_scalar_functions() {
+    let dir = fresh_dir("db_scalar_functions");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("INSERT INTO t (id, name) VALUES (-7, 'b'), (3, 'a'), (10, 'c');")
+        .unwrap();
+    assert_eq!(
+        rows_as_text(
+            &mut db,
+            "SELECT ABS(id), MOD(id, 2), MIN(id, 5), MAX(name, 'b') FROM t ORDER BY id;"
+        ),
+        ["7 -1 -7 b", "3 1 3 b", "10 0 5 c"]
+    );
+    // MIN and MAX of one value are still aggregates.
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT MIN(id), MAX(MIN(id, 5)) FROM t;"),
+        ["-7 5"]
+    );
+    assert_eq!(
+        rows_as_text(
+            &mut db,
+            "SELECT TYPEOF(id), TYPEOF(UPPER(name)) FROM t WHERE id = 3;"
+        ),
+        ["INT TEXT"]
+    );
+
+    // The same seed makes the same numbers again.
+    let draw = |db: &mut Database| first_column(db, "SELECT MOD(RANDOM(), 1000) FROM t;");
+    db.execute("SELECT SETSEED(42);").unwrap();
+    let first = draw(&mut db);
+    assert_ne!(draw(&mut db), first);
+    db.execute("SELECT SETSEED(42);").unwrap();
+    assert_eq!(draw(&mut db), first);
+    let result = db.execute("SELECT RANDOM() FROM t;").unwrap();
+    assert!(
+        result
+            .rows
+            .iter()
+            .all(|row| row.get::<i64>("random").unwrap() >= 0)
+    );
+
+    for (sql, expected) in [
+        ("SELECT MOD(id, 0) FROM t;", "Division by zero"),
+        ("SELECT ABS(name) FROM t;", "ABS needs an INT argument"),
+        ("SELECT MOD(id) FROM t;", "MOD takes two arguments"),
+        ("SELECT MIN(id, name) FROM t;", "same type"),
+        ("SELECT TYPEOF(id, id) FROM t;", "TYPEOF takes one argument"),
+        ("SELECT RANDOM(1);", "RANDOM takes no arguments"),
+        ("SELECT SETSEED('x');", "SETSEED needs an INT argument"),
+        ("SELECT ABS(-9223372036854775807 - 1);", "out of range"),
+        ("SELECT id FROM t WHERE MIN(id) > 0;", "cannot be used here"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_coalesce_and_nullif() {
+    let dir = fresh_dir("db_coalesce_nullif");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("INSERT INTO t (id, name) VALUES (1, 'a'), (2, 'b');")
+        .unwrap();
+    // Nothing is NULL yet, so COALESCE never gets past its first argument:
+    // the division by zero after it is not evaluated.
+    assert_eq!(
+        rows_as_text(
+            &mut db,
+            "SELECT COALESCE(name, 'anonymous'), COALESCE(id, MOD(id, 0)) FROM t ORDER BY id;"
+        ),
+        ["a 1", "b 2"]
+    );
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT NULLIF(name, 'b') FROM t WHERE id = 1;"),
+        ["a"]
+    );
+
+    for (sql, expected) in [
+        ("SELECT NULLIF(name, 'b') FROM t;", "would be NULL"),
+        ("SELECT COALESCE(id, name) FROM t;", "same type"),
+        ("SELECT COALESCE() FROM t;", "at least one argument"),
+        ("SELECT NULLIF(id) FROM t;", "NULLIF takes two arguments"),
+        ("SELECT NULLIF(id, name) FROM t;", "same type"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_date_trunc_extract_and_intervals() {
+    let dir = fresh_dir("db_date_functions");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE events (id INT, at INT);").unwrap();
+    // 2024-02-29 13:45:30, 1969-12-31 23:59:59 and 2023-12-31 00:00:00 UTC.
+    db.execute("INSERT INTO events (id, at) VALUES (1, 1709214330), (2, -1), (3, 1703980800);")
+        .unwrap();
+
+    assert_eq!(
+        int_rows(
+            &mut db,
+            "SELECT DATE_TRUNC('minute', at), DATE_TRUNC('hour', at), DATE_TRUNC('day', at), \
+             DATE_TRUNC('week', at), DATE_TRUNC('month', at), DATE_TRUNC('year', at) \
+             FROM events ORDER BY id;"
+        ),
+        [
+            [
+                1709214300, 1709211600, 1709164800, 1708905600, 1706745600, 1704067200
+            ],
+            [-60, -3600, -86400, -259200, -2678400, -31536000],
+            [
+                1703980800, 1703980800, 1703980800, 1703462400, 1701388800, 1672531200
+            ],
+        ]
+    );
+    assert_eq!(
+        int_rows(
+            &mut db,
+            "SELECT EXTRACT(YEAR FROM at), EXTRACT(MONTH FROM at), EXTRACT(DAY FROM at), \
+             EXTRACT(HOUR FROM at), EXTRACT(MINUTE FROM at), EXTRACT(SECOND FROM at), \
+             EXTRACT(DOW FROM at), EXTRACT(DOY FROM at) FROM events ORDER BY id;"
+        ),
+        [
+            [2024, 2, 29, 13, 45, 30, 4, 60],
+            [1969, 12, 31, 23, 59, 59, 3, 365],
+            [2023, 12, 31, 0, 0, 0, 0, 365],
+        ]
+    );
+
+    // Intervals are seconds, so they add to times and the results compare.
+    assert_eq!(
+        int_rows(
+            &mut db,
+            "SELECT id, at + INTERVAL '7' DAY - at FROM events \
+             WHERE at + INTERVAL '61' days > 1709214330 ORDER BY id;"
+        ),
+        [[1, 604800], [3, 604800]]
+    );
+    assert_eq!(
+        int_rows(
+            &mut db,
+            "SELECT id FROM events WHERE DATE_TRUNC('day', at) <= at - INTERVAL '13' HOUR \
+             ORDER BY DATE_TRUNC('year', at) DESC;"
+        ),
+        [[1], [2]]
+    );
+    assert_eq!(
+        int_rows(
+            &mut db,
+            "SELECT EXTRACT(YEAR FROM at), COUNT(*) FROM events \
+             GROUP BY EXTRACT(YEAR FROM at) ORDER BY EXTRACT(YEAR FROM at);"
+        ),
+        [[1969, 1], [2023, 1], [2024, 1]]
+    );
+
+    for (sql, expected) in [
+        (
+            "SELECT DATE_TRUNC('fortnight', at) FROM events;",
+            "Unknown DATE_TRUNC unit",
+        ),
+        (
+            "SELECT EXTRACT(CENTURY FROM at) FROM events;",
+            "Unknown EXTRACT field",
+        ),
+        (
+            "SELECT EXTRACT(YEAR FROM 'today') FROM events;",
+            "EXTRACT needs an INT time",
+        ),
+        (
+            "SELECT at + INTERVAL '1' MONTH FROM events;",
+            "differ in length",
+        ),
+        (
+            "SELECT at + INTERVAL 'soon' DAY FROM events;",
+            "whole number",
+        ),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_union_all() {
+    let dir = fresh_dir("db_union_all");
+    let mut db = Database::open(&dir).unwrap();
+    for sql in [
+        "CREATE TABLE a (id INT, name TEXT);",
+        "CREATE TABLE b (id INT, name TEXT);",
+        "CREATE INDEX a_id ON a (id);",
+        "CREATE INDEX b_id ON b (id);",
+        "INSERT INTO a (id, name) VALUES (5, 'a5'), (1, 'a1'), (9, 'a9'), (3, 'a3');",
+        "INSERT INTO b (id, name) VALUES (4, 'b4'), (3, 'b3'), (10, 'b10'), (0, 'b0');",
+    ] {
+        db.execute(sql).unwrap();
+    }
+    // Without ORDER BY, each SELECT's rows follow the last's.
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT name FROM a UNION ALL SELECT name FROM b;"),
+        ["a5", "a1", "a9", "a3", "b4", "b3", "b10", "b0"]
+    );
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT 1 UNION ALL SELECT 2 ORDER BY 1 DESC;"),
+        ["2", "1"]
+    );
+
+    // Both sides come out of an index in order, so they are merged; read
+    // whole, they are sorted. A tie goes to the first SELECT either way.
+    let merged = "SELECT id, name FROM a WHERE id >= 0 \
+                  UNION ALL SELECT id, name FROM b WHERE id >= 0 ORDER BY id;";
+    let sorted = "SELECT id, name FROM a UNION ALL SELECT id, name FROM b ORDER BY 1;";
+    let plan = explain(&mut db, merged);
+    assert!(plan[0].starts_with("Merge (2 inputs"), "{:?}", plan);
+    let plan = explain(&mut db, sorted);
+    assert!(plan[0].starts_with("Sort"), "{:?}", plan);
+    assert_eq!(plan[1], "Append (2 inputs)");
+    let expected = [
+        "0 b0", "1 a1", "3 a3", "3 b3", "4 b4", "5 a5", "9 a9", "10 b10",
+    ];
+    assert_eq!(rows_as_text(&mut db, merged), expected);
+    assert_eq!(rows_as_text(&mut db, sorted), expected);
+    // An order the indexes do not give is still sorted.
+    let descending = merged.replace("ORDER BY id", "ORDER BY id DESC");
+    let plan = explain(&mut db, &descending);
+    assert!(plan[0].starts_with("Sort"), "{:?}", plan);
+    assert_eq!(rows_as_text(&mut db, &descending)[0], "10 b10");
+
+    for (tail, expected) in [
+        ("SELECT id, name FROM b;", "needs 1 columns"),
+        ("SELECT name FROM b;", "Column 1 of SELECT 2"),
+        ("SELECT id FROM b ORDER BY name;", "not in the result"),
+        ("SELECT id FROM b ORDER BY 2;", "position 2"),
+    ] {
+        let sql = format!("SELECT id FROM a UNION ALL {}", tail);
+        let error = format!("{:#}", db.execute(&sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    for (sql, expected) in [
+        ("SELECT id FROM a UNION SELECT id FROM b;", "Expected All"),
+        ("SELECT 1 FROM a ORDER BY 1 UNION ALL SELECT 1;", "last"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_exists() {
+    let dir = fresh_dir("db_exists");
+    let mut db = Database::open(&dir).unwrap();
+    for sql in [
+        "CREATE TABLE customers (id INT, name TEXT);",
+        "CREATE TABLE orders (id INT, customer INT, total INT);",
+        "INSERT INTO customers (id, name) VALUES (1, 'ann'), (2, 'bob'), (3, 'cy'), (4, 'di');",
+        "INSERT INTO orders (id, customer, total) VALUES (1, 1, 50), (2, 1, 500), (3, 3, 20);",
+    ] {
+        db.execute(sql).unwrap();
+    }
+    // Ann has two orders but comes out once.
+    let has_orders = "SELECT name FROM customers \
+                      WHERE EXISTS (SELECT 1 FROM orders WHERE orders.customer = customers.id);";
+    assert_eq!(rows_as_text(&mut db, has_orders), ["ann", "cy"]);
+    let no_orders = has_orders.replace("WHERE EXISTS", "WHERE NOT EXISTS");
+    assert_eq!(rows_as_text(&mut db, &no_orders), ["bob", "di"]);
+    let plan = explain(&mut db, has_orders);
+    assert_eq!(plan[1], "HashSemiJoin (1 keys)");
+    let plan = explain(&mut db, &no_orders);
+    assert_eq!(plan[1], "HashAntiJoin (1 keys)");
+
+    // The subquery's other conditions pick which of its rows count, and
+    // the query's own still apply.
+    let big = "SELECT name FROM customers WHERE id > 0 AND NOT EXISTS \
+               (SELECT * FROM orders WHERE customers.id = customer AND total > 100);";
+    assert_eq!(rows_as_text(&mut db, big), ["bob", "cy", "di"]);
+    // Without an equality, every row finds the same answer.
+    let none =
+        "SELECT name FROM customers WHERE EXISTS (SELECT id FROM orders WHERE total > 1000);";
+    assert!(rows_as_text(&mut db, none).is_empty());
+    let all = none.replace("WHERE EXISTS", "WHERE NOT EXISTS");
+    assert_eq!(rows_as_text(&mut db, &all).len(), 4);
+
+    for (condition, expected) in [
+        ("orders.customer < customers.id", "has to be an equality"),
+        ("customer = customers.name", "not of one type"),
+        ("customers.id = 1", "belongs in the outer WHERE"),
+        ("shops.id = customer", "not a table this query reads"),
+    ] {
+        let sql = format!(
+            "SELECT name FROM customers WHERE EXISTS (SELECT 1 FROM orders WHERE {});",
+            condition
+        );
+        let error = format!("{:#}", db.execute(&sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    let sql = "SELECT name FROM customers WHERE id = 2 OR EXISTS (SELECT 1 FROM orders);";
+    let error = format!("{:#}", db.execute(sql).unwrap_err());
+    assert!(error.contains("ANDs together"), "{}", error);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_schemas_keep_tables_apart() {
+    let dir = fresh_dir("db_schemas");
+    let mut db = Database::open(&dir).unwrap();
+    for sql in [
+        "CREATE SCHEMA app;",
+        "CREATE TABLE users (id INT, name TEXT);",
+        "CREATE TABLE app.users (id INT, name TEXT);",
+        "CREATE TABLE app.orders (id INT, user_id INT REFERENCES app.users(id));",
+        "CREATE VIEW app.named AS SELECT name FROM app.users;",
+        "INSERT INTO users (id, name) VALUES (1, 'public');",
+        "INSERT INTO app.users (id, name) VALUES (1, 'app');",
+        "INSERT INTO app.orders (id, user_id) VALUES (10, 1);",
+    ] {
+        db.execute(sql).unwrap();
+    }
+    assert_eq!(rows_as_text(&mut db, "SELECT name FROM users;"), ["public"]);
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT name FROM public.users;"),
+        ["public"]
+    );
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT name FROM app.users;"),
+        ["app"]
+    );
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT name FROM app.named;"),
+        ["app"]
+    );
+    assert_eq!(
+        first_column(&mut db, "SHOW TABLES IN app;"),
+        ["NAMED", "ORDERS", "USERS"].map(DbValue::from)
+    );
+    assert_eq!(
+        first_column(&mut db, "SHOW TABLES;"),
+        ["APP.NAMED", "APP.ORDERS", "APP.USERS", "USERS"].map(DbValue::from)
+    );
+    db.execute("ALTER TABLE app.orders RENAME TO sales;")
+        .unwrap();
+    assert_eq!(rows_as_text(&mut db, "SELECT id FROM app.sales;"), ["10"]);
+
+    for (sql, expected) in [
+        ("CREATE SCHEMA app;", "already exists"),
+        ("CREATE SCHEMA public;", "already exists"),
+        ("CREATE TABLE nowhere.t (id INT);", "'NOWHERE' not found"),
+        ("CREATE TEMP TABLE app.t (id INT);", "in no schema"),
+        ("SELECT id FROM nowhere.t;", "NOWHERE.T"),
+        ("SHOW TABLES IN nowhere;", "Schema 'NOWHERE' not found"),
+        ("DROP SCHEMA public;", "cannot be dropped"),
+        ("DROP SCHEMA app;", "CASCADE"),
+        (
+            "INSERT INTO app.sales (id, user_id) VALUES (2, 2);",
+            "foreign key",
+        ),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+
+    // A view elsewhere still reading one of its tables keeps the schema, and
+    // what CASCADE dropped before finding that out comes back.
+    db.execute("CREATE VIEW everyone AS SELECT * FROM app.users;")
+        .unwrap();
+    let error = format!("{:#}", db.execute("DROP SCHEMA app CASCADE;").unwrap_err());
+    assert!(error.contains("EVERYONE"), "{}", error);
+    assert_eq!(
+        rows_as_text(&mut db, "SELECT name FROM app.named;"),
+        ["app"]
+    );
+    assert_eq!(rows_as_text(&mut db, "SELECT id FROM app.sales;"), ["10"]);
+    db.execute("DROP VIEW everyone;").unwrap();
+    db.execute("DROP SCHEMA app CASCADE;").unwrap();
+    assert_eq!(
+        first_column(&mut db, "SHOW TABLES;"),
+        [DbValue::from("USERS")]
+    );
+    assert!(db.execute("SHOW TABLES IN app;").is_err());
+    db.execute("CREATE SCHEMA app;").unwrap();
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sorts_spill_past_work_mem_and_aggregates_run_out() {
+    let dir = fresh_dir("db_work_mem");
+    let config = DatabaseConfig {
+        work_mem: 8 * 1024,
+        ..DatabaseConfig::new(&dir)
+    };
+    let mut db = Database::open(config).unwrap();
+    db.execute("CREATE TABLE t (id INT, grp INT, name TEXT);")
+        .unwrap();
+    let values: Vec<String> = (0..1000)
+        .map(|i| format!("({}, {}, 'row {}')", i, (i * 7919) % 13, i))
+        .collect();
+    db.execute(&format!(
+        "INSERT INTO t (id, grp, name) VALUES {};",
+        values.join(", ")
+    ))
+    .unwrap();
+
+    // Far more rows than fit, sorted in runs and merged; ties keep the
+    // order the rows were inserted in.
+    let mut expected: Vec<Vec<i64>> = (0..1000).map(|i| vec![(i * 7919) % 13, i]).collect();
+    expected.sort_by_key(|row| std::cmp::Reverse(row[0]));
+    assert_eq!(
+        int_rows(&mut db, "SELECT grp, id FROM t ORDER BY grp DESC;"),
+        expected
+    );
+
+    let explained = explain(&mut db, "ANALYZE SELECT grp, id FROM t ORDER BY grp, name;");
+    assert!(
+        explained.contains(&"Rows: 1000".to_string()),
+        "{:?}",
+        explained
+    );
+    let memory = explained.last().unwrap();
+    assert!(memory.starts_with("Memory: "), "{}", memory);
+    assert!(memory.contains("of 8192 work_mem"), "{}", memory);
+    assert!(!memory.ends_with(" 0 runs spilled"), "{}", memory);
+    // Only analyzing runs a statement.
+    assert!(
+        db.execute("EXPLAIN ANALYZE INSERT INTO t (id, grp, name) VALUES (1, 1, 'a');")
+            .is_err()
+    );
+
+    // A handful of groups fit; one a row cannot spill.
+    assert_eq!(
+        int_rows(&mut db, "SELECT grp, COUNT(*) FROM t GROUP BY grp;").len(),
+        13
+    );
+    let error = db
+        .execute("SELECT id, COUNT(*) FROM t GROUP BY id;")
+        .unwrap_err();
+    match error.downcast_ref::<DbError>() {
+        Some(DbError::OutOfMemory {
+            message,
+            operator,
+            budget,
+        }) => {
+            assert_eq!((operator.as_str(), *budget), ("Aggregate", 8192));
+            assert!(message.contains("work_mem"), "{}", message);
+        }
+        other => panic!("expected an out of memory error, got {:?}", other),
+    }
+    // Nothing stays held once a statement is done.
+    assert_eq!(
+        int_rows(
+            &mut db,
+            "SELECT grp, COUNT(*) FROM t WHERE grp < 2 GROUP BY grp;"
+        ),
+        [[0, 77], [1, 77]]
+    );
+    drop(db);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_placeholders_take_values_and_are_described() {
+    let dir = fresh_dir("db_placeholders");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE users (id INT, name TEXT);").unwrap();
+    let insert = "INSERT INTO users (id, name) VALUES (?, ?), (?, '?');";
+    let params = [Value::Int(-1), Value::from("o'brien -- ?"), Value::Int(2)];
+    assert_eq!(db.execute_with(insert, &params).unwrap().affected, Some(2));
+    let names = db
+        .query_map(
+            "SELECT name FROM users WHERE id = ? OR name = ?;",
+            &[Value::Int(2), Value::from("o'brien -- ?")],
+            |row| row.get::<String>("name"),
+        )
+        .unwrap();
+    assert_eq!(names, vec!["o'brien -- ?".to_string(), "?".to_string()]);
+    let err = db.execute_with(insert, &params[..2]).unwrap_err();
+    assert!(err.to_string().contains("3 placeholders but 2 values"), "{}", err);
+
+    let described = db
+        .describe("SELECT id, name FROM users WHERE name = ? AND ? < id;")
+        .unwrap();
+    assert_eq!(
+        described.columns,
+        vec![
+            ("ID".to_string(), ColumnType::Int),
+            ("NAME".to_string(), ColumnType::Text)
+        ]
+    );
+    assert_eq!(
+        described.params,
+        vec![Some(ColumnType::Text), Some(ColumnType::Int)]
+    );
+    let described = db.describe("INSERT INTO users (name, id) VALUES (?, ?);").unwrap();
+    assert_eq!(
+        described.params,
+        vec![Some(ColumnType::Text), Some(ColumnType::Int)]
+    );
+    let err = db.describe("SELECT nope FROM users WHERE id = ?;").unwrap_err();
+    assert!(
+        matches!(err.downcast_ref::<DbError>(), Some(DbError::Bind(_))),
+        "{}",
+        err
+    );
+    // Nothing ran.
+    assert_eq!(ids_of(&mut db), vec![-1, 2]);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn ids_of(db: &mut Database) -> Vec<i64> {
+    let result = db.execute("SELECT id FROM users;").unwrap();
+    result.rows.iter().map(|row| row.get("id").unwrap()).collect()
+}
+
+#[test]
+fn test_variables_are_bound_in_statements() {
+    let dir = fresh_dir("db_variables");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE users (id INT, name TEXT);").unwrap();
+    db.execute("SET @id = 7;").unwrap();
+    db.execute("SET @name = 'ada';").unwrap();
+    db.execute("INSERT INTO users (id, name) VALUES (@id, @name), (@id + 1, 'bob');")
+        .unwrap();
+    let result = db.execute("SELECT name FROM users WHERE id > @id;").unwrap();
+    assert_eq!(result.rows[0].get::<String>("name").unwrap(), "bob");
+    let err = db.execute("SELECT name FROM users WHERE id = @nope;").unwrap_err();
+    assert!(
+        matches!(err.downcast_ref::<DbError>(), Some(DbError::Bind(m)) if m.contains("'@NOPE'")),
+        "{}",
+        err
+    );
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,271 @@
+use engine::net::client::{DbError, DbValue};
+use engine::testing::TestDb;
+use engine::tx::log_manager::{MasterRecord, WAL_HEADER_SIZE, segment_path};
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+fn ids(db: &mut TestDb) -> Vec<i64> {
+    let mut ids: Vec<i64> = db
+        .rows("SELECT id FROM t;")
+        .into_iter()
+        .map(|row| row[0].as_i64().unwrap())
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_execute_autocommits_and_reports_rows() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE t (id INT, name TEXT);");
+    let inserted = db.execute("INSERT INTO t (id, name) VALUES (1, 'a'), (2, 'b');");
+    assert_eq!(inserted.affected, Some(2));
+
+    let result = db.execute("SELECT name FROM t WHERE id = 1;");
+    assert_eq!(result.columns(), vec!["NAME"]);
+    db.assert_rows("SELECT name FROM t WHERE id = 1;", [vec!["a".into()]]);
+    assert!(!db.db().in_transaction());
+}
+
+#[test]
+fn test_reopen_after_drop_with_open_transaction() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE t (id INT);");
+    db.execute("BEGIN;");
+    db.execute("INSERT INTO t (id) VALUES (1);");
+    // Closed with the transaction still open.
+    db.restart();
+    assert!(!db.db().in_transaction());
+    assert_eq!(ids(&mut db), Vec::<i64>::new());
+    db.execute("INSERT INTO t (id) VALUES (2);");
+    assert_eq!(ids(&mut db), vec![2]);
+}
+
+#[test]
+fn test_index_is_used_and_finds_rows() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE t (id INT, name TEXT);");
+    db.execute("CREATE INDEX t_id ON t (id);");
+    let values: Vec<String> = (0..300).map(|i| format!("({}, 'n{}')", i, i)).collect();
+    db.execute(&format!(
+        "INSERT INTO t (id, name) VALUES {};",
+        values.join(", ")
+    ));
+    let plan = db.rows("EXPLAIN SELECT name FROM t WHERE id = 123;");
+    assert!(
+        plan.iter()
+            .any(|row| matches!(&row[0], DbValue::Text(line) if line.contains("IndexScan"))),
+        "{:?}",
+        plan
+    );
+    db.assert_rows("SELECT name FROM t WHERE id = 123;", [vec!["n123".into()]]);
+    db.assert_row_set(
+        "SELECT id FROM t WHERE id > 296;",
+        [vec![297.into()], vec![298.into()], vec![299.into()]],
+    );
+    db.assert_rows("SELECT id FROM t WHERE id = 300;", []);
+}
+
+#[test]
+fn test_index_on_text_and_several_columns() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE t (id INT, name TEXT, city TEXT);");
+    db.execute("CREATE INDEX t_city_name ON t (city, name);");
+    db.execute(
+        "INSERT INTO t (id, name, city) VALUES (1, 'a', 'Oslo'), (2, 'b', 'Oslo'), (3, 'a', 'Rome');",
+    );
+    let plan = db.rows("EXPLAIN SELECT id FROM t WHERE city = 'Oslo' AND name = 'b';");
+    assert!(
+        plan.iter().any(|row| matches!(&row[0],
+            DbValue::Text(line) if line.contains("using T_CITY_NAME (CITY, NAME)"))),
+        "{:?}",
+        plan
+    );
+    // Rows with the same key in every column are refused.
+    let err = db
+        .try_execute("INSERT INTO t (id, name, city) VALUES (4, 'a', 'Rome');")
+        .unwrap_err();
+    assert!(err.to_string().contains("Duplicate"), "{}", err);
+
+    db.assert_rows(
+        "SELECT id FROM t WHERE city = 'Oslo' AND name = 'b';",
+        [vec![2.into()]],
+    );
+    db.assert_row_set(
+        "SELECT id FROM t WHERE city = 'Oslo';",
+        [vec![1.into()], vec![2.into()]],
+    );
+}
+
+#[test]
+fn test_recovery_after_kill_keeps_only_committed_rows() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE t (id INT, name TEXT);");
+    for i in 0..20 {
+        db.execute(&format!(
+            "INSERT INTO t (id, name) VALUES ({}, 'survivor {}');",
+            i, i
+        ));
+    }
+    db.execute("BEGIN;");
+    db.execute("INSERT INTO t (id, name) VALUES (1000, 'uncommitted');");
+    db.kill();
+
+    // Redo put back what never left the pool, and undo took out the open
+    // transaction's row.
+    assert!(!db.db().in_transaction());
+    assert_eq!(ids(&mut db), (0..20).collect::<Vec<_>>());
+    db.assert_rows(
+        "SELECT name FROM t WHERE id = 7;",
+        [vec!["survivor 7".into()]],
+    );
+    db.assert_rows("SELECT id FROM t WHERE name = 'uncommitted';", []);
+    db.execute("INSERT INTO t (id, name) VALUES (20, 'after');");
+    assert!(db.execute("CHECK;").rows.is_empty());
+    db.restart();
+    assert_eq!(ids(&mut db), (0..21).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_tables_outlast_the_log_a_checkpoint_drops() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE t (id INT, name TEXT);");
+    db.execute("CREATE INDEX t_id ON t (id);");
+    db.execute("INSERT INTO t (id, name) VALUES (1, 'a'), (2, 'b');");
+    db.execute("CHECKPOINT;");
+    db.execute("INSERT INTO t (id, name) VALUES (3, 'c');");
+
+    // Recovery must not need anything logged before the checkpoint, the
+    // CREATE TABLE included: the catalog saved with it has the table.
+    let wal = db.config().wal();
+    let master = MasterRecord::read(&wal).unwrap().unwrap();
+    let segment = OpenOptions::new()
+        .write(true)
+        .open(segment_path(&wal, 1))
+        .unwrap();
+    let prefix = vec![0xff; (master.offset - WAL_HEADER_SIZE) as usize];
+    segment.write_all_at(&prefix, WAL_HEADER_SIZE).unwrap();
+    db.kill();
+
+    assert_eq!(ids(&mut db), vec![1, 2, 3]);
+    db.assert_rows("SELECT name FROM t WHERE id = 3;", [vec!["c".into()]]);
+    db.execute("INSERT INTO t (id, name) VALUES (4, 'd');");
+    db.restart();
+    assert_eq!(ids(&mut db), vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn test_deferred_foreign_keys_are_checked_at_commit() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE users (id INT);");
+    db.execute("CREATE TABLE orders (id INT, user_id INT REFERENCES users(id));");
+    let error = db.try_execute("SET CONSTRAINTS ALL DEFERRED;").unwrap_err();
+    assert!(format!("{:#}", error).contains("transaction block"), "{:#}", error);
+
+    // A child can go in before its parent, including through COPY.
+    db.execute("BEGIN;");
+    db.execute("SET CONSTRAINTS ALL DEFERRED;");
+    db.execute("INSERT INTO orders (id, user_id) VALUES (10, 1);");
+    db.db()
+        .copy("COPY orders FROM STDIN;", "11\t2\n".as_bytes())
+        .unwrap();
+    db.execute("INSERT INTO users (id) VALUES (1), (2);");
+    db.execute("COMMIT;");
+    db.assert_row_set(
+        "SELECT id, user_id FROM orders;",
+        [vec![10.into(), 1.into()], vec![11.into(), 2.into()]],
+    );
+
+    // COMMIT names every row still without a parent and rolls back.
+    db.execute("BEGIN;");
+    db.execute("SET CONSTRAINTS ALL DEFERRED;");
+    db.execute("INSERT INTO users (id) VALUES (3);");
+    db.execute("INSERT INTO orders (id, user_id) VALUES (12, 3), (13, 8), (14, 9);");
+    let error = db.try_execute("COMMIT;").unwrap_err();
+    match error.downcast_ref::<DbError>() {
+        Some(DbError::ForeignKeyViolation {
+            message,
+            constraint,
+            table,
+        }) => {
+            assert_eq!(
+                (constraint.as_str(), table.as_str()),
+                ("ORDERS_USER_ID_FKEY", "ORDERS")
+            );
+            assert!(message.starts_with("2 deferred"), "{}", message);
+            assert!(message.contains("has no ID 8") && message.contains("has no ID 9"));
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(!db.db().in_transaction());
+    db.assert_row_set("SELECT id FROM users;", [vec![1.into()], vec![2.into()]]);
+
+    // IMMEDIATE checks the queue there and then; a rollback drops it.
+    db.execute("BEGIN;");
+    db.execute("SET CONSTRAINTS ALL DEFERRED;");
+    db.execute("INSERT INTO orders (id, user_id) VALUES (15, 7);");
+    assert!(db.try_execute("SET CONSTRAINTS ALL IMMEDIATE;").is_err());
+    assert!(!db.db().in_transaction());
+    db.execute("BEGIN;");
+    db.execute("SET CONSTRAINTS ALL DEFERRED;");
+    db.execute("INSERT INTO orders (id, user_id) VALUES (16, 7);");
+    db.execute("ROLLBACK;");
+    db.execute("BEGIN;");
+    db.execute("COMMIT;");
+    assert!(db.try_execute("INSERT INTO orders (id, user_id) VALUES (17, 7);").is_err());
+    assert_eq!(db.rows("SELECT id FROM orders;").len(), 2);
+}
+
+#[test]
+fn test_create_table_as_copies_a_result() {
+    let mut db = TestDb::new();
+    db.execute("CREATE TABLE users (id INT, name TEXT COLLATE NOCASE, score INT);");
+    let values: Vec<String> = (1..=300)
+        .map(|i| format!("({}, 'user{}', {})", i, i, i % 7 * 50))
+        .collect();
+    db.execute(&format!(
+        "INSERT INTO users (id, name, score) VALUES {};",
+        values.join(", ")
+    ));
+
+    db.execute("CREATE TABLE top_users AS SELECT id, name FROM users WHERE score > 100 ORDER BY id;");
+    let top = db.rows("SELECT id FROM top_users;");
+    let expected: Vec<_> = (1..=300).filter(|i| i % 7 * 50 > 100).collect();
+    assert_eq!(top.len(), expected.len());
+    assert_eq!(top[0], vec![DbValue::from(expected[0])]);
+    // The copy keeps the column's type and collation, and SHOW TABLES
+    // counts its rows.
+    db.assert_rows("SELECT id FROM top_users WHERE name = 'USER3';", [vec![3.into()]]);
+    let tables = db.rows("SHOW TABLES;");
+    let row = tables.iter().find(|row| row[0] == "TOP_USERS".into()).unwrap();
+    assert_eq!(row[1], DbValue::from(expected.len() as i64));
+
+    // Columns the SELECT works out are named by the list, or after the
+    // function or their position.
+    db.execute("CREATE TABLE sums (id, twice) AS SELECT id, score + score FROM users WHERE id < 3;");
+    db.assert_rows(
+        "SELECT twice FROM sums WHERE id = 2;",
+        [vec![200.into()]],
+    );
+    db.execute("CREATE TABLE named AS SELECT id, score * 2, UPPER(name) FROM users WHERE id = 1;");
+    db.assert_rows(
+        "SELECT id, column2, upper FROM named;",
+        [vec![1.into(), 100.into(), "USER1".into()]],
+    );
+
+    // A name already taken fails before anything is read or written, and a
+    // failed statement leaves no table behind.
+    for (sql, expected) in [
+        ("CREATE TABLE users AS SELECT id FROM top_users;", "already exists"),
+        ("CREATE TABLE bad AS SELECT id, id FROM users;", "twice"),
+        ("CREATE TABLE bad (a) AS SELECT id, name FROM users;", "1 column names"),
+        ("CREATE TABLE bad AS SELECT nope FROM users;", "NOPE"),
+    ] {
+        let error = format!("{:#}", db.try_execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    assert_eq!(db.rows("SELECT id FROM users;").len(), 300);
+    assert!(db.try_execute("SELECT * FROM bad;").is_err());
+    db.execute("BEGIN;");
+    assert!(db.try_execute("CREATE TABLE inside AS SELECT id FROM users;").is_err());
+}
@@ -0,0 +1,147 @@
+use engine::index::hash_index::HashIndex;
+use engine::query::binder::{Binder, Catalog, Value};
+use engine::query::parser::Parser;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, IndexKind, Storage};
+use engine::testing::run;
+use std::fs::remove_file;
+
+fn bind(storage: &mut Storage, sql: &str) {
+    let stmt = Parser::new(sql).unwrap().parse_statement().unwrap();
+    let mut catalog = Catalog::from_storage(&storage.catalog);
+    Binder::new(&mut catalog, storage).bind(stmt).unwrap();
+}
+
+fn explain(storage: &mut Storage, sql: &str) -> String {
+    run(storage, &format!("EXPLAIN {}", sql))
+        .unwrap()
+        .into_iter()
+        .map(|r| match &r[0] {
+            Value::String(s) => s.clone(),
+            _ => panic!("expected string"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[test]
+fn test_hash_index_grows_on_load_factor() {
+    let path = "test_hash_index_growth.db";
+    let mut storage = Storage::new(path, 512, 64).unwrap();
+    let mut index = HashIndex::create(&mut storage).unwrap();
+    let initial = index.stats().unwrap();
+
+    for key in 0..1000u64 {
+        index.insert(key * 3, (key, 0)).unwrap();
+    }
+    let stats = index.stats().unwrap();
+    assert_eq!(stats.entries, 1000);
+    assert!(stats.buckets > initial.buckets, "{:?}", stats);
+    assert!(stats.level > 0, "{:?}", stats);
+
+    for key in 0..1000u64 {
+        assert_eq!(index.get(key * 3).unwrap(), vec![(key, 0)]);
+    }
+    assert!(index.get(1).unwrap().is_empty());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_hash_index_overflow_chains_and_duplicates() {
+    let path = "test_hash_index_overflow.db";
+    let mut storage = Storage::new(path, 256, 64).unwrap();
+    let capacity = HashIndex::bucket_capacity(256) as u64;
+    let mut index = HashIndex::create(&mut storage).unwrap();
+
+    for slot in 0..capacity * 3 {
+        index.insert(42, (7, slot as u16)).unwrap();
+    }
+    index.insert(43, (8, 0)).unwrap();
+
+    let stats = index.stats().unwrap();
+    assert!(stats.overflow_pages >= 2, "{:?}", stats);
+    let mut rids = index.get(42).unwrap();
+    rids.sort();
+    assert_eq!(
+        rids,
+        (0..capacity * 3).map(|s| (7, s as u16)).collect::<Vec<_>>()
+    );
+    assert_eq!(index.get(43).unwrap(), vec![(8, 0)]);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_hash_index_used_only_for_equality() {
+    let path = "test_hash_index_planner.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    storage
+        .create_table(
+            "T".into(),
+            vec![
+                ColumnInfo {
+                    name: "ID".into(),
+                    data_type: DataType::Int,
+                    collation: Collation::Binary,
+                },
+                ColumnInfo {
+                    name: "NAME".into(),
+                    data_type: DataType::String,
+                    collation: Collation::Binary,
+                },
+            ],
+        )
+        .unwrap();
+    for i in 0..20 {
+        run(
+            &mut storage,
+            &format!("INSERT INTO t (id, name) VALUES ({}, 'n{}');", i % 10, i),
+        )
+        .unwrap();
+    }
+    bind(&mut storage, "CREATE INDEX t_id ON t (id) USING HASH;");
+    assert_eq!(storage.get_indexes("T")[0].kind, IndexKind::Hash);
+
+    let plan = explain(&mut storage, "SELECT name FROM t WHERE id = 3;");
+    assert!(plan.contains("HashIndexScan on T using T_ID"), "{}", plan);
+    let rows = run(&mut storage, "SELECT name FROM t WHERE id = 3;").unwrap();
+    let mut names: Vec<String> = rows
+        .into_iter()
+        .map(|r| match &r[0] {
+            Value::String(s) => s.clone(),
+            _ => panic!("expected string"),
+        })
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["n13".to_string(), "n3".to_string()]);
+
+    let plan = explain(&mut storage, "SELECT name FROM t WHERE id IN (3, 8);");
+    assert!(
+        plan.contains("HashIndexScan on T using T_ID (ID, 2 probes)"),
+        "{}",
+        plan
+    );
+    assert_eq!(
+        run(&mut storage, "SELECT name FROM t WHERE id IN (3, 8);")
+            .unwrap()
+            .len(),
+        4
+    );
+
+    let plan = explain(&mut storage, "SELECT name FROM t WHERE id > 3;");
+    assert!(!plan.contains("HashIndexScan"), "{}", plan);
+    assert!(plan.contains("SeqScan"), "{}", plan);
+    assert_eq!(
+        run(&mut storage, "SELECT name FROM t WHERE id > 7;")
+            .unwrap()
+            .len(),
+        4
+    );
+
+    run(&mut storage, "INSERT INTO t (id, name) VALUES (3, 'late');").unwrap();
+    assert_eq!(
+        run(&mut storage, "SELECT name FROM t WHERE id = 3;")
+            .unwrap()
+            .len(),
+        3
+    );
+    remove_file(path).unwrap();
+}
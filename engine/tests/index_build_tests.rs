@@ -0,0 +1,94 @@
+use engine::index::bplustree::BPlusTree;
+use engine::query::binder::Value;
+use engine::storage::index_build::BACKFILL_CHUNK_ROWS;
+use engine::testing::table_with_rows;
+use std::fs::remove_file;
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread;
+
+#[test]
+fn test_index_built_while_rows_are_inserted_has_every_row() {
+    let path = "test_index_build_concurrent.db";
+    let storage = Arc::new(RwLock::new(table_with_rows(path, 3000)));
+    let key = ["ID".to_string()];
+    let rids = storage
+        .write()
+        .unwrap()
+        .begin_index_build(1, "T", &key, "T_ID")
+        .unwrap();
+    assert_eq!(rids.len(), 3000);
+
+    let started = Arc::new(Barrier::new(2));
+    let writer = {
+        let storage = storage.clone();
+        let started = started.clone();
+        thread::spawn(move || {
+            let names = vec!["ID".to_string(), "NAME".to_string()];
+            started.wait();
+            for id in 3000..4000 {
+                storage
+                    .write()
+                    .unwrap()
+                    .insert_row(
+                        "T",
+                        &names,
+                        vec![Value::Int(id), Value::String(format!("name{}", id))],
+                    )
+                    .unwrap();
+            }
+        })
+    };
+    started.wait();
+    let mut keys = Vec::new();
+    for chunk in rids.chunks(BACKFILL_CHUNK_ROWS / 4) {
+        let storage = storage.read().unwrap();
+        keys.extend(storage.read_index_keys("T", &key, chunk).unwrap());
+    }
+    assert_eq!(keys.len(), 3000);
+    writer.join().unwrap();
+
+    let mut storage = Arc::into_inner(storage).unwrap().into_inner().unwrap();
+    storage.add_index_keys(1, "T", "T_ID", keys).unwrap();
+    // Rows can still come in until the table is locked.
+    storage
+        .insert_row(
+            "T",
+            &["ID".to_string(), "NAME".to_string()],
+            vec![Value::Int(4000), Value::String("name4000".into())],
+        )
+        .unwrap();
+    assert!(storage.get_indexes("T").is_empty());
+
+    storage.set_transaction(Some(1));
+    storage.create_index("T", "ID", "T_ID", Some(4)).unwrap();
+    storage.set_transaction(None);
+    let info = storage.get_indexes("T").remove(0);
+    let records = storage.catalog.get_table("T").unwrap().records.clone();
+    let mut tree = BPlusTree::<i64>::open(&mut storage, &info);
+    assert_eq!(tree.check().unwrap(), 4001);
+    for id in 0..=4000 {
+        let rid = tree.get(id).unwrap().unwrap();
+        assert!(records.contains(&rid));
+    }
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_abandoned_index_build_leaves_no_index() {
+    let path = "test_index_build_abandoned.db";
+    let mut storage = table_with_rows(path, 10);
+    let key = ["ID".to_string()];
+    let rids = storage.begin_index_build(1, "T", &key, "T_ID").unwrap();
+    assert!(storage.begin_index_build(2, "T", &key, "T_ID").is_err());
+    let unknown = ["NOPE".to_string()];
+    assert!(storage.begin_index_build(1, "T", &unknown, "T_X").is_err());
+    let keys = storage.read_index_keys("T", &key, &rids).unwrap();
+    storage.add_index_keys(1, "T", "T_ID", keys).unwrap();
+
+    storage.drop_index_builds(1);
+    assert!(storage.get_indexes("T").is_empty());
+    assert!(storage.add_index_keys(1, "T", "T_ID", Vec::new()).is_err());
+    // The name is free again.
+    storage.begin_index_build(2, "T", &key, "T_ID").unwrap();
+    remove_file(path).unwrap();
+}
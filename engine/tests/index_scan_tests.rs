@@ -0,0 +1,422 @@
+use engine::query::binder::Value;
+use engine::query::executor::Tuple;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use engine::testing::run;
+use std::fs::remove_file;
+use std::sync::atomic::Ordering;
+
+fn wide_table(path: &str) -> Storage {
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    let mut columns = vec![ColumnInfo {
+        name: "ID".into(),
+        data_type: DataType::Int,
+        collation: Collation::Binary,
+    }];
+    for i in 0..8 {
+        columns.push(ColumnInfo {
+            name: format!("PAD{}", i),
+            data_type: DataType::String,
+            collation: Collation::Binary,
+        });
+    }
+    let names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+    storage.create_table("T".into(), columns).unwrap();
+    for id in 0..50 {
+        let mut row = vec![Value::Int(id)];
+        row.extend((0..8).map(|_| Value::String("x".repeat(40))));
+        storage.insert_row("T", &names, row).unwrap();
+    }
+    storage.create_index("T", "ID", "T_ID", Some(4)).unwrap();
+    storage
+}
+
+fn explain(storage: &mut Storage, sql: &str) -> String {
+    run(storage, &format!("EXPLAIN {}", sql))
+        .unwrap()
+        .into_iter()
+        .map(|r| match &r[0] {
+            Value::String(s) => s.clone(),
+            _ => panic!("expected string"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn ids(rows: &[Tuple]) -> Vec<i64> {
+    rows.iter()
+        .map(|r| match r[0] {
+            Value::Int(i) => i,
+            _ => panic!("expected int"),
+        })
+        .collect()
+}
+
+#[test]
+fn test_covering_query_uses_index_only_scan() {
+    let path = "test_index_only_scan.db";
+    let mut storage = wide_table(path);
+
+    let plan = run(
+        &mut storage,
+        "EXPLAIN SELECT id FROM t WHERE id BETWEEN 10 AND 20;",
+    )
+    .unwrap();
+    let text: Vec<String> = plan
+        .into_iter()
+        .map(|r| match &r[0] {
+            Value::String(s) => s.clone(),
+            _ => panic!("expected string"),
+        })
+        .collect();
+    assert!(
+        text.iter().any(|l| l.contains("IndexOnlyScan")),
+        "{:?}",
+        text
+    );
+
+    let before = storage.heap_fetches.load(Ordering::Relaxed);
+    let rows = run(&mut storage, "SELECT id FROM t WHERE id BETWEEN 10 AND 20;").unwrap();
+    assert_eq!(ids(&rows), (10..=20).collect::<Vec<_>>());
+    assert_eq!(storage.heap_fetches.load(Ordering::Relaxed), before);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_index_only_scan_skips_the_heap_fetches_of_the_same_range() {
+    let path = "test_index_only_scan_savings.db";
+    let mut storage = wide_table(path);
+
+    let before = storage.heap_fetches.load(Ordering::Relaxed);
+    let rows = run(
+        &mut storage,
+        "SELECT id, pad7 FROM t WHERE id BETWEEN 10 AND 39;",
+    )
+    .unwrap();
+    assert_eq!(ids(&rows), (10..40).collect::<Vec<_>>());
+    let with_heap = storage.heap_fetches.load(Ordering::Relaxed) - before;
+    assert_eq!(with_heap, 30);
+
+    let before = storage.heap_fetches.load(Ordering::Relaxed);
+    let rows = run(&mut storage, "SELECT id FROM t WHERE id BETWEEN 10 AND 39;").unwrap();
+    assert_eq!(ids(&rows), (10..40).collect::<Vec<_>>());
+    let index_only = storage.heap_fetches.load(Ordering::Relaxed) - before;
+    assert_eq!(index_only, 0);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_non_covering_query_fetches_heap() {
+    let path = "test_index_scan_heap.db";
+    let mut storage = wide_table(path);
+
+    let before = storage.heap_fetches.load(Ordering::Relaxed);
+    let rows = run(&mut storage, "SELECT id, pad0 FROM t WHERE id > 44;").unwrap();
+    assert_eq!(ids(&rows), (45..50).collect::<Vec<_>>());
+    assert_eq!(storage.heap_fetches.load(Ordering::Relaxed) - before, 5);
+
+    let seq = run(&mut storage, "SELECT id FROM t WHERE pad0 = 'y';").unwrap();
+    assert!(seq.is_empty());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_negative_keys_sort_before_positive() {
+    let path = "test_index_scan_negative.db";
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    let columns = vec![ColumnInfo {
+        name: "X".into(),
+        data_type: DataType::Int,
+        collation: Collation::Binary,
+    }];
+    storage.create_table("T".into(), columns).unwrap();
+    let names = vec!["X".to_string()];
+    for x in [5, -5, 0, i64::MIN, i64::MAX, -1] {
+        storage
+            .insert_row("T", &names, vec![Value::Int(x)])
+            .unwrap();
+    }
+    storage.create_index("T", "X", "T_X", Some(4)).unwrap();
+
+    let plan = run(&mut storage, "EXPLAIN SELECT x FROM t WHERE x < 0;").unwrap();
+    assert!(
+        plan.iter()
+            .any(|r| matches!(&r[0], Value::String(s) if s.contains("IndexOnlyScan"))),
+        "{:?}",
+        plan
+    );
+    let rows = run(&mut storage, "SELECT x FROM t WHERE x < 0;").unwrap();
+    assert_eq!(ids(&rows), vec![i64::MIN, -5, -1]);
+    let rows = run(&mut storage, "SELECT x FROM t WHERE x >= 0;").unwrap();
+    assert_eq!(ids(&rows), vec![0, 5, i64::MAX]);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_in_list_probes_the_index_once_per_value() {
+    let path = "test_index_scan_in_list.db";
+    let mut storage = wide_table(path);
+
+    let sql = "SELECT id, pad0 FROM t WHERE id IN (40, 7, 3, 7);";
+    let plan = explain(&mut storage, sql);
+    assert!(
+        plan.contains("IndexScan on T using T_ID (ID, 3 probes)"),
+        "{}",
+        plan
+    );
+    let before = storage.heap_fetches.load(Ordering::Relaxed);
+    let rows = run(&mut storage, sql).unwrap();
+    assert_eq!(ids(&rows), vec![3, 7, 40]);
+    assert_eq!(storage.heap_fetches.load(Ordering::Relaxed) - before, 3);
+
+    // Values next to each other are read as one range, and ranges joined
+    // by OR as one probe each.
+    let plan = explain(&mut storage, "SELECT id FROM t WHERE id IN (4, 5, 6);");
+    assert!(
+        plan.contains("IndexOnlyScan on T using T_ID (ID)"),
+        "{}",
+        plan
+    );
+    let sql = "SELECT id FROM t WHERE id < 2 OR id = 30 OR id >= 48;";
+    let plan = explain(&mut storage, sql);
+    assert!(plan.contains("(ID, 3 probes)"), "{}", plan);
+    assert_eq!(
+        ids(&run(&mut storage, sql).unwrap()),
+        vec![0, 1, 30, 48, 49]
+    );
+
+    // AND narrows each value's probe; a contradiction reads nothing.
+    let sql = "SELECT id FROM t WHERE id IN (1, 20, 30) AND id > 10 AND pad0 <> 'y';";
+    assert!(explain(&mut storage, sql).contains("(ID, 2 probes)"));
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![20, 30]);
+    assert!(
+        run(&mut storage, "SELECT id FROM t WHERE id = 1 AND id = 2;")
+            .unwrap()
+            .is_empty()
+    );
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_predicates_the_index_cannot_answer_scan_the_table() {
+    let path = "test_index_scan_fallback.db";
+    let mut storage = wide_table(path);
+
+    // Equality on a TEXT column, a side of an OR on another column, and a
+    // TEXT compared with the INT key all plan a SeqScan rather than fail.
+    for sql in [
+        "SELECT id FROM t WHERE pad0 = 'x';",
+        "SELECT id FROM t WHERE id = 1 OR pad0 = 'y';",
+        "SELECT id FROM t WHERE id = '1';",
+        "SELECT id FROM t WHERE id IN (1, 'two');",
+    ] {
+        let plan = explain(&mut storage, sql);
+        assert!(plan.contains("SeqScan on T"), "{}: {}", sql, plan);
+        assert!(!plan.contains("IndexScan"), "{}: {}", sql, plan);
+    }
+    assert_eq!(
+        run(&mut storage, "SELECT id FROM t WHERE pad0 <> 'y';")
+            .unwrap()
+            .len(),
+        50
+    );
+
+    // A TEXT beside an INT the index can use leaves the filter to judge it.
+    let plan = explain(
+        &mut storage,
+        "SELECT id FROM t WHERE id = 5 AND pad0 <> 'y';",
+    );
+    assert!(plan.contains("IndexScan on T using T_ID (ID)"), "{}", plan);
+    remove_file(path).unwrap();
+}
+
+fn pages_fetched(storage: &Storage) -> u64 {
+    let stats = &storage.buffer_pool.stats;
+    stats.hits.load(Ordering::Relaxed)
+        + stats.misses.load(Ordering::Relaxed)
+        + stats.prefetch_hits.load(Ordering::Relaxed)
+}
+
+// Runs `sql` in a transaction of its own, as the server would.
+fn run_in_transaction(storage: &mut Storage, sql: &str) -> Vec<Tuple> {
+    let tx = storage.txns.begin();
+    storage.set_transaction(Some(tx));
+    storage.take_snapshot();
+    let rows = run(storage, sql).unwrap();
+    storage.txns.commit(tx);
+    storage.set_transaction(None);
+    rows
+}
+
+#[test]
+fn test_delete_finds_its_rows_through_an_index() {
+    let path = "test_index_scan_delete.db";
+    let mut storage = wide_table(path);
+    let columns: Vec<String> = storage
+        .catalog
+        .get_table("T")
+        .unwrap()
+        .columns
+        .iter()
+        .map(|c| c.name.clone())
+        .collect();
+    for id in 50..500 {
+        let mut row = vec![Value::Int(id)];
+        row.extend((0..8).map(|_| Value::String("x".repeat(40))));
+        storage.insert_row("T", &columns, row).unwrap();
+    }
+    let heap_pages = storage.catalog.get_table("T").unwrap().stats.page_count() as u64;
+
+    let plan = explain(&mut storage, "DELETE FROM t WHERE id = 5;");
+    assert_eq!(
+        plan,
+        "Delete from T\n  Filter\n    IndexScan on T using T_ID (ID)"
+    );
+    let pages = pages_fetched(&storage);
+    let rows = storage.heap_fetches.load(Ordering::Relaxed);
+    let deleted = run_in_transaction(&mut storage, "DELETE FROM t WHERE id = 5;");
+    assert_eq!(deleted, vec![vec![Value::Int(1)]]);
+    // The row is read once to find it and once more as it is deleted.
+    assert_eq!(storage.heap_fetches.load(Ordering::Relaxed) - rows, 2);
+    let by_index = pages_fetched(&storage) - pages;
+
+    // Without an index on the column every heap page is read.
+    let plan = explain(&mut storage, "DELETE FROM t WHERE pad0 = 'y';");
+    assert!(plan.contains("SeqScan on T"), "{}", plan);
+    let pages = pages_fetched(&storage);
+    run_in_transaction(&mut storage, "DELETE FROM t WHERE pad0 = 'y';");
+    let by_scan = pages_fetched(&storage) - pages;
+    assert!(by_scan >= heap_pages, "{} of {}", by_scan, heap_pages);
+    assert!(by_index * 4 < by_scan, "{} against {}", by_index, by_scan);
+
+    // A range goes through the index too, and a deleted row's key is not
+    // handed out from it.
+    let deleted = run_in_transaction(&mut storage, "DELETE FROM t WHERE id BETWEEN 100 AND 109;");
+    assert_eq!(deleted, vec![vec![Value::Int(10)]]);
+    let rows = run(&mut storage, "SELECT id FROM t WHERE id BETWEEN 4 AND 6;").unwrap();
+    assert_eq!(ids(&rows), vec![4, 6]);
+    let rows = run(
+        &mut storage,
+        "SELECT id FROM t WHERE id >= 98 AND id < 112;",
+    )
+    .unwrap();
+    assert_eq!(ids(&rows), vec![98, 99, 110, 111]);
+    remove_file(path).unwrap();
+}
+
+fn people(path: &str) -> Storage {
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    let column = |name: &str, data_type, collation| ColumnInfo {
+        name: name.into(),
+        data_type,
+        collation,
+    };
+    let columns = vec![
+        column("ID", DataType::Int, Collation::Binary),
+        column("NAME", DataType::String, Collation::NoCase),
+        column("CITY", DataType::String, Collation::Binary),
+    ];
+    let names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+    storage.create_table("T".into(), columns).unwrap();
+    let rows = [
+        (1, "Alice", "Oslo"),
+        (2, "bob", "Rome"),
+        (3, "Carol", "Oslo"),
+        (4, "dave", "Oslo"),
+        (5, "Eve", "Rome"),
+        (6, "Frank", "Paris"),
+    ];
+    for (id, name, city) in rows {
+        let row = vec![
+            Value::Int(id),
+            Value::String(name.into()),
+            Value::String(city.into()),
+        ];
+        storage.insert_row("T", &names, row).unwrap();
+    }
+    storage
+}
+
+#[test]
+fn test_text_index_serves_string_equality() {
+    let path = "test_index_scan_text.db";
+    let mut storage = people(path);
+    storage
+        .create_index_on("T", &["NAME".to_string()], "T_NAME", Some(4))
+        .unwrap();
+
+    // The literal is folded as the column's NOCASE collation folds it.
+    let sql = "SELECT id FROM t WHERE name = 'BOB';";
+    let plan = explain(&mut storage, sql);
+    assert!(
+        plan.contains("IndexScan on T using T_NAME (NAME)"),
+        "{}",
+        plan
+    );
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![2]);
+
+    let sql = "SELECT id FROM t WHERE name IN ('eve', 'ALICE', 'nobody');";
+    let plan = explain(&mut storage, sql);
+    assert!(plan.contains("(NAME, 3 probes)"), "{}", plan);
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![1, 5]);
+
+    // A range reads the keys in order, and a row inserted later is found.
+    run_in_transaction(
+        &mut storage,
+        "INSERT INTO t (id, name, city) VALUES (7, 'Dan', 'Rome');",
+    );
+    let sql = "SELECT id FROM t WHERE name >= 'd' AND name < 'F';";
+    let plan = explain(&mut storage, sql);
+    assert!(
+        plan.contains("IndexScan on T using T_NAME (NAME)"),
+        "{}",
+        plan
+    );
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![7, 4, 5]);
+
+    // An INT no TEXT key can hold leaves the table to be scanned.
+    let plan = explain(&mut storage, "SELECT id FROM t WHERE name = 5;");
+    assert!(plan.contains("SeqScan on T"), "{}", plan);
+    assert!(!plan.contains("IndexScan"), "{}", plan);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_composite_index_serves_a_prefix_of_its_columns() {
+    let path = "test_index_scan_composite.db";
+    let mut storage = people(path);
+    let key = ["CITY".to_string(), "ID".to_string()];
+    storage
+        .create_index_on("T", &key, "T_CITY_ID", Some(4))
+        .unwrap();
+
+    let sql = "SELECT id FROM t WHERE city = 'Oslo';";
+    let plan = explain(&mut storage, sql);
+    assert!(
+        plan.contains("IndexScan on T using T_CITY_ID (CITY, ID)"),
+        "{}",
+        plan
+    );
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![1, 3, 4]);
+
+    // Every column pinned is one point per combination of values.
+    let sql = "SELECT id FROM t WHERE city = 'Oslo' AND id = 3;";
+    assert!(explain(&mut storage, sql).contains("(CITY, ID)"));
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![3]);
+    let sql = "SELECT id FROM t WHERE city IN ('Rome', 'Oslo') AND id IN (2, 4, 9);";
+    let plan = explain(&mut storage, sql);
+    assert!(plan.contains("(CITY, ID, 6 probes)"), "{}", plan);
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![4, 2]);
+
+    // A range on the column after the prefix narrows each probe.
+    let sql = "SELECT id FROM t WHERE city IN ('Rome', 'Paris') AND id > 2;";
+    let plan = explain(&mut storage, sql);
+    assert!(plan.contains("(CITY, ID, 2 probes)"), "{}", plan);
+    assert_eq!(ids(&run(&mut storage, sql).unwrap()), vec![6, 5]);
+
+    // Without the first column the index cannot be used.
+    let plan = explain(&mut storage, "SELECT id FROM t WHERE id = 3;");
+    assert!(plan.contains("SeqScan on T"), "{}", plan);
+    let plan = explain(&mut storage, "SELECT id FROM t WHERE city = 1;");
+    assert!(plan.contains("SeqScan on T"), "{}", plan);
+    remove_file(path).unwrap();
+}
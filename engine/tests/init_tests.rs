@@ -0,0 +1,137 @@
+use engine::cli::args::InitArgs;
+use engine::cli::init::run_init;
+use engine::database::Database;
+use engine::net::auth::{Secret, UserStore};
+use engine::query::binder::Value;
+use engine::storage::atomic_file::previous_path;
+use engine::storage::backup::catalog_path;
+use engine::storage::check::check_file;
+use engine::storage::format::{CATALOG_PAGE, FileHeader, create_data_file};
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use engine::testing::fresh_dir;
+use engine::tx::log_manager::Manifest;
+use std::path::Path;
+use std::process::Command;
+
+fn init_args(dir: &Path, extra: &[&str]) -> InitArgs {
+    let mut list = vec!["--data-dir".to_string(), dir.display().to_string()];
+    list.extend(extra.iter().map(|a| a.to_string()));
+    InitArgs::parse_with_env(&list, |_| None).unwrap()
+}
+
+fn init(args: &InitArgs) -> anyhow::Result<String> {
+    let mut out = Vec::new();
+    run_init(args, Some(Secret::from("password")), &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn test_init_creates_the_data_file_wal_and_admin() {
+    let dir = fresh_dir("init_creates");
+    let args = init_args(&dir, &["--page-size", "8192", "--admin"]);
+    assert_eq!(args.wal, dir.join("wal.log"));
+    let out = init(&args).unwrap();
+    assert!(out.contains("with 8192-byte pages"), "{}", out);
+    assert!(out.contains("Created user 'admin'\n"), "{}", out);
+
+    let header = FileHeader::read(&args.data_file()).unwrap().unwrap();
+    assert_eq!((header.page_size, header.catalog_page), (8192, CATALOG_PAGE));
+    assert_eq!(std::fs::metadata(args.data_file()).unwrap().len(), 2 * 8192);
+    assert!(Manifest::read(&args.wal).unwrap().is_some());
+    let users = UserStore::open(UserStore::path(&args.wal)).unwrap();
+    assert!(users.bootstrap(None).unwrap().is_none());
+
+    // Rows go after the header and catalog pages, and the file checks out.
+    let mut storage = Storage::new(args.data_file().to_str().unwrap(), 8192, 16).unwrap();
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    let rid = storage
+        .insert_row("T", &["ID".to_string()], vec![Value::Int(1)])
+        .unwrap();
+    assert_eq!(rid.0, 2);
+    storage.flush().unwrap();
+    drop(storage);
+    let report = check_file(&args.data_file(), 8192).unwrap();
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.heap_pages, 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_init_refuses_a_directory_in_use_without_force() {
+    let dir = fresh_dir("init_force");
+    let args = init_args(&dir, &["--admin"]);
+    init(&args).unwrap();
+    let err = init(&args).unwrap_err().to_string();
+    assert!(err.contains("is not empty; pass --force"), "{}", err);
+
+    std::fs::write(args.data_file(), b"old data").unwrap();
+    let forced = init_args(&dir, &["--force"]);
+    let out = init(&forced).unwrap();
+    assert!(!out.contains("Created user"), "{}", out);
+    assert_eq!(
+        FileHeader::read(&forced.data_file()).unwrap().unwrap().page_size,
+        4096
+    );
+    // The accounts went with the old database.
+    assert!(!UserStore::path(&forced.wal).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_init_force_drops_the_saved_catalog() {
+    let dir = fresh_dir("init_force_catalog");
+    let args = init_args(&dir, &[]);
+    init(&args).unwrap();
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT);").unwrap();
+    db.execute("INSERT INTO t (id) VALUES (1);").unwrap();
+    db.close().unwrap();
+    let catalog = catalog_path(&args.wal);
+    assert!(catalog.exists());
+
+    init(&init_args(&dir, &["--force"])).unwrap();
+    assert!(!catalog.exists());
+    assert!(!previous_path(&catalog).exists());
+    // Nothing of the old database comes back with the new one.
+    let mut db = Database::open(&dir).unwrap();
+    let err = db.execute("SELECT id FROM t;").unwrap_err();
+    assert!(err.to_string().contains("Unknown table"), "{}", err);
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_server_refuses_an_uninitialized_directory() {
+    let dir = fresh_dir("init_server");
+    let server = |dir: &Path, extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_engine"))
+            .arg("server")
+            .args(["--data-dir", dir.to_str().unwrap(), "--listen", "127.0.0.1:0"])
+            .args(extra)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let err = server(&dir, &[]);
+    assert!(err.contains("create one with `mydb init --data-dir"), "{}", err);
+    assert!(!dir.exists());
+
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("data.db"), b"not a database").unwrap();
+    assert!(server(&dir, &[]).contains("is not a mydb data file"));
+
+    create_data_file(&dir.join("data.db"), 8192).unwrap();
+    let err = server(&dir, &[]);
+    assert!(err.contains("start the server with --page-size 8192"), "{}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,502 @@
+use engine::query::binder::Value;
+use engine::storage::atomic_file::atomic_remove;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use engine::testing::run;
+use engine::tx::lock_manager::{DeadlockPolicy, LockError, LockManager, LockMode, Resource};
+use engine::tx::log_manager::{LogManager, LogRecordType, Manifest, segment_path};
+use engine::tx::wal_reader::WalReader;
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Takes `first`, then `second`, exclusively; aborts if a lock is refused.
+async fn session(
+    locks: Arc<LockManager>,
+    wal: Arc<LogManager>,
+    tx: u64,
+    first: &str,
+    second: &str,
+) -> anyhow::Result<()> {
+    wal.log_begin(tx)?;
+    let result = async {
+        locks
+            .lock(tx, Resource::Table(first.into()), LockMode::Exclusive)
+            .await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        locks
+            .lock(tx, Resource::Table(second.into()), LockMode::Exclusive)
+            .await
+    }
+    .await;
+    match result {
+        Ok(()) => wal.log_commit(tx)?,
+        Err(_) => wal.log_abort(tx)?,
+    };
+    locks.unlock_all(tx);
+    result
+}
+
+// Runs two sessions taking "a" and "b" in opposite orders and checks that
+// tx 1 commits while tx 2 aborts, returning tx 2's error.
+async fn opposite_order(locks: Arc<LockManager>, wal_path: &str) -> LockError {
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let s1 = tokio::spawn(session(locks.clone(), wal.clone(), 1, "a", "b"));
+    let s2 = tokio::spawn(session(locks.clone(), wal.clone(), 2, "b", "a"));
+    let (r1, r2) = tokio::time::timeout(Duration::from_secs(5), async {
+        (s1.await.unwrap(), s2.await.unwrap())
+    })
+    .await
+    .expect("sessions deadlocked");
+
+    assert!(r1.is_ok());
+    let err = r2.unwrap_err().downcast::<LockError>().unwrap();
+
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut outcomes = Vec::new();
+    while let Some(record) = reader.next_record().unwrap() {
+        if matches!(
+            record.header.typ,
+            LogRecordType::Commit | LogRecordType::Abort
+        ) {
+            outcomes.push((record.header.tx_id, record.header.typ));
+        }
+    }
+    assert_eq!(
+        outcomes,
+        vec![(2, LogRecordType::Abort), (1, LogRecordType::Commit)]
+    );
+    remove_file(segment_path(Path::new(wal_path), 1)).unwrap();
+    atomic_remove(&Manifest::path(Path::new(wal_path))).unwrap();
+    err
+}
+
+#[tokio::test]
+async fn test_deadlock_detector_aborts_youngest() {
+    let locks = Arc::new(LockManager::new());
+    let detector = locks.spawn_deadlock_detector(Duration::from_millis(20));
+    let err = opposite_order(locks, "test_lock_deadlock.wal").await;
+    detector.abort();
+    assert_eq!(err, LockError::DeadlockVictim { tx: 2 });
+}
+
+#[tokio::test]
+async fn test_wait_die_fails_younger_requester() {
+    // No detector: if tx 2 were allowed to wait, the sessions would hang.
+    let locks = Arc::new(LockManager::new().with_policy(DeadlockPolicy::WaitDie));
+    let err = opposite_order(locks, "test_lock_wait_die.wal").await;
+    assert_eq!(
+        err,
+        LockError::Retry {
+            tx: 2,
+            resource: Resource::Table("a".into()),
+            older: vec![1],
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_resolve_deadlock_without_cycle() {
+    let locks = LockManager::new();
+    let table = Resource::Table("t".into());
+    locks
+        .lock(1, table.clone(), LockMode::Shared)
+        .await
+        .unwrap();
+    locks
+        .lock(2, table.clone(), LockMode::Shared)
+        .await
+        .unwrap();
+    assert_eq!(locks.detect_deadlock(), None);
+    assert_eq!(locks.resolve_deadlock(), None);
+    assert!(!locks.abort_waiter(1, LockError::DeadlockVictim { tx: 1 }));
+}
+
+#[tokio::test]
+async fn test_upgrade_waiting_on_others_is_no_deadlock() {
+    let locks = Arc::new(LockManager::new());
+    let table = Resource::Table("t".into());
+    locks
+        .lock(1, table.clone(), LockMode::IntentionShared)
+        .await
+        .unwrap();
+    locks
+        .lock(2, table.clone(), LockMode::IntentionExclusive)
+        .await
+        .unwrap();
+
+    let upgrade = {
+        let locks = locks.clone();
+        let table = table.clone();
+        tokio::spawn(async move { locks.lock(1, table, LockMode::Exclusive).await })
+    };
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert_eq!(locks.detect_deadlock(), None);
+    locks.unlock_all(2);
+    tokio::time::timeout(Duration::from_millis(500), upgrade)
+        .await
+        .expect("upgrade not granted once the other holder left")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_lock_times_out_and_reports_holders() {
+    let locks = Arc::new(LockManager::new().with_timeout(Duration::from_millis(50)));
+    let table = Resource::Table("t".into());
+    locks
+        .lock(1, table.clone(), LockMode::Exclusive)
+        .await
+        .unwrap();
+
+    let started = std::time::Instant::now();
+    let err = locks
+        .lock(2, table.clone(), LockMode::Shared)
+        .await
+        .unwrap_err();
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(
+        err.downcast_ref::<LockError>(),
+        Some(&LockError::LockTimeout {
+            resource: table.clone(),
+            held_by: vec![1],
+        })
+    );
+
+    // The timed-out request is gone: releasing tx 1 hands the lock straight
+    // to the next requester.
+    locks.unlock_all(1);
+    tokio::time::timeout(
+        Duration::from_millis(10),
+        locks.lock(3, table.clone(), LockMode::Exclusive),
+    )
+    .await
+    .expect("lock still queued behind a timed-out waiter")
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_timed_out_waiter_does_not_strand_queue() {
+    let locks = Arc::new(LockManager::new());
+    let table = Resource::Table("t".into());
+    locks
+        .lock(1, table.clone(), LockMode::Shared)
+        .await
+        .unwrap();
+
+    let writer = {
+        let locks = locks.clone();
+        let table = table.clone();
+        tokio::spawn(async move {
+            locks
+                .lock_with_timeout(
+                    2,
+                    table,
+                    LockMode::Exclusive,
+                    Some(Duration::from_millis(30)),
+                )
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let reader = {
+        let locks = locks.clone();
+        let table = table.clone();
+        tokio::spawn(async move { locks.lock(3, table, LockMode::Shared).await })
+    };
+
+    assert!(writer.await.unwrap().is_err());
+    tokio::time::timeout(Duration::from_millis(500), reader)
+        .await
+        .expect("reader stranded behind timed-out writer")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrent_inserts_lock_rows_not_table() {
+    let db = "test_lock_rows.db";
+    let locks = Arc::new(LockManager::new());
+    let mut storage = Storage::new(db, 4096, 16).unwrap();
+    storage.attach_locks(locks.clone());
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    let table = Resource::Table("T".into());
+
+    // Both transactions stay open across each other's insert.
+    for (tx, id) in [(1, 1), (2, 2)] {
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            locks.lock(tx, table.clone(), LockMode::IntentionExclusive),
+        )
+        .await
+        .expect("insert blocked on the table lock")
+        .unwrap();
+        storage.set_transaction(Some(tx));
+        run(
+            &mut storage,
+            &format!("INSERT INTO t (id) VALUES ({});", id),
+        )
+        .unwrap();
+    }
+    storage.set_transaction(None);
+
+    let rids: Vec<_> = storage.catalog.get_table("T").unwrap().records.clone();
+    assert_eq!(rids.len(), 2);
+    let row = Resource::Row("T".into(), rids[0]);
+    assert_eq!(
+        locks.try_lock(3, row.clone(), LockMode::Shared),
+        Err(LockError::WouldBlock {
+            resource: row.clone(),
+            held_by: vec![1],
+        })
+    );
+    // DDL still needs the whole table.
+    assert!(
+        locks
+            .try_lock(3, table.clone(), LockMode::Exclusive)
+            .is_err()
+    );
+
+    locks.unlock_all(1);
+    locks.try_lock(3, row, LockMode::Shared).unwrap();
+    locks.unlock_all(2);
+    locks.unlock_all(3);
+    locks.try_lock(4, table, LockMode::Exclusive).unwrap();
+    remove_file(db).unwrap();
+}
+
+#[test]
+fn test_lock_mode_compatibility_matrix() {
+    use LockMode::*;
+    let modes = [IntentionShared, IntentionExclusive, Shared, Exclusive];
+    // Rows are the held mode, columns the requested one, in `modes` order.
+    let expected = [
+        [true, true, true, false],
+        [true, true, false, false],
+        [true, false, true, false],
+        [false, false, false, false],
+    ];
+    for (i, &held) in modes.iter().enumerate() {
+        for (j, &requested) in modes.iter().enumerate() {
+            assert_eq!(
+                requested.compatible(held),
+                expected[i][j],
+                "{:?} requested while {:?} held",
+                requested,
+                held
+            );
+            let locks = LockManager::new();
+            let table = Resource::Table("t".into());
+            locks.try_lock(1, table.clone(), held).unwrap();
+            assert_eq!(locks.try_lock(2, table, requested).is_ok(), expected[i][j]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_drop_table_waits_for_row_locks() {
+    let db = "test_lock_drop.db";
+    let locks = Arc::new(LockManager::new());
+    let mut storage = Storage::new(db, 4096, 16).unwrap();
+    storage.attach_locks(locks.clone());
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    let table = Resource::Table("T".into());
+
+    storage.set_transaction(Some(1));
+    run(&mut storage, "INSERT INTO t (id) VALUES (1);").unwrap();
+    storage.set_transaction(None);
+
+    let drop_lock = {
+        let locks = locks.clone();
+        let table = table.clone();
+        tokio::spawn(async move { locks.lock(2, table, LockMode::Exclusive).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!drop_lock.is_finished(), "DROP TABLE ran past a row lock");
+
+    locks.unlock_all(1);
+    tokio::time::timeout(Duration::from_millis(500), drop_lock)
+        .await
+        .expect("DROP TABLE still blocked after the writer finished")
+        .unwrap()
+        .unwrap();
+    storage.set_transaction(Some(2));
+    run(&mut storage, "DROP TABLE t;").unwrap();
+    assert!(storage.catalog.get_table("T").is_err());
+    locks.unlock_all(2);
+    remove_file(db).unwrap();
+}
+
+#[tokio::test]
+async fn test_queued_writer_is_not_starved_by_readers() {
+    let locks = Arc::new(LockManager::new());
+    let table = Resource::Table("t".into());
+    locks
+        .lock(1, table.clone(), LockMode::Shared)
+        .await
+        .unwrap();
+
+    let spawn_lock = |tx, mode| {
+        let locks = locks.clone();
+        let table = table.clone();
+        tokio::spawn(async move { locks.lock(tx, table, mode).await })
+    };
+    let writer = spawn_lock(2, LockMode::Exclusive);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let reader = spawn_lock(3, LockMode::Shared);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!writer.is_finished());
+    assert!(!reader.is_finished(), "reader jumped the queued writer");
+
+    locks.unlock_all(1);
+    writer.await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!reader.is_finished());
+
+    locks.unlock_all(2);
+    reader.await.unwrap().unwrap();
+    locks.unlock_all(3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_many_lockers_respect_compatibility() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TASKS: u64 = 32;
+    const ROUNDS: u64 = 20;
+    let locks = Arc::new(LockManager::new());
+    let readers = Arc::new(AtomicUsize::new(0));
+    let writers = Arc::new(AtomicUsize::new(0));
+    let table = Resource::Table("t".into());
+
+    let mut tasks = Vec::new();
+    for task in 0..TASKS {
+        let (locks, readers, writers, table) = (
+            locks.clone(),
+            readers.clone(),
+            writers.clone(),
+            table.clone(),
+        );
+        tasks.push(tokio::spawn(async move {
+            for round in 0..ROUNDS {
+                let tx = task * ROUNDS + round + 1;
+                let exclusive = (task + round) % 4 == 0;
+                let mode = if exclusive {
+                    LockMode::Exclusive
+                } else {
+                    LockMode::Shared
+                };
+                locks.lock(tx, table.clone(), mode).await.unwrap();
+                if exclusive {
+                    assert_eq!(writers.fetch_add(1, Ordering::SeqCst), 0);
+                    assert_eq!(readers.load(Ordering::SeqCst), 0);
+                } else {
+                    readers.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(writers.load(Ordering::SeqCst), 0);
+                }
+                tokio::task::yield_now().await;
+                if exclusive {
+                    writers.fetch_sub(1, Ordering::SeqCst);
+                } else {
+                    readers.fetch_sub(1, Ordering::SeqCst);
+                }
+                locks.unlock_all(tx);
+            }
+        }));
+    }
+    tokio::time::timeout(Duration::from_secs(10), async {
+        for task in tasks {
+            task.await.unwrap();
+        }
+    })
+    .await
+    .expect("a waiter was never woken");
+
+    // Every lock was released, so nothing should be left behind.
+    locks.try_lock(0, table, LockMode::Exclusive).unwrap();
+}
+
+#[tokio::test]
+async fn test_snapshot_and_show_locks() {
+    let db = "test_lock_snapshot.db";
+    let locks = Arc::new(LockManager::new());
+    let mut storage = Storage::new(db, 4096, 16).unwrap();
+    storage.attach_locks(locks.clone());
+    let table = Resource::Table("t".into());
+    locks
+        .lock(1, table.clone(), LockMode::Exclusive)
+        .await
+        .unwrap();
+    let waiter = {
+        let locks = locks.clone();
+        let table = table.clone();
+        tokio::spawn(async move { locks.lock(2, table, LockMode::Shared).await })
+    };
+    tokio::time::sleep(Duration::from_millis(30)).await;
+
+    let snapshot = locks.snapshot();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].resource, table);
+    assert_eq!(snapshot[0].holders, vec![(1, LockMode::Exclusive)]);
+    assert_eq!(snapshot[0].waiters.len(), 1);
+    assert_eq!(snapshot[0].waiters[0].tx, 2);
+    assert_eq!(snapshot[0].waiters[0].mode, LockMode::Shared);
+    assert!(snapshot[0].waiters[0].waited >= Duration::from_millis(30));
+    assert_eq!(locks.held_exclusively(), vec![table.clone()]);
+
+    let rows = run(&mut storage, "SHOW LOCKS;").unwrap();
+    assert_eq!(rows.len(), 2);
+    assert!(matches!(rows[0][1], Value::Int(1)));
+    assert!(matches!(&rows[0][3], Value::String(s) if s == "held"));
+    assert!(matches!(rows[1][1], Value::Int(2)));
+    assert!(matches!(&rows[1][3], Value::String(s) if s == "waiting"));
+    assert!(matches!(rows[1][4], Value::Int(ms) if ms >= 30));
+
+    locks.unlock_all(1);
+    waiter.await.unwrap().unwrap();
+    assert!(locks.held_exclusively().is_empty());
+    locks.unlock_all(2);
+    assert!(locks.snapshot().is_empty());
+    assert!(run(&mut storage, "SHOW LOCKS;").unwrap().is_empty());
+    remove_file(db).unwrap();
+}
+
+#[tokio::test]
+async fn test_hand_over_keeps_waiters_waiting() {
+    let locks = Arc::new(LockManager::new());
+    let table = || Resource::Table("t".into());
+    locks.lock(1, table(), LockMode::Exclusive).await.unwrap();
+    let waiter = {
+        let locks = locks.clone();
+        tokio::spawn(async move { locks.lock(2, table(), LockMode::Shared).await })
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    // Transaction 3 now holds what 1 did, and 1 letting go frees nothing.
+    locks.hand_over(1, 3);
+    locks.unlock_all(1);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!waiter.is_finished());
+    assert!(locks.try_lock(4, table(), LockMode::Shared).is_err());
+
+    locks.unlock_all(3);
+    waiter.await.unwrap().unwrap();
+}
@@ -0,0 +1,121 @@
+use engine::net::auth::{Secret, UserStore};
+use engine::net::server::{ServerConfig, serve_until};
+use engine::storage::atomic_file::atomic_remove;
+use engine::storage::backup::catalog_path;
+use engine::storage::storage::Storage;
+use engine::tx::log_manager::{Manifest, MasterRecord, segment_path};
+use reqwest::Client;
+use std::fs::remove_file;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tracing_subscriber::fmt::MakeWriter;
+
+// Collects everything the subscriber writes.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Captured;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[tokio::test]
+async fn test_queries_log_one_summary_line_in_their_span() {
+    let captured = Captured::default();
+    tracing_subscriber::fmt()
+        .with_writer(captured.clone())
+        .with_ansi(false)
+        .init();
+
+    let (db, wal) = ("test_logging.db", "test_logging.wal");
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (shutdown, stop) = oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        Storage::new(db, 4096, 64).unwrap(),
+        PathBuf::from(wal),
+        ServerConfig {
+            admin_password: Some(Secret::from("password")),
+            ..ServerConfig::default()
+        },
+        async {
+            let _ = stop.await;
+        },
+    ));
+    let client = Client::builder().cookie_store(true).build().unwrap();
+    client
+        .post(format!("{}/login", url))
+        .json(&serde_json::json!({ "user": "admin", "pass": "password" }))
+        .send()
+        .await
+        .unwrap();
+    for sql in [
+        "CREATE TABLE t (id INT);",
+        "INSERT INTO t (id) VALUES (1);",
+        "SELECT id FROM t WHERE id = 1;",
+        "SELEC",
+    ] {
+        client
+            .post(format!("{}/query", url))
+            .json(&serde_json::json!({ "sql": sql }))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+    }
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let select = logs
+        .lines()
+        .filter(|line| line.contains(r#"sql="SELECT id FROM t WHERE id = 1;""#))
+        .collect::<Vec<_>>();
+    assert_eq!(select.len(), 1, "{}", logs);
+    let line = select[0];
+    assert!(line.contains(r#"user="admin""#), "{}", line);
+    for field in ["tx_id=", "parse_us=", "bind_us=", "plan_us=", "execute_us="] {
+        assert!(line.contains(field), "{} missing from {}", field, line);
+    }
+    assert!(
+        line.contains("Query finished rows=1 latency_ms="),
+        "{}",
+        line
+    );
+    let parse_error = logs
+        .lines()
+        .find(|line| line.contains(r#"sql="SELEC""#) && line.contains("Query answered"))
+        .unwrap_or_else(|| panic!("{}", logs));
+    assert!(parse_error.contains("status=400"), "{}", parse_error);
+
+    remove_file(db).unwrap();
+    let path = Path::new(wal);
+    let manifest = Manifest::read(path).unwrap().unwrap();
+    for segment in manifest.segments() {
+        remove_file(segment_path(path, segment)).unwrap();
+    }
+    atomic_remove(&Manifest::path(path)).unwrap();
+    atomic_remove(&UserStore::path(path)).unwrap();
+    atomic_remove(&MasterRecord::path(path)).unwrap();
+    atomic_remove(&catalog_path(path)).unwrap();
+}
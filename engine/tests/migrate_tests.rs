@@ -0,0 +1,269 @@
+use engine::cli::args::MigrateArgs;
+use engine::cli::migrate::{MigrateReport, run_migrate};
+use engine::database::{Database, DatabaseConfig};
+use engine::net::auth::Secret;
+use engine::net::client::{DbValue, SqlClient};
+use engine::net::server::{ServerConfig, serve_until};
+use engine::storage::atomic_file::{atomic_read, atomic_remove, atomic_write};
+use engine::storage::backup::catalog_path;
+use engine::storage::format::FileHeader;
+use engine::storage::storage::{Catalog, Storage};
+use engine::testing::fresh_dir;
+use std::path::{Path, PathBuf};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+// Runs a server over `dir/live`, has it run `setup` and backs it up into
+// `dir/backup`, which it returns. With `open`, another session has run
+// those statements and not committed when the backup is taken.
+async fn backup_after(dir: &Path, setup: &[String], open: &[&str]) -> PathBuf {
+    let live = dir.join("live");
+    std::fs::create_dir_all(&live).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let storage = Storage::new(live.join("data.db").to_str().unwrap(), 4096, 64).unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        storage,
+        live.join("wal.log"),
+        ServerConfig {
+            admin_password: Some(Secret::from("password")),
+            ..ServerConfig::default()
+        },
+        async {
+            let _ = stopped.await;
+        },
+    ));
+    let client = SqlClient::new(&url);
+    client.login("admin", "password").await.unwrap();
+    for sql in setup {
+        client.query(sql).await.unwrap();
+    }
+    let other = SqlClient::new(&url);
+    other.login("admin", "password").await.unwrap();
+    for sql in open {
+        other.query(sql).await.unwrap();
+    }
+    let backup = dir.join("backup");
+    client.backup(&backup).await.unwrap();
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    backup
+}
+
+fn migrate_args(from: &Path, to: &Path, extra: &[&str]) -> MigrateArgs {
+    let mut list = vec![
+        "--from".to_string(),
+        from.display().to_string(),
+        "--to".to_string(),
+        to.display().to_string(),
+    ];
+    list.extend(extra.iter().map(|a| a.to_string()));
+    MigrateArgs::parse(&list).unwrap()
+}
+
+fn migrate(args: &MigrateArgs) -> anyhow::Result<(MigrateReport, String)> {
+    let mut out = Vec::new();
+    let report = run_migrate(args, &mut out)?;
+    Ok((report, String::from_utf8(out).unwrap()))
+}
+
+fn ints(db: &mut Database, sql: &str) -> Vec<i64> {
+    db.execute(sql)
+        .unwrap()
+        .rows
+        .iter()
+        .map(|row| match row[0] {
+            DbValue::Int(i) => i,
+            ref other => panic!("expected an int, got {:?}", other),
+        })
+        .collect()
+}
+
+fn setup() -> Vec<String> {
+    let mut setup: Vec<String> = [
+        "CREATE TABLE users (id INT CHECK (id >= 0), name TEXT);",
+        "CREATE INDEX users_id ON users (id);",
+        "CREATE TABLE orders (id INT, user_id INT REFERENCES users(id));",
+        "CREATE SCHEMA app;",
+        "CREATE TABLE app.notes (body TEXT);",
+        "INSERT INTO app.notes (body) VALUES ('it''s'), ('');",
+        "CREATE VIEW named AS SELECT name FROM users;",
+        "CREATE SEQUENCE ids START 10 INCREMENT 5;",
+        "SELECT NEXTVAL('ids');",
+    ]
+    .iter()
+    .map(|sql| sql.to_string())
+    .collect();
+    let users: Vec<String> = (0..300)
+        .map(|id| format!("({}, 'user {}')", id, id))
+        .collect();
+    setup.push(format!(
+        "INSERT INTO users (id, name) VALUES {};",
+        users.join(", ")
+    ));
+    let orders: Vec<String> = (0..300).map(|id| format!("({}, {})", id, id % 7)).collect();
+    setup.push(format!(
+        "INSERT INTO orders (id, user_id) VALUES {};",
+        orders.join(", ")
+    ));
+    setup.push("DELETE FROM orders WHERE id >= 250;".to_string());
+    setup
+}
+
+#[tokio::test]
+async fn test_migrate_copies_a_backup_into_the_current_format() {
+    let dir = fresh_dir("migrate_copies");
+    let backup = backup_after(&dir, &setup(), &[]).await;
+    let before = std::fs::read(backup.join("data.db")).unwrap();
+    let to = dir.join("new");
+
+    let (report, out) = migrate(&migrate_args(&backup, &to, &["--page-size", "8192"])).unwrap();
+    // A server's data file made without `mydb init` has no header.
+    assert_eq!((report.version, report.page_size), (0, 4096));
+    let tables: Vec<(&str, usize, bool)> = report
+        .tables
+        .iter()
+        .map(|t| (t.name.as_str(), t.rows, t.resumed))
+        .collect();
+    assert_eq!(
+        tables,
+        [
+            ("APP.NOTES", 2, false),
+            ("USERS", 300, false),
+            ("ORDERS", 250, false)
+        ]
+    );
+    assert!(report.tables.iter().all(|t| t.sampled > 0));
+    assert_eq!(report.left_out, ["view NAMED"]);
+    assert!(out.contains("format version 0, 4096-byte pages"), "{}", out);
+    assert!(out.contains("Copied USERS: 300 rows, 1 indexes"), "{}", out);
+    assert!(out.contains("Verified ORDERS: 250 rows"), "{}", out);
+    assert!(out.contains("Not migrated: view NAMED"), "{}", out);
+    // The old server reserved 32 values with its first, so any of them may
+    // have been handed out.
+    assert!(
+        out.contains("Copied sequence IDS: next value 170"),
+        "{}",
+        out
+    );
+
+    // The old database is only read.
+    assert_eq!(std::fs::read(backup.join("data.db")).unwrap(), before);
+    assert!(!to.join("migrate.progress").exists());
+    let header = FileHeader::read(&to.join("data.db")).unwrap().unwrap();
+    assert_eq!(header.page_size, 8192);
+
+    let mut db = Database::open(DatabaseConfig {
+        page_size: 8192,
+        ..DatabaseConfig::new(&to)
+    })
+    .unwrap();
+    assert_eq!(ints(&mut db, "SELECT COUNT(*) FROM users;"), [300]);
+    assert_eq!(ints(&mut db, "SELECT COUNT(*) FROM orders;"), [250]);
+    assert_eq!(ints(&mut db, "SELECT id FROM users WHERE id = 123;"), [123]);
+    let plan = db
+        .execute("EXPLAIN SELECT id FROM users WHERE id = 123;")
+        .unwrap();
+    assert!(
+        plan.rows
+            .iter()
+            .any(|row| row[0].to_string().contains("on USERS using USERS_ID")),
+        "{:?}",
+        plan.rows
+    );
+    let notes = db.execute("SELECT body FROM app.notes;").unwrap();
+    assert_eq!(notes.rows[0][0], DbValue::from("it's"));
+    // The constraints came along.
+    assert!(
+        db.execute("INSERT INTO users (id, name) VALUES (-1, 'x');")
+            .is_err()
+    );
+    assert!(
+        db.execute("INSERT INTO orders (id, user_id) VALUES (1, 999);")
+            .is_err()
+    );
+    assert!(db.execute("CHECK;").unwrap().rows.is_empty());
+    assert_eq!(ints(&mut db, "SELECT NEXTVAL('ids');"), [170]);
+    db.close().unwrap();
+
+    // A finished migration is not run again over its target.
+    let err = migrate(&migrate_args(&backup, &to, &[])).unwrap_err();
+    assert!(err.to_string().contains("is not empty"), "{:#}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_migrate_resumes_at_the_table_it_stopped_at() {
+    let dir = fresh_dir("migrate_resumes");
+    let backup = backup_after(&dir, &setup(), &[]).await;
+    let to = dir.join("new");
+    let args = migrate_args(&backup, &to, &[]);
+
+    // A row ORDERS lists that is not in the file stops the copy of that
+    // table, after USERS is done.
+    let path = catalog_path(&backup.join("wal.log"));
+    let saved = atomic_read(&path).unwrap().unwrap();
+    let mut catalog: Catalog = serde_json::from_slice(&saved).unwrap();
+    catalog
+        .tables
+        .get_mut("ORDERS")
+        .unwrap()
+        .records
+        .push((100_000, 0));
+    atomic_write(&path, &serde_json::to_vec(&catalog).unwrap()).unwrap();
+    let err = migrate(&args).unwrap_err();
+    assert!(format!("{:#}", err).contains("ORDERS"), "{:#}", err);
+    assert!(to.join("migrate.progress").exists());
+
+    atomic_write(&path, &saved).unwrap();
+    let (report, out) = migrate(&args).unwrap();
+    assert!(
+        out.contains("Resuming: 2 tables were copied before"),
+        "{}",
+        out
+    );
+    assert!(!out.contains("Copied USERS"), "{}", out);
+    assert!(out.contains("Copied ORDERS: 250 rows"), "{}", out);
+    let resumed: Vec<(&str, bool)> = report
+        .tables
+        .iter()
+        .map(|t| (t.name.as_str(), t.resumed))
+        .collect();
+    assert_eq!(
+        resumed,
+        [("APP.NOTES", true), ("USERS", true), ("ORDERS", false)]
+    );
+
+    let mut db = Database::open(&to).unwrap();
+    assert_eq!(ints(&mut db, "SELECT COUNT(*) FROM users;"), [300]);
+    assert_eq!(ints(&mut db, "SELECT COUNT(*) FROM orders;"), [250]);
+    assert!(db.execute("CHECK;").unwrap().rows.is_empty());
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_migrate_refuses_a_database_that_needs_recovery() {
+    let dir = fresh_dir("migrate_recovery");
+    let setup = vec!["CREATE TABLE t (id INT);".to_string()];
+    let backup = backup_after(&dir, &setup, &["BEGIN;", "INSERT INTO t (id) VALUES (1);"]).await;
+    let to = dir.join("new");
+    let err = migrate(&migrate_args(&backup, &to, &[])).unwrap_err();
+    assert!(err.to_string().contains("needs WAL recovery"), "{:#}", err);
+    assert!(!to.exists());
+
+    // Nor is there anything to migrate without the catalog a checkpoint
+    // leaves.
+    let mut db = Database::open(dir.join("plain")).unwrap();
+    db.execute("CREATE TABLE t (id INT);").unwrap();
+    db.close().unwrap();
+    atomic_remove(&catalog_path(&dir.join("plain").join("wal.log"))).unwrap();
+    let err = migrate(&migrate_args(&dir.join("plain"), &to, &[])).unwrap_err();
+    assert!(err.to_string().contains("No catalog"), "{:#}", err);
+
+    let err = MigrateArgs::parse(&["--from".into(), "a".into()]).unwrap_err();
+    assert!(err.to_string().contains("--to is required"), "{}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,177 @@
+use engine::query::binder::Value;
+use engine::query::parser::Parser;
+use engine::query::pipeline::run_ddl;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, ForeignKeyViolation, Storage};
+use engine::testing::run;
+use engine::tx::mvcc::{Snapshot, TxStatus, TxStatusTable};
+use std::collections::HashSet;
+use std::fs::remove_file;
+
+fn table(db: &str) -> Storage {
+    let mut storage = Storage::new(db, 4096, 16).unwrap();
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    storage
+}
+
+// Runs a SELECT as seen from `snapshot`.
+fn ids(storage: &mut Storage, snapshot: &Snapshot) -> Vec<i64> {
+    storage.tx_id = snapshot.tx;
+    storage.snapshot = Some(snapshot.clone());
+    let mut ids: Vec<i64> = run(storage, "SELECT id FROM t;")
+        .unwrap()
+        .into_iter()
+        .map(|r| match r[0] {
+            Value::Int(i) => i,
+            _ => panic!("expected int"),
+        })
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_select_reads_its_snapshot() {
+    let db = "test_mvcc_snapshot.db";
+    let mut storage = table(db);
+    let txns = storage.txns.clone();
+
+    let loader = txns.begin();
+    storage.set_transaction(Some(loader));
+    run(&mut storage, "INSERT INTO t (id) VALUES (1), (2), (3);").unwrap();
+    txns.commit(loader);
+
+    let writer = txns.begin();
+    storage.set_transaction(Some(writer));
+    run(&mut storage, "INSERT INTO t (id) VALUES (4);").unwrap();
+    let first = storage.catalog.get_table("T").unwrap().records[0];
+    storage.delete_row("T", first).unwrap();
+
+    let reader = txns.begin();
+    let before = txns.snapshot(Some(reader));
+    assert_eq!(ids(&mut storage, &before), vec![1, 2, 3]);
+    assert_eq!(
+        ids(&mut storage, &txns.snapshot(Some(writer))),
+        vec![2, 3, 4]
+    );
+
+    txns.commit(writer);
+    assert_eq!(txns.status(writer), TxStatus::Committed);
+    // The old snapshot keeps its view; a new one sees the commit.
+    assert_eq!(ids(&mut storage, &before), vec![1, 2, 3]);
+    assert_eq!(
+        ids(&mut storage, &txns.snapshot(Some(reader))),
+        vec![2, 3, 4]
+    );
+    assert_eq!(ids(&mut storage, &txns.snapshot(None)), vec![2, 3, 4]);
+    remove_file(db).unwrap();
+}
+
+#[test]
+fn test_delete_conflicts_until_deleter_aborts() {
+    let db = "test_mvcc_delete.db";
+    let mut storage = table(db);
+    let txns = storage.txns.clone();
+
+    let loader = txns.begin();
+    storage.set_transaction(Some(loader));
+    run(&mut storage, "INSERT INTO t (id) VALUES (1);").unwrap();
+    txns.commit(loader);
+    let rid = storage.catalog.get_table("T").unwrap().records[0];
+
+    let first = txns.begin();
+    storage.set_transaction(Some(first));
+    storage.delete_row("T", rid).unwrap();
+
+    let second = txns.begin();
+    storage.set_transaction(Some(second));
+    assert!(storage.delete_row("T", rid).is_err());
+    assert_eq!(ids(&mut storage, &txns.snapshot(Some(second))), vec![1]);
+
+    // Once the first deleter is known to have aborted, its stamp is ignored.
+    txns.abort(first);
+    assert_eq!(txns.status(first), TxStatus::Aborted);
+    assert_eq!(ids(&mut storage, &txns.snapshot(Some(second))), vec![1]);
+    storage.set_transaction(Some(second));
+    storage.delete_row("T", rid).unwrap();
+    assert_eq!(
+        ids(&mut storage, &txns.snapshot(Some(second))),
+        Vec::<i64>::new()
+    );
+    remove_file(db).unwrap();
+}
+
+#[test]
+fn test_deletes_follow_foreign_keys() {
+    let db = "test_mvcc_foreign_keys.db";
+    let mut storage = Storage::new(db, 4096, 16).unwrap();
+    let txns = storage.txns.clone();
+    let loader = txns.begin();
+    storage.set_transaction(Some(loader));
+    for sql in [
+        "CREATE TABLE users (id INT);",
+        "CREATE TABLE orders (id INT, user_id INT REFERENCES users(id) ON DELETE CASCADE);",
+        "CREATE INDEX orders_user ON orders (user_id) USING HASH;",
+        "CREATE TABLE reviews (id INT, user_id INT REFERENCES users(id));",
+    ] {
+        let stmt = Parser::new(sql).unwrap().parse_statement().unwrap();
+        run_ddl(&mut storage, &stmt, None).unwrap().unwrap();
+    }
+    for sql in [
+        "INSERT INTO users (id) VALUES (1), (2);",
+        "INSERT INTO orders (id, user_id) VALUES (10, 1), (11, 1), (12, 2);",
+        "INSERT INTO reviews (id, user_id) VALUES (20, 2);",
+    ] {
+        run(&mut storage, sql).unwrap();
+    }
+    txns.commit(loader);
+    let users = storage.catalog.get_table("USERS").unwrap().records.clone();
+
+    let deleter = txns.begin();
+    storage.set_transaction(Some(deleter));
+    storage.delete_row("USERS", users[0]).unwrap();
+    storage.snapshot = Some(txns.snapshot(Some(deleter)));
+    let orders = run(&mut storage, "SELECT id FROM orders;").unwrap();
+    assert_eq!(orders, vec![vec![Value::Int(12)]]);
+
+    // A review still refers to the second user.
+    let error = storage.delete_row("USERS", users[1]).unwrap_err();
+    let violation = error.downcast_ref::<ForeignKeyViolation>().unwrap();
+    assert_eq!(violation.constraint, "REVIEWS_USER_ID_FKEY");
+    assert_eq!(violation.table, "USERS");
+    remove_file(db).unwrap();
+}
+
+#[test]
+fn test_aborted_transactions_are_forgotten_once_undone() {
+    let txns = TxStatusTable::new();
+    let undone = txns.begin();
+    let temp_writer = txns.begin();
+    txns.wrote_unlogged(temp_writer);
+    txns.abort(undone);
+    txns.abort(temp_writer);
+    assert_eq!(
+        txns.snapshot(None).aborted,
+        HashSet::from([undone, temp_writer])
+    );
+
+    // No row carries the first one's id any more, but the rows it left in a
+    // temporary table still carry the second's.
+    txns.forget(undone);
+    txns.forget(temp_writer);
+    assert_eq!(txns.status(undone), TxStatus::Committed);
+    assert_eq!(txns.status(temp_writer), TxStatus::Aborted);
+    assert_eq!(txns.snapshot(None).aborted, HashSet::from([temp_writer]));
+
+    let open = txns.begin();
+    txns.forget(open);
+    assert_eq!(txns.status(open), TxStatus::Active);
+}
@@ -0,0 +1,118 @@
+use engine::index::bplustree::BPlusTree;
+use engine::index::node_serializer::{
+    InternalNodeSerializer, LeafNodeSerializer, NodeError, NodeHeader, NodeType,
+};
+use engine::storage::storage::{IndexInfo, IndexKind, Storage};
+use std::fs::remove_file;
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[test]
+fn test_random_bytes_never_panic() {
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    let internal = InternalNodeSerializer::<u64>::new(4);
+    let leaf = LeafNodeSerializer::<u64>::new(4);
+    for _ in 0..5000 {
+        let len = (rng.next() % 300) as usize;
+        let mut buf: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+        if let Some(b) = buf.first_mut() {
+            *b %= 3;
+        }
+        let _ = NodeHeader::deserialize(&buf);
+        let _ = internal.deserialize(&buf);
+        let _ = leaf.deserialize(&buf);
+    }
+}
+
+#[test]
+fn test_deserialize_errors() {
+    let leaf = LeafNodeSerializer::<u64>::new(4);
+    let internal = InternalNodeSerializer::<u64>::new(4);
+
+    assert_eq!(
+        NodeHeader::deserialize(&[1, 0]).err(),
+        Some(NodeError::TruncatedNode {
+            expected: NodeHeader::SIZE,
+            got: 2
+        })
+    );
+
+    let mut page = vec![0u8; 64];
+    page[0] = 7;
+    assert_eq!(
+        leaf.deserialize(&page).err(),
+        Some(NodeError::InvalidNodeType(7))
+    );
+
+    let header = NodeHeader {
+        node_type: NodeType::Leaf,
+        key_count: 2,
+        parent: 0,
+    };
+    let page = leaf.serialize(&header, &[1, 2], &[(1, 0), (2, 0)], 0, 64);
+    assert_eq!(
+        internal.deserialize(&page).err(),
+        Some(NodeError::UnexpectedNodeType {
+            expected: NodeType::Internal,
+            got: NodeType::Leaf
+        })
+    );
+
+    let mut page = page;
+    page[1..3].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert_eq!(
+        leaf.deserialize(&page).err(),
+        Some(NodeError::TruncatedNode {
+            expected: NodeHeader::SIZE + 18 * u16::MAX as usize + 8,
+            got: 64
+        })
+    );
+}
+
+#[test]
+fn test_corrupt_page_reports_page_number() {
+    let path = "test_node_corrupt_page.db";
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    let root = {
+        let mut tree = BPlusTree::<u64>::create(&mut storage, 4, "T".into()).unwrap();
+        tree.insert(1, (1, 0)).unwrap();
+        tree.root_page()
+    };
+
+    let frame = storage.buffer_pool.fetch_page(root).unwrap();
+    frame.data[0] = 9;
+    storage.buffer_pool.unpin_page(root, true);
+
+    let info = IndexInfo {
+        name: "I".into(),
+        table: "T".into(),
+        column: "ID".into(),
+        more_columns: Vec::new(),
+        order: 4,
+        root_page: root,
+        kind: IndexKind::BTree,
+        bloom_page: None,
+    };
+    let err = BPlusTree::<u64>::open(&mut storage, &info)
+        .get(1)
+        .unwrap_err();
+    let msg = format!("{:#}", err);
+    assert!(msg.contains(&format!("page {}", root)), "{}", msg);
+    assert!(msg.contains("invalid node type byte 9"), "{}", msg);
+    assert!(
+        BPlusTree::<u64>::open(&mut storage, &info)
+            .insert(2, (2, 0))
+            .is_err()
+    );
+    assert!(storage.buffer_pool.pool.values().all(|f| f.pin_count == 0));
+    remove_file(path).unwrap();
+}
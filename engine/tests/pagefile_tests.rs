@@ -1,21 +1,21 @@
 use std::fs::remove_file;
 use std::path::Path;
-use storage::pagefile::PageFile;
+use engine::storage::pagefile::PageFile;
 
 #[test]
 fn test_open_create_file() {
-    let path = "test_pagefile.db";
+    let path = "test_pagefile_open_create_file.db";
     if Path::new(path).exists() {
         remove_file(path).unwrap();
     }
-    let pf = PageFile::open(path, 4096).expect("open/create failed");
+    let _pf = PageFile::open(path, 4096).expect("open/create failed");
     assert!(Path::new(path).exists());
     remove_file(path).unwrap();
 }
 
 #[test]
 fn test_read_write_single_page() {
-    let path = "test_pagefile.db";
+    let path = "test_pagefile_read_write_single_page.db";
     let mut pf = PageFile::open(path, 4096).unwrap();
     let data = vec![0xABu8; 4096];
     pf.write_page(0, &data).unwrap();
@@ -26,7 +26,7 @@ fn test_read_write_single_page() {
 
 #[test]
 fn test_write_page_overflow() {
-    let path = "test_pagefile.db";
+    let path = "test_pagefile_write_page_overflow.db";
     let mut pf = PageFile::open(path, 4096).unwrap();
     let data = vec![0u8; 5000];
     assert!(pf.write_page(0, &data).is_err());
@@ -35,7 +35,7 @@ fn test_write_page_overflow() {
 
 #[test]
 fn test_allocate_and_count_pages() {
-    let path = "test_pagefile.db";
+    let path = "test_pagefile_allocate_and_count_pages.db";
     let mut pf = PageFile::open(path, 4096).unwrap();
     let initial = pf.num_pages().unwrap();
     let new_page = pf.allocate_page().unwrap();
@@ -46,7 +46,7 @@ fn test_allocate_and_count_pages() {
 
 #[test]
 fn test_sync_all() {
-    let path = "test_pagefile.db";
+    let path = "test_pagefile_sync_all.db";
     let mut pf = PageFile::open(path, 4096).unwrap();
     let data = vec![0xCDu8; 4096];
     pf.write_page(0, &data).unwrap();
@@ -0,0 +1,796 @@
+use engine::database::Database;
+use engine::net::client::DbValue;
+use engine::query::binder::{Value, expand_views};
+use engine::query::lexer::{KEYWORDS, LexError, Lexer, TokenKind, keyword, quote_identifier};
+use engine::query::parser::{BinaryOp, Expr, MAX_EXPR_DEPTH, ParseLimits, Parser, Statement};
+use engine::query::source::{SourceError, Span};
+use engine::storage::storage::{Catalog, Collation, ColumnInfo, DataType, Privilege, ViewInfo};
+
+fn parse_error(sql: &str) -> SourceError {
+    let error = Parser::new(sql)
+        .and_then(|mut parser| parser.parse_statement())
+        .unwrap_err();
+    error.downcast::<SourceError>().unwrap()
+}
+
+#[test]
+fn test_tokens_carry_byte_spans() {
+    let sql = "SELECT id, 'café'\n  FROM t_1 WHERE id <= 42;";
+    let tokens: Vec<_> = Lexer::new(sql).map(Result::unwrap).collect();
+    let text: Vec<&str> = tokens
+        .iter()
+        .map(|token| &sql[token.span.start..token.span.end])
+        .collect();
+    assert_eq!(
+        text,
+        vec![
+            "SELECT", "id", ",", "'café'", "FROM", "t_1", "WHERE", "id", "<=", "42", ";", ""
+        ]
+    );
+    assert_eq!(tokens.last().unwrap().kind, TokenKind::EOF);
+    assert_eq!(tokens[4].span.position(sql), (2, 3));
+    // Columns count characters: the closing quote is byte 17 but the 17th
+    // character, the é taking two bytes.
+    assert_eq!(Span::new(17, 18).position(sql), (1, 17));
+}
+
+#[test]
+fn test_lex_errors_point_at_the_bad_text() {
+    let sql = "SELECT 'open";
+    match Lexer::new(sql).nth(1).unwrap() {
+        Err(LexError::UnterminatedString(span)) => assert_eq!(span, Span::new(7, 12)),
+        other => panic!("expected an unterminated string, got {:?}", other),
+    }
+    let error = parse_error("SELECT id FROM t WHERE id = 99999999999999999999;");
+    assert_eq!((error.line, error.col), (1, 29));
+    assert!(
+        error.message.starts_with("Invalid number"),
+        "{}",
+        error.message
+    );
+}
+
+#[test]
+fn test_keywords_lex_as_their_own_tokens() {
+    let kinds: Vec<TokenKind> = Lexer::new("order By limit \"order\" orders")
+        .map(|token| token.unwrap().kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TokenKind::Order,
+            TokenKind::By,
+            TokenKind::Limit,
+            TokenKind::Identifier("ORDER".to_string()),
+            TokenKind::Identifier("ORDERS".to_string()),
+            TokenKind::EOF,
+        ]
+    );
+    // The lookup is a binary search, so the table has to stay sorted.
+    assert!(KEYWORDS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(keyword("DISTINCT"), Some(TokenKind::Distinct));
+    assert_eq!(keyword("INT"), None);
+
+    match Lexer::new("SELECT \"open").nth(1).unwrap() {
+        Err(LexError::UnterminatedIdentifier(span)) => assert_eq!(span, Span::new(7, 12)),
+        other => panic!("expected an unterminated identifier, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_quoted_identifiers_may_be_keywords() {
+    let error = parse_error("CREATE TABLE order (id INT);");
+    assert_eq!(error.message, "Expected table name, found Order");
+
+    let dir = std::env::temp_dir().join(format!("mydb_quoted_names_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE \"order\" (id INT, \"limit\" INT);")
+        .unwrap();
+    db.execute("INSERT INTO \"ORDER\" (id, \"limit\") VALUES (1, 10);")
+        .unwrap();
+    let result = db
+        .execute("SELECT \"limit\" FROM \"order\" WHERE id = 1;")
+        .unwrap();
+    assert_eq!(result.columns(), ["LIMIT"]);
+    assert_eq!(result.rows, vec![vec![DbValue::Int(10)]]);
+    drop(db);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(quote_identifier("USERS"), "USERS");
+    assert_eq!(quote_identifier("ORDER"), "\"ORDER\"");
+    assert_eq!(quote_identifier("MY \"T\""), "\"MY \"\"T\"\"\"");
+}
+
+#[test]
+fn test_parse_errors_quote_the_line_with_a_caret() {
+    let error = parse_error("SELECT id name FROM t;");
+    assert_eq!(error.message, "Expected From, found Identifier(\"NAME\")");
+    assert_eq!(
+        error.to_string(),
+        "Expected From, found Identifier(\"NAME\") at 1:11\n  |\n1 | SELECT id name FROM t;\n  |           ^^^^"
+    );
+}
+
+#[test]
+fn test_parse_errors_in_multi_line_statements_name_the_right_line() {
+    let sql = "SELECT id,\n       name\n  FROM t\n WHERE id = ;";
+    let error = parse_error(sql);
+    assert_eq!((error.line, error.col), (4, 13));
+    assert_eq!(error.excerpt, "  |\n4 |  WHERE id = ;\n  |             ^");
+
+    // Past the end of the input the caret sits just after the last character.
+    let error = parse_error("SELECT id\nFROM t");
+    assert_eq!((error.line, error.col), (2, 7));
+    assert!(
+        error.excerpt.ends_with("2 | FROM t\n  |       ^"),
+        "{}",
+        error.excerpt
+    );
+}
+
+#[test]
+fn test_bind_errors_underline_the_unknown_name() {
+    let dir = std::env::temp_dir().join(format!("mydb_bind_excerpt_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE users (id INT, name TEXT);")
+        .unwrap();
+
+    let error = db.execute("SELECT nmae FROM users;").unwrap_err();
+    let message = error.to_string();
+    assert!(
+        message.contains("Unknown column 'NMAE' in 'USERS'"),
+        "{}",
+        message
+    );
+    assert!(
+        message.ends_with("  |\n1 | SELECT nmae FROM users;\n  |        ^^^^"),
+        "{}",
+        message
+    );
+
+    let error = db
+        .execute("SELECT id\n  FROM users\n WHERE nmae = 'x';")
+        .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .ends_with("3 |  WHERE nmae = 'x';\n  |        ^^^^"),
+        "{}",
+        error
+    );
+
+    let error = db.execute("SELECT id FROM usres;").unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .ends_with("1 | SELECT id FROM usres;\n  |                ^^^^^")
+    );
+    drop(db);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_parse_script_recovers_at_the_next_semicolon() {
+    let script = "SELECT id FROM t;\nSELECT FROM t;\nINSERT INTO t (id) VALUES (1);\nCREATE TABLE (id INT);\nSELECT id FROM t WHERE id = 1;";
+    let diagnostics = Parser::parse_script(script).unwrap_err();
+    let places: Vec<(usize, usize)> = diagnostics.0.iter().map(|e| (e.line, e.col)).collect();
+    assert_eq!(places, vec![(2, 8), (4, 14)]);
+    assert!(diagnostics.to_string().starts_with("2 syntax errors\n"));
+
+    assert_eq!(
+        Parser::parse_script(
+            script
+                .replace("SELECT FROM", "SELECT id FROM")
+                .replace("TABLE (", "TABLE u (")
+                .as_str()
+        )
+        .unwrap()
+        .len(),
+        5
+    );
+    assert!(
+        Parser::parse_script("-- nothing to do\n")
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_parse_script_reports_lex_errors_once_per_statement() {
+    // The `@` would leave a valid statement behind if it were just dropped.
+    let script = "SELECT id FROM t WHERE id = 1 @;\nSELECT 'open;\n";
+    let diagnostics = Parser::parse_script(script).unwrap_err();
+    let messages: Vec<&str> = diagnostics.0.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec!["Unexpected character '@'", "Unterminated string literal"]
+    );
+    assert_eq!((diagnostics.0[1].line, diagnostics.0[1].col), (2, 8));
+}
+
+#[test]
+fn test_parse_one_wants_exactly_one_statement() {
+    assert!(Parser::parse_one("SELECT id FROM t;").is_ok());
+    let none = Parser::parse_one("  ").unwrap_err();
+    assert_eq!(none.0[0].message, "Expected a statement, found EOF");
+    let two = Parser::parse_one("SELECT id FROM t; SELECT id FROM t;").unwrap_err();
+    assert_eq!((two.0[0].line, two.0[0].col), (1, 19));
+}
+
+#[test]
+fn test_cursor_statements() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    let select = Parser::parse_one("SELECT id FROM t;").unwrap();
+    let declared = Statement::DeclareCursor {
+        name: "C".to_string(),
+        select: Box::new(select),
+    };
+    assert_eq!(parse("DECLARE CURSOR c FOR SELECT id FROM t;"), declared);
+    assert_eq!(parse("declare c cursor for select id from t;"), declared);
+    let fetch = |count| Statement::Fetch {
+        cursor: "C".to_string(),
+        count,
+    };
+    assert_eq!(parse("FETCH 100 FROM c;"), fetch(100));
+    assert_eq!(parse("FETCH FROM c;"), fetch(1));
+    assert_eq!(parse("FETCH ALL FROM c;"), fetch(u64::MAX));
+    assert_eq!(
+        parse("CLOSE c;"),
+        Statement::CloseCursor {
+            name: "C".to_string()
+        }
+    );
+    assert!(Parser::parse_one("FETCH 0 FROM c;").is_err());
+    assert!(Parser::parse_one("DECLARE CURSOR c FOR INSERT INTO t (id) VALUES (1);").is_err());
+    // They are only words, so they still name tables.
+    assert!(Parser::parse_one("SELECT fetch FROM close;").is_ok());
+}
+
+#[test]
+fn test_create_table_as() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    let select = Box::new(parse("SELECT id FROM users ORDER BY id;"));
+    assert_eq!(
+        parse("CREATE TABLE top AS SELECT id FROM users ORDER BY id;"),
+        Statement::CreateTableAs {
+            name: "TOP".to_string(),
+            columns: Vec::new(),
+            temporary: false,
+            select: select.clone(),
+        }
+    );
+    assert_eq!(
+        parse("CREATE TEMP TABLE top (user_id) AS SELECT id FROM users ORDER BY id;"),
+        Statement::CreateTableAs {
+            name: "TOP".to_string(),
+            columns: vec!["USER_ID".to_string()],
+            temporary: true,
+            select,
+        }
+    );
+    // Column definitions still make an empty table.
+    assert!(matches!(
+        parse("CREATE TABLE t (id INT);"),
+        Statement::CreateTable { .. }
+    ));
+    assert!(Parser::parse_one("CREATE TABLE t (id) AS INSERT INTO u (id) VALUES (1);").is_err());
+    assert!(Parser::parse_one("CREATE TABLE t (id INT) AS SELECT id FROM u;").is_err());
+}
+
+#[test]
+fn test_set_constraints() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    assert_eq!(
+        parse("SET CONSTRAINTS ALL DEFERRED;"),
+        Statement::SetConstraints { deferred: true }
+    );
+    assert_eq!(
+        parse("set constraints all immediate;"),
+        Statement::SetConstraints { deferred: false }
+    );
+    assert!(Parser::parse_one("SET CONSTRAINTS orders_user_id_fkey DEFERRED;").is_err());
+    assert!(Parser::parse_one("SET CONSTRAINTS ALL LATER;").is_err());
+}
+
+#[test]
+fn test_checkpoint_and_flush_tables() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    assert_eq!(parse("checkpoint;"), Statement::Checkpoint);
+    assert_eq!(
+        parse("FLUSH TABLES;"),
+        Statement::FlushTables { tables: vec![] }
+    );
+    assert_eq!(
+        parse("FLUSH TABLES orders, app.users;"),
+        Statement::FlushTables {
+            tables: vec!["ORDERS".into(), "APP.USERS".into()]
+        }
+    );
+    assert!(Parser::parse_one("CHECKPOINT orders;").is_err());
+    assert!(Parser::parse_one("FLUSH orders;").is_err());
+}
+
+#[test]
+fn test_delete_and_vacuum() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    assert_eq!(
+        parse("DELETE FROM orders;"),
+        Statement::Delete {
+            table: "ORDERS".into(),
+            filter: None
+        }
+    );
+    assert!(matches!(
+        parse("delete from app.orders where id < 10;"),
+        Statement::Delete { table, filter: Some(_) } if table == "APP.ORDERS"
+    ));
+    assert_eq!(parse("VACUUM;"), Statement::Vacuum { tables: vec![] });
+    assert_eq!(
+        parse("vacuum orders, app.users;"),
+        Statement::Vacuum {
+            tables: vec!["ORDERS".into(), "APP.USERS".into()]
+        }
+    );
+    assert!(Parser::parse_one("DELETE orders;").is_err());
+    assert!(Parser::parse_one("DELETE FROM orders WHERE;").is_err());
+}
+
+#[test]
+fn test_grant_and_revoke() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    assert_eq!(
+        parse("GRANT SELECT (id, body) ON notes TO alice;"),
+        Statement::Grant {
+            privilege: Privilege::Select,
+            columns: vec!["ID".to_string(), "BODY".to_string()],
+            table: "NOTES".to_string(),
+            user: "ALICE".to_string(),
+        }
+    );
+    assert_eq!(
+        parse("revoke all on notes from bob;"),
+        Statement::Revoke {
+            privilege: Privilege::All,
+            columns: Vec::new(),
+            table: "NOTES".to_string(),
+            user: "BOB".to_string(),
+        }
+    );
+    assert_eq!(parse("SHOW GRANTS;"), Statement::ShowGrants);
+
+    let error = parse_error("GRANT DELETE ON notes TO alice;");
+    assert!(
+        error.message.contains("SELECT, INSERT or ALL"),
+        "{}",
+        error.message
+    );
+    // ALL is on the whole table only.
+    let error = parse_error("GRANT ALL (id) ON notes TO alice;");
+    assert_eq!((error.line, error.col), (1, 11));
+    let error = parse_error("REVOKE INSERT ON notes TO alice;");
+    assert_eq!((error.line, error.col), (1, 24));
+}
+
+#[test]
+fn test_explain_analyze() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    match parse("EXPLAIN ANALYZE SELECT id FROM t;") {
+        Statement::Explain { stmt, analyze } => {
+            assert!(analyze);
+            assert!(matches!(*stmt, Statement::Select { .. }));
+        }
+        other => panic!("{:?}", other),
+    }
+    // ANALYZE before a table name is the statement being explained.
+    assert_eq!(
+        parse("EXPLAIN ANALYZE t;"),
+        Statement::Explain {
+            stmt: Box::new(Statement::Analyze {
+                table: "T".to_string()
+            }),
+            analyze: false,
+        }
+    );
+    assert!(Parser::parse_one("EXPLAIN ANALYZE EXPLAIN SELECT id FROM t;").is_err());
+}
+
+#[test]
+fn test_sequence_statements_and_calls() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    assert_eq!(
+        parse("CREATE SEQUENCE s START 100 INCREMENT -5;"),
+        Statement::CreateSequence {
+            name: "S".to_string(),
+            start: 100,
+            increment: -5,
+        }
+    );
+    assert_eq!(
+        parse("create sequence s;"),
+        Statement::CreateSequence {
+            name: "S".to_string(),
+            start: 1,
+            increment: 1,
+        }
+    );
+    assert_eq!(
+        parse("DROP SEQUENCE s;"),
+        Statement::DropSequence {
+            name: "S".to_string()
+        }
+    );
+    assert_eq!(
+        parse("SELECT nextval('s');"),
+        Statement::Select {
+            projections: vec![Expr::Call {
+                name: "NEXTVAL".to_string(),
+                args: vec![Expr::Literal(Value::String("s".to_string()))],
+            }],
+            table: None,
+            as_of: None,
+            filter: None,
+            group_by: Vec::new(),
+            order_by: Vec::new(),
+        }
+    );
+
+    assert!(matches!(
+        parse("SELECT * FROM t AS OF LSN 42 WHERE id = 1;"),
+        Statement::Select {
+            as_of: Some(42),
+            filter: Some(_),
+            ..
+        }
+    ));
+    let error = parse_error("SELECT * FROM t AS u;");
+    assert!(error.message.contains("OF"), "{}", error.message);
+
+    let error = parse_error("CREATE SEQUENCE s START 'one';");
+    assert!(error.message.contains("start value"), "{}", error.message);
+    // A WHERE needs a FROM to filter.
+    let error = parse_error("SELECT 1 WHERE 1 = 1;");
+    assert_eq!((error.line, error.col), (1, 10));
+}
+
+#[test]
+fn test_schema_statements_and_qualified_names() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    assert_eq!(
+        parse("CREATE SCHEMA app;"),
+        Statement::CreateSchema {
+            name: "APP".to_string()
+        }
+    );
+    assert_eq!(
+        parse("DROP SCHEMA app CASCADE;"),
+        Statement::DropSchema {
+            name: "APP".to_string(),
+            cascade: true
+        }
+    );
+    assert_eq!(
+        parse("DROP SCHEMA app RESTRICT;"),
+        Statement::DropSchema {
+            name: "APP".to_string(),
+            cascade: false
+        }
+    );
+    assert_eq!(
+        parse("SHOW TABLES IN app;"),
+        Statement::ShowTables {
+            schema: Some("APP".to_string())
+        }
+    );
+    assert!(matches!(
+        parse("SELECT id FROM app.users AS OF LSN 7;"),
+        Statement::Select { table: Some(table), as_of: Some(7), .. } if table == "APP.USERS"
+    ));
+    assert!(matches!(
+        parse("INSERT INTO \"app\".users (id) VALUES (1);"),
+        Statement::Insert { table, .. } if table == "APP.USERS"
+    ));
+    assert_eq!(
+        parse("SET search_path = app, public;"),
+        Statement::Set {
+            name: "SEARCH_PATH".to_string(),
+            value: "APP, PUBLIC".to_string(),
+            global: false
+        }
+    );
+    let error = Parser::parse_one("SELECT id FROM app.;")
+        .unwrap_err()
+        .0
+        .remove(0);
+    assert!(error.message.contains("table name"), "{}", error.message);
+}
+
+#[test]
+fn test_view_expansion_stops_at_cycles_and_missing_columns() {
+    let mut catalog = Catalog::new();
+    let column = |name: &str| ColumnInfo {
+        name: name.to_string(),
+        data_type: DataType::Int,
+        collation: Collation::Binary,
+    };
+    catalog
+        .create_table("T".to_string(), vec![column("ID")])
+        .unwrap();
+    // Nothing CREATE VIEW lets through, but a catalog can still come to this.
+    for (name, table, query) in [
+        ("A", "B", "SELECT * FROM b;"),
+        ("B", "A", "SELECT * FROM a;"),
+        ("OLD", "T", "SELECT id, gone FROM t;"),
+    ] {
+        let view = ViewInfo {
+            name: name.to_string(),
+            table: table.to_string(),
+            query: query.to_string(),
+        };
+        catalog.views.insert(name.to_string(), view);
+    }
+    let expand = |sql: &str| {
+        let stmt = Parser::parse_one(sql).unwrap();
+        format!("{:#}", expand_views(&catalog, stmt).unwrap_err())
+    };
+    assert_eq!(
+        expand("SELECT * FROM a;"),
+        "Views refer back to themselves: A -> B -> A"
+    );
+    assert_eq!(
+        expand("SELECT id FROM old;"),
+        "View 'OLD' uses column 'GONE', which 'T' no longer has"
+    );
+
+    let Statement::CreateView { name, query, .. } =
+        Parser::parse_one("CREATE VIEW v AS SELECT id FROM t WHERE id > 1;").unwrap()
+    else {
+        panic!("expected CREATE VIEW");
+    };
+    assert_eq!(name, "V");
+    assert_eq!(query, "SELECT id FROM t WHERE id > 1;");
+}
+
+#[test]
+fn test_arithmetic_binds_tighter_than_comparison() {
+    let dir = std::env::temp_dir().join("mydb_parser_arithmetic");
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("INSERT INTO t (id, name) VALUES (1, 'a'), (3, 'b');")
+        .unwrap();
+    let result = db
+        .execute("SELECT name FROM t WHERE id * 2 + 1 = 7 - 0 / 5;")
+        .unwrap();
+    assert_eq!(result.rows, vec![vec![DbValue::Text("b".to_string())]]);
+
+    for (sql, expected) in [
+        ("SELECT id FROM t WHERE id / 0 = 1;", "Division by zero"),
+        ("SELECT id FROM t WHERE name + 1 = 2;", "INT"),
+    ] {
+        let error = format!("{:#}", db.execute(sql).unwrap_err());
+        assert!(error.contains(expected), "{}: {}", sql, error);
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_in_lists_expand_to_comparisons() {
+    let Statement::Select { filter, .. } =
+        Parser::parse_one("SELECT id FROM t WHERE id NOT IN (1, 2) AND name IN ('a');").unwrap()
+    else {
+        panic!("not a SELECT");
+    };
+    let compare = |column: &str, op, value| Expr::BinaryOp {
+        left: Box::new(Expr::Column(column.to_string())),
+        op,
+        right: Box::new(Expr::Literal(value)),
+    };
+    let not_in = Expr::BinaryOp {
+        left: Box::new(compare("ID", BinaryOp::NotEq, Value::Int(1))),
+        op: BinaryOp::And,
+        right: Box::new(compare("ID", BinaryOp::NotEq, Value::Int(2))),
+    };
+    assert_eq!(
+        filter,
+        Some(Expr::BinaryOp {
+            left: Box::new(not_in),
+            op: BinaryOp::And,
+            right: Box::new(compare("NAME", BinaryOp::Eq, Value::String("a".into()))),
+        })
+    );
+    assert!(Parser::parse_one("SELECT id FROM t WHERE id IN ();").is_err());
+    assert!(Parser::parse_one("SELECT id FROM t WHERE id IN 1;").is_err());
+
+    let dir = std::env::temp_dir().join("mydb_parser_in");
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("INSERT INTO t (id, name) VALUES (1, 'a'), (2, 'b'), (3, 'c');")
+        .unwrap();
+    let ids = |db: &mut Database, sql: &str| db.execute(sql).unwrap().rows;
+    assert_eq!(
+        ids(
+            &mut db,
+            "SELECT id FROM t WHERE id IN (3, 1 + 0) OR name = 'b';"
+        )
+        .len(),
+        3
+    );
+    assert_eq!(
+        ids(&mut db, "SELECT id FROM t WHERE name NOT IN ('a', 'c');"),
+        vec![vec![DbValue::Int(2)]]
+    );
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// An expression nested `depth` deep in one of the shapes that recurse.
+fn nested(shape: usize, depth: usize) -> String {
+    match shape {
+        0 => format!("{}1{}", "(".repeat(depth), ")".repeat(depth)),
+        1 => vec!["id = 1"; depth].join(" AND "),
+        2 => format!("1{}", " + 1".repeat(depth)),
+        3 => format!("{}1{}", "ABS(".repeat(depth), ")".repeat(depth)),
+        4 => format!(
+            "{}1 = 1{}",
+            "EXISTS (SELECT 1 FROM t WHERE ".repeat(depth),
+            ")".repeat(depth)
+        ),
+        _ => format!("{}id{}", "(1 * ".repeat(depth), ")".repeat(depth)),
+    }
+}
+
+#[test]
+fn test_deeply_nested_expressions_are_refused() {
+    for shape in 0..6 {
+        for depth in [MAX_EXPR_DEPTH + 1, 10_000, 100_000] {
+            let sql = format!("SELECT id FROM t WHERE {};", nested(shape, depth));
+            let error = Parser::parse_one(&sql).unwrap_err();
+            assert!(
+                error.0[0]
+                    .message
+                    .starts_with("Expression too deeply nested"),
+                "{}: {}",
+                shape,
+                error
+            );
+        }
+    }
+
+    // Random mixes of them, each either read or refused, never overflowing.
+    let mut seed: u64 = 0x2510;
+    for _ in 0..100 {
+        let mut sql = String::from("1");
+        let steps = (seed >> 33) as usize % 1000;
+        for _ in 0..steps {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            sql = match (seed >> 33) % 5 {
+                0 => format!("({})", sql),
+                1 => format!("{} AND id > 2", sql),
+                2 => format!("ABS({})", sql),
+                3 => format!("1 - {}", sql),
+                _ => format!("{} BETWEEN 1 AND 2", sql),
+            };
+        }
+        if let Err(error) = Parser::parse_one(&format!("SELECT id FROM t WHERE {};", sql)) {
+            assert!(error.0[0].message.contains("too"), "{}", error);
+        }
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    }
+
+    // Nested as deep as the parser lets through, every shape binds, plans
+    // and runs; but for EXISTS, which the binder does not nest yet.
+    let dir = std::env::temp_dir().join("mydb_parser_nesting");
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT);").unwrap();
+    db.execute("INSERT INTO t (id) VALUES (1);").unwrap();
+    for shape in [0, 1, 2, 3, 5] {
+        let sql = (1..=MAX_EXPR_DEPTH)
+            .rev()
+            .map(|depth| format!("SELECT id FROM t WHERE {};", nested(shape, depth)))
+            .find(|sql| Parser::parse_one(sql).is_ok())
+            .unwrap();
+        db.execute(&sql)
+            .unwrap_or_else(|e| panic!("{}: {:#}", shape, e));
+    }
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_parse_limits_are_checked_per_statement() {
+    let limits = ParseLimits {
+        max_depth: 3,
+        max_statement_bytes: 40,
+        max_statement_tokens: 12,
+    };
+    assert!(Parser::parse_one_with("SELECT (1);", limits).is_ok());
+    let error = Parser::parse_one_with("SELECT (((1)));", limits).unwrap_err();
+    assert_eq!(
+        error.0[0].message,
+        "Expression too deeply nested, more than 3 levels"
+    );
+
+    // Each BETWEEN copies its operand, so chaining them doubles the
+    // expression: these copy 1, 6 and then 16 tokens' worth.
+    let sql = "SELECT 1 BETWEEN 0 AND 1 BETWEEN 0 AND 1 BETWEEN 0 AND 1;";
+    let between = ParseLimits {
+        max_depth: 10,
+        max_statement_bytes: 100,
+        max_statement_tokens: 20,
+    };
+    let error = Parser::parse_one_with(sql, between).unwrap_err();
+    assert_eq!(
+        error.0[0].message,
+        "Statement too long once BETWEEN and IN are expanded, more than 20 tokens"
+    );
+
+    let error = Parser::parse_one_with("SELECT 1, 2, 3, 4, 5, 6, 7;", limits).unwrap_err();
+    assert_eq!(
+        error.0[0].message,
+        "Statement too long: 15 tokens, at most 12"
+    );
+    let error =
+        Parser::parse_one_with("SELECT 'a string longer than forty bytes';", limits).unwrap_err();
+    assert_eq!(
+        error.0[0].message,
+        "Statement too long: 42 bytes, at most 40"
+    );
+
+    // Only the statement over the limit is refused; the ones around it
+    // are read as usual.
+    let error =
+        Parser::parse_script_with("SELECT 1;\nSELECT 1, 2, 3, 4, 5, 6, 7;\nSELECT 2;", limits)
+            .unwrap_err();
+    assert_eq!(error.0.len(), 1);
+    assert_eq!(error.0[0].line, 2);
+    assert_eq!(
+        Parser::parse_script_with("SELECT 1; SELECT 2, 3; SELECT 4;", limits)
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn test_session_variables() {
+    let parse = |sql: &str| Parser::parse_one(sql).unwrap();
+    assert_eq!(
+        parse("SET @cutoff = 100;"),
+        Statement::SetVariable {
+            name: "CUTOFF".to_string(),
+            value: Value::Int(100),
+        }
+    );
+    assert_eq!(
+        parse("set @Who to 'ada';"),
+        Statement::SetVariable {
+            name: "WHO".to_string(),
+            value: Value::String("ada".to_string()),
+        }
+    );
+    assert_eq!(
+        parse("SET @low = -5;"),
+        Statement::SetVariable {
+            name: "LOW".to_string(),
+            value: Value::Int(-5),
+        }
+    );
+    let Statement::Select { filter, .. } = parse("SELECT id FROM t WHERE score > @cutoff;") else {
+        panic!("not a SELECT");
+    };
+    assert_eq!(
+        filter,
+        Some(Expr::BinaryOp {
+            left: Box::new(Expr::Column("SCORE".to_string())),
+            op: BinaryOp::Gt,
+            right: Box::new(Expr::Variable("CUTOFF".to_string())),
+        })
+    );
+    assert!(Parser::parse_one("SET @x = score;").is_err());
+    assert!(Parser::parse_one("SELECT @ FROM t;").is_err());
+}
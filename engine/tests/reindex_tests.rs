@@ -0,0 +1,97 @@
+use engine::index::bplustree::BPlusTree;
+use engine::query::binder::Value;
+use engine::storage::storage::{IndexKind, Storage};
+use engine::testing::{run, table_with_rows};
+use std::fs::remove_file;
+
+#[test]
+fn test_bulk_load_builds_valid_tree() {
+    let path = "test_reindex_bulk_load.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    let entries: Vec<(u64, (u64, u16))> = (0..1000u64).map(|k| (k * 2, (k, 0))).collect();
+    let mut tree = BPlusTree::bulk_load(&mut storage, 4, "T".into(), &entries).unwrap();
+    assert_eq!(tree.check().unwrap(), 1000);
+    for &(key, rid) in &entries {
+        assert_eq!(tree.get(key).unwrap(), Some(rid));
+    }
+    assert_eq!(tree.range_scan_keys(10, 20).unwrap().len(), 6);
+    tree.insert(1, (9, 9)).unwrap();
+    assert_eq!(tree.check().unwrap(), 1001);
+
+    let empty = BPlusTree::<u64>::bulk_load(&mut storage, 4, "T".into(), &[]);
+    assert_eq!(empty.unwrap().check().unwrap(), 0);
+    let unsorted = [(2u64, (0, 0)), (1, (0, 1))];
+    assert!(BPlusTree::bulk_load(&mut storage, 4, "T".into(), &unsorted).is_err());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_reindex_swaps_root_and_releases_pages() {
+    let path = "test_reindex_btree.db";
+    let mut storage = table_with_rows(path, 300);
+    storage.create_index("T", "ID", "T_ID", Some(4)).unwrap();
+    let old = storage.get_indexes("T").remove(0);
+    let old_pages = BPlusTree::<i64>::open(&mut storage, &old).pages().unwrap();
+
+    let rows = run(&mut storage, "REINDEX t_id ON t;").unwrap();
+    assert_eq!(rows.len(), 1);
+    assert!(matches!(rows[0][0], Value::Int(300)));
+    assert!(matches!(rows[0][1], Value::Int(ms) if ms >= 0));
+
+    let new = storage.get_indexes("T").remove(0);
+    assert_ne!(new.root_page, old.root_page);
+    assert_eq!(
+        storage.buffer_pool.pagefile.free_page_count(),
+        old_pages.len()
+    );
+    assert_eq!(
+        BPlusTree::<i64>::open(&mut storage, &new).check().unwrap(),
+        300
+    );
+
+    let ids = run(
+        &mut storage,
+        "SELECT id FROM t WHERE id BETWEEN 100 AND 104;",
+    )
+    .unwrap();
+    assert_eq!(ids.len(), 5);
+
+    let before = storage.buffer_pool.pagefile.free_page_count();
+    storage
+        .insert_row(
+            "T",
+            &["ID".to_string(), "NAME".to_string()],
+            vec![Value::Int(1000), Value::String("name1000".into())],
+        )
+        .unwrap();
+    let after = storage.buffer_pool.pagefile.free_page_count();
+    assert!(after <= before);
+    assert_eq!(
+        run(&mut storage, "SELECT id FROM t WHERE id = 1000;")
+            .unwrap()
+            .len(),
+        1
+    );
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_reindex_hash_index_and_errors() {
+    let path = "test_reindex_hash.db";
+    let mut storage = table_with_rows(path, 50);
+    storage
+        .create_index_using("T", &["ID".to_string()], "T_H", IndexKind::Hash)
+        .unwrap();
+    let rows = run(&mut storage, "REINDEX t_h ON t;").unwrap();
+    assert!(matches!(rows[0][0], Value::Int(50)));
+    assert_eq!(
+        run(&mut storage, "SELECT id FROM t WHERE id = 7;")
+            .unwrap()
+            .len(),
+        1
+    );
+
+    assert!(run(&mut storage, "REINDEX missing ON t;").is_err());
+    assert!(run(&mut storage, "REINDEX t_h ON nope;").is_err());
+    remove_file(path).unwrap();
+}
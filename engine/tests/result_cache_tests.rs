@@ -0,0 +1,59 @@
+use bytes::Bytes;
+use engine::net::result_cache::{ResultCache, key};
+
+fn select(n: usize) -> String {
+    key(&format!("SELECT id FROM t{};", n), "json").unwrap()
+}
+
+#[test]
+fn test_result_cache_keys_ignore_spacing_case_and_comments() {
+    let plain = key("SELECT name FROM t WHERE name = 'Ab';", "json").unwrap();
+    assert_eq!(
+        key("select name\n  from T -- who\nwhere name = 'Ab'", "json"),
+        Some(plain.clone())
+    );
+    assert_ne!(
+        key("SELECT name FROM t WHERE name = 'ab';", "json"),
+        Some(plain.clone())
+    );
+    assert_ne!(
+        key("SELECT name FROM t WHERE name = 'Ab';", "text"),
+        Some(plain)
+    );
+    assert_eq!(key("INSERT INTO t (id) VALUES (1);", "json"), None);
+    assert_eq!(key("select_count FROM t", "json"), None);
+}
+
+#[test]
+fn test_result_cache_evicts_the_least_recently_used_and_stale_entries() {
+    let cache = ResultCache::new(30);
+    let body = Bytes::from_static(b"0123456789");
+    for n in 0..3 {
+        let seen = cache.versions(&[&format!("t{}", n)]);
+        cache.insert(select(n), seen, body.clone());
+    }
+    assert_eq!(cache.used(), 30);
+    assert!(cache.get(&select(0)).is_some());
+    let seen = cache.versions(&["t3"]);
+    cache.insert(select(3), seen, body.clone());
+    // t1 was used least recently.
+    assert!(cache.get(&select(1)).is_none());
+    assert!(cache.get(&select(0)).is_some());
+
+    // A result computed before a commit to its table is not kept.
+    let seen = cache.versions(&["t1"]);
+    cache.invalidate("T1");
+    cache.insert(select(1), seen, body.clone());
+    assert!(cache.get(&select(1)).is_none());
+    cache.invalidate("t0");
+    assert!(cache.get(&select(0)).is_none());
+    assert_eq!(cache.used(), 20);
+
+    let seen = cache.versions(&["t0"]);
+    cache.clear();
+    cache.insert(select(0), seen, body.clone());
+    assert_eq!(cache.used(), 0);
+    cache.insert(select(0), cache.versions(&["t0"]), Bytes::from(vec![0; 31]));
+    assert_eq!(cache.used(), 0);
+    assert_eq!((cache.hits(), cache.misses()), (2, 3));
+}
@@ -0,0 +1,99 @@
+use engine::index::bplustree::BPlusTree;
+use engine::query::binder::Value;
+use engine::storage::atomic_file::atomic_remove;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use engine::testing::run;
+use engine::tx::log_manager::{LogManager, Manifest, segment_path};
+use engine::tx::recovery_manager::abort_transaction;
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+fn ids(storage: &mut Storage) -> Vec<i64> {
+    let mut ids: Vec<i64> = storage
+        .scan_table("T")
+        .unwrap()
+        .into_iter()
+        .map(|r| match r[0] {
+            Value::Int(i) => i,
+            _ => panic!("expected int"),
+        })
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_failed_multi_row_insert_is_rolled_back() {
+    let (db, wal_path) = ("test_rollback_insert.db", "test_rollback_insert.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = Storage::new(db, 4096, 64).unwrap();
+    storage.attach_wal(wal.clone());
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    storage.create_index("T", "ID", "T_ID", Some(4)).unwrap();
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    run(&mut storage, "INSERT INTO t (id) VALUES (1), (2), (3);").unwrap();
+    wal.log_commit(1).unwrap();
+
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    let err = run(
+        &mut storage,
+        "INSERT INTO t (id) VALUES (10), (11), (2), (12);",
+    );
+    assert!(err.is_err());
+    assert_eq!(ids(&mut storage), vec![1, 2, 2, 3, 10, 11]);
+    let undone = abort_transaction(&mut storage, &wal, 2).unwrap();
+    assert!(undone > 0);
+
+    assert_eq!(ids(&mut storage), vec![1, 2, 3]);
+    let info = storage.get_indexes("T").remove(0);
+    let mut tree = BPlusTree::<i64>::open(&mut storage, &info);
+    assert_eq!(tree.check().unwrap(), 3);
+    assert_eq!(tree.get(10).unwrap(), None);
+    assert!(
+        run(&mut storage, "SELECT id FROM t WHERE id = 11;")
+            .unwrap()
+            .is_empty()
+    );
+
+    storage.set_transaction(Some(3));
+    wal.log_begin(3).unwrap();
+    run(&mut storage, "INSERT INTO t (id) VALUES (10), (11);").unwrap();
+    wal.log_commit(3).unwrap();
+    assert_eq!(ids(&mut storage), vec![1, 2, 3, 10, 11]);
+    remove_file(db).unwrap();
+    remove_file(segment_path(Path::new(wal_path), 1)).unwrap();
+    atomic_remove(&Manifest::path(Path::new(wal_path))).unwrap();
+}
+
+#[test]
+fn test_multi_row_insert_arity_is_checked() {
+    let path = "test_rollback_arity.db";
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    let err = run(&mut storage, "INSERT INTO t (id) VALUES (1), (2, 3);").unwrap_err();
+    assert!(err.to_string().contains("row 2"), "{}", err);
+    assert!(ids(&mut storage).is_empty());
+    remove_file(path).unwrap();
+}
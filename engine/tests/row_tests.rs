@@ -0,0 +1,173 @@
+use engine::query::binder::Value;
+use engine::storage::record::Page;
+use engine::storage::storage::{
+    Collation, ColumnInfo, DEFAULT_MAX_COLUMNS, DataType, Storage, decode_column, decode_columns,
+    decode_row, encode_values,
+};
+use engine::tx::mvcc::ROW_HEADER_SIZE;
+use std::fs::remove_file;
+
+fn stored(values: &[Value]) -> Vec<u8> {
+    let mut data = vec![0; ROW_HEADER_SIZE];
+    encode_values(values, &mut data);
+    data
+}
+
+#[test]
+fn test_columns_decode_as_the_whole_row_does() {
+    let row = vec![
+        Value::Int(i64::MIN),
+        Value::from(""),
+        Value::Int(0),
+        Value::from("naïve ☃"),
+        Value::Int(i64::MAX),
+        Value::from("x".repeat(300).as_str()),
+    ];
+    let data = stored(&row);
+    let whole = decode_row(&data).unwrap();
+    assert_eq!(whole, row);
+    for (ordinal, value) in whole.iter().enumerate() {
+        assert_eq!(&decode_column(&data, ordinal).unwrap(), value);
+    }
+    assert_eq!(
+        decode_columns(&data, &[5, 0, 3, 0]).unwrap(),
+        vec![
+            whole[5].clone(),
+            whole[0].clone(),
+            whole[3].clone(),
+            whole[0].clone()
+        ]
+    );
+    assert!(decode_columns(&data, &[]).unwrap().is_empty());
+
+    assert!(decode_column(&data, 6).is_err());
+    // Only the bytes up to the value asked for have to be there.
+    let cut = &data[..data.len() - 1];
+    assert_eq!(decode_column(cut, 4).unwrap(), Value::Int(i64::MAX));
+    assert!(decode_column(cut, 5).is_err());
+    assert!(decode_column(&data[..ROW_HEADER_SIZE + 2], 0).is_err());
+}
+
+#[test]
+fn test_storage_fetches_columns_of_a_row() {
+    let path = "test_row_fetch_columns.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    let columns = vec![
+        ColumnInfo {
+            name: "ID".into(),
+            data_type: DataType::Int,
+            collation: Collation::Binary,
+        },
+        ColumnInfo {
+            name: "NAME".into(),
+            data_type: DataType::String,
+            collation: Collation::Binary,
+        },
+    ];
+    storage.create_table("T".into(), columns).unwrap();
+    let names = vec!["ID".to_string(), "NAME".to_string()];
+    let rid = storage
+        .insert_row("T", &names, vec![Value::Int(7), Value::from("seven")])
+        .unwrap();
+    let table = storage.catalog.get_table("T").unwrap().clone();
+
+    let whole = decode_row(&storage.fetch(rid).unwrap()).unwrap();
+    assert_eq!(storage.fetch_column(rid, &table, 0).unwrap(), whole[0]);
+    assert_eq!(storage.fetch_column(rid, &table, 1).unwrap(), whole[1]);
+    assert_eq!(
+        storage.fetch_columns(rid, &table, &[1, 0]).unwrap(),
+        vec![Value::from("seven"), Value::Int(7)]
+    );
+    let err = storage.fetch_column(rid, &table, 2).unwrap_err();
+    assert!(err.to_string().contains("not a column 2"), "{}", err);
+    assert!(storage.fetch_column((rid.0, rid.1 + 1), &table, 0).is_err());
+    remove_file(path).unwrap();
+}
+
+fn int_columns(count: usize) -> Vec<ColumnInfo> {
+    (0..count)
+        .map(|i| ColumnInfo {
+            name: format!("C{}", i),
+            data_type: DataType::Int,
+            collation: Collation::Binary,
+        })
+        .collect()
+}
+
+#[test]
+fn test_create_table_takes_at_most_max_columns() {
+    let path = "test_row_max_columns.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    storage
+        .create_table("WIDEST".into(), int_columns(DEFAULT_MAX_COLUMNS))
+        .unwrap();
+    let err = storage
+        .create_table("TOO_WIDE".into(), int_columns(DEFAULT_MAX_COLUMNS + 1))
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("at most 1024 columns, not 1025"),
+        "{}",
+        err
+    );
+    assert!(storage.catalog.get_table("TOO_WIDE").is_err());
+
+    storage.max_columns = 3;
+    storage.create_table("T3".into(), int_columns(3)).unwrap();
+    let err = storage
+        .create_table("T4".into(), int_columns(4))
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("at most 3 columns, not 4"),
+        "{}",
+        err
+    );
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_rows_are_limited_to_what_a_page_holds() {
+    let path = "test_row_max_size.db";
+    let mut storage = Storage::new(path, 4096, 64).unwrap();
+    let columns = vec![ColumnInfo {
+        name: "BODY".into(),
+        data_type: DataType::String,
+        collation: Collation::Binary,
+    }];
+    storage.create_table("T".into(), columns).unwrap();
+    let names = vec!["BODY".to_string()];
+    // The row header, the value count and the value's tag and length come
+    // to 25 bytes, leaving 4047 of the page's 4072 for the text.
+    let fits = "x".repeat(4047);
+    let rid = storage
+        .insert_row("T", &names, vec![Value::from(fits.as_str())])
+        .unwrap();
+    assert_eq!(
+        decode_row(&storage.fetch(rid).unwrap()).unwrap(),
+        vec![Value::from(fits.as_str())]
+    );
+    let err = storage
+        .insert_row("T", &names, vec![Value::from("x".repeat(4048).as_str())])
+        .unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.contains("4073 bytes is over the 4072-byte limit"),
+        "{}",
+        message
+    );
+    assert!(message.contains("overflow pages"), "{}", message);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_page_refuses_a_tuple_past_its_slot_limit() {
+    assert_eq!(Page::max_tuple_size(4096), 4072);
+    let mut page = Page::new(0, 4096);
+    assert!(page.insert_tuple(&[7; 4073]).is_err());
+    assert_eq!(page.slot_count(), 0);
+    let (_, slot) = page.insert_tuple(&[7; 4072]).unwrap();
+    assert_eq!(page.get_tuple(slot).unwrap(), &[7; 4072][..]);
+    assert_eq!(page.free_space(), 0);
+    assert!(page.insert_tuple(&[1]).is_err());
+    assert_eq!(page.slot_count(), 1);
+    assert!(Storage::new("test_row_huge_page.db", 1 << 16, 4).is_err());
+}
@@ -0,0 +1,115 @@
+use engine::query::binder::{Binder, Catalog, Value};
+use engine::query::executor::{Executor, Tuple, build_operator};
+use engine::query::optimizer::Optimizer;
+use engine::query::parser::Parser;
+use engine::query::physical_planner::{PhysicalPlan, PhysicalPlanner};
+use engine::query::pipeline::run_ddl;
+use engine::query::planner::Planner;
+use engine::storage::storage::{SchemaChanged, Storage};
+use std::fs::remove_file;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+// Binds and plans without running, as a prepared statement would.
+fn prepare(storage: &Storage, sql: &str) -> PhysicalPlan {
+    let stmt = Parser::new(sql).unwrap().parse_statement().unwrap();
+    let mut catalog = Catalog::from_storage(&storage.catalog);
+    let bound = Binder::shared(&mut catalog, storage).bind(stmt).unwrap();
+    let logical = Planner::new(&catalog.tables).plan(bound).unwrap();
+    let optimized = Optimizer::optimize(logical).unwrap();
+    PhysicalPlanner::new(&catalog, storage)
+        .create_physical_plan(optimized)
+        .unwrap()
+}
+
+fn run(storage: &mut Storage, plan: PhysicalPlan) -> anyhow::Result<Vec<Tuple>> {
+    Executor::new(build_operator(plan, storage)?).execute()
+}
+
+fn ddl(storage: &mut Storage, sql: &str) {
+    let stmt = Parser::new(sql).unwrap().parse_statement().unwrap();
+    run_ddl(storage, &stmt, None).unwrap().unwrap();
+}
+
+fn setup(path: &str) -> Storage {
+    let _ = remove_file(path);
+    let mut storage = Storage::new(path, 4096, 16).unwrap();
+    ddl(&mut storage, "CREATE TABLE T (ID INT, NAME VARCHAR);");
+    let names = vec!["ID".to_string(), "NAME".to_string()];
+    for id in 0..10 {
+        let row = vec![Value::Int(id), Value::String(format!("n{}", id))];
+        storage.insert_row("T", &names, row).unwrap();
+    }
+    storage
+}
+
+fn changed_table(result: anyhow::Result<Vec<Tuple>>) -> String {
+    let e = result.expect_err("a stale plan ran");
+    e.downcast_ref::<SchemaChanged>()
+        .unwrap_or_else(|| panic!("not a schema change: {:#}", e))
+        .table
+        .clone()
+}
+
+#[test]
+fn test_alter_table_between_planning_and_running_is_reported() {
+    let path = "test_schema_change.db";
+    let storage = Arc::new(RwLock::new(setup(path)));
+
+    // The reader plans under a read lock and lets it go; the ALTER gets the
+    // write lock before the reader takes it back to run the plan.
+    let (planned, wait_planned) = channel();
+    let (altered, wait_altered) = channel();
+    let reader = {
+        let storage = storage.clone();
+        thread::spawn(move || {
+            let plan = prepare(&storage.read().unwrap(), "SELECT NAME FROM T WHERE ID = 3;");
+            planned.send(()).unwrap();
+            wait_altered.recv().unwrap();
+            run(&mut storage.write().unwrap(), plan)
+        })
+    };
+    wait_planned.recv().unwrap();
+    ddl(
+        &mut storage.write().unwrap(),
+        "ALTER TABLE T ADD CHECK (ID >= 0);",
+    );
+    altered.send(()).unwrap();
+    assert_eq!(changed_table(reader.join().unwrap()), "T");
+
+    // Planned again, it sees the table as it is now.
+    let mut storage = storage.write().unwrap();
+    let plan = prepare(&storage, "SELECT NAME FROM T WHERE ID = 3;");
+    let rows = run(&mut storage, plan).unwrap();
+    assert_eq!(rows, vec![vec![Value::String("n3".into())]]);
+    drop(storage);
+    let _ = remove_file(path);
+}
+
+#[test]
+fn test_plans_are_stale_after_any_change_to_their_table() {
+    let path = "test_schema_change_kinds.db";
+    let mut storage = setup(path);
+
+    let plan = prepare(&storage, "SELECT ID FROM T;");
+    ddl(&mut storage, "CREATE INDEX T_ID ON T (ID);");
+    assert_eq!(changed_table(run(&mut storage, plan)), "T");
+
+    let plan = prepare(&storage, "INSERT INTO T (ID, NAME) VALUES (10, 'n10');");
+    ddl(&mut storage, "ALTER INDEX T_ID RENAME TO T_KEY;");
+    assert_eq!(changed_table(run(&mut storage, plan)), "T");
+
+    // A table dropped and made again under the same name is another table.
+    let plan = prepare(&storage, "SELECT ID FROM T WHERE ID = 1;");
+    ddl(&mut storage, "ALTER TABLE T RENAME TO OLD_T;");
+    ddl(&mut storage, "CREATE TABLE T (NAME VARCHAR, ID INT);");
+    assert_eq!(changed_table(run(&mut storage, plan)), "T");
+
+    // A change to another table leaves the plan alone.
+    let plan = prepare(&storage, "SELECT ID FROM OLD_T WHERE ID = 1;");
+    ddl(&mut storage, "CREATE INDEX T_NAME ON T (ID);");
+    assert_eq!(run(&mut storage, plan).unwrap(), vec![vec![Value::Int(1)]]);
+    drop(storage);
+    let _ = remove_file(path);
+}
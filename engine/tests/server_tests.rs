@@ -0,0 +1,3943 @@
+use engine::cli::completion::SchemaCache;
+use engine::cli::import::{ImportState, import_file};
+use engine::database::Database;
+use engine::net::admission::WhenBusy;
+use engine::net::auth::{Secret, UserStore};
+use engine::net::binary;
+use engine::net::client::{DbError, DbValue, QueryResult, QueryStats, RetryPolicy, SqlClient};
+use engine::net::copy::CopyReport;
+use engine::net::csv_io::{CsvOptions, ImportProgress, OnError, RowError};
+use engine::net::queries::QueryState;
+use engine::net::replication::StandbyConfig;
+use engine::net::row::ColumnType;
+use engine::net::schema::{ColumnSchema, TableSchema, TableSummary};
+use engine::net::server::{
+    LOCK_WAIT_HEADER, PAGES_CACHED_HEADER, PAGES_READ_HEADER, QUERY_MILLIS_HEADER, ROWS_HEADER,
+    SEARCH_PATH_HEADER, ServerConfig, TX_ID_HEADER, TX_STATUS_HEADER, serve_until,
+};
+use engine::net::session::TxState;
+use engine::net::settings::ConfigFile;
+use engine::query::binder::Value as EngineValue;
+use engine::query::parser::Parser;
+use engine::storage::atomic_file::atomic_remove;
+use engine::storage::backup::catalog_path;
+use engine::storage::storage::Storage;
+use engine::tx::log_manager::{
+    CheckpointPayload, LogRecordType, Manifest, MasterRecord, segment_path,
+};
+use engine::tx::recovery_manager::inspect_log;
+use engine::tx::wal_reader::WalReader;
+use flate2::read::GzDecoder;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::{Client, StatusCode};
+use serde_json::{Value, json};
+use std::fs::remove_file;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{Message, client::IntoClientRequest};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+struct TestServer {
+    url: String,
+    client: Client,
+    handle: JoinHandle<anyhow::Result<()>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    db: &'static str,
+    wal: &'static str,
+}
+
+impl TestServer {
+    async fn start(db: &'static str, wal: &'static str) -> Self {
+        Self::start_with(db, wal, ServerConfig::default()).await
+    }
+
+    async fn start_with(db: &'static str, wal: &'static str, config: ServerConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let storage = Storage::new(db, 4096, 64).unwrap();
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let handle = tokio::spawn(serve_until(
+            listener,
+            storage,
+            PathBuf::from(wal),
+            ServerConfig {
+                admin_password: Some(Secret::from("password")),
+                ..config
+            },
+            async {
+                let _ = stop.await;
+            },
+        ));
+        let client = login(&url, "admin", "password").await.unwrap();
+        TestServer {
+            url,
+            client,
+            handle,
+            shutdown: Some(shutdown),
+            db,
+            wal,
+        }
+    }
+
+    async fn query(&self, sql: &str) -> (StatusCode, String) {
+        query_as(&self.client, &self.url, sql).await
+    }
+
+    async fn get(&self, path: &str) -> String {
+        self.client
+            .get(format!("{}{}", self.url, path))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    // Shuts the server down the way SIGTERM does and waits for it to finish.
+    async fn shut_down(&mut self) -> anyhow::Result<()> {
+        self.shutdown.take().unwrap().send(()).unwrap();
+        (&mut self.handle).await.unwrap()
+    }
+
+    fn stop(self) {
+        self.handle.abort();
+        remove_file(self.db).unwrap();
+        let path = Path::new(self.wal);
+        let manifest = Manifest::read(path).unwrap().unwrap();
+        for segment in manifest.segments() {
+            remove_file(segment_path(path, segment)).unwrap();
+        }
+        atomic_remove(&Manifest::path(path)).unwrap();
+        atomic_remove(&UserStore::path(path)).unwrap();
+        atomic_remove(&MasterRecord::path(path)).unwrap();
+        atomic_remove(&catalog_path(path)).unwrap();
+    }
+}
+
+// Logs in as `user` with a client of its own, or returns the status the
+// login was refused with.
+async fn login(url: &str, user: &str, pass: &str) -> Result<Client, StatusCode> {
+    let client = Client::builder().cookie_store(true).build().unwrap();
+    let resp = client
+        .post(format!("{}/login", url))
+        .json(&serde_json::json!({ "user": user, "pass": pass }))
+        .send()
+        .await
+        .unwrap();
+    match resp.status() {
+        StatusCode::OK => Ok(client),
+        status => Err(status),
+    }
+}
+
+async fn query_as(client: &Client, url: &str, sql: &str) -> (StatusCode, String) {
+    let request = client
+        .post(format!("{}/query", url))
+        .json(&serde_json::json!({ "sql": sql }))
+        .send();
+    let resp = tokio::time::timeout(Duration::from_secs(5), request)
+        .await
+        .unwrap_or_else(|_| panic!("{:?} did not finish", sql))
+        .unwrap();
+    (resp.status(), resp.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn test_failed_statements_roll_back_and_release_locks() {
+    let server = TestServer::start("test_server_errors.db", "test_server_errors.wal").await;
+    let (status, _) = server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Failing DDL used to panic the connection with the table lock held.
+    let (status, body) = server.query("CREATE TABLE t (id INT);").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("already exists"), "{}", body);
+    let (status, body) = server.query("CREATE INDEX t_x ON t (x);").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("CREATE INDEX failed"), "{}", body);
+
+    // The first row goes in before the second, too big for any page, fails.
+    let huge = "x".repeat(8192);
+    let sql = format!("INSERT INTO t (id, name) VALUES (1, 'a'), (2, '{}');", huge);
+    let (status, _) = server.query(&sql).await;
+    assert!(status.is_server_error() || status.is_client_error());
+
+    let (status, body) = server.query("SELECT id FROM t;").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"idle"}"#
+    );
+    let (status, _) = server
+        .query("INSERT INTO t (id, name) VALUES (1, 'a');")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = server.query("SELECT id, name FROM t;").await;
+    assert_eq!(
+        body,
+        r#"{"columns":["ID","NAME"],"rows":[[1,"a"]],"row_count":1,"tx_status":"idle"}"#
+    );
+    assert_eq!(server.get("/debug/locks").await, "[]");
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_user_accounts() {
+    let server = TestServer::start("test_server_users.db", "test_server_users.wal").await;
+    let url = server.url.clone();
+    assert_eq!(
+        login(&url, "admin", "wrong").await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(
+        login(&url, "alice", "s3cret").await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+
+    let (status, _) = server.query("CREATE USER alice PASSWORD 's3cret';").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = server.query("CREATE USER alice PASSWORD 'x';").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("already exists"), "{}", body);
+
+    let alice = login(&url, "alice", "s3cret").await.unwrap();
+    let (status, _) = query_as(&alice, &url, "CREATE TABLE t (id INT);").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = query_as(&alice, &url, "DROP USER admin;").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(body.contains("only an admin"), "{}", body);
+    let (status, body) = server.query("DROP USER admin;").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("only admin"), "{}", body);
+
+    // Only salted hashes are stored, and they survive a restart.
+    let path = UserStore::path(Path::new(server.wal));
+    let stored = String::from_utf8_lossy(&std::fs::read(&path).unwrap()).into_owned();
+    assert!(!stored.contains("s3cret") && !stored.contains("password"));
+    assert!(stored.contains("$argon2"));
+    let reopened = UserStore::open(path).unwrap();
+    let user = reopened
+        .authenticate("alice", &Secret::from("s3cret"))
+        .unwrap();
+    assert!(!user.admin);
+    assert!(!format!("{:?}", user).contains("argon2"));
+    // The statement is logged, so its password must not show up either.
+    let stmt = Parser::new("CREATE USER bob PASSWORD 'hunter2';")
+        .unwrap()
+        .parse_statement()
+        .unwrap();
+    assert!(!format!("{:?}", stmt).contains("hunter2"));
+
+    let (status, _) = server.query("DROP USER alice;").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = query_as(&alice, &url, "SELECT id FROM t;").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        login(&url, "alice", "s3cret").await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+    server.stop();
+}
+
+async fn raw_login(url: &str) -> String {
+    let resp = Client::new()
+        .post(format!("{}/login", url))
+        .json(&serde_json::json!({ "user": "admin", "pass": "password" }))
+        .send()
+        .await
+        .unwrap();
+    let cookie = resp.headers()["set-cookie"].to_str().unwrap();
+    let token = cookie.strip_prefix("session_token=").unwrap();
+    token[..token.find(';').unwrap()].to_string()
+}
+
+async fn query_with_cookie(url: &str, cookie: Option<&str>, sql: &str) -> (StatusCode, String) {
+    let mut request = Client::new()
+        .post(format!("{}/query", url))
+        .json(&serde_json::json!({ "sql": sql }));
+    if let Some(cookie) = cookie {
+        request = request.header("cookie", cookie);
+    }
+    let resp = request.send().await.unwrap();
+    (resp.status(), resp.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn test_session_tokens() {
+    let server = TestServer::start("test_server_tokens.db", "test_server_tokens.wal").await;
+    let url = server.url.clone();
+    let first = raw_login(&url).await;
+    let second = raw_login(&url).await;
+    assert_ne!(first, second);
+    assert_eq!(first.len(), 64);
+    let first = format!("session_token={}", first);
+    let second = format!("session_token={}", second);
+
+    let (status, body) = query_with_cookie(&url, None, "SHOW LOCKS;").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body, "Not logged in");
+    let (status, body) = query_with_cookie(&url, Some("session_token=guess"), "SHOW LOCKS;").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.starts_with("Invalid session token"), "{}", body);
+
+    let (status, _) = query_with_cookie(&url, Some(&first), "CREATE TABLE t (id INT);").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = query_with_cookie(&url, Some(&first), "BEGIN;").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = query_with_cookie(&url, Some(&first), "INSERT INTO t (id) VALUES (1);").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Logging out ends the token and rolls back its open transaction.
+    let resp = Client::new()
+        .post(format!("{}/logout", url))
+        .header("cookie", &first)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let (status, body) = query_with_cookie(&url, Some(&first), "SELECT id FROM t;").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.starts_with("Invalid session token"), "{}", body);
+    let (status, body) = query_with_cookie(&url, Some(&second), "SELECT id FROM t;").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"idle"}"#
+    );
+    assert_eq!(server.get("/debug/locks").await, "[]");
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_session_tokens_expire() {
+    let config = ServerConfig {
+        session_ttl: Some(Duration::from_millis(300)),
+        ..ServerConfig::default()
+    };
+    let server = TestServer::start_with("test_server_ttl.db", "test_server_ttl.wal", config).await;
+    let (status, _) = server.query("SHOW LOCKS;").await;
+    assert_eq!(status, StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let (status, body) = server.query("SHOW LOCKS;").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body, "Session expired, log in again");
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_typed_and_text_results() {
+    let server = TestServer::start("test_server_formats.db", "test_server_formats.wal").await;
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    server
+        .query("INSERT INTO t (id, name) VALUES (5, '5');")
+        .await;
+    let (_, body) = server.query("SELECT id, name FROM t;").await;
+    assert_eq!(
+        body,
+        r#"{"columns":["ID","NAME"],"rows":[[5,"5"]],"row_count":1,"tx_status":"idle"}"#
+    );
+
+    let select = |format: &str| {
+        server
+            .client
+            .post(format!("{}/query?format={}", server.url, format))
+            .json(&serde_json::json!({ "sql": "SELECT id, name FROM t;" }))
+            .send()
+    };
+    let resp = select("text").await.unwrap();
+    assert_eq!(
+        resp.text().await.unwrap(),
+        r#"{"columns":["ID","NAME"],"rows":[["5","5"]],"row_count":1,"tx_status":"idle"}"#
+    );
+    let resp = select("xml").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let result = client.query("SELECT id, name FROM t;").await.unwrap();
+    assert_eq!(result.rows, vec![vec![DbValue::Int(5), DbValue::from("5")]]);
+    let cells: Vec<String> = result.rows[0].iter().map(DbValue::to_string).collect();
+    assert_eq!(cells, ["5", "5"]);
+    assert_eq!(DbValue::Null.to_string(), "NULL");
+    let result = client.query("SELECT name, id FROM t;").await.unwrap();
+    assert_eq!(result.columns(), ["NAME", "ID"]);
+    assert_eq!(result.rows, vec![vec![DbValue::from("5"), DbValue::Int(5)]]);
+    assert_eq!(result.affected, None);
+    let schema = SchemaCache::load(&client).await.unwrap();
+    assert_eq!(schema.columns["T"], ["ID", "NAME"]);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_binary_results() {
+    let server = TestServer::start_with(
+        "test_server_binary.db",
+        "test_server_binary.wal",
+        ServerConfig {
+            result_cache_bytes: Some(1 << 20),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    let select = |sql: &'static str, accept: &'static str| {
+        server
+            .client
+            .post(format!("{}/query", server.url))
+            .header("accept", accept)
+            .json(&json!({ "sql": sql }))
+            .send()
+    };
+
+    let resp = select(
+        "INSERT INTO t (id, name) VALUES (1, 'one'), (-2, '');",
+        binary::CONTENT_TYPE,
+    )
+    .await
+    .unwrap();
+    let body = resp.bytes().await.unwrap();
+    let frame = binary::decode(&body).unwrap();
+    assert_eq!(
+        frame.trailer,
+        br#"{"row_count":0,"affected":2,"tx_status":"idle"}"#
+    );
+
+    let resp = select(
+        "SELECT id, name FROM t;",
+        "text/html, application/x-mydb-binary;q=0.9",
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.headers()["content-type"], binary::CONTENT_TYPE);
+    assert_eq!(resp.headers()["x-result-cache"], "miss");
+    let body = resp.bytes().await.unwrap();
+    let frame = binary::decode(&body).unwrap();
+    assert_eq!(frame.schema.names(), ["ID", "NAME"]);
+    assert_eq!(frame.schema.column_type(0), Some(ColumnType::Int));
+    assert_eq!(frame.schema.column_type(1), Some(ColumnType::Text));
+    assert_eq!(
+        frame.rows,
+        vec![
+            vec![DbValue::Int(1), DbValue::from("one")],
+            vec![DbValue::Int(-2), DbValue::from("")],
+        ]
+    );
+    assert_eq!(frame.trailer, br#"{"row_count":2,"tx_status":"idle"}"#);
+    // A body cut off anywhere before its trailer is an error.
+    let rows_end = body.len() - frame.trailer.len();
+    assert!(binary::decode(&body[..rows_end - 1]).is_err());
+
+    // The cache keeps the two formats apart.
+    let resp = select("SELECT id, name FROM t;", binary::CONTENT_TYPE)
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["x-result-cache"], "hit");
+    assert_eq!(resp.headers()["content-type"], binary::CONTENT_TYPE);
+    assert_eq!(resp.bytes().await.unwrap(), body);
+    let resp = select("SELECT id, name FROM t;", "application/json")
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["x-result-cache"], "miss");
+    assert_eq!(
+        resp.text().await.unwrap(),
+        r#"{"columns":["ID","NAME"],"rows":[[1,"one"],[-2,""]],"row_count":2,"tx_status":"idle"}"#
+    );
+
+    // The client asks for the binary format and gets the column types.
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let result = client.query("SELECT name, id FROM t;").await.unwrap();
+    assert_eq!(result.schema.column_type(0), Some(ColumnType::Text));
+    assert_eq!(result.rows[0].get::<i64>("id").unwrap(), 1);
+    let result = client
+        .query("INSERT INTO t (id, name) VALUES (3, 'three');")
+        .await
+        .unwrap();
+    assert_eq!(result.affected, Some(1));
+    let err = client.query("SELECT nope FROM t;").await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::Bind(_))
+    ));
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_client_reports_affected_rows_and_typed_errors() {
+    let server = TestServer::start("test_server_typed.db", "test_server_typed.wal").await;
+    let client = SqlClient::builder(&server.url)
+        .connect_timeout(Duration::from_secs(5))
+        .request_timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let err = client.query("SELECT 1;").await.unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::Unauthorized(_))
+        ),
+        "{:#}",
+        err
+    );
+    let err = client.login("admin", "wrong").await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::Unauthorized(_))
+    ));
+    client.login("admin", "password").await.unwrap();
+
+    client.query("CREATE TABLE t (id INT);").await.unwrap();
+    let result = client
+        .query("INSERT INTO t (id) VALUES (1), (2), (3);")
+        .await
+        .unwrap();
+    assert_eq!(
+        result,
+        QueryResult {
+            affected: Some(3),
+            ..QueryResult::default()
+        }
+    );
+    let (_, body) = server.query("INSERT INTO t (id) VALUES (4);").await;
+    assert_eq!(
+        body,
+        r#"{"columns":[],"rows":[],"row_count":0,"affected":1,"tx_status":"idle"}"#
+    );
+
+    let err = client.query("SELEC id FROM t;").await.unwrap_err();
+    match err.downcast_ref::<DbError>() {
+        Some(DbError::Parse { message, .. }) => assert!(message.starts_with("Parse error:")),
+        _ => panic!("unexpected error: {:#}", err),
+    }
+    // Every syntax error is listed, and nothing runs.
+    let (status, body) = server
+        .query("INSERT INTO t (id) VALUES (5);\nSELECT FROM t;\nSELECT id FROM t WHERE;")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let places: Vec<(u64, u64)> = body["diagnostics"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| (d["line"].as_u64().unwrap(), d["col"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(places, vec![(2, 8), (3, 23)]);
+    let err = client.query("SELECT id FROM t; SELECT id FROM t;").await.unwrap_err();
+    match err.downcast_ref::<DbError>() {
+        Some(DbError::Parse { diagnostics, .. }) => assert_eq!(
+            diagnostics[0].message,
+            "Expected a single statement, found another"
+        ),
+        _ => panic!("unexpected error: {:#}", err),
+    }
+    let err = client.query("SELECT nope FROM t;").await.unwrap_err();
+    match err.downcast_ref::<DbError>() {
+        Some(DbError::Bind(message)) => assert!(
+            message.ends_with("1 | SELECT nope FROM t;\n  |        ^^^^"),
+            "{}",
+            message
+        ),
+        _ => panic!("unexpected error: {:#}", err),
+    }
+
+    // A plain user may read but not manage users.
+    client
+        .query("CREATE USER reader PASSWORD 'pw';")
+        .await
+        .unwrap();
+    let reader = SqlClient::new(&server.url);
+    reader.login("reader", "pw").await.unwrap();
+    let err = reader
+        .query("CREATE USER other PASSWORD 'pw';")
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.downcast_ref::<DbError>(), Some(DbError::Forbidden(_))),
+        "{:#}",
+        err
+    );
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_client_retries_reads_until_the_server_is_up() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let client = SqlClient::builder(&url)
+        .retry(RetryPolicy {
+            max_retries: 2,
+            backoff: Duration::from_millis(10),
+        })
+        .build()
+        .unwrap();
+    // Nothing listens there, so every try fails to connect.
+    let started = std::time::Instant::now();
+    let err = client.tables().await.unwrap_err();
+    assert!(
+        err.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_connect())
+    );
+    assert!(started.elapsed() >= Duration::from_millis(30));
+}
+
+#[tokio::test]
+async fn test_large_results_stream_in_chunks() {
+    let server = TestServer::start("test_server_stream.db", "test_server_stream.wal").await;
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    let values: Vec<String> = (0..1000).map(|i| format!("({}, 'n{}')", i, i)).collect();
+    let sql = format!("INSERT INTO t (id, name) VALUES {};", values.join(", "));
+    let (status, _) = server.query(&sql).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let resp = server
+        .client
+        .post(format!("{}/query", server.url))
+        .json(&json!({ "sql": "SELECT id, name FROM t;" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["transfer-encoding"], "chunked");
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["rows"].as_array().unwrap().len(), 1000);
+    assert_eq!(body["row_count"], 1000);
+
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let mut rows = client
+        .query_stream("SELECT id, name FROM t;")
+        .await
+        .unwrap();
+    let mut ids = Vec::new();
+    while let Some(row) = rows.next_row().await.unwrap() {
+        ids.push(row.get::<i64>("id").unwrap());
+    }
+    ids.sort();
+    assert_eq!(ids, (0..1000).collect::<Vec<_>>());
+    assert_eq!(rows.columns(), ["ID", "NAME"]);
+
+    // The columns are there before any row is read, and a stream can be
+    // consumed as one.
+    let rows = client.query_stream("SELECT name FROM t;").await.unwrap();
+    assert_eq!(rows.columns(), ["NAME"]);
+    let names: Vec<_> = rows.collect().await;
+    assert_eq!(names.len(), 1000);
+    assert!(names.iter().all(|row| row.is_ok()));
+    let mut rows = client
+        .query_stream("INSERT INTO t (id, name) VALUES (2000, 'y'), (2001, 'z');")
+        .await
+        .unwrap();
+    assert!(rows.columns().is_empty());
+    assert_eq!(rows.next_row().await.unwrap(), None);
+    assert_eq!(rows.affected(), Some(2));
+    assert!(client.query_stream("SELECT nope FROM t;").await.is_err());
+    // The lock is free again once the stream has been read to the end.
+    let (status, _) = server
+        .query("INSERT INTO t (id, name) VALUES (1000, 'x');")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_responses_carry_what_the_statement_cost() {
+    let server = TestServer::start("test_server_stats.db", "test_server_stats.wal").await;
+    server.query("CREATE TABLE t (id INT);").await;
+    let values: Vec<String> = (0..1000).map(|i| format!("({})", i)).collect();
+    let sql = format!("INSERT INTO t (id) VALUES {};", values.join(", "));
+    assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+    server.query("CREATE TABLE u (name TEXT);").await;
+
+    let send = |sql: &str| {
+        server
+            .client
+            .post(format!("{}/query", server.url))
+            .json(&json!({ "sql": sql }))
+            .send()
+    };
+    let resp = send("SELECT id FROM t WHERE id < 10;").await.unwrap();
+    let header = |name| {
+        resp.headers()[name]
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap()
+    };
+    assert_eq!(header(ROWS_HEADER), 10);
+    assert!(header(PAGES_READ_HEADER) + header(PAGES_CACHED_HEADER) > 0);
+    assert!(header(QUERY_MILLIS_HEADER) < 60_000);
+    assert_eq!(header(LOCK_WAIT_HEADER), 0);
+    let resp = send("INSERT INTO t (id) VALUES (1000), (1001);")
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()[ROWS_HEADER], "2");
+    // A failed statement still says what it cost.
+    let huge = format!("INSERT INTO u (name) VALUES ('{}');", "x".repeat(8192));
+    let resp = send(&huge).await.unwrap();
+    assert!(!resp.status().is_success());
+    assert!(resp.headers().contains_key(QUERY_MILLIS_HEADER));
+    // Rows streamed in more than one chunk go out before the counts exist.
+    let resp = send("SELECT id FROM t;").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(!resp.headers().contains_key(ROWS_HEADER));
+
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let result = client
+        .query("SELECT id FROM t WHERE id < 3;")
+        .await
+        .unwrap();
+    let stats = result.stats.unwrap();
+    assert_eq!(stats.rows, 3);
+    assert!(stats.pages_read + stats.pages_cached > 0);
+    let rows = client
+        .query_stream("SELECT id FROM t WHERE id = 5;")
+        .await
+        .unwrap();
+    assert!(matches!(rows.stats(), Some(QueryStats { rows: 1, .. })));
+    let rows = client.query_stream("SELECT id FROM t;").await.unwrap();
+    assert_eq!(rows.stats(), None);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_pages_read_ahead_are_counted_once_read() {
+    let server = TestServer::start("test_server_read_ahead.db", "test_server_read_ahead.wal").await;
+    server.query("CREATE TABLE w (id INT, pad TEXT);").await;
+    // Twice the pages the pool holds, so a scan finds the first ones on disk.
+    for chunk in 0..10 {
+        let values: Vec<String> = (0..200)
+            .map(|i| format!("({}, '{}')", chunk * 200 + i, "x".repeat(200)))
+            .collect();
+        let sql = format!("INSERT INTO w (id, pad) VALUES {};", values.join(", "));
+        assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+    }
+    let pool = || async {
+        let metrics = server.get("/metrics").await;
+        let value = |name: &str| -> u64 {
+            let line = metrics
+                .lines()
+                .find(|l| l.starts_with(name) && l[name.len()..].starts_with(' '))
+                .unwrap_or_else(|| panic!("{} missing from:\n{}", name, metrics));
+            line[name.len() + 1..].parse().unwrap()
+        };
+        [
+            value("mydb_buffer_pool_hits_total"),
+            value("mydb_buffer_pool_misses_total"),
+            value("mydb_buffer_pool_prefetched_total"),
+            value("mydb_buffer_pool_prefetch_hits_total"),
+        ]
+    };
+
+    let before = pool().await;
+    let resp = server
+        .client
+        .post(format!("{}/query", server.url))
+        .json(&json!({ "sql": "SELECT COUNT(*) FROM w;" }))
+        .send()
+        .await
+        .unwrap();
+    let header = |name| {
+        resp.headers()[name]
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap()
+    };
+    let (read, cached) = (header(PAGES_READ_HEADER), header(PAGES_CACHED_HEADER));
+    let after = pool().await;
+    let [hits, misses, prefetched, prefetch_hits] = [0, 1, 2, 3].map(|i| after[i] - before[i]);
+    assert!(prefetch_hits > 0, "{:?} to {:?}", before, after);
+    // A page is read from disk once, ahead of the scan, and found in memory
+    // when the scan gets to it.
+    assert_eq!(read, misses + prefetched);
+    assert_eq!(cached, hits + prefetch_hits);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_shutdown_rolls_back_and_checkpoints() {
+    let mut server = TestServer::start("test_server_shutdown.db", "test_server_shutdown.wal").await;
+    let url = server.url.clone();
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    let (status, _) = server
+        .query("INSERT INTO t (id, name) VALUES (1, 'survivor');")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    // Another session is mid-transaction when the server goes down.
+    let other = login(&url, "admin", "password").await.unwrap();
+    query_as(&other, &url, "BEGIN;").await;
+    let (status, _) = query_as(
+        &other,
+        &url,
+        "INSERT INTO t (id, name) VALUES (2, 'uncommitted');",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    server.shut_down().await.unwrap();
+    assert!(Client::new().get(&url).send().await.is_err());
+
+    // The log ends in a checkpoint with nothing left to redo or undo.
+    let wal = Path::new(server.wal);
+    let mut reader = WalReader::open(wal).unwrap();
+    let mut last = None;
+    while let Some(record) = reader.next_record().unwrap() {
+        last = Some(record);
+    }
+    let last = last.unwrap();
+    assert_eq!(last.header.typ, LogRecordType::Checkpoint);
+    let checkpoint = CheckpointPayload::decode(&last.payload).unwrap();
+    assert!(checkpoint.active_txns.is_empty());
+    assert!(checkpoint.dirty_pages.is_empty());
+    let master = MasterRecord::read(wal).unwrap().unwrap();
+    assert_eq!(master.checkpoint_lsn, last.header.lsn);
+    assert!(!inspect_log(wal).unwrap().needs_recovery);
+
+    // Started again over the same files, the server has the table, with
+    // the committed row and not the open transaction's.
+    let (db, wal) = (server.db, server.wal);
+    drop(server);
+    let server = TestServer::start(db, wal).await;
+    let (status, body) = server.query("SELECT id, name FROM t;").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        r#"{"columns":["ID","NAME"],"rows":[[1,"survivor"]],"row_count":1,"tx_status":"idle"}"#
+    );
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_health_and_metrics() {
+    let config = ServerConfig {
+        metrics_require_login: true,
+        ..ServerConfig::default()
+    };
+    let server =
+        TestServer::start_with("test_server_metrics.db", "test_server_metrics.wal", config).await;
+    let anonymous = Client::new();
+    let resp = anonymous
+        .get(format!("{}/health", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let health: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(
+        health,
+        json!({
+            "status": "ok",
+            "role": "primary",
+            "mode": "read_write",
+            "storage": "ok",
+            "wal": "ok"
+        })
+    );
+    let resp = anonymous
+        .get(format!("{}/metrics", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    for sql in [
+        "CREATE TABLE m (id INT);",
+        "INSERT INTO m (id) VALUES (1);",
+        "INSERT INTO m (id) VALUES (2);",
+        "SELECT id FROM m;",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK);
+    }
+    let metrics = server.get("/metrics").await;
+    let value = |name: &str| -> f64 {
+        let line = metrics
+            .lines()
+            .find(|l| l.starts_with(name) && l[name.len()..].starts_with(' '))
+            .unwrap_or_else(|| panic!("{} missing from:\n{}", name, metrics));
+        line[name.len() + 1..].parse().unwrap()
+    };
+    assert_eq!(value(r#"mydb_queries_total{type="ddl"}"#), 1.0);
+    assert_eq!(value(r#"mydb_queries_total{type="insert"}"#), 2.0);
+    assert_eq!(value(r#"mydb_queries_total{type="select"}"#), 1.0);
+    assert_eq!(value("mydb_query_duration_seconds_count"), 4.0);
+    assert_eq!(
+        value(r#"mydb_query_duration_seconds_bucket{le="+Inf"}"#),
+        4.0
+    );
+    assert!(value("mydb_buffer_pool_hits_total") + value("mydb_buffer_pool_misses_total") > 0.0);
+    assert!(value("mydb_wal_bytes_written_total") > 0.0);
+    assert_eq!(value("mydb_active_transactions"), 0.0);
+    assert_eq!(value("mydb_lock_waits_total"), 0.0);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_checkpoint_and_flush_tables() {
+    let server =
+        TestServer::start("test_server_checkpoint.db", "test_server_checkpoint.wal").await;
+    server.query("CREATE TABLE t (id INT);").await;
+    server.query("CREATE INDEX t_id ON t (id);").await;
+    server.query("CREATE TABLE u (id INT);").await;
+    let values: Vec<String> = (0..500).map(|i| format!("({})", i)).collect();
+    let sql = format!("INSERT INTO t (id) VALUES {};", values.join(", "));
+    assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+
+    let report = |sql: &'static str| async {
+        let (status, body) = server.query(sql).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        (body["columns"].clone(), body["rows"][0].clone())
+    };
+    let (columns, row) = report("FLUSH TABLES t;").await;
+    assert_eq!(columns, json!(["pages", "bytes"]));
+    assert!(row[0].as_i64().unwrap() > 0, "{}", row);
+    assert_eq!(row[1].as_i64(), Some(row[0].as_i64().unwrap() * 4096));
+    assert_eq!(report("FLUSH TABLES t;").await.1, json!([0, 0]));
+    server.query("INSERT INTO u (id) VALUES (1);").await;
+    let (_, row) = report("CHECKPOINT;").await;
+    assert!(row[1].as_i64().unwrap() > 0, "{}", row);
+    let lsn = row[0].as_i64().unwrap();
+    let (columns, row) = report("CHECKPOINT;").await;
+    assert_eq!(columns, json!(["lsn", "pages", "bytes", "elapsed_ms"]));
+    assert!(row[0].as_i64().unwrap() > lsn);
+    assert_eq!((&row[1], &row[2]), (&json!(0), &json!(0)));
+
+    let metrics = server.get("/metrics").await;
+    let expected = format!("\nmydb_last_checkpoint_lsn {}\n", row[0]);
+    assert!(metrics.contains(&expected), "{}", metrics);
+    assert!(metrics.contains("\nmydb_last_checkpoint_duration_seconds "));
+
+    server.query("CREATE USER alice PASSWORD 's3cret';").await;
+    let alice = login(&server.url, "alice", "s3cret").await.unwrap();
+    for sql in ["CHECKPOINT;", "FLUSH TABLES;"] {
+        let (status, body) = query_as(&alice, &server.url, sql).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    }
+    let (status, body) = server.query("FLUSH TABLES nope;").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("Unknown table 'NOPE'"), "{}", body);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_delete_and_vacuum_report_dead_rows() {
+    let server = TestServer::start("test_server_vacuum.db", "test_server_vacuum.wal").await;
+    server.query("CREATE TABLE d (id INT);").await;
+    let values: Vec<String> = (0..100).map(|i| format!("({})", i)).collect();
+    let sql = format!("INSERT INTO d (id) VALUES {};", values.join(", "));
+    assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+
+    let (status, body) = server.query("DELETE FROM d WHERE id < 40;").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["rows"], json!([[40]]));
+    let metrics = server.get("/metrics").await;
+    assert!(metrics.contains("\nmydb_table_dead_rows{table=\"D\"} 40\n"), "{}", metrics);
+    assert!(metrics.contains("\nmydb_queries_total{type=\"delete\"} 1\n"), "{}", metrics);
+
+    let (status, body) = server.query("VACUUM d;").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["columns"], json!(["table", "rows", "pages", "skipped"]));
+    assert_eq!(body["rows"][0][1], json!(40));
+    let metrics = server.get("/metrics").await;
+    assert!(metrics.contains("\nmydb_table_dead_rows{table=\"D\"} 0\n"), "{}", metrics);
+
+    let (_, body) = server.query("SELECT id FROM d;").await;
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["rows"].as_array().unwrap().len(), 60);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_query_timeout_stops_and_rolls_back() {
+    let server = TestServer::start("test_server_timeout.db", "test_server_timeout.wal").await;
+    server.query("CREATE TABLE t (id INT);").await;
+    let values: Vec<String> = (0..5000).map(|i| format!("({})", i)).collect();
+    let sql = format!("INSERT INTO t (id) VALUES {};", values.join(", "));
+    assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+
+    let timed_query = |sql: &'static str, timeout_ms: u64| {
+        server
+            .client
+            .post(format!("{}/query", server.url))
+            .json(&json!({ "sql": sql, "timeout_ms": timeout_ms }))
+            .send()
+    };
+    // Matches nothing, so the whole table is scanned before any row is sent.
+    let resp = timed_query("SELECT id FROM t WHERE id < 0;", 1)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("timed out after"));
+    assert_eq!(body["timeout_ms"], 1);
+    assert!(body["elapsed_ms"].as_u64().is_some());
+
+    // Inside a transaction block the whole transaction goes.
+    server.query("BEGIN;").await;
+    server.query("INSERT INTO t (id) VALUES (99999);").await;
+    let resp = timed_query("SELECT id FROM t WHERE id < 0;", 1)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("rolled back"));
+    let resp = timed_query("SELECT id FROM t WHERE id > 5000;", 60_000)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(server.query("ROLLBACK;").await.0, StatusCode::OK);
+    let resp = timed_query("SELECT id FROM t WHERE id > 5000;", 60_000)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.text().await.unwrap(),
+        r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"idle"}"#
+    );
+    let resp = timed_query("SELECT id FROM t;", 0).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    server.stop();
+}
+
+// The status, the transaction state headers and the body of a statement.
+async fn tx_query(server: &TestServer, sql: &str) -> (StatusCode, String, Option<u64>, String) {
+    let resp = server
+        .client
+        .post(format!("{}/query", server.url))
+        .json(&json!({ "sql": sql }))
+        .send()
+        .await
+        .unwrap();
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .map(|v| v.to_str().unwrap().to_string())
+    };
+    let tx_status = header(TX_STATUS_HEADER).unwrap();
+    let tx_id = header(TX_ID_HEADER).map(|id| id.parse().unwrap());
+    (resp.status(), tx_status, tx_id, resp.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn test_transaction_status_follows_the_session() {
+    let server = TestServer::start("test_server_tx_status.db", "test_server_tx_status.wal").await;
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    let (status, tx_status, tx_id, _) = tx_query(&server, "SELECT id FROM t;").await;
+    assert_eq!(
+        (status, tx_status.as_str(), tx_id),
+        (StatusCode::OK, "idle", None)
+    );
+
+    let (status, tx_status, tx_id, _) = tx_query(&server, "BEGIN;").await;
+    assert_eq!((status, tx_status.as_str()), (StatusCode::OK, "open"));
+    let tx_id = tx_id.unwrap();
+    let (_, tx_status, id, body) =
+        tx_query(&server, "INSERT INTO t (id, name) VALUES (1, 'a');").await;
+    assert_eq!((tx_status.as_str(), id), ("open", Some(tx_id)));
+    assert_eq!(
+        body,
+        format!(
+            r#"{{"columns":[],"rows":[],"row_count":0,"affected":1,"tx_status":"open","tx_id":{}}}"#,
+            tx_id
+        )
+    );
+    // A statement refused before it runs leaves the transaction as it was.
+    let (status, tx_status, _, _) = tx_query(&server, "SELEC id FROM t;").await;
+    assert_eq!(
+        (status, tx_status.as_str()),
+        (StatusCode::BAD_REQUEST, "open")
+    );
+
+    // One that fails while it runs takes the transaction with it.
+    let huge = "x".repeat(8192);
+    let sql = format!("INSERT INTO t (id, name) VALUES (2, '{}');", huge);
+    let (status, tx_status, id, body) = tx_query(&server, &sql).await;
+    assert!(!status.is_success());
+    assert_eq!((tx_status.as_str(), id), ("aborted", Some(tx_id)));
+    assert!(body.contains("refused until ROLLBACK"), "{}", body);
+    for sql in ["SELECT id FROM t;", "COMMIT;", "BEGIN;"] {
+        let (status, tx_status, id, body) = tx_query(&server, sql).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", sql);
+        assert_eq!((tx_status.as_str(), id), ("aborted", Some(tx_id)));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "error": format!(
+                    "Transaction {} is aborted; statements are refused until ROLLBACK",
+                    tx_id
+                ),
+                "code": "TRANSACTION_ABORTED",
+                "tx_status": "aborted",
+                "tx_id": tx_id,
+            })
+        );
+    }
+    // So is a batch, rather than told it is inside a transaction block.
+    let resp = server
+        .client
+        .post(format!("{}/batch", server.url))
+        .json(&json!({ "statements": ["SELECT id FROM t;"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body = resp.text().await.unwrap();
+    assert!(body.contains("refused until ROLLBACK"), "{}", body);
+    let (status, tx_status, id, _) = tx_query(&server, "ROLLBACK;").await;
+    assert_eq!(
+        (status, tx_status.as_str(), id),
+        (StatusCode::OK, "idle", None)
+    );
+    let (_, _, _, body) = tx_query(&server, "SELECT id FROM t;").await;
+    assert_eq!(
+        body,
+        r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"idle"}"#
+    );
+    let (status, _, _, body) = tx_query(&server, "ROLLBACK;").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body, "No transaction in progress");
+
+    // A statement that fails once its first rows are out went out with the
+    // transaction open; the trailer says where it left it.
+    let values: Vec<String> = (0..300).map(|i| format!("({}, 'n')", i)).collect();
+    let fill = format!("INSERT INTO t (id, name) VALUES {};", values.join(", "));
+    assert_eq!(server.query(&fill).await.0, StatusCode::OK);
+    let failing = "SELECT 1 / (id - 299) FROM t;";
+    tx_query(&server, "BEGIN;").await;
+    let (status, tx_status, id, body) = tx_query(&server, failing).await;
+    assert_eq!((status, tx_status.as_str()), (StatusCode::OK, "open"));
+    assert!(body.contains("Division by zero"), "{}", body);
+    assert!(
+        body.ends_with(&format!(
+            r#","tx_status":"aborted","tx_id":{}}}"#,
+            id.unwrap()
+        )),
+        "{}",
+        body
+    );
+    tx_query(&server, "ROLLBACK;").await;
+
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    assert_eq!(client.tx_state(), TxState::Idle);
+    client.query("BEGIN;").await.unwrap();
+    let TxState::Open(tx_id) = client.tx_state() else {
+        panic!("{:?}", client.tx_state());
+    };
+    assert!(client.query(&sql).await.is_err());
+    assert_eq!(client.tx_state(), TxState::Aborted(tx_id));
+    let e = client.query("SELECT id FROM t;").await.unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<DbError>(),
+        Some(DbError::TransactionAborted(_))
+    ));
+    assert_eq!(client.tx_state(), TxState::Aborted(tx_id));
+    client.query("ROLLBACK;").await.unwrap();
+    assert_eq!(client.tx_state(), TxState::Idle);
+    client.query("BEGIN;").await.unwrap();
+    let TxState::Open(tx_id) = client.tx_state() else {
+        panic!("{:?}", client.tx_state());
+    };
+    assert!(client.query(failing).await.is_err());
+    assert_eq!(client.tx_state(), TxState::Aborted(tx_id));
+    client.query("ROLLBACK;").await.unwrap();
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_cursors_page_through_a_result() {
+    let server = TestServer::start("test_server_cursors.db", "test_server_cursors.wal").await;
+    server.query("CREATE TABLE t (id INT);").await;
+    let values: Vec<String> = (0..250).map(|i| format!("({})", 249 - i)).collect();
+    let sql = format!("INSERT INTO t (id) VALUES {};", values.join(", "));
+    assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+
+    let declare = "DECLARE CURSOR c FOR SELECT id FROM t ORDER BY id;";
+    assert_eq!(server.query(declare).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        server.query("FETCH 1 FROM c;").await.0,
+        StatusCode::BAD_REQUEST
+    );
+
+    server.query("BEGIN;").await;
+    let (status, body) = server.query(declare).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        body.starts_with(
+            r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"open","tx_id":"#
+        ),
+        "{}",
+        body
+    );
+    let (status, body) = server.query(declare).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("already exists"), "{}", body);
+    let random = "DECLARE CURSOR r FOR SELECT RANDOM() FROM t;";
+    assert_eq!(server.query(random).await.0, StatusCode::BAD_REQUEST);
+    // A row the transaction inserts before fetching is read like any other.
+    server.query("INSERT INTO t (id) VALUES (250);").await;
+
+    let fetch = |sql: &'static str| async {
+        let (status, body) = server.query(sql).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let ids: Vec<i64> = body["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row[0].as_i64().unwrap())
+            .collect();
+        ids
+    };
+    assert_eq!(fetch("FETCH 100 FROM c;").await, (0..100).collect::<Vec<_>>());
+    assert_eq!(fetch("FETCH 100 FROM c;").await, (100..200).collect::<Vec<_>>());
+    assert_eq!(fetch("FETCH 100 FROM c;").await, (200..251).collect::<Vec<_>>());
+    assert!(fetch("FETCH 100 FROM c;").await.is_empty());
+    assert!(fetch("FETCH FROM c;").await.is_empty());
+    assert_eq!(server.query("CLOSE c;").await.0, StatusCode::OK);
+    assert_eq!(
+        server.query("FETCH 1 FROM c;").await.0,
+        StatusCode::BAD_REQUEST
+    );
+
+    // A cursor goes with its transaction.
+    server.query(declare).await;
+    assert_eq!(fetch("FETCH 2 FROM c;").await, vec![0, 1]);
+    assert_eq!(server.query("COMMIT;").await.0, StatusCode::OK);
+    server.query("BEGIN;").await;
+    assert_eq!(
+        server.query("FETCH 1 FROM c;").await.0,
+        StatusCode::BAD_REQUEST
+    );
+    server.query("ROLLBACK;").await;
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_deferred_foreign_keys_fail_the_commit() {
+    let server = TestServer::start("test_server_deferred.db", "test_server_deferred.wal").await;
+    server.query("CREATE TABLE users (id INT);").await;
+    server
+        .query("CREATE TABLE orders (id INT, user_id INT REFERENCES users(id));")
+        .await;
+    let deferred = "SET CONSTRAINTS ALL DEFERRED;";
+    assert_eq!(server.query(deferred).await.0, StatusCode::BAD_REQUEST);
+
+    server.query("BEGIN;").await;
+    assert_eq!(server.query(deferred).await.0, StatusCode::OK);
+    let (status, body) = server
+        .query("INSERT INTO orders (id, user_id) VALUES (1, 1), (2, 2);")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    server.query("INSERT INTO users (id) VALUES (1);").await;
+    let (status, body) = server.query("COMMIT;").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["code"], "FOREIGN_KEY_VIOLATION");
+    assert_eq!(body["constraint"], "ORDERS_USER_ID_FKEY");
+    assert!(body["error"].as_str().unwrap().contains("has no ID 2"));
+
+    // Nothing of it stayed, and the session is out of its transaction.
+    let (status, body) = server.query("SELECT id FROM orders;").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body.contains(r#""row_count":0"#), "{}", body);
+    assert_eq!(server.query("COMMIT;").await.0, StatusCode::BAD_REQUEST);
+
+    // A parent inserted later in the transaction is found at COMMIT.
+    server.query("BEGIN;").await;
+    server.query(deferred).await;
+    server.query("INSERT INTO orders (id, user_id) VALUES (3, 3);").await;
+    server.query("INSERT INTO users (id) VALUES (3);").await;
+    assert_eq!(server.query("COMMIT;").await.0, StatusCode::OK);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_request_body_limit() {
+    let config = ServerConfig {
+        max_body_bytes: Some(1024),
+        ..ServerConfig::default()
+    };
+    let server =
+        TestServer::start_with("test_server_body.db", "test_server_body.wal", config).await;
+    let long_sql = format!("SELECT id FROM t WHERE id = {};", "1".repeat(2000));
+    let (status, body) = server.query(&long_sql).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["limit_bytes"], 1024);
+
+    // Without a Content-Length the body is cut off once it passes the limit.
+    let mut stream = TcpStream::connect(server.url.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    let chunk = "x".repeat(2000);
+    let request = format!(
+        "POST /login HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+        chunk.len(),
+        chunk
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = vec![0; 64];
+    let n = stream.read(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response[..n]).starts_with("HTTP/1.1 413"));
+
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let err = client.query(&long_sql).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<DbError>(),
+        Some(&DbError::BodyTooLarge {
+            limit_bytes: Some(1024)
+        })
+    );
+    assert!(client.query("SHOW LOCKS;").await.is_ok());
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_reads_run_alongside_each_other() {
+    let server = TestServer::start("test_server_readers.db", "test_server_readers.wal").await;
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    let pad = "x".repeat(500);
+    for batch in 0..10 {
+        let values: Vec<String> = (batch * 1000..(batch + 1) * 1000)
+            .map(|i| format!("({}, '{}')", i, pad))
+            .collect();
+        let sql = format!("INSERT INTO t (id, name) VALUES {};", values.join(", "));
+        assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+    }
+
+    // A client that stops reading leaves its SELECT stuck partway through,
+    // still holding the storage lock.
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let addr = server.url.trim_start_matches("http://").parse().unwrap();
+    let mut stalled = socket.connect(addr).await.unwrap();
+    let body = json!({ "sql": "SELECT id, name FROM t;" }).to_string();
+    let request = format!(
+        "POST /query HTTP/1.1\r\nHost: localhost\r\nCookie: session_token={}\r\nContent-Length: {}\r\n\r\n{}",
+        raw_login(&server.url).await,
+        body.len(),
+        body
+    );
+    stalled.write_all(request.as_bytes()).await.unwrap();
+    let mut status_line = [0; 12];
+    stalled.read_exact(&mut status_line).await.unwrap();
+    assert_eq!(&status_line, b"HTTP/1.1 200");
+
+    // Other reads still get through while it is running.
+    let started = std::time::Instant::now();
+    for _ in 0..2 {
+        let (status, body) = server.query("SELECT id FROM t WHERE id = 9999;").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"{"columns":["ID"],"rows":[[9999]],"row_count":1,"tx_status":"idle"}"#
+        );
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let metrics = server.get("/metrics").await;
+    assert!(
+        metrics.contains("\nmydb_active_transactions 1\n"),
+        "{}",
+        metrics
+    );
+    drop(stalled);
+
+    // Reads still only see what was committed before they started.
+    let other = login(&server.url, "admin", "password").await.unwrap();
+    query_as(&other, &server.url, "BEGIN;").await;
+    let (status, _) = query_as(
+        &other,
+        &server.url,
+        "INSERT INTO t (id, name) VALUES (10000, 'new');",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let select = "SELECT id FROM t WHERE id = 10000;";
+    assert_eq!(
+        server.query(select).await.1,
+        r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"idle"}"#
+    );
+    let (_, body) = query_as(&other, &server.url, select).await;
+    assert!(
+        body.starts_with(r#"{"columns":["ID"],"rows":[[10000]],"row_count":1,"tx_status":"open""#),
+        "{}",
+        body
+    );
+    query_as(&other, &server.url, "COMMIT;").await;
+    assert_eq!(
+        server.query(select).await.1,
+        r#"{"columns":["ID"],"rows":[[10000]],"row_count":1,"tx_status":"idle"}"#
+    );
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_batch_commits_or_rolls_back_as_one() {
+    let server = TestServer::start("test_server_batch.db", "test_server_batch.wal").await;
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let results = client
+        .batch(&[
+            "CREATE TABLE a (id INT, name TEXT);",
+            "INSERT INTO a (id, name) VALUES (1, 'x'), (2, 'y');",
+            "SELECT id FROM a;",
+        ])
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[1].affected, Some(2));
+    assert_eq!(
+        results[2].rows,
+        vec![vec![DbValue::Int(1)], vec![DbValue::Int(2)]]
+    );
+    let results = client
+        .batch(&["SELECT name, id FROM a WHERE id = 2;"])
+        .await
+        .unwrap();
+    assert_eq!(results[0].columns(), ["NAME", "ID"]);
+    assert_eq!(
+        results[0].rows,
+        vec![vec![DbValue::from("y"), DbValue::Int(2)]]
+    );
+
+    // Everything before the failing statement is undone, DDL included.
+    let err = client
+        .batch(&[
+            "CREATE TABLE b (id INT);",
+            "INSERT INTO a (id, name) VALUES (3, 'z');",
+            "INSERT INTO b (id) VALUES (1);",
+            "INSERT INTO missing (id) VALUES (1);",
+        ])
+        .await
+        .unwrap_err();
+    match err.downcast_ref::<DbError>() {
+        Some(DbError::BatchFailed { index, .. }) => assert_eq!(*index, Some(3)),
+        _ => panic!("unexpected error: {:#}", err),
+    }
+    let (_, body) = server.query("SELECT id FROM a;").await;
+    assert_eq!(
+        body,
+        r#"{"columns":["ID"],"rows":[[1],[2]],"row_count":2,"tx_status":"idle"}"#
+    );
+    let (status, _) = server.query("SELECT id FROM b;").await;
+    assert!(!status.is_success());
+    assert_eq!(server.get("/debug/locks").await, "[]");
+
+    // A statement that does not parse stops the batch before anything runs.
+    let resp = server
+        .client
+        .post(format!("{}/batch", server.url))
+        .json(&json!({ "statements": ["INSERT INTO a (id, name) VALUES (4, 'w');", "SELEC"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let report: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(report["status"], "rolled_back");
+    assert_eq!(report["failed_index"], 1);
+    let (_, body) = server.query("SELECT id FROM a;").await;
+    assert_eq!(
+        body,
+        r#"{"columns":["ID"],"rows":[[1],[2]],"row_count":2,"tx_status":"idle"}"#
+    );
+    server.stop();
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn send_json(socket: &mut Socket, message: Value) {
+    socket
+        .send(Message::Text(message.to_string()))
+        .await
+        .unwrap();
+}
+
+// The next message from the server, as JSON.
+async fn next_json(socket: &mut Socket) -> Value {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no message from the server")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+// Runs `sql` as query `id` and collects its rows up to the closing message.
+async fn socket_query(socket: &mut Socket, id: u64, sql: &str) -> (Vec<Value>, Value) {
+    send_json(socket, json!({ "type": "query", "id": id, "sql": sql })).await;
+    let mut rows = Vec::new();
+    loop {
+        let message = next_json(socket).await;
+        assert_eq!(message["id"], id, "{}", message);
+        match message["type"].as_str().unwrap() {
+            "rows" => rows.extend(message["rows"].as_array().unwrap().iter().cloned()),
+            _ => return (rows, message),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_websocket_sessions() {
+    let server = TestServer::start("test_server_ws.db", "test_server_ws.wal").await;
+    let url = format!("{}/ws", server.url.replace("http://", "ws://"));
+    let (mut socket, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    let (_, reply) = socket_query(&mut socket, 1, "SHOW LOCKS;").await;
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["status"], 401);
+    send_json(
+        &mut socket,
+        json!({ "type": "auth", "user": "admin", "pass": "password" }),
+    )
+    .await;
+    assert_eq!(next_json(&mut socket).await["type"], "authenticated");
+
+    let (_, reply) = socket_query(&mut socket, 2, "CREATE TABLE t (id INT, name TEXT);").await;
+    assert_eq!(
+        reply,
+        json!({ "type": "done", "id": 2, "columns": [], "row_count": 0, "tx_status": "idle" })
+    );
+    // A statement that fails inside a transaction says it is aborted.
+    let (_, reply) = socket_query(&mut socket, 3, "BEGIN;").await;
+    let tx_id = reply["tx_id"].as_u64().unwrap();
+    assert_eq!(reply["tx_status"], "open");
+    let huge = format!(
+        "INSERT INTO t (id, name) VALUES (2, '{}');",
+        "x".repeat(8192)
+    );
+    let (_, reply) = socket_query(&mut socket, 4, &huge).await;
+    assert_eq!(reply["type"], "error");
+    assert_eq!(
+        (&reply["tx_status"], &reply["tx_id"]),
+        (&json!("aborted"), &json!(tx_id))
+    );
+    let (_, reply) = socket_query(&mut socket, 5, "ROLLBACK;").await;
+    assert_eq!(reply["tx_status"], "idle");
+
+    socket_query(&mut socket, 6, "BEGIN;").await;
+    socket_query(&mut socket, 7, "INSERT INTO t (id, name) VALUES (1, 'a');").await;
+    let (rows, reply) = socket_query(&mut socket, 8, "SELECT id, name FROM t;").await;
+    assert_eq!(rows, vec![json!([1, "a"])]);
+    assert_eq!(reply["columns"], json!(["ID", "NAME"]));
+    assert_eq!(reply["row_count"], 1);
+    assert_eq!(reply["tx_status"], "open");
+    assert_ne!(server.get("/debug/locks").await, "[]");
+
+    // Hanging up rolls back the open transaction and frees its locks.
+    socket.close(None).await.unwrap();
+    let mut locks = String::new();
+    for _ in 0..50 {
+        locks = server.get("/debug/locks").await;
+        if locks == "[]" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(locks, "[]");
+    let (_, body) = server.query("SELECT id FROM t;").await;
+    assert_eq!(
+        body,
+        r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"idle"}"#
+    );
+
+    // A socket opened with a login cookie needs no auth message. Its
+    // statement is stuck behind rows the client has not read, and a
+    // cancel stops it once they are.
+    let pad = "x".repeat(500);
+    for batch in 0..10 {
+        let values: Vec<String> = (batch * 1000..(batch + 1) * 1000)
+            .map(|i| format!("({}, '{}')", i, pad))
+            .collect();
+        let sql = format!("INSERT INTO t (id, name) VALUES {};", values.join(", "));
+        assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+    }
+    let tcp = TcpSocket::new_v4().unwrap();
+    tcp.set_recv_buffer_size(4096).unwrap();
+    let addr = server.url.trim_start_matches("http://").parse().unwrap();
+    let stream = tcp.connect(addr).await.unwrap();
+    let mut request = url.as_str().into_client_request().unwrap();
+    let cookie = format!("session_token={}", raw_login(&server.url).await);
+    request
+        .headers_mut()
+        .insert("cookie", cookie.parse().unwrap());
+    let (mut socket, _) = tokio_tungstenite::client_async(request, MaybeTlsStream::Plain(stream))
+        .await
+        .unwrap();
+    send_json(
+        &mut socket,
+        json!({ "type": "query", "id": 1, "sql": "SELECT id, name FROM t;" }),
+    )
+    .await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    send_json(&mut socket, json!({ "type": "cancel", "id": 1 })).await;
+    let mut rows = 0;
+    let mut acknowledged = false;
+    let last = loop {
+        let message = next_json(&mut socket).await;
+        match message["type"].as_str().unwrap() {
+            "rows" => rows += message["rows"].as_array().unwrap().len(),
+            "cancelling" => acknowledged = true,
+            _ => break message,
+        }
+    };
+    assert!(acknowledged);
+    assert!(rows < 10000, "all {} rows were sent", rows);
+    assert_eq!(last["type"], "error");
+    assert_eq!(last["error"], "Statement cancelled");
+    let (rows, _) = socket_query(&mut socket, 2, "SELECT id FROM t WHERE id = 9999;").await;
+    assert_eq!(rows, vec![json!([9999])]);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_schema_endpoints() {
+    let server = TestServer::start("test_server_schema.db", "test_server_schema.wal").await;
+    for sql in [
+        "CREATE TABLE users (id INT, name TEXT);",
+        "CREATE TABLE empty (id INT);",
+        "INSERT INTO users (id, name) VALUES (1, 'a'), (2, 'b');",
+        "CREATE INDEX users_id ON users (id);",
+        "CREATE INDEX users_id_hash ON users (id) USING HASH;",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let client = SqlClient::new(&server.url);
+    assert!(client.tables().await.is_err());
+    client.login("admin", "password").await.unwrap();
+
+    // Each row is its 16-byte version header, a 4-byte value count and the
+    // values: an INT takes 9 bytes, a TEXT 5 plus its length.
+    let users_bytes = 2 * (16 + 4 + 9 + 5 + 1);
+    assert_eq!(
+        client.tables().await.unwrap(),
+        vec![
+            TableSummary {
+                name: "EMPTY".into(),
+                row_count: 0,
+                page_count: 0,
+                bytes: 0,
+                inserted: 0,
+                deleted: 0,
+                analyzed_at: None,
+            },
+            TableSummary {
+                name: "USERS".into(),
+                row_count: 2,
+                page_count: 1,
+                bytes: users_bytes,
+                inserted: 2,
+                deleted: 0,
+                analyzed_at: None,
+            },
+        ]
+    );
+    // Names are matched the way SQL matches them.
+    let users = client.table("users").await.unwrap().unwrap();
+    assert_eq!(
+        users.columns,
+        vec![
+            ColumnSchema {
+                name: "ID".into(),
+                data_type: "INT".into(),
+                collation: None,
+            },
+            ColumnSchema {
+                name: "NAME".into(),
+                data_type: "TEXT".into(),
+                collation: None,
+            },
+        ]
+    );
+    let indexes: Vec<_> = users
+        .indexes
+        .iter()
+        .map(|i| (i.name.as_str(), i.columns.clone(), i.kind.as_str()))
+        .collect();
+    assert_eq!(
+        indexes,
+        vec![
+            ("USERS_ID", vec!["ID".to_string()], "btree"),
+            ("USERS_ID_HASH", vec!["ID".to_string()], "hash"),
+        ]
+    );
+    assert!(users.indexes.iter().all(|i| i.root_page > 0));
+    assert_eq!(client.indexes().await.unwrap(), users.indexes);
+    assert_eq!(client.table("missing").await.unwrap(), None);
+
+    let resp = server
+        .client
+        .get(format!("{}/tables/missing", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body, json!({ "error": "Table 'MISSING' does not exist" }));
+    server.stop();
+}
+
+// The table named `name` as /tables lists it.
+async fn table_summary(client: &SqlClient, name: &str) -> TableSummary {
+    let tables = client.tables().await.unwrap();
+    tables.into_iter().find(|t| t.name == name).unwrap()
+}
+
+#[tokio::test]
+async fn test_tables_are_analyzed_again_as_their_rows_change() {
+    let server = TestServer::start_with(
+        "test_server_auto_analyze.db",
+        "test_server_auto_analyze.wal",
+        ServerConfig {
+            auto_analyze_interval: Some(Duration::from_millis(50)),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let ids: Vec<String> = (0..200).map(|id| format!("({})", id)).collect();
+    for sql in [
+        "CREATE TABLE t (id INT);".to_string(),
+        "CREATE TABLE few (id INT);".to_string(),
+        format!("INSERT INTO t (id) VALUES {};", ids.join(", ")),
+        "INSERT INTO few (id) VALUES (1), (2);".to_string(),
+    ] {
+        assert_eq!(server.query(&sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+
+    let mut analyzed = None;
+    for _ in 0..100 {
+        analyzed = table_summary(&client, "T").await.analyzed_at;
+        if analyzed.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let first = analyzed.expect("T was not analyzed");
+    let t = table_summary(&client, "T").await;
+    assert_eq!((t.row_count, t.inserted, t.deleted), (200, 0, 0));
+    // Two rows are too few to bother with, however new.
+    let few = table_summary(&client, "FEW").await;
+    assert_eq!((few.inserted, few.analyzed_at), (2, None));
+    let (_, body) = server.query("SHOW TABLES;").await;
+    let shown: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        shown["columns"],
+        json!([
+            "table",
+            "rows",
+            "pages",
+            "bytes",
+            "kind",
+            "inserted",
+            "deleted",
+            "analyzed_at"
+        ])
+    );
+    assert_eq!(shown["rows"][1][7], json!(first));
+
+    // Turned off, the statistics stay as they are.
+    server.query("SET GLOBAL auto_analyze = 0;").await;
+    server.query("DELETE FROM t WHERE id < 60;").await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let t = table_summary(&client, "T").await;
+    assert_eq!((t.deleted, t.analyzed_at), (60, Some(first)));
+
+    // 60 of 200 rows is more than a fifth.
+    server.query("SET GLOBAL auto_analyze = 20;").await;
+    let mut t = t;
+    for _ in 0..100 {
+        t = table_summary(&client, "T").await;
+        if t.deleted == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(t.deleted, 0);
+    assert!(t.analyzed_at.unwrap() >= first);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_debug_queries_lists_and_cancels() {
+    let server = TestServer::start("test_server_queries.db", "test_server_queries.wal").await;
+    let url = server.url.clone();
+    for sql in [
+        "CREATE TABLE t (id INT, name TEXT);",
+        "CREATE USER bob PASSWORD 'pw';",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let admin = SqlClient::new(&url);
+    admin.login("admin", "password").await.unwrap();
+    let padding = "x".repeat(300);
+    let rows: Vec<Vec<EngineValue>> = (0..20_000)
+        .map(|id| vec![EngineValue::Int(id), EngineValue::String(padding.clone())])
+        .collect();
+    admin.copy_in("t", &[], &rows).await.unwrap();
+
+    // A client that stops reading holds the executor up mid-result.
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let addr = url.trim_start_matches("http://").parse().unwrap();
+    let mut stalled = socket.connect(addr).await.unwrap();
+    let body = json!({ "sql": "SELECT id, name FROM t;" }).to_string();
+    let request = format!(
+        "POST /query HTTP/1.1\r\nHost: localhost\r\nCookie: session_token={}\r\nContent-Length: {}\r\n\r\n{}",
+        raw_login(&url).await,
+        body.len(),
+        body
+    );
+    stalled.write_all(request.as_bytes()).await.unwrap();
+    let mut status_line = [0; 12];
+    stalled.read_exact(&mut status_line).await.unwrap();
+    assert_eq!(&status_line, b"HTTP/1.1 200");
+    let running = loop {
+        let list = admin.queries().await.unwrap();
+        let mut queries = list.running.into_iter();
+        let scan = queries.find(|q| q.sql.starts_with("SELECT"));
+        if let Some(scan) = scan.filter(|q| q.rows > 0) {
+            break scan;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(running.state, QueryState::Executing);
+    assert_eq!(running.user, "admin");
+
+    // Anyone else sees only their own queries and cannot cancel others'.
+    let bob = SqlClient::new(&url);
+    bob.login("bob", "pw").await.unwrap();
+    assert!(bob.query("SELECT id FROM t;").await.is_err());
+    let theirs = bob.queries().await.unwrap();
+    let finished = theirs.finished;
+    assert!(theirs.running.is_empty());
+    assert_eq!(finished.len(), 1);
+    assert_eq!(finished[0].state, QueryState::Failed);
+    assert!(bob.cancel_query(running.id).await.is_err());
+
+    admin.cancel_query(running.id).await.unwrap();
+    // The rest of the body ends with why it stopped.
+    let cancelled = b"Statement cancelled";
+    let mut tail = Vec::new();
+    let mut read = vec![0; 1 << 16];
+    while !tail.windows(cancelled.len()).any(|w| w == cancelled) {
+        let n = stalled.read(&mut read).await.unwrap();
+        assert!(n > 0, "{}", String::from_utf8_lossy(&tail));
+        tail.drain(..tail.len().saturating_sub(cancelled.len()));
+        tail.extend_from_slice(&read[..n]);
+    }
+    // An entry moves over once its statement has let go of the storage,
+    // which can be just after the last of the body has been sent.
+    let finished = || async {
+        loop {
+            let list = admin.queries().await.unwrap();
+            if list.running.is_empty() {
+                break list.finished;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    let list = finished().await;
+    let done = list.iter().find(|q| q.id == running.id).unwrap();
+    assert_eq!(done.state, QueryState::Failed);
+    assert_eq!(done.error.as_deref(), Some("Statement cancelled"));
+    assert!(done.parse_us.is_some() && done.plan_us.is_some() && done.execute_us.is_some());
+    let (status, _) = server.query("SELECT id FROM t WHERE id = 1;").await;
+    assert_eq!(status, StatusCode::OK);
+    let last = finished().await.remove(0);
+    assert_eq!((last.state, last.rows), (QueryState::Finished, 1));
+    assert!(admin.cancel_query(running.id).await.is_err());
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_copy_from_stdin() {
+    let server = TestServer::start("test_server_copy.db", "test_server_copy.wal").await;
+    for sql in [
+        "CREATE TABLE t (id INT, name TEXT);",
+        "CREATE INDEX t_id ON t (id);",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+
+    let rows: Vec<Vec<EngineValue>> = (0..2000)
+        .map(|id| vec![EngineValue::Int(id), EngineValue::String(id.to_string())])
+        .collect();
+    let report = client.copy_in("t", &[], &rows).await.unwrap();
+    assert_eq!((report.table.as_str(), report.rows), ("T", 2000));
+
+    let copy = |body: &'static str| {
+        server
+            .client
+            .post(format!("{}/copy", server.url))
+            .body(body)
+            .send()
+    };
+    let resp = copy("COPY t (name, id) FROM STDIN;\nx\t2000\ny\t2001\n")
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let report: CopyReport = resp.json().await.unwrap();
+    assert_eq!(report.rows, 2);
+    // The rows of a COPY that fails are all left out.
+    for (body, status) in [
+        ("COPY t FROM STDIN;\n3000\tz\n3\n", StatusCode::BAD_REQUEST),
+        ("COPY missing FROM STDIN;\n1\n", StatusCode::NOT_FOUND),
+        ("SELECT id FROM t;\n", StatusCode::BAD_REQUEST),
+    ] {
+        assert_eq!(copy(body).await.unwrap().status(), status, "{}", body);
+    }
+    let (status, body) = server.query("COPY t FROM STDIN;").await;
+    assert_ne!(status, StatusCode::OK, "{}", body);
+
+    let result = client
+        .query("SELECT name FROM t WHERE id = 2001;")
+        .await
+        .unwrap();
+    assert_eq!(result.rows, vec![vec![DbValue::from("y")]]);
+    let result = client.query("SELECT id FROM t;").await.unwrap();
+    assert_eq!(result.rows.len(), 2002);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_csv_import_and_export() {
+    let server = TestServer::start("test_server_csv.db", "test_server_csv.wal").await;
+    for sql in [
+        "CREATE TABLE users (id INT, name TEXT);",
+        "CREATE INDEX users_id ON users (id);",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+
+    // Columns are matched by name, and a bad row is skipped, not fatal.
+    let csv = "name,id\na,1\nb,two\n\"c, d\",3\ne\n";
+    let report = client
+        .import_csv("users", csv, CsvOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(report.table, "USERS");
+    assert!(!report.created);
+    assert_eq!(report.rows_imported, 2);
+    assert_eq!(report.error_count, 2);
+    assert_eq!(
+        report.errors,
+        vec![
+            RowError {
+                line: 3,
+                column: Some("ID".into()),
+                error: "Column 'ID': \"two\" is not an integer".into()
+            },
+            RowError {
+                line: 5,
+                column: None,
+                error: "Expected 2 fields, found 1".into()
+            },
+        ]
+    );
+    // The index saw the imported rows.
+    let result = client
+        .query("SELECT name FROM users WHERE id = 3;")
+        .await
+        .unwrap();
+    assert_eq!(result.rows, vec![vec![DbValue::from("c, d")]]);
+
+    let semicolons = CsvOptions {
+        delimiter: b';',
+        ..CsvOptions::default()
+    };
+    assert_eq!(
+        client.export_csv("users", semicolons).await.unwrap(),
+        "ID;NAME\n1;a\n3;c, d\n"
+    );
+
+    // A missing table is only created when asked for, with types from the data.
+    let csv = "id\tlabel\n7\tseven\n";
+    let tabs = CsvOptions {
+        delimiter: b'\t',
+        ..CsvOptions::default()
+    };
+    assert!(
+        client
+            .import_csv("labels", csv, tabs.clone())
+            .await
+            .is_err()
+    );
+    let created = CsvOptions {
+        create: true,
+        ..tabs
+    };
+    let report = client.import_csv("labels", csv, created).await.unwrap();
+    assert!(report.created);
+    assert_eq!(report.rows_imported, 1);
+    let labels = client.table("labels").await.unwrap().unwrap();
+    let types: Vec<_> = labels
+        .columns
+        .iter()
+        .map(|c| c.data_type.as_str())
+        .collect();
+    assert_eq!(types, vec!["INT", "TEXT"]);
+
+    // A header that does not fit the table imports nothing.
+    let resp = server
+        .client
+        .post(format!("{}/tables/users/import", server.url))
+        .body("id,email\n9,x\n")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "error": "Import failed: Table 'USERS' has no column 'email'" })
+    );
+    let export = server
+        .client
+        .get(format!("{}/tables/users/export?header=false", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(export.headers()["content-type"], "text/csv");
+    assert_eq!(export.text().await.unwrap(), "1,a\n3,\"c, d\"\n");
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_csv_import_options() {
+    let server = TestServer::start("test_server_csv_opts.db", "test_server_csv_opts.wal").await;
+    server
+        .query("CREATE TABLE events (id INT, kind TEXT);")
+        .await;
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+
+    // A headerless export from elsewhere: a preamble to skip, tabs, padded
+    // fields, columns in another order and `\N` for NULL.
+    let tsv = "exported by\ntool\n'a\tb'\t 1 \nclick\t2\n\\N\t3\n";
+    let options = CsvOptions {
+        header: false,
+        delimiter: b'\t',
+        quote: Some(b'\''),
+        null: Some("\\N".into()),
+        trim: true,
+        skip_rows: 2,
+        columns: Some(vec!["kind".into(), "id".into()]),
+        ..CsvOptions::default()
+    };
+    let report = client
+        .import_csv("events", tsv, options.clone())
+        .await
+        .unwrap();
+    assert_eq!(report.rows_imported, 2);
+    assert_eq!(
+        report.errors,
+        vec![RowError {
+            line: 5,
+            column: Some("KIND".into()),
+            error: "Column 'KIND': NULL is not supported".into()
+        }]
+    );
+    let result = client
+        .query("SELECT kind FROM events WHERE id = 1;")
+        .await
+        .unwrap();
+    assert_eq!(result.rows, vec![vec![DbValue::from("a\tb")]]);
+
+    // Aborting on the bad row imports none of the others.
+    let abort = CsvOptions {
+        on_error: OnError::Abort,
+        ..options
+    };
+    let err = client.import_csv("events", tsv, abort).await.unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Line 5: Column 'KIND': NULL is not supported"),
+        "{}",
+        err
+    );
+    let result = client.query("SELECT id FROM events;").await.unwrap();
+    assert_eq!(result.rows.len(), 2);
+
+    // Export quotes with the same character, and the options survive the
+    // query string.
+    let single = CsvOptions {
+        delimiter: b'\t',
+        quote: Some(b'\''),
+        ..CsvOptions::default()
+    };
+    assert_eq!(
+        client.export_csv("events", single).await.unwrap(),
+        "ID\tKIND\n1\t'a\tb'\n2\tclick\n"
+    );
+    let options = CsvOptions {
+        null: Some(String::new()),
+        columns: Some(vec!["a b".into(), "c&d".into()]),
+        on_error: OnError::Abort,
+        quote: None,
+        ..CsvOptions::default()
+    };
+    assert_eq!(
+        CsvOptions::from_query(Some(&options.to_query())),
+        Ok(options)
+    );
+    assert!(CsvOptions::from_query(Some("quote=ab")).is_err());
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_csv_import_commits_in_batches() {
+    let server = TestServer::start("test_server_csv_batch.db", "test_server_csv_batch.wal").await;
+    for sql in ["CREATE TABLE t (id INT);", "CREATE INDEX t_id ON t (id);"] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+
+    let batches = CsvOptions {
+        batch_rows: Some(2),
+        ..CsvOptions::default()
+    };
+    let report = client
+        .import_csv("t", "id\n1\n2\n3\n", batches.clone())
+        .await
+        .unwrap();
+    assert_eq!(report.rows_imported, 3);
+    assert!(report.rows_per_second > 0);
+
+    // Only the batch the bad row is in is rolled back.
+    let abort = CsvOptions {
+        on_error: OnError::Abort,
+        ..batches
+    };
+    let err = client
+        .import_csv("t", "id\n4\n5\n6\nseven\n8\n", abort)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("2 rows were committed before it: Line 5"),
+        "{}",
+        err
+    );
+    let result = client
+        .query("SELECT id FROM t WHERE id > 3;")
+        .await
+        .unwrap();
+    assert_eq!(
+        result.rows,
+        vec![vec![DbValue::Int(4)], vec![DbValue::Int(5)]]
+    );
+    let result = client
+        .query("SELECT id FROM t WHERE id = 6;")
+        .await
+        .unwrap();
+    assert!(result.rows.is_empty());
+
+    // The table is free again for others.
+    assert_eq!(
+        server.query("INSERT INTO t (id) VALUES (9);").await.0,
+        StatusCode::OK
+    );
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_csv_import_progress_and_resume() {
+    let server = TestServer::start("test_server_csv_resume.db", "test_server_csv_resume.wal").await;
+    assert_eq!(
+        server.query("CREATE TABLE t (id INT);").await.0,
+        StatusCode::OK
+    );
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+
+    let abort = CsvOptions {
+        batch_rows: Some(2),
+        on_error: OnError::Abort,
+        ..CsvOptions::default()
+    };
+    let mut events = Vec::new();
+    let err = client
+        .import_csv_with_progress("t", "id\n1\n2\n3\nfour\n5\n", abort, |p| {
+            events.push(p.clone())
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::Server { status: 400, .. })
+        ),
+        "{:?}",
+        err
+    );
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0],
+        ImportProgress {
+            rows_imported: 2,
+            bytes_read: events[0].bytes_read,
+            rows_per_second: events[0].rows_per_second,
+            committed_line: Some(3),
+        }
+    );
+    assert!(events[0].bytes_read >= "id\n1\n2\n".len() as u64);
+
+    // With the file fixed, the rows of the committed batch are passed over.
+    let resume = CsvOptions {
+        resume_after: events[0].committed_line,
+        ..CsvOptions::default()
+    };
+    let report = client
+        .import_csv_with_progress("t", "id\n1\n2\n3\n4\n5\n", resume, |_| {})
+        .await
+        .unwrap();
+    assert_eq!(report.rows_imported, 3);
+    let result = client.query("SELECT id FROM t;").await.unwrap();
+    let ids: Vec<i64> = result.rows.iter().filter_map(|r| r[0].as_i64()).collect();
+    assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+
+    // The shell's \import does the same from a state file beside the CSV.
+    let file = Path::new("test_server_csv_resume.csv");
+    std::fs::write(file, "id\n10\n11\n12\n").unwrap();
+    let err = import_file(&client, "u", file, true, |_| {})
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("no unfinished import"), "{}", err);
+    client
+        .import_csv(
+            "u",
+            "id\n10\n",
+            CsvOptions {
+                create: true,
+                ..CsvOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+    let state = ImportState {
+        table: "U".into(),
+        committed_line: 2,
+        rows_imported: 1,
+    };
+    state.save(file).unwrap();
+    assert_eq!(ImportState::load(file).unwrap(), Some(state));
+    let (report, earlier) = import_file(&client, "u", file, true, |_| {}).await.unwrap();
+    assert_eq!((report.rows_imported, earlier), (2, 1));
+    assert!(!ImportState::path(file).exists());
+    let result = client.query("SELECT id FROM u;").await.unwrap();
+    assert_eq!(result.rows.len(), 3);
+
+    remove_file(file).unwrap();
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_lock_conflicts_are_retried() {
+    let server = TestServer::start("test_server_retry.db", "test_server_retry.wal").await;
+    server.query("CREATE TABLE t (id INT);").await;
+    server.query("INSERT INTO t (id) VALUES (1);").await;
+    let holder = login(&server.url, "admin", "password").await.unwrap();
+    query_as(&holder, &server.url, "BEGIN;").await;
+    query_as(&holder, &server.url, "INSERT INTO t (id) VALUES (2);").await;
+
+    // Under repeatable read a scan locks every row it passes, the open
+    // transaction's new one included, and finds it taken.
+    let reader = login(&server.url, "admin", "password").await.unwrap();
+    for sql in [
+        "SET isolation = 'repeatable read';",
+        "SET lock_retries = 2;",
+        "SET lock_retry_backoff = 1;",
+    ] {
+        assert_eq!(query_as(&reader, &server.url, sql).await.0, StatusCode::OK);
+    }
+    let select = |client: &Client| {
+        client
+            .post(format!("{}/query", server.url))
+            .json(&json!({ "sql": "SELECT id FROM t;" }))
+            .send()
+    };
+    let resp = select(&reader).await.unwrap();
+    assert!(!resp.status().is_success());
+    assert_eq!(resp.headers()["x-lock-retries"], "2");
+    let body = resp.text().await.unwrap();
+    assert!(body.contains("locked"), "{}", body);
+
+    // Inside a transaction block the client decides.
+    query_as(&reader, &server.url, "BEGIN;").await;
+    let resp = select(&reader).await.unwrap();
+    assert!(!resp.status().is_success());
+    assert!(resp.headers().get("x-lock-retries").is_none());
+    query_as(&reader, &server.url, "ROLLBACK;").await;
+
+    // A retry after the holder commits gets every row.
+    query_as(&reader, &server.url, "SET lock_retries = 10;").await;
+    query_as(&reader, &server.url, "SET lock_retry_backoff = 20;").await;
+    let waiting = tokio::spawn(select(&reader));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    query_as(&holder, &server.url, "COMMIT;").await;
+    let resp = waiting.await.unwrap().unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let retries: u32 = resp.headers()["x-lock-retries"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..10).contains(&retries), "{}", retries);
+    assert_eq!(
+        resp.text().await.unwrap(),
+        r#"{"columns":["ID"],"rows":[[1],[2]],"row_count":2,"tx_status":"idle"}"#
+    );
+    let metrics = server.get("/metrics").await;
+    assert!(
+        metrics.contains("\nmydb_queries_retried_total 2\n"),
+        "{}",
+        metrics
+    );
+    server.stop();
+}
+
+// Leaves the server's only slot taken by a CREATE INDEX waiting on the lock
+// of an open transaction, which ends when its client logs out.
+async fn take_only_slot(server: &TestServer) -> (Client, JoinHandle<(StatusCode, String)>) {
+    server.query("CREATE TABLE t (id INT);").await;
+    let holder = login(&server.url, "admin", "password").await.unwrap();
+    query_as(&holder, &server.url, "BEGIN;").await;
+    query_as(&holder, &server.url, "INSERT INTO t (id) VALUES (1);").await;
+    let waiter = login(&server.url, "admin", "password").await.unwrap();
+    let url = server.url.clone();
+    let index = "CREATE INDEX t_id ON t (id);";
+    let waiting = tokio::spawn(async move { query_as(&waiter, &url, index).await });
+    while !server
+        .get("/metrics")
+        .await
+        .contains("\nmydb_queries_in_flight 1\n")
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    (holder, waiting)
+}
+
+#[tokio::test]
+async fn test_admission_control() {
+    let server = TestServer::start_with(
+        "test_server_admission.db",
+        "test_server_admission.wal",
+        ServerConfig {
+            max_running_queries: Some(1),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let (holder, waiting) = take_only_slot(&server).await;
+    let resp = server
+        .client
+        .post(format!("{}/query", server.url))
+        .json(&json!({ "sql": "SELECT id FROM t;" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()["retry-after"], "1");
+    let metrics = server.get("/metrics").await;
+    assert!(
+        metrics.contains("\nmydb_admission_rejections_total{reason=\"busy\"} 1\n"),
+        "{}",
+        metrics
+    );
+    // Logging out rolls back without needing a slot.
+    holder
+        .post(format!("{}/logout", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(waiting.await.unwrap().0, StatusCode::OK);
+    assert_eq!(server.query("SELECT id FROM t;").await.0, StatusCode::OK);
+    server.stop();
+
+    let server = TestServer::start_with(
+        "test_server_admission_queue.db",
+        "test_server_admission_queue.wal",
+        ServerConfig {
+            max_running_queries: Some(1),
+            when_busy: WhenBusy::Queue,
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let (holder, waiting) = take_only_slot(&server).await;
+    let queued = {
+        let (client, url) = (server.client.clone(), server.url.clone());
+        tokio::spawn(async move { query_as(&client, &url, "SELECT id FROM t;").await })
+    };
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!queued.is_finished());
+    holder
+        .post(format!("{}/logout", server.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(waiting.await.unwrap().0, StatusCode::OK);
+    assert_eq!(
+        queued.await.unwrap(),
+        (
+            StatusCode::OK,
+            r#"{"columns":["ID"],"rows":[],"row_count":0,"tx_status":"idle"}"#.to_string()
+        )
+    );
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_session_rate_limit() {
+    let server = TestServer::start_with(
+        "test_server_rate.db",
+        "test_server_rate.wal",
+        ServerConfig {
+            rate_limit: Some(3),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let tables = |client: Client| {
+        let url = format!("{}/tables", server.url);
+        async move { client.get(url).send().await.unwrap() }
+    };
+    for _ in 0..3 {
+        assert_eq!(tables(server.client.clone()).await.status(), StatusCode::OK);
+    }
+    let resp = tables(server.client.clone()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()["retry-after"], "1");
+    // Other sessions have buckets of their own.
+    let other = login(&server.url, "admin", "password").await.unwrap();
+    assert_eq!(tables(other).await.status(), StatusCode::OK);
+    // Without a session /metrics is not limited.
+    let metrics = reqwest::get(format!("{}/metrics", server.url))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        metrics.contains("\nmydb_admission_rejections_total{reason=\"rate_limited\"} 1\n"),
+        "{}",
+        metrics
+    );
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(tables(server.client.clone()).await.status(), StatusCode::OK);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_result_cache_serves_selects_until_their_table_changes() {
+    let server = TestServer::start_with(
+        "test_server_result_cache.db",
+        "test_server_result_cache.wal",
+        ServerConfig {
+            result_cache_bytes: Some(1 << 20),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    server.query("CREATE TABLE t (id INT);").await;
+    server.query("CREATE TABLE u (id INT);").await;
+    server.query("INSERT INTO t (id) VALUES (1);").await;
+    let select = |sql: &'static str, cache: Option<bool>| {
+        let request = server
+            .client
+            .post(format!("{}/query", server.url))
+            .json(&json!({ "sql": sql, "cache": cache }));
+        async move {
+            let resp = request.send().await.unwrap();
+            let header = resp
+                .headers()
+                .get("x-result-cache")
+                .map(|v| v.to_str().unwrap().to_string());
+            let body: Value = resp.json().await.unwrap();
+            (header, body["rows"].clone())
+        }
+    };
+
+    let (header, rows) = select("SELECT id FROM t;", None).await;
+    assert_eq!((header.as_deref(), rows), (Some("miss"), json!([[1]])));
+    // Spacing, case and comments do not make a different query.
+    let (header, rows) = select("select  id\n from T -- again", None).await;
+    assert_eq!((header.as_deref(), rows), (Some("hit"), json!([[1]])));
+    let (header, _) = select("SELECT id FROM t;", Some(false)).await;
+    assert_eq!(header, None);
+
+    // A write to another table leaves the entry alone; one to t drops it.
+    server.query("INSERT INTO u (id) VALUES (5);").await;
+    assert_eq!(select("SELECT id FROM t;", None).await.0.as_deref(), Some("hit"));
+    server.query("INSERT INTO t (id) VALUES (2);").await;
+    let (header, rows) = select("SELECT id FROM t;", None).await;
+    assert_eq!((header.as_deref(), rows), (Some("miss"), json!([[1], [2]])));
+
+    // Inside a transaction reads bypass the cache, and its writes count once
+    // it commits.
+    server.query("BEGIN;").await;
+    server.query("INSERT INTO t (id) VALUES (3);").await;
+    let (header, rows) = select("SELECT id FROM t;", None).await;
+    assert_eq!((header, rows), (None, json!([[1], [2], [3]])));
+    assert_eq!(select("SELECT id FROM t;", None).await.0, None);
+    server.query("COMMIT;").await;
+    let (header, rows) = select("SELECT id FROM t;", None).await;
+    assert_eq!((header.as_deref(), rows), (Some("miss"), json!([[1], [2], [3]])));
+
+    let metrics = server.get("/metrics").await;
+    assert!(metrics.contains("\nmydb_result_cache_hits_total 2\n"), "{}", metrics);
+    assert!(metrics.contains("\nmydb_result_cache_misses_total 3\n"), "{}", metrics);
+    // A result of a function that can give new values every call is not kept.
+    let (_, first) = select("SELECT RANDOM() FROM t;", None).await;
+    let (header, second) = select("SELECT RANDOM() FROM t;", None).await;
+    assert_ne!(header.as_deref(), Some("hit"));
+    assert_ne!(first, second);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_large_results_are_gzipped() {
+    let server = TestServer::start("test_server_gzip.db", "test_server_gzip.wal").await;
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    for batch in 0..10 {
+        let values: Vec<String> = (batch * 1000..(batch + 1) * 1000)
+            .map(|i| format!("({}, 'name {}')", i, i))
+            .collect();
+        let sql = format!("INSERT INTO t (id, name) VALUES {};", values.join(", "));
+        assert_eq!(server.query(&sql).await.0, StatusCode::OK);
+    }
+    // Decompression is left to the test, so it sees the bytes on the wire.
+    let raw = Client::builder()
+        .cookie_store(true)
+        .no_gzip()
+        .build()
+        .unwrap();
+    raw.post(format!("{}/login", server.url))
+        .json(&json!({ "user": "admin", "pass": "password" }))
+        .send()
+        .await
+        .unwrap();
+    let fetch = |sql: &'static str, gzip: bool| {
+        let mut request = raw
+            .post(format!("{}/query", server.url))
+            .json(&json!({ "sql": sql }));
+        if gzip {
+            request = request.header("accept-encoding", "gzip");
+        }
+        async move { request.send().await.unwrap() }
+    };
+
+    let select = "SELECT id, name FROM t;";
+    let plain = fetch(select, false).await;
+    assert!(plain.headers().get("content-encoding").is_none());
+    let plain = plain.bytes().await.unwrap();
+    let compressed = fetch(select, true).await;
+    assert_eq!(compressed.headers()["content-encoding"], "gzip");
+    let compressed = compressed.bytes().await.unwrap();
+    assert!(
+        compressed.len() * 4 < plain.len(),
+        "{} gzipped bytes against {}",
+        compressed.len(),
+        plain.len()
+    );
+    let mut unzipped = Vec::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_end(&mut unzipped)
+        .unwrap();
+    assert_eq!(unzipped, plain);
+
+    // A small result is not worth it.
+    let small = fetch("SELECT id FROM t WHERE id = 7;", true).await;
+    assert!(small.headers().get("content-encoding").is_none());
+    let body = small.text().await.unwrap();
+    assert_eq!(
+        body,
+        r#"{"columns":["ID"],"rows":[[7]],"row_count":1,"tx_status":"idle"}"#
+    );
+
+    let export = raw
+        .get(format!("{}/tables/t/export", server.url))
+        .header("accept-encoding", "gzip;q=1, br;q=0.5")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(export.headers()["content-encoding"], "gzip");
+    let mut csv = String::new();
+    GzDecoder::new(&export.bytes().await.unwrap()[..])
+        .read_to_string(&mut csv)
+        .unwrap();
+    assert_eq!(csv.lines().count(), 10_001);
+
+    // The client asks for gzip and undoes it without being told.
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    assert_eq!(client.query(select).await.unwrap().rows.len(), 10_000);
+    server.stop();
+}
+
+// Polls until `sql` on `server` answers with `expected`, or fails after a
+// few seconds.
+async fn wait_for_rows(server: &TestServer, sql: &str, expected: &str) {
+    let mut body = String::new();
+    for _ in 0..100 {
+        body = server.query(sql).await.1;
+        if body == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} still answers {} instead of {}", sql, body, expected);
+}
+
+#[tokio::test]
+async fn test_standby_follows_primary_and_promotes() {
+    let primary = TestServer::start("test_server_primary.db", "test_server_primary.wal").await;
+    for sql in [
+        "CREATE TABLE t (id INT, name TEXT);",
+        "INSERT INTO t (id, name) VALUES (1, 'a'), (2, 'b');",
+        "CREATE INDEX t_id ON t (id);",
+    ] {
+        assert_eq!(primary.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let standby = TestServer::start_with(
+        "test_server_standby.db",
+        "test_server_standby.wal",
+        ServerConfig {
+            standby_of: Some(StandbyConfig {
+                primary: primary.url.clone(),
+                user: "admin".into(),
+                password: Secret::from("password"),
+                poll_interval: Duration::from_millis(20),
+            }),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let select = "SELECT id, name FROM t;";
+    wait_for_rows(
+        &standby,
+        select,
+        r#"{"columns":["ID","NAME"],"rows":[[1,"a"],[2,"b"]],"row_count":2,"tx_status":"idle"}"#,
+    )
+    .await;
+    let health: Value = serde_json::from_str(&standby.get("/health").await).unwrap();
+    assert_eq!(health["role"], "standby");
+
+    // Writes are refused, however they arrive.
+    let (status, body) = standby
+        .query("INSERT INTO t (id, name) VALUES (3, 'c');")
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(body.contains("read-only standby"), "{}", body);
+    assert_eq!(
+        standby.query("CREATE TABLE u (id INT);").await.0,
+        StatusCode::FORBIDDEN
+    );
+    let client = SqlClient::new(&standby.url);
+    client.login("admin", "password").await.unwrap();
+    let err = client
+        .batch(&[
+            "SELECT id FROM t;",
+            "INSERT INTO t (id, name) VALUES (3, 'c');",
+        ])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<DbError>(),
+        Some(&DbError::BatchFailed {
+            index: Some(1),
+            message: "This server is a read-only standby; promote it to write".into()
+        })
+    );
+    assert!(
+        client
+            .import_csv("t", "id,name\n3,c\n", CsvOptions::default())
+            .await
+            .is_err()
+    );
+
+    // Committed changes follow; ones still in a transaction do not.
+    let writer = login(&primary.url, "admin", "password").await.unwrap();
+    for sql in ["BEGIN;", "INSERT INTO t (id, name) VALUES (4, 'd');"] {
+        assert_eq!(query_as(&writer, &primary.url, sql).await.0, StatusCode::OK);
+    }
+    primary
+        .query("INSERT INTO t (id, name) VALUES (3, 'c');")
+        .await;
+    wait_for_rows(
+        &standby,
+        select,
+        r#"{"columns":["ID","NAME"],"rows":[[1,"a"],[2,"b"],[3,"c"]],"row_count":3,"tx_status":"idle"}"#,
+    )
+    .await;
+    // The primary's WAL on the standby's side of things: whole records from
+    // where it was asked for.
+    let wal = SqlClient::new(&primary.url);
+    wal.login("admin", "password").await.unwrap();
+    let point = wal.replication_point(None).await.unwrap();
+    assert!(point.catalog.unwrap().tables.contains_key("T"));
+    assert!(
+        wal.replication_point(Some(point.wal_end))
+            .await
+            .unwrap()
+            .catalog
+            .is_none()
+    );
+    assert!(
+        !wal.wal(point.wal_base, point.wal_end)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // Promotion stops replay, drops the transaction the primary never
+    // finished and builds the index replay could not keep.
+    client.promote().await.unwrap();
+    assert!(client.promote().await.is_err());
+    assert_eq!(
+        query_as(&writer, &primary.url, "COMMIT;").await.0,
+        StatusCode::OK
+    );
+    let (status, _) = standby
+        .query("INSERT INTO t (id, name) VALUES (5, 'e');")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        standby.query(select).await.1,
+        r#"{"columns":["ID","NAME"],"rows":[[1,"a"],[2,"b"],[3,"c"],[5,"e"]],"row_count":4,"tx_status":"idle"}"#
+    );
+    let indexes = client.table("t").await.unwrap().unwrap().indexes;
+    assert_eq!(indexes.len(), 1);
+    assert_eq!(indexes[0].name, "T_ID");
+    assert_eq!(
+        standby.query("SELECT name FROM t WHERE id = 5;").await.1,
+        r#"{"columns":["NAME"],"rows":[["e"]],"row_count":1,"tx_status":"idle"}"#
+    );
+    let health: Value = serde_json::from_str(&standby.get("/health").await).unwrap();
+    assert_eq!(health["role"], "primary");
+
+    standby.stop();
+    primary.stop();
+}
+
+// Runs `mydb <command>` against `url` as admin, with `stdin` piped in.
+async fn run_mydb(url: &str, command: &str, args: &[&str], stdin: &str) -> (i32, String, String) {
+    let mut child = tokio::process::Command::new(env!("CARGO_BIN_EXE_engine"))
+        .arg(command)
+        .args(args)
+        .env("MYDB_URL", url)
+        .env("MYDB_USER", "admin")
+        .env("MYDB_PASSWORD", "password")
+        .env_remove("MYDB_FORMAT")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut input = child.stdin.take().unwrap();
+    input.write_all(stdin.as_bytes()).await.unwrap();
+    drop(input);
+    let output = child.wait_with_output().await.unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn test_dump_and_restore_round_trip() {
+    let server = TestServer::start("test_dump_source.db", "test_dump_source.wal").await;
+    for sql in [
+        "CREATE TABLE notes (id INT, body TEXT);",
+        "CREATE INDEX notes_id ON notes (id) USING HASH;",
+        "INSERT INTO notes (id, body) VALUES (1, 'it''s'), (-2, 'two\nlines; -- not a comment'), (3, '');",
+        "CREATE TABLE empty (id INT, tag TEXT COLLATE NOCASE);",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let import = (0..1200)
+        .map(|i| format!("{},'quoted' \"{}\"\n", i + 10, i))
+        .collect::<String>();
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let options = CsvOptions {
+        header: false,
+        ..CsvOptions::default()
+    };
+    client.import_csv("notes", import, options).await.unwrap();
+
+    let (code, dump, err) = run_mydb(&server.url, "dump", &[], "").await;
+    assert_eq!(code, 0, "{}", err);
+    assert!(
+        dump.contains("CREATE INDEX NOTES_ID ON NOTES (ID) USING HASH;"),
+        "{}",
+        dump
+    );
+    let (code, only_empty, err) = run_mydb(&server.url, "dump", &["--table", "empty"], "").await;
+    assert_eq!(code, 0, "{}", err);
+    let created = "CREATE TABLE EMPTY (ID INT, TAG TEXT COLLATE NOCASE);";
+    assert_eq!(only_empty, format!("-- mydb dump\n\n{}\n", created));
+
+    // Restoring is running the dump through the shell.
+    let copy = TestServer::start("test_dump_copy.db", "test_dump_copy.wal").await;
+    let (code, _, err) = run_mydb(&copy.url, "shell", &[], &dump).await;
+    assert_eq!(code, 0, "{}", err);
+    let restored = SqlClient::new(&copy.url);
+    restored.login("admin", "password").await.unwrap();
+    for sql in [
+        "SELECT id, body FROM notes;",
+        "SELECT body FROM notes WHERE id = -2;",
+        "SELECT id FROM empty;",
+    ] {
+        assert_eq!(
+            restored.query(sql).await.unwrap(),
+            client.query(sql).await.unwrap(),
+            "{}",
+            sql
+        );
+    }
+    assert_eq!(
+        restored
+            .query("SELECT id FROM notes;")
+            .await
+            .unwrap()
+            .rows
+            .len(),
+        1203
+    );
+    // The same indexes, wherever their pages ended up.
+    let definitions = |schema: TableSchema| {
+        schema
+            .indexes
+            .into_iter()
+            .map(|i| (i.name, i.columns, i.kind))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        definitions(restored.table("notes").await.unwrap().unwrap()),
+        definitions(client.table("notes").await.unwrap().unwrap())
+    );
+    let columns = restored.table("empty").await.unwrap().unwrap().columns;
+    assert_eq!(columns[1].collation.as_deref(), Some("NOCASE"));
+    server.stop();
+    copy.stop();
+}
+
+#[tokio::test]
+async fn test_shell_one_shot_exit_codes() {
+    let server = TestServer::start("test_shell_one_shot.db", "test_shell_one_shot.wal").await;
+    let url = server.url.clone();
+
+    let (code, out, err) = run_mydb(
+        &url,
+        "shell",
+        &[
+            "-c",
+            "CREATE TABLE t (id INT); INSERT INTO t (id) VALUES (1);",
+        ],
+        "",
+    )
+    .await;
+    assert_eq!((code, out.as_str()), (0, ""), "{}", err);
+
+    // Piped statements print only their results, in the chosen format.
+    let (code, out, err) =
+        run_mydb(&url, "shell", &["--format", "csv"], "SELECT id FROM t;\n").await;
+    assert_eq!((code, out.as_str()), (0, "ID\n1\n"), "{}", err);
+
+    let (code, out, err) = run_mydb(&url, "shell", &["-c", "SELECT * FROM nope;"], "").await;
+    assert_eq!((code, out.as_str()), (1, ""));
+    assert!(err.starts_with("Error: "), "{}", err);
+
+    let (code, _, err) = run_mydb(&url, "shell", &[], "SELECT id FROM t;\nSELECT x FROM;\n").await;
+    assert_eq!(code, 1);
+    assert!(err.contains("stdin:2:"), "{}", err);
+
+    // A refused login and an unreachable server both exit with 2.
+    let (code, _, err) =
+        run_mydb(&url, "shell", &["--user", "nobody", "-c", "SELECT 1;"], "").await;
+    assert_eq!(code, 2, "{}", err);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let (code, _, err) = run_mydb(&closed, "shell", &["-c", "SELECT 1;"], "").await;
+    assert_eq!(code, 2, "{}", err);
+
+    server.stop();
+}
+
+// Serves one canned response to one request on a port of its own.
+async fn canned_response(response: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0; 4096];
+        let _ = stream.read(&mut request).await.unwrap();
+        stream.write_all(response.as_bytes()).await.unwrap();
+    });
+    url
+}
+
+#[tokio::test]
+async fn test_row_stream_ends_with_an_error_when_the_result_fails() {
+    // The server failed after sending two rows.
+    let body = r#"{"columns":["ID"],"rows":[[1],[2]],"error":"Exec error: disk on fire"}"#;
+    let response: &'static str = Box::leak(
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .into_boxed_str(),
+    );
+    let client = SqlClient::new(&canned_response(response).await);
+    let rows: Vec<_> = client
+        .query_stream("SELECT id FROM t;")
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[1].as_ref().unwrap(), &vec![DbValue::Int(2)]);
+    let err = rows[2].as_ref().unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<DbError>(),
+        Some(&DbError::Execution("Exec error: disk on fire".into()))
+    );
+
+    // A body cut off before its end is an error too, not a shorter result.
+    let response =
+        "HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n{\"columns\":[\"ID\"],\"rows\":[[1]";
+    let client = SqlClient::new(&canned_response(response).await);
+    let mut rows = client.query_stream("SELECT id FROM t;").await.unwrap();
+    let row = rows.next_row().await.unwrap().unwrap();
+    assert_eq!(row, vec![DbValue::Int(1)]);
+    assert!(rows.next_row().await.is_err());
+    assert_eq!(rows.next_row().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_backup_while_writing() {
+    let server = TestServer::start("test_backup_live.db", "test_backup_live.wal").await;
+    server.query("CREATE TABLE t (id INT);").await;
+    server.query("CREATE INDEX t_id ON t (id);").await;
+    server.query("CREATE TABLE pending (pad TEXT);").await;
+    let other = login(&server.url, "admin", "password").await.unwrap();
+    query_as(&other, &server.url, "BEGIN;").await;
+    // Rolling a row back puts its page back as it was, so the open row
+    // fills a page of its own for the writer's rows not to land on it.
+    let fill = format!("INSERT INTO pending (pad) VALUES ('{}');", "x".repeat(4040));
+    let (status, body) = query_as(&other, &server.url, &fill).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // One writer commits ids in order, so the backup has to hold a prefix
+    // of them.
+    let url = server.url.clone();
+    let writer = tokio::spawn(async move {
+        let client = SqlClient::new(&url);
+        client.login("admin", "password").await.unwrap();
+        for id in 0..200 {
+            client
+                .query(&format!("INSERT INTO t (id) VALUES ({});", id))
+                .await
+                .unwrap();
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let dir = std::env::temp_dir().join(format!("mydb_backup_{}", std::process::id()));
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    let report = client.backup(&dir).await.unwrap();
+    assert_eq!(report.dir, dir);
+    writer.await.unwrap();
+    query_as(&other, &server.url, "COMMIT;").await;
+
+    // Only an admin may, and not into a directory already in use.
+    assert!(client.backup(&dir).await.is_err());
+    server.query("CREATE USER reader PASSWORD 'pw';").await;
+    let reader = SqlClient::new(&server.url);
+    reader.login("reader", "pw").await.unwrap();
+    assert!(reader.backup(&dir.join("x")).await.is_err());
+
+    let mut db = Database::open(&dir).unwrap();
+    let ids = db.execute("SELECT id FROM t;").unwrap().rows;
+    let ids: Vec<i64> = ids
+        .into_iter()
+        .map(|row| match row[0] {
+            DbValue::Int(id) => id,
+            ref other => panic!("{:?}", other),
+        })
+        .collect();
+    let mut sorted = ids.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..ids.len() as i64).collect::<Vec<_>>());
+    let through_index = db.execute("SELECT id FROM t WHERE id = 0;").unwrap();
+    assert_eq!(
+        through_index.rows.len(),
+        ids.iter().filter(|&&id| id == 0).count()
+    );
+    let pending = db.execute("SELECT pad FROM pending;").unwrap();
+    assert!(pending.rows.is_empty());
+    assert!(db.execute("CHECK;").unwrap().rows.is_empty());
+    db.close().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_grants_decide_what_users_may_do() {
+    let server = TestServer::start("test_grants.db", "test_grants.wal").await;
+    for sql in [
+        "CREATE TABLE notes (id INT, body TEXT, secret TEXT);",
+        "INSERT INTO notes (id, body, secret) VALUES (1, 'hello', 'pin');",
+        "CREATE USER reader PASSWORD 'r';",
+        "CREATE USER writer PASSWORD 'w';",
+        "GRANT SELECT (id, body) ON notes TO reader;",
+        "GRANT INSERT ON notes TO writer;",
+    ] {
+        let (status, body) = server.query(sql).await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", sql, body);
+    }
+    let (status, body) = server.query("GRANT SELECT ON notes TO nobody;").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("does not exist"), "{}", body);
+
+    let reader = SqlClient::new(&server.url);
+    reader.login("reader", "r").await.unwrap();
+    let writer = SqlClient::new(&server.url);
+    writer.login("writer", "w").await.unwrap();
+    let denied = |result: anyhow::Result<QueryResult>| match result {
+        Err(e) => match e.downcast::<DbError>() {
+            Ok(DbError::PermissionDenied {
+                table, privilege, ..
+            }) => (table, privilege),
+            other => panic!("expected a permission error, got {:?}", other),
+        },
+        Ok(result) => panic!("expected a permission error, got {:?}", result),
+    };
+
+    let rows = reader
+        .query("SELECT id, body FROM notes;")
+        .await
+        .unwrap()
+        .rows;
+    assert_eq!(rows.len(), 1);
+    let no_select = (Some("NOTES".to_string()), Some("SELECT".to_string()));
+    assert_eq!(
+        denied(reader.query("SELECT secret FROM notes;").await),
+        no_select
+    );
+    assert_eq!(
+        denied(
+            reader
+                .query("SELECT id FROM notes WHERE secret = 'pin';")
+                .await
+        ),
+        no_select
+    );
+    // Each SELECT of a UNION ALL needs what it reads.
+    let union = "SELECT body FROM notes UNION ALL SELECT secret FROM notes;";
+    assert_eq!(denied(reader.query(union).await), no_select);
+    // So does a subquery, its table's columns named without the table.
+    let exists = "SELECT id FROM notes WHERE EXISTS (SELECT 1 FROM notes WHERE secret = 'pin');";
+    assert_eq!(denied(reader.query(exists).await), no_select);
+    let exists = "SELECT body FROM notes WHERE NOT EXISTS (SELECT 1 FROM notes WHERE id = 2);";
+    assert_eq!(reader.query(exists).await.unwrap().rows.len(), 1);
+    assert_eq!(
+        denied(reader.query("INSERT INTO notes (id) VALUES (2);").await),
+        (Some("NOTES".to_string()), Some("INSERT".to_string()))
+    );
+    assert!(
+        reader
+            .export_csv("notes", CsvOptions::default())
+            .await
+            .is_err()
+    );
+
+    writer
+        .query("INSERT INTO notes (id, body, secret) VALUES (2, 'from writer', '');")
+        .await
+        .unwrap();
+    assert_eq!(
+        denied(writer.query("SELECT id FROM notes;").await),
+        no_select
+    );
+    assert_eq!(
+        denied(writer.query("GRANT SELECT ON notes TO writer;").await),
+        (None, None)
+    );
+
+    // Refused before it would wait behind the admin's lock on the table.
+    let admin = login(&server.url, "admin", "password").await.unwrap();
+    query_as(&admin, &server.url, "BEGIN;").await;
+    query_as(
+        &admin,
+        &server.url,
+        "INSERT INTO notes (id, body, secret) VALUES (3, '', '');",
+    )
+    .await;
+    let (status, body) = query_as(
+        &login(&server.url, "reader", "r").await.unwrap(),
+        &server.url,
+        "DROP TABLE notes;",
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["code"], "PERMISSION_DENIED");
+    assert_eq!(body["privilege"], "ALL");
+    query_as(&admin, &server.url, "COMMIT;").await;
+
+    // Whoever creates a table may do anything with it.
+    for sql in [
+        "CREATE TABLE drafts (id INT);",
+        "INSERT INTO drafts (id) VALUES (1);",
+        "CREATE INDEX drafts_id ON drafts (id);",
+        "SELECT id FROM drafts;",
+        "DROP TABLE drafts;",
+    ] {
+        writer.query(sql).await.unwrap();
+    }
+
+    let admin_client = SqlClient::new(&server.url);
+    admin_client.login("admin", "password").await.unwrap();
+    let grants: Vec<Vec<String>> = admin_client
+        .query("SHOW GRANTS;")
+        .await
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|value| match value {
+                    DbValue::Text(text) => text,
+                    other => panic!("{:?}", other),
+                })
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        grants,
+        vec![
+            vec!["NOTES", "reader", "SELECT", "BODY"],
+            vec!["NOTES", "reader", "SELECT", "ID"],
+            vec!["NOTES", "writer", "INSERT", ""],
+        ]
+    );
+
+    server
+        .query("REVOKE SELECT (body) ON notes FROM reader;")
+        .await;
+    assert_eq!(
+        denied(reader.query("SELECT id, body FROM notes;").await),
+        no_select
+    );
+    reader.query("SELECT id FROM notes;").await.unwrap();
+
+    // A user dropped and made again starts with nothing.
+    server.query("DROP USER writer;").await;
+    server.query("CREATE USER writer PASSWORD 'w';").await;
+    writer.login("writer", "w").await.unwrap();
+    assert_eq!(
+        denied(writer.query("INSERT INTO notes (id) VALUES (4);").await),
+        (Some("NOTES".to_string()), Some("INSERT".to_string()))
+    );
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_read_only_server() {
+    let (db, wal) = ("test_read_only.db", "test_read_only.wal");
+    let writable = TestServer::start(db, wal).await;
+    for sql in [
+        "CREATE TABLE t (id INT, name TEXT);",
+        "CREATE INDEX t_id ON t (id);",
+        "INSERT INTO t (id, name) VALUES (1, 'kept');",
+    ] {
+        assert_eq!(writable.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    let other = login(&writable.url, "admin", "password").await.unwrap();
+    query_as(&other, &writable.url, "BEGIN;").await;
+    query_as(
+        &other,
+        &writable.url,
+        "INSERT INTO t (id, name) VALUES (2, 'lost');",
+    )
+    .await;
+    // Gone without a shutdown; the read-only start still recovers.
+    writable.handle.abort();
+    let _ = writable.handle.await;
+
+    let config = ServerConfig {
+        read_only: true,
+        ..ServerConfig::default()
+    };
+    let mut server = TestServer::start_with(db, wal, config).await;
+    let wal_bytes = || {
+        let path = Path::new(wal);
+        let manifest = Manifest::read(path).unwrap().unwrap();
+        manifest
+            .segments()
+            .map(|segment| {
+                std::fs::metadata(segment_path(path, segment))
+                    .unwrap()
+                    .len()
+            })
+            .sum::<u64>()
+    };
+    let logged = wal_bytes();
+    let health: Value = serde_json::from_str(&server.get("/health").await).unwrap();
+    assert_eq!(health["mode"], "read_only");
+
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    client.query("SELECT id, name FROM t;").await.unwrap();
+    client.query("BEGIN;").await.unwrap();
+    client
+        .query("SELECT id FROM t WHERE id = 1;")
+        .await
+        .unwrap();
+    client.query("EXPLAIN SELECT id FROM t;").await.unwrap();
+    client.query("COMMIT;").await.unwrap();
+    assert!(client.query("CHECK;").await.unwrap().rows.is_empty());
+
+    for sql in [
+        "INSERT INTO t (id, name) VALUES (3, 'no');",
+        "CREATE TABLE u (id INT);",
+        "DROP TABLE t;",
+        "ANALYZE t;",
+        "CREATE USER reader PASSWORD 'pw';",
+    ] {
+        match client.query(sql).await.unwrap_err().downcast::<DbError>() {
+            Ok(DbError::ReadOnly(message)) => assert!(message.contains("--read-only")),
+            other => panic!("{}: {:?}", sql, other),
+        }
+    }
+    let (status, body) = server
+        .query("INSERT INTO t (id, name) VALUES (3, 'no');")
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["code"], "READ_ONLY");
+    let err = client
+        .batch(&["SELECT id FROM t;", "CREATE TABLE u (id INT);"])
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::BatchFailed { index: Some(1), .. })
+    ));
+    assert!(
+        client
+            .import_csv("t", "id,name\n3,no\n", CsvOptions::default())
+            .await
+            .is_err()
+    );
+    assert!(
+        client
+            .backup(&std::env::temp_dir().join("mydb_ro"))
+            .await
+            .is_err()
+    );
+
+    // Nothing was logged, shutting down included.
+    server.shut_down().await.unwrap();
+    assert_eq!(wal_bytes(), logged);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_sequences_never_hand_out_a_value_twice() {
+    let (db, wal) = ("test_server_sequences.db", "test_server_sequences.wal");
+    let server = TestServer::start(db, wal).await;
+    for sql in [
+        "CREATE SEQUENCE s START 100 INCREMENT 5;",
+        "CREATE TABLE t (id INT, name TEXT);",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    assert_eq!(
+        server.query("SELECT NEXTVAL('s');").await.1,
+        r#"{"columns":["nextval"],"rows":[[100]],"row_count":1,"tx_status":"idle"}"#
+    );
+    let (status, body) = server
+        .query("INSERT INTO t (id, name) VALUES (NEXTVAL('s'), 'a'), (NEXTVAL('s'), 'b');")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(
+        server.query("SELECT id, name FROM t;").await.1,
+        r#"{"columns":["ID","NAME"],"rows":[[105,"a"],[110,"b"]],"row_count":2,"tx_status":"idle"}"#
+    );
+    assert_eq!(
+        server.query("SELECT CURRVAL('s');").await.1,
+        r#"{"columns":["currval"],"rows":[[110]],"row_count":1,"tx_status":"idle"}"#
+    );
+
+    // Clients drawing at once each get values of their own.
+    let mut tasks = Vec::new();
+    for _ in 0..6 {
+        let client = login(&server.url, "admin", "password").await.unwrap();
+        let url = server.url.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut values = Vec::new();
+            for _ in 0..10 {
+                let (_, body) = query_as(&client, &url, "SELECT NEXTVAL('s');").await;
+                let body: Value = serde_json::from_str(&body).unwrap();
+                values.push(body["rows"][0][0].as_i64().unwrap());
+            }
+            values
+        }));
+    }
+    let mut values = Vec::new();
+    for task in tasks {
+        values.extend(task.await.unwrap());
+    }
+    values.sort();
+    assert_eq!(values, (115..=410).step_by(5).collect::<Vec<i64>>());
+
+    // Gone without a shutdown. Values are reserved 32 at a time, so the
+    // sequence goes on after the second reservation, which ended at 415.
+    server.handle.abort();
+    let _ = server.handle.await;
+    let server = TestServer::start(db, wal).await;
+    assert_eq!(
+        server.query("SELECT NEXTVAL('s');").await.1,
+        r#"{"columns":["nextval"],"rows":[[420]],"row_count":1,"tx_status":"idle"}"#
+    );
+
+    let (status, body) = server.query("CREATE SEQUENCE bad INCREMENT 0;").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("increment of 0"), "{}", body);
+    assert_eq!(server.query("DROP SEQUENCE s;").await.0, StatusCode::OK);
+    let (status, body) = server.query("SELECT NEXTVAL('s');").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("Sequence 's' not found"), "{}", body);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_temp_tables_belong_to_their_session() {
+    let (db, wal) = ("test_server_temp_tables.db", "test_server_temp_tables.wal");
+    let server = TestServer::start(db, wal).await;
+    for sql in ["CREATE TABLE t (id INT);", "INSERT INTO t (id) VALUES (1);"] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK, "{}", sql);
+    }
+    // Nothing the temporary tables do from here on is logged.
+    let logged = || {
+        let mut reader = WalReader::open(Path::new(wal)).unwrap();
+        let mut logged = 0;
+        while let Some(record) = reader.next_record().unwrap() {
+            let typ = record.header.typ;
+            logged += usize::from(typ == LogRecordType::Update || typ.is_ddl());
+        }
+        logged
+    };
+    let before = logged();
+    for sql in [
+        "CREATE TEMP TABLE t (id INT, note TEXT);",
+        "CREATE TEMPORARY TABLE tmp_results (id INT);",
+        "INSERT INTO t (id, note) VALUES (2, 'mine');",
+        "INSERT INTO tmp_results (id) VALUES (3), (4);",
+    ] {
+        let (status, body) = server.query(sql).await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", sql, body);
+    }
+    assert_eq!(logged(), before);
+
+    // The session's own T hides the table everyone else sees.
+    assert_eq!(
+        server.query("SELECT id, note FROM t;").await.1,
+        r#"{"columns":["ID","NOTE"],"rows":[[2,"mine"]],"row_count":1,"tx_status":"idle"}"#
+    );
+    assert_eq!(
+        server
+            .query("SELECT id FROM tmp_results WHERE id > 3;")
+            .await
+            .1,
+        r#"{"columns":["ID"],"rows":[[4]],"row_count":1,"tx_status":"idle"}"#
+    );
+    assert!(server.query("SHOW TABLES;").await.1.contains("TMP_RESULTS"));
+    assert_eq!(
+        server.query("CREATE INDEX i ON tmp_results (id);").await.0,
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+
+    let other = login(&server.url, "admin", "password").await.unwrap();
+    assert_eq!(
+        query_as(&other, &server.url, "SELECT id FROM t;").await.1,
+        r#"{"columns":["ID"],"rows":[[1]],"row_count":1,"tx_status":"idle"}"#
+    );
+    assert!(
+        !query_as(&other, &server.url, "SHOW TABLES;")
+            .await
+            .1
+            .contains("TMP_RESULTS")
+    );
+    let (status, body) = query_as(&other, &server.url, "SELECT id FROM tmp_results;").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("Unknown table 'TMP_RESULTS'"), "{}", body);
+
+    // Dropping the temporary T leaves the other one in view again.
+    assert_eq!(server.query("DROP TABLE t;").await.0, StatusCode::OK);
+    assert_eq!(
+        server.query("SELECT id FROM t;").await.1,
+        r#"{"columns":["ID"],"rows":[[1]],"row_count":1,"tx_status":"idle"}"#
+    );
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_settings_are_set_per_session_or_for_the_server() {
+    let server = TestServer::start_with(
+        "test_server_settings.db",
+        "test_server_settings.wal",
+        ServerConfig {
+            result_cache_bytes: Some(1 << 20),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let url = server.url.clone();
+    let rows = |body: &str| serde_json::from_str::<Value>(body).unwrap()["rows"].clone();
+    let (status, body) = server.query("SHOW ALL;").await;
+    assert_eq!(status, StatusCode::OK);
+    let expected = json!([
+        ["query_timeout", "30000"],
+        ["lock_timeout", "10000"],
+        ["isolation", "read committed"],
+        ["result_cache", "on"],
+        ["search_path", "PUBLIC"],
+        ["log_level", "info"],
+        ["rate_limit", "0"],
+        ["result_cache_size", "1048576"],
+        ["work_mem", "67108864"],
+        ["lock_retries", "3"],
+        ["lock_retry_backoff", "10"],
+        ["scan_error_policy", "strict"],
+        ["auto_analyze", "10"]
+    ]);
+    assert_eq!(rows(&body), expected);
+
+    // A session's SET is its own; SET GLOBAL reaches every session that has
+    // not set the same thing itself.
+    server.query("CREATE USER bob PASSWORD 'pw';").await;
+    let bob = login(&url, "bob", "pw").await.unwrap();
+    let (status, _) = query_as(&bob, &url, "SET lock_timeout = 200;").await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = query_as(&bob, &url, "SHOW lock_timeout;").await;
+    assert_eq!(rows(&body), json!([["200"]]));
+    let (_, body) = server.query("SHOW lock_timeout;").await;
+    assert_eq!(rows(&body), json!([["10000"]]));
+    let (status, body) = query_as(&bob, &url, "SET GLOBAL lock_timeout = 1;").await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    server.query("SET GLOBAL query_timeout = 1234;").await;
+    server.query("SET GLOBAL lock_timeout = 4321;").await;
+    let (_, body) = query_as(&bob, &url, "SHOW ALL;").await;
+    assert_eq!(rows(&body)[0], json!(["query_timeout", "1234"]));
+    assert_eq!(rows(&body)[1], json!(["lock_timeout", "200"]));
+
+    for (sql, expected) in [
+        ("SET lock_timeout = 0;", "at least 1"),
+        ("SET nope = 1;", "Unknown setting 'nope'"),
+        ("SET isolation = serializable;", "repeatable read"),
+        ("SET result_cache = maybe;", "on or off"),
+        ("SET rate_limit = 5;", "SET GLOBAL"),
+        ("SET GLOBAL rate_limit = lots;", "requests per second"),
+        ("SET GLOBAL log_level = 'engine=loud';", "log filter"),
+        ("SET work_mem = 0;", "at least 1"),
+        ("SET lock_retries = often;", "number of retries"),
+        ("SET lock_retry_backoff = 0;", "at least 1"),
+        ("SET scan_error_policy = lenient;", "'strict' or 'skip_page'"),
+        ("SHOW nope;", "Unknown setting"),
+    ] {
+        let (status, body) = server.query(sql).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", sql);
+        assert!(body.contains(expected), "{}: {}", sql, body);
+    }
+    server.query("SET isolation TO 'Repeatable Read';").await;
+    let (_, body) = server.query("SHOW isolation;").await;
+    assert_eq!(rows(&body), json!([["repeatable read"]]));
+    server.query("SET scan_error_policy = 'SKIP_PAGE';").await;
+    let (_, body) = server.query("SHOW scan_error_policy;").await;
+    assert_eq!(rows(&body), json!([["skip_page"]]));
+
+    // A statement waits for a table lock only as long as its session says.
+    server.query("CREATE TABLE t (id INT);").await;
+    server.query("BEGIN;").await;
+    server.query("INSERT INTO t (id) VALUES (1);").await;
+    let admin = login(&url, "admin", "password").await.unwrap();
+    query_as(&admin, &url, "SET lock_timeout = 100;").await;
+    let started = std::time::Instant::now();
+    let (status, body) = query_as(&admin, &url, "ANALYZE t;").await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    server.query("COMMIT;").await;
+
+    // With the cache off for the session, nothing is served from it or
+    // kept in it.
+    query_as(&admin, &url, "SET result_cache = off;").await;
+    for _ in 0..2 {
+        let resp = admin
+            .post(format!("{}/query", url))
+            .json(&json!({ "sql": "SELECT id FROM t;" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.headers().get("x-result-cache"), None);
+    }
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_work_mem_is_set_per_session() {
+    let server = TestServer::start_with(
+        "test_server_work_mem.db",
+        "test_server_work_mem.wal",
+        ServerConfig {
+            work_mem: Some(1 << 20),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    server.query("CREATE TABLE t (id INT, name TEXT);").await;
+    let values: Vec<String> = (0..500)
+        .map(|i| format!("({}, 'name {}')", 499 - i, i))
+        .collect();
+    let (status, body) = server
+        .query(&format!(
+            "INSERT INTO t (id, name) VALUES {};",
+            values.join(", ")
+        ))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let client = SqlClient::new(&server.url);
+    client.login("admin", "password").await.unwrap();
+    client.query("SET work_mem = 4096;").await.unwrap();
+    // A sort spills what does not fit.
+    let result = client.query("SELECT id FROM t ORDER BY id;").await.unwrap();
+    let ids: Vec<i64> = result
+        .rows
+        .iter()
+        .map(|row| row.get::<i64>("id").unwrap())
+        .collect();
+    assert_eq!(ids, (0..500).collect::<Vec<_>>());
+    // An aggregate cannot, and the statement fails with the budget.
+    let err = client
+        .query("SELECT id, COUNT(*) FROM t GROUP BY id;")
+        .await
+        .unwrap_err();
+    match err.downcast_ref::<DbError>() {
+        Some(DbError::OutOfMemory {
+            operator, budget, ..
+        }) => assert_eq!((operator.as_str(), *budget), ("Aggregate", 4096)),
+        other => panic!("expected an out of memory error, got {:?}", other),
+    }
+    let (status, body) = query_as(
+        &login(&server.url, "admin", "password").await.unwrap(),
+        &server.url,
+        "SELECT id, COUNT(*) FROM t GROUP BY id;",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // SET GLOBAL lowers it for every session that has not set its own.
+    server.query("SET GLOBAL work_mem = 4096;").await;
+    let (status, body) = query_as(
+        &login(&server.url, "admin", "password").await.unwrap(),
+        &server.url,
+        "SELECT name, COUNT(*) FROM t GROUP BY name;",
+    )
+    .await;
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["code"], "OUT_OF_MEMORY");
+    assert_eq!(body["budget"], 4096);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_random_is_seeded_per_session() {
+    let server = TestServer::start("test_server_random.db", "test_server_random.wal").await;
+    let url = server.url.clone();
+    server.query("CREATE USER bob PASSWORD 'pw';").await;
+    let bob = login(&url, "bob", "pw").await.unwrap();
+    let draw = |client: Client| {
+        let url = url.clone();
+        async move {
+            let (status, body) = query_as(&client, &url, "SELECT RANDOM();").await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+            serde_json::from_str::<Value>(&body).unwrap()["rows"].clone()
+        }
+    };
+    // Each session goes on from its own seed, whatever the other draws.
+    server.query("SELECT SETSEED(7);").await;
+    query_as(&bob, &url, "SELECT SETSEED(7);").await;
+    let first = draw(server.client.clone()).await;
+    let second = draw(server.client.clone()).await;
+    assert_ne!(first, second);
+    assert_eq!(draw(bob.clone()).await, first);
+    assert_eq!(draw(bob.clone()).await, second);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_session_variables() {
+    let server = TestServer::start_with(
+        "test_server_variables.db",
+        "test_server_variables.wal",
+        ServerConfig {
+            result_cache_bytes: Some(1 << 20),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let url = server.url.clone();
+    let rows = |body: &str| serde_json::from_str::<Value>(body).unwrap()["rows"].clone();
+    server.query("CREATE TABLE t (id INT, score INT);").await;
+    server
+        .query("INSERT INTO t (id, score) VALUES (1, 50), (2, 150);")
+        .await;
+    let (status, _) = server.query("SET @cutoff = 100;").await;
+    assert_eq!(status, StatusCode::OK);
+    let select = "SELECT id FROM t WHERE score > @cutoff ORDER BY id;";
+    let (_, body) = server.query(select).await;
+    assert_eq!(rows(&body), json!([[2]]));
+    // The same text with another value is not answered from the cache.
+    server.query("SET @cutoff = 10;").await;
+    let (_, body) = server.query(select).await;
+    assert_eq!(rows(&body), json!([[1], [2]]));
+    server.query("SET @next = 3;").await;
+    server.query("SET @label = 'three';").await;
+    let (status, body) = server
+        .query("INSERT INTO t (id, score) VALUES (@next, @next * 100);")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_, body) = server.query("SELECT score, @label FROM t WHERE id = @next;").await;
+    assert_eq!(rows(&body), json!([[300, "three"]]));
+
+    // Another session has none of them.
+    server.query("CREATE USER bob PASSWORD 'pw';").await;
+    server.query("GRANT SELECT ON t TO bob;").await;
+    let bob = login(&url, "bob", "pw").await.unwrap();
+    let (status, body) = query_as(&bob, &url, select).await;
+    assert_ne!(status, StatusCode::OK);
+    assert!(body.contains("Variable '@CUTOFF' is not set"), "{}", body);
+    let (status, body) = server
+        .query("CREATE VIEW high AS SELECT id FROM t WHERE score > @cutoff;")
+        .await;
+    assert_ne!(status, StatusCode::OK);
+    assert!(body.contains("A view cannot use a variable"), "{}", body);
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_config_file_is_applied_on_start_and_on_reload() {
+    let path = PathBuf::from("test_server_reload.conf");
+    std::fs::write(
+        &path,
+        "# Started with these\nquery_timeout = 5000\npage_size = 4096\n",
+    )
+    .unwrap();
+    let server = TestServer::start_with(
+        "test_server_reload.db",
+        "test_server_reload.wal",
+        ServerConfig {
+            config_file: Some(ConfigFile::read(&path).unwrap()),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let url = server.url.clone();
+    let rows = |body: &str| serde_json::from_str::<Value>(body).unwrap()["rows"].clone();
+    let reload = |client: Client| {
+        let url = url.clone();
+        async move {
+            let resp = client.post(format!("{}/reload", url)).send().await.unwrap();
+            (resp.status(), resp.text().await.unwrap())
+        }
+    };
+    let (_, body) = server.query("SHOW query_timeout;").await;
+    assert_eq!(rows(&body), json!([["5000"]]));
+
+    // Only what the file changes is reported, and a key that is only read
+    // on start is reported as needing a restart rather than applied.
+    std::fs::write(
+        &path,
+        "query_timeout = 5000\nrate_limit = 1\nlog_level = engine=debug\npage_size = 8192\n",
+    )
+    .unwrap();
+    let (status, body) = reload(server.client.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let reloaded: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        reloaded["changed"],
+        json!([
+            {"setting": "rate_limit", "from": "0", "to": "1"},
+            {"setting": "log_level", "from": "info", "to": "engine=debug"}
+        ])
+    );
+    assert_eq!(reloaded["needs_restart"], json!(["page_size"]));
+    let (_, body) = server.query("SHOW rate_limit;").await;
+    assert_eq!(rows(&body), json!([["1"]]));
+    // The new rate holds from the next request on.
+    let (status, _) = server.query("SHOW rate_limit;").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    std::fs::write(&path, "rate_limit = 0\n").unwrap();
+    let (status, body) = reload(server.client.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // A file with a mistake in it changes nothing.
+    std::fs::write(&path, "query_timeout = 6000\nrate_limit = soon\n").unwrap();
+    let (status, body) = reload(server.client.clone()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert!(body.contains("line 2"), "{}", body);
+    std::fs::write(&path, "query_timeout = 6000\nresult_cache = on\n").unwrap();
+    let (status, body) = reload(server.client.clone()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert!(body.contains("no result cache"), "{}", body);
+    let (_, body) = server.query("SHOW query_timeout;").await;
+    assert_eq!(rows(&body), json!([["5000"]]));
+
+    server.query("CREATE USER bob PASSWORD 'pw';").await;
+    let bob = login(&url, "bob", "pw").await.unwrap();
+    let (status, _) = reload(bob).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    remove_file(&path).unwrap();
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_tagged_statements_are_audited() {
+    let path = PathBuf::from("test_server_audit.log");
+    let _ = remove_file(&path);
+    let server = TestServer::start_with(
+        "test_server_audit.db",
+        "test_server_audit.wal",
+        ServerConfig {
+            audit_log: Some(path.clone()),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let url = server.url.clone();
+    let (status, _) = server.query("CREATE TABLE t (id INT);").await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The login's tag goes on every statement of the session, unless the
+    // statement brings its own.
+    let billing = Client::builder().cookie_store(true).build().unwrap();
+    let resp = billing
+        .post(format!("{}/login", url))
+        .json(&json!({ "user": "admin", "pass": "password", "application_name": "billing" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let (status, _) = query_as(&billing, &url, "INSERT INTO t (id) VALUES (1);").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = query_as(&billing, &url, "SELECT id FROM t;").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = query_as(&billing, &url, "INSERT INTO missing (id) VALUES (1);").await;
+    assert!(!status.is_success(), "{}", status);
+    let resp = billing
+        .post(format!("{}/query", url))
+        .json(&json!({ "sql": "INSERT INTO t (id) VALUES (2);", "tag": "nightly" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = billing
+        .post(format!("{}/batch", url))
+        .json(&json!({ "statements": ["INSERT INTO t (id) VALUES (3);", "SELECT id FROM t;"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let queries: Value = serde_json::from_str(&server.get("/debug/queries").await).unwrap();
+    let tags: Vec<&Value> = queries["finished"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|q| &q["tag"])
+        .collect();
+    assert_eq!(tags[..2], [&json!("nightly"), &json!("billing")]);
+
+    // The log is written behind the statements' backs, so it may take a
+    // moment to catch up. The SELECTs are not in it.
+    let entries = loop {
+        let text = std::fs::read_to_string(&path).unwrap_or_default();
+        let entries: Vec<Value> = text.lines().map(|line| line.parse().unwrap()).collect();
+        if entries.len() >= 5 {
+            break entries;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    let summary: Vec<Value> = entries
+        .iter()
+        .map(|e| json!([e["tag"], e["kind"], e["objects"], e["outcome"]]))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            json!([null, "ddl", ["T"], "ok"]),
+            json!(["billing", "insert", ["T"], "ok"]),
+            json!(["billing", "insert", ["MISSING"], "failed"]),
+            json!(["nightly", "insert", ["T"], "ok"]),
+            json!(["billing", "insert", ["T"], "ok"]),
+        ]
+    );
+    assert!(entries.iter().all(|e| e["user"] == "admin"));
+    assert!(entries[2]["error"].as_str().is_some());
+    remove_file(&path).unwrap();
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_search_path_picks_between_tables_of_the_same_name() {
+    let server =
+        TestServer::start("test_server_search_path.db", "test_server_search_path.wal").await;
+    let url = server.url.clone();
+    for sql in [
+        "CREATE SCHEMA app1;",
+        "CREATE SCHEMA app2;",
+        "CREATE TABLE users (id INT);",
+        "CREATE TABLE app1.users (id INT);",
+        "CREATE TABLE app2.users (id INT);",
+        "INSERT INTO users (id) VALUES (0);",
+        "INSERT INTO app1.users (id) VALUES (1);",
+        "INSERT INTO app2.users (id) VALUES (2);",
+    ] {
+        let (status, body) = server.query(sql).await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", sql, body);
+    }
+    let ids = |id: i64| {
+        format!(
+            r#"{{"columns":["ID"],"rows":[[{}]],"row_count":1,"tx_status":"idle"}}"#,
+            id
+        )
+    };
+
+    // With both schemas on the path, the first to have the name wins.
+    let alice = login(&url, "admin", "password").await.unwrap();
+    let bob = login(&url, "admin", "password").await.unwrap();
+    query_as(&alice, &url, "SET search_path = app1, app2;").await;
+    query_as(&bob, &url, "SET search_path = app2, app1, public;").await;
+    let (_, body) = query_as(&alice, &url, "SELECT id FROM users;").await;
+    assert_eq!(body, ids(1));
+    let (_, body) = query_as(&bob, &url, "SELECT id FROM users;").await;
+    assert_eq!(body, ids(2));
+    let (_, body) = query_as(&alice, &url, "SHOW search_path;").await;
+    assert!(body.contains("APP1, APP2"), "{}", body);
+    // A schema named outright is not looked for on the path.
+    let (_, body) = query_as(&alice, &url, "SELECT id FROM public.users;").await;
+    assert_eq!(body, ids(0));
+    let (_, body) = server.query("SELECT id FROM users;").await;
+    assert_eq!(body, ids(0));
+
+    // Unqualified DDL goes to the first schema on the path.
+    query_as(&alice, &url, "CREATE TABLE orders (id INT);").await;
+    let (_, body) = server.query("SHOW TABLES IN app1;").await;
+    assert!(body.contains(r#"["ORDERS",0,0,0,"table",0,0,0]"#), "{}", body);
+    let (status, body) = server.query("SELECT id FROM orders;").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("Unknown table 'ORDERS'"), "{}", body);
+    let (status, body) = query_as(&alice, &url, "SELECT id FROM nowhere.orders;").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("NOWHERE.ORDERS"), "{}", body);
+
+    // Dropping the schema found first uncovers the next one.
+    let (status, body) = server.query("DROP SCHEMA app1;").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(body.contains("CASCADE"), "{}", body);
+    let (status, _) = server.query("DROP SCHEMA app1 CASCADE;").await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = query_as(&alice, &url, "SELECT id FROM users;").await;
+    assert_eq!(body, ids(2));
+    server.stop();
+}
+
+#[tokio::test]
+async fn test_client_follows_and_restores_the_search_path() {
+    let config = ServerConfig {
+        session_ttl: Some(Duration::from_millis(500)),
+        ..ServerConfig::default()
+    };
+    let server = TestServer::start_with(
+        "test_server_client_path.db",
+        "test_server_client_path.wal",
+        config,
+    )
+    .await;
+    for sql in [
+        "CREATE SCHEMA app1;",
+        "CREATE TABLE app1.users (id INT);",
+        "INSERT INTO app1.users (id) VALUES (1);",
+    ] {
+        assert_eq!(server.query(sql).await.0, StatusCode::OK);
+    }
+    let resp = server
+        .client
+        .post(format!("{}/query", server.url))
+        .json(&json!({ "sql": "SET search_path = app1, app2;" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()[SEARCH_PATH_HEADER], "APP1,APP2");
+
+    let client = SqlClient::new(&server.url);
+    assert_eq!(client.search_path(), None);
+    client.login("admin", "password").await.unwrap();
+    assert_eq!(client.search_path(), Some(vec!["PUBLIC".to_string()]));
+    client
+        .query("SET search_path = app1, public;")
+        .await
+        .unwrap();
+    let path = Some(vec!["APP1".to_string(), "PUBLIC".to_string()]);
+    assert_eq!(client.search_path(), path);
+
+    // The session expires; a new one starts on the default path until the
+    // client sets the old one again.
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    let err = client.query("SELECT id FROM users;").await.unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::Unauthorized(_))
+        ),
+        "{:#}",
+        err
+    );
+    client.reconnect().await.unwrap();
+    assert_eq!(client.search_path(), path);
+    let result = client.query("SELECT id FROM users;").await.unwrap();
+    assert_eq!(result.rows, vec![vec![DbValue::Int(1)]]);
+    assert!(SqlClient::new(&server.url).reconnect().await.is_err());
+    server.stop();
+}
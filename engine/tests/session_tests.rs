@@ -0,0 +1,143 @@
+use engine::net::session::SessionManager;
+use engine::net::settings::{Setting, SettingValue};
+use engine::storage::atomic_file::atomic_remove;
+use engine::storage::storage::{Collation, ColumnInfo, DataType, Storage};
+use engine::testing::run;
+use engine::tx::lock_manager::{LockManager, LockMode, Resource};
+use engine::tx::log_manager::{LogManager, Manifest, segment_path};
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::time::{Instant, advance};
+
+#[tokio::test(start_paused = true)]
+async fn test_idle_transaction_is_aborted() {
+    let (db, wal_path) = ("test_session_idle.db", "test_session_idle.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let locks = Arc::new(LockManager::new());
+    let mut storage = Storage::new(db, 4096, 16).unwrap();
+    storage.attach_wal(wal.clone());
+    storage.attach_locks(locks.clone());
+    storage
+        .create_table(
+            "T".into(),
+            vec![ColumnInfo {
+                name: "ID".into(),
+                data_type: DataType::Int,
+                collation: Collation::Binary,
+            }],
+        )
+        .unwrap();
+    let txns = storage.txns.clone();
+    let storage = RwLock::new(storage);
+    let sessions = SessionManager::new().with_idle_timeout(Duration::from_secs(30));
+
+    // BEGIN; INSERT ...; and then the client goes quiet.
+    let tx = txns.begin();
+    wal.log_begin(tx).unwrap();
+    sessions.begin("s", tx, txns.snapshot(Some(tx))).unwrap();
+    let mut open = sessions.take("s").unwrap();
+    let table = Resource::Table("T".into());
+    locks
+        .lock(tx, table.clone(), LockMode::IntentionExclusive)
+        .await
+        .unwrap();
+    {
+        let mut storage = storage.write().await;
+        storage.set_transaction(Some(tx));
+        run(&mut storage, "INSERT INTO t (id) VALUES (1), (2);").unwrap();
+        open.pending_rows = std::mem::take(&mut storage.pending_rows);
+        storage.set_transaction(None);
+    }
+    sessions.put_back("s", open);
+
+    advance(Duration::from_secs(20)).await;
+    assert!(
+        sessions
+            .abort_expired(&storage, &wal, &locks)
+            .await
+            .is_empty()
+    );
+    assert!(sessions.take_abort_notice("s").is_none());
+
+    advance(Duration::from_secs(15)).await;
+    assert_eq!(
+        sessions.abort_expired(&storage, &wal, &locks).await,
+        vec![tx]
+    );
+    // Its rows are undone, so nothing has to remember that it aborted.
+    assert_eq!(txns.active_count(), 0);
+    assert!(txns.snapshot(None).aborted.is_empty());
+    assert!(!sessions.in_transaction("s"));
+    assert!(storage.write().await.scan_table("T").unwrap().is_empty());
+    locks.try_lock(tx + 1, table, LockMode::Exclusive).unwrap();
+
+    let notice = sessions.take_abort_notice("s").unwrap();
+    assert!(notice.contains("idle timeout"), "{}", notice);
+    assert!(sessions.take_abort_notice("s").is_none());
+
+    remove_file(db).unwrap();
+    remove_file(segment_path(Path::new(wal_path), 1)).unwrap();
+    atomic_remove(&Manifest::path(Path::new(wal_path))).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_max_age_caps_busy_transaction() {
+    let sessions = SessionManager::new()
+        .with_idle_timeout(Duration::from_secs(30))
+        .with_max_age(Duration::from_secs(60));
+    let txns = engine::tx::mvcc::TxStatusTable::new();
+    let tx = txns.begin();
+    sessions.begin("s", tx, txns.snapshot(Some(tx))).unwrap();
+    assert!(sessions.begin("s", tx + 1, txns.snapshot(None)).is_err());
+
+    // A statement every 20 seconds keeps it clear of the idle timeout...
+    for _ in 0..2 {
+        advance(Duration::from_secs(20)).await;
+        let open = sessions.take("s").unwrap();
+        sessions.put_back("s", open);
+        assert!(sessions.expire(Instant::now()).is_empty());
+    }
+    // ...but not of the age cap.
+    advance(Duration::from_secs(20)).await;
+    let expired = sessions.expire(Instant::now());
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].tx_id, tx);
+    let notice = sessions.take_abort_notice("s").unwrap();
+    assert!(notice.contains("max transaction age"), "{}", notice);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_expired_login_forgets_session() {
+    let sessions = SessionManager::new();
+    let txns = engine::tx::mvcc::TxStatusTable::new();
+    sessions.open("s", Instant::now() + Duration::from_secs(60));
+    sessions.set(
+        "s",
+        Setting::LockTimeout,
+        SettingValue::Duration(Duration::from_secs(1)),
+    );
+    let tx = txns.begin();
+    sessions.begin("s", tx, txns.snapshot(Some(tx))).unwrap();
+    // A WebSocket session is not tied to a login.
+    sessions
+        .begin("socket", tx + 1, txns.snapshot(Some(tx + 1)))
+        .unwrap();
+
+    advance(Duration::from_secs(59)).await;
+    let (open, temp) = sessions.expire_logins(Instant::now());
+    assert!(open.is_empty() && temp.is_empty());
+    assert!(!sessions.settings("s").is_empty());
+
+    advance(Duration::from_secs(1)).await;
+    let (open, temp) = sessions.expire_logins(Instant::now());
+    assert_eq!(open.len(), 1);
+    assert_eq!(open[0].tx_id, tx);
+    assert!(temp.is_empty());
+    assert!(!sessions.in_transaction("s"));
+    assert!(sessions.settings("s").is_empty());
+    assert!(sessions.take_abort_notice("s").is_none());
+    assert!(sessions.in_transaction("socket"));
+}
@@ -0,0 +1,459 @@
+use engine::cli::completion::{SchemaCache, SqlHelper};
+use engine::cli::shell::{
+    Format, MetaCommand, PASSWORD_FILE, PageRenderer, ScriptStatement, StatementBuffer,
+    check_syntax, describe_table, find_password, prompt, split_script, stats_line, stored_login,
+};
+use engine::net::auth::Secret;
+use engine::net::client::{DbValue, QueryResult, QueryStats};
+use engine::net::row::Schema;
+use engine::net::schema::{ColumnSchema, IndexSchema, TableSchema};
+use engine::net::session::TxState;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[test]
+fn test_statement_buffer_joins_lines_until_semicolon() {
+    let mut buffer = StatementBuffer::new();
+    assert!(buffer.push_line("CREATE TABLE users (").is_empty());
+    assert!(!buffer.is_empty());
+    assert!(buffer.push_line("  id INT,").is_empty());
+    let done = buffer.push_line("  name TEXT);");
+    assert_eq!(done, vec!["CREATE TABLE users (\n  id INT,\n  name TEXT);"]);
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_statement_buffer_ignores_semicolons_in_strings_and_comments() {
+    let mut buffer = StatementBuffer::new();
+    assert!(buffer.push_line("INSERT INTO t VALUES ('a;").is_empty());
+    assert!(buffer.push_line("b'); -- done;").len() == 1);
+    assert!(buffer.is_empty());
+
+    assert!(buffer.push_line("SELECT * FROM t -- not yet;").is_empty());
+    assert_eq!(
+        buffer.push_line(";"),
+        vec!["SELECT * FROM t -- not yet;\n;"]
+    );
+}
+
+#[test]
+fn test_statement_buffer_splits_statements_and_clears() {
+    let mut buffer = StatementBuffer::new();
+    let done = buffer.push_line("SELECT 1; SELECT 2; SELECT");
+    assert_eq!(done, vec!["SELECT 1;", "SELECT 2;"]);
+    assert!(!buffer.is_empty());
+    buffer.clear();
+    assert!(buffer.is_empty());
+    assert!(buffer.push_line("   ").is_empty());
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_table_format_aligns_columns_under_a_header() {
+    let result = QueryResult::new(
+        Schema::new(vec!["ID".into(), "NAME".into()]),
+        vec![
+            vec![DbValue::Int(1), DbValue::from("alice")],
+            vec![DbValue::Int(42), DbValue::Null],
+        ],
+    );
+    let table = Format::Table { max_width: 40 }.render(&result, Some(Duration::from_millis(13)));
+    let expected = [
+        "┌────┬────────┐",
+        "│ ID │ NAME   │",
+        "├────┼────────┤",
+        "│  1 │ alice  │",
+        "│ 42 │ (null) │",
+        "└────┴────────┘",
+        "(2 rows, 13 ms)",
+    ];
+    assert_eq!(table, expected.join("\n") + "\n");
+
+    let plain = Format::Plain.render(&result, None);
+    assert_eq!(plain, "1 | alice\n42 | NULL\n");
+}
+
+#[test]
+fn test_table_format_truncates_long_values() {
+    let result = QueryResult::new(
+        Schema::new(vec!["NOTE".into()]),
+        vec![vec![DbValue::from("a rather long note\nover two lines")]],
+    );
+    let table = Format::Table { max_width: 10 }.render(&result, Some(Duration::ZERO));
+    assert!(table.contains("│ a rather … │"), "{}", table);
+    assert!(table.ends_with("(1 row, 0 ms)\n"));
+
+    let empty = Format::Table { max_width: 10 }.render(&QueryResult::default(), None);
+    assert_eq!(empty, "OK\n");
+    let inserted = QueryResult {
+        affected: Some(3),
+        ..QueryResult::default()
+    };
+    let table = Format::Table { max_width: 10 }.render(&inserted, Some(Duration::ZERO));
+    assert_eq!(table, "OK, 3 rows affected (0 ms)\n");
+}
+
+#[test]
+fn test_prompt_shows_login_server_and_schema() {
+    let path = ["APP1".to_string(), "PUBLIC".to_string()];
+    assert_eq!(
+        prompt("admin", "http://127.0.0.1:3000", Some(&path), TxState::Idle),
+        "admin@127.0.0.1:3000/app1 sql> "
+    );
+    assert_eq!(
+        prompt("alice", "https://db.example.com/", None, TxState::Idle),
+        "alice@db.example.com sql> "
+    );
+    assert_eq!(
+        prompt("alice", "https://db.example.com/", None, TxState::Open(7)),
+        "alice@db.example.com sql*> "
+    );
+    assert_eq!(
+        prompt(
+            "admin",
+            "http://127.0.0.1:3000",
+            Some(&path),
+            TxState::Aborted(7)
+        ),
+        "admin@127.0.0.1:3000/app1 sql!> "
+    );
+}
+
+#[test]
+fn test_stats_line_shows_what_the_server_reported() {
+    let stats = QueryStats {
+        millis: 12,
+        rows: 1,
+        pages_read: 3,
+        pages_cached: 40,
+        lock_wait_millis: 5,
+    };
+    assert_eq!(
+        stats_line(&stats),
+        "Server: 12 ms, 1 row, 3 pages read, 40 cached, 5 ms waiting for locks\n"
+    );
+}
+
+#[test]
+fn test_meta_commands_parse() {
+    assert_eq!(MetaCommand::parse("\\dt").unwrap(), MetaCommand::ListTables);
+    assert_eq!(MetaCommand::parse("\\d").unwrap(), MetaCommand::ListTables);
+    assert_eq!(
+        MetaCommand::parse("  \\d users;").unwrap(),
+        MetaCommand::Describe("users".into())
+    );
+    assert_eq!(
+        MetaCommand::parse("\\i schema.sql").unwrap(),
+        MetaCommand::Include("schema.sql".into())
+    );
+    assert!(MetaCommand::parse("\\i").is_err());
+    assert_eq!(
+        MetaCommand::parse("\\c").unwrap(),
+        MetaCommand::Connect(None)
+    );
+    assert_eq!(
+        MetaCommand::parse("\\c app1,public;").unwrap(),
+        MetaCommand::Connect(Some("app1,public".into()))
+    );
+    assert_eq!(MetaCommand::parse("\\timing").unwrap(), MetaCommand::Timing);
+    assert_eq!(
+        MetaCommand::parse("\\format csv").unwrap(),
+        MetaCommand::Format(Some("csv".into()))
+    );
+    assert_eq!(
+        MetaCommand::parse("\\o out.csv").unwrap(),
+        MetaCommand::Output(Some("out.csv".into()))
+    );
+    assert_eq!(
+        MetaCommand::parse("\\o").unwrap(),
+        MetaCommand::Output(None)
+    );
+    assert_eq!(
+        MetaCommand::parse("\\import users users.csv --resume").unwrap(),
+        MetaCommand::Import {
+            table: "users".into(),
+            path: "users.csv".into(),
+            resume: true,
+        }
+    );
+    assert!(MetaCommand::parse("\\import users").is_err());
+    assert_eq!(MetaCommand::parse("\\q").unwrap(), MetaCommand::Quit);
+    assert_eq!(MetaCommand::parse("\\help").unwrap(), MetaCommand::Help);
+
+    let unknown = MetaCommand::parse("\\x").unwrap_err().to_string();
+    assert!(unknown.contains("Unknown command \\x"), "{}", unknown);
+    assert!(MetaCommand::parse("\\q now").is_err());
+    assert!(MetaCommand::parse("\\d a b").is_err());
+}
+
+#[test]
+fn test_describe_table_lists_columns_and_indexes() {
+    let table = TableSchema {
+        name: "USERS".into(),
+        row_count: 3,
+        page_count: 1,
+        bytes: 120,
+        columns: vec![
+            ColumnSchema {
+                name: "ID".into(),
+                data_type: "INT".into(),
+                collation: None,
+            },
+            ColumnSchema {
+                name: "NAME".into(),
+                data_type: "TEXT".into(),
+                collation: Some("NOCASE".into()),
+            },
+        ],
+        indexes: vec![IndexSchema {
+            name: "USERS_ID".into(),
+            table: "USERS".into(),
+            columns: vec!["ID".into()],
+            kind: "btree".into(),
+            root_page: 4,
+        }],
+        foreign_keys: Vec::new(),
+        checks: Vec::new(),
+    };
+    let described = describe_table(&table, Format::Plain);
+    assert_eq!(
+        described,
+        "Table USERS\nID | INT\nNAME | TEXT COLLATE NOCASE\nIndexes:\n    USERS_ID btree (ID)\n"
+    );
+}
+
+#[test]
+fn test_split_script_keeps_statement_lines() {
+    let script = [
+        "-- schema",
+        "CREATE TABLE t (",
+        "  id INT,",
+        "  note TEXT);",
+        "",
+        "INSERT INTO t (id, note) VALUES (1, 'a; b'); INSERT INTO t (id, note)",
+        "  VALUES (2, 'c');",
+    ]
+    .join("\n");
+    let statements = split_script(&script).unwrap();
+    let lines: Vec<usize> = statements.iter().map(|s| s.line).collect();
+    assert_eq!(lines, [2, 6, 6]);
+    assert_eq!(
+        statements[1],
+        ScriptStatement {
+            line: 6,
+            sql: "INSERT INTO t (id, note) VALUES (1, 'a; b');".into(),
+        }
+    );
+
+    let err = split_script("SELECT 1;\nSELECT id\nFROM t").unwrap_err();
+    assert!(err.to_string().contains("line 2"), "{}", err);
+}
+
+#[test]
+fn test_check_syntax_reports_every_error_in_a_script() {
+    let script = [
+        "CREATE TABLE t (id INT);",
+        "SELEC id FROM t;",
+        "INSERT INTO t (id) VALUES (1);",
+        "SELECT id",
+        "  FROM t WHERE id = ;",
+    ]
+    .join("\n");
+    let err = check_syntax(Some("load.sql"), &script).unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.starts_with("2 syntax errors, nothing was run:\nload.sql:2:1: "),
+        "{}",
+        message
+    );
+    assert!(message.contains("\nload.sql:5:21: Expected an expression"), "{}", message);
+    assert!(check_syntax(None, "SELECT id FROM t;").is_ok());
+}
+
+#[test]
+fn test_csv_and_json_formats() {
+    let result = QueryResult::new(
+        Schema::new(vec!["ID".into(), "NOTE".into()]),
+        vec![
+            vec![DbValue::Int(1), DbValue::from("plain")],
+            vec![DbValue::Int(2), DbValue::from("with, comma and \"quotes\"")],
+            vec![DbValue::Int(3), DbValue::Null],
+        ],
+    );
+    assert_eq!(
+        Format::Csv.render(&result, Some(Duration::ZERO)),
+        "ID,NOTE\n1,plain\n2,\"with, comma and \"\"quotes\"\"\"\n3,\n"
+    );
+
+    let json = Format::Json.render(&result, None);
+    assert!(
+        json.starts_with("[\n  {\"ID\":1,\"NOTE\":\"plain\"},"),
+        "{}",
+        json
+    );
+    let parsed: Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed[1]["NOTE"], "with, comma and \"quotes\"");
+    assert_eq!(parsed[2], json!({ "ID": 3, "NOTE": null }));
+
+    let empty = QueryResult::default();
+    assert_eq!(Format::Csv.render(&empty, None), "");
+    assert_eq!(Format::Json.render(&empty, None), "[]\n");
+    assert_eq!(Format::parse("json", 40), Some(Format::Json));
+    assert_eq!(Format::parse("yaml", 40), None);
+}
+
+fn helper() -> SqlHelper {
+    let columns = HashMap::from([
+        (
+            "USERS".to_string(),
+            vec!["ID".to_string(), "NAME".to_string()],
+        ),
+        (
+            "ORDERS".to_string(),
+            vec!["ID".to_string(), "USER_ID".to_string()],
+        ),
+    ]);
+    SqlHelper {
+        schema: SchemaCache { columns },
+        pending: String::new(),
+        masking: false,
+    }
+}
+
+#[test]
+fn test_completion_offers_keywords_tables_and_columns() {
+    let mut helper = helper();
+    assert_eq!(helper.candidates("SEL", 3), (0, vec!["SELECT".to_string()]));
+    // Candidates follow the case of what was typed.
+    assert_eq!(helper.candidates("sel", 3), (0, vec!["select".to_string()]));
+
+    // After FROM only tables fit.
+    let line = "SELECT id FROM u";
+    assert_eq!(
+        helper.candidates(line, line.len()),
+        (15, vec!["users".to_string()])
+    );
+    assert_eq!(helper.candidates("SELECT * FROM O", 15).1, ["ORDERS"]);
+
+    // Columns come from the table the statement names, even after the cursor.
+    let line = "SELECT na FROM users;";
+    assert_eq!(helper.candidates(line, 9), (7, vec!["name".to_string()]));
+    assert!(helper.candidates("SELECT na", 9).1.is_empty());
+    // Keywords the parser has no clause for yet are still offered.
+    assert_eq!(
+        helper.candidates("SELECT * FROM users ORD", 23).1,
+        ["ORDER", "ORDERS"]
+    );
+
+    // The lines typed before count too.
+    helper.pending = "SELECT user_id\nFROM orders".to_string();
+    assert_eq!(
+        helper.candidates("WHERE US", 8).1,
+        ["USER", "USERS", "USER_ID", "USING"]
+    );
+    assert!(helper.candidates("WHERE ", 6).1.is_empty());
+}
+
+#[test]
+fn test_password_file_lines_match_server_and_user() {
+    let text = "\
+# comments and blank lines are skipped
+
+http://db:3000 alice first secret
+* * anywhere
+http://other:3000 carol other
+";
+    let url = "http://db:3000";
+    let secret = |s: &str| Secret(s.to_string());
+    // The password runs to the end of the line.
+    assert_eq!(
+        find_password(text, url, Some("alice")),
+        Some((Some("alice".to_string()), secret("first secret")))
+    );
+    // With no user given, the file names one.
+    assert_eq!(
+        find_password(text, url, None),
+        Some((Some("alice".to_string()), secret("first secret")))
+    );
+    assert_eq!(
+        find_password(text, url, Some("bob")),
+        Some((None, secret("anywhere")))
+    );
+    assert_eq!(
+        find_password(text, "http://other:3000", None),
+        Some((Some("carol".to_string()), secret("other")))
+    );
+    assert_eq!(find_password("* * pw", url, None), None);
+}
+
+#[test]
+fn test_stored_login_reads_env_then_password_file() {
+    let home = std::env::temp_dir().join(format!("mydb_passfile_{}", std::process::id()));
+    std::fs::create_dir_all(&home).unwrap();
+    let home_str = home.to_str().unwrap().to_string();
+    let url = "http://db:3000";
+
+    let env = |key: &str| (key == "MYDB_PASSWORD").then(|| "from env".to_string());
+    let (user, password) = stored_login(url, Some("alice".into()), env).unwrap();
+    assert_eq!(user.as_deref(), Some("alice"));
+    assert_eq!(password, Some(Secret("from env".to_string())));
+
+    // Nothing stored leaves both to be prompted for.
+    let env = |key: &str| (key == "HOME").then(|| home_str.clone());
+    assert_eq!(stored_login(url, None, env).unwrap(), (None, None));
+
+    let path = home.join(PASSWORD_FILE);
+    std::fs::write(&path, "http://db:3000/ alice pw\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Others being able to read it is refused outright.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let err = stored_login(url, None, env).unwrap_err().to_string();
+        assert!(err.contains("chmod 600"), "{}", err);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let (user, password) = stored_login(url, None, env).unwrap();
+    assert_eq!(user.as_deref(), Some("alice"));
+    assert_eq!(password, Some(Secret("pw".to_string())));
+    assert_eq!(
+        stored_login(url, Some("bob".into()), env).unwrap(),
+        (Some("bob".to_string()), None)
+    );
+    std::fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn test_pages_render_like_the_whole_result() {
+    let whole = QueryResult::new(
+        Schema::new(vec!["ID".into(), "NOTE".into()]),
+        (1..=5)
+            .map(|i| vec![DbValue::Int(i), DbValue::from(format!("n{}", i))])
+            .collect(),
+    );
+    let page = |rows: std::ops::Range<usize>| QueryResult {
+        rows: whole.rows[rows].to_vec(),
+        ..whole.clone()
+    };
+    for format in [Format::Plain, Format::Csv, Format::Json] {
+        let mut renderer = PageRenderer::new(format);
+        let mut paged = renderer.page(&page(0..2));
+        paged += &renderer.page(&page(2..4));
+        paged += &renderer.page(&page(4..5));
+        paged += &renderer.finish(&whole, None);
+        assert_eq!(paged, format.render(&whole, None), "{}", format.name());
+    }
+
+    // Each page of a table is boxed on its own; the footer counts them all.
+    let mut renderer = PageRenderer::new(Format::Table { max_width: 40 });
+    let first = renderer.page(&page(0..3));
+    let second = renderer.page(&page(3..5));
+    assert!(
+        first.starts_with("┌") && first.ends_with("┘\n"),
+        "{}",
+        first
+    );
+    assert!(second.contains("│  4 │ n4   │"), "{}", second);
+    assert_eq!(renderer.finish(&whole, None), "(5 rows)\n");
+}
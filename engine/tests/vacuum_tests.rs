@@ -0,0 +1,187 @@
+use engine::database::Database;
+use engine::net::client::DbValue;
+use engine::query::binder::Value;
+use engine::storage::check::check;
+use engine::storage::record::Page;
+use engine::storage::storage::{Storage, VacuumReport};
+use engine::testing::{fresh_dir, run, table_with_rows};
+use engine::tx::mvcc::RowHeader;
+use std::fs::remove_file;
+
+fn free_space(storage: &Storage, page: u64) -> usize {
+    let data = storage.buffer_pool.read_page(page).unwrap();
+    Page::from_bytes(data, storage.page_size).free_space()
+}
+
+fn count(storage: &mut Storage) -> usize {
+    run(storage, "SELECT id FROM t;").unwrap().len()
+}
+
+fn ids(db: &mut Database, sql: &str) -> Vec<i64> {
+    let mut ids: Vec<i64> = db
+        .execute(sql)
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| match row[0] {
+            DbValue::Int(i) => i,
+            _ => panic!("expected int"),
+        })
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_delete_leaves_tombstones_that_scans_skip() {
+    let path = "test_vacuum_tombstones.db";
+    let mut storage = table_with_rows(path, 20);
+    let first = storage.catalog.get_table("T").unwrap().records[0];
+
+    let tx = storage.txns.begin();
+    storage.set_transaction(Some(tx));
+    storage.take_snapshot();
+    assert_eq!(
+        run(&mut storage, "DELETE FROM t WHERE id < 5;").unwrap(),
+        vec![vec![Value::Int(5)]]
+    );
+    assert_eq!(count(&mut storage), 15);
+
+    // The row stays where it was, marked by the transaction that deleted it.
+    let header = RowHeader::read(&storage.fetch(first).unwrap());
+    assert!(header.tombstone);
+    assert_eq!(header.xmax, tx);
+    let stats = &storage.catalog.get_table("T").unwrap().stats;
+    assert_eq!((stats.rows, stats.dead_rows), (20, 5));
+    assert_eq!(storage.free_list.dead_rows(first.0), Some(5));
+
+    storage.txns.commit(tx);
+    storage.set_transaction(None);
+    assert_eq!(count(&mut storage), 15);
+    assert!(check(&mut storage).unwrap().is_ok());
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_vacuum_waits_for_the_oldest_snapshot() {
+    let path = "test_vacuum_horizon.db";
+    let mut storage = table_with_rows(path, 20);
+    let page = storage.catalog.get_table("T").unwrap().records[0].0;
+    let free_before = free_space(&storage, page);
+
+    let reader = storage.txns.begin();
+    let snapshot = storage.txns.snapshot(Some(reader));
+    let deleter = storage.txns.begin();
+    storage.set_transaction(Some(deleter));
+    storage.take_snapshot();
+    run(&mut storage, "DELETE FROM t WHERE id < 5;").unwrap();
+    storage.txns.commit(deleter);
+    storage.set_transaction(None);
+
+    // The reader started before the delete committed, so it still sees them.
+    assert_eq!(storage.vacuum("T").unwrap(), VacuumReport::default());
+    let records = storage.catalog.get_table("T").unwrap().records.clone();
+    let visible = records
+        .iter()
+        .filter(|&&rid| snapshot.is_visible(&RowHeader::read(&storage.fetch(rid).unwrap())))
+        .count();
+    assert_eq!(visible, 20);
+
+    storage.txns.commit(reader);
+    assert_eq!(
+        storage.vacuum("T").unwrap(),
+        VacuumReport {
+            rows: 5,
+            pages: 1,
+            skipped: 0
+        }
+    );
+    let table = storage.catalog.get_table("T").unwrap();
+    assert_eq!(table.records.len(), 15);
+    assert_eq!((table.stats.rows, table.stats.dead_rows), (15, 0));
+    assert_eq!(storage.free_list.dead_rows(page), Some(0));
+    assert!(free_space(&storage, page) > free_before);
+    assert!(check(&mut storage).unwrap().is_ok());
+
+    // Nothing is left to reclaim, and a new row takes a freed slot.
+    assert_eq!(storage.vacuum("T").unwrap(), VacuumReport::default());
+    let names = vec!["ID".to_string(), "NAME".to_string()];
+    let rid = storage
+        .insert_row(
+            "T",
+            &names,
+            vec![Value::Int(99), Value::String("new".into())],
+        )
+        .unwrap();
+    assert_eq!(rid, (page, 0));
+    assert_eq!(count(&mut storage), 16);
+    remove_file(path).unwrap();
+}
+
+#[test]
+fn test_delete_and_vacuum_statements() {
+    let dir = fresh_dir("vacuum_statements");
+    let mut db = Database::open(&dir).unwrap();
+    db.execute("CREATE TABLE t (id INT, name TEXT);").unwrap();
+    db.execute("CREATE INDEX t_id ON t (id);").unwrap();
+    for id in 0..200 {
+        db.execute(&format!(
+            "INSERT INTO t (id, name) VALUES ({}, 'n{}');",
+            id, id
+        ))
+        .unwrap();
+    }
+
+    // A rolled-back delete leaves every row, and the counts, as they were.
+    db.execute("BEGIN;").unwrap();
+    let deleted = db.execute("DELETE FROM t WHERE id >= 100;").unwrap();
+    assert_eq!(deleted.rows[0].get::<i64>("deleted").unwrap(), 100);
+    db.execute("ROLLBACK;").unwrap();
+    assert_eq!(ids(&mut db, "SELECT id FROM t;").len(), 200);
+    assert!(db.execute("CHECK;").unwrap().rows.is_empty());
+
+    db.execute("DELETE FROM t WHERE id >= 100;").unwrap();
+    assert_eq!(
+        ids(&mut db, "SELECT id FROM t;"),
+        (0..100).collect::<Vec<_>>()
+    );
+    assert!(ids(&mut db, "SELECT id FROM t WHERE id = 150;").is_empty());
+
+    let report = db.execute("VACUUM t;").unwrap();
+    let row = &report.rows[0];
+    assert_eq!(row.get::<String>("table").unwrap(), "T");
+    assert_eq!(row.get::<i64>("rows").unwrap(), 100);
+    assert!(row.get::<i64>("pages").unwrap() >= 1);
+    assert_eq!(row.get::<i64>("skipped").unwrap(), 0);
+    assert_eq!(
+        db.execute("VACUUM;").unwrap().rows[0]
+            .get::<i64>("rows")
+            .unwrap(),
+        0
+    );
+
+    // The index was rebuilt over the rows that moved.
+    assert_eq!(ids(&mut db, "SELECT id FROM t WHERE id = 50;"), vec![50]);
+    assert!(ids(&mut db, "SELECT id FROM t WHERE id = 150;").is_empty());
+    assert!(db.execute("CHECK;").unwrap().rows.is_empty());
+
+    let plan: Vec<String> = db
+        .execute("EXPLAIN DELETE FROM t WHERE id = 50;")
+        .unwrap()
+        .rows
+        .iter()
+        .map(|row| row.get::<String>("plan").unwrap())
+        .collect();
+    assert!(plan[2].contains("IndexScan on T using T_ID"), "{:?}", plan);
+    assert!(db.execute("EXPLAIN ANALYZE DELETE FROM t;").is_err());
+    assert_eq!(ids(&mut db, "SELECT id FROM t WHERE id = 50;"), vec![50]);
+
+    db.execute("BEGIN;").unwrap();
+    assert!(db.execute("VACUUM t;").is_err());
+    db.execute("ROLLBACK;").unwrap();
+
+    let deleted = db.execute("DELETE FROM t;").unwrap();
+    assert_eq!(deleted.rows[0].get::<i64>("deleted").unwrap(), 100);
+    assert!(ids(&mut db, "SELECT id FROM t;").is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,61 @@
+use engine::query::binder::Value;
+use engine::query::parser::{Expr, Parser, Statement};
+use std::collections::HashSet;
+
+#[test]
+fn test_values_compare_within_and_across_types() {
+    assert_eq!(Value::Int(3), Value::from(3));
+    assert_ne!(Value::Int(1), Value::from("1"));
+    assert!(Value::Int(-5) < Value::Int(2));
+    assert!(Value::from("apple") < Value::from("banana"));
+    // Every INT sorts before every TEXT.
+    assert!(Value::Int(i64::MAX) < Value::from(""));
+
+    let mut values = vec![
+        Value::from("b"),
+        Value::Int(10),
+        Value::from("a"),
+        Value::Int(-1),
+        Value::Int(10),
+    ];
+    values.sort();
+    assert_eq!(
+        values,
+        vec![
+            Value::Int(-1),
+            Value::Int(10),
+            Value::Int(10),
+            Value::from("a"),
+            Value::from("b"),
+        ]
+    );
+    let distinct: HashSet<Value> = values.into_iter().collect();
+    assert_eq!(distinct.len(), 4);
+}
+
+#[test]
+fn test_parser_literals_are_engine_values() {
+    let stmt = Parser::new("INSERT INTO t (id, name) VALUES (-7, 'x');")
+        .unwrap()
+        .parse_statement()
+        .unwrap();
+    let Statement::Insert { rows, .. } = stmt else {
+        panic!("expected an INSERT");
+    };
+    assert_eq!(
+        rows[0],
+        vec![
+            Expr::Literal(Value::Int(-7)),
+            Expr::Literal(Value::from("x"))
+        ]
+    );
+}
+
+#[test]
+fn test_truthiness_and_accessors() {
+    assert!(Value::Int(2).is_truthy() && !Value::Int(0).is_truthy());
+    assert!(Value::from("x").is_truthy() && !Value::from("").is_truthy());
+    assert_eq!(Value::Int(4).as_int(), Some(4));
+    assert_eq!(Value::from("4").as_int(), None);
+    assert_eq!(Value::from("4").type_name(), "TEXT");
+}
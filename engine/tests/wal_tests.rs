@@ -0,0 +1,1215 @@
+use engine::index::bplustree::BPlusTree;
+use engine::index::hash_index::HashIndex;
+use engine::query::binder::Value;
+use engine::storage::atomic_file::atomic_remove;
+use engine::storage::backup::{catalog_path, load_catalog};
+use engine::storage::check::check;
+use engine::storage::record::{Page as RecordPage, RID};
+use engine::storage::storage::{
+    Collation, ColumnInfo, DataType, Grants, IndexKind, Privilege, Storage,
+};
+use engine::tx::log_manager::{
+    ActiveTx, CheckpointPayload, CompensationPayload, DdlPayload, FlushPolicy, LogManager,
+    LogRecordType, Manifest, MasterRecord, UpdatePayload, WAL_HEADER_SIZE, segment_path,
+};
+use engine::tx::recovery_manager::{
+    LsnIndex, RecoveryManager, abort_transaction, checkpoint, compensate, recover_storage,
+    undo_transaction,
+};
+use engine::tx::wal_reader::WalReader;
+use std::collections::HashSet;
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+fn logged_storage(db: &str, wal: &Arc<LogManager>, pool_size: usize) -> Storage {
+    let mut storage = Storage::new(db, 4096, pool_size).unwrap();
+    storage.attach_wal(wal.clone());
+    storage
+        .create_table(
+            "T".into(),
+            vec![
+                ColumnInfo {
+                    name: "ID".into(),
+                    data_type: DataType::Int,
+                    collation: Collation::Binary,
+                },
+                ColumnInfo {
+                    name: "NAME".into(),
+                    data_type: DataType::String,
+                    collation: Collation::Binary,
+                },
+            ],
+        )
+        .unwrap();
+    storage
+}
+
+fn remove_wal(wal_path: &str) {
+    let path = Path::new(wal_path);
+    let manifest = Manifest::read(path).unwrap().unwrap();
+    for segment in manifest.segments() {
+        remove_file(segment_path(path, segment)).unwrap();
+    }
+    atomic_remove(&Manifest::path(path)).unwrap();
+    atomic_remove(&MasterRecord::path(path)).unwrap();
+    atomic_remove(&catalog_path(path)).unwrap();
+}
+
+fn insert_rows(storage: &mut Storage, ids: std::ops::Range<i64>) -> Vec<RID> {
+    let names = vec!["ID".to_string(), "NAME".to_string()];
+    for id in ids {
+        let row = vec![Value::Int(id), Value::String(format!("row-{}", id))];
+        storage.insert_row("T", &names, row).unwrap();
+    }
+    storage.catalog.get_table("T").unwrap().records.clone()
+}
+
+#[test]
+fn test_update_payload_round_trip() {
+    let update = UpdatePayload {
+        page_no: 42,
+        offset: 100,
+        before: vec![0, 0, 0],
+        after: vec![1, 2, 3],
+    };
+    assert_eq!(UpdatePayload::decode(&update.encode()).unwrap(), update);
+    assert!(UpdatePayload::decode(&[0; 13]).is_err());
+    assert!(UpdatePayload::decode(&[0; 4]).is_err());
+}
+
+#[test]
+fn test_compensation_payload_round_trip() {
+    let clr = CompensationPayload {
+        undo_next: 7,
+        update: UpdatePayload {
+            page_no: 3,
+            offset: 64,
+            before: vec![9, 9],
+            after: vec![0, 0],
+        },
+    };
+    assert_eq!(CompensationPayload::decode(&clr.encode()).unwrap(), clr);
+    assert!(CompensationPayload::decode(&[0; 6]).is_err());
+}
+
+#[tokio::test]
+async fn test_committed_rows_survive_crash() {
+    let (db, wal_path) = ("test_wal_crash.db", "test_wal_crash.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let committed = insert_rows(&mut storage, 0..200);
+    wal.log_commit(1).unwrap();
+
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    let all = insert_rows(&mut storage, 200..210);
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+    // Crash: the buffer pool goes away without writing any dirty page.
+    drop(storage);
+    drop(wal);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+
+    let mut storage = storage.write().await;
+    for (id, &rid) in committed.iter().enumerate() {
+        let raw = storage.fetch(rid).unwrap();
+        let row = storage.deserialize_row(&raw).unwrap();
+        assert!(matches!(row[0], Value::Int(v) if v == id as i64));
+        assert!(matches!(&row[1], Value::String(s) if *s == format!("row-{}", id)));
+    }
+    for &rid in &all[committed.len()..] {
+        assert!(storage.fetch(rid).is_err());
+    }
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[test]
+fn test_wal_flushed_before_dirty_page_eviction() {
+    let (db, wal_path) = ("test_wal_evict.db", "test_wal_evict.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 2);
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    insert_rows(&mut storage, 0..500);
+    assert!(wal.flushed_lsn() > 0);
+    assert!(wal.flushed_lsn() <= wal.last_lsn(1).unwrap());
+    for frame in storage.buffer_pool.pool.values() {
+        assert!(frame.lsn > 0);
+    }
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[test]
+fn test_checkpoint_payload_round_trip() {
+    let payload = CheckpointPayload {
+        dirty_pages: vec![(3, 17), (9, 20)],
+        active_txns: vec![ActiveTx {
+            tx_id: 4,
+            last_lsn: 19,
+            first_offset: 512,
+        }],
+        max_tx_id: 6,
+    };
+    assert_eq!(
+        CheckpointPayload::decode(&payload.encode()).unwrap(),
+        payload
+    );
+    assert_eq!(
+        CheckpointPayload::decode(&CheckpointPayload::default().encode()).unwrap(),
+        CheckpointPayload::default()
+    );
+    assert!(CheckpointPayload::decode(&payload.encode()[..20]).is_err());
+}
+
+#[test]
+fn test_ddl_payload_round_trip() {
+    let ddl = DdlPayload::CreateTable {
+        name: "T".into(),
+        columns: vec![ColumnInfo {
+            name: "ID".into(),
+            data_type: DataType::Int,
+            collation: Collation::Binary,
+        }],
+    };
+    assert_eq!(DdlPayload::decode(&ddl.encode()).unwrap(), ddl);
+    assert_eq!(ddl.record_type(), LogRecordType::CreateTable);
+    assert!(DdlPayload::decode(b"{").is_err());
+
+    let mut after = Grants::default();
+    after.grant(Privilege::Select, &["ID".to_string()]);
+    let ddl = DdlPayload::Grant {
+        table: "T".into(),
+        user: "alice".into(),
+        before: Grants::default(),
+        after,
+    };
+    assert_eq!(DdlPayload::decode(&ddl.encode()).unwrap(), ddl);
+    assert_eq!(ddl.record_type(), LogRecordType::Grant);
+}
+
+#[tokio::test]
+async fn test_crash_between_index_build_and_commit_undoes_the_index() {
+    let (db, wal_path) = ("test_wal_ddl_crash.db", "test_wal_ddl_crash.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = Storage::new(db, 4096, 64).unwrap();
+    storage.attach_wal(wal.clone());
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let columns = vec![
+        ColumnInfo {
+            name: "ID".into(),
+            data_type: DataType::Int,
+            collation: Collation::Binary,
+        },
+        ColumnInfo {
+            name: "NAME".into(),
+            data_type: DataType::String,
+            collation: Collation::Binary,
+        },
+    ];
+    storage.create_table("T".into(), columns.clone()).unwrap();
+    insert_rows(&mut storage, 0..50);
+    storage.create_index("T", "ID", "BY_ID", None).unwrap();
+    wal.log_commit(1).unwrap();
+    let info = storage.get_indexes("T").pop().unwrap();
+    let built = BPlusTree::<i64>::open(&mut storage, &info).pages().unwrap();
+
+    // The hash index's pages are allocated and its record logged, but the
+    // commit never comes.
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    storage
+        .create_index_using("T", &["ID".to_string()], "ID_HASH", IndexKind::Hash)
+        .unwrap();
+    let info = storage.get_indexes("T").pop().unwrap();
+    let mut pages: HashSet<u64> = HashIndex::open(&mut storage, &info)
+        .pages()
+        .unwrap()
+        .into_iter()
+        .collect();
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+    drop(storage);
+    drop(wal);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+    let mut storage = storage.write().await;
+    assert_eq!(storage.catalog.get_table("T").unwrap().columns, columns);
+    let indexes = storage.get_indexes("T");
+    assert_eq!(indexes.len(), 1);
+    assert_eq!(indexes[0].name, "BY_ID");
+    // Built again after the crash, as index pages are not logged.
+    let mut tree = BPlusTree::<i64>::open(&mut storage, &indexes[0]);
+    assert!(tree.get(7).unwrap().is_some());
+    pages.extend(built);
+    for page in tree.pages().unwrap() {
+        pages.remove(&page);
+    }
+    assert_eq!(storage.buffer_pool.pagefile.free_page_count(), pages.len());
+    let report = check(&mut storage).unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+
+    // A rollback takes DDL back as well, without a crash. The new index may
+    // reuse the pages freed above.
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    storage.attach_wal(wal.clone());
+    storage.set_transaction(Some(3));
+    wal.log_begin(3).unwrap();
+    storage.create_table("U".into(), columns).unwrap();
+    storage.create_index("T", "ID", "AGAIN", None).unwrap();
+    let info = storage.get_indexes("T").pop().unwrap();
+    pages.extend(BPlusTree::<i64>::open(&mut storage, &info).pages().unwrap());
+    abort_transaction(&mut storage, &wal, 3).unwrap();
+    assert!(storage.catalog.get_table("U").is_err());
+    assert_eq!(storage.get_indexes("T").len(), 1);
+    assert_eq!(storage.buffer_pool.pagefile.free_page_count(), pages.len());
+    drop(storage);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_renames_are_undone_on_abort_and_redone_after_a_crash() {
+    let (db, wal_path) = ("test_wal_rename.db", "test_wal_rename.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = Storage::new(db, 4096, 64).unwrap();
+    storage.attach_wal(wal.clone());
+    let columns = vec![
+        ColumnInfo {
+            name: "ID".into(),
+            data_type: DataType::Int,
+            collation: Collation::Binary,
+        },
+        ColumnInfo {
+            name: "NAME".into(),
+            data_type: DataType::String,
+            collation: Collation::Binary,
+        },
+    ];
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    storage.create_table("T".into(), columns.clone()).unwrap();
+    storage.create_index("T", "ID", "BY_ID", None).unwrap();
+    wal.log_commit(1).unwrap();
+
+    let index_of = |storage: &Storage, table: &str| {
+        let indexes = storage.get_indexes(table).into_iter();
+        indexes.map(|i| (i.name, i.table)).collect::<Vec<_>>()
+    };
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    storage.rename_table("T", "U").unwrap();
+    storage.rename_index("BY_ID", "U_ID").unwrap();
+    assert_eq!(index_of(&storage, "U"), [("U_ID".into(), "U".into())]);
+    abort_transaction(&mut storage, &wal, 2).unwrap();
+    assert!(storage.catalog.get_table("U").is_err());
+    assert_eq!(index_of(&storage, "T"), [("BY_ID".into(), "T".into())]);
+
+    storage.set_transaction(Some(3));
+    wal.log_begin(3).unwrap();
+    storage.rename_table("T", "U").unwrap();
+    storage.rename_index("BY_ID", "U_ID").unwrap();
+    wal.log_commit(3).unwrap();
+    wal.flush_all().unwrap();
+    drop(storage);
+    drop(wal);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+    let storage = storage.read().await;
+    assert!(storage.catalog.get_table("T").is_err());
+    assert_eq!(storage.catalog.get_table("U").unwrap().columns, columns);
+    assert_eq!(index_of(&storage, "U"), [("U_ID".into(), "U".into())]);
+    drop(storage);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_recovery_starts_at_checkpoint() {
+    let (db, wal_path) = ("test_wal_checkpoint.db", "test_wal_checkpoint.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+
+    for tx in 1..=20 {
+        storage.set_transaction(Some(tx));
+        wal.log_begin(tx).unwrap();
+        insert_rows(&mut storage, (tx as i64 - 1) * 20..tx as i64 * 20);
+        wal.log_commit(tx).unwrap();
+    }
+    checkpoint(&mut storage, &wal).unwrap();
+    let master = MasterRecord::read(Path::new(wal_path)).unwrap().unwrap();
+    assert!(master.offset > 0);
+    assert_eq!(wal.bytes_since_checkpoint(), {
+        let len = std::fs::metadata(segment_path(Path::new(wal_path), 1))
+            .unwrap()
+            .len();
+        len - master.offset
+    });
+
+    storage.set_transaction(Some(21));
+    wal.log_begin(21).unwrap();
+    let committed = insert_rows(&mut storage, 400..420);
+    wal.log_commit(21).unwrap();
+    storage.set_transaction(Some(22));
+    wal.log_begin(22).unwrap();
+    let all = insert_rows(&mut storage, 420..430);
+    wal.flush(wal.last_lsn(22).unwrap()).unwrap();
+    drop(storage);
+    drop(wal);
+
+    // Scribble over everything before the checkpoint: recovery must not read it.
+    let segment = segment_path(Path::new(wal_path), 1);
+    let mut bytes = std::fs::read(&segment).unwrap();
+    bytes[WAL_HEADER_SIZE as usize..master.offset as usize].fill(0xff);
+    std::fs::write(&segment, bytes).unwrap();
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+
+    let mut storage = storage.write().await;
+    for (id, &rid) in committed.iter().enumerate() {
+        let raw = storage.fetch(rid).unwrap();
+        let row = storage.deserialize_row(&raw).unwrap();
+        assert!(matches!(row[0], Value::Int(v) if v == id as i64));
+    }
+    for &rid in &all[committed.len()..] {
+        assert!(storage.fetch(rid).is_err());
+    }
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_undo_reaches_back_past_checkpoint() {
+    let (db, wal_path) = (
+        "test_wal_checkpoint_undo.db",
+        "test_wal_checkpoint_undo.wal",
+    );
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let committed = insert_rows(&mut storage, 0..50);
+    wal.log_commit(1).unwrap();
+
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    insert_rows(&mut storage, 50..60);
+    checkpoint(&mut storage, &wal).unwrap();
+    let all = insert_rows(&mut storage, 60..70);
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+    drop(storage);
+    drop(wal);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+
+    let mut storage = storage.write().await;
+    for &rid in &committed {
+        assert!(storage.fetch(rid).is_ok());
+    }
+    for &rid in &all[committed.len()..] {
+        assert!(storage.fetch(rid).is_err());
+    }
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[test]
+fn test_rows_rolled_back_after_a_checkpoint_stay_out_of_the_catalog() {
+    let (db, wal_path) = (
+        "test_wal_catalog_rollback.db",
+        "test_wal_catalog_rollback.wal",
+    );
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    insert_rows(&mut storage, 0..10);
+    wal.log_commit(1).unwrap();
+
+    // The catalog saved with the checkpoint lists the open transaction's
+    // rows, and the rollback after it takes them out in memory only. The
+    // next rows may take their slots.
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    insert_rows(&mut storage, 10..20);
+    checkpoint(&mut storage, &wal).unwrap();
+    abort_transaction(&mut storage, &wal, 2).unwrap();
+    storage.set_transaction(Some(3));
+    wal.log_begin(3).unwrap();
+    let expected = insert_rows(&mut storage, 20..25);
+    wal.log_commit(3).unwrap();
+    drop(storage);
+    drop(wal);
+
+    let mut storage = Storage::new(db, 4096, 64).unwrap();
+    assert!(load_catalog(&mut storage, Path::new(wal_path)).unwrap());
+    recover_storage(Path::new(wal_path), &mut storage).unwrap();
+    let records = storage.catalog.get_table("T").unwrap().records.clone();
+    assert_eq!(records, expected);
+    let ids: Vec<i64> = records
+        .iter()
+        .map(|&rid| {
+            let raw = storage.fetch(rid).unwrap();
+            match storage.deserialize_row(&raw).unwrap()[0] {
+                Value::Int(id) => id,
+                ref other => panic!("{:?}", other),
+            }
+        })
+        .collect();
+    assert_eq!(ids, (0..10).chain(20..25).collect::<Vec<_>>());
+    assert_eq!(storage.catalog.get_table("T").unwrap().stats.rows, 15);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_truncate_keeps_active_transactions() {
+    let (db, wal_path) = ("test_wal_truncate.db", "test_wal_truncate.wal");
+    let archive = PathBuf::from("test_wal_truncate_archive");
+    let wal = Arc::new(
+        LogManager::new(PathBuf::from(wal_path))
+            .unwrap()
+            .with_segment_size(1024)
+            .with_archive_dir(archive.clone()),
+    );
+    let mut storage = logged_storage(db, &wal, 64);
+    assert_eq!(wal.truncate().unwrap(), 0);
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let committed = insert_rows(&mut storage, 0..100);
+    wal.log_commit(1).unwrap();
+
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    insert_rows(&mut storage, 100..110);
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+    let tx2_start = wal.first_offset(2).unwrap();
+    checkpoint(&mut storage, &wal).unwrap();
+
+    let before = wal.manifest();
+    assert!(before.active_segment > 2);
+    let removed = wal.truncate().unwrap();
+    let after = wal.manifest();
+    assert!(removed > 0);
+    assert_eq!(wal.base_offset(), WAL_HEADER_SIZE + removed);
+    assert!(wal.base_offset() <= tx2_start);
+    assert_eq!(after.active_segment, before.active_segment);
+    assert!(after.oldest_segment > before.oldest_segment);
+    for segment in before.oldest_segment..after.oldest_segment {
+        assert!(!segment_path(Path::new(wal_path), segment).exists());
+        assert!(
+            segment_path(&archive.join(wal_path), segment).exists(),
+            "segment {} not archived",
+            segment
+        );
+    }
+
+    let all = insert_rows(&mut storage, 110..120);
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+    drop(storage);
+    drop(wal);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+
+    let mut storage = storage.write().await;
+    for &rid in &committed {
+        assert!(storage.fetch(rid).is_ok());
+    }
+    for &rid in &all[committed.len()..] {
+        assert!(storage.fetch(rid).is_err());
+    }
+
+    // Once nothing is active, only the checkpoint's segment has to stay.
+    let wal = LogManager::new(PathBuf::from(wal_path))
+        .unwrap()
+        .with_segment_size(1024);
+    checkpoint(&mut storage, &wal).unwrap();
+    wal.truncate().unwrap();
+    let master = MasterRecord::read(Path::new(wal_path)).unwrap().unwrap();
+    let manifest = wal.manifest();
+    assert!(wal.base_offset() <= master.offset);
+    assert!(manifest.active_segment - manifest.oldest_segment <= 1);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+    std::fs::remove_dir_all(archive).unwrap();
+}
+
+#[test]
+fn test_log_rolls_over_into_segments() {
+    let (db, wal_path) = ("test_wal_segments.db", "test_wal_segments.wal");
+    let wal = Arc::new(
+        LogManager::new(PathBuf::from(wal_path))
+            .unwrap()
+            .with_segment_size(512),
+    );
+    let mut storage = logged_storage(db, &wal, 64);
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    insert_rows(&mut storage, 0..50);
+    wal.log_commit(1).unwrap();
+
+    let manifest = wal.manifest();
+    assert_eq!(manifest.oldest_segment, 1);
+    assert!(manifest.active_segment > 1);
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut records = 0;
+    let mut last = 0;
+    while let Some(record) = reader.next_record().unwrap() {
+        assert!(record.header.lsn > last);
+        last = record.header.lsn;
+        records += 1;
+    }
+    assert_eq!(last, records);
+    for segment in manifest.segments() {
+        let len = std::fs::metadata(segment_path(Path::new(wal_path), segment))
+            .unwrap()
+            .len();
+        assert!(len > WAL_HEADER_SIZE || segment == manifest.active_segment);
+    }
+
+    // Reopening appends to the active segment rather than starting over.
+    drop(storage);
+    drop(wal);
+    let wal = LogManager::new(PathBuf::from(wal_path)).unwrap();
+    assert_eq!(wal.manifest(), manifest);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_recovery_stops_at_torn_tail() {
+    let (db, wal_path) = ("test_wal_torn.db", "test_wal_torn.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let committed = insert_rows(&mut storage, 0..20);
+    wal.log_commit(1).unwrap();
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    let all = insert_rows(&mut storage, 20..30);
+    wal.log_commit(2).unwrap();
+    drop(storage);
+    drop(wal);
+
+    // Lose the second half of tx 2's commit record.
+    let segment = segment_path(Path::new(wal_path), 1);
+    let len = std::fs::metadata(&segment).unwrap().len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&segment)
+        .unwrap();
+    file.set_len(len - 20).unwrap();
+    drop(file);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+    {
+        let mut storage = storage.write().await;
+        for &rid in &committed {
+            assert!(storage.fetch(rid).is_ok());
+        }
+        for &rid in &all[committed.len()..] {
+            assert!(storage.fetch(rid).is_err());
+        }
+    }
+
+    // The torn bytes are gone, so records logged afterwards are readable.
+    let wal = LogManager::new(PathBuf::from(wal_path)).unwrap();
+    wal.log_begin(3).unwrap();
+    wal.log_commit(3).unwrap();
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut last = None;
+    while let Some(record) = reader.next_record().unwrap() {
+        last = Some((record.header.tx_id, record.header.typ));
+    }
+    assert_eq!(last, Some((3, LogRecordType::Commit)));
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+// The data file ends before the pages the log has changes for, as after a
+// crash that came before they were written: the committed row comes back on
+// a page redo adds, and the loser's is taken off it again.
+#[tokio::test]
+async fn test_recovery_extends_a_file_cut_short_of_logged_pages() {
+    let (db, wal_path) = ("test_wal_short_file.db", "test_wal_short_file.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+    let pages_before = storage.buffer_pool.pagefile.num_pages().unwrap();
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let committed = insert_rows(&mut storage, 0..1);
+    wal.log_commit(1).unwrap();
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    let all = insert_rows(&mut storage, 1..2);
+    wal.flush_all().unwrap();
+    assert!(storage.buffer_pool.pagefile.num_pages().unwrap() > pages_before);
+    drop(storage);
+    drop(wal);
+
+    let file = std::fs::OpenOptions::new().write(true).open(db).unwrap();
+    file.set_len(pages_before * 4096).unwrap();
+    drop(file);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+    {
+        let mut storage = storage.write().await;
+        let row = storage.fetch(committed[0]).unwrap();
+        assert_eq!(
+            storage.deserialize_row(&row).unwrap(),
+            vec![Value::Int(0), Value::String("row-0".into())]
+        );
+        assert!(storage.fetch(all[1]).is_err());
+    }
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[test]
+fn test_checksum_mismatch_ends_the_log() {
+    let wal_path = "test_wal_crc.wal";
+    let wal = LogManager::new(PathBuf::from(wal_path)).unwrap();
+    for tx in 1..=3 {
+        wal.log_begin(tx).unwrap();
+        wal.log_commit(tx).unwrap();
+    }
+    drop(wal);
+
+    // Flip one byte inside the fourth record (tx 2's begin).
+    let segment = segment_path(Path::new(wal_path), 1);
+    let mut bytes = std::fs::read(&segment).unwrap();
+    let record_len = (bytes.len() - WAL_HEADER_SIZE as usize) / 6;
+    bytes[WAL_HEADER_SIZE as usize + 3 * record_len + 10] ^= 0xff;
+    std::fs::write(&segment, bytes).unwrap();
+
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut count = 0;
+    while reader.next_record().unwrap().is_some() {
+        count += 1;
+    }
+    assert_eq!(count, 3);
+
+    let wal = LogManager::new(PathBuf::from(wal_path)).unwrap();
+    drop(wal);
+    assert_eq!(
+        std::fs::metadata(&segment).unwrap().len(),
+        WAL_HEADER_SIZE + 3 * record_len as u64
+    );
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_redo_skips_changes_already_on_disk() {
+    let (db, wal_path) = ("test_wal_page_lsn.db", "test_wal_page_lsn.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let committed = insert_rows(&mut storage, 0..10);
+    wal.log_commit(1).unwrap();
+    let page_no = committed[0].0;
+    let frame = storage.buffer_pool.fetch_page(page_no).unwrap();
+    assert_eq!(RecordPage::lsn_of(&frame.data), frame.lsn);
+    assert!(frame.lsn > 0);
+    storage.buffer_pool.unpin_page(page_no, false);
+
+    // Roll tx 2 back online and flush: the heap on disk no longer has its
+    // rows, but its update records are still in the log.
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    let all = insert_rows(&mut storage, 10..20);
+    abort_transaction(&mut storage, &wal, 2).unwrap();
+    storage.flush().unwrap();
+    drop(storage);
+    drop(wal);
+
+    let storage = Arc::new(RwLock::new(Storage::new(db, 4096, 64).unwrap()));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+
+    let mut storage = storage.write().await;
+    for &rid in &committed {
+        assert!(storage.fetch(rid).is_ok());
+    }
+    for &rid in &all[committed.len()..] {
+        assert!(storage.fetch(rid).is_err());
+    }
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_restart_resumes_lsn_numbering() {
+    let (db, wal_path) = ("test_wal_resume.db", "test_wal_resume.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    insert_rows(&mut storage, 0..10);
+    wal.log_commit(1).unwrap();
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    insert_rows(&mut storage, 10..20);
+    let tx2_last = wal.last_lsn(2).unwrap();
+    wal.flush(tx2_last).unwrap();
+    drop(storage);
+    drop(wal);
+
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    assert_eq!(wal.last_lsn(1), None);
+    assert_eq!(wal.last_lsn(2), Some(tx2_last));
+    assert_eq!(wal.flushed_lsn(), tx2_last);
+
+    let mut storage = Storage::new(db, 4096, 64).unwrap();
+    storage.attach_wal(wal.clone());
+    let storage = Arc::new(RwLock::new(storage));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+    assert_eq!(wal.last_lsn(2), None);
+    wal.log_begin(3).unwrap();
+    wal.log_commit(3).unwrap();
+
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut last = 0;
+    let mut abort = None;
+    let mut first_clr = None;
+    while let Some(record) = reader.next_record().unwrap() {
+        assert!(
+            record.header.lsn > last,
+            "LSN {} after {}",
+            record.header.lsn,
+            last
+        );
+        last = record.header.lsn;
+        match record.header.typ {
+            LogRecordType::Abort => abort = Some(record.header),
+            LogRecordType::Compensation if first_clr.is_none() => first_clr = Some(record.header),
+            _ => {}
+        }
+    }
+    let abort = abort.unwrap();
+    let first_clr = first_clr.unwrap();
+    assert_eq!(abort.tx_id, 2);
+    assert_eq!(first_clr.prev_lsn, Some(tx2_last));
+    assert!(abort.prev_lsn > Some(tx2_last));
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+#[tokio::test]
+async fn test_restart_never_reuses_tx_ids() {
+    let (db, wal_path) = ("test_wal_tx_ids.db", "test_wal_tx_ids.wal");
+    let run = |storage: &mut Storage, wal: &LogManager, ids: std::ops::Range<i64>| {
+        let tx = storage.txns.begin();
+        storage.set_transaction(Some(tx));
+        wal.log_begin(tx).unwrap();
+        insert_rows(storage, ids);
+        wal.log_commit(tx).unwrap();
+        storage.txns.commit(tx);
+        storage.set_transaction(None);
+        tx
+    };
+
+    let wal = Arc::new(
+        LogManager::new(PathBuf::from(wal_path))
+            .unwrap()
+            .with_segment_size(1024),
+    );
+    let mut storage = logged_storage(db, &wal, 64);
+    for i in 0..3 {
+        run(&mut storage, &wal, i * 50..(i + 1) * 50);
+    }
+    let before = wal.max_tx_id();
+    assert_eq!(before, 3);
+    // The checkpoint carries the high-water mark once the records that
+    // held those ids are truncated away.
+    checkpoint(&mut storage, &wal).unwrap();
+    assert!(wal.truncate().unwrap() > 0);
+    drop(storage);
+    drop(wal);
+
+    let wal = Arc::new(
+        LogManager::new(PathBuf::from(wal_path))
+            .unwrap()
+            .with_segment_size(1024),
+    );
+    assert_eq!(wal.max_tx_id(), before);
+    let mut storage = Storage::new(db, 4096, 64).unwrap();
+    storage.attach_wal(wal.clone());
+    storage.txns.advance_past(wal.max_tx_id());
+    let storage = Arc::new(RwLock::new(storage));
+    RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+        .recover()
+        .await
+        .unwrap();
+    let storage = storage.write().await;
+    let tx = storage.txns.begin();
+    assert!(tx > before);
+    storage.txns.abort(tx);
+    drop(storage);
+    drop(wal);
+
+    // A restart without a checkpoint finds the ids in the records alone.
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+    storage.txns.advance_past(wal.max_tx_id());
+    let next = run(&mut storage, &wal, 150..160);
+    assert_eq!(next, before + 1);
+    drop(storage);
+    drop(wal);
+    let wal = LogManager::new(PathBuf::from(wal_path)).unwrap();
+    assert_eq!(wal.max_tx_id(), next);
+
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut last = 0;
+    while let Some(record) = reader.next_record().unwrap() {
+        if record.header.typ == LogRecordType::Begin {
+            assert!(
+                record.header.tx_id > last,
+                "transaction {} began after {}",
+                record.header.tx_id,
+                last
+            );
+            last = record.header.tx_id;
+        }
+    }
+    assert_eq!(last, next);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+// Counts tx's update and compensation records and whether it was aborted.
+fn undo_progress(wal_path: &str, tx: u64) -> (usize, usize, bool) {
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let (mut updates, mut clrs, mut aborted) = (0, 0, false);
+    while let Some(record) = reader.next_record().unwrap() {
+        if record.header.tx_id != tx {
+            continue;
+        }
+        match record.header.typ {
+            LogRecordType::Update => updates += 1,
+            LogRecordType::Compensation => clrs += 1,
+            LogRecordType::Abort => aborted = true,
+            _ => {}
+        }
+    }
+    (updates, clrs, aborted)
+}
+
+#[tokio::test]
+async fn test_crash_during_undo_resumes_from_clr() {
+    let (db, wal_path) = ("test_wal_clr.db", "test_wal_clr.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let committed = insert_rows(&mut storage, 0..10);
+    wal.log_commit(1).unwrap();
+
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    let all = insert_rows(&mut storage, 10..40);
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+
+    // Undo the last two updates of tx 2 by hand, get the pages to disk and
+    // crash before the rest of the rollback.
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut updates = Vec::new();
+    while let Some(record) = reader.next_record().unwrap() {
+        if record.header.tx_id == 2 && record.header.typ == LogRecordType::Update {
+            updates.push(record);
+        }
+    }
+    for record in updates.iter().rev().take(2) {
+        let update = UpdatePayload::decode(&record.payload).unwrap();
+        let undo_next = record.header.prev_lsn.unwrap();
+        compensate(&mut storage, &wal, 2, &update, undo_next).unwrap();
+    }
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+    storage.flush().unwrap();
+    drop(storage);
+    drop(wal);
+    assert_eq!(undo_progress(wal_path, 2), (updates.len(), 2, false));
+
+    for _ in 0..2 {
+        let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+        let mut storage = Storage::new(db, 4096, 64).unwrap();
+        storage.attach_wal(wal.clone());
+        let storage = Arc::new(RwLock::new(storage));
+        RecoveryManager::new(PathBuf::from(wal_path), storage.clone())
+            .recover()
+            .await
+            .unwrap();
+
+        let mut storage = storage.write().await;
+        for &rid in &committed {
+            assert!(storage.fetch(rid).is_ok());
+        }
+        for &rid in &all[committed.len()..] {
+            assert!(storage.fetch(rid).is_err());
+        }
+        // Every update is undone exactly once, across both rollbacks.
+        assert_eq!(
+            undo_progress(wal_path, 2),
+            (updates.len(), updates.len(), true)
+        );
+    }
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+fn counting_wal(wal_path: &str, policy: FlushPolicy) -> (Arc<LogManager>, Arc<AtomicUsize>) {
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let counter = syncs.clone();
+    let wal = LogManager::new(PathBuf::from(wal_path))
+        .unwrap()
+        .with_flush_policy(policy)
+        .with_sync_hook(Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+    (Arc::new(wal), syncs)
+}
+
+// LSN of the last record that actually made it into the file.
+fn last_lsn_on_disk(wal_path: &str) -> u64 {
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut last = 0;
+    while let Some(record) = reader.next_record().unwrap() {
+        last = record.header.lsn;
+    }
+    last
+}
+
+#[test]
+fn test_per_commit_policy_syncs_every_commit() {
+    let wal_path = "test_wal_flush_commit.wal";
+    let (wal, syncs) = counting_wal(wal_path, FlushPolicy::PerCommit);
+    for tx in 1..=5 {
+        wal.log_begin(tx).unwrap();
+        let lsn = wal.log_commit(tx).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), tx as usize);
+        assert_eq!(wal.flushed_lsn(), lsn);
+        assert_eq!(last_lsn_on_disk(wal_path), lsn);
+    }
+    drop(wal);
+    remove_wal(wal_path);
+}
+
+#[test]
+fn test_buffer_full_policy_batches_syncs() {
+    let wal_path = "test_wal_flush_buffer.wal";
+    let (wal, syncs) = counting_wal(wal_path, FlushPolicy::OnBufferFull(1024));
+    let mut last = 0;
+    for tx in 1..=100 {
+        wal.log_begin(tx).unwrap();
+        last = wal.log_commit(tx).unwrap();
+        assert_eq!(last_lsn_on_disk(wal_path), wal.flushed_lsn());
+    }
+    // 200 records of 37 bytes each fill the buffer seven times.
+    assert_eq!(syncs.load(Ordering::SeqCst), 7);
+    assert!(wal.flushed_lsn() < last);
+
+    wal.flush(last + 10).unwrap();
+    assert_eq!(wal.flushed_lsn(), last);
+    assert_eq!(last_lsn_on_disk(wal_path), last);
+    // Records written after their transaction ended leave nothing behind.
+    assert!((1..=100).all(|tx| wal.first_offset(tx).is_none()));
+    let lsn = wal.log_begin(101).unwrap();
+    assert!(lsn > wal.flushed_lsn());
+    wal.flush(lsn).unwrap();
+    assert!(wal.first_offset(101).is_some());
+    drop(wal);
+    remove_wal(wal_path);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_interval_policy_flushes_in_background() {
+    let wal_path = "test_wal_flush_interval.wal";
+    let (wal, syncs) = counting_wal(wal_path, FlushPolicy::Interval(Duration::from_millis(50)));
+    let flusher = wal.spawn_flusher().unwrap();
+    tokio::time::sleep(Duration::from_millis(1)).await;
+
+    wal.log_begin(1).unwrap();
+    let lsn = wal.log_commit(1).unwrap();
+    assert_eq!(syncs.load(Ordering::SeqCst), 0);
+    assert!(wal.flushed_lsn() < lsn);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(syncs.load(Ordering::SeqCst), 1);
+    assert_eq!(wal.flushed_lsn(), lsn);
+    assert_eq!(last_lsn_on_disk(wal_path), lsn);
+
+    // An idle log is not synced again.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(syncs.load(Ordering::SeqCst), 1);
+
+    drop(wal);
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert!(flusher.is_finished());
+    remove_wal(wal_path);
+}
+
+#[test]
+fn test_undo_reads_each_record_once() {
+    let (db, wal_path) = ("test_wal_undo_index.db", "test_wal_undo_index.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let rid = insert_rows(&mut storage, 0..1)[0];
+    wal.log_commit(1).unwrap();
+
+    // 10k one-byte updates to the same spot, each undoing the next.
+    let offset = 200;
+    let frame = storage.buffer_pool.fetch_page(rid.0).unwrap();
+    let original = frame.data[offset];
+    storage.buffer_pool.unpin_page(rid.0, false);
+    wal.log_begin(2).unwrap();
+    for i in 0..10_000u32 {
+        let update = UpdatePayload {
+            page_no: rid.0,
+            offset: offset as u32,
+            before: vec![original.wrapping_add(i as u8)],
+            after: vec![original.wrapping_add(i as u8).wrapping_add(1)],
+        };
+        wal.log_update(2, update.encode()).unwrap();
+    }
+    let last_lsn = wal.last_lsn(2).unwrap();
+    wal.flush(last_lsn).unwrap();
+
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let index = LsnIndex::build(
+        &mut reader,
+        wal.first_offset(2).unwrap(),
+        &HashSet::from([2]),
+    )
+    .unwrap();
+    assert_eq!(index.len(), 10_001);
+    let scanned = reader.records_read();
+    let undone = undo_transaction(&mut storage, &wal, &mut reader, &index, 2, last_lsn).unwrap();
+    assert_eq!(undone, 10_000);
+    assert_eq!(reader.records_read() - scanned, 10_001);
+
+    let frame = storage.buffer_pool.fetch_page(rid.0).unwrap();
+    assert_eq!(frame.data[offset], original);
+    storage.buffer_pool.unpin_page(rid.0, false);
+    drop(storage);
+    drop(wal);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
+
+// Bytes of a heap page outside its LSN, which the images leave out.
+fn without_lsn(mut page: Vec<u8>) -> Vec<u8> {
+    page[RecordPage::LSN_RANGE].fill(0);
+    page
+}
+
+#[test]
+fn test_logged_images_replay_inserts_and_deletes_byte_for_byte() {
+    let (db, wal_path) = ("test_wal_images.db", "test_wal_images.wal");
+    let wal = Arc::new(LogManager::new(PathBuf::from(wal_path)).unwrap());
+    let mut storage = logged_storage(db, &wal, 64);
+    storage.set_transaction(Some(1));
+    wal.log_begin(1).unwrap();
+    let rid = insert_rows(&mut storage, 0..3)[0];
+    wal.log_commit(1).unwrap();
+    let before = storage.buffer_pool.read_page(rid.0).unwrap();
+
+    storage.set_transaction(Some(2));
+    wal.log_begin(2).unwrap();
+    insert_rows(&mut storage, 3..5);
+    storage.delete_row("T", rid).unwrap();
+    wal.flush(wal.last_lsn(2).unwrap()).unwrap();
+    let after = storage.buffer_pool.read_page(rid.0).unwrap();
+
+    let mut reader = WalReader::open(Path::new(wal_path)).unwrap();
+    let mut updates = Vec::new();
+    while let Some(record) = reader.next_record().unwrap() {
+        if record.header.tx_id == 2 && record.header.typ == LogRecordType::Update {
+            updates.push(UpdatePayload::decode(&record.payload).unwrap());
+        }
+    }
+    assert!(updates.iter().all(|u| u.page_no == rid.0));
+    // The slot directory, the row payloads and the deleted row's header all
+    // changed, each in a range of its own.
+    let page = RecordPage::from_bytes(after.clone(), 4096);
+    let payload_start = page.payload_start();
+    assert!(updates.iter().any(|u| (u.offset as usize) < payload_start));
+    assert!(updates.iter().any(|u| (u.offset as usize) >= payload_start));
+    let deleted_at = page.slot_entry(rid.1).unwrap().0;
+    assert!(updates.iter().any(|u| u.offset as usize == deleted_at + 8));
+
+    // Redo from the committed page: each before image is what is there.
+    let mut page = without_lsn(before.clone());
+    for u in &updates {
+        let range = u.offset as usize..u.offset as usize + u.after.len();
+        assert_eq!(page[range.clone()], u.before[..]);
+        page[range].copy_from_slice(&u.after);
+    }
+    assert_eq!(page, without_lsn(after));
+    // Undo, newest first, gets the committed page back.
+    for u in updates.iter().rev() {
+        let range = u.offset as usize..u.offset as usize + u.before.len();
+        assert_eq!(page[range.clone()], u.after[..]);
+        page[range].copy_from_slice(&u.before);
+    }
+    assert_eq!(page, without_lsn(before));
+    drop(storage);
+    drop(wal);
+    remove_file(db).unwrap();
+    remove_wal(wal_path);
+}
@@ -0,0 +1,83 @@
+use engine::cli::waldump::{WaldumpArgs, dump};
+use engine::storage::atomic_file::atomic_remove;
+use engine::tx::log_manager::{LogManager, Manifest, UpdatePayload, segment_path};
+use std::fs::{OpenOptions, remove_file};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+fn remove_wal(wal_path: &str) {
+    let path = Path::new(wal_path);
+    let manifest = Manifest::read(path).unwrap().unwrap();
+    for segment in manifest.segments() {
+        remove_file(segment_path(path, segment)).unwrap();
+    }
+    atomic_remove(&Manifest::path(path)).unwrap();
+}
+
+fn run(args: &[&str]) -> String {
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    let mut out = Vec::new();
+    dump(&WaldumpArgs::parse(&args).unwrap(), &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_waldump_prints_records_and_summary() {
+    let wal_path = "test_waldump.wal";
+    let wal = LogManager::new(PathBuf::from(wal_path)).unwrap();
+    wal.log_begin(1).unwrap();
+    let update = UpdatePayload {
+        page_no: 3,
+        offset: 40,
+        before: vec![0x00, 0x01],
+        after: vec![0xab, 0xcd],
+    };
+    wal.log_update(1, update.encode()).unwrap();
+    wal.log_commit(1).unwrap();
+    wal.log_begin(2).unwrap();
+    wal.log_abort(2).unwrap();
+    wal.log_begin(3).unwrap();
+    wal.flush(wal.last_lsn(3).unwrap()).unwrap();
+    drop(wal);
+    // Half a record at the end, as a crash mid-write leaves it.
+    OpenOptions::new()
+        .append(true)
+        .open(segment_path(Path::new(wal_path), 1))
+        .unwrap()
+        .write_all(&[40, 0, 0, 0, 1, 2])
+        .unwrap();
+
+    let out = run(&[wal_path]);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "lsn=1 prev=- tx=1 Begin");
+    assert_eq!(
+        lines[1],
+        "lsn=2 prev=1 tx=1 Update page=3 offset=40 before=0001 after=abcd"
+    );
+    assert_eq!(lines[2], "lsn=3 prev=2 tx=1 Commit");
+    assert!(lines[6].starts_with("log ends in a torn record at offset "));
+    assert_eq!(
+        &lines[8..],
+        [
+            "transactions:",
+            "  tx 1: committed (3 records)",
+            "  tx 2: aborted (2 records)",
+            "  tx 3: in progress (1 records)",
+        ]
+    );
+
+    let out = run(&[wal_path, "--tx", "2"]);
+    assert!(out.starts_with("lsn=4 prev=- tx=2 Begin\nlsn=5 prev=4 tx=2 Abort\n"));
+    assert!(out.ends_with("transactions:\n  tx 2: aborted (2 records)\n"));
+    let out = run(&[wal_path, "--from-lsn", "6"]);
+    assert!(out.starts_with("lsn=6 prev=- tx=3 Begin\n"));
+    assert!(out.ends_with("transactions:\n  tx 3: in progress (1 records)\n"));
+
+    let args =
+        |a: &[&str]| WaldumpArgs::parse(&a.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+    assert!(args(&[]).is_err());
+    assert!(args(&[wal_path, "--tx"]).is_err());
+    assert!(args(&[wal_path, "--tx", "x"]).is_err());
+    assert!(args(&[wal_path, "--bogus"]).is_err());
+    remove_wal(wal_path);
+}